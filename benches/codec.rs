@@ -0,0 +1,61 @@
+//! Encoding of heartbeat-heavy traffic, by framing and encoding of integers.
+//!
+//! The size of the encoded heartbeats is printed before each benchmark, to
+//! compare the fixed and the varint encodings of integers.
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use tokio_util::codec::Encoder;
+use uuid::Uuid;
+
+use area_net::codec::Format;
+use area_net::frame::{IntEncoding, Limits};
+use area_net::message::{HeartbeatRequest, HeartbeatResponse};
+use area_net::{Frame, FrameCodec, Message};
+
+const HEARTBEATS: usize = 1000;
+
+fn heartbeats() -> Vec<Frame> {
+    let id = Uuid::new_v4();
+    (0..HEARTBEATS)
+        .map(|i| {
+            let request = HeartbeatRequest::now(id, "alice".to_owned());
+            let msg = if i % 2 == 0 {
+                Message::HeartbeatRequest(request)
+            } else {
+                let src = request.src;
+                Message::HeartbeatResponse(HeartbeatResponse::now(id, "bob".to_owned(), src))
+            };
+            msg.into_frame().expect("heartbeat frame")
+        })
+        .collect()
+}
+
+fn encode(format: Format, integers: IntEncoding, frames: &[Frame]) -> BytesMut {
+    let mut codec = FrameCodec::connecting(Limits::default(), format).with_integers(integers);
+    let mut dst = BytesMut::new();
+    for frame in frames {
+        codec
+            .encode(frame.clone(), &mut dst)
+            .expect("encoded heartbeat");
+    }
+    dst
+}
+
+fn bench_heartbeats(c: &mut Criterion) {
+    let frames = heartbeats();
+    for format in [Format::Text, Format::Binary] {
+        for integers in [IntEncoding::Fixed, IntEncoding::Varint] {
+            let size = encode(format, integers, &frames).len();
+            println!(
+                "{format:?}/{integers:?}: {size} bytes for {HEARTBEATS} heartbeats ({} per heartbeat)",
+                size / HEARTBEATS
+            );
+            c.bench_function(&format!("heartbeats {format:?}/{integers:?}"), |b| {
+                b.iter(|| encode(format, integers, black_box(&frames)))
+            });
+        }
+    }
+}
+
+criterion_group!(benches, bench_heartbeats);
+criterion_main!(benches);
@@ -0,0 +1,35 @@
+//! Decodes arbitrary bytes as a frame, in the text and binary framings, with
+//! the default and the strict limits. A frame which passes `Frame::check`
+//! must parse, and encode back to the length it announces.
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+use area_net::binary;
+use area_net::frame::Limits;
+use area_net::Frame;
+
+fuzz_target!(|data: &[u8]| {
+    let strict = Limits {
+        strict: true,
+        ..Limits::default()
+    };
+    for limits in [Limits::default(), strict] {
+        let mut src = Cursor::new(data);
+        if Frame::check_with_limits(&mut src, &limits).is_ok() {
+            let mut src = Cursor::new(data);
+            let frame = Frame::parse_with_limits(&mut src, &limits).expect("checked frame parses");
+            let mut dst = BytesMut::new();
+            if frame.write_with_limits(&mut dst, &limits).is_ok() {
+                assert_eq!(dst.len(), frame.encoded_len());
+            }
+        }
+
+        let mut src = Cursor::new(data);
+        if let Ok(frame) = binary::parse(&mut src, &limits) {
+            let mut dst = BytesMut::new();
+            binary::write(&frame, &mut dst, &limits).expect("decoded frame encodes");
+        }
+    }
+});
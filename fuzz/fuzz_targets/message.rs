@@ -0,0 +1,17 @@
+//! Feeds arbitrary bytes to a listener's codec, which detects the framing and
+//! unwraps compressed and checksummed frames, and decodes the messages.
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+use area_net::frame::Limits;
+use area_net::{FrameCodec, Message};
+
+fuzz_target!(|data: &[u8]| {
+    let mut codec = FrameCodec::detecting(Limits::default());
+    let mut src = BytesMut::from(data);
+    while let Ok(Some(frame)) = codec.decode(&mut src) {
+        let _ = Message::from_frame(frame);
+    }
+});
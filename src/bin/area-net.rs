@@ -1,14 +1,35 @@
-use area_net::network::{controller::NetworkController, Network};
-use clap::Parser;
+use area_net::codec::Format;
+use area_net::network::{
+    admin::LogFilter, controller::NetworkController, journal, stress, Network,
+};
+use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 use std::fmt;
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt as tracing_fmt, reload, EnvFilter};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt::init();
+    // The filter is reloadable, so that it can be changed from the admin server.
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, log_filter) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_fmt::layer())
+        .init();
+    // Records from the 'log' crate are capped when the subscriber is installed,
+    // so let them all through, and leave the filtering to the reloadable filter.
+    log::set_max_level(log::LevelFilter::Trace);
 
     let opt = Opt::parse();
+    if let Some(Cmd::Stress(options)) = opt.command {
+        let report = stress::run(&options.into()).await;
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return Ok(());
+    }
+    let replay_journal = opt.replay_journal.clone();
 
     let config: Config = match opt.try_into() {
         Ok(config) => config,
@@ -20,7 +41,11 @@ async fn main() -> Result<(), Error> {
 
     log::info!("config: {}", serde_json::to_string(&config).unwrap());
 
-    if let Err(err) = run(config).await {
+    let res = match replay_journal {
+        Some(path) => replay(config, &path).await,
+        None => run(config, log_filter).await,
+    };
+    if let Err(err) = res {
         log::error!("Error: {err}");
         std::process::exit(1);
     } else {
@@ -28,27 +53,60 @@ async fn main() -> Result<(), Error> {
     }
 }
 
-async fn run(config: Config) -> Result<(), Error> {
+async fn run(config: Config, log_filter: LogFilter) -> Result<(), Error> {
     let mut controller =
         NetworkController::new(config.label, config.network.controller).map_err(|err| {
             Error::Controller {
-                source: err,
+                source: Box::new(err),
                 detail: "Could not create network controller".to_owned(),
             }
         })?;
+    controller.log_filter = Some(log_filter);
 
     controller
         .initialize()
         .await
         .map_err(|err| Error::Controller {
-            source: err,
+            source: Box::new(err),
             detail: "Could not initialize network controller".to_owned(),
         })?;
 
-    controller.run().await.map_err(|err| Error::Controller {
-        source: err,
-        detail: "An error occured while running the network controller".to_owned(),
-    })
+    // Peers say goodbye to their remote on Ctrl-C.
+    let shutdown = controller.shutdown_token();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            shutdown.cancel();
+        }
+    });
+
+    controller
+        .run_on_runtime()
+        .await
+        .map_err(|err| Error::Controller {
+            source: Box::new(err),
+            detail: "An error occured while running the network controller".to_owned(),
+        })
+}
+
+/// Replay a journal through a network controller, in a dry-run, and print the report.
+async fn replay(config: Config, path: &Path) -> Result<(), Error> {
+    let controller =
+        NetworkController::new(config.label, config.network.controller).map_err(|err| {
+            Error::Controller {
+                source: Box::new(err),
+                detail: "Could not create network controller".to_owned(),
+            }
+        })?;
+    let entries = journal::read(path).map_err(|err| Error::Journal { source: err })?;
+    let report = journal::replay(controller, entries).await;
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    if !report.divergences.is_empty() {
+        log::warn!(
+            "Replay diverged from the journal after {} event(s)",
+            report.divergences.len()
+        );
+    }
+    Ok(())
 }
 
 /// Peer 2 Peer Network Controller
@@ -58,11 +116,11 @@ async fn run(config: Config) -> Result<(), Error> {
 /// and also try to connect to peer nodes depending on the
 /// configuration.
 #[derive(Parser)]
-#[command(author, version, about, long_about)]
+#[command(author, version, about, long_about, subcommand_negates_reqs = true)]
 struct Opt {
     /// Root configuration file.
-    #[arg(value_parser = clap::value_parser!(PathBuf), short = 'c', long = "config-dir")]
-    pub config_dir: PathBuf,
+    #[arg(value_parser = clap::value_parser!(PathBuf), short = 'c', long = "config-dir", required = true)]
+    pub config_dir: Option<PathBuf>,
 
     /// Configuration overrides
     #[arg(short = 's', long = "setting")]
@@ -71,6 +129,76 @@ struct Opt {
     /// set the log level
     #[clap(short = 'p', long = "profile")]
     pub profile: Option<String>,
+
+    /// Replay a journal in a dry-run and print the report, instead of running the node.
+    #[arg(value_parser = clap::value_parser!(PathBuf), long = "replay-journal")]
+    pub replay_journal: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Cmd>,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Capacity-test a node: open many connections, complete the handshakes,
+    /// and send requests at a fixed rate, reporting latency percentiles.
+    Stress(StressOpt),
+}
+
+#[derive(clap::Args)]
+struct StressOpt {
+    /// Address of the node under test.
+    #[arg(long = "target")]
+    pub target: SocketAddr,
+
+    /// Number of connections.
+    #[arg(long = "conns", default_value_t = 10)]
+    pub conns: usize,
+
+    /// Requests per second, on each connection.
+    #[arg(long = "rate", default_value_t = 1.0, value_parser = parse_rate)]
+    pub rate: f64,
+
+    /// Duration of the test (seconds), once connected.
+    #[arg(long = "duration", default_value_t = 10)]
+    pub duration: u64,
+
+    /// Send a contact request every so many requests (0: heartbeats only).
+    #[arg(long = "contact-every", default_value_t = 0)]
+    pub contact_every: u64,
+
+    /// Use the binary framing.
+    #[arg(long = "binary")]
+    pub binary: bool,
+
+    /// Network of the node under test, if not the default one.
+    #[arg(long = "network-id", default_value = "")]
+    pub network_id: String,
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("'{s}' is not a positive number")),
+    }
+}
+
+impl From<StressOpt> for stress::Options {
+    fn from(opt: StressOpt) -> Self {
+        stress::Options {
+            target: opt.target,
+            conns: opt.conns,
+            rate: opt.rate,
+            duration: std::time::Duration::from_secs(opt.duration),
+            contact_every: opt.contact_every,
+            format: if opt.binary {
+                Format::Binary
+            } else {
+                Format::Text
+            },
+            network: opt.network_id,
+        }
+    }
 }
 
 /// Top level error type.
@@ -78,12 +206,18 @@ struct Opt {
 pub enum Error {
     /// Node Error
     Controller {
-        /// Source
-        source: area_net::network::controller::Error,
+        /// Source, boxed as the controller error is large.
+        source: Box<area_net::network::controller::Error>,
         /// Error detail
         detail: String,
     },
 
+    /// Journal Error
+    Journal {
+        /// Source
+        source: journal::Error,
+    },
+
     /// Configuration Error
     Configuration {
         /// Source
@@ -115,6 +249,9 @@ impl fmt::Display for Error {
             Error::Controller { source, detail } => {
                 write!(f, "Network Controller Error: {} => {}", source, detail)
             }
+            Error::Journal { source } => {
+                write!(f, "Journal Error: {}", source)
+            }
             Error::Configuration { source } => {
                 write!(f, "Configuration Error: {}", source)
             }
@@ -135,8 +272,10 @@ impl TryInto<Config> for Opt {
     type Error = Error;
 
     fn try_into(self) -> Result<Config, Self::Error> {
+        // The config dir is required, unless a subcommand is given.
+        let config_dir = self.config_dir.unwrap_or_default();
         let config = area_net::config::merge_configuration(
-            self.config_dir.as_ref(),
+            config_dir.as_ref(),
             &["network"],
             self.profile.as_deref(),
             self.settings.clone(),
@@ -0,0 +1,367 @@
+//! Binary framing
+//!
+//! Frames are encoded with the same type markers as the text framing, but
+//! strings, arrays and maps are prefixed by their length (u32, big endian), and
+//! numbers are written as 8 bytes (big endian), so nothing has to be scanned
+//! for an end of frame marker. A connection using the binary framing starts
+//! with a preamble, which lets a listener tell it apart from the text framing.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io::Cursor;
+
+use crate::frame::{self, Error, IntEncoding, Key, Limits, SIGNED_VARINT, VARINT};
+use crate::Frame;
+
+/// Sent by the initiator of a connection using the binary framing, before its
+/// first frame. Text frames never start with a NUL byte.
+pub const PREAMBLE: &[u8; 4] = b"\0AN\x01";
+
+/// Decode a frame from `src`, within the given limits.
+/// Returns `Error::Incomplete` if `src` does not hold a whole frame yet.
+pub fn parse(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<Frame, Error> {
+    parse_depth(src, limits, 0)
+}
+
+/// Number of bytes of the frame at the start of `src`, found from the lengths
+/// it declares, without decoding it. Returns `Error::Incomplete` if `src` does
+/// not hold a whole frame yet.
+pub fn frame_len(src: &[u8], limits: &Limits) -> Result<usize, Error> {
+    let mut buf = Cursor::new(src);
+    skip_depth(&mut buf, limits, 0)?;
+    Ok(buf.position() as usize)
+}
+
+/// Key of the message at the start of `src`, read without decoding the frame.
+/// A sealed message (an array holding the message, then its HMAC) is looked
+/// into. Returns None if `src` does not hold the key yet, or does not start
+/// with a message.
+pub fn peek_key(src: &[u8]) -> Option<Key<'_>> {
+    let mut buf = Cursor::new(src);
+    // The message, or the array sealing it.
+    for _ in 0..2 {
+        if get_u8(&mut buf).ok()? != b'*' {
+            return None;
+        }
+        get_array::<4>(&mut buf).ok()?;
+        let position = buf.position();
+        match get_u8(&mut buf).ok()? {
+            b'+' => {
+                let tag = get_bytes(&mut buf, &Limits::default()).ok()?;
+                return std::str::from_utf8(tag).ok().map(Key::Tag);
+            }
+            b':' => return Some(Key::Id(u64::from_be_bytes(get_array(&mut buf).ok()?))),
+            VARINT => return frame::get_varint(&mut buf).ok().map(Key::Id),
+            b'*' => buf.set_position(position),
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Encode a frame into `dst`. Arrays nested deeper than the limits are
+/// rejected, as the remote would not decode them.
+pub fn write(frame: &Frame, dst: &mut BytesMut, limits: &Limits) -> Result<(), Error> {
+    write_with_encoding(frame, dst, limits, IntEncoding::Fixed)
+}
+
+/// Encode a frame into `dst`, within the limits, with the given encoding of integers.
+pub fn write_with_encoding(
+    frame: &Frame,
+    dst: &mut BytesMut,
+    limits: &Limits,
+    ints: IntEncoding,
+) -> Result<(), Error> {
+    // The length is exact, so the frame is written without reallocating.
+    dst.reserve(encoded_len(frame, ints));
+    write_depth(frame, dst, limits, ints, 0)
+}
+
+/// Number of bytes of the frame in the binary framing, with the given
+/// encoding of integers.
+pub fn encoded_len(frame: &Frame, ints: IntEncoding) -> usize {
+    match frame {
+        Frame::UInt(val) if ints == IntEncoding::Varint => 1 + frame::varint_len(*val),
+        Frame::Int(val) if ints == IntEncoding::Varint => {
+            1 + frame::varint_len(frame::zigzag(*val))
+        }
+        Frame::String(val) | Frame::Error(val) => 5 + val.len(),
+        Frame::Bulk(val) => 5 + val.len(),
+        Frame::UInt(_) | Frame::Int(_) | Frame::Double(_) => 9,
+        Frame::Bool(_) => 2,
+        Frame::Null => 1,
+        Frame::Array(frames) => frames.iter().fold(5, |acc, f| acc + encoded_len(f, ints)),
+        Frame::Map(entries) => entries
+            .iter()
+            .fold(5, |acc, (k, v)| acc + 4 + k.len() + encoded_len(v, ints)),
+    }
+}
+
+// Write a frame nested in 'depth' arrays.
+fn write_depth(
+    frame: &Frame,
+    dst: &mut BytesMut,
+    limits: &Limits,
+    ints: IntEncoding,
+    depth: usize,
+) -> Result<(), Error> {
+    match frame {
+        Frame::UInt(val) if ints == IntEncoding::Varint => {
+            dst.put_u8(VARINT);
+            frame::write_varint(dst, *val);
+        }
+        Frame::Int(val) if ints == IntEncoding::Varint => {
+            dst.put_u8(SIGNED_VARINT);
+            frame::write_varint(dst, frame::zigzag(*val));
+        }
+        Frame::String(val) => {
+            dst.extend_from_slice(b"+");
+            write_bytes(dst, val.as_bytes())?;
+        }
+        Frame::Error(val) => {
+            dst.extend_from_slice(b"-");
+            write_bytes(dst, val.as_bytes())?;
+        }
+        Frame::UInt(val) => {
+            dst.extend_from_slice(b":");
+            dst.extend_from_slice(&val.to_be_bytes());
+        }
+        Frame::Int(val) => {
+            dst.extend_from_slice(b"@");
+            dst.extend_from_slice(&val.to_be_bytes());
+        }
+        Frame::Bool(val) => {
+            dst.extend_from_slice(&[b'?', u8::from(*val)]);
+        }
+        Frame::Double(val) => {
+            dst.extend_from_slice(b",");
+            dst.extend_from_slice(&val.to_be_bytes());
+        }
+        Frame::Null => {
+            dst.extend_from_slice(b"_");
+        }
+        Frame::Bulk(val) => {
+            dst.extend_from_slice(b"$");
+            write_bytes(dst, val)?;
+        }
+        Frame::Array(val) => {
+            frame::check_nesting(limits, depth + 1)?;
+            let len: u32 = val.len().try_into()?;
+            dst.extend_from_slice(b"*");
+            dst.extend_from_slice(&len.to_be_bytes());
+            for entry in val {
+                write_depth(entry, dst, limits, ints, depth + 1)?;
+            }
+        }
+        Frame::Map(val) => {
+            frame::check_nesting(limits, depth + 1)?;
+            let len: u32 = val.len().try_into()?;
+            dst.extend_from_slice(b"#");
+            dst.extend_from_slice(&len.to_be_bytes());
+            for (key, value) in val {
+                write_bytes(dst, key.as_bytes())?;
+                write_depth(value, dst, limits, ints, depth + 1)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_depth(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<Frame, Error> {
+    match get_u8(src)? {
+        b'+' => Ok(Frame::String(get_string(src, limits)?)),
+        b'-' => Ok(Frame::Error(get_string(src, limits)?)),
+        b':' => Ok(Frame::UInt(u64::from_be_bytes(get_array(src)?))),
+        b'@' => Ok(Frame::Int(i64::from_be_bytes(get_array(src)?))),
+        VARINT => Ok(Frame::UInt(frame::get_varint(src)?)),
+        SIGNED_VARINT => Ok(Frame::Int(frame::unzigzag(frame::get_varint(src)?))),
+        b'?' => match get_u8(src)? {
+            0 => Ok(Frame::Bool(false)),
+            1 => Ok(Frame::Bool(true)),
+            byte => Err(Error::UnexpectedBytes {
+                detail: format!("Invalid boolean {byte:#04x}"),
+            }),
+        },
+        b',' => Ok(Frame::Double(f64::from_be_bytes(get_array(src)?))),
+        b'_' => Ok(Frame::Null),
+        b'$' => Ok(Frame::Bulk(Bytes::copy_from_slice(get_bytes(src, limits)?))),
+        b'*' => {
+            frame::check_nesting(limits, depth + 1)?;
+            let len = u32::from_be_bytes(get_array(src)?);
+            let len = frame::check_array_len(len.into(), limits)?;
+            // Each element takes at least one byte.
+            let mut frames = Vec::with_capacity(len.min(src.remaining()));
+            for _ in 0..len {
+                frames.push(parse_depth(src, limits, depth + 1)?);
+            }
+            Ok(Frame::Array(frames))
+        }
+        b'#' => {
+            frame::check_nesting(limits, depth + 1)?;
+            let len = u32::from_be_bytes(get_array(src)?);
+            let len = frame::check_array_len(len.into(), limits)?;
+            // Each entry takes at least five bytes: the key length and a type.
+            let mut entries = Vec::with_capacity(len.min(src.remaining() / 5));
+            for _ in 0..len {
+                let key = get_string(src, limits)?;
+                entries.push((key, parse_depth(src, limits, depth + 1)?));
+            }
+            Ok(Frame::Map(entries))
+        }
+        byte => Err(Error::UnexpectedBytes {
+            detail: format!("Invalid frame type {byte:#04x}"),
+        }),
+    }
+}
+
+// Move past a frame nested in 'depth' arrays, checking the lengths it declares.
+fn skip_depth(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<(), Error> {
+    match get_u8(src)? {
+        b'+' | b'-' | b'$' => {
+            get_bytes(src, limits)?;
+        }
+        b':' | b'@' | b',' => {
+            get_array::<8>(src)?;
+        }
+        VARINT | SIGNED_VARINT => {
+            frame::get_varint(src)?;
+        }
+        b'?' => {
+            get_u8(src)?;
+        }
+        b'_' => {}
+        b'*' => {
+            frame::check_nesting(limits, depth + 1)?;
+            let len = u32::from_be_bytes(get_array(src)?);
+            for _ in 0..frame::check_array_len(len.into(), limits)? {
+                skip_depth(src, limits, depth + 1)?;
+            }
+        }
+        b'#' => {
+            frame::check_nesting(limits, depth + 1)?;
+            let len = u32::from_be_bytes(get_array(src)?);
+            for _ in 0..frame::check_array_len(len.into(), limits)? {
+                get_bytes(src, limits)?;
+                skip_depth(src, limits, depth + 1)?;
+            }
+        }
+        byte => {
+            return Err(Error::UnexpectedBytes {
+                detail: format!("Invalid frame type {byte:#04x}"),
+            })
+        }
+    }
+    Ok(())
+}
+
+fn write_bytes(dst: &mut BytesMut, val: &[u8]) -> Result<(), Error> {
+    let len: u32 = val.len().try_into()?;
+    dst.extend_from_slice(&len.to_be_bytes());
+    dst.extend_from_slice(val);
+    Ok(())
+}
+
+fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    Ok(get_array::<1>(src)?[0])
+}
+
+fn get_array<const N: usize>(src: &mut Cursor<&[u8]>) -> Result<[u8; N], Error> {
+    if src.remaining() < N {
+        return Err(Error::Incomplete {
+            detail: format!("expected {N} bytes, buflen < {N}"),
+        });
+    }
+    let mut buf = [0u8; N];
+    src.copy_to_slice(&mut buf);
+    Ok(buf)
+}
+
+// Read a length prefixed sequence of bytes. The length is declared by the
+// remote, so it is checked against the buffer limit before waiting for the bytes.
+fn get_bytes<'a>(src: &mut Cursor<&'a [u8]>, limits: &Limits) -> Result<&'a [u8], Error> {
+    let len: usize = u32::from_be_bytes(get_array(src)?).try_into()?;
+    if len > limits.max_buffer_size {
+        return Err(Error::LimitExceeded {
+            detail: format!(
+                "String of {} bytes, more than {}",
+                len, limits.max_buffer_size
+            ),
+        });
+    }
+    if src.remaining() < len {
+        return Err(Error::Incomplete {
+            detail: format!("expected {len} bytes, buflen < {len}"),
+        });
+    }
+    let start = src.position() as usize;
+    src.advance(len);
+    Ok(&src.get_ref()[start..start + len])
+}
+
+fn get_string(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<String, Error> {
+    let bytes = get_bytes(src, limits)?;
+    if limits.strict {
+        frame::check_strict_string(bytes)?;
+    }
+    String::from_utf8(bytes.to_vec()).map_err(|err| Error::UnexpectedBytes {
+        detail: format!("Invalid UTF8: {err}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_decode_nested_frames() {
+        let frame = Frame::Array(vec![
+            Frame::String("CTCT_RESP".to_owned()),
+            Frame::UInt(42),
+            Frame::Int(-42),
+            Frame::Bool(true),
+            Frame::Double(-0.125),
+            Frame::Null,
+            Frame::Bulk(Bytes::from_static(b"\r\n")),
+            Frame::Array(vec![Frame::Error("oops".to_owned())]),
+            Frame::Map(vec![("rtt".to_owned(), Frame::UInt(12))]),
+        ]);
+        let mut buf = BytesMut::new();
+        write(&frame, &mut buf, &Limits::default()).unwrap();
+        let mut src = Cursor::new(&buf[..]);
+        let decoded = parse(&mut src, &Limits::default()).unwrap();
+        assert_eq!(src.position() as usize, buf.len());
+        assert_eq!(format!("{decoded:?}"), format!("{frame:?}"));
+    }
+
+    #[test]
+    fn should_wait_for_incomplete_frames() {
+        let frame = Frame::Array(vec![Frame::String("HBT_REQ".to_owned()), Frame::Int(1)]);
+        let mut buf = BytesMut::new();
+        write(&frame, &mut buf, &Limits::default()).unwrap();
+        for len in 0..buf.len() {
+            let mut src = Cursor::new(&buf[..len]);
+            assert!(matches!(
+                parse(&mut src, &Limits::default()),
+                Err(Error::Incomplete { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn should_reject_declared_lengths_beyond_limits() {
+        let limits = Limits {
+            max_array_len: 2,
+            max_buffer_size: 16,
+            ..Limits::default()
+        };
+        let mut src = Cursor::new(&b"*\0\0\0\x03"[..]);
+        assert!(matches!(
+            parse(&mut src, &limits),
+            Err(Error::LimitExceeded { .. })
+        ));
+        let mut src = Cursor::new(&b"+\0\0\x01\0"[..]);
+        assert!(matches!(
+            parse(&mut src, &limits),
+            Err(Error::LimitExceeded { .. })
+        ));
+    }
+}
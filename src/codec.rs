@@ -1,14 +1,592 @@
 //! Frame Codec
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
 use std::io::Cursor;
+use std::sync::{Arc, Mutex};
 use tokio_util::codec::{Decoder, Encoder};
 
+use crate::binary;
+use crate::document::{self, Encoding};
+use crate::frame::{self, CheckState, IntEncoding, Key, Limits};
+use crate::message::Registry;
 use crate::Frame;
 
+/// Framing used on the wire.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Text framing, understood by all nodes.
+    #[default]
+    Text,
+    /// Binary framing, announced by a preamble.
+    Binary,
+    /// CBOR documents, announced by a preamble.
+    Cbor,
+    /// MessagePack documents, announced by a preamble.
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl Format {
+    /// Preamble sent before the first frame, if the framing has one.
+    pub fn preamble(&self) -> Option<&'static [u8; 4]> {
+        match self {
+            Format::Text => None,
+            Format::Binary => Some(binary::PREAMBLE),
+            Format::Cbor => Some(document::CBOR_PREAMBLE),
+            Format::MessagePack => Some(document::MSGPACK_PREAMBLE),
+        }
+    }
+
+    /// Name used in the negotiation messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Text => "text",
+            Format::Binary => "binary",
+            Format::Cbor => "cbor",
+            Format::MessagePack => "msgpack",
+        }
+    }
+
+    /// The framing with the given name, if known.
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "binary" => Some(Format::Binary),
+            "cbor" => Some(Format::Cbor),
+            "msgpack" => Some(Format::MessagePack),
+            _ => None,
+        }
+    }
+
+    // The framing announced by the given preamble.
+    fn from_preamble(preamble: &[u8]) -> Option<Format> {
+        [Format::Binary, Format::Cbor, Format::MessagePack]
+            .into_iter()
+            .find(|format| format.preamble().map(|p| &p[..]) == Some(preamble))
+    }
+
+    // Serialization of the documents, for the document framings.
+    fn encoding(&self) -> Option<Encoding> {
+        match self {
+            Format::Cbor => Some(Encoding::Cbor),
+            Format::MessagePack => Some(Encoding::MessagePack),
+            _ => None,
+        }
+    }
+}
+
+/// Type of a compressed frame, in both framings. It is followed by the
+/// algorithm, the decompressed and compressed lengths (u32, big endian), and
+/// the compressed bytes of a frame in the framing of the connection.
+const COMPRESSED: u8 = b'~';
+const COMPRESSED_HEADER_LEN: usize = 10;
+
+/// Type of a checksummed frame, in both framings. It is followed by the
+/// length of the frame (u32, big endian), the frame, possibly compressed, and
+/// its CRC32 (u32, big endian).
+const CHECKSUMMED: u8 = b'%';
+const CHECKSUMMED_HEADER_LEN: usize = 5;
+const CHECKSUM_LEN: usize = 4;
+
+/// Compression of the frames, negotiated during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Zstandard, for the best ratio.
+    Zstd,
+    /// LZ4, for the lowest CPU cost.
+    Lz4,
+}
+
+impl Compression {
+    /// Name used in the handshake messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    /// The compression with the given name, if known.
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "zstd" => Some(Compression::Zstd),
+            "lz4" => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Compression> {
+        match id {
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    fn compress(&self, src: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::Zstd => Ok(zstd::bulk::compress(src, 0)?),
+            Compression::Lz4 => Ok(lz4_flex::block::compress(src)),
+        }
+    }
+
+    // The declared length is checked against the limits before decompressing.
+    fn decompress(&self, src: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        let invalid = |err: &dyn fmt::Display| Error::UnexpectedBytes {
+            detail: format!("Invalid {} compressed frame: {err}", self.name()),
+        };
+        let bytes = match self {
+            Compression::Zstd => zstd::bulk::decompress(src, len).map_err(|err| invalid(&err))?,
+            Compression::Lz4 => {
+                lz4_flex::block::decompress(src, len).map_err(|err| invalid(&err))?
+            }
+        };
+        if bytes.len() != len {
+            return Err(invalid(&"length mismatch"));
+        }
+        Ok(bytes)
+    }
+}
+
+/// Switches on the compression of the frames a codec sends. The peer keeps
+/// a handle, as the codec is out of its reach once the connection is split.
+#[derive(Debug, Clone, Default)]
+pub struct Compressor {
+    // The compression, and the size above which frames are compressed.
+    state: Arc<Mutex<Option<(Compression, usize)>>>,
+}
+
+impl Compressor {
+    /// Compress the frames larger than `threshold` bytes from now on.
+    pub fn enable(&self, compression: Compression, threshold: usize) {
+        *self.state.lock().expect("compressor lock") = Some((compression, threshold));
+    }
+
+    /// The compression in use, if any.
+    pub fn compression(&self) -> Option<Compression> {
+        self.get().map(|(compression, _)| compression)
+    }
+
+    fn get(&self) -> Option<(Compression, usize)> {
+        *self.state.lock().expect("compressor lock")
+    }
+}
+
+/// Switches the framing and the integer encoding of the frames a codec
+/// sends, once agreed with the remote. Like the compressor, the peer keeps a
+/// handle. The new framing is announced by its preamble, so only a codec
+/// sending text frames can switch to another framing.
+#[derive(Debug, Clone, Default)]
+pub struct Switcher {
+    // The framing and integer encoding to switch to, before the next frame.
+    state: Arc<Mutex<Option<(Format, IntEncoding)>>>,
+}
+
+impl Switcher {
+    /// Send the next frames in the given framing, with the given integer
+    /// encoding.
+    pub fn switch(&self, format: Format, integers: IntEncoding) {
+        *self.state.lock().expect("switcher lock") = Some((format, integers));
+    }
+
+    fn take(&self) -> Option<(Format, IntEncoding)> {
+        self.state.lock().expect("switcher lock").take()
+    }
+}
+
+/// Size ceilings of the messages received, by tag. A frame whose message is
+/// over its ceiling is skipped before it is decoded, and handed over to the
+/// handler given with `on_drop`, with its size.
+#[derive(Clone, Default)]
+pub struct Ceilings {
+    sizes: Arc<HashMap<String, usize>>,
+    registry: Arc<Registry>,
+    on_drop: Option<OnDrop>,
+}
+
+// Handler of the frames skipped, given their tag and size.
+type OnDrop = Arc<dyn Fn(&str, usize) + Send + Sync>;
+
+impl Ceilings {
+    /// Ceilings (bytes) by message tag.
+    pub fn new(sizes: Arc<HashMap<String, usize>>) -> Ceilings {
+        Ceilings {
+            sizes,
+            registry: Arc::default(),
+            on_drop: None,
+        }
+    }
+
+    /// Tell the messages of the application by their id with `registry`.
+    pub fn registry(mut self, registry: Arc<Registry>) -> Ceilings {
+        self.registry = registry;
+        self
+    }
+
+    /// Call `on_drop` with the tag and the size of each frame skipped.
+    pub fn on_drop(mut self, on_drop: impl Fn(&str, usize) + Send + Sync + 'static) -> Ceilings {
+        self.on_drop = Some(Arc::new(on_drop));
+        self
+    }
+
+    // The tag of the message, if its size is over its ceiling.
+    fn exceeded(&self, key: Key, size: usize) -> Option<String> {
+        if self.sizes.is_empty() {
+            return None;
+        }
+        let tag = match key {
+            Key::Tag(tag) => tag.to_owned(),
+            Key::Id(id) => self.registry.tag(id)?,
+        };
+        let max_size = self.sizes.get(&tag)?;
+        (size > *max_size).then_some(tag)
+    }
+
+    fn dropped(&self, tag: &str, size: usize) {
+        if let Some(on_drop) = self.on_drop.as_ref() {
+            on_drop(tag, size);
+        }
+    }
+}
+
+impl fmt::Debug for Ceilings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ceilings")
+            .field("sizes", &self.sizes)
+            .finish()
+    }
+}
+
+/// Codec options, agreed on right after the handshake when the wire is set
+/// to negotiate them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CodecOptions {
+    /// Framing of the frames sent.
+    pub format: Format,
+    /// Compression of the frames sent.
+    pub compression: Option<Compression>,
+    /// Encoding of the integers sent.
+    pub integers: IntEncoding,
+}
+
+/// Configuration of the wire protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Wire {
+    /// Framing used for outgoing connections.
+    #[serde(default)]
+    pub format: Format,
+    /// Compatibility mode: incoming connections are served in the framing
+    /// the remote speaks, detected from its first bytes. Otherwise, only
+    /// the configured framing is accepted.
+    #[serde(default = "default_compatibility")]
+    pub compatibility: bool,
+    /// Compression offered to, and accepted from, remote nodes. Frames are
+    /// only compressed when both ends agree on it.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Frames smaller than this (bytes) are sent uncompressed.
+    #[serde(default = "default_compression_threshold")]
+    pub compression_threshold: usize,
+    /// Append a CRC32 checksum to the frames sent. Checksummed frames are
+    /// always accepted, whatever this setting.
+    #[serde(default)]
+    pub checksum: bool,
+    /// Encoding of the integers sent.
+    #[serde(default)]
+    pub integers: IntEncoding,
+    /// Frames larger than this (bytes) are split into chunks of this size,
+    /// sent in between other messages. Remote nodes must understand the
+    /// `CHUNK` message.
+    #[serde(default)]
+    pub chunk_size: Option<usize>,
+    /// What to do with messages we don't know, sent by newer nodes.
+    #[serde(default)]
+    pub unknown_messages: UnknownMessages,
+    /// Start the messages sent with their numeric id rather than their tag.
+    /// Remote nodes must understand numeric ids.
+    #[serde(default)]
+    pub numeric_ids: bool,
+    /// Agree on the framing, the compression and the integer encoding with
+    /// the remote right after the handshake, rather than upfront. Outgoing
+    /// connections start in text, with fixed integers and no compression,
+    /// which every node understands, then switch to the options the remote
+    /// accepts. Remote nodes must understand the `CODEC_OFFER` message.
+    #[serde(default)]
+    pub negotiate: bool,
+}
+
+/// What a peer does with a message it doesn't know. The connection is kept
+/// in all cases, so that newer nodes can talk to older ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownMessages {
+    /// Drop the message.
+    Ignore,
+    /// Drop the message, and log its tag.
+    #[default]
+    Log,
+    /// Hand the message over to the application, as
+    /// `NetworkEvent::UnknownMessage`.
+    Notify,
+}
+
+impl Default for Wire {
+    fn default() -> Self {
+        Wire {
+            format: Format::default(),
+            compatibility: default_compatibility(),
+            compression: None,
+            compression_threshold: default_compression_threshold(),
+            checksum: false,
+            integers: IntEncoding::default(),
+            chunk_size: None,
+            unknown_messages: UnknownMessages::default(),
+            numeric_ids: false,
+            negotiate: false,
+        }
+    }
+}
+
+impl Wire {
+    /// Codec options offered to the remote, when negotiating them.
+    pub fn offer(&self) -> CodecOptions {
+        CodecOptions {
+            format: self.format,
+            compression: self.compression,
+            integers: self.integers,
+        }
+    }
+
+    /// Codec options accepted from an offer: the framing, if we serve it
+    /// (text otherwise), the compression, if it is ours, and the integer
+    /// encoding, since varints are always decoded.
+    pub fn agree(&self, offer: CodecOptions) -> CodecOptions {
+        let format = match self.compatibility || offer.format == self.format {
+            true => offer.format,
+            false => Format::Text,
+        };
+        CodecOptions {
+            format,
+            compression: offer.compression.filter(|c| self.compression == Some(*c)),
+            integers: offer.integers,
+        }
+    }
+}
+
+fn default_compatibility() -> bool {
+    true
+}
+
+fn default_compression_threshold() -> usize {
+    1024
+}
+
 /// codec
 #[derive(Debug)]
-pub struct FrameCodec;
+pub struct FrameCodec {
+    /// Bounds applied to decoded frames
+    limits: Limits,
+    /// Progress of the validation of the frame being received
+    state: CheckState,
+    /// Framing of the connection. None until detected from the remote's first bytes.
+    format: Option<Format>,
+    /// Framing of the frames sent, once switched away from the framing of the connection.
+    sending: Option<Format>,
+    /// Switches the framing and the integer encoding of the frames sent.
+    switcher: Switcher,
+    /// The preamble must be sent before the first frame.
+    send_preamble: bool,
+    /// The preamble must be received before the first frame.
+    expect_preamble: bool,
+    /// Compression of the frames sent. Compressed frames are always accepted.
+    compressor: Compressor,
+    /// A checksum is appended to the frames sent.
+    checksum: bool,
+    /// Encoding of the integers sent.
+    integers: IntEncoding,
+    /// Buffers reused to write frames before compressing or checksumming them.
+    pool: Vec<BytesMut>,
+    /// Size ceilings of the messages received.
+    ceilings: Ceilings,
+    /// Bytes of a skipped frame still to be received, and discarded.
+    discard: usize,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        FrameCodec::new(Limits::default())
+    }
+}
+
+impl FrameCodec {
+    /// Creates a codec decoding frames within the given limits
+    pub fn new(limits: Limits) -> FrameCodec {
+        FrameCodec {
+            limits,
+            state: CheckState::default(),
+            format: Some(Format::Text),
+            sending: None,
+            switcher: Switcher::default(),
+            send_preamble: false,
+            expect_preamble: false,
+            compressor: Compressor::default(),
+            checksum: false,
+            integers: IntEncoding::default(),
+            pool: Vec::new(),
+            ceilings: Ceilings::default(),
+            discard: 0,
+        }
+    }
+
+    /// Creates a codec for a connection we initiate, using the given framing.
+    pub fn connecting(limits: Limits, format: Format) -> FrameCodec {
+        FrameCodec {
+            format: Some(format),
+            send_preamble: format.preamble().is_some(),
+            ..FrameCodec::new(limits)
+        }
+    }
+
+    /// Creates a codec for a connection initiated by the remote, which only
+    /// accepts the given framing.
+    pub fn accepting(limits: Limits, format: Format) -> FrameCodec {
+        FrameCodec {
+            format: Some(format),
+            expect_preamble: format.preamble().is_some(),
+            ..FrameCodec::new(limits)
+        }
+    }
+
+    /// Creates a codec for a connection initiated by the remote, which
+    /// detects the framing from the remote's first bytes.
+    pub fn detecting(limits: Limits) -> FrameCodec {
+        FrameCodec {
+            format: None,
+            ..FrameCodec::new(limits)
+        }
+    }
+
+    /// Creates a codec for a connection initiated by the remote, according
+    /// to the wire configuration.
+    pub fn incoming(limits: Limits, wire: &Wire) -> FrameCodec {
+        if wire.compatibility {
+            FrameCodec::detecting(limits)
+        } else {
+            FrameCodec::accepting(limits, wire.format)
+        }
+    }
+
+    /// Append a checksum to the frames sent.
+    pub fn with_checksum(mut self, checksum: bool) -> FrameCodec {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Encode the integers sent with the given encoding.
+    pub fn with_integers(mut self, integers: IntEncoding) -> FrameCodec {
+        self.integers = integers;
+        self
+    }
+
+    /// Skip the frames received whose message is over its ceiling.
+    pub fn with_ceilings(mut self, ceilings: Ceilings) -> FrameCodec {
+        self.ceilings = ceilings;
+        self
+    }
+
+    /// Number of bytes of the frame in the framing it is sent in (the text
+    /// framing until it is known), before compression and checksum.
+    pub fn encoded_len(&self, frame: &Frame) -> usize {
+        match self.sending() {
+            Some(Format::Binary) => binary::encoded_len(frame, self.integers),
+            Some(format @ (Format::Cbor | Format::MessagePack)) => {
+                document::encoded_len(frame, format.encoding().expect("document framing"))
+            }
+            _ => frame.encoded_len_with(self.integers),
+        }
+    }
+
+    /// Framing of the connection, if known.
+    pub fn format(&self) -> Option<Format> {
+        self.format
+    }
+
+    /// Handle to switch on the compression of the frames sent.
+    pub fn compressor(&self) -> Compressor {
+        self.compressor.clone()
+    }
+
+    /// Handle to switch the framing and the integer encoding of the frames sent.
+    pub fn switcher(&self) -> Switcher {
+        self.switcher.clone()
+    }
+
+    // Framing of the frames sent.
+    fn sending(&self) -> Option<Format> {
+        self.sending.or(self.format)
+    }
+
+    // Applies the switch asked for since the last frame, if any.
+    fn apply_switch(&mut self) {
+        let Some((format, integers)) = self.switcher.take() else {
+            return;
+        };
+        self.integers = integers;
+        if self.sending() == Some(Format::Text) && format != Format::Text {
+            self.sending = Some(format);
+            self.send_preamble = true;
+        }
+    }
+
+    // Detects the framing, and consumes the preamble, if any.
+    // Returns false if more bytes are needed.
+    fn read_preamble(&mut self, src: &mut BytesMut) -> Result<bool, Error> {
+        if self.format.is_none() {
+            // All preambles start with a NUL byte, and differ by their last byte.
+            if src[0] != binary::PREAMBLE[0] {
+                self.format = Some(Format::Text);
+            } else if src.len() < binary::PREAMBLE.len() {
+                return Ok(false);
+            } else {
+                let format =
+                    Format::from_preamble(&src[..binary::PREAMBLE.len()]).ok_or_else(|| {
+                        Error::UnexpectedBytes {
+                            detail: "Unknown framing preamble".to_owned(),
+                        }
+                    })?;
+                self.format = Some(format);
+                self.expect_preamble = true;
+            }
+        }
+        let preamble = match self.format.and_then(|format| format.preamble()) {
+            Some(preamble) if self.expect_preamble => preamble,
+            _ => return Ok(true),
+        };
+        let len = src.len().min(preamble.len());
+        if src[..len] != preamble[..len] {
+            return Err(Error::UnexpectedBytes {
+                detail: "Invalid framing preamble".to_owned(),
+            });
+        }
+        if len < preamble.len() {
+            return Ok(false);
+        }
+        src.advance(preamble.len());
+        self.expect_preamble = false;
+        Ok(true)
+    }
+}
 
 /// Error type for the codec
 #[derive(Debug)]
@@ -35,6 +613,16 @@ pub enum Error {
         /// Error source
         source: std::io::Error,
     },
+    /// Too many bytes received without a complete frame
+    BufferFull {
+        /// Error detail
+        detail: String,
+    },
+    /// The frame does not match its checksum: it was corrupted on the way.
+    ChecksumMismatch {
+        /// Error detail
+        detail: String,
+    },
 }
 
 impl Decoder for FrameCodec {
@@ -45,29 +633,319 @@ impl Decoder for FrameCodec {
         &mut self,
         src: &mut BytesMut,
     ) -> std::result::Result<Option<Self::Item>, Self::Error> {
-        let mut buf = Cursor::new(&src[..]);
-        if !buf.has_remaining() {
-            Ok(None)
-        } else {
-            match Frame::check(&mut buf) {
-                Ok(_) => {
-                    let len = buf.position() as usize;
-                    buf.set_position(0);
-                    let frame = Frame::parse(&mut buf)?;
-                    src.advance(len);
-                    Ok(Some(frame))
+        loop {
+            let discarded = self.discard.min(src.len());
+            src.advance(discarded);
+            self.discard -= discarded;
+            if !src.has_remaining() {
+                return Ok(None);
+            }
+            if !self.read_preamble(src)? || !src.has_remaining() {
+                return Ok(None);
+            }
+            // Text frames never start with a NUL byte: the remote switched to
+            // another framing, announced by its preamble. We keep sending in ours.
+            if self.format == Some(Format::Text) && src[0] == binary::PREAMBLE[0] {
+                self.sending = self.sending();
+                self.format = None;
+                if !self.read_preamble(src)? || !src.has_remaining() {
+                    return Ok(None);
+                }
+            }
+            let len = src.len();
+            let frame = match src[0] {
+                CHECKSUMMED => self.decode_checksummed(src)?,
+                _ => self.decode_frame(src)?,
+            };
+            // A frame over its ceiling was skipped: the next one may follow.
+            if frame.is_some() || (src.len() == len && self.discard == 0) {
+                return Ok(frame);
+            }
+        }
+    }
+}
+
+impl FrameCodec {
+    // Returns the next frame, possibly compressed, if complete. A frame over
+    // its ceiling is skipped, without being decoded.
+    fn decode_frame(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        if src[0] == COMPRESSED {
+            return self.decode_compressed(src);
+        }
+        let len = match self.frame_len(src) {
+            Ok(len) => len,
+            Err(frame::Error::Incomplete { .. }) => return self.wait(src),
+            Err(err) => return Err(err.into()),
+        };
+        if let Some(tag) = self.oversized(src, len) {
+            self.ceilings.dropped(&tag, len);
+            let skipped = len.min(src.len());
+            src.advance(skipped);
+            self.discard = len - skipped;
+            return Ok(None);
+        }
+        // Documents declare their length before they are whole.
+        if src.len() < len {
+            return self.wait(src);
+        }
+        let mut buf = Cursor::new(&src[..len]);
+        let frame = match self.format {
+            Some(Format::Binary) => binary::parse(&mut buf, &self.limits)?,
+            Some(format @ (Format::Cbor | Format::MessagePack)) => {
+                let encoding = format.encoding().expect("document framing");
+                document::parse(&mut buf, &self.limits, encoding)?
+            }
+            _ => Frame::parse_with_limits(&mut buf, &self.limits)?,
+        };
+        src.advance(len);
+        Ok(Some(frame))
+    }
+
+    // Length of the frame at the start of 'src', found without decoding it.
+    fn frame_len(&mut self, src: &BytesMut) -> Result<usize, frame::Error> {
+        match self.format {
+            // Lengths are declared upfront, so there is no need to keep track
+            // of the validation progress.
+            Some(Format::Binary) => binary::frame_len(&src[..], &self.limits),
+            Some(Format::Cbor | Format::MessagePack) => {
+                document::declared_len(&src[..], &self.limits)
+            }
+            _ => match Frame::check_incremental(&src[..], &self.limits, &mut self.state) {
+                Err(err @ frame::Error::Incomplete { .. }) => Err(err),
+                res => {
+                    self.state = CheckState::default();
+                    res
                 }
-                Err(err) => Err(err.into()),
+            },
+        }
+    }
+
+    // The tag of the message at the start of 'bytes', in the framing of the
+    // connection, if a frame of 'size' bytes is over its ceiling.
+    fn oversized(&self, bytes: &[u8], size: usize) -> Option<String> {
+        let key = match self.format {
+            Some(Format::Binary) => binary::peek_key(bytes),
+            Some(format @ (Format::Cbor | Format::MessagePack)) => {
+                document::peek_key(bytes, format.encoding().expect("document framing"))
+            }
+            _ => Frame::peek_key(bytes),
+        }?;
+        self.ceilings.exceeded(key, size)
+    }
+
+    // We wait for more bytes, but not forever: a remote that never completes
+    // its frame must not make the buffer grow indefinitely.
+    fn wait(&self, src: &BytesMut) -> Result<Option<Frame>, Error> {
+        if src.len() > self.limits.max_buffer_size {
+            return Err(Error::BufferFull {
+                detail: format!(
+                    "{} bytes received without a complete frame (max {})",
+                    src.len(),
+                    self.limits.max_buffer_size
+                ),
+            });
+        }
+        Ok(None)
+    }
+
+    // Returns the next checksummed frame, if complete.
+    fn decode_checksummed(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        if src.len() < CHECKSUMMED_HEADER_LEN {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([src[1], src[2], src[3], src[4]]) as usize;
+        if len > self.limits.max_buffer_size {
+            return Err(frame::Error::LimitExceeded {
+                detail: format!(
+                    "Checksummed frame of {len} bytes (max {})",
+                    self.limits.max_buffer_size
+                ),
+            }
+            .into());
+        }
+        let end = CHECKSUMMED_HEADER_LEN + len;
+        // A frame over its ceiling is skipped as it comes, without checking it.
+        let inner = &src[CHECKSUMMED_HEADER_LEN..src.len().min(end)];
+        if inner.first() != Some(&COMPRESSED) {
+            if let Some(tag) = self.oversized(inner, len) {
+                self.ceilings.dropped(&tag, len);
+                let skipped = src.len().min(end + CHECKSUM_LEN);
+                src.advance(skipped);
+                self.discard = end + CHECKSUM_LEN - skipped;
+                return Ok(None);
+            }
+        }
+        if src.len() < end + CHECKSUM_LEN {
+            return Ok(None);
+        }
+        let expected = u32::from_be_bytes([src[end], src[end + 1], src[end + 2], src[end + 3]]);
+        let actual = crc32fast::hash(&src[CHECKSUMMED_HEADER_LEN..end]);
+        if actual != expected {
+            return Err(Error::ChecksumMismatch {
+                detail: format!("Expected {expected:08x}, computed {actual:08x} over {len} bytes"),
+            });
+        }
+        let mut inner = BytesMut::from(&src[CHECKSUMMED_HEADER_LEN..end]);
+        let frame = match inner.first() {
+            Some(&CHECKSUMMED) | None => None,
+            Some(_) => self.decode_frame(&mut inner)?,
+        };
+        self.state = CheckState::default();
+        match frame {
+            Some(frame) if inner.is_empty() => {
+                src.advance(end + CHECKSUM_LEN);
+                Ok(Some(frame))
             }
+            // The compressed frame was over its ceiling.
+            None if inner.is_empty() && len > 0 => {
+                src.advance(end + CHECKSUM_LEN);
+                Ok(None)
+            }
+            _ => Err(Error::UnexpectedBytes {
+                detail: "Checksummed frame does not hold a single frame".to_owned(),
+            }),
+        }
+    }
+
+    // Returns the next compressed frame, if complete.
+    fn decode_compressed(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        if src.len() < COMPRESSED_HEADER_LEN {
+            return Ok(None);
+        }
+        let compression = Compression::from_id(src[1]).ok_or_else(|| Error::UnexpectedBytes {
+            detail: format!("Unknown compression {}", src[1]),
+        })?;
+        let len = u32::from_be_bytes([src[2], src[3], src[4], src[5]]) as usize;
+        let compressed_len = u32::from_be_bytes([src[6], src[7], src[8], src[9]]) as usize;
+        let max = self.limits.max_buffer_size;
+        if len > max || compressed_len > max {
+            return Err(frame::Error::LimitExceeded {
+                detail: format!(
+                    "Compressed frame of {compressed_len} bytes, {len} bytes decompressed (max {max})"
+                ),
+            }
+            .into());
+        }
+        if src.len() < COMPRESSED_HEADER_LEN + compressed_len {
+            return Ok(None);
+        }
+        let bytes = compression.decompress(
+            &src[COMPRESSED_HEADER_LEN..COMPRESSED_HEADER_LEN + compressed_len],
+            len,
+        )?;
+        if let Some(tag) = self.oversized(&bytes, len) {
+            self.ceilings.dropped(&tag, len);
+            src.advance(COMPRESSED_HEADER_LEN + compressed_len);
+            return Ok(None);
+        }
+        let mut buf = Cursor::new(&bytes[..]);
+        let frame = match self.format {
+            Some(Format::Binary) => binary::parse(&mut buf, &self.limits)?,
+            Some(format @ (Format::Cbor | Format::MessagePack)) => {
+                let encoding = format.encoding().expect("document framing");
+                document::parse(&mut buf, &self.limits, encoding)?
+            }
+            _ => {
+                Frame::check_with_limits(&mut buf, &self.limits)?;
+                buf.set_position(0);
+                Frame::parse_with_limits(&mut buf, &self.limits)?
+            }
+        };
+        if buf.position() as usize != bytes.len() {
+            return Err(Error::UnexpectedBytes {
+                detail: "Trailing bytes in compressed frame".to_owned(),
+            });
+        }
+        src.advance(COMPRESSED_HEADER_LEN + compressed_len);
+        Ok(Some(frame))
+    }
+
+    // A buffer from the pool, or a new one if they are all in use.
+    fn take_buffer(&mut self) -> BytesMut {
+        self.pool.pop().unwrap_or_default()
+    }
+
+    // Give a buffer back to the pool, keeping its capacity for the next frames.
+    fn give_buffer(&mut self, mut buf: BytesMut) {
+        buf.clear();
+        self.pool.push(buf);
+    }
+
+    // Writes the frame in the framing of the connection, compressed if it is large enough.
+    fn write_frame(&mut self, frame: &Frame, dst: &mut BytesMut) -> Result<(), Error> {
+        let (limits, integers, format) = (self.limits, self.integers, self.sending());
+        let write = |dst: &mut BytesMut| match format {
+            Some(Format::Binary) => binary::write_with_encoding(frame, dst, &limits, integers),
+            // Documents encode integers in their own way.
+            Some(format @ (Format::Cbor | Format::MessagePack)) => {
+                let encoding = format.encoding().expect("document framing");
+                document::write(frame, dst, &limits, encoding)
+            }
+            _ => frame.write_with_encoding(dst, &limits, integers),
+        };
+        match self.compressor.get() {
+            Some((compression, threshold)) if self.encoded_len(frame) > threshold => {
+                let mut plain = self.pool.pop().unwrap_or_default();
+                let res = write(&mut plain)
+                    .map_err(Error::from)
+                    .and_then(|_| compression.compress(&plain));
+                let len = plain.len();
+                self.give_buffer(plain);
+                let compressed = res?;
+                let len = u32::try_from(len).map_err(frame::Error::from)?;
+                let compressed_len = u32::try_from(compressed.len()).map_err(frame::Error::from)?;
+                dst.reserve(COMPRESSED_HEADER_LEN + compressed.len());
+                dst.put_u8(COMPRESSED);
+                dst.put_u8(compression.id());
+                dst.put_u32(len);
+                dst.put_u32(compressed_len);
+                dst.extend_from_slice(&compressed);
+                Ok(())
+            }
+            _ => Ok(write(dst)?),
         }
     }
+
+    // Writes the frame followed by its checksum.
+    fn write_checksummed(&mut self, frame: &Frame, dst: &mut BytesMut) -> Result<(), Error> {
+        let mut inner = self.take_buffer();
+        let res = self.write_frame(frame, &mut inner).and_then(|_| {
+            let len = u32::try_from(inner.len()).map_err(frame::Error::from)?;
+            dst.reserve(CHECKSUMMED_HEADER_LEN + inner.len() + CHECKSUM_LEN);
+            dst.put_u8(CHECKSUMMED);
+            dst.put_u32(len);
+            dst.extend_from_slice(&inner);
+            dst.put_u32(crc32fast::hash(&inner));
+            Ok(())
+        });
+        self.give_buffer(inner);
+        res
+    }
 }
 
 impl Encoder<Frame> for FrameCodec {
     type Error = Error;
 
     fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        frame.write(dst)?;
+        // An invalid frame can be detected halfway through writing it, so we
+        // remove what was written, rather than leaving a partial frame in the buffer.
+        let len = dst.len();
+        self.apply_switch();
+        if self.send_preamble {
+            if let Some(preamble) = self.sending().and_then(|format| format.preamble()) {
+                dst.extend_from_slice(preamble);
+            }
+        }
+        let res = if self.checksum {
+            self.write_checksummed(&frame, dst)
+        } else {
+            self.write_frame(&frame, dst)
+        };
+        if let Err(err) = res {
+            dst.truncate(len);
+            return Err(err);
+        }
+        self.send_preamble = false;
         Ok(())
     }
 }
@@ -81,6 +959,8 @@ impl fmt::Display for Error {
             }
             Error::IoError { source } => write!(f, "Frame IO Error: {}", source),
             Error::UnexpectedBytes { detail } => write!(f, "Invalid Frame Content: {}", detail),
+            Error::BufferFull { detail } => write!(f, "Receive Buffer Full: {}", detail),
+            Error::ChecksumMismatch { detail } => write!(f, "Frame Checksum Mismatch: {}", detail),
         }
     }
 }
@@ -102,10 +982,322 @@ impl From<crate::frame::Error> for Error {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     #[tokio::test]
     async fn decoder_on_simple_frames() {}
 
     #[tokio::test]
     async fn decoder_on_array_frame() {}
+
+    #[test]
+    fn decoder_waits_for_incomplete_frames() {
+        let mut codec = FrameCodec::default();
+        let mut src = BytesMut::from(&b"*2\r\n:1\r\n"[..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(b":2\r\n");
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(Frame::Array(frames)) if frames.len() == 2
+        ));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decoder_resumes_validation_across_frames() {
+        let mut codec = FrameCodec::default();
+        let mut src = BytesMut::from(&b"*2\r\n+HBT_"[..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(b"REQ\r\n@42\r\n+next");
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(Frame::Array(frames)) if frames.len() == 2
+        ));
+        assert_eq!(&src[..], b"+next");
+        src.extend_from_slice(b"\r\n");
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(Frame::String(s)) if s == "next"
+        ));
+    }
+
+    #[test]
+    fn decoder_rejects_unterminated_frames_beyond_buffer_limit() {
+        let mut codec = FrameCodec::new(Limits {
+            max_buffer_size: 16,
+            ..Limits::default()
+        });
+        let mut src = BytesMut::from(&b"+0123456789"[..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(b"0123456789");
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(Error::BufferFull { .. })
+        ));
+    }
+
+    #[test]
+    fn decoder_detects_the_framing_of_the_remote() {
+        let frame = Frame::Array(vec![Frame::String("CTCT_REQ".to_owned())]);
+
+        let mut src = BytesMut::new();
+        FrameCodec::connecting(Limits::default(), Format::Binary)
+            .encode(frame.clone(), &mut src)
+            .unwrap();
+        assert!(src.starts_with(binary::PREAMBLE));
+        let mut codec = FrameCodec::detecting(Limits::default());
+        // The preamble arrives in two parts.
+        let mut partial = src.split_to(2);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(src);
+        assert!(matches!(
+            codec.decode(&mut partial).unwrap(),
+            Some(Frame::Array(frames)) if frames.len() == 1
+        ));
+        assert_eq!(codec.format(), Some(Format::Binary));
+
+        let mut src = BytesMut::new();
+        FrameCodec::connecting(Limits::default(), Format::Text)
+            .encode(frame, &mut src)
+            .unwrap();
+        let mut codec = FrameCodec::detecting(Limits::default());
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert_eq!(codec.format(), Some(Format::Text));
+    }
+
+    #[test]
+    fn codec_exchanges_documents() {
+        let mut frame = Frame::map();
+        frame
+            .insert("type".to_owned(), Frame::String("HBT_REQ".to_owned()))
+            .unwrap();
+        frame.insert("seq".to_owned(), Frame::Int(-42)).unwrap();
+        frame.insert("ts".to_owned(), Frame::UInt(1 << 40)).unwrap();
+        frame.insert("alive".to_owned(), Frame::Bool(true)).unwrap();
+        frame
+            .insert("body".to_owned(), Frame::Bulk("hello".into()))
+            .unwrap();
+        frame.insert("none".to_owned(), Frame::Null).unwrap();
+        let expected = format!("{:?}", frame);
+        for format in [Format::Cbor, Format::MessagePack] {
+            let mut src = BytesMut::new();
+            FrameCodec::connecting(Limits::default(), format)
+                .encode(frame.clone(), &mut src)
+                .unwrap();
+            assert!(src.starts_with(format.preamble().unwrap()));
+            let mut codec = FrameCodec::detecting(Limits::default());
+            let mut partial = src.split_to(src.len() - 1);
+            assert!(codec.decode(&mut partial).unwrap().is_none());
+            partial.unsplit(src);
+            let decoded = codec.decode(&mut partial).unwrap().unwrap();
+            assert_eq!(format!("{:?}", decoded), expected);
+            assert_eq!(codec.format(), Some(format));
+        }
+    }
+
+    #[test]
+    fn codec_knows_the_encoded_length_of_frames() {
+        let mut frame = Frame::map();
+        frame
+            .insert("seq".to_owned(), Frame::UInt(1 << 20))
+            .unwrap();
+        frame
+            .insert("data".to_owned(), Frame::Bulk("hello".into()))
+            .unwrap();
+        let frame = Frame::Array(vec![Frame::String("PAYLOAD".to_owned()), frame]);
+        for format in [
+            Format::Text,
+            Format::Binary,
+            Format::Cbor,
+            Format::MessagePack,
+        ] {
+            let mut codec =
+                FrameCodec::accepting(Limits::default(), format).with_integers(IntEncoding::Varint);
+            let mut dst = BytesMut::new();
+            codec.encode(frame.clone(), &mut dst).unwrap();
+            assert_eq!(codec.encoded_len(&frame), dst.len(), "{format:?}");
+        }
+        assert_eq!(
+            frame.encoded_len(),
+            frame.encoded_len_with(IntEncoding::Fixed)
+        );
+    }
+
+    #[test]
+    fn codec_compresses_large_frames() {
+        let large = Frame::Array(vec![
+            Frame::String("PAYLOAD".to_owned()),
+            Frame::String("a".repeat(4096)),
+        ]);
+        let small = Frame::Array(vec![Frame::String("HBT_REQ".to_owned())]);
+        for (format, compression) in [
+            (Format::Text, Compression::Zstd),
+            (Format::Binary, Compression::Lz4),
+        ] {
+            let mut encoder = FrameCodec::connecting(Limits::default(), format);
+            encoder.compressor().enable(compression, 1024);
+            let mut src = BytesMut::new();
+            encoder.encode(large.clone(), &mut src).unwrap();
+            assert!(src.len() < 1024);
+            encoder.encode(small.clone(), &mut src).unwrap();
+            let mut decoder = FrameCodec::detecting(Limits::default());
+            assert!(matches!(
+                decoder.decode(&mut src).unwrap(),
+                Some(Frame::Array(frames)) if matches!(&frames[1], Frame::String(s) if s.len() == 4096)
+            ));
+            assert!(matches!(
+                decoder.decode(&mut src).unwrap(),
+                Some(Frame::Array(frames)) if frames.len() == 1
+            ));
+            assert!(src.is_empty());
+        }
+
+        // The decompressed size is bounded by the buffer limit.
+        let mut encoder = FrameCodec::default();
+        encoder.compressor().enable(Compression::Zstd, 0);
+        let mut src = BytesMut::new();
+        encoder.encode(large, &mut src).unwrap();
+        let mut decoder = FrameCodec::new(Limits {
+            max_buffer_size: 1024,
+            ..Limits::default()
+        });
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(Error::InvalidFrame { .. })
+        ));
+    }
+
+    #[test]
+    fn decoder_detects_corrupted_frames() {
+        let frame = Frame::Array(vec![
+            Frame::String("PAYLOAD".to_owned()),
+            Frame::String("a".repeat(2048)),
+        ]);
+        let mut encoder =
+            FrameCodec::connecting(Limits::default(), Format::Binary).with_checksum(true);
+        encoder.compressor().enable(Compression::Lz4, 1024);
+        let mut src = BytesMut::new();
+        encoder.encode(frame.clone(), &mut src).unwrap();
+        encoder.encode(frame, &mut src).unwrap();
+        // A checksummed frame is accepted by any decoder.
+        let mut decoder = FrameCodec::detecting(Limits::default());
+        assert!(decoder.decode(&mut src).unwrap().is_some());
+
+        let last = src.len() - CHECKSUM_LEN - 1;
+        src[last] ^= 0x01;
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn decoder_skips_messages_over_their_ceiling() {
+        let big = Frame::Array(vec![
+            Frame::String("PAYLOAD".to_owned()),
+            Frame::Bulk(vec![0u8; 4096].into()),
+        ]);
+        let sealed = Frame::Array(vec![big.clone(), Frame::Bulk(vec![0u8; 32].into())]);
+        let small = Frame::Array(vec![Frame::String("HBT_REQ".to_owned()), Frame::UInt(1)]);
+        let sizes = HashMap::from([("PAYLOAD".to_owned(), 1024), ("HBT_REQ".to_owned(), 64)]);
+        for format in [
+            Format::Text,
+            Format::Binary,
+            Format::Cbor,
+            Format::MessagePack,
+        ] {
+            for (checksum, compression) in
+                [(false, None), (true, None), (true, Some(Compression::Lz4))]
+            {
+                let mut encoder =
+                    FrameCodec::connecting(Limits::default(), format).with_checksum(checksum);
+                if let Some(compression) = compression {
+                    encoder.compressor().enable(compression, 1024);
+                }
+                let mut bytes = BytesMut::new();
+                for frame in [&big, &small, &sealed, &small] {
+                    encoder.encode(frame.clone(), &mut bytes).unwrap();
+                }
+                let dropped = Arc::new(Mutex::new(Vec::new()));
+                let ceilings = Ceilings::new(Arc::new(sizes.clone())).on_drop({
+                    let dropped = dropped.clone();
+                    move |tag, _| dropped.lock().unwrap().push(tag.to_owned())
+                });
+                let mut decoder = FrameCodec::detecting(Limits::default()).with_ceilings(ceilings);
+                // The frames arrive in small parts, so that the large ones are
+                // skipped before they are whole.
+                let mut src = BytesMut::new();
+                let mut frames = Vec::new();
+                for part in bytes.chunks(100) {
+                    src.extend_from_slice(part);
+                    while let Some(frame) = decoder.decode(&mut src).unwrap() {
+                        frames.push(frame);
+                    }
+                }
+                assert_eq!(frames.len(), 2, "{format:?} {checksum} {compression:?}");
+                assert!(src.is_empty());
+                assert_eq!(*dropped.lock().unwrap(), vec!["PAYLOAD", "PAYLOAD"]);
+            }
+        }
+    }
+
+    #[test]
+    fn decoder_follows_the_switch_of_the_remote() {
+        let frame = Frame::Array(vec![Frame::String("PING".to_owned()), Frame::UInt(1)]);
+        let mut encoder = FrameCodec::connecting(Limits::default(), Format::Text);
+        let mut src = BytesMut::new();
+        encoder.encode(frame.clone(), &mut src).unwrap();
+        encoder
+            .switcher()
+            .switch(Format::Binary, IntEncoding::Varint);
+        let len = src.len();
+        encoder.encode(frame.clone(), &mut src).unwrap();
+        assert!(src[len..].starts_with(binary::PREAMBLE));
+        encoder.encode(frame, &mut src).unwrap();
+
+        let mut decoder = FrameCodec::accepting(Limits::default(), Format::Text);
+        for _ in 0..3 {
+            assert!(matches!(
+                decoder.decode(&mut src).unwrap(),
+                Some(Frame::Array(frames)) if matches!(frames[1], Frame::UInt(1))
+            ));
+        }
+        assert!(src.is_empty());
+        assert_eq!(decoder.format(), Some(Format::Binary));
+        // It keeps sending text frames until it switches too.
+        decoder
+            .encode(Frame::Array(vec![Frame::UInt(1)]), &mut src)
+            .unwrap();
+        assert_eq!(&src[..], b"*1\r\n:1\r\n");
+
+        // Only the framings we serve are agreed on.
+        let offer = CodecOptions {
+            format: Format::Cbor,
+            compression: Some(Compression::Zstd),
+            integers: IntEncoding::Varint,
+        };
+        let wire = Wire {
+            compatibility: false,
+            compression: Some(Compression::Lz4),
+            ..Wire::default()
+        };
+        assert_eq!(
+            wire.agree(offer),
+            CodecOptions {
+                integers: IntEncoding::Varint,
+                ..CodecOptions::default()
+            }
+        );
+        assert_eq!(Wire::default().agree(offer).format, Format::Cbor);
+    }
+
+    #[test]
+    fn decoder_rejects_unexpected_framing() {
+        let mut codec = FrameCodec::accepting(Limits::default(), Format::Binary);
+        let mut src = BytesMut::from(&b"*1\r\n+CTCT_REQ\r\n"[..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(Error::UnexpectedBytes { .. })
+        ));
+    }
 }
@@ -0,0 +1,265 @@
+//! Document framings
+//!
+//! Frames are serialized as self-describing documents, in CBOR or MessagePack,
+//! so that nodes written in other languages can use an off-the-shelf library
+//! instead of implementing the text or binary framing. Each document is
+//! prefixed by its length (u32, big endian). Like the binary framing, a
+//! connection using a document framing starts with a preamble.
+
+use bytes::{Buf, BufMut, BytesMut};
+use std::io::Cursor;
+
+use crate::frame::{self, Error, Key, Limits};
+use crate::Frame;
+
+/// Sent by the initiator of a connection using the CBOR framing.
+pub const CBOR_PREAMBLE: &[u8; 4] = b"\0AN\x02";
+
+/// Sent by the initiator of a connection using the MessagePack framing.
+pub const MSGPACK_PREAMBLE: &[u8; 4] = b"\0AN\x03";
+
+const LENGTH_LEN: usize = 4;
+
+/// Serialization of the documents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Concise Binary Object Representation (RFC 8949).
+    Cbor,
+    /// MessagePack.
+    MessagePack,
+}
+
+/// Decode a frame from `src`, within the given limits.
+/// Returns `Error::Incomplete` if `src` does not hold a whole document yet.
+pub fn parse(src: &mut Cursor<&[u8]>, limits: &Limits, encoding: Encoding) -> Result<Frame, Error> {
+    if src.remaining() < LENGTH_LEN {
+        return Err(Error::Incomplete {
+            detail: "Missing document length".to_owned(),
+        });
+    }
+    let start = src.position() as usize;
+    let len = src.get_u32() as usize;
+    if len > limits.max_buffer_size {
+        return Err(Error::LimitExceeded {
+            detail: format!(
+                "Document of {} bytes, more than {}",
+                len, limits.max_buffer_size
+            ),
+        });
+    }
+    if src.remaining() < len {
+        src.set_position(start as u64);
+        return Err(Error::Incomplete {
+            detail: format!("Document of {} bytes", len),
+        });
+    }
+    let begin = start + LENGTH_LEN;
+    let doc = &src.get_ref()[begin..begin + len];
+    let frame: Frame = match encoding {
+        Encoding::Cbor => ciborium::de::from_reader(doc).map_err(|err| Error::UnexpectedBytes {
+            detail: format!("Invalid CBOR document: {}", err),
+        })?,
+        Encoding::MessagePack => {
+            rmp_serde::from_slice(doc).map_err(|err| Error::UnexpectedBytes {
+                detail: format!("Invalid MessagePack document: {}", err),
+            })?
+        }
+    };
+    check_limits(&frame, limits, 0)?;
+    src.advance(len);
+    Ok(frame)
+}
+
+/// Number of bytes of the document at the start of `src`, length included, as
+/// it declares. Its bytes may not all be received yet.
+pub fn declared_len(src: &[u8], limits: &Limits) -> Result<usize, Error> {
+    let len = src.get(..LENGTH_LEN).ok_or_else(|| Error::Incomplete {
+        detail: "Missing document length".to_owned(),
+    })?;
+    let len = u32::from_be_bytes(len.try_into().expect("length bytes")) as usize;
+    if len > limits.max_buffer_size {
+        return Err(Error::LimitExceeded {
+            detail: format!(
+                "Document of {} bytes, more than {}",
+                len, limits.max_buffer_size
+            ),
+        });
+    }
+    Ok(LENGTH_LEN + len)
+}
+
+/// Key of the message in the document at the start of `src`, read from the
+/// heads of its first items, without decoding the document. A sealed message
+/// (an array holding the message, then its HMAC) is looked into. Returns None
+/// if `src` does not hold the key yet, or does not hold a message.
+pub fn peek_key(src: &[u8], encoding: Encoding) -> Option<Key<'_>> {
+    let mut pos = LENGTH_LEN;
+    // The message, or the array sealing it.
+    for _ in 0..2 {
+        let (Head::Array, len) = head(src.get(pos..)?, encoding)? else {
+            return None;
+        };
+        pos += len;
+        match head(src.get(pos..)?, encoding)? {
+            (Head::Str(tag_len), len) => {
+                let tag = src.get(pos + len..pos + len + tag_len)?;
+                return std::str::from_utf8(tag).ok().map(Key::Tag);
+            }
+            (Head::UInt(id), _) => return Some(Key::Id(id)),
+            (Head::Array, _) => {}
+            (Head::Other, _) => return None,
+        }
+    }
+    None
+}
+
+// Kind of a CBOR or MessagePack item, as told by its head.
+enum Head {
+    Array,
+    Str(usize),
+    UInt(u64),
+    Other,
+}
+
+// Kind of the item at the start of `src`, and the length of its head.
+fn head(src: &[u8], encoding: Encoding) -> Option<(Head, usize)> {
+    let initial = *src.first()?;
+    let be = |len: usize| -> Option<u64> {
+        let bytes = src.get(1..1 + len)?;
+        Some(
+            bytes
+                .iter()
+                .fold(0, |acc, byte| (acc << 8) | u64::from(*byte)),
+        )
+    };
+    match encoding {
+        Encoding::Cbor => {
+            let (major, info) = (initial >> 5, initial & 0x1f);
+            let (arg, len) = match info {
+                0..=23 => (u64::from(info), 1),
+                24 => (be(1)?, 2),
+                25 => (be(2)?, 3),
+                26 => (be(4)?, 5),
+                27 => (be(8)?, 9),
+                // Indefinite length.
+                31 => (0, 1),
+                _ => return None,
+            };
+            let head = match major {
+                0 if info != 31 => Head::UInt(arg),
+                3 if info != 31 => Head::Str(usize::try_from(arg).ok()?),
+                4 => Head::Array,
+                _ => Head::Other,
+            };
+            Some((head, len))
+        }
+        Encoding::MessagePack => Some(match initial {
+            0x00..=0x7f => (Head::UInt(u64::from(initial)), 1),
+            0xcc => (Head::UInt(be(1)?), 2),
+            0xcd => (Head::UInt(be(2)?), 3),
+            0xce => (Head::UInt(be(4)?), 5),
+            0xcf => (Head::UInt(be(8)?), 9),
+            0xa0..=0xbf => (Head::Str(usize::from(initial & 0x1f)), 1),
+            0xd9 => (Head::Str(be(1)? as usize), 2),
+            0xda => (Head::Str(be(2)? as usize), 3),
+            0xdb => (Head::Str(be(4)? as usize), 5),
+            0x90..=0x9f => (Head::Array, 1),
+            0xdc => (Head::Array, 3),
+            0xdd => (Head::Array, 5),
+            _ => (Head::Other, 1),
+        }),
+    }
+}
+
+/// Encode a frame into `dst`. Frames exceeding the limits are rejected, as
+/// the remote would not decode them.
+pub fn write(
+    frame: &Frame,
+    dst: &mut BytesMut,
+    limits: &Limits,
+    encoding: Encoding,
+) -> Result<(), Error> {
+    check_limits(frame, limits, 0)?;
+    // The document is serialized in place, after room for its length.
+    let start = dst.len();
+    dst.put_u32(0);
+    let res = match encoding {
+        Encoding::Cbor => {
+            ciborium::ser::into_writer(frame, dst.writer()).map_err(|err| Error::InvalidFrameType {
+                detail: format!("Cannot serialize CBOR document: {}", err),
+            })
+        }
+        Encoding::MessagePack => {
+            rmp_serde::encode::write(&mut dst.writer(), frame).map_err(|err| {
+                Error::InvalidFrameType {
+                    detail: format!("Cannot serialize MessagePack document: {}", err),
+                }
+            })
+        }
+    };
+    if let Err(err) = res {
+        dst.truncate(start);
+        return Err(err);
+    }
+    let len = u32::try_from(dst.len() - start - LENGTH_LEN)?;
+    dst[start..start + LENGTH_LEN].copy_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+/// Number of bytes of the frame as a document, length included. The
+/// document is serialized to find out, without being stored.
+pub fn encoded_len(frame: &Frame, encoding: Encoding) -> usize {
+    struct Counter(usize);
+
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(LENGTH_LEN);
+    // A frame which cannot be serialized is not sent: only what was counted matters.
+    match encoding {
+        Encoding::Cbor => {
+            let _ = ciborium::ser::into_writer(frame, &mut counter);
+        }
+        Encoding::MessagePack => {
+            let _ = rmp_serde::encode::write(&mut counter, frame);
+        }
+    }
+    counter.0
+}
+
+// Documents are decoded in one go, so the nesting and the lengths are checked
+// once the frame is built.
+fn check_limits(frame: &Frame, limits: &Limits, depth: usize) -> Result<(), Error> {
+    match frame {
+        Frame::Array(frames) => {
+            frame::check_nesting(limits, depth + 1)?;
+            frame::check_array_len(frames.len() as u64, limits)?;
+            for frame in frames {
+                check_limits(frame, limits, depth + 1)?;
+            }
+        }
+        Frame::Map(entries) => {
+            frame::check_nesting(limits, depth + 1)?;
+            frame::check_array_len(entries.len() as u64, limits)?;
+            for (key, frame) in entries {
+                if limits.strict {
+                    frame::check_strict_string(key.as_bytes())?;
+                }
+                check_limits(frame, limits, depth + 1)?;
+            }
+        }
+        Frame::String(val) | Frame::Error(val) if limits.strict => {
+            frame::check_strict_string(val.as_bytes())?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
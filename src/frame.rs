@@ -1,6 +1,7 @@
 //! This is based on mini-redis
 
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::fmt;
 use std::io::Cursor;
@@ -17,12 +18,162 @@ pub enum Frame {
     UInt(u64),
     /// A signed integer
     Int(i64),
+    /// A boolean
+    Bool(bool),
+    /// A floating point number
+    Double(f64),
     /// Raw bytes
     Bulk(Bytes),
     /// Empty frame
     Null,
     /// Multiple frames
     Array(Vec<Frame>),
+    /// Frames by name, in order. Keys are strings.
+    Map(Vec<(String, Frame)>),
+}
+
+/// Build an array frame from values convertible into frames.
+///
+/// ```
+/// use area_net::{frame, Frame};
+///
+/// let frame = frame!["HBT_REQ", "alice", 42u64, frame![true, Frame::Null]];
+/// assert_eq!(frame.to_string(), r#"["HBT_REQ", "alice", 42, [true, nil]]"#);
+/// ```
+#[macro_export]
+macro_rules! frame {
+    ($($value:expr),* $(,)?) => {
+        $crate::Frame::Array(vec![$($crate::Frame::from($value)),*])
+    };
+}
+
+/// Default maximum nesting depth of arrays.
+pub const DEFAULT_MAX_DEPTH: usize = 16;
+
+/// Default maximum number of elements in an array.
+pub const DEFAULT_MAX_ARRAY_LEN: usize = 4096;
+
+/// Default maximum number of bytes buffered while waiting for a complete frame.
+pub const DEFAULT_MAX_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Type of an unsigned integer encoded as a varint (LEB128), in both framings.
+pub(crate) const VARINT: u8 = b'&';
+/// Type of a signed integer encoded as a zigzag varint, in both framings.
+pub(crate) const SIGNED_VARINT: u8 = b'^';
+/// A varint holds 7 bits per byte.
+const MAX_VARINT_LEN: usize = 10;
+
+/// Encoding of the integer frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IntEncoding {
+    /// Decimal digits in the text framing, 8 bytes in the binary framing.
+    #[default]
+    Fixed,
+    /// Variable length (LEB128, zigzag for signed integers): small values
+    /// take a single byte. Varints are accepted whatever the encoding.
+    Varint,
+}
+
+impl IntEncoding {
+    /// Name used in the negotiation messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            IntEncoding::Fixed => "fixed",
+            IntEncoding::Varint => "varint",
+        }
+    }
+
+    /// The encoding with the given name, if known.
+    pub fn from_name(name: &str) -> Option<IntEncoding> {
+        match name {
+            "fixed" => Some(IntEncoding::Fixed),
+            "varint" => Some(IntEncoding::Varint),
+            _ => None,
+        }
+    }
+}
+
+/// Smallest encoding of a frame: type, empty content, and end of frame marker.
+const MIN_FRAME_LEN: usize = 3;
+
+/// Bounds applied when decoding frames received from remote peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Maximum nesting depth of arrays. A top level array has depth 1.
+    #[serde(default = "default_max_depth")]
+    pub max_depth: usize,
+    /// Maximum number of elements in an array.
+    #[serde(default = "default_max_array_len")]
+    pub max_array_len: usize,
+    /// Maximum number of bytes buffered while waiting for a complete frame.
+    #[serde(default = "default_max_buffer_size")]
+    pub max_buffer_size: usize,
+    /// Strict mode: strings with a NUL byte or invalid UTF-8 are rejected as
+    /// soon as they are received, rather than once the whole frame is, and so
+    /// are arrays declaring more elements than the buffer could hold.
+    #[serde(default)]
+    pub strict: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_array_len: DEFAULT_MAX_ARRAY_LEN,
+            max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
+            strict: false,
+        }
+    }
+}
+
+fn default_max_depth() -> usize {
+    DEFAULT_MAX_DEPTH
+}
+
+fn default_max_array_len() -> usize {
+    DEFAULT_MAX_ARRAY_LEN
+}
+
+fn default_max_buffer_size() -> usize {
+    DEFAULT_MAX_BUFFER_SIZE
+}
+
+/// First element of a message frame, which names the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key<'a> {
+    /// Tag of the message
+    Tag(&'a str),
+    /// Numeric id of the message
+    Id(u64),
+}
+
+/// Progress of the validation of a frame received in several parts.
+#[derive(Debug, Default)]
+pub struct CheckState {
+    /// Position of the first element not validated yet.
+    pos: usize,
+    /// Position from which to look for the end of the current element.
+    scanned: usize,
+    /// Number of elements still expected by each open array, outermost first.
+    pending: Vec<usize>,
+}
+
+impl CheckState {
+    // The element ending at 'pos' is complete, and so may be the arrays it
+    // closes. Returns true when the whole frame is complete.
+    fn complete(&mut self, pos: usize) -> bool {
+        self.pos = pos;
+        self.scanned = pos;
+        while let Some(remaining) = self.pending.last_mut() {
+            *remaining -= 1;
+            if *remaining > 0 {
+                return false;
+            }
+            self.pending.pop();
+        }
+        true
+    }
 }
 
 /// Error type for frames
@@ -53,195 +204,296 @@ pub enum Error {
         /// Error detail
         detail: String,
     },
+    /// The frame exceeds one of the decoding limits
+    LimitExceeded {
+        /// Error detail
+        detail: String,
+    },
 }
 
 impl Frame {
-    /// Returns an empty array
-    pub(crate) fn array() -> Frame {
-        Frame::Array(vec![])
-    }
-
-    /// Returns the number of bytes this frame requires
+    /// Returns an upper bound of the number of bytes this frame requires.
+    /// Use `Frame::encoded_len` for the exact size.
     pub fn bytes_count(&self) -> usize {
         match self {
             Frame::String(val) => 3 + val.as_bytes().len(),
             Frame::Error(val) => 3 + val.as_bytes().len(),
             Frame::UInt(_) => 11,
             Frame::Int(_) => 11,
+            Frame::Bool(_) => 4,
+            Frame::Double(_) => 27,
             Frame::Null => 5,
             Frame::Bulk(val) => 11 + val.len(),
             Frame::Array(frames) => frames.iter().fold(11, |acc, f| acc + f.bytes_count()),
+            Frame::Map(entries) => entries
+                .iter()
+                .fold(11, |acc, (k, v)| acc + 3 + k.len() + v.bytes_count()),
         }
     }
 
-    /// push simple
-    pub(crate) fn push_string(&mut self, s: String) -> Result<(), Error> {
-        match self {
-            Frame::Array(vec) => {
-                vec.push(Frame::String(s));
-                Ok(())
-            }
-            _ => Err(Error::InvalidFrameType {
-                detail: String::from("Expected Frame Type Array"),
-            }),
-        }
+    /// Returns an empty map
+    pub fn map() -> Frame {
+        Frame::Map(vec![])
     }
 
-    /// push integer
-    pub(crate) fn push_integer(&mut self, i: i64) -> Result<(), Error> {
+    /// insert a named frame
+    pub fn insert(&mut self, key: String, f: Frame) -> Result<(), Error> {
+        check_line(key.as_bytes())?;
         match self {
-            Frame::Array(vec) => {
-                vec.push(Frame::Int(i));
+            Frame::Map(entries) => {
+                entries.push((key, f));
                 Ok(())
             }
             _ => Err(Error::InvalidFrameType {
-                detail: String::from("Expected Frame Type Array"),
+                detail: String::from("Expected Frame Type Map"),
             }),
         }
     }
 
-    /// push unsigned integer
-    pub(crate) fn push_unsigned(&mut self, u: u64) -> Result<(), Error> {
-        match self {
-            Frame::Array(vec) => {
-                vec.push(Frame::UInt(u));
-                Ok(())
+    /// Key of the message at the start of `src`, in the text framing, read
+    /// without decoding the frame. A sealed message (an array holding the
+    /// message, then its HMAC) is looked into. Returns None if `src` does not
+    /// hold the key yet, or does not start with a message.
+    pub fn peek_key(src: &[u8]) -> Option<Key<'_>> {
+        let mut pos = 0;
+        // The message, or the array sealing it.
+        for _ in 0..2 {
+            if *src.get(pos)? != b'*' {
+                return None;
             }
-            _ => Err(Error::InvalidFrameType {
-                detail: String::from("Expected Frame Type Array"),
-            }),
-        }
-    }
-
-    /// push a frame
-    pub(crate) fn push_frame(&mut self, f: Frame) -> Result<(), Error> {
-        match self {
-            Frame::Array(vec) => {
-                vec.push(f);
-                Ok(())
+            pos = find_end_of_frame(src, pos + 1, pos + 1)? + 2;
+            match *src.get(pos)? {
+                b'+' => {
+                    let end = find_end_of_frame(src, pos + 1, pos + 1)?;
+                    return std::str::from_utf8(&src[pos + 1..end]).ok().map(Key::Tag);
+                }
+                b':' => {
+                    let end = find_end_of_frame(src, pos + 1, pos + 1)?;
+                    return parse_unsigned(&src[pos + 1..end]).ok().map(Key::Id);
+                }
+                VARINT => return read_varint(src, pos + 1).ok().map(|(id, _)| Key::Id(id)),
+                b'*' => {}
+                _ => return None,
             }
-            _ => Err(Error::InvalidFrameType {
-                detail: String::from("Expected Frame Type Array"),
-            }),
         }
+        None
     }
 
     /// Checks if an entire message can be decoded from `src`
     pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
-        // We already checked src.has_remaining(), so the get_u8() won't panic.
-        match src.get_u8() {
-            b'+' => {
-                get_line(src)?;
-                Ok(())
-            }
-            b'-' => {
-                get_line(src)?;
-                Ok(())
-            }
-            b':' => {
-                get_unsigned(src)?;
-                Ok(())
-            }
-            b'@' => {
-                get_integer(src)?;
-                Ok(())
+        Frame::check_with_limits(src, &Limits::default())
+    }
+
+    /// Checks if an entire message can be decoded from `src`, within the given limits.
+    pub fn check_with_limits(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<(), Error> {
+        let mut state = CheckState {
+            pos: src.position() as usize,
+            ..CheckState::default()
+        };
+        let end = Frame::check_incremental(src.get_ref(), limits, &mut state)?;
+        src.set_position(end as u64);
+        Ok(())
+    }
+
+    /// Checks if an entire message can be decoded from `src`, and returns its length.
+    /// Validation resumes from the progress saved in `state` by a previous call on
+    /// the same buffer, before more bytes were appended to it. So a frame received in
+    /// many parts is validated only once. The state must be reset once the frame is
+    /// consumed, or after an error.
+    pub fn check_incremental(
+        src: &[u8],
+        limits: &Limits,
+        state: &mut CheckState,
+    ) -> Result<usize, Error> {
+        loop {
+            let mut cur = Cursor::new(src);
+            cur.set_position(state.pos as u64);
+            let frame_type = get_u8(&mut cur)?;
+            let start = cur.position() as usize;
+            // The bytes of a varint may contain an end of frame marker: it has none.
+            if frame_type == VARINT || frame_type == SIGNED_VARINT {
+                let (_, next) = read_varint(src, start)?;
+                if state.complete(next) {
+                    return Ok(next);
+                }
+                continue;
             }
-            b'*' => {
-                let len = get_unsigned(src)?;
-                for _ in 0..len {
-                    Frame::check(src)?;
+            let end = match find_end_of_frame(src, start, state.scanned.max(start)) {
+                Some(end) => end,
+                None => {
+                    // Next time, we only scan the bytes we haven't seen yet.
+                    state.scanned = src.len();
+                    return Err(Error::Incomplete {
+                        detail: String::from("get line, no end of frame"),
+                    });
+                }
+            };
+            let line = &src[start..end];
+            let mut next = end + 2;
+            match frame_type {
+                b'+' | b'-' if limits.strict => {
+                    check_strict_string(line)?;
+                }
+                b'+' | b'-' => {}
+                b':' => {
+                    parse_unsigned(line)?;
+                }
+                b'@' => {
+                    parse_integer(line)?;
+                }
+                b'?' => {
+                    parse_bool(line)?;
+                }
+                b',' => {
+                    parse_double(line)?;
+                }
+                // The bytes follow the length, and may contain an end of frame marker.
+                b'$' if line != b"-1" => {
+                    let len = check_bulk_len(parse_unsigned(line)?, limits)?;
+                    next = bulk_end(src, end + 2, len)?;
+                }
+                b'$' => {}
+                b'*' => {
+                    check_nesting(limits, state.pending.len() + 1)?;
+                    let len = check_array_len(parse_unsigned(line)?, limits)?;
+                    if len > 0 {
+                        state.pending.push(len);
+                        state.pos = end + 2;
+                        state.scanned = state.pos;
+                        continue;
+                    }
+                }
+                // Each entry is a key followed by its value.
+                b'#' => {
+                    check_nesting(limits, state.pending.len() + 1)?;
+                    let len = check_array_len(parse_unsigned(line)?, limits)?;
+                    if len > 0 {
+                        state.pending.push(2 * len);
+                        state.pos = end + 2;
+                        state.scanned = state.pos;
+                        continue;
+                    }
+                }
+                actual => {
+                    return Err(Error::InvalidFrameType {
+                        detail: format!("Unexpected frame id: {}", actual),
+                    })
                 }
-                Ok(())
             }
-            actual => Err(Error::InvalidFrameType {
-                detail: format!("Unexpected frame id: {}", actual),
-            }),
+            if state.complete(next) {
+                return Ok(next);
+            }
         }
     }
 
     /// The message has already been validated with check
     pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
-        match src.get_u8() {
-            b'+' => {
-                let line = get_line(src)?;
-                let string =
-                    String::from_utf8(line[..].to_vec()).map_err(|err| Error::UnexpectedBytes {
-                        detail: format!("Invalid UTF8: {err}"),
-                    })?;
-                Ok(Frame::String(string))
-            }
-            b'-' => {
-                let line = get_line(src)?;
-                let string =
-                    String::from_utf8(line[..].to_vec()).map_err(|err| Error::UnexpectedBytes {
-                        detail: format!("Invalid UTF8: {err}"),
-                    })?;
-                Ok(Frame::Error(string))
-            }
-            b':' => {
-                let len = get_unsigned(src)?;
-                Ok(Frame::UInt(len))
-            }
-            b'@' => {
-                let ts = get_integer(src)?;
-                Ok(Frame::Int(ts))
-            }
-            b'*' => {
-                let len: usize = get_unsigned(src)?.try_into()?;
-                let mut frames = Vec::with_capacity(len);
-                for _ in 0..len {
-                    frames.push(Frame::parse(src)?);
-                }
-                Ok(Frame::Array(frames))
-            }
-            _ => unimplemented!(),
-        }
+        Frame::parse_with_limits(src, &Limits::default())
+    }
+
+    /// The message has already been validated with check_with_limits
+    pub fn parse_with_limits(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<Frame, Error> {
+        parse_depth(src, limits, 0)
     }
 
-    /// Documentation
+    /// Encode the frame into `dst`.
     pub fn write(&self, dst: &mut BytesMut) -> Result<(), Error> {
-        // Arrays are encoded by encoding each entry. All other frame types are
-        // considered literals.
-        match self {
-            Frame::Array(val) => {
-                // Encode the frame type prefix. For an array, it is `*`.
-                dst.extend_from_slice(b"*");
+        self.write_with_limits(dst, &Limits::default())
+    }
 
-                // Encode the length of the array.
-                write_unsigned(dst, val.len() as u64)?;
+    /// Encode the frame into `dst`. Arrays nested deeper than the limits are
+    /// rejected, as the remote would not decode them.
+    pub fn write_with_limits(&self, dst: &mut BytesMut, limits: &Limits) -> Result<(), Error> {
+        self.write_with_encoding(dst, limits, IntEncoding::Fixed)
+    }
 
-                // Iterate and encode each entry in the array.
-                for entry in &**val {
-                    entry.write_value(dst)?;
-                }
-            }
-            // The frame type is a literal. Encode the value directly.
-            _ => self.write_value(dst)?,
-        }
+    /// Encode the frame into `dst`, within the limits, with the given encoding of integers.
+    pub fn write_with_encoding(
+        &self,
+        dst: &mut BytesMut,
+        limits: &Limits,
+        ints: IntEncoding,
+    ) -> Result<(), Error> {
+        // The length is exact, so the frame is written without reallocating.
+        dst.reserve(self.encoded_len_with(ints));
+        self.write_depth(dst, limits, ints, 0)
+    }
 
-        Ok(())
+    /// Number of bytes written by `Frame::write`, ie in the text framing.
+    pub fn encoded_len(&self) -> usize {
+        self.encoded_len_with(IntEncoding::Fixed)
+    }
+
+    /// Number of bytes of the frame in the text framing, with the given
+    /// encoding of integers.
+    pub fn encoded_len_with(&self, ints: IntEncoding) -> usize {
+        match self {
+            Frame::String(val) | Frame::Error(val) => val.len() + 3,
+            Frame::UInt(val) if ints == IntEncoding::Varint => 1 + varint_len(*val),
+            Frame::Int(val) if ints == IntEncoding::Varint => 1 + varint_len(zigzag(*val)),
+            Frame::UInt(val) => decimal_len(val) + 3,
+            Frame::Int(val) => decimal_len(val) + 3,
+            Frame::Bool(_) => 4,
+            Frame::Double(val) => decimal_len(val) + 3,
+            Frame::Null => 5,
+            Frame::Bulk(val) => decimal_len(&val.len()) + val.len() + 5,
+            Frame::Array(frames) => frames
+                .iter()
+                .fold(decimal_len(&frames.len()) + 3, |acc, f| {
+                    acc + f.encoded_len_with(ints)
+                }),
+            Frame::Map(entries) => entries
+                .iter()
+                .fold(decimal_len(&entries.len()) + 3, |acc, (k, v)| {
+                    acc + k.len() + 3 + v.encoded_len_with(ints)
+                }),
+        }
     }
 
-    /// Write a frame literal to the file
-    fn write_value(&self, dst: &mut BytesMut) -> Result<(), Error> {
+    // Write a frame nested in 'depth' arrays.
+    fn write_depth(
+        &self,
+        dst: &mut BytesMut,
+        limits: &Limits,
+        ints: IntEncoding,
+        depth: usize,
+    ) -> Result<(), Error> {
         match self {
             Frame::String(val) => {
+                check_line(val.as_bytes())?;
                 dst.extend_from_slice(b"+");
                 dst.extend_from_slice(val.as_bytes());
                 dst.extend_from_slice(b"\r\n");
             }
             Frame::Error(val) => {
+                check_line(val.as_bytes())?;
                 dst.extend_from_slice(b"-");
                 dst.extend_from_slice(val.as_bytes());
                 dst.extend_from_slice(b"\r\n");
             }
+            Frame::UInt(val) if ints == IntEncoding::Varint => {
+                dst.put_u8(VARINT);
+                write_varint(dst, *val);
+            }
+            Frame::Int(val) if ints == IntEncoding::Varint => {
+                dst.put_u8(SIGNED_VARINT);
+                write_varint(dst, zigzag(*val));
+            }
             Frame::UInt(val) => {
                 dst.extend_from_slice(b":");
-                write_unsigned(dst, *val)?;
+                write_decimal(dst, val)?;
             }
             Frame::Int(val) => {
                 dst.extend_from_slice(b"@");
-                write_integer(dst, *val)?;
+                write_decimal(dst, val)?;
+            }
+            Frame::Bool(val) => {
+                dst.extend_from_slice(if *val { b"?t\r\n" } else { b"?f\r\n" });
+            }
+            Frame::Double(val) => {
+                // The shortest representation which parses back to the same value.
+                dst.extend_from_slice(b",");
+                write_decimal(dst, val)?;
             }
             Frame::Null => {
                 dst.extend_from_slice(b"$-1\r\n");
@@ -250,19 +502,30 @@ impl Frame {
                 let len = val.len();
 
                 dst.extend_from_slice(b"$");
-                write_unsigned(dst, len as u64)?;
+                write_decimal(dst, &len)?;
                 dst.extend_from_slice(val);
                 dst.extend_from_slice(b"\r\n");
             }
             Frame::Array(val) => {
-                let len = val.len();
+                // The elements follow the length: there is no end of array marker.
+                check_nesting(limits, depth + 1)?;
                 dst.extend_from_slice(b"*");
-                write_unsigned(dst, len as u64)?;
-                let dst = val.iter().try_fold(dst, |buff, frame| {
-                    frame.write_value(buff)?;
-                    Ok::<_, Error>(buff)
-                })?;
-                dst.extend_from_slice(b"\r\n");
+                write_decimal(dst, &val.len())?;
+                for entry in val {
+                    entry.write_depth(dst, limits, ints, depth + 1)?;
+                }
+            }
+            Frame::Map(val) => {
+                check_nesting(limits, depth + 1)?;
+                dst.extend_from_slice(b"#");
+                write_decimal(dst, &val.len())?;
+                for (key, value) in val {
+                    check_line(key.as_bytes())?;
+                    dst.extend_from_slice(b"+");
+                    dst.extend_from_slice(key.as_bytes());
+                    dst.extend_from_slice(b"\r\n");
+                    value.write_depth(dst, limits, ints, depth + 1)?;
+                }
             }
         }
 
@@ -270,38 +533,312 @@ impl Frame {
     }
 }
 
-/// Write a unsigned frame to the file
-fn write_unsigned(dst: &mut BytesMut, val: u64) -> Result<(), Error> {
-    use std::io::Write;
+/// Number of bytes shown in hexadecimal on each line of a dump.
+const DUMP_WIDTH: usize = 16;
+
+/// Render frames in the text framing, one element per line: its offset, its
+/// bytes in hexadecimal, and what they decode to. Elements of arrays and maps
+/// are indented. The dump stops at the first element which does not decode,
+/// with the reason.
+pub fn dump(src: &[u8]) -> String {
+    let mut out = String::new();
+    let mut cursor = Cursor::new(src);
+    let limits = Limits::default();
+    while cursor.has_remaining() {
+        if dump_depth(&mut cursor, &limits, 0, &mut out).is_err() {
+            break;
+        }
+    }
+    out
+}
+
+// Dump an element nested in 'depth' arrays or maps.
+fn dump_depth(
+    src: &mut Cursor<&[u8]>,
+    limits: &Limits,
+    depth: usize,
+    out: &mut String,
+) -> Result<(), Error> {
+    let bytes = *src.get_ref();
+    let start = src.position() as usize;
+    let is_map = match bytes.get(start) {
+        Some(b'*') => false,
+        Some(b'#') => true,
+        kind => {
+            let frame = match parse_depth(src, limits, depth) {
+                Ok(frame) => frame,
+                Err(err) => {
+                    dump_line(out, &bytes[start..], start, depth, &err);
+                    return Err(err);
+                }
+            };
+            let line = &bytes[start..src.position() as usize];
+            let varint = if matches!(kind, Some(&VARINT | &SIGNED_VARINT)) {
+                " (varint)"
+            } else {
+                ""
+            };
+            let note = match &frame {
+                Frame::String(_) => format!("string {frame}"),
+                Frame::Error(val) => format!("error {val:?}"),
+                Frame::UInt(_) => format!("uint {frame}{varint}"),
+                Frame::Int(_) => format!("int {frame}{varint}"),
+                Frame::Bool(_) => format!("bool {frame}"),
+                Frame::Double(_) => format!("double {frame}"),
+                Frame::Bulk(val) => format!("bulk, {} bytes", val.len()),
+                Frame::Null => "null".to_owned(),
+                _ => format!("{frame}"),
+            };
+            dump_line(out, line, start, depth, &note);
+            return Ok(());
+        }
+    };
+    src.advance(1);
+    let len = match check_nesting(limits, depth + 1).and_then(|()| get_array_len(src, limits)) {
+        Ok(len) => len,
+        Err(err) => {
+            dump_line(out, &bytes[start..], start, depth, &err);
+            return Err(err);
+        }
+    };
+    let line = &bytes[start..src.position() as usize];
+    if is_map {
+        dump_line(out, line, start, depth, &format_args!("map, {len} entries"));
+    } else {
+        dump_line(
+            out,
+            line,
+            start,
+            depth,
+            &format_args!("array, {len} elements"),
+        );
+    }
+    for _ in 0..len {
+        if is_map {
+            dump_depth(src, limits, depth + 1, out)?;
+        }
+        dump_depth(src, limits, depth + 1, out)?;
+    }
+    Ok(())
+}
 
-    // Convert the value to a string
-    let mut buf = [0u8; 20];
-    let mut buf = Cursor::new(&mut buf[..]);
-    write!(&mut buf, "{}", val)?;
+// Append a line of the dump: long elements only show their first bytes.
+fn dump_line(out: &mut String, bytes: &[u8], offset: usize, depth: usize, note: &dyn fmt::Display) {
+    use fmt::Write;
 
-    let pos = buf.position() as usize;
-    dst.extend_from_slice(&buf.get_ref()[..pos]);
-    dst.extend_from_slice(b"\r\n");
+    let shown = &bytes[..bytes.len().min(DUMP_WIDTH)];
+    let mut hex = String::with_capacity(3 * DUMP_WIDTH);
+    for byte in shown {
+        let _ = write!(hex, "{byte:02x} ");
+    }
+    if shown.len() < bytes.len() {
+        hex.replace_range(hex.len() - 3.., "...");
+    }
+    let _ = writeln!(
+        out,
+        "{offset:08x}  {hex:<width$} {:indent$}{note}",
+        "",
+        width = 3 * DUMP_WIDTH,
+        indent = 2 * depth
+    );
+}
 
+pub(crate) fn check_nesting(limits: &Limits, depth: usize) -> Result<(), Error> {
+    if depth > limits.max_depth {
+        return Err(Error::LimitExceeded {
+            detail: format!("Arrays nested deeper than {}", limits.max_depth),
+        });
+    }
     Ok(())
 }
 
-/// Write an integer frame to the file
-fn write_integer(dst: &mut BytesMut, val: i64) -> Result<(), Error> {
-    use std::io::Write;
+// Parse a frame nested in 'depth' arrays.
+fn parse_depth(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<Frame, Error> {
+    match get_u8(src)? {
+        b'+' => {
+            let line = get_line(src)?;
+            check_line(line)?;
+            let string =
+                String::from_utf8(line[..].to_vec()).map_err(|err| Error::UnexpectedBytes {
+                    detail: format!("Invalid UTF8: {err}"),
+                })?;
+            Ok(Frame::String(string))
+        }
+        b'-' => {
+            let line = get_line(src)?;
+            check_line(line)?;
+            let string =
+                String::from_utf8(line[..].to_vec()).map_err(|err| Error::UnexpectedBytes {
+                    detail: format!("Invalid UTF8: {err}"),
+                })?;
+            Ok(Frame::Error(string))
+        }
+        b':' => {
+            let len = get_unsigned(src)?;
+            Ok(Frame::UInt(len))
+        }
+        b'@' => {
+            let ts = get_integer(src)?;
+            Ok(Frame::Int(ts))
+        }
+        b'?' => Ok(Frame::Bool(parse_bool(get_line(src)?)?)),
+        VARINT => Ok(Frame::UInt(get_varint(src)?)),
+        SIGNED_VARINT => Ok(Frame::Int(unzigzag(get_varint(src)?))),
+        b',' => Ok(Frame::Double(parse_double(get_line(src)?)?)),
+        b'*' => {
+            check_nesting(limits, depth + 1)?;
+            let len = get_array_len(src, limits)?;
+            // The length is declared by the remote: we don't allocate more than
+            // the remaining bytes could possibly hold.
+            let mut frames = Vec::with_capacity(len.min(src.remaining() / MIN_FRAME_LEN));
+            for _ in 0..len {
+                frames.push(parse_depth(src, limits, depth + 1)?);
+            }
+            Ok(Frame::Array(frames))
+        }
+        b'#' => {
+            check_nesting(limits, depth + 1)?;
+            let len = get_array_len(src, limits)?;
+            let mut entries = Vec::with_capacity(len.min(src.remaining() / (2 * MIN_FRAME_LEN)));
+            for _ in 0..len {
+                let key = match parse_depth(src, limits, depth + 1)? {
+                    Frame::String(key) => key,
+                    frame => {
+                        return Err(Error::InvalidFrameType {
+                            detail: format!("Expected String map key, got {frame:?}"),
+                        })
+                    }
+                };
+                entries.push((key, parse_depth(src, limits, depth + 1)?));
+            }
+            Ok(Frame::Map(entries))
+        }
+        b'$' => {
+            let line = get_line(src)?;
+            if line == b"-1" {
+                return Ok(Frame::Null);
+            }
+            let len = check_bulk_len(parse_unsigned(line)?, limits)?;
+            let start = src.position() as usize;
+            let end = bulk_end(src.get_ref(), start, len)?;
+            let bytes = Bytes::copy_from_slice(&src.get_ref()[start..start + len]);
+            src.set_position(end as u64);
+            Ok(Frame::Bulk(bytes))
+        }
+        actual => Err(Error::InvalidFrameType {
+            detail: format!("Unexpected frame id: {}", actual),
+        }),
+    }
+}
 
-    // Convert the value to a string
-    let mut buf = [0u8; 20];
-    let mut buf = Cursor::new(&mut buf[..]);
-    write!(&mut buf, "{}", val)?;
+// Check the length of a bulk frame against the limits: a remote must not make
+// us wait for more bytes than we are willing to buffer.
+fn check_bulk_len(len: u64, limits: &Limits) -> Result<usize, Error> {
+    let len: usize = len.try_into()?;
+    if len > limits.max_buffer_size {
+        return Err(Error::LimitExceeded {
+            detail: format!(
+                "Bulk of {} bytes, more than {}",
+                len, limits.max_buffer_size
+            ),
+        });
+    }
+    Ok(len)
+}
 
-    let pos = buf.position() as usize;
-    dst.extend_from_slice(&buf.get_ref()[..pos]);
-    dst.extend_from_slice(b"\r\n");
+// Returns the position following the 'len' bytes starting at 'start', and
+// their end of frame marker.
+fn bulk_end(src: &[u8], start: usize, len: usize) -> Result<usize, Error> {
+    let end = start + len;
+    match src.get(end..end + 2) {
+        Some(b"\r\n") => Ok(end + 2),
+        Some(_) => Err(Error::UnexpectedBytes {
+            detail: String::from("Bulk frame longer than its declared length"),
+        }),
+        None => Err(Error::Incomplete {
+            detail: format!("get bulk, buflen < {}", len + 2),
+        }),
+    }
+}
 
+// Read the length of an array, and check it against the limits.
+fn get_array_len(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<usize, Error> {
+    check_array_len(get_unsigned(src)?, limits)
+}
+
+pub(crate) fn check_array_len(len: u64, limits: &Limits) -> Result<usize, Error> {
+    let len: usize = len.try_into()?;
+    if len > limits.max_array_len {
+        return Err(Error::LimitExceeded {
+            detail: format!(
+                "Array of {} elements, more than {}",
+                len, limits.max_array_len
+            ),
+        });
+    }
+    // Each element takes at least a byte, in any framing.
+    if limits.strict && len > limits.max_buffer_size {
+        return Err(Error::LimitExceeded {
+            detail: format!(
+                "Array of {} elements, larger than the buffer ({} bytes)",
+                len, limits.max_buffer_size
+            ),
+        });
+    }
+    Ok(len)
+}
+
+/// In strict mode, strings must be valid UTF-8, without NUL bytes.
+pub(crate) fn check_strict_string(val: &[u8]) -> Result<(), Error> {
+    if memchr::memchr(0, val).is_some() {
+        return Err(Error::UnexpectedBytes {
+            detail: String::from("NUL byte in string"),
+        });
+    }
+    std::str::from_utf8(val).map_err(|err| Error::UnexpectedBytes {
+        detail: format!("Invalid UTF8: {err}"),
+    })?;
     Ok(())
 }
 
+/// Write a number in decimal, followed by the end of line, straight into the
+/// destination buffer.
+fn write_decimal(dst: &mut BytesMut, val: &impl fmt::Display) -> Result<(), Error> {
+    use std::fmt::Write;
+
+    write!(dst, "{}\r\n", val).map_err(|err| Error::IoError {
+        detail: format!("Could not write number: {err}"),
+    })
+}
+
+/// Number of bytes of a number written in decimal, without formatting it
+/// into a buffer.
+fn decimal_len(val: &impl fmt::Display) -> usize {
+    struct Counter(usize);
+
+    impl fmt::Write for Counter {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 += s.len();
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    let _ = fmt::write(&mut counter, format_args!("{}", val));
+    counter.0
+}
+
+// Read the frame type. The elements of an array may not have arrived yet,
+// so we can't assume there is a byte to read.
+fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete {
+            detail: String::from("get frame type, buflen < 1"),
+        });
+    }
+    Ok(src.get_u8())
+}
+
 // Find a End Of Frame Marker (\r\n), and returns a slice up to that mark
 // Change the position of the cursor to point to just after the end of frame.
 fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
@@ -329,22 +866,205 @@ fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
     })
 }
 
+// Strings are delimited by the End Of Frame Marker, so they cannot contain
+// CR or LF: a label such as "alice\r\n:42" would otherwise inject frames.
+fn check_line(line: &[u8]) -> Result<(), Error> {
+    match memchr::memchr2(b'\r', b'\n', line) {
+        Some(pos) => Err(Error::UnexpectedBytes {
+            detail: format!("String contains CR or LF at position {pos}"),
+        }),
+        None => Ok(()),
+    }
+}
+
+// Returns the position of the End Of Frame Marker (\r\n) ending the line
+// starting at 'start'. The bytes before 'from' are known not to contain it.
+fn find_end_of_frame(src: &[u8], start: usize, from: usize) -> Option<usize> {
+    // A marker straddling 'from' has its '\n' at 'from'.
+    let mut pos = from;
+    while let Some(i) = memchr::memchr(b'\n', src.get(pos..)?) {
+        let lf = pos + i;
+        if lf > start && src[lf - 1] == b'\r' {
+            return Some(lf - 1);
+        }
+        pos = lf + 1;
+    }
+    None
+}
+
 fn get_unsigned(src: &mut Cursor<&[u8]>) -> Result<u64, Error> {
-    use atoi::atoi;
-    let line = get_line(src)?;
-    atoi::<u64>(line).ok_or_else(|| Error::UnexpectedBytes {
+    parse_unsigned(get_line(src)?)
+}
+
+fn get_integer(src: &mut Cursor<&[u8]>) -> Result<i64, Error> {
+    parse_integer(get_line(src)?)
+}
+
+fn parse_unsigned(line: &[u8]) -> Result<u64, Error> {
+    atoi::atoi::<u64>(line).ok_or_else(|| Error::UnexpectedBytes {
         detail: String::from("Invalid unsigned frame"),
     })
 }
 
-fn get_integer(src: &mut Cursor<&[u8]>) -> Result<i64, Error> {
-    use atoi::atoi;
-    let line = get_line(src)?;
-    atoi::<i64>(line).ok_or_else(|| Error::UnexpectedBytes {
+/// Number of bytes of the varint of a value.
+pub(crate) fn varint_len(val: u64) -> usize {
+    (u64::BITS - val.leading_zeros()).max(1).div_ceil(7) as usize
+}
+
+pub(crate) fn write_varint(dst: &mut BytesMut, mut val: u64) {
+    while val >= 0x80 {
+        dst.put_u8((val as u8) | 0x80);
+        val >>= 7;
+    }
+    dst.put_u8(val as u8);
+}
+
+// Read the varint starting at 'start'. Returns its value, and the position following it.
+fn read_varint(src: &[u8], start: usize) -> Result<(u64, usize), Error> {
+    let mut val = 0u64;
+    for (i, byte) in src.iter().skip(start).take(MAX_VARINT_LEN).enumerate() {
+        if i == MAX_VARINT_LEN - 1 && *byte > 1 {
+            break;
+        }
+        val |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((val, start + i + 1));
+        }
+    }
+    if src.len() < start + MAX_VARINT_LEN {
+        return Err(Error::Incomplete {
+            detail: String::from("get varint, no last byte"),
+        });
+    }
+    Err(Error::UnexpectedBytes {
+        detail: String::from("Varint overflows 64 bits"),
+    })
+}
+
+pub(crate) fn get_varint(src: &mut Cursor<&[u8]>) -> Result<u64, Error> {
+    let (val, next) = read_varint(src.get_ref(), src.position() as usize)?;
+    src.set_position(next as u64);
+    Ok(val)
+}
+
+// Signed integers are mapped to unsigned ones so that small negative
+// values also have a short varint: 0, -1, 1, -2... become 0, 1, 2, 3...
+pub(crate) fn zigzag(val: i64) -> u64 {
+    ((val << 1) ^ (val >> 63)) as u64
+}
+
+pub(crate) fn unzigzag(val: u64) -> i64 {
+    ((val >> 1) as i64) ^ -((val & 1) as i64)
+}
+
+fn parse_bool(line: &[u8]) -> Result<bool, Error> {
+    match line {
+        b"t" => Ok(true),
+        b"f" => Ok(false),
+        _ => Err(Error::UnexpectedBytes {
+            detail: String::from("Invalid boolean frame"),
+        }),
+    }
+}
+
+fn parse_double(line: &[u8]) -> Result<f64, Error> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.parse::<f64>().ok())
+        .ok_or_else(|| Error::UnexpectedBytes {
+            detail: String::from("Invalid double frame"),
+        })
+}
+
+fn parse_integer(line: &[u8]) -> Result<i64, Error> {
+    atoi::atoi::<i64>(line).ok_or_else(|| Error::UnexpectedBytes {
         detail: String::from("Invalid integer frame"),
     })
 }
 
+// Strings are not checked for CR or LF when converted: frames holding them
+// are rejected when written.
+impl From<&str> for Frame {
+    fn from(val: &str) -> Self {
+        Frame::String(val.to_owned())
+    }
+}
+
+impl From<String> for Frame {
+    fn from(val: String) -> Self {
+        Frame::String(val)
+    }
+}
+
+impl From<u64> for Frame {
+    fn from(val: u64) -> Self {
+        Frame::UInt(val)
+    }
+}
+
+impl From<i64> for Frame {
+    fn from(val: i64) -> Self {
+        Frame::Int(val)
+    }
+}
+
+impl From<bool> for Frame {
+    fn from(val: bool) -> Self {
+        Frame::Bool(val)
+    }
+}
+
+impl From<f64> for Frame {
+    fn from(val: f64) -> Self {
+        Frame::Double(val)
+    }
+}
+
+impl From<Bytes> for Frame {
+    fn from(val: Bytes) -> Self {
+        Frame::Bulk(val)
+    }
+}
+
+impl From<Vec<Frame>> for Frame {
+    fn from(val: Vec<Frame>) -> Self {
+        Frame::Array(val)
+    }
+}
+
+/// A readable rendering of the frame, on one line. The alternate form,
+/// `{:#}`, puts each element of an array or a map on its own line.
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Elements are rendered with the flags of the formatter, so that the
+        // alternate form is indented at every level.
+        struct Shown<'a>(&'a Frame);
+
+        impl fmt::Debug for Shown<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Display::fmt(self.0, f)
+            }
+        }
+
+        match self {
+            Frame::String(val) => write!(f, "{val:?}"),
+            Frame::Error(val) => write!(f, "(error) {val:?}"),
+            Frame::UInt(val) => write!(f, "{val}"),
+            Frame::Int(val) => write!(f, "{val}"),
+            Frame::Bool(val) => write!(f, "{val}"),
+            // Debug keeps the decimal point of integral values.
+            Frame::Double(val) => write!(f, "{val:?}"),
+            Frame::Bulk(val) => write!(f, "b\"{}\"", val.escape_ascii()),
+            Frame::Null => write!(f, "nil"),
+            Frame::Array(frames) => f.debug_list().entries(frames.iter().map(Shown)).finish(),
+            Frame::Map(entries) => f
+                .debug_map()
+                .entries(entries.iter().map(|(key, val)| (key, Shown(val))))
+                .finish(),
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -353,6 +1073,7 @@ impl fmt::Display for Error {
             Error::UnexpectedBytes { detail } => write!(f, "Invalid Frame Content: {}", detail),
             Error::InvalidNumeric { detail } => write!(f, "Invalid Numerical Value: {}", detail),
             Error::IoError { detail } => write!(f, "IO Error: {}", detail),
+            Error::LimitExceeded { detail } => write!(f, "Frame Limit Exceeded: {}", detail),
         }
     }
 }
@@ -408,6 +1129,302 @@ mod tests {
         assert_eq!(&a, b"");
     }
 
+    #[test]
+    fn should_check_a_frame_received_byte_by_byte() {
+        let bytes = b"*3\r\n+CTCT_RESP\r\n*2\r\n+[::1]:8090\r\n+[::1]:8091\r\n*0\r\n";
+        let limits = Limits::default();
+        let mut state = CheckState::default();
+        for len in 1..bytes.len() {
+            assert!(matches!(
+                Frame::check_incremental(&bytes[..len], &limits, &mut state),
+                Err(Error::Incomplete { .. })
+            ));
+        }
+        // Only the last element was left to validate.
+        assert_eq!(state.pos, bytes.len() - 4);
+        assert_eq!(state.pending, vec![1]);
+        let len = Frame::check_incremental(&bytes[..], &limits, &mut state).unwrap();
+        assert_eq!(len, bytes.len());
+    }
+
+    #[test]
+    fn should_encode_decode_maps() {
+        let frame = Frame::Array(vec![
+            Frame::String("CTCT_RESP".to_owned()),
+            Frame::Map(vec![
+                ("label".to_owned(), Frame::String("bob".to_owned())),
+                ("addrs".to_owned(), Frame::Array(vec![Frame::UInt(1)])),
+                ("empty".to_owned(), Frame::map()),
+            ]),
+        ]);
+        let mut buf = BytesMut::new();
+        frame.write(&mut buf).unwrap();
+        let limits = Limits::default();
+        let mut state = CheckState::default();
+        for len in 1..buf.len() {
+            assert!(Frame::check_incremental(&buf[..len], &limits, &mut state).is_err());
+        }
+        assert_eq!(
+            Frame::check_incremental(&buf[..], &limits, &mut state).unwrap(),
+            buf.len()
+        );
+        let decoded = Frame::parse(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(format!("{decoded:?}"), format!("{frame:?}"));
+
+        // Keys are strings.
+        let mut cur = Cursor::new(&b"#1\r\n:1\r\n:2\r\n"[..]);
+        assert!(matches!(
+            Frame::parse(&mut cur),
+            Err(Error::InvalidFrameType { .. })
+        ));
+    }
+
+    #[test]
+    fn should_display_and_dump_frames() {
+        let frame = Frame::Array(vec![
+            Frame::String("PING".to_owned()),
+            Frame::Map(vec![
+                ("id".to_owned(), Frame::UInt(42)),
+                ("at".to_owned(), Frame::Double(1.0)),
+            ]),
+            Frame::Bulk(Bytes::from_static(b"a\0")),
+            Frame::Null,
+        ]);
+        assert_eq!(
+            frame.to_string(),
+            r#"["PING", {"id": 42, "at": 1.0}, b"a\x00", nil]"#
+        );
+        assert_eq!(
+            format!("{frame:#}").lines().nth(3),
+            Some(r#"        "id": 42,"#)
+        );
+
+        let mut buf = BytesMut::new();
+        frame.write(&mut buf).unwrap();
+        buf.extend_from_slice(b"@12\r");
+        let dump = dump(&buf);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 10);
+        assert!(lines[0].starts_with("00000000  2a 34 0d 0a "));
+        assert!(lines[0].ends_with(" array, 4 elements"));
+        assert!(lines[4].ends_with("    uint 42"));
+        assert!(lines[7].ends_with("  bulk, 2 bytes"));
+        assert!(lines[9].ends_with(" Incomplete Frame: get line, buflen < 2"));
+    }
+
+    #[test]
+    fn should_encode_decode_bools_and_doubles() {
+        let doubles = [0.1, -2.5e-300, 1e21, f64::INFINITY];
+        let mut frames: Vec<Frame> = doubles.iter().map(|d| Frame::Double(*d)).collect();
+        frames.extend([Frame::Bool(true), Frame::Bool(false)]);
+        let frame = Frame::Array(frames);
+        let mut buf = BytesMut::new();
+        frame.write(&mut buf).unwrap();
+        let mut cur = Cursor::new(&buf[..]);
+        Frame::check(&mut cur).unwrap();
+        assert_eq!(cur.position() as usize, buf.len());
+        let decoded = Frame::parse(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(format!("{decoded:?}"), format!("{frame:?}"));
+
+        let mut cur = Cursor::new(&b"?x\r\n"[..]);
+        assert!(Frame::check(&mut cur).is_err());
+        let mut cur = Cursor::new(&b",1.5.2\r\n"[..]);
+        assert!(Frame::check(&mut cur).is_err());
+    }
+
+    #[test]
+    fn strict_mode_rejects_invalid_strings_early() {
+        let strict = Limits {
+            strict: true,
+            ..Limits::default()
+        };
+        // The array is incomplete, but its first string is already invalid.
+        for src in [&b"*2\r\n+HBT\0REQ\r\n"[..], &b"*2\r\n+\xff\xfe\r\n"[..]] {
+            let mut state = CheckState::default();
+            assert!(matches!(
+                Frame::check_incremental(src, &Limits::default(), &mut state),
+                Err(Error::Incomplete { .. })
+            ));
+            let mut state = CheckState::default();
+            assert!(matches!(
+                Frame::check_incremental(src, &strict, &mut state),
+                Err(Error::UnexpectedBytes { .. })
+            ));
+        }
+
+        let frame = Frame::String("HBT\0REQ".to_owned());
+        let mut dst = BytesMut::new();
+        crate::binary::write(&frame, &mut dst, &strict).unwrap();
+        assert!(crate::binary::parse(&mut Cursor::new(&dst[..]), &Limits::default()).is_ok());
+        assert!(matches!(
+            crate::binary::parse(&mut Cursor::new(&dst[..]), &strict),
+            Err(Error::UnexpectedBytes { .. })
+        ));
+    }
+
+    #[test]
+    fn should_write_frames_in_a_single_allocation() {
+        let mut frame = Frame::map();
+        frame
+            .insert("tag".to_owned(), Frame::String("HBT_REQ".to_owned()))
+            .unwrap();
+        frame.insert("int".to_owned(), Frame::Int(-1234)).unwrap();
+        frame
+            .insert("big".to_owned(), Frame::UInt(u64::MAX))
+            .unwrap();
+        frame
+            .insert("ratio".to_owned(), Frame::Double(0.1))
+            .unwrap();
+        frame
+            .insert("bulk".to_owned(), Frame::Bulk(Bytes::from(vec![0u8; 300])))
+            .unwrap();
+        let frame = Frame::Array(vec![frame, Frame::Null, Frame::Bool(true), Frame::Int(0)]);
+        for ints in [IntEncoding::Fixed, IntEncoding::Varint] {
+            let len = frame.encoded_len_with(ints);
+            let mut dst = BytesMut::new();
+            frame
+                .write_with_encoding(&mut dst, &Limits::default(), ints)
+                .unwrap();
+            assert_eq!(dst.len(), len);
+            assert_eq!(dst.capacity(), len);
+
+            let len = crate::binary::encoded_len(&frame, ints);
+            let mut dst = BytesMut::new();
+            crate::binary::write_with_encoding(&frame, &mut dst, &Limits::default(), ints).unwrap();
+            assert_eq!(dst.len(), len);
+            assert_eq!(dst.capacity(), len);
+        }
+    }
+
+    #[test]
+    fn should_encode_decode_varints() {
+        let frame = Frame::Array(vec![
+            Frame::UInt(0),
+            Frame::UInt(13),
+            Frame::UInt(u64::MAX),
+            Frame::Int(-1),
+            Frame::Int(i64::MIN),
+            Frame::Int(i64::MAX),
+        ]);
+        let mut buf = BytesMut::new();
+        frame
+            .write_with_encoding(&mut buf, &Limits::default(), IntEncoding::Varint)
+            .unwrap();
+        // 13 is a carriage return, which does not end a varint.
+        assert_eq!(&buf[..8], b"*6\r\n&\0&\r");
+        let limits = Limits::default();
+        let mut state = CheckState::default();
+        for len in 1..buf.len() {
+            assert!(Frame::check_incremental(&buf[..len], &limits, &mut state).is_err());
+        }
+        assert_eq!(
+            Frame::check_incremental(&buf[..], &limits, &mut state).unwrap(),
+            buf.len()
+        );
+        let decoded = Frame::parse(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(format!("{decoded:?}"), format!("{frame:?}"));
+
+        let mut cur = Cursor::new(
+            &[
+                VARINT, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02,
+            ][..],
+        );
+        assert!(matches!(
+            Frame::check(&mut cur),
+            Err(Error::UnexpectedBytes { .. })
+        ));
+    }
+
+    #[test]
+    fn should_check_one_frame_at_a_time() {
+        let mut cur = Cursor::new(&b":1\r\n+hello\r\n"[..]);
+        Frame::check(&mut cur).unwrap();
+        assert_eq!(cur.position(), 4);
+        Frame::check(&mut cur).unwrap();
+        assert_eq!(cur.position(), 12);
+    }
+
+    #[test]
+    fn should_reject_frames_nested_too_deep() {
+        let limits = Limits {
+            max_depth: 2,
+            ..Limits::default()
+        };
+        let mut cur = Cursor::new(&b"*1\r\n*1\r\n:1\r\n"[..]);
+        assert!(Frame::check_with_limits(&mut cur, &limits).is_ok());
+        let mut cur = Cursor::new(&b"*1\r\n*1\r\n*1\r\n:1\r\n"[..]);
+        assert!(matches!(
+            Frame::check_with_limits(&mut cur, &limits),
+            Err(Error::LimitExceeded { .. })
+        ));
+        let mut cur = Cursor::new(&b"*1\r\n*1\r\n*1\r\n:1\r\n"[..]);
+        assert!(matches!(
+            Frame::parse_with_limits(&mut cur, &limits),
+            Err(Error::LimitExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn should_reject_arrays_too_long() {
+        let limits = Limits {
+            max_array_len: 2,
+            ..Limits::default()
+        };
+        let mut cur = Cursor::new(&b"*2\r\n:1\r\n:2\r\n"[..]);
+        assert!(Frame::check_with_limits(&mut cur, &limits).is_ok());
+        let mut cur = Cursor::new(&b"*3\r\n:1\r\n:2\r\n:3\r\n"[..]);
+        assert!(matches!(
+            Frame::check_with_limits(&mut cur, &limits),
+            Err(Error::LimitExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn should_not_trust_declared_array_length() {
+        // The declared length is within limits but much larger than the content:
+        // parsing must fail on the missing elements, not allocate for them.
+        let mut cur = Cursor::new(&b"*4000\r\n:1\r\n"[..]);
+        assert!(Frame::parse(&mut cur).is_err());
+    }
+
+    #[test]
+    fn should_reject_strings_with_end_of_frame() {
+        let mut bytes = BytesMut::new();
+        assert!(Frame::String("alice\r\n:42".to_owned())
+            .write(&mut bytes)
+            .is_err());
+        assert!(Frame::Error("oops\n".to_owned()).write(&mut bytes).is_err());
+        assert!(frame!["a\rb"].write(&mut bytes).is_err());
+        let mut cur = Cursor::new(&b"+ali\nce\r\n"[..]);
+        assert!(Frame::parse(&mut cur).is_err());
+    }
+
+    #[test]
+    fn should_encode_decode_bulk_frames() {
+        let frame = frame![Bytes::from_static(b"\0\r\n\xff"), Frame::Null, "after"];
+        let mut bytes = BytesMut::new();
+        frame.write(&mut bytes).unwrap();
+        // The bytes may hold an end of frame marker, even when received byte by byte.
+        let mut state = CheckState::default();
+        for len in 1..bytes.len() {
+            assert!(matches!(
+                Frame::check_incremental(&bytes[..len], &Limits::default(), &mut state),
+                Err(Error::Incomplete { .. })
+            ));
+        }
+        let len = Frame::check_incremental(&bytes, &Limits::default(), &mut state).unwrap();
+        assert_eq!(len, bytes.len());
+        let mut cur = Cursor::new(&bytes[..]);
+        let decoded = Frame::parse(&mut cur).unwrap();
+        assert_eq!(format!("{decoded:?}"), format!("{frame:?}"));
+
+        let mut cur = Cursor::new(&b"$3\r\nabcd\r\n"[..]);
+        assert!(matches!(
+            Frame::check(&mut cur),
+            Err(Error::UnexpectedBytes { .. })
+        ));
+    }
+
     #[test]
     fn should_encode_decode_a_string() {
         let frame = Frame::String("Hello World!".to_owned());
@@ -449,9 +1466,7 @@ mod tests {
 
     #[tokio::test]
     async fn should_encode_decode_an_array() {
-        let mut frame = Frame::array();
-        frame.push_integer(42).unwrap();
-        frame.push_string("Hello World!".to_owned()).unwrap();
+        let frame = frame![42i64, "Hello World!"];
         let mut bytes = BytesMut::new();
         frame.write(&mut bytes).unwrap();
         let mut cur = Cursor::new(&bytes[..]);
@@ -472,14 +1487,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn should_encode_elements_after_a_nested_array() {
+        let inner = Frame::Array(vec![Frame::Array(vec![Frame::UInt(1)]), Frame::UInt(2)]);
+        let frame = Frame::Array(vec![inner, Frame::String("after".to_owned())]);
+        let mut bytes = BytesMut::new();
+        frame.write(&mut bytes).unwrap();
+        assert_eq!(&bytes[..], b"*2\r\n*2\r\n*1\r\n:1\r\n:2\r\n+after\r\n");
+        let mut cur = Cursor::new(&bytes[..]);
+        Frame::check(&mut cur).unwrap();
+        assert_eq!(cur.position() as usize, bytes.len());
+
+        let limits = Limits {
+            max_depth: 2,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            frame.write_with_limits(&mut BytesMut::new(), &limits),
+            Err(Error::LimitExceeded { .. })
+        ));
+    }
+
     #[tokio::test]
     async fn should_encode_decode_a_recursive_array() {
-        let mut inner_frame = Frame::array();
-        inner_frame.push_integer(42).unwrap();
-        inner_frame.push_string("Inner String".to_owned()).unwrap();
-        let mut frame = Frame::array();
-        frame.push_string("Outer String".to_owned()).unwrap();
-        frame.push_frame(inner_frame).unwrap();
+        let frame = frame!["Outer String", frame![42i64, "Inner String"]];
         let mut bytes = BytesMut::new();
         frame.write(&mut bytes).unwrap();
         let mut cur = Cursor::new(&bytes[..]);
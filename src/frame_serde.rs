@@ -0,0 +1,763 @@
+//! Conversion of serde types to and from frames.
+//!
+//! Instead of writing `parse_frames` and `into_fields` by hand, a message can
+//! derive `Serialize` and `Deserialize`, and get its wire conversion from
+//! `impl_wire_message!`. Structs, tuples and sequences become arrays (the
+//! fields of a struct are positional, as in the hand written messages), maps
+//! become map frames, bytes become bulk frames, `None` and `()` become null
+//! frames, and `Some(v)` is `v`. Unit enum variants are strings, other variants
+//! are a map with a single entry, from the variant name to its content.
+//!
+//! Trailing fields of a struct missing from the frame take their default
+//! value if they are marked `#[serde(default)]`: this is how optional fields
+//! sent by newer nodes are added.
+use bytes::Bytes;
+use serde::de::value::StringDeserializer;
+use serde::de::{
+    self, Deserialize, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess,
+    Visitor,
+};
+use serde::ser::{self, Serialize};
+use std::fmt;
+use std::vec;
+
+use crate::Frame;
+
+/// Error type for the conversion of serde types
+#[derive(Debug)]
+pub enum Error {
+    /// The value cannot be represented by a frame
+    Serialize {
+        /// Error detail
+        detail: String,
+    },
+    /// The frame does not hold the expected value
+    Deserialize {
+        /// Error detail
+        detail: String,
+    },
+}
+
+/// Types which can be converted into a frame.
+pub trait FrameSerialize {
+    /// Convert the value into a frame.
+    fn to_frame(&self) -> Result<Frame, Error>;
+}
+
+/// Types which can be extracted from a frame.
+pub trait FrameDeserialize: Sized {
+    /// Extract the value from the frame.
+    fn from_frame(frame: Frame) -> Result<Self, Error>;
+}
+
+impl<T: Serialize> FrameSerialize for T {
+    fn to_frame(&self) -> Result<Frame, Error> {
+        self.serialize(Serializer)
+    }
+}
+
+impl<T: DeserializeOwned> FrameDeserialize for T {
+    fn from_frame(frame: Frame) -> Result<Self, Error> {
+        T::deserialize(Deserializer { frame })
+    }
+}
+
+/// Frames themselves map to the serde data model, so they can be sent in
+/// self-describing formats such as CBOR or MessagePack. Error frames have no
+/// representation.
+impl Serialize for Frame {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Frame::String(s) => serializer.serialize_str(s),
+            Frame::Error(_) => Err(ser::Error::custom("Error frames cannot be serialized")),
+            Frame::UInt(u) => serializer.serialize_u64(*u),
+            Frame::Int(i) => serializer.serialize_i64(*i),
+            Frame::Bool(b) => serializer.serialize_bool(*b),
+            Frame::Double(d) => serializer.serialize_f64(*d),
+            Frame::Bulk(b) => serializer.serialize_bytes(b),
+            Frame::Null => serializer.serialize_unit(),
+            Frame::Array(frames) => serializer.collect_seq(frames),
+            Frame::Map(entries) => serializer.collect_map(entries.iter().map(|(k, v)| (k, v))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Frame {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Frame, D::Error> {
+        deserializer.deserialize_any(FrameVisitor)
+    }
+}
+
+/// Builds a frame from any value of the serde data model.
+struct FrameVisitor;
+
+impl<'de> Visitor<'de> for FrameVisitor {
+    type Value = Frame;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a frame")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Frame, E> {
+        Ok(Frame::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Frame, E> {
+        Ok(Frame::Int(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Frame, E> {
+        Ok(Frame::UInt(v))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Frame, E> {
+        Ok(Frame::Double(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Frame, E> {
+        Ok(Frame::String(v.to_owned()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Frame, E> {
+        Ok(Frame::String(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Frame, E> {
+        Ok(Frame::Bulk(Bytes::copy_from_slice(v)))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Frame, E> {
+        Ok(Frame::Bulk(Bytes::from(v)))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Frame, E> {
+        Ok(Frame::Null)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Frame, E> {
+        Ok(Frame::Null)
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<Frame, D::Error> {
+        Frame::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Frame, A::Error> {
+        // The length is declared by the remote: it is not trusted for the allocation.
+        let mut frames = Vec::with_capacity(seq.size_hint().unwrap_or_default().min(64));
+        while let Some(frame) = seq.next_element()? {
+            frames.push(frame);
+        }
+        Ok(Frame::Array(frames))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Frame, A::Error> {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or_default().min(64));
+        while let Some(entry) = map.next_entry::<String, Frame>()? {
+            entries.push(entry);
+        }
+        Ok(Frame::Map(entries))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Serialize { detail } => write!(f, "Frame Serialization Error: {}", detail),
+            Error::Deserialize { detail } => write!(f, "Frame Deserialization Error: {}", detail),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Serialize {
+            detail: msg.to_string(),
+        }
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Deserialize {
+            detail: msg.to_string(),
+        }
+    }
+}
+
+/// Serializes a value into a frame.
+struct Serializer;
+
+/// Collects the elements of an array frame.
+struct SeqSerializer {
+    frames: Vec<Frame>,
+    // Name of the enum variant holding the array, if any.
+    variant: Option<&'static str>,
+}
+
+/// Collects the entries of a map frame.
+struct MapSerializer {
+    entries: Vec<(String, Frame)>,
+    key: Option<String>,
+}
+
+// A variant with content is a map with a single entry.
+fn variant(name: &'static str, frame: Frame) -> Frame {
+    Frame::Map(vec![(name.to_owned(), frame)])
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Frame;
+    type Error = Error;
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = SeqSerializer;
+    type SerializeTupleStruct = SeqSerializer;
+    type SerializeTupleVariant = SeqSerializer;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = SeqSerializer;
+    type SerializeStructVariant = SeqSerializer;
+
+    fn serialize_bool(self, v: bool) -> Result<Frame, Error> {
+        Ok(Frame::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Frame, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Frame, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Frame, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Frame, Error> {
+        Ok(Frame::Int(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Frame, Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Frame, Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Frame, Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Frame, Error> {
+        Ok(Frame::UInt(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Frame, Error> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Frame, Error> {
+        Ok(Frame::Double(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Frame, Error> {
+        Ok(Frame::String(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Frame, Error> {
+        Ok(Frame::String(v.to_owned()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Frame, Error> {
+        Ok(Frame::Bulk(Bytes::copy_from_slice(v)))
+    }
+
+    fn serialize_none(self) -> Result<Frame, Error> {
+        Ok(Frame::Null)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Frame, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Frame, Error> {
+        Ok(Frame::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Frame, Error> {
+        Ok(Frame::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Frame, Error> {
+        Ok(Frame::String(variant.to_owned()))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Frame, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Frame, Error> {
+        Ok(variant(name, value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer, Error> {
+        Ok(SeqSerializer {
+            frames: Vec::with_capacity(len.unwrap_or_default()),
+            variant: None,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SeqSerializer, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SeqSerializer, Error> {
+        Ok(SeqSerializer {
+            frames: Vec::with_capacity(len),
+            variant: Some(variant),
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<MapSerializer, Error> {
+        Ok(MapSerializer {
+            entries: Vec::with_capacity(len.unwrap_or_default()),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SeqSerializer, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SeqSerializer, Error> {
+        self.serialize_tuple_variant(name, index, variant, len)
+    }
+}
+
+impl SeqSerializer {
+    fn push<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.frames.push(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Frame, Error> {
+        let frame = Frame::Array(self.frames);
+        Ok(match self.variant {
+            Some(name) => variant(name, frame),
+            None => frame,
+        })
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = Frame;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Frame, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer {
+    type Ok = Frame;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Frame, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqSerializer {
+    type Ok = Frame;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Frame, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SeqSerializer {
+    type Ok = Frame;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Frame, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SeqSerializer {
+    type Ok = Frame;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Frame, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SeqSerializer {
+    type Ok = Frame;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Frame, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeMap for MapSerializer {
+    type Ok = Frame;
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        match key.serialize(Serializer)? {
+            Frame::String(key) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            frame => Err(Error::Serialize {
+                detail: format!("Map keys must be strings, got {frame:?}"),
+            }),
+        }
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self.key.take().ok_or_else(|| Error::Serialize {
+            detail: "Map value without a key".to_owned(),
+        })?;
+        self.entries.push((key, value.serialize(Serializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Frame, Error> {
+        Ok(Frame::Map(self.entries))
+    }
+}
+
+/// Deserializes a value from a frame.
+struct Deserializer {
+    frame: Frame,
+}
+
+/// Hands out the elements of an array frame.
+struct SeqDeserializer {
+    frames: vec::IntoIter<Frame>,
+}
+
+/// Hands out the entries of a map frame.
+struct MapDeserializer {
+    entries: vec::IntoIter<(String, Frame)>,
+    value: Option<Frame>,
+}
+
+/// Hands out the content of an enum variant.
+struct VariantDeserializer {
+    name: String,
+    content: Frame,
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.frame {
+            Frame::String(s) => visitor.visit_string(s),
+            Frame::Error(s) => Err(Error::Deserialize {
+                detail: format!("Unexpected error frame: {s}"),
+            }),
+            Frame::UInt(u) => visitor.visit_u64(u),
+            Frame::Int(i) => visitor.visit_i64(i),
+            Frame::Bool(b) => visitor.visit_bool(b),
+            Frame::Double(d) => visitor.visit_f64(d),
+            Frame::Bulk(b) => visitor.visit_byte_buf(b.to_vec()),
+            Frame::Null => visitor.visit_unit(),
+            Frame::Array(frames) => visitor.visit_seq(SeqDeserializer {
+                frames: frames.into_iter(),
+            }),
+            Frame::Map(entries) => visitor.visit_map(MapDeserializer {
+                entries: entries.into_iter(),
+                value: None,
+            }),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.frame {
+            Frame::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.frame {
+            Frame::String(name) => {
+                let name: StringDeserializer<Error> = name.into_deserializer();
+                visitor.visit_enum(name)
+            }
+            Frame::Map(entries) if entries.len() == 1 => {
+                let (name, content) = entries.into_iter().next().expect("one entry");
+                visitor.visit_enum(VariantDeserializer { name, content })
+            }
+            frame => Err(Error::Deserialize {
+                detail: format!("Expected enum variant, got {frame:?}"),
+            }),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for SeqDeserializer {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        self.frames
+            .next()
+            .map(|frame| seed.deserialize(Deserializer { frame }))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.frames.len())
+    }
+}
+
+impl<'de> de::MapAccess<'de> for MapDeserializer {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                let key: StringDeserializer<Error> = key.into_deserializer();
+                seed.deserialize(key).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let frame = self.value.take().ok_or_else(|| Error::Deserialize {
+            detail: "Map value without a key".to_owned(),
+        })?;
+        seed.deserialize(Deserializer { frame })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for VariantDeserializer {
+    type Error = Error;
+    type Variant = Deserializer;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Deserializer), Error> {
+        let name: StringDeserializer<Error> = self.name.into_deserializer();
+        let variant = seed.deserialize(name)?;
+        Ok((
+            variant,
+            Deserializer {
+                frame: self.content,
+            },
+        ))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Deserializer {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        match self.frame {
+            Frame::Null => Ok(()),
+            frame => Err(Error::Deserialize {
+                detail: format!("Expected unit variant, got {frame:?}"),
+            }),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+    use uuid::Uuid;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Kind {
+        Seed,
+        Rtt(f64),
+        Tagged { tags: Vec<String> },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Sample {
+        id: Uuid,
+        addr: SocketAddr,
+        count: u32,
+        offset: i64,
+        alive: bool,
+        data: Bytes,
+        kinds: Vec<Kind>,
+        scores: BTreeMap<String, f64>,
+        #[serde(default)]
+        label: Option<String>,
+    }
+
+    #[test]
+    fn should_convert_to_and_from_frames() {
+        let sample = Sample {
+            id: Uuid::new_v4(),
+            addr: "[::1]:8090".parse().unwrap(),
+            count: 3,
+            offset: -7,
+            alive: true,
+            data: Bytes::from_static(b"\r\n"),
+            kinds: vec![
+                Kind::Seed,
+                Kind::Rtt(0.5),
+                Kind::Tagged {
+                    tags: vec!["eu".to_owned()],
+                },
+            ],
+            scores: BTreeMap::from([("alice".to_owned(), 1.5)]),
+            label: Some("bob".to_owned()),
+        };
+        let frame = sample.to_frame().unwrap();
+        assert!(matches!(&frame, Frame::Array(fields) if fields.len() == 9));
+        assert_eq!(Sample::from_frame(frame).unwrap(), sample);
+    }
+
+    #[test]
+    fn should_default_missing_trailing_fields() {
+        let frame = Frame::Array(vec![
+            Frame::String(Uuid::nil().to_string()),
+            Frame::String("127.0.0.1:8090".to_owned()),
+            Frame::UInt(3),
+            Frame::UInt(7),
+            Frame::Bool(false),
+            Frame::Bulk(Bytes::new()),
+            Frame::Array(vec![]),
+            Frame::map(),
+        ]);
+        let sample = Sample::from_frame(frame).unwrap();
+        assert_eq!(sample.offset, 7);
+        assert_eq!(sample.label, None);
+        assert!(matches!(
+            Sample::from_frame(Frame::Array(vec![Frame::UInt(1)])),
+            Err(Error::Deserialize { .. })
+        ));
+    }
+}
@@ -0,0 +1,17 @@
+//! Hexadecimal encoding, for binary values carried in text.
+
+/// Decode a hexadecimal string, or None if it is not one.
+pub(crate) fn decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Encode bytes in lowercase hexadecimal.
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
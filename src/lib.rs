@@ -3,7 +3,11 @@
 
 pub mod frame;
 pub use frame::Frame;
+pub mod binary;
 pub mod codec;
+pub mod document;
+#[cfg(feature = "proto")]
+pub mod proto;
 pub use codec::FrameCodec;
 pub mod message;
 pub use message::Message;
@@ -11,5 +15,8 @@ pub mod error;
 pub use error::Error;
 pub mod parse;
 pub use parse::Parse;
+pub mod frame_serde;
+pub use frame_serde::{FrameDeserialize, FrameSerialize};
 pub mod config;
+pub(crate) mod hex;
 pub mod network;
@@ -0,0 +1,74 @@
+//! Authentication messages
+//!
+//! An incoming peer which authenticates its remotes answers the connection
+//! request with a challenge. The remote answers with the HMAC of the nonce,
+//! computed with the secret shared by the nodes of the network. The incoming
+//! peer then proves it knows the secret too, with an answer to the signature
+//! of the connection request, before its connection response.
+use bytes::Bytes;
+
+use super::error::Error;
+use super::WireMessage;
+use crate::Frame;
+use crate::Parse;
+
+/// Challenge sent in place of a connection response
+#[derive(Debug)]
+pub struct AuthChallenge {
+    /// Random bytes, chosen by the incoming peer
+    pub nonce: Bytes,
+}
+
+/// Answer to a challenge, or to the connection request
+#[derive(Debug)]
+pub struct AuthResponse {
+    /// HMAC of the nonce, or of the signature of the connection request,
+    /// empty if the node has no secret
+    pub mac: Bytes,
+}
+
+impl AuthChallenge {
+    /// Creates a new message
+    pub fn new(nonce: Bytes) -> AuthChallenge {
+        AuthChallenge { nonce }
+    }
+}
+
+impl AuthResponse {
+    /// Creates a new message
+    pub fn new(mac: Bytes) -> AuthResponse {
+        AuthResponse { mac }
+    }
+}
+
+impl WireMessage for AuthChallenge {
+    const TAG: &'static str = "AUTH_CHAL";
+    const ID: u64 = 16;
+
+    /// Extract an Auth Challenge message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<AuthChallenge, Error> {
+        let nonce = parse.next_bytes()?;
+        Ok(AuthChallenge { nonce })
+    }
+
+    /// The Auth Challenge fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![self.nonce])
+    }
+}
+
+impl WireMessage for AuthResponse {
+    const TAG: &'static str = "AUTH_RESP";
+    const ID: u64 = 17;
+
+    /// Extract an Auth Response message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<AuthResponse, Error> {
+        let mac = parse.next_bytes()?;
+        Ok(AuthResponse { mac })
+    }
+
+    /// The Auth Response fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![self.mac])
+    }
+}
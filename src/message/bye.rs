@@ -0,0 +1,91 @@
+//! Goodbye
+//!
+//! Sent by a peer closing its connection, so that the remote tears down its
+//! side right away, instead of waiting for a heartbeat timeout.
+use std::fmt;
+
+use super::error::Error;
+use super::WireMessage;
+use crate::Frame;
+use crate::Parse;
+
+/// Why a peer closes its connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// The node is shutting down, or was asked to close the connection.
+    Shutdown,
+    /// The remote did not answer in time.
+    Timeout,
+    /// The peer failed to process a command.
+    Error,
+    /// A code this node doesn't know, from a newer node.
+    Other(u64),
+}
+
+impl Reason {
+    /// The code sent on the wire.
+    pub fn code(self) -> u64 {
+        match self {
+            Reason::Shutdown => 0,
+            Reason::Timeout => 1,
+            Reason::Error => 2,
+            Reason::Other(code) => code,
+        }
+    }
+
+    /// The reason for a code received from the wire.
+    pub fn from_code(code: u64) -> Reason {
+        match code {
+            0 => Reason::Shutdown,
+            1 => Reason::Timeout,
+            2 => Reason::Error,
+            code => Reason::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Shutdown => write!(f, "shutdown"),
+            Reason::Timeout => write!(f, "timeout"),
+            Reason::Error => write!(f, "error"),
+            Reason::Other(code) => write!(f, "code {code}"),
+        }
+    }
+}
+
+/// The last message sent on a connection.
+#[derive(Debug)]
+pub struct Bye {
+    /// Why the connection is closed
+    pub reason: Reason,
+}
+
+impl Bye {
+    /// Creates a new message
+    pub fn new(reason: Reason) -> Bye {
+        Bye { reason }
+    }
+
+    /// Accessor for the reason
+    pub fn reason(&self) -> Reason {
+        self.reason
+    }
+}
+
+impl WireMessage for Bye {
+    const TAG: &'static str = "BYE";
+    const ID: u64 = 13;
+
+    /// Extract a Bye message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<Bye, Error> {
+        let reason = Reason::from_code(parse.next_unsigned()?);
+        Ok(Bye { reason })
+    }
+
+    /// The Bye fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![self.reason.code()])
+    }
+}
@@ -0,0 +1,60 @@
+//! Chunk
+//!
+//! Carries a part of a frame too large to be sent at once. The frame is
+//! encoded in the binary framing, split into chunks sharing a stream id, and
+//! reassembled by the remote once it receives the last chunk.
+use bytes::Bytes;
+
+use super::error::Error;
+use super::WireMessage;
+use crate::Frame;
+use crate::Parse;
+
+/// Part of a large frame
+#[derive(Debug)]
+pub struct Chunk {
+    /// Id of the stream, unique among the streams of a connection.
+    pub stream: u64,
+    /// Position of the chunk in the stream, starting at 0.
+    pub seq: u64,
+    /// True for the last chunk of the stream.
+    pub last: bool,
+    /// Bytes of the encoded frame
+    pub data: Bytes,
+}
+
+impl Chunk {
+    /// Creates a new message
+    pub fn new(stream: u64, seq: u64, last: bool, data: Bytes) -> Chunk {
+        Chunk {
+            stream,
+            seq,
+            last,
+            data,
+        }
+    }
+}
+
+impl WireMessage for Chunk {
+    const TAG: &'static str = "CHUNK";
+    const ID: u64 = 12;
+
+    /// Extract a Chunk message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<Chunk, Error> {
+        let stream = parse.next_unsigned()?;
+        let seq = parse.next_unsigned()?;
+        let last = parse.next_bool()?;
+        let data = parse.next_bytes()?;
+        Ok(Chunk {
+            stream,
+            seq,
+            last,
+            data,
+        })
+    }
+
+    /// The Chunk fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![self.stream, self.seq, self.last, self.data])
+    }
+}
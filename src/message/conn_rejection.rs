@@ -1,52 +1,86 @@
 //! Connection Rejection
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
 
-use super::error::Error;
-use crate::Frame;
-use crate::Parse;
+/// Why a node refuses a connection. On the wire, the reason is a snake case
+/// string, and reasons this node doesn't know are read as `Other`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    /// The node has as many incoming connections as it accepts.
+    TooManyConnections,
+    /// The remote address is not allowed to connect.
+    Banned,
+    /// The remote belongs to another network.
+    NetworkMismatch,
+    /// The remote could not prove its identity, or did not answer the
+    /// authentication challenge.
+    Unauthenticated,
+    /// The remote did not answer the proof-of-work challenge.
+    InsufficientWork,
+    /// Any other reason.
+    #[serde(other)]
+    Other,
+}
 
-/// Get the value of a key
-#[derive(Debug)]
+impl RejectReason {
+    /// The name of the reason, as sent on the wire.
+    pub fn name(self) -> &'static str {
+        match self {
+            RejectReason::TooManyConnections => "too_many_connections",
+            RejectReason::Banned => "banned",
+            RejectReason::NetworkMismatch => "network_mismatch",
+            RejectReason::Unauthenticated => "unauthenticated",
+            RejectReason::InsufficientWork => "insufficient_work",
+            RejectReason::Other => "other",
+        }
+    }
+
+    /// The reason with the given name, `Other` if unknown.
+    pub fn from_name(name: &str) -> RejectReason {
+        match name {
+            "too_many_connections" => RejectReason::TooManyConnections,
+            "banned" => RejectReason::Banned,
+            "network_mismatch" => RejectReason::NetworkMismatch,
+            "unauthenticated" => RejectReason::Unauthenticated,
+            "insufficient_work" => RejectReason::InsufficientWork,
+            _ => RejectReason::Other,
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Sent by a node refusing a connection, in place of a connection response.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ConnRejection {
     /// Id of the peer issuing a connection rejection
-    pub id: String,
+    pub id: Uuid,
 
-    /// Reason for the rejection. This could become an enum,
-    /// like 'banned', 'duplicate'
-    pub reason: String,
+    /// Reason for the rejection.
+    pub reason: RejectReason,
 }
 
 impl ConnRejection {
     /// Creates a new message
-    pub fn new(id: impl ToString, reason: impl ToString) -> ConnRejection {
-        ConnRejection {
-            id: id.to_string(),
-            reason: reason.to_string(),
-        }
+    pub fn new(id: Uuid, reason: RejectReason) -> ConnRejection {
+        ConnRejection { id, reason }
     }
 
     /// Accessor for the id
-    pub fn id(&self) -> &str {
-        &self.id
+    pub fn id(&self) -> Uuid {
+        self.id
     }
 
     /// Accessor for the reason
-    pub fn reason(&self) -> &str {
-        &self.reason
-    }
-
-    /// Extract a ConnRejection message from the parse.
-    pub fn parse_frames(parse: &mut Parse) -> Result<ConnRejection, Error> {
-        let id = parse.next_string()?;
-        let reason = parse.next_string()?;
-        Ok(ConnRejection { id, reason })
-    }
-
-    /// Convert the Connection Rejection into a frame
-    pub fn into_frame(self) -> Result<Frame, Error> {
-        let mut frame = Frame::array();
-        frame.push_string(String::from("CONN_REJECT"))?;
-        frame.push_string(self.id)?;
-        frame.push_string(self.reason)?;
-        Ok(frame)
+    pub fn reason(&self) -> RejectReason {
+        self.reason
     }
 }
+
+crate::impl_wire_message!(ConnRejection, 3, "CONN_REJECT");
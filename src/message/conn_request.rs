@@ -1,6 +1,26 @@
 //! Connection Request
+//!
+//! A signed request carries a random nonce and the time it was sent, which
+//! the signature covers. The accepting node refuses requests which are too
+//! old, or whose nonce it has already seen, so that a captured request
+//! cannot be replayed. The response is signed after the request, nonce
+//! included, so it only matches this request.
+//!
+//! The request also names the network of the sender, so that nodes of
+//! unrelated deployments don't connect to each other. It follows the nonce,
+//! and is left out for the default network.
+//!
+//! When its frames are not encrypted, the sender adds an ephemeral public
+//! key, after the network, from which both sides derive the session keys.
+use chrono::Utc;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::net::SocketAddr;
+use uuid::Uuid;
 
 use super::error::Error;
+use super::WireMessage;
+use crate::codec::Compression;
+use crate::hex;
 use crate::Frame;
 use crate::Parse;
 
@@ -8,25 +28,110 @@ use crate::Parse;
 #[derive(Debug)]
 pub struct ConnRequest {
     /// Id of the OutAlive peer's controller
-    pub id: String,
+    pub id: Uuid,
     /// label of the OutAlive peer's controller
     pub label: String,
     /// address of the OutAlive peer's controller
     /// This can be used by the in peer to dial
     /// back in the out peer, if the connection
     /// is lost,
-    pub address: String,
+    pub address: SocketAddr,
+    /// Compression offered by the OutAlive peer. Older nodes don't send it.
+    pub compression: Option<Compression>,
+    /// Public key of the OutAlive peer's controller, from which its id is
+    /// derived. Older nodes don't send it.
+    pub public_key: Option<Vec<u8>>,
+    /// Signature of the transcript, with the key of the OutAlive peer's controller.
+    pub signature: Option<Vec<u8>>,
+    /// Random number, chosen for this request only.
+    pub nonce: Option<u64>,
+    /// Time (micros) when the request was sent.
+    pub timestamp: Option<i64>,
+    /// Id of the network of the OutAlive peer's controller, empty for the
+    /// default network.
+    pub network: String,
+    /// Ephemeral X25519 public key of the OutAlive peer, from which the
+    /// session keys are derived. Only sent if the frames are not encrypted.
+    pub key_share: Option<Vec<u8>>,
 }
 
 impl ConnRequest {
     /// Creates a new message
-    pub fn new(id: String, label: String, address: String) -> ConnRequest {
-        ConnRequest { id, label, address }
+    pub fn new(
+        id: Uuid,
+        label: String,
+        address: SocketAddr,
+        compression: Option<Compression>,
+    ) -> ConnRequest {
+        ConnRequest {
+            id,
+            label,
+            address,
+            compression,
+            public_key: None,
+            signature: None,
+            nonce: None,
+            timestamp: None,
+            network: String::new(),
+            key_share: None,
+        }
+    }
+
+    /// Set the id of the network of the sender, which the transcript includes.
+    pub fn with_network(mut self, network: String) -> ConnRequest {
+        self.network = network;
+        self
+    }
+
+    /// Add the ephemeral public key of the sender, which the transcript includes.
+    pub fn with_key_share(mut self, key_share: Vec<u8>) -> ConnRequest {
+        self.key_share = Some(key_share);
+        self
+    }
+
+    /// Add the public key of the sender, which the transcript includes.
+    pub fn with_public_key(mut self, public_key: Vec<u8>) -> ConnRequest {
+        self.public_key = Some(public_key);
+        self
+    }
+
+    /// Add a new nonce and the current time, which the transcript includes.
+    pub fn with_nonce(mut self) -> ConnRequest {
+        let mut nonce = [0u8; 8];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("system random number generator");
+        self.nonce = Some(u64::from_be_bytes(nonce));
+        self.timestamp = Some(Utc::now().timestamp_micros());
+        self
+    }
+
+    /// Bytes signed by the sender: every field but the signature.
+    pub fn transcript(&self) -> Vec<u8> {
+        let mut transcript = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            Self::TAG,
+            self.id,
+            self.label,
+            self.address,
+            self.compression.map_or("", |c| c.name()),
+            hex::encode(self.public_key.as_deref().unwrap_or_default())
+        );
+        if let Some((nonce, timestamp)) = self.nonce.zip(self.timestamp) {
+            transcript.push_str(&format!("\n{nonce}\n{timestamp}"));
+        }
+        if !self.network.is_empty() || self.key_share.is_some() {
+            transcript.push_str(&format!("\n{}", self.network));
+        }
+        if let Some(key_share) = &self.key_share {
+            transcript.push_str(&format!("\n{}", hex::encode(key_share)));
+        }
+        transcript.into_bytes()
     }
 
     /// Accessor for the key
-    pub fn id(&self) -> &str {
-        &self.id
+    pub fn id(&self) -> Uuid {
+        self.id
     }
 
     /// Accessor for the label
@@ -35,26 +140,90 @@ impl ConnRequest {
     }
 
     /// Accessor for the address
-    pub fn address(&self) -> &str {
-        &self.address
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Accessor for the offered compression
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
     }
+}
+
+impl WireMessage for ConnRequest {
+    const TAG: &'static str = "CONN_REQ";
+    const ID: u64 = 1;
 
     /// Extract a ConnRequest message from the parse.
-    pub fn parse_frames(parse: &mut Parse) -> Result<ConnRequest, Error> {
-        let id = parse.next_string()?;
+    fn parse_frames(parse: &mut Parse) -> Result<ConnRequest, Error> {
+        let id = parse.next_uuid()?;
         let label = parse.next_string()?;
-        let address = parse.next_string()?;
-        Ok(ConnRequest { id, label, address })
-    }
-
-    /// Convert the ConnRequest into a frame
-    pub fn into_frame(self) -> Result<Frame, Error> {
-        let ConnRequest { id, label, address } = self;
-        let mut frame = Frame::array();
-        frame.push_string(String::from("CONN_REQ"))?;
-        frame.push_string(id)?;
-        frame.push_string(label)?;
-        frame.push_string(address)?;
-        Ok(frame)
+        let address = parse.next_addr()?;
+        // A compression we don't know is not offered.
+        let compression = parse
+            .next_string_opt()?
+            .and_then(|compression| Compression::from_name(&compression));
+        // Older nodes don't sign their requests.
+        let public_key = parse.next_string_opt()?.and_then(|key| hex::decode(&key));
+        let signature = parse
+            .next_string_opt()?
+            .and_then(|signature| hex::decode(&signature));
+        let nonce = parse.next_unsigned_opt()?;
+        let timestamp = parse.next_integer_opt()?;
+        let network = parse.next_string_opt()?.unwrap_or_default();
+        let key_share = parse.next_string_opt()?.and_then(|key| hex::decode(&key));
+        Ok(ConnRequest {
+            id,
+            label,
+            address,
+            compression,
+            public_key,
+            signature,
+            nonce,
+            timestamp,
+            network,
+            key_share,
+        })
+    }
+
+    /// The ConnRequest fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        let ConnRequest {
+            id,
+            label,
+            address,
+            compression,
+            public_key,
+            signature,
+            nonce,
+            timestamp,
+            network,
+            key_share,
+        } = self;
+        let signed = public_key.zip(signature);
+        let mut fields = vec![
+            id.to_string().into(),
+            label.into(),
+            address.to_string().into(),
+        ];
+        // The compression is left empty to send the signature.
+        if compression.is_some() || signed.is_some() {
+            fields.push(compression.map_or("", |c| c.name()).into());
+        }
+        if let Some((public_key, signature)) = signed {
+            fields.push(hex::encode(&public_key).into());
+            fields.push(hex::encode(&signature).into());
+            if let Some((nonce, timestamp)) = nonce.zip(timestamp) {
+                fields.push(nonce.into());
+                fields.push(timestamp.into());
+                if !network.is_empty() || key_share.is_some() {
+                    fields.push(network.into());
+                }
+                if let Some(key_share) = key_share {
+                    fields.push(hex::encode(&key_share).into());
+                }
+            }
+        }
+        Ok(fields.into())
     }
 }
@@ -1,6 +1,11 @@
 //! Connection Response
+use std::net::SocketAddr;
+use uuid::Uuid;
 
 use super::error::Error;
+use super::WireMessage;
+use crate::codec::Compression;
+use crate::hex;
 use crate::Frame;
 use crate::Parse;
 
@@ -8,20 +13,78 @@ use crate::Parse;
 #[derive(Debug)]
 pub struct ConnResponse {
     /// Id of the InAlive peer.
-    pub id: String,
+    pub id: Uuid,
     /// label of the InAlive peer.
     pub label: String,
+    /// Address the connection request came from, as seen by the InAlive peer.
+    /// Older nodes don't send it.
+    pub observed: Option<SocketAddr>,
+    /// Compression accepted by the InAlive peer, among the one offered.
+    pub compression: Option<Compression>,
+    /// Public key of the InAlive peer, from which its id is derived.
+    /// Older nodes don't send it.
+    pub public_key: Option<Vec<u8>>,
+    /// Signature of the transcript, following the signature of the request,
+    /// with the key of the InAlive peer.
+    pub signature: Option<Vec<u8>>,
+    /// Ephemeral X25519 public key of the InAlive peer, from which the
+    /// session keys are derived. Only sent in answer to a request with one.
+    pub key_share: Option<Vec<u8>>,
 }
 
 impl ConnResponse {
     /// Creates a new message
-    pub fn new(id: String, label: String) -> ConnResponse {
-        ConnResponse { id, label }
+    pub fn new(
+        id: Uuid,
+        label: String,
+        observed: Option<SocketAddr>,
+        compression: Option<Compression>,
+    ) -> ConnResponse {
+        ConnResponse {
+            id,
+            label,
+            observed,
+            compression,
+            public_key: None,
+            signature: None,
+            key_share: None,
+        }
+    }
+
+    /// Add the public key of the sender, which the transcript includes.
+    pub fn with_public_key(mut self, public_key: Vec<u8>) -> ConnResponse {
+        self.public_key = Some(public_key);
+        self
+    }
+
+    /// Add the ephemeral public key of the sender, which the transcript includes.
+    pub fn with_key_share(mut self, key_share: Vec<u8>) -> ConnResponse {
+        self.key_share = Some(key_share);
+        self
+    }
+
+    /// Bytes signed by the sender: every field but the signature.
+    pub fn transcript(&self) -> Vec<u8> {
+        let mut transcript = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            Self::TAG,
+            self.id,
+            self.label,
+            self.observed
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            self.compression.map_or("", |c| c.name()),
+            hex::encode(self.public_key.as_deref().unwrap_or_default())
+        );
+        if let Some(key_share) = &self.key_share {
+            transcript.push_str(&format!("\n{}", hex::encode(key_share)));
+        }
+        transcript.into_bytes()
     }
 
     /// Accessor for the key
-    pub fn id(&self) -> &str {
-        &self.id
+    pub fn id(&self) -> Uuid {
+        self.id
     }
 
     /// Accessor for the label
@@ -29,20 +92,78 @@ impl ConnResponse {
         &self.label
     }
 
+    /// Accessor for the observed address
+    pub fn observed(&self) -> Option<SocketAddr> {
+        self.observed
+    }
+
+    /// Accessor for the accepted compression
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+}
+
+impl WireMessage for ConnResponse {
+    const TAG: &'static str = "CONN_RESP";
+    const ID: u64 = 2;
+
     /// Extract a Set message from the parse.
-    pub fn parse_frames(parse: &mut Parse) -> Result<ConnResponse, Error> {
-        let id = parse.next_string()?;
+    fn parse_frames(parse: &mut Parse) -> Result<ConnResponse, Error> {
+        let id = parse.next_uuid()?;
         let label = parse.next_string()?;
-        Ok(ConnResponse { id, label })
+        // A trailing field which is not an address comes from a newer node,
+        // and is ignored.
+        let observed = parse
+            .next_string_opt()?
+            .and_then(|observed| observed.parse().ok());
+        let compression = parse
+            .next_string_opt()?
+            .and_then(|compression| Compression::from_name(&compression));
+        // Older nodes don't sign their responses.
+        let public_key = parse.next_string_opt()?.and_then(|key| hex::decode(&key));
+        let signature = parse
+            .next_string_opt()?
+            .and_then(|signature| hex::decode(&signature));
+        let key_share = parse.next_string_opt()?.and_then(|key| hex::decode(&key));
+        Ok(ConnResponse {
+            id,
+            label,
+            observed,
+            compression,
+            public_key,
+            signature,
+            key_share,
+        })
     }
 
-    /// Convert the Connection Response into a frame
-    pub fn into_frame(self) -> Result<Frame, Error> {
-        let ConnResponse { id, label } = self;
-        let mut frame = Frame::array();
-        frame.push_string(String::from("CONN_RESP"))?;
-        frame.push_string(id)?;
-        frame.push_string(label)?;
-        Ok(frame)
+    /// The Connection Response fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        let ConnResponse {
+            id,
+            label,
+            observed,
+            compression,
+            public_key,
+            signature,
+            key_share,
+        } = self;
+        let signed = public_key.zip(signature);
+        let mut fields = vec![id.to_string().into(), label.into()];
+        // Absent fields are left empty to send the fields after them.
+        if observed.is_some() || compression.is_some() || signed.is_some() {
+            let observed = observed.map(|addr| addr.to_string()).unwrap_or_default();
+            fields.push(observed.into());
+        }
+        if compression.is_some() || signed.is_some() {
+            fields.push(compression.map_or("", |c| c.name()).into());
+        }
+        if let Some((public_key, signature)) = signed {
+            fields.push(hex::encode(&public_key).into());
+            fields.push(hex::encode(&signature).into());
+            if let Some(key_share) = key_share {
+                fields.push(hex::encode(&key_share).into());
+            }
+        }
+        Ok(fields.into())
     }
 }
@@ -1,6 +1,7 @@
 //! Contactection Request
 
 use super::error::Error;
+use super::WireMessage;
 use crate::Frame;
 use crate::Parse;
 
@@ -8,17 +9,17 @@ use crate::Parse;
 #[derive(Debug)]
 pub struct ContactRequest;
 
-impl ContactRequest {
+impl WireMessage for ContactRequest {
+    const TAG: &'static str = "CTCT_REQ";
+    const ID: u64 = 6;
+
     /// Extract a ContactRequest message from the parse.
-    pub fn parse_frames(_parse: &mut Parse) -> Result<ContactRequest, Error> {
+    fn parse_frames(_parse: &mut Parse) -> Result<ContactRequest, Error> {
         Ok(ContactRequest)
     }
 
-    /// Convert the ContactRequest into a frame
-    pub fn into_frame(self) -> Result<Frame, Error> {
-        let ContactRequest = self;
-        let mut frame = Frame::array();
-        frame.push_string(String::from("CTCT_REQ"))?;
-        Ok(frame)
+    /// A ContactRequest has no fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![])
     }
 }
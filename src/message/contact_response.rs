@@ -1,8 +1,23 @@
 //! Contact Response
+//!
+//! The addresses known to the sender, followed by optional trailing fields:
+//! the tags of each address, then, for each address, the id and label of the
+//! node there, the last time (micros) the sender heard from it, and how the
+//! sender knows it. The tags are always sent when the contacts are, so that
+//! older nodes still read the addresses and the tags.
+//!
+//! A sender with contacts may sign the list with its node key, so that it
+//! answers for every entry.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::net::SocketAddr;
-use std::string::ToString;
+use uuid::Uuid;
 
 use super::error::Error;
+use super::WireMessage;
+use crate::hex;
+use crate::parse;
 use crate::Frame;
 use crate::Parse;
 
@@ -10,42 +25,209 @@ use crate::Parse;
 #[derive(Debug)]
 pub struct ContactResponse {
     /// Id of the InAlive peer.
-    pub addrs: Vec<String>,
+    pub addrs: Vec<SocketAddr>,
+    /// Tags of each address, or nothing if the sender knows no tags
+    /// (or is an older node).
+    pub tags: Vec<BTreeMap<String, String>>,
+    /// Node at each address, or nothing if the sender does not send them
+    /// (or is an older node).
+    pub contacts: Vec<Contact>,
+    /// Signature of the sender over the transcript, if it signed the list.
+    pub signature: Option<Vec<u8>>,
+}
+
+/// A node known to the sender of a contact response.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    /// Id of the node.
+    pub id: Uuid,
+    /// Label of the node.
+    pub label: String,
+    /// Last time (micros) the sender heard from the node.
+    pub last_seen: i64,
+    /// How the sender knows the node.
+    pub provenance: Provenance,
+}
+
+/// How the sender of a contact knows the node. On the wire, the provenance is
+/// a snake case string, and those this node doesn't know are read as
+/// `Incoming`, the weaker claim.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Provenance {
+    /// The sender dialed the node at this address.
+    Outgoing,
+    /// The node connected to the sender, and advertised this address.
+    Incoming,
+}
+
+impl Provenance {
+    /// The name of the provenance, as sent on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provenance::Outgoing => "outgoing",
+            Provenance::Incoming => "incoming",
+        }
+    }
+
+    /// The provenance with the given name, `Incoming` if unknown.
+    pub fn from_name(name: &str) -> Provenance {
+        match name {
+            "outgoing" => Provenance::Outgoing,
+            _ => Provenance::Incoming,
+        }
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
 }
 
 impl ContactResponse {
     /// Creates a new message
-    pub fn new(addrs: Vec<SocketAddr>) -> ContactResponse {
+    pub fn new(addrs: Vec<SocketAddr>, tags: Vec<BTreeMap<String, String>>) -> ContactResponse {
         ContactResponse {
-            addrs: addrs.into_iter().map(|addr| addr.to_string()).collect(),
+            addrs,
+            tags,
+            contacts: Vec::new(),
+            signature: None,
         }
     }
 
+    /// Adds the node at each address.
+    pub fn with_contacts(mut self, contacts: Vec<Contact>) -> ContactResponse {
+        self.contacts = contacts;
+        self
+    }
+
     /// Accessor for the key
-    pub fn addrs(&self) -> &[String] {
+    pub fn addrs(&self) -> &[SocketAddr] {
         &self.addrs
     }
 
+    /// Accessor for the tags
+    pub fn tags(&self) -> &[BTreeMap<String, String>] {
+        &self.tags
+    }
+
+    /// Accessor for the contacts
+    pub fn contacts(&self) -> &[Contact] {
+        &self.contacts
+    }
+
+    /// Bytes signed by the sender: every entry, with its tags and contact.
+    pub fn transcript(&self) -> Vec<u8> {
+        let mut transcript = String::from(Self::TAG);
+        for (i, addr) in self.addrs.iter().enumerate() {
+            transcript.push_str(&format!("\n{addr}"));
+            if let Some(tags) = self.tags.get(i) {
+                transcript.push_str(&format!(" {}", encode_tags(tags)));
+            }
+            if let Some(contact) = self.contacts.get(i) {
+                transcript.push_str(&format!(
+                    " {} {} {} {}",
+                    contact.id, contact.label, contact.last_seen, contact.provenance
+                ));
+            }
+        }
+        transcript.into_bytes()
+    }
+}
+
+impl WireMessage for ContactResponse {
+    const TAG: &'static str = "CTCT_RESP";
+    const ID: u64 = 7;
+
     /// Extract a Set message from the parse.
-    pub fn parse_frames(parse: &mut Parse) -> Result<ContactResponse, Error> {
+    fn parse_frames(parse: &mut Parse) -> Result<ContactResponse, Error> {
         let count = parse.next_unsigned()? as usize;
         let mut addrs = Vec::new();
         for _ in 0..count {
-            let addr = parse.next_string().unwrap();
+            let addr = parse.next_addr()?;
             addrs.push(addr);
         }
-        Ok(ContactResponse { addrs })
+        // The tags are optional trailing fields, one for each address.
+        let mut tags = Vec::new();
+        if parse.remaining() > 0 {
+            for _ in 0..count {
+                tags.push(decode_tags(&parse.next_string()?)?);
+            }
+        }
+        let mut contacts = Vec::new();
+        if parse.remaining() > 0 {
+            for _ in 0..count {
+                let id = parse.next_uuid()?;
+                let label = parse.next_string()?;
+                let last_seen = parse.next_integer()?;
+                let provenance = Provenance::from_name(&parse.next_string()?);
+                contacts.push(Contact {
+                    id,
+                    label,
+                    last_seen,
+                    provenance,
+                });
+            }
+        }
+        let signature = parse
+            .next_string_opt()?
+            .and_then(|signature| hex::decode(&signature));
+        Ok(ContactResponse {
+            addrs,
+            tags,
+            contacts,
+            signature,
+        })
     }
 
-    /// Convert the Contact Response into a frame
-    pub fn into_frame(self) -> Result<Frame, Error> {
-        let ContactResponse { addrs } = self;
-        let mut frame = Frame::array();
-        frame.push_string(String::from("CTCT_RESP"))?;
-        frame.push_unsigned(addrs.len().try_into().unwrap())?;
-        addrs.into_iter().for_each(|addr| {
-            frame.push_string(addr).unwrap();
-        });
-        Ok(frame)
+    /// The Contact Response fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        let ContactResponse {
+            addrs,
+            tags,
+            contacts,
+            signature,
+        } = self;
+        let mut fields = vec![Frame::UInt(addrs.len() as u64)];
+        let with_contacts = contacts.len() == addrs.len() && !contacts.is_empty();
+        let with_tags = tags.len() == addrs.len()
+            && (with_contacts || tags.iter().any(|tags| !tags.is_empty()));
+        fields.extend(addrs.iter().map(|addr| addr.to_string().into()));
+        if with_tags {
+            fields.extend(tags.iter().map(|tags| encode_tags(tags).into()));
+        }
+        if with_tags && with_contacts {
+            for contact in contacts {
+                fields.push(contact.id.to_string().into());
+                fields.push(contact.label.into());
+                fields.push(contact.last_seen.into());
+                fields.push(contact.provenance.name().into());
+            }
+            if let Some(signature) = signature {
+                fields.push(hex::encode(&signature).into());
+            }
+        }
+        Ok(fields.into())
     }
 }
+
+// Tags are sent as 'name=value' pairs separated by commas.
+fn encode_tags(tags: &BTreeMap<String, String>) -> String {
+    tags.iter()
+        .map(|(tag, value)| format!("{tag}={value}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn decode_tags(s: &str) -> Result<BTreeMap<String, String>, parse::Error> {
+    s.split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((tag, value)) => Ok((tag.to_owned(), value.to_owned())),
+            None => Err(parse::Error::InvalidValue {
+                detail: format!("Expected 'name=value' tag, got '{pair}'"),
+            }),
+        })
+        .collect()
+}
@@ -0,0 +1,52 @@
+//! Messages defined by the application
+//!
+//! The `Message` enum only holds the built-in messages. An application adds
+//! its own by implementing `CustomMessage`, and registering it on the
+//! controller with `NetworkController::register`. They travel as
+//! `Message::Unknown`, and the controller parses the ones it receives, and
+//! hands them to the registered handler.
+//!
+//! Messages deriving `Serialize` and `Deserialize` can implement the trait
+//! with `wire::parse_deserialized` and `wire::serialized_fields`.
+use super::error::Error;
+use super::wire::tagged_frame;
+use super::Message;
+use crate::Frame;
+use crate::Parse;
+
+/// A message of the application.
+pub trait CustomMessage: Sized + Send + 'static {
+    /// Tag identifying the message type on the wire. It cannot be the tag of
+    /// a built-in message.
+    const TAG: &'static str;
+
+    /// Stable numeric id, from `registry::CUSTOM_ID_BASE`.
+    const ID: u64;
+
+    /// Extract the message from the parse, positioned after the tag.
+    fn parse_frames(parse: &mut Parse) -> Result<Self, Error>;
+
+    /// The message's fields, but not the tag, as an array frame. A frame
+    /// which is not an array is a single field.
+    fn into_fields(self) -> Result<Frame, Error>;
+
+    /// Convert the message into a frame, laid out as the built-in messages.
+    fn into_frame(self) -> Result<Frame, Error> {
+        Ok(tagged_frame(Self::TAG, self.into_fields()?))
+    }
+
+    /// Parse the message from a frame, starting with its tag or id.
+    fn from_frame(frame: Frame) -> Result<Self, Error> {
+        let mut parse = Parse::new(frame)?;
+        let _ = parse.next_frame()?;
+        Self::parse_frames(&mut parse)
+    }
+
+    /// Convert the message into one the peers can send.
+    fn into_message(self) -> Result<Message, Error> {
+        Ok(Message::Unknown {
+            tag: Self::TAG.to_owned(),
+            frame: self.into_frame()?,
+        })
+    }
+}
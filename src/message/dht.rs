@@ -0,0 +1,86 @@
+//! DHT lookups
+//!
+//! A node asks a remote node it is connected to for the nodes it knows
+//! closest to a target id, in the XOR metric of Kademlia. The remote answers
+//! with at most `k` nodes, with the address they listen on, closest first.
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use super::error::Error;
+use super::WireMessage;
+use crate::Frame;
+use crate::Parse;
+
+/// Request for the nodes closest to a target id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FindNode {
+    /// Id looked up
+    pub target: Uuid,
+}
+
+/// Nodes closest to a target id, known to the sender
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nodes {
+    /// Id looked up
+    pub target: Uuid,
+    /// Nodes, closest first
+    pub nodes: Vec<Node>,
+}
+
+/// A node of a DHT answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Node {
+    /// Controller id of the node
+    pub id: Uuid,
+    /// Address the node listens on
+    pub addr: SocketAddr,
+}
+
+impl WireMessage for FindNode {
+    const TAG: &'static str = "FIND_NODE";
+    const ID: u64 = 25;
+
+    /// Extract a Find Node message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<FindNode, Error> {
+        let target = parse.next_uuid()?;
+        Ok(FindNode { target })
+    }
+
+    /// The Find Node fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![self.target.to_string()])
+    }
+}
+
+impl WireMessage for Nodes {
+    const TAG: &'static str = "NODES";
+    const ID: u64 = 26;
+
+    /// Extract a Nodes message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<Nodes, Error> {
+        let target = parse.next_uuid()?;
+        let count = parse.next_unsigned()?;
+        let mut nodes = Vec::new();
+        for _ in 0..count {
+            let id = parse.next_uuid()?;
+            let addr = parse.next_addr()?;
+            nodes.push(Node { id, addr });
+        }
+        Ok(Nodes { target, nodes })
+    }
+
+    /// The Nodes fields: the target, the number of nodes, then the id and
+    /// address of each node.
+    fn into_fields(self) -> Result<Frame, Error> {
+        let mut fields = vec![
+            self.target.to_string().into(),
+            Frame::UInt(self.nodes.len() as u64),
+        ];
+        for node in self.nodes {
+            fields.push(node.id.to_string().into());
+            fields.push(node.addr.to_string().into());
+        }
+        Ok(fields.into())
+    }
+}
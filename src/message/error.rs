@@ -17,11 +17,23 @@ pub enum Error {
         source: crate::frame::Error,
     },
 
+    /// Conversion Error, for messages converted with serde
+    Serde {
+        /// source field
+        source: crate::frame_serde::Error,
+    },
+
     /// Unexpected Message
     UnexpectedMessage {
         /// details about the unexpected message.
         detail: String,
     },
+
+    /// A custom message could not be registered
+    InvalidRegistration {
+        /// details about the registration.
+        detail: String,
+    },
 }
 
 impl fmt::Display for Error {
@@ -29,7 +41,9 @@ impl fmt::Display for Error {
         match self {
             Error::Parse { source } => write!(f, "Parsing Error: {source}"),
             Error::Frame { source } => write!(f, "Framing Error: {source}"),
+            Error::Serde { source } => write!(f, "Conversion Error: {source}"),
             Error::UnexpectedMessage { detail } => write!(f, "Unexpected Message {detail}"),
+            Error::InvalidRegistration { detail } => write!(f, "Invalid Registration: {detail}"),
         }
     }
 }
@@ -40,6 +54,12 @@ impl From<crate::parse::Error> for Error {
     }
 }
 
+impl From<crate::frame_serde::Error> for Error {
+    fn from(err: crate::frame_serde::Error) -> Self {
+        Error::Serde { source: err }
+    }
+}
+
 impl From<crate::frame::Error> for Error {
     fn from(err: crate::frame::Error) -> Self {
         Error::Frame { source: err }
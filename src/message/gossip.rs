@@ -0,0 +1,66 @@
+//! Gossip
+//!
+//! Application data flooded through the network. Each node forwards it to
+//! its other peers, with one hop less to live, and drops the messages whose
+//! id it has already seen.
+use bytes::Bytes;
+use uuid::Uuid;
+
+use super::error::Error;
+use super::WireMessage;
+use crate::Frame;
+use crate::Parse;
+
+/// Data for every node of the network
+#[derive(Debug)]
+pub struct Gossip {
+    /// Id of the message, chosen by the node it originates from
+    pub id: Uuid,
+    /// Number of hops left, this one included
+    pub ttl: u64,
+    /// Topic of the data, empty if none
+    pub topic: String,
+    /// Opaque data
+    pub data: Bytes,
+}
+
+impl Gossip {
+    /// Creates a new message
+    pub fn new(id: Uuid, ttl: u64, topic: String, data: Bytes) -> Gossip {
+        Gossip {
+            id,
+            ttl,
+            topic,
+            data,
+        }
+    }
+}
+
+impl WireMessage for Gossip {
+    const TAG: &'static str = "GOSSIP";
+    const ID: u64 = 18;
+
+    /// Extract a Gossip message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<Gossip, Error> {
+        let id = parse.next_uuid()?;
+        let ttl = parse.next_unsigned()?;
+        let topic = parse.next_string()?;
+        let data = parse.next_bytes()?;
+        Ok(Gossip {
+            id,
+            ttl,
+            topic,
+            data,
+        })
+    }
+
+    /// The Gossip fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![
+            self.id.to_string(),
+            self.ttl,
+            self.topic,
+            self.data
+        ])
+    }
+}
@@ -1,7 +1,14 @@
 //! Heartbeat Request
+//!
+//! Heartbeats may carry the health of their sender, so that nodes learn how
+//! their neighbours are doing without extra round trips. The health fields
+//! trail the message, and older nodes don't send them.
 use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use super::error::Error;
+use super::WireMessage;
 use crate::Frame;
 use crate::Parse;
 
@@ -11,28 +18,72 @@ use crate::Parse;
 #[derive(Debug)]
 pub struct HeartbeatRequest {
     /// id of the OutAlive peer.
-    pub id: String,
+    pub id: Uuid,
     /// label of the OutAlive peer.
     pub label: String,
     /// timestamp (micros) when the message was sent by the OutAlive peer.
     /// This is used to estimate Round Trip Time (RTT)
     pub src: i64,
+    /// Health of the OutAlive peer's node.
+    pub health: Option<Health>,
+}
+
+/// Basic health of a node, piggybacked on heartbeats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Health {
+    /// Number of remote nodes the node is connected to.
+    pub peers: u64,
+    /// Number of commands and chunks waiting for the peer sending the
+    /// heartbeat.
+    pub queue: u64,
+    /// Seconds since the node started.
+    pub uptime: u64,
+}
+
+impl Health {
+    /// Extract the health from the fields left in the parse, if any.
+    pub fn parse_opt(parse: &mut Parse) -> Result<Option<Health>, Error> {
+        let Some(peers) = parse.next_unsigned_opt()? else {
+            return Ok(None);
+        };
+        let queue = parse.next_unsigned()?;
+        let uptime = parse.next_unsigned()?;
+        Ok(Some(Health {
+            peers,
+            queue,
+            uptime,
+        }))
+    }
+
+    /// Append the health fields.
+    pub fn push_fields(self, fields: &mut Vec<Frame>) {
+        fields.push(self.peers.into());
+        fields.push(self.queue.into());
+        fields.push(self.uptime.into());
+    }
 }
 
 impl HeartbeatRequest {
     /// Creates a new message
-    pub fn now(id: String, label: String) -> HeartbeatRequest {
+    pub fn now(id: Uuid, label: String) -> HeartbeatRequest {
         let dt = Utc::now();
         HeartbeatRequest {
             id,
             label,
             src: dt.timestamp_micros(),
+            health: None,
         }
     }
 
+    /// Add the health of the sender.
+    pub fn with_health(mut self, health: Health) -> HeartbeatRequest {
+        self.health = Some(health);
+        self
+    }
+
     /// Accessor for the id
-    pub fn id(&self) -> &str {
-        &self.id
+    pub fn id(&self) -> Uuid {
+        self.id
     }
 
     /// Accessor for the label
@@ -44,23 +95,38 @@ impl HeartbeatRequest {
     pub fn src(&self) -> i64 {
         self.src
     }
+}
+
+impl WireMessage for HeartbeatRequest {
+    const TAG: &'static str = "HBT_REQ";
+    const ID: u64 = 4;
 
     /// Extract a Heartbeat Request message from the parse.
-    pub fn parse_frames(parse: &mut Parse) -> Result<HeartbeatRequest, Error> {
-        let id = parse.next_string()?;
+    fn parse_frames(parse: &mut Parse) -> Result<HeartbeatRequest, Error> {
+        let id = parse.next_uuid()?;
         let label = parse.next_string()?;
         let src = parse.next_integer()?;
-        Ok(HeartbeatRequest { id, label, src })
+        let health = Health::parse_opt(parse)?;
+        Ok(HeartbeatRequest {
+            id,
+            label,
+            src,
+            health,
+        })
     }
 
-    /// Convert the Heartbeat Request into a frame
-    pub fn into_frame(self) -> Result<Frame, Error> {
-        let HeartbeatRequest { id, label, src } = self;
-        let mut frame = Frame::array();
-        frame.push_string(String::from("HBT_REQ"))?;
-        frame.push_string(id)?;
-        frame.push_string(label)?;
-        frame.push_integer(src)?;
-        Ok(frame)
+    /// The Heartbeat Request fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        let HeartbeatRequest {
+            id,
+            label,
+            src,
+            health,
+        } = self;
+        let mut fields = vec![id.to_string().into(), label.into(), src.into()];
+        if let Some(health) = health {
+            health.push_fields(&mut fields);
+        }
+        Ok(fields.into())
     }
 }
@@ -1,7 +1,10 @@
-//! Heartbeat Request
+//! Heartbeat Response
 use chrono::Utc;
+use uuid::Uuid;
 
 use super::error::Error;
+use super::heartbeat_request::Health;
+use super::WireMessage;
 use crate::Frame;
 use crate::Parse;
 
@@ -11,30 +14,39 @@ use crate::Parse;
 #[derive(Debug)]
 pub struct HeartbeatResponse {
     /// id of the InAlive peer.
-    pub id: String,
+    pub id: Uuid,
     /// label of the InAlive peer.
     pub label: String,
     /// timestamp (micros) when the message was sent from the OutAlive peer
     pub src: i64,
     /// timestamp (micros) when the message was sent from the InAlive peer
     pub dst: i64,
+    /// Health of the InAlive peer's node.
+    pub health: Option<Health>,
 }
 
 impl HeartbeatResponse {
     /// Creates a new message
-    pub fn now(id: String, label: String, src: i64) -> HeartbeatResponse {
+    pub fn now(id: Uuid, label: String, src: i64) -> HeartbeatResponse {
         let dt = Utc::now();
         HeartbeatResponse {
             id,
             label,
             src,
             dst: dt.timestamp_micros(),
+            health: None,
         }
     }
 
+    /// Add the health of the sender.
+    pub fn with_health(mut self, health: Health) -> HeartbeatResponse {
+        self.health = Some(health);
+        self
+    }
+
     /// Accessor for the id
-    pub fn id(&self) -> &str {
-        &self.id
+    pub fn id(&self) -> Uuid {
+        self.id
     }
 
     /// Accessor for the label
@@ -51,35 +63,41 @@ impl HeartbeatResponse {
     pub fn dst(&self) -> i64 {
         self.dst
     }
+}
+
+impl WireMessage for HeartbeatResponse {
+    const TAG: &'static str = "HBT_RESP";
+    const ID: u64 = 5;
 
     /// Extract a Heartbeat Response message from the parse.
-    pub fn parse_frames(parse: &mut Parse) -> Result<HeartbeatResponse, Error> {
-        let id = parse.next_string()?;
+    fn parse_frames(parse: &mut Parse) -> Result<HeartbeatResponse, Error> {
+        let id = parse.next_uuid()?;
         let label = parse.next_string()?;
         let src = parse.next_integer()?;
         let dst = parse.next_integer()?;
+        let health = Health::parse_opt(parse)?;
         Ok(HeartbeatResponse {
             id,
             label,
             src,
             dst,
+            health,
         })
     }
 
-    /// Convert the Heartbeat Request into a frame
-    pub fn into_frame(self) -> Result<Frame, Error> {
+    /// The Heartbeat Response fields
+    fn into_fields(self) -> Result<Frame, Error> {
         let HeartbeatResponse {
             id,
             label,
             src,
             dst,
+            health,
         } = self;
-        let mut frame = Frame::array();
-        frame.push_string(String::from("HBT_RESP"))?;
-        frame.push_string(id)?;
-        frame.push_string(label)?;
-        frame.push_integer(src)?;
-        frame.push_integer(dst)?;
-        Ok(frame)
+        let mut fields = vec![id.to_string().into(), label.into(), src.into(), dst.into()];
+        if let Some(health) = health {
+            health.push_fields(&mut fields);
+        }
+        Ok(fields.into())
     }
 }
@@ -0,0 +1,77 @@
+//! Node information
+//!
+//! A node asks a remote node it is connected to about itself: the version it
+//! runs, its label, its number of connections and its uptime, so that
+//! operators can check on any node they reach.
+use serde::{Deserialize, Serialize};
+
+use super::error::Error;
+use super::WireMessage;
+use crate::Frame;
+use crate::Parse;
+
+/// Request for the information of the remote node
+#[derive(Debug)]
+pub struct InfoRequest;
+
+/// Information of a node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InfoResponse {
+    /// Version of area-net the node runs
+    pub version: String,
+    /// Label of the node
+    pub label: String,
+    /// Number of outgoing connections
+    pub outgoing: u64,
+    /// Number of incoming connections
+    pub incoming: u64,
+    /// Seconds since the node started
+    pub uptime: u64,
+}
+
+impl WireMessage for InfoRequest {
+    const TAG: &'static str = "INFO_REQ";
+    const ID: u64 = 21;
+
+    /// Extract an Info Request message from the parse.
+    fn parse_frames(_parse: &mut Parse) -> Result<InfoRequest, Error> {
+        Ok(InfoRequest)
+    }
+
+    /// An Info Request has no fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![])
+    }
+}
+
+impl WireMessage for InfoResponse {
+    const TAG: &'static str = "INFO_RESP";
+    const ID: u64 = 22;
+
+    /// Extract an Info Response message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<InfoResponse, Error> {
+        let version = parse.next_string()?;
+        let label = parse.next_string()?;
+        let outgoing = parse.next_unsigned()?;
+        let incoming = parse.next_unsigned()?;
+        let uptime = parse.next_unsigned()?;
+        Ok(InfoResponse {
+            version,
+            label,
+            outgoing,
+            incoming,
+            uptime,
+        })
+    }
+
+    /// The Info Response fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![
+            self.version,
+            self.label,
+            self.outgoing,
+            self.incoming,
+            self.uptime
+        ])
+    }
+}
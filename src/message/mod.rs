@@ -11,13 +11,41 @@ pub use conn_response::ConnResponse;
 pub mod conn_rejection;
 pub use conn_rejection::ConnRejection;
 pub mod heartbeat_request;
-pub use heartbeat_request::HeartbeatRequest;
+pub use heartbeat_request::{Health, HeartbeatRequest};
 pub mod heartbeat_response;
 pub use heartbeat_response::HeartbeatResponse;
 pub mod contact_request;
 pub use contact_request::ContactRequest;
 pub mod contact_response;
-pub use contact_response::ContactResponse;
+pub use contact_response::{Contact, ContactResponse, Provenance};
+pub mod payload;
+pub use payload::Payload;
+pub mod chunk;
+pub use chunk::Chunk;
+pub mod custom;
+pub use custom::CustomMessage;
+pub mod dht;
+pub use dht::{FindNode, Nodes};
+pub mod bye;
+pub use bye::Bye;
+pub mod ping;
+pub use ping::{Ping, Pong};
+pub mod auth;
+pub use auth::{AuthChallenge, AuthResponse};
+pub mod gossip;
+pub use gossip::Gossip;
+pub mod info;
+pub use info::{InfoRequest, InfoResponse};
+pub mod negotiation;
+pub use negotiation::{CodecAccept, CodecOffer};
+pub mod pow;
+pub use pow::{PowChallenge, PowResponse};
+pub mod registry;
+pub use registry::Registry;
+pub mod relay;
+pub use relay::{RelayClose, RelayData, RelayOpen};
+pub mod wire;
+pub use wire::WireMessage;
 
 /// List of P2P messages
 #[derive(Debug)]
@@ -36,29 +64,139 @@ pub enum Message {
     ContactRequest(ContactRequest),
     /// Contact Response
     ContactResponse(ContactResponse),
+    /// Relay Open
+    RelayOpen(RelayOpen),
+    /// Relay Data
+    RelayData(RelayData),
+    /// Relay Close
+    RelayClose(RelayClose),
+    /// Payload
+    Payload(Payload),
+    /// Chunk
+    Chunk(Chunk),
+    /// Bye
+    Bye(Bye),
+    /// Ping
+    Ping(Ping),
+    /// Pong
+    Pong(Pong),
+    /// Auth Challenge
+    AuthChallenge(AuthChallenge),
+    /// Auth Response
+    AuthResponse(AuthResponse),
+    /// Gossip
+    Gossip(Gossip),
+    /// PoW Challenge
+    PowChallenge(PowChallenge),
+    /// PoW Response
+    PowResponse(PowResponse),
+    /// Info Request
+    InfoRequest(InfoRequest),
+    /// Info Response
+    InfoResponse(InfoResponse),
+    /// Codec Offer
+    CodecOffer(CodecOffer),
+    /// Codec Accept
+    CodecAccept(CodecAccept),
+    /// Find Node
+    FindNode(FindNode),
+    /// Nodes
+    Nodes(Nodes),
+    /// A message this node doesn't know, from a newer node. The frame is
+    /// kept whole, tag included.
+    Unknown {
+        /// tag of the message, as sent
+        tag: String,
+        /// frame of the message
+        frame: Frame,
+    },
 }
 
 impl Message {
-    /// Parse a message from o frame
+    /// Parse a message from o frame, starting with the message's tag or
+    /// numeric id. A message with an unknown tag or id is returned as
+    /// `Message::Unknown`, so that newer nodes can talk to us.
     pub fn from_frame(frame: Frame) -> Result<Message, Error> {
+        Message::from_frame_in(frame, &Registry::default())
+    }
+
+    /// Parse a message from a frame, as `from_frame` does, naming the
+    /// messages of the application by their tag in `registry`.
+    pub fn from_frame_in(frame: Frame, registry: &Registry) -> Result<Message, Error> {
         let mut parse = Parse::new(frame)?;
-        let id = parse.next_string()?.to_uppercase();
-        let message = match id.as_str() {
-            "CONN_REQ" => Message::ConnRequest(ConnRequest::parse_frames(&mut parse)?),
-            "CONN_RESP" => Message::ConnResponse(ConnResponse::parse_frames(&mut parse)?),
-            "CONN_REJECT" => Message::ConnRejection(ConnRejection::parse_frames(&mut parse)?),
-            "HBT_REQ" => Message::HeartbeatRequest(HeartbeatRequest::parse_frames(&mut parse)?),
-            "HBT_RESP" => Message::HeartbeatResponse(HeartbeatResponse::parse_frames(&mut parse)?),
-            "CTCT_REQ" => Message::ContactRequest(ContactRequest::parse_frames(&mut parse)?),
-            "CTCT_RESP" => Message::ContactResponse(ContactResponse::parse_frames(&mut parse)?),
-            _ => {
-                return Err(Error::UnexpectedMessage { detail: id });
+        let key = parse.next_frame()?;
+        let parse_fn = match &key {
+            Frame::String(tag) => registry.parser(tag),
+            Frame::UInt(id) => registry.parser_by_id(*id),
+            // Self-describing formats may not tell signed integers apart.
+            Frame::Int(id) if *id >= 0 => registry.parser_by_id(*id as u64),
+            frame => {
+                return Err(Error::from(crate::parse::Error::InvalidFrameType {
+                    detail: format!("Expected message tag or id, got {frame:?}"),
+                }))
+            }
+        };
+        let Some(parse_fn) = parse_fn else {
+            let tag = match &key {
+                Frame::String(tag) => tag.clone(),
+                Frame::UInt(id) => registry.tag(*id).unwrap_or_else(|| id.to_string()),
+                Frame::Int(id) => registry.tag(*id as u64).unwrap_or_else(|| id.to_string()),
+                _ => unreachable!("checked above"),
+            };
+            let mut fields = vec![key];
+            if let Frame::Array(rest) = parse.rest() {
+                fields.extend(rest);
             }
+            return Ok(Message::Unknown {
+                tag,
+                frame: Frame::Array(fields),
+            });
         };
-        parse.finish()?;
+        let message = parse_fn(&mut parse)?;
+        // Newer nodes may append optional fields we don't know about yet.
+        if parse.remaining() > 0 {
+            log::debug!(
+                "Ignoring {} trailing field(s) in '{}'",
+                parse.remaining(),
+                message.tag()
+            );
+        }
         Ok(message)
     }
 
+    /// Wire tag identifying the message type (eg 'CONN_REQ')
+    pub fn tag(&self) -> &str {
+        match self {
+            Message::ConnRequest(_) => ConnRequest::TAG,
+            Message::ConnResponse(_) => ConnResponse::TAG,
+            Message::ConnRejection(_) => ConnRejection::TAG,
+            Message::HeartbeatRequest(_) => HeartbeatRequest::TAG,
+            Message::HeartbeatResponse(_) => HeartbeatResponse::TAG,
+            Message::ContactRequest(_) => ContactRequest::TAG,
+            Message::ContactResponse(_) => ContactResponse::TAG,
+            Message::RelayOpen(_) => RelayOpen::TAG,
+            Message::RelayData(_) => RelayData::TAG,
+            Message::RelayClose(_) => RelayClose::TAG,
+            Message::Payload(_) => Payload::TAG,
+            Message::Chunk(_) => Chunk::TAG,
+            Message::Bye(_) => Bye::TAG,
+            Message::Ping(_) => Ping::TAG,
+            Message::Pong(_) => Pong::TAG,
+            Message::AuthChallenge(_) => AuthChallenge::TAG,
+            Message::AuthResponse(_) => AuthResponse::TAG,
+            Message::Gossip(_) => Gossip::TAG,
+            Message::PowChallenge(_) => PowChallenge::TAG,
+            Message::PowResponse(_) => PowResponse::TAG,
+            Message::InfoRequest(_) => InfoRequest::TAG,
+            Message::InfoResponse(_) => InfoResponse::TAG,
+            Message::CodecOffer(_) => CodecOffer::TAG,
+            Message::CodecAccept(_) => CodecAccept::TAG,
+            Message::FindNode(_) => FindNode::TAG,
+            Message::Nodes(_) => Nodes::TAG,
+            Message::Unknown { tag, .. } => tag,
+        }
+    }
+
     /// Serialize into frame.
     pub fn into_frame(self) -> Result<Frame, Error> {
         match self {
@@ -69,40 +207,222 @@ impl Message {
             Message::HeartbeatResponse(response) => response.into_frame(),
             Message::ContactRequest(request) => request.into_frame(),
             Message::ContactResponse(response) => response.into_frame(),
+            Message::RelayOpen(open) => open.into_frame(),
+            Message::RelayData(data) => data.into_frame(),
+            Message::RelayClose(close) => close.into_frame(),
+            Message::Payload(payload) => payload.into_frame(),
+            Message::Chunk(chunk) => chunk.into_frame(),
+            Message::Bye(bye) => bye.into_frame(),
+            Message::Ping(ping) => ping.into_frame(),
+            Message::Pong(pong) => pong.into_frame(),
+            Message::AuthChallenge(challenge) => challenge.into_frame(),
+            Message::AuthResponse(response) => response.into_frame(),
+            Message::Gossip(gossip) => gossip.into_frame(),
+            Message::PowChallenge(challenge) => challenge.into_frame(),
+            Message::PowResponse(response) => response.into_frame(),
+            Message::InfoRequest(request) => request.into_frame(),
+            Message::InfoResponse(response) => response.into_frame(),
+            Message::CodecOffer(offer) => offer.into_frame(),
+            Message::CodecAccept(accept) => accept.into_frame(),
+            Message::FindNode(find) => find.into_frame(),
+            Message::Nodes(nodes) => nodes.into_frame(),
+            Message::Unknown { frame, .. } => Ok(frame),
         }
     }
+
+    /// Convert from a protobuf envelope.
+    #[cfg(feature = "proto")]
+    pub fn from_proto(envelope: crate::proto::Envelope) -> Result<Message, Error> {
+        envelope
+            .message
+            .ok_or_else(|| Error::UnexpectedMessage {
+                detail: "Empty envelope".to_owned(),
+            })?
+            .try_into()
+    }
+
+    /// Convert into a protobuf envelope. Unknown messages have no protobuf
+    /// form.
+    #[cfg(feature = "proto")]
+    pub fn into_proto(self) -> Result<crate::proto::Envelope, Error> {
+        Ok(crate::proto::Envelope {
+            message: Some(self.try_into()?),
+        })
+    }
+}
+
+macro_rules! impl_from_message {
+    ($($variant:ident),*) => {
+        $(
+            impl From<$variant> for Message {
+                fn from(msg: $variant) -> Self {
+                    Message::$variant(msg)
+                }
+            }
+        )*
+    };
 }
 
+impl_from_message!(
+    ConnRequest,
+    ConnResponse,
+    ConnRejection,
+    HeartbeatRequest,
+    HeartbeatResponse,
+    ContactRequest,
+    ContactResponse,
+    RelayOpen,
+    RelayData,
+    RelayClose,
+    Payload,
+    Chunk,
+    Bye,
+    Ping,
+    Pong,
+    AuthChallenge,
+    AuthResponse,
+    Gossip,
+    PowChallenge,
+    PowResponse,
+    InfoRequest,
+    InfoResponse,
+    CodecOffer,
+    CodecAccept,
+    FindNode,
+    Nodes
+);
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::codec::Compression;
+    use crate::network::identity::{Identity, Proof};
+    use std::collections::BTreeMap;
     use std::net::SocketAddr;
     use std::str::FromStr;
+    use uuid::Uuid;
 
     #[test]
     fn should_encode_decode_connection_request() {
+        let id = Uuid::new_v4();
         let msg_in = Message::ConnRequest(ConnRequest::new(
-            "id".into(),
+            id,
             "bob".into(),
-            "[::1]:8000".into(),
+            SocketAddr::from_str("[::1]:8000").unwrap(),
+            Some(Compression::Lz4),
         ));
         let frame = msg_in.into_frame().unwrap();
         if let Message::ConnRequest(response) = Message::from_frame(frame).unwrap() {
-            assert_eq!(response.id, "id");
+            assert_eq!(response.id, id);
             assert_eq!(response.label, "bob");
-            assert_eq!(response.address, "[::1]:8000");
+            assert_eq!(response.address.to_string(), "[::1]:8000");
+            assert_eq!(response.compression, Some(Compression::Lz4));
         } else {
             panic!("Message from frame should be a ConnRequest");
         }
     }
 
+    #[test]
+    fn should_encode_decode_a_signed_connection_request() {
+        let mut msg_in = ConnRequest::new(
+            Uuid::new_v4(),
+            "bob".into(),
+            SocketAddr::from_str("[::1]:8000").unwrap(),
+            None,
+        )
+        .with_public_key(vec![1; 32])
+        .with_nonce()
+        .with_network("testnet".into())
+        .with_key_share(vec![3; 32]);
+        msg_in.signature = Some(vec![2; 64]);
+        let nonce = msg_in.nonce;
+        let transcript = msg_in.transcript();
+        let frame = Message::ConnRequest(msg_in).into_frame().unwrap();
+        if let Message::ConnRequest(request) = Message::from_frame(frame).unwrap() {
+            assert_eq!(request.compression, None);
+            assert_eq!(request.public_key, Some(vec![1; 32]));
+            assert_eq!(request.signature, Some(vec![2; 64]));
+            assert!(nonce.is_some());
+            assert_eq!(request.nonce, nonce);
+            assert_eq!(request.network, "testnet");
+            assert_eq!(request.key_share, Some(vec![3; 32]));
+            assert_eq!(request.transcript(), transcript);
+        } else {
+            panic!("Message from frame should be a ConnRequest");
+        }
+    }
+
+    #[test]
+    fn should_ignore_unknown_trailing_fields() {
+        let msg_in =
+            Message::ConnResponse(ConnResponse::new(Uuid::new_v4(), "bob".into(), None, None));
+        let mut frame = msg_in.into_frame().unwrap();
+        if let Frame::Array(fields) = &mut frame {
+            fields.push("from a newer node".into());
+        }
+        if let Message::ConnResponse(response) = Message::from_frame(frame).unwrap() {
+            assert_eq!(response.label, "bob");
+        } else {
+            panic!("Message from frame should be a ConnResponse");
+        }
+    }
+
     #[test]
     fn should_encode_decode_connection_response() {
-        let msg_in = Message::ConnResponse(ConnResponse::new("id".into(), "bob".into()));
+        let id = Uuid::new_v4();
+        let observed = SocketAddr::from_str("203.0.113.7:51000").unwrap();
+        let msg_in =
+            Message::ConnResponse(ConnResponse::new(id, "bob".into(), Some(observed), None));
         let frame = msg_in.into_frame().unwrap();
         if let Message::ConnResponse(response) = Message::from_frame(frame).unwrap() {
-            assert_eq!(response.id, "id");
+            assert_eq!(response.id, id);
             assert_eq!(response.label, "bob");
+            assert_eq!(response.observed, Some(observed));
+        } else {
+            panic!("Message from frame should be a ConnResponse");
+        }
+    }
+
+    #[test]
+    fn should_encode_decode_connection_rejection() {
+        let id = Uuid::new_v4();
+        let msg_in = Message::ConnRejection(ConnRejection::new(
+            id,
+            conn_rejection::RejectReason::TooManyConnections,
+        ));
+        let frame = msg_in.into_frame().unwrap();
+        assert!(matches!(&frame, Frame::Array(fields) if fields.len() == 3));
+        if let Message::ConnRejection(rejection) = Message::from_frame(frame).unwrap() {
+            assert_eq!(rejection.id, id);
+            assert_eq!(
+                rejection.reason,
+                conn_rejection::RejectReason::TooManyConnections
+            );
+        } else {
+            panic!("Message from frame should be a ConnRejection");
+        }
+
+        // Reasons from newer nodes are kept as 'other'.
+        let frame = crate::frame!["CONN_REJECT", id.to_string(), "duplicate"];
+        if let Message::ConnRejection(rejection) = Message::from_frame(frame).unwrap() {
+            assert_eq!(rejection.reason, conn_rejection::RejectReason::Other);
+        } else {
+            panic!("Message from frame should be a ConnRejection");
+        }
+    }
+
+    #[test]
+    fn should_accept_a_compression_without_observed_address() {
+        let msg_in = Message::ConnResponse(ConnResponse::new(
+            Uuid::new_v4(),
+            "bob".into(),
+            None,
+            Some(Compression::Zstd),
+        ));
+        let frame = msg_in.into_frame().unwrap();
+        if let Message::ConnResponse(response) = Message::from_frame(frame).unwrap() {
+            assert_eq!(response.observed, None);
+            assert_eq!(response.compression, Some(Compression::Zstd));
         } else {
             panic!("Message from frame should be a ConnResponse");
         }
@@ -110,29 +430,68 @@ mod tests {
 
     #[test]
     fn should_encode_decode_heartbeat_request() {
-        let msg_in = Message::HeartbeatRequest(HeartbeatRequest::now("id".into(), "bob".into()));
+        let id = Uuid::new_v4();
+        let msg_in = Message::HeartbeatRequest(HeartbeatRequest::now(id, "bob".into()));
         let frame = msg_in.into_frame().unwrap();
         if let Message::HeartbeatRequest(response) = Message::from_frame(frame).unwrap() {
-            assert_eq!(response.id, "id");
+            assert_eq!(response.id, id);
             assert_eq!(response.label, "bob");
         } else {
             panic!("Message from frame should be a HeartbeatRequest");
         }
+
+        // The id is validated when parsed.
+        let frame = crate::frame!["HBT_REQ", "id", "bob", 42i64];
+        assert!(matches!(
+            Message::from_frame(frame),
+            Err(Error::Parse {
+                source: crate::parse::Error::InvalidValue { .. }
+            })
+        ));
     }
 
     #[test]
     fn should_encode_decode_heartbeat_response() {
-        let msg_in =
-            Message::HeartbeatResponse(HeartbeatResponse::now("id".into(), "bob".into(), 42));
+        let id = Uuid::new_v4();
+        let msg_in = Message::HeartbeatResponse(HeartbeatResponse::now(id, "bob".into(), 42));
         let frame = msg_in.into_frame().unwrap();
         if let Message::HeartbeatResponse(response) = Message::from_frame(frame).unwrap() {
-            assert_eq!(response.id, "id");
+            assert_eq!(response.id, id);
             assert_eq!(response.label, "bob");
         } else {
             panic!("Message from frame should be a HeartbeatRequest");
         }
     }
 
+    #[test]
+    fn should_encode_decode_heartbeats_with_health() {
+        let health = Health {
+            peers: 3,
+            queue: 1,
+            uptime: 600,
+        };
+        let id = Uuid::new_v4();
+        let request = HeartbeatRequest::now(id, "bob".into()).with_health(health);
+        let frame = Message::HeartbeatRequest(request).into_frame().unwrap();
+        match Message::from_frame(frame).unwrap() {
+            Message::HeartbeatRequest(request) => assert_eq!(request.health, Some(health)),
+            msg => panic!("Expected a HeartbeatRequest, got {msg:?}"),
+        }
+        let response = HeartbeatResponse::now(id, "bob".into(), 42).with_health(health);
+        let frame = Message::HeartbeatResponse(response).into_frame().unwrap();
+        match Message::from_frame(frame).unwrap() {
+            Message::HeartbeatResponse(response) => assert_eq!(response.health, Some(health)),
+            msg => panic!("Expected a HeartbeatResponse, got {msg:?}"),
+        }
+
+        // Older nodes don't send their health.
+        let frame = crate::frame!["HBT_REQ", id.to_string(), "bob", 42i64];
+        match Message::from_frame(frame).unwrap() {
+            Message::HeartbeatRequest(request) => assert_eq!(request.health, None),
+            msg => panic!("Expected a HeartbeatRequest, got {msg:?}"),
+        }
+    }
+
     #[test]
     fn should_encode_decode_contact_request() {
         let msg_in = Message::ContactRequest(ContactRequest);
@@ -152,13 +511,306 @@ mod tests {
             .iter()
             .map(|addr| SocketAddr::from_str(addr).unwrap())
             .collect::<Vec<_>>();
-        let msg_in = Message::ContactResponse(ContactResponse::new(sock_addrs));
+        let msg_in = Message::ContactResponse(ContactResponse::new(sock_addrs.clone(), Vec::new()));
         let frame = msg_in.into_frame().unwrap();
         println!("frame: {frame:?}");
         if let Message::ContactResponse(response) = Message::from_frame(frame).unwrap() {
+            assert_eq!(response.addrs, sock_addrs);
+            assert!(response.tags.is_empty());
+        } else {
+            panic!("Message from frame should be a ContactResponse");
+        }
+    }
+
+    #[test]
+    fn should_encode_decode_nodes() {
+        let target = Uuid::new_v4();
+        let nodes = vec![
+            dht::Node {
+                id: Uuid::new_v4(),
+                addr: SocketAddr::from_str("[::1]:8090").unwrap(),
+            },
+            dht::Node {
+                id: Uuid::new_v4(),
+                addr: SocketAddr::from_str("192.168.0.10:8085").unwrap(),
+            },
+        ];
+        let frame = Message::FindNode(FindNode { target }).into_frame().unwrap();
+        assert!(
+            matches!(Message::from_frame(frame).unwrap(), Message::FindNode(find) if find.target == target)
+        );
+        let msg_in = Message::Nodes(Nodes {
+            target,
+            nodes: nodes.clone(),
+        });
+        let frame = msg_in.into_frame().unwrap();
+        if let Message::Nodes(answer) = Message::from_frame(frame).unwrap() {
+            assert_eq!(answer.target, target);
+            assert_eq!(answer.nodes, nodes);
+        } else {
+            panic!("Message from frame should be a Nodes");
+        }
+    }
+
+    #[test]
+    fn should_encode_decode_relay_data() {
+        let circuit = Uuid::new_v4();
+        let data = b"*1\r\n+CONN_REQ\r\n\r\n".to_vec();
+        let msg_in = Message::RelayData(RelayData::new(circuit, data.clone()));
+        let frame = msg_in.into_frame().unwrap();
+        if let Message::RelayData(relayed) = Message::from_frame(frame).unwrap() {
+            assert_eq!(relayed.circuit, circuit);
+            assert_eq!(relayed.data, data);
+        } else {
+            panic!("Message from frame should be a RelayData");
+        }
+    }
+
+    #[test]
+    fn should_encode_decode_bye() {
+        let frame = Message::Bye(Bye::new(bye::Reason::Timeout))
+            .into_frame()
+            .unwrap();
+        assert_eq!(frame.to_string(), r#"["BYE", 1]"#);
+        if let Message::Bye(bye) = Message::from_frame(frame).unwrap() {
+            assert_eq!(bye.reason(), bye::Reason::Timeout);
+        } else {
+            panic!("Message from frame should be a Bye");
+        }
+
+        // Codes from newer nodes are kept.
+        let frame = crate::frame![13u64, 42u64];
+        if let Message::Bye(bye) = Message::from_frame(frame).unwrap() {
+            assert_eq!(bye.reason(), bye::Reason::Other(42));
+        } else {
+            panic!("Message from frame should be a Bye");
+        }
+    }
+
+    #[test]
+    fn should_encode_decode_payload() {
+        let data = bytes::Bytes::from_static(b"\0\x01\r\n\xff");
+        let frame = Message::Payload(Payload::new("sensors".to_owned(), data.clone()))
+            .into_frame()
+            .unwrap();
+        let mut bytes = bytes::BytesMut::new();
+        frame.write(&mut bytes).unwrap();
+        let mut cur = std::io::Cursor::new(&bytes[..]);
+        let frame = Frame::parse(&mut cur).unwrap();
+        if let Message::Payload(payload) = Message::from_frame(frame).unwrap() {
+            assert_eq!(payload.topic, "sensors");
+            assert_eq!(payload.data, data);
+        } else {
+            panic!("Message from frame should be a Payload");
+        }
+
+        // Without a topic, the field is left out, as older nodes send it.
+        let frame = Message::Payload(Payload::new(String::new(), data.clone()))
+            .into_frame()
+            .unwrap();
+        assert_eq!(frame.to_string(), r#"["PAYLOAD", b"\x00\x01\r\n\xff"]"#);
+        if let Message::Payload(payload) = Message::from_frame(frame).unwrap() {
+            assert!(payload.topic.is_empty());
+        } else {
+            panic!("Message from frame should be a Payload");
+        }
+    }
+
+    #[test]
+    fn should_encode_decode_gossip() {
+        let id = Uuid::new_v4();
+        let data = bytes::Bytes::from_static(b"\0\x01\r\n\xff");
+        let frame = Message::Gossip(Gossip::new(id, 3, "news".to_owned(), data.clone()))
+            .into_frame()
+            .unwrap();
+        if let Message::Gossip(gossip) = Message::from_frame(frame).unwrap() {
+            assert_eq!(gossip.id, id);
+            assert_eq!(gossip.ttl, 3);
+            assert_eq!(gossip.topic, "news");
+            assert_eq!(gossip.data, data);
+        } else {
+            panic!("Message from frame should be a Gossip");
+        }
+    }
+
+    #[test]
+    fn should_encode_decode_pow_challenge() {
+        let nonce = bytes::Bytes::from_static(b"\0\x01\r\n\xff");
+        let frame = Message::PowChallenge(PowChallenge::new(nonce.clone(), 20))
+            .into_frame()
+            .unwrap();
+        if let Message::PowChallenge(challenge) = Message::from_frame(frame).unwrap() {
+            assert_eq!(challenge.nonce, nonce);
+            assert_eq!(challenge.difficulty, 20);
+        } else {
+            panic!("Message from frame should be a PowChallenge");
+        }
+        let frame = Message::PowResponse(PowResponse::new(42))
+            .into_frame()
+            .unwrap();
+        if let Message::PowResponse(response) = Message::from_frame(frame).unwrap() {
+            assert_eq!(response.solution, 42);
+        } else {
+            panic!("Message from frame should be a PowResponse");
+        }
+    }
+
+    #[test]
+    fn should_encode_decode_info_response() {
+        let info = InfoResponse {
+            version: "0.1.0".to_owned(),
+            label: "bob".to_owned(),
+            outgoing: 3,
+            incoming: 2,
+            uptime: 3600,
+        };
+        let frame = Message::InfoResponse(info.clone()).into_frame().unwrap();
+        if let Message::InfoResponse(response) = Message::from_frame(frame).unwrap() {
+            assert_eq!(response, info);
+        } else {
+            panic!("Message from frame should be an InfoResponse");
+        }
+    }
+
+    #[test]
+    fn should_encode_decode_codec_offer() {
+        let options = crate::codec::CodecOptions {
+            format: crate::codec::Format::Binary,
+            compression: Some(Compression::Zstd),
+            integers: crate::frame::IntEncoding::Varint,
+        };
+        let frame = Message::CodecOffer(CodecOffer { options })
+            .into_frame()
+            .unwrap();
+        if let Message::CodecOffer(offer) = Message::from_frame(frame).unwrap() {
+            assert_eq!(offer.options, options);
+        } else {
+            panic!("Message from frame should be a CodecOffer");
+        }
+        // Options we don't know are read as those every node understands.
+        let frame = crate::frame!["CODEC_ACCEPT", "morse", "brotli", "roman"];
+        if let Message::CodecAccept(accept) = Message::from_frame(frame).unwrap() {
+            assert_eq!(accept.options, crate::codec::CodecOptions::default());
+        } else {
+            panic!("Message from frame should be a CodecAccept");
+        }
+    }
+
+    #[test]
+    fn should_encode_decode_contact_response_tags() {
+        let addrs = vec![
+            SocketAddr::from_str("[::1]:8090").unwrap(),
+            SocketAddr::from_str("[::1]:8085").unwrap(),
+        ];
+        let tags = vec![
+            BTreeMap::from([
+                ("region".to_owned(), "eu".to_owned()),
+                ("role".to_owned(), "relay".to_owned()),
+            ]),
+            BTreeMap::new(),
+        ];
+        let msg_in = Message::ContactResponse(ContactResponse::new(addrs.clone(), tags.clone()));
+        let frame = msg_in.into_frame().unwrap();
+        if let Message::ContactResponse(response) = Message::from_frame(frame).unwrap() {
+            assert_eq!(response.addrs, addrs);
+            assert_eq!(response.tags, tags);
+        } else {
+            panic!("Message from frame should be a ContactResponse");
+        }
+    }
+
+    #[test]
+    fn should_encode_decode_contact_response_contacts() {
+        let addrs = vec![
+            SocketAddr::from_str("[::1]:8090").unwrap(),
+            SocketAddr::from_str("[::1]:8085").unwrap(),
+        ];
+        let contacts = vec![
+            Contact {
+                id: Uuid::new_v4(),
+                label: "alice".to_owned(),
+                last_seen: 1_670_000_000_000_000,
+                provenance: Provenance::Outgoing,
+            },
+            Contact {
+                id: Uuid::new_v4(),
+                label: "bob".to_owned(),
+                last_seen: 1_670_000_001_000_000,
+                provenance: Provenance::Incoming,
+            },
+        ];
+        // Without tags, empty ones are sent ahead of the contacts.
+        let mut response = ContactResponse::new(addrs.clone(), vec![BTreeMap::new(); 2])
+            .with_contacts(contacts.clone());
+        let sender = Identity::new();
+        response.signature = Some(sender.sign(&[], &response.transcript()));
+        let frame = Message::ContactResponse(response).into_frame().unwrap();
+        if let Message::ContactResponse(mut response) = Message::from_frame(frame).unwrap() {
             assert_eq!(response.addrs, addrs);
+            assert_eq!(response.tags, vec![BTreeMap::new(); 2]);
+            assert_eq!(response.contacts, contacts);
+            let proof = |response: &ContactResponse| Proof {
+                public_key: sender.public_key().to_vec(),
+                transcript: response.transcript(),
+                signature: response.signature.clone().unwrap(),
+                freshness: None,
+                key_share: None,
+            };
+            assert!(proof(&response).verify(sender.id(), &[]));
+            // The signature covers every entry.
+            response.contacts[1].provenance = Provenance::Outgoing;
+            assert!(!proof(&response).verify(sender.id(), &[]));
         } else {
             panic!("Message from frame should be a ContactResponse");
         }
     }
+
+    #[test]
+    fn should_keep_unknown_messages() {
+        let frame = crate::frame!["Nope", 42u64, "from a newer node"];
+        let msg = Message::from_frame(frame.clone()).unwrap();
+        assert!(matches!(&msg, Message::Unknown { tag, .. } if tag == "Nope"));
+        assert_eq!(msg.tag(), "Nope");
+        assert_eq!(msg.into_frame().unwrap().to_string(), frame.to_string());
+
+        // So is a message with an unknown id.
+        let msg = Message::from_frame(crate::frame![42u64]).unwrap();
+        assert!(matches!(&msg, Message::Unknown { tag, .. } if tag == "42"));
+
+        // A frame which is not a message is still rejected.
+        assert!(matches!(
+            Message::from_frame(crate::frame![true]),
+            Err(Error::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn should_dispatch_on_the_message_tag() {
+        let msg = Message::from_frame(ContactRequest.into_frame().unwrap()).unwrap();
+        assert_eq!(msg.tag(), ContactRequest::TAG);
+        let mut tags = registry::MESSAGES
+            .iter()
+            .map(|(_, tag, _)| *tag)
+            .collect::<Vec<_>>();
+        tags.sort_unstable();
+        tags.dedup();
+        assert_eq!(tags.len(), registry::MESSAGES.len());
+
+        // The id of a message stands for its tag.
+        let frame = Registry::default().with_id(ContactRequest.into_frame().unwrap());
+        assert!(matches!(&frame, Frame::Array(fields) if matches!(fields[0], Frame::UInt(6))));
+        let msg = Message::from_frame(frame).unwrap();
+        assert_eq!(msg.tag(), ContactRequest::TAG);
+    }
+
+    #[test]
+    fn should_reject_invalid_connection_request() {
+        let frame = crate::frame!["CONN_REQ", "id", "bob", "[::1]:8000"];
+        assert!(matches!(
+            Message::from_frame(frame),
+            Err(Error::Parse {
+                source: crate::parse::Error::InvalidValue { .. }
+            })
+        ));
+    }
 }
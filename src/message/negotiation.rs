@@ -0,0 +1,78 @@
+//! Codec negotiation
+//!
+//! With `wire.negotiate`, the node which initiated the connection offers its
+//! codec options right after the handshake, and the remote answers with
+//! those it accepts. Both then switch the frames they send to the accepted
+//! options. Options are sent by name, and those a node doesn't know are read
+//! as the ones every node understands: text, no compression, fixed integers.
+use super::error::Error;
+use super::WireMessage;
+use crate::codec::{CodecOptions, Compression, Format};
+use crate::frame::IntEncoding;
+use crate::Frame;
+use crate::Parse;
+
+/// Codec options offered by the node which initiated the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecOffer {
+    /// Options offered
+    pub options: CodecOptions,
+}
+
+/// Codec options accepted by the remote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecAccept {
+    /// Options accepted
+    pub options: CodecOptions,
+}
+
+impl WireMessage for CodecOffer {
+    const TAG: &'static str = "CODEC_OFFER";
+    const ID: u64 = 23;
+
+    /// Extract a Codec Offer message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<CodecOffer, Error> {
+        let options = parse_options(parse)?;
+        Ok(CodecOffer { options })
+    }
+
+    /// The Codec Offer fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(options_fields(self.options))
+    }
+}
+
+impl WireMessage for CodecAccept {
+    const TAG: &'static str = "CODEC_ACCEPT";
+    const ID: u64 = 24;
+
+    /// Extract a Codec Accept message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<CodecAccept, Error> {
+        let options = parse_options(parse)?;
+        Ok(CodecAccept { options })
+    }
+
+    /// The Codec Accept fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(options_fields(self.options))
+    }
+}
+
+fn parse_options(parse: &mut Parse) -> Result<CodecOptions, Error> {
+    let format = Format::from_name(&parse.next_string()?).unwrap_or_default();
+    let compression = Compression::from_name(&parse.next_string()?);
+    let integers = IntEncoding::from_name(&parse.next_string()?).unwrap_or_default();
+    Ok(CodecOptions {
+        format,
+        compression,
+        integers,
+    })
+}
+
+fn options_fields(options: CodecOptions) -> Frame {
+    crate::frame![
+        options.format.name(),
+        options.compression.map_or("", |c| c.name()),
+        options.integers.name()
+    ]
+}
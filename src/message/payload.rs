@@ -0,0 +1,50 @@
+//! Payload
+//!
+//! Carries application data between two connected nodes. The data is sent
+//! as a bulk frame, so it can hold any bytes. The topic lets applications
+//! tell their kinds of data apart; it is a trailing field, left out when
+//! empty, which older nodes ignore.
+use bytes::Bytes;
+
+use super::error::Error;
+use super::WireMessage;
+use crate::Frame;
+use crate::Parse;
+
+/// Application data
+#[derive(Debug)]
+pub struct Payload {
+    /// Topic chosen by the application, empty if none
+    pub topic: String,
+    /// Bytes sent by the application
+    pub data: Bytes,
+}
+
+impl Payload {
+    /// Creates a new message
+    pub fn new(topic: String, data: Bytes) -> Payload {
+        Payload { topic, data }
+    }
+}
+
+impl WireMessage for Payload {
+    const TAG: &'static str = "PAYLOAD";
+    const ID: u64 = 11;
+
+    /// Extract a Payload message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<Payload, Error> {
+        let data = parse.next_bytes()?;
+        let topic = parse.next_string_opt()?.unwrap_or_default();
+        Ok(Payload { topic, data })
+    }
+
+    /// The Payload fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        let Payload { topic, data } = self;
+        if topic.is_empty() {
+            Ok(crate::frame![data])
+        } else {
+            Ok(crate::frame![data, topic])
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Ping messages
+//!
+//! A node pings a remote on demand, apart from heartbeats, to check it is
+//! live. The remote echoes the data of the ping back in a pong, so that
+//! pings of growing size can probe the largest message the path carries.
+use bytes::Bytes;
+
+use super::error::Error;
+use super::WireMessage;
+use crate::Frame;
+use crate::Parse;
+
+/// Ask the remote to echo some data
+#[derive(Debug)]
+pub struct Ping {
+    /// Sequence number, chosen by the sender
+    pub seq: u64,
+    /// Opaque data, echoed by the remote
+    pub data: Bytes,
+}
+
+/// Echo of a ping
+#[derive(Debug)]
+pub struct Pong {
+    /// Sequence number of the ping
+    pub seq: u64,
+    /// Data of the ping
+    pub data: Bytes,
+}
+
+impl Ping {
+    /// Creates a new message
+    pub fn new(seq: u64, data: Bytes) -> Ping {
+        Ping { seq, data }
+    }
+}
+
+impl Pong {
+    /// Creates a new message
+    pub fn new(seq: u64, data: Bytes) -> Pong {
+        Pong { seq, data }
+    }
+}
+
+impl WireMessage for Ping {
+    const TAG: &'static str = "PING";
+    const ID: u64 = 14;
+
+    /// Extract a Ping message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<Ping, Error> {
+        let seq = parse.next_unsigned()?;
+        let data = parse.next_bytes()?;
+        Ok(Ping { seq, data })
+    }
+
+    /// The Ping fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![self.seq, self.data])
+    }
+}
+
+impl WireMessage for Pong {
+    const TAG: &'static str = "PONG";
+    const ID: u64 = 15;
+
+    /// Extract a Pong message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<Pong, Error> {
+        let seq = parse.next_unsigned()?;
+        let data = parse.next_bytes()?;
+        Ok(Pong { seq, data })
+    }
+
+    /// The Pong fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![self.seq, self.data])
+    }
+}
@@ -0,0 +1,75 @@
+//! Proof-of-work messages
+//!
+//! An incoming peer which requires work from its remotes answers the
+//! connection request with a challenge: a nonce, and a difficulty. The remote
+//! answers with a solution, a number whose hash with the nonce starts with as
+//! many zero bits as the difficulty.
+use bytes::Bytes;
+
+use super::error::Error;
+use super::WireMessage;
+use crate::Frame;
+use crate::Parse;
+
+/// Challenge sent in place of a connection response
+#[derive(Debug)]
+pub struct PowChallenge {
+    /// Random bytes, chosen by the incoming peer
+    pub nonce: Bytes,
+    /// Number of leading zero bits required
+    pub difficulty: u64,
+}
+
+/// Answer to a challenge
+#[derive(Debug)]
+pub struct PowResponse {
+    /// Solution found by the remote
+    pub solution: u64,
+}
+
+impl PowChallenge {
+    /// Creates a new message
+    pub fn new(nonce: Bytes, difficulty: u64) -> PowChallenge {
+        PowChallenge { nonce, difficulty }
+    }
+}
+
+impl PowResponse {
+    /// Creates a new message
+    pub fn new(solution: u64) -> PowResponse {
+        PowResponse { solution }
+    }
+}
+
+impl WireMessage for PowChallenge {
+    const TAG: &'static str = "POW_CHAL";
+    const ID: u64 = 19;
+
+    /// Extract a PoW Challenge message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<PowChallenge, Error> {
+        let nonce = parse.next_bytes()?;
+        let difficulty = parse.next_unsigned()?;
+        Ok(PowChallenge { nonce, difficulty })
+    }
+
+    /// The PoW Challenge fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![self.nonce, self.difficulty])
+    }
+}
+
+impl WireMessage for PowResponse {
+    const TAG: &'static str = "POW_RESP";
+    const ID: u64 = 20;
+
+    /// Extract a PoW Response message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<PowResponse, Error> {
+        let solution = parse.next_unsigned()?;
+        Ok(PowResponse { solution })
+    }
+
+    /// The PoW Response fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![self.solution])
+    }
+}
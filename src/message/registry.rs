@@ -0,0 +1,197 @@
+//! Registry of the messages, by tag and by numeric id
+//!
+//! Besides its tag, each message has a stable numeric id, the same as its
+//! field in the protobuf envelope. Either can start a message on the wire:
+//! with `wire.numeric_ids`, the id is sent, which takes one or two bytes
+//! where the tag takes up to a dozen. The tag is still what is logged and
+//! counted in the metrics.
+//!
+//! Applications can register their own messages, with ids from
+//! `CUSTOM_ID_BASE`, in the registry of their controller, so that they are
+//! sent with their id too. This node
+//! doesn't parse them: they are received as `Message::Unknown`, named after
+//! their registered tag, and passed on to the controller, which hands them to
+//! the handler registered with `NetworkController::register`.
+use super::error::Error;
+use super::{wire, Message, WireMessage};
+use super::{
+    AuthChallenge, AuthResponse, Bye, Chunk, CodecAccept, CodecOffer, ConnRejection, ConnRequest,
+    ConnResponse, ContactRequest, ContactResponse, FindNode, Gossip, HeartbeatRequest,
+    HeartbeatResponse, InfoRequest, InfoResponse, Nodes, Payload, Ping, Pong, PowChallenge,
+    PowResponse, RelayClose, RelayData, RelayOpen,
+};
+use crate::Frame;
+use crate::Parse;
+
+/// Parser of a message, positioned after its tag or id.
+pub(crate) type ParseFn = fn(&mut Parse) -> Result<Message, Error>;
+
+/// The built-in messages, with their id, tag and parser.
+pub(crate) const MESSAGES: &[(u64, &str, ParseFn)] = &[
+    entry::<ConnRequest>(),
+    entry::<ConnResponse>(),
+    entry::<ConnRejection>(),
+    entry::<HeartbeatRequest>(),
+    entry::<HeartbeatResponse>(),
+    entry::<ContactRequest>(),
+    entry::<ContactResponse>(),
+    entry::<RelayOpen>(),
+    entry::<RelayData>(),
+    entry::<RelayClose>(),
+    entry::<Payload>(),
+    entry::<Chunk>(),
+    entry::<Bye>(),
+    entry::<Ping>(),
+    entry::<Pong>(),
+    entry::<AuthChallenge>(),
+    entry::<AuthResponse>(),
+    entry::<Gossip>(),
+    entry::<PowChallenge>(),
+    entry::<PowResponse>(),
+    entry::<InfoRequest>(),
+    entry::<InfoResponse>(),
+    entry::<CodecOffer>(),
+    entry::<CodecAccept>(),
+    entry::<FindNode>(),
+    entry::<Nodes>(),
+];
+
+/// Ids below this one are reserved for the built-in messages.
+pub const CUSTOM_ID_BASE: u64 = 1024;
+
+/// The messages known to a controller: the built-in ones, and those its
+/// application registered, by id and tag.
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    custom: Vec<(u64, String)>,
+}
+
+const fn entry<M: WireMessage>() -> (u64, &'static str, ParseFn) {
+    (M::ID, M::TAG, wire::parse_message::<M>)
+}
+
+impl Registry {
+    /// Register a message of the application. The id must not be below
+    /// `CUSTOM_ID_BASE`, and neither the id nor the tag can be taken already.
+    pub fn register(&mut self, id: u64, tag: &str) -> Result<(), Error> {
+        if id < CUSTOM_ID_BASE {
+            return Err(Error::InvalidRegistration {
+                detail: format!(
+                    "Id {id} of '{tag}' is reserved, custom ids start at {CUSTOM_ID_BASE}"
+                ),
+            });
+        }
+        let taken = MESSAGES
+            .iter()
+            .any(|(_, known, _)| known.eq_ignore_ascii_case(tag))
+            || self
+                .custom
+                .iter()
+                .any(|(known_id, known)| *known_id == id || known.eq_ignore_ascii_case(tag));
+        if taken {
+            return Err(Error::InvalidRegistration {
+                detail: format!("Id {id} or tag '{tag}' is already registered"),
+            });
+        }
+        self.custom.push((id, tag.to_owned()));
+        Ok(())
+    }
+
+    /// The id of a message, built-in or registered, by tag.
+    pub fn id(&self, tag: &str) -> Option<u64> {
+        MESSAGES
+            .iter()
+            .find(|(_, known, _)| known.eq_ignore_ascii_case(tag))
+            .map(|(id, _, _)| *id)
+            .or_else(|| {
+                self.custom
+                    .iter()
+                    .find(|(_, known)| known.eq_ignore_ascii_case(tag))
+                    .map(|(id, _)| *id)
+            })
+    }
+
+    /// The tag of a message, built-in or registered, by id.
+    pub fn tag(&self, id: u64) -> Option<String> {
+        MESSAGES
+            .iter()
+            .find(|(known, _, _)| *known == id)
+            .map(|(_, tag, _)| (*tag).to_owned())
+            .or_else(|| {
+                self.custom
+                    .iter()
+                    .find(|(known, _)| *known == id)
+                    .map(|(_, tag)| tag.clone())
+            })
+    }
+
+    /// The parser of a built-in message, by tag. Tags are case insensitive.
+    /// Registered messages have none: they are received as `Message::Unknown`.
+    pub(crate) fn parser(&self, tag: &str) -> Option<ParseFn> {
+        MESSAGES
+            .iter()
+            .find(|(_, known, _)| known.eq_ignore_ascii_case(tag))
+            .map(|(_, _, parse_fn)| *parse_fn)
+    }
+
+    /// The parser of a built-in message, by id.
+    pub(crate) fn parser_by_id(&self, id: u64) -> Option<ParseFn> {
+        MESSAGES
+            .iter()
+            .find(|(known, _, _)| *known == id)
+            .map(|(_, _, parse_fn)| *parse_fn)
+    }
+
+    /// Replace the tag starting a message frame by the message's id, if it
+    /// has one.
+    pub fn with_id(&self, frame: Frame) -> Frame {
+        match frame {
+            Frame::Array(mut fields) => {
+                if let Some(Frame::String(tag)) = fields.first() {
+                    if let Some(id) = self.id(tag) {
+                        fields[0] = Frame::UInt(id);
+                    }
+                }
+                Frame::Array(fields)
+            }
+            frame => frame,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_register_custom_messages() {
+        let mut ids = MESSAGES.iter().map(|(id, _, _)| *id).collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), MESSAGES.len());
+        assert!(ids.iter().all(|id| *id < CUSTOM_ID_BASE));
+
+        let mut registry = Registry::default();
+        assert!(registry.register(7, "VOTE").is_err());
+        assert!(registry.register(CUSTOM_ID_BASE + 1, "hbt_req").is_err());
+        registry.register(CUSTOM_ID_BASE + 1, "VOTE").unwrap();
+        assert!(registry.register(CUSTOM_ID_BASE + 1, "OTHER").is_err());
+        assert_eq!(registry.id("vote"), Some(CUSTOM_ID_BASE + 1));
+        assert_eq!(registry.tag(CUSTOM_ID_BASE + 1).as_deref(), Some("VOTE"));
+
+        // Custom messages are sent with their id, and received by tag.
+        let frame = registry.with_id(crate::frame!["VOTE", "hello"]);
+        assert_eq!(frame.to_string(), r#"[1025, "hello"]"#);
+        match Message::from_frame_in(frame.clone(), &registry).unwrap() {
+            Message::Unknown { tag, .. } => assert_eq!(tag, "VOTE"),
+            msg => panic!("Expected an unknown message, got {msg:?}"),
+        }
+
+        // Other registries do not know the message.
+        assert_eq!(Registry::default().id("VOTE"), None);
+        match Message::from_frame(frame).unwrap() {
+            Message::Unknown { tag, .. } => assert_eq!(tag, "1025"),
+            msg => panic!("Expected an unknown message, got {msg:?}"),
+        }
+    }
+}
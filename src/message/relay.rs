@@ -0,0 +1,120 @@
+//! Relay messages
+//!
+//! Two nodes which cannot reach each other open a circuit through a relay
+//! node they are both connected to. The circuit carries the byte stream of a
+//! connection between the two ends, hex encoded in data messages.
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use super::error::Error;
+use super::WireMessage;
+use crate::hex;
+use crate::parse;
+use crate::Frame;
+use crate::Parse;
+
+/// Open a circuit
+#[derive(Debug)]
+pub struct RelayOpen {
+    /// Id of the circuit, chosen by the node opening it.
+    pub circuit: Uuid,
+    /// Address of the other end: the node to reach when sent to the relay,
+    /// and the node which opened the circuit when sent by the relay.
+    pub addr: SocketAddr,
+}
+
+/// Bytes sent on a circuit
+#[derive(Debug)]
+pub struct RelayData {
+    /// Id of the circuit
+    pub circuit: Uuid,
+    /// Bytes of the connection carried by the circuit
+    pub data: Vec<u8>,
+}
+
+/// Close a circuit
+#[derive(Debug)]
+pub struct RelayClose {
+    /// Id of the circuit
+    pub circuit: Uuid,
+}
+
+impl RelayOpen {
+    /// Creates a new message
+    pub fn new(circuit: Uuid, addr: SocketAddr) -> RelayOpen {
+        RelayOpen { circuit, addr }
+    }
+}
+
+impl RelayData {
+    /// Creates a new message
+    pub fn new(circuit: Uuid, data: Vec<u8>) -> RelayData {
+        RelayData { circuit, data }
+    }
+}
+
+impl RelayClose {
+    /// Creates a new message
+    pub fn new(circuit: Uuid) -> RelayClose {
+        RelayClose { circuit }
+    }
+}
+
+impl WireMessage for RelayOpen {
+    const TAG: &'static str = "RELAY_OPEN";
+    const ID: u64 = 8;
+
+    /// Extract a RelayOpen message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<RelayOpen, Error> {
+        let circuit = parse.next_uuid()?;
+        let addr = parse.next_addr()?;
+        Ok(RelayOpen { circuit, addr })
+    }
+
+    /// The Relay Open fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![
+            self.circuit.to_string(),
+            self.addr.to_string()
+        ])
+    }
+}
+
+impl WireMessage for RelayData {
+    const TAG: &'static str = "RELAY_DATA";
+    const ID: u64 = 9;
+
+    /// Extract a RelayData message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<RelayData, Error> {
+        let circuit = parse.next_uuid()?;
+        let data = parse.next_string()?;
+        let data = hex::decode(&data).ok_or_else(|| parse::Error::InvalidValue {
+            detail: "Expected hexadecimal data".to_owned(),
+        })?;
+        Ok(RelayData { circuit, data })
+    }
+
+    /// The Relay Data fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![
+            self.circuit.to_string(),
+            hex::encode(&self.data)
+        ])
+    }
+}
+
+impl WireMessage for RelayClose {
+    const TAG: &'static str = "RELAY_CLOSE";
+    const ID: u64 = 10;
+
+    /// Extract a RelayClose message from the parse.
+    fn parse_frames(parse: &mut Parse) -> Result<RelayClose, Error> {
+        let circuit = parse.next_uuid()?;
+        Ok(RelayClose { circuit })
+    }
+
+    /// The Relay Close fields
+    fn into_fields(self) -> Result<Frame, Error> {
+        Ok(crate::frame![self.circuit.to_string()])
+    }
+}
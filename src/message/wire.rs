@@ -0,0 +1,84 @@
+//! Trait shared by all the messages of the protocol
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::error::Error;
+use super::Message;
+use crate::frame_serde::{FrameDeserialize, FrameSerialize};
+use crate::Frame;
+use crate::Parse;
+
+/// A message that can be sent over the wire.
+///
+/// On the wire, a message is an array frame, starting with the message's tag,
+/// followed by the message's fields.
+pub trait WireMessage: Sized + Into<Message> {
+    /// Tag identifying the message type on the wire (eg 'CONN_REQ')
+    const TAG: &'static str;
+
+    /// Stable numeric id, sent instead of the tag with `wire.numeric_ids`.
+    /// See `message::registry`.
+    const ID: u64;
+
+    /// Extract the message from the parse, positioned after the tag.
+    fn parse_frames(parse: &mut Parse) -> Result<Self, Error>;
+
+    /// The message's fields, but not the tag, as an array frame. A frame
+    /// which is not an array is a single field.
+    fn into_fields(self) -> Result<Frame, Error>;
+
+    /// Convert the message into a frame.
+    fn into_frame(self) -> Result<Frame, Error> {
+        Ok(tagged_frame(Self::TAG, self.into_fields()?))
+    }
+}
+
+/// The frame of a message, built-in or custom: an array starting with the
+/// message's tag, followed by its fields.
+pub fn tagged_frame(tag: &str, fields: Frame) -> Frame {
+    let mut frame = vec![Frame::from(tag)];
+    match fields {
+        Frame::Array(fields) => frame.extend(fields),
+        field => frame.push(field),
+    }
+    Frame::Array(frame)
+}
+
+/// Extract a message deriving `Deserialize` from the fields left in the parse.
+/// Fields sent by newer nodes, which the message doesn't have, are ignored.
+pub fn parse_deserialized<M: DeserializeOwned>(parse: &mut Parse) -> Result<M, Error> {
+    Ok(M::from_frame(parse.rest())?)
+}
+
+/// The fields of a message deriving `Serialize`.
+pub fn serialized_fields<M: Serialize>(msg: &M) -> Result<Frame, Error> {
+    Ok(msg.to_frame()?)
+}
+
+/// Implement `WireMessage` for messages deriving `Serialize` and `Deserialize`,
+/// with the given id and tag. The fields are sent in the order they are declared.
+#[macro_export]
+macro_rules! impl_wire_message {
+    ($message:ty, $id:expr, $tag:expr) => {
+        impl $crate::message::WireMessage for $message {
+            const TAG: &'static str = $tag;
+            const ID: u64 = $id;
+
+            fn parse_frames(
+                parse: &mut $crate::Parse,
+            ) -> Result<Self, $crate::message::error::Error> {
+                $crate::message::wire::parse_deserialized(parse)
+            }
+
+            fn into_fields(self) -> Result<$crate::Frame, $crate::message::error::Error> {
+                $crate::message::wire::serialized_fields(&self)
+            }
+        }
+    };
+}
+
+/// Parse the message `M`, and wrap it into a `Message`.
+pub(crate) fn parse_message<M: WireMessage>(parse: &mut Parse) -> Result<Message, Error> {
+    M::parse_frames(parse).map(Into::into)
+}
@@ -0,0 +1,206 @@
+//! Address book.
+//!
+//! The controller keeps the nodes it knows in a JSON file: those it is
+//! connected to, and its idle addresses, with their id and label when known,
+//! the last time they were seen, and their score. The book is written
+//! periodically and on shutdown, and read at startup along with the target
+//! file, so that a restarted node rejoins the network without a list of peers.
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::sync::Mutex;
+use tokio::task;
+use uuid::Uuid;
+
+use super::controller::{IdleState, IncomingState, OutgoingState};
+use super::policy::Tags;
+use super::score;
+use super::snapshot;
+
+/// A node of the address book.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Address the node listens on.
+    pub addr: SocketAddr,
+    /// Controller id of the node, if we were connected to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    /// Label of the node, if we were connected to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Last time (micros) the node was seen, by us or by our peers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<i64>,
+    /// Score of the node, between 0 (excluded) and 1.
+    pub score: f64,
+    /// Tags of the node.
+    #[serde(default, skip_serializing_if = "Tags::is_empty")]
+    pub tags: Tags,
+}
+
+/// Collect the nodes we are connected to, and the idle addresses. The nodes we
+/// are connected to are seen now.
+pub async fn collect(
+    incoming: &Mutex<IncomingState>,
+    outgoing: &Mutex<OutgoingState>,
+    idle: &Mutex<IdleState>,
+) -> Vec<Entry> {
+    let now = Utc::now().timestamp_micros();
+    let mut entries = HashMap::new();
+    let idle = idle.lock().await;
+    let score = |addr| score::peer_score(idle.history.get(&addr));
+    for info in idle.addrs.iter() {
+        entries.insert(
+            info.addr,
+            Entry {
+                addr: info.addr,
+                id: None,
+                label: None,
+                last_seen: idle.history.get(&info.addr).and_then(|h| h.last_seen),
+                score: score(info.addr),
+                tags: info.tags.clone(),
+            },
+        );
+    }
+    for info in incoming.lock().await.connected.values() {
+        entries.insert(
+            info.addr,
+            Entry {
+                addr: info.addr,
+                id: Some(info.id),
+                label: Some(info.label.clone()),
+                last_seen: Some(now),
+                score: score(info.addr),
+                tags: Tags::new(),
+            },
+        );
+    }
+    for info in outgoing.lock().await.connected.values() {
+        entries.insert(
+            info.addr,
+            Entry {
+                addr: info.addr,
+                id: Some(info.id),
+                label: Some(info.label.clone()),
+                last_seen: Some(now),
+                score: score(info.addr),
+                tags: info.tags.clone(),
+            },
+        );
+    }
+    let mut entries = entries.into_values().collect::<Vec<_>>();
+    entries.sort_by(|a, b| b.score.total_cmp(&a.score));
+    entries
+}
+
+/// Write the address book to the given path, atomically.
+pub async fn save(path: &Path, entries: &[Entry]) -> Result<(), Error> {
+    let content =
+        serde_json::to_vec_pretty(entries).map_err(|err| Error::Serialize { source: err })?;
+    let path = path.to_owned();
+    task::spawn_blocking(move || snapshot::write_atomic(&path, &content))
+        .await
+        .map_err(|err| Error::IO {
+            source: err.into(),
+            detail: "Address book thread failed".to_owned(),
+        })?
+        .map_err(|err| Error::Write { source: err })
+}
+
+/// Read the address book at the given path. A missing book is empty.
+pub async fn load(path: &Path) -> Result<Vec<Entry>, Error> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(Error::IO {
+                source: err,
+                detail: format!("Could not read {}", path.display()),
+            })
+        }
+    };
+    serde_json::from_str(&content).map_err(|err| Error::Serialize { source: err })
+}
+
+/// Error type for the address book
+#[derive(Debug)]
+pub enum Error {
+    /// IO Error
+    IO {
+        /// Source
+        source: std::io::Error,
+        /// Error detail
+        detail: String,
+    },
+    /// The book could not be written
+    Write {
+        /// Source
+        source: snapshot::Error,
+    },
+    /// The book could not be serialized, or deserialized
+    Serialize {
+        /// Source
+        source: serde_json::Error,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IO { source, detail } => write!(f, "IO Error => {} [{}]", detail, source),
+            Error::Write { source } => write!(f, "Could not write address book: {}", source),
+            Error::Serialize { source } => write!(f, "Invalid address book: {}", source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use crate::network::controller::{AddrInfo, OutConnInfo};
+
+    #[tokio::test]
+    async fn should_keep_known_nodes_across_restarts() {
+        let idle_addr = SocketAddr::from_str("[::1]:8090").unwrap();
+        let peer_addr = SocketAddr::from_str("[::1]:8091").unwrap();
+        let idle = Mutex::new(IdleState::default());
+        idle.lock().await.addrs.insert(AddrInfo {
+            addr: idle_addr,
+            attempt: Arc::new(Mutex::new(0)),
+            tags: Tags::new(),
+        });
+        let outgoing = Mutex::new(OutgoingState::default());
+        let id = Uuid::new_v4();
+        outgoing.lock().await.connected.insert(
+            Uuid::new_v4(),
+            OutConnInfo {
+                addr: peer_addr,
+                id,
+                label: "bob".to_owned(),
+                rtt: i64::MAX,
+                offset: 0,
+                tags: Tags::new(),
+                health: None,
+            },
+        );
+        let entries = collect(&Mutex::new(IncomingState::default()), &outgoing, &idle).await;
+        assert_eq!(entries.len(), 2);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("addrbook.json");
+        assert!(load(&path).await.unwrap().is_empty());
+        save(&path, &entries).await.unwrap();
+        let loaded = load(&path).await.unwrap();
+        assert_eq!(loaded, entries);
+        let bob = loaded.iter().find(|entry| entry.addr == peer_addr).unwrap();
+        assert_eq!(bob.id, Some(id));
+        assert!(bob.last_seen.is_some());
+    }
+}
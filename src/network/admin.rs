@@ -0,0 +1,185 @@
+//! Administration server
+//!
+//! The admin server exposes the state of the network controller over HTTP.
+//! It listens on its own address, separate from the peer-to-peer listener,
+//! and has its own allow list, so that the management plane can be restricted
+//! (eg to localhost) while the peer-to-peer listener is public.
+use axum::extract::ConnectInfo;
+use axum::http::{Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use super::allowlist::AllowList;
+use super::controller::{summary, IdleState, InConnInfo, IncomingState, OutgoingState, Summary};
+use super::metrics::{Metrics, MetricsSnapshot};
+use super::snapshot;
+
+/// Handle to change the log filter at runtime.
+/// The application builds its subscriber with a reloadable filter, and hands
+/// this handle over to the network controller.
+pub type LogFilter = reload::Handle<EnvFilter, Registry>;
+
+/// Data shared by the admin handlers.
+#[derive(Debug, Clone)]
+pub struct AdminState {
+    /// Identity of the network controller
+    pub controller: InConnInfo,
+    /// Incoming state of the network controller
+    pub incoming: Arc<Mutex<IncomingState>>,
+    /// Outgoing state of the network controller
+    pub outgoing: Arc<Mutex<OutgoingState>>,
+    /// Idle state of the network controller
+    pub idle: Arc<Mutex<IdleState>>,
+    /// Metrics of the network controller
+    pub metrics: Arc<Metrics>,
+    /// Addresses allowed to use the admin server
+    pub allow: Arc<AllowList>,
+    /// Path snapshots are written to, if snapshots are configured.
+    pub snapshot: Option<PathBuf>,
+    /// Handle to the log filter, if the application made it reloadable.
+    pub log_filter: Option<LogFilter>,
+}
+
+/// Serve the admin endpoints on the given address.
+pub async fn serve(addr: SocketAddr, state: AdminState) -> Result<(), Error> {
+    let app = Router::new()
+        .route("/status", get(status))
+        .route("/metrics", get(metrics))
+        .route("/snapshot", post(take_snapshot))
+        .route("/log", get(log_filter).put(set_log_filter))
+        .route_layer(middleware::from_fn(guard))
+        .layer(Extension(state));
+
+    let server = axum::Server::try_bind(&addr).map_err(|err| Error::Bind {
+        detail: format!("Admin server cannot bind to addr {} ({})", addr, err),
+    })?;
+
+    log::info!("Admin | listening on {}.", addr);
+
+    server
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .map_err(|err| Error::Serve { source: err })
+}
+
+/// Reject requests coming from addresses not in the allow list.
+async fn guard<B>(req: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
+    let allowed = match (
+        req.extensions().get::<ConnectInfo<SocketAddr>>(),
+        req.extensions().get::<AdminState>(),
+    ) {
+        (Some(ConnectInfo(addr)), Some(state)) => state.allow.allows(&addr.ip()),
+        _ => false,
+    };
+    if allowed {
+        Ok(next.run(req).await)
+    } else {
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+async fn status(Extension(state): Extension<AdminState>) -> Json<Summary> {
+    Json(
+        summary(
+            state.controller.clone(),
+            &state.incoming,
+            &state.outgoing,
+            &state.idle,
+        )
+        .await,
+    )
+}
+
+async fn metrics(Extension(state): Extension<AdminState>) -> Json<MetricsSnapshot> {
+    Json(state.metrics.snapshot())
+}
+
+/// Write a snapshot of the controller's state to the configured file,
+/// and return it.
+async fn take_snapshot(
+    Extension(state): Extension<AdminState>,
+) -> Result<Json<snapshot::State>, (StatusCode, String)> {
+    let path = state.snapshot.as_ref().ok_or((
+        StatusCode::NOT_FOUND,
+        "Snapshots are not configured".to_owned(),
+    ))?;
+    let snapshot = snapshot::take(
+        state.controller.clone(),
+        &state.incoming,
+        &state.outgoing,
+        &state.idle,
+        &state.metrics,
+    )
+    .await;
+    snapshot
+        .save(path)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    log::info!("Admin | Snapshot written to {}", path.display());
+    Ok(Json(snapshot))
+}
+
+/// Return the current log filter.
+async fn log_filter(
+    Extension(state): Extension<AdminState>,
+) -> Result<String, (StatusCode, String)> {
+    let handle = state.log_filter.as_ref().ok_or((
+        StatusCode::NOT_FOUND,
+        "Log filter is not reloadable".to_owned(),
+    ))?;
+    handle
+        .with_current(|filter| filter.to_string())
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+/// Replace the log filter with the one given in the body, using the RUST_LOG
+/// syntax (eg 'info,area_net::network::peer=trace'), and return it.
+async fn set_log_filter(
+    Extension(state): Extension<AdminState>,
+    body: String,
+) -> Result<String, (StatusCode, String)> {
+    let handle = state.log_filter.as_ref().ok_or((
+        StatusCode::NOT_FOUND,
+        "Log filter is not reloadable".to_owned(),
+    ))?;
+    let filter = EnvFilter::try_new(body.trim())
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    let filter_str = filter.to_string();
+    handle
+        .reload(filter)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    log::info!("Admin | Log filter set to '{}'", filter_str);
+    Ok(filter_str)
+}
+
+/// Error type for the admin server
+#[derive(Debug)]
+pub enum Error {
+    /// The admin server could not bind its address
+    Bind {
+        /// Error detail
+        detail: String,
+    },
+    /// An error occured while serving requests
+    Serve {
+        /// Source
+        source: hyper::Error,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Bind { detail } => write!(f, "Cannot Bind Socket Address => {}", detail),
+            Error::Serve { source } => write!(f, "Admin Server Error => {}", source),
+        }
+    }
+}
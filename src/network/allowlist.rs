@@ -0,0 +1,109 @@
+//! List of network addresses allowed to connect to a listener.
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A list of IP networks, given as addresses ('::1') or
+/// prefixes ('10.0.0.0/8', 'fd00::/8').
+/// An empty list allows every address.
+#[derive(Debug, Clone, Default)]
+pub struct AllowList {
+    nets: Vec<(IpAddr, u8)>,
+}
+
+/// Error type for allow lists
+#[derive(Debug)]
+pub struct Error {
+    /// Entry that could not be parsed
+    pub entry: String,
+}
+
+impl AllowList {
+    /// Build an allow list from its configuration entries.
+    pub fn new<S: AsRef<str>>(entries: &[S]) -> Result<AllowList, Error> {
+        let nets = entries
+            .iter()
+            .map(|entry| parse_net(entry.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(AllowList { nets })
+    }
+
+    /// Returns true if the address is allowed.
+    pub fn allows(&self, addr: &IpAddr) -> bool {
+        if self.nets.is_empty() {
+            return true;
+        }
+        // An IPv6 listener sees IPv4 clients as IPv4-mapped addresses.
+        let addr = addr.to_canonical();
+        self.nets
+            .iter()
+            .any(|(net, prefix)| contains(net, *prefix, &addr))
+    }
+}
+
+fn parse_net(entry: &str) -> Result<(IpAddr, u8), Error> {
+    let error = || Error {
+        entry: entry.to_owned(),
+    };
+    let (addr, prefix) = match entry.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (entry, None),
+    };
+    let addr = IpAddr::from_str(addr.trim()).map_err(|_| error())?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.trim().parse::<u8>().map_err(|_| error())?,
+        None => max,
+    };
+    if prefix > max {
+        return Err(error());
+    }
+    Ok((addr, prefix))
+}
+
+fn contains(net: &IpAddr, prefix: u8, addr: &IpAddr) -> bool {
+    match (net, addr) {
+        (IpAddr::V4(net), IpAddr::V4(addr)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(*net) & mask == u32::from(*addr) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(addr)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(*net) & mask == u128::from(*addr) & mask
+        }
+        _ => false,
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid allow list entry: {}", self.entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_allow_everything_when_empty() {
+        let list = AllowList::new::<&str>(&[]).unwrap();
+        assert!(list.allows(&IpAddr::from_str("192.168.1.1").unwrap()));
+    }
+
+    #[test]
+    fn should_match_addresses_and_prefixes() {
+        let list = AllowList::new(&["::1", "10.0.0.0/8"]).unwrap();
+        assert!(list.allows(&IpAddr::from_str("::1").unwrap()));
+        assert!(list.allows(&IpAddr::from_str("10.1.2.3").unwrap()));
+        assert!(list.allows(&IpAddr::from_str("::ffff:10.1.2.3").unwrap()));
+        assert!(!list.allows(&IpAddr::from_str("11.1.2.3").unwrap()));
+        assert!(!list.allows(&IpAddr::from_str("::2").unwrap()));
+    }
+
+    #[test]
+    fn should_reject_invalid_entries() {
+        assert!(AllowList::new(&["10.0.0.0/33"]).is_err());
+        assert!(AllowList::new(&["localhost"]).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Authentication of the handshake.
+//!
+//! Nodes of a closed network share a secret. When a node has one, its
+//! incoming peers answer a connection request with a challenge, a random
+//! nonce, instead of a connection response. The remote must return the
+//! HMAC-SHA256 of the nonce with the shared secret; otherwise the connection
+//! is rejected. The proof is mutual: the incoming peer then returns the HMAC
+//! of the signature of the connection request, which the remote made fresh,
+//! and the remote refuses a connection response without it.
+//!
+//! Each side prefixes what it authenticates with its own label, so that the
+//! answer of one side cannot be reflected as the answer of the other.
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use std::path::Path;
+
+/// Number of random bytes in a challenge.
+pub const NONCE_LEN: usize = 32;
+
+/// Side of the handshake answering a challenge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// The peer which dialed, answering the nonce of the remote.
+    Dialer,
+    /// The peer which listened, answering the connection request.
+    Listener,
+}
+
+/// A secret shared by the nodes of the network.
+#[derive(Clone)]
+pub struct Secret {
+    key: hmac::Key,
+}
+
+/// Error type for the authentication configuration
+#[derive(Debug)]
+pub struct Error {
+    /// Error detail
+    pub detail: String,
+}
+
+impl Secret {
+    /// Create a secret from its bytes.
+    pub fn new(secret: &[u8]) -> Secret {
+        Secret {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    /// Read the secret from a file. Surrounding whitespace is not part of it.
+    pub fn with_file(path: &Path) -> Result<Secret, Error> {
+        let content = std::fs::read(path).map_err(|err| Error {
+            detail: format!("Could not read {}: {err}", path.display()),
+        })?;
+        let secret = content.trim_ascii();
+        if secret.is_empty() {
+            return Err(Error {
+                detail: format!("{} does not hold a secret", path.display()),
+            });
+        }
+        Ok(Secret::new(secret))
+    }
+
+    /// The answer of `side` to a challenge.
+    pub fn sign(&self, side: Side, challenge: &[u8]) -> Vec<u8> {
+        hmac::sign(&self.key, &side.label(challenge))
+            .as_ref()
+            .to_vec()
+    }
+
+    /// Returns true if the answer of `side` to the challenge is right.
+    pub fn verify(&self, side: Side, challenge: &[u8], mac: &[u8]) -> bool {
+        hmac::verify(&self.key, &side.label(challenge), mac).is_ok()
+    }
+}
+
+impl Side {
+    // The challenge, prefixed with the label of the side.
+    fn label(self, challenge: &[u8]) -> Vec<u8> {
+        let label: &[u8] = match self {
+            Side::Dialer => b"area-net auth dialer\n",
+            Side::Listener => b"area-net auth listener\n",
+        };
+        [label, challenge].concat()
+    }
+}
+
+// The key must not end up in the logs.
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret")
+    }
+}
+
+/// A new random challenge.
+pub fn nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .expect("system random number generator");
+    nonce
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid authentication configuration: {}", self.detail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_answer_challenges_with_the_shared_secret() {
+        let secret = Secret::new(b"open sesame");
+        let nonce = nonce();
+        let mac = secret.sign(Side::Dialer, &nonce);
+        assert!(secret.verify(Side::Dialer, &nonce, &mac));
+        assert!(!secret.verify(Side::Dialer, &super::nonce(), &mac));
+        assert!(!Secret::new(b"open barley").verify(Side::Dialer, &nonce, &mac));
+        assert!(!secret.verify(Side::Dialer, &nonce, &[]));
+        // The answer of one side is not the answer of the other.
+        assert!(!secret.verify(Side::Listener, &nonce, &mac));
+    }
+}
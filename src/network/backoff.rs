@@ -0,0 +1,36 @@
+//! Delay between the connection attempts to an address.
+//!
+//! The controller waits longer after each failed attempt to an address, so
+//! that a node which is down, or drops our connections, is not dialed every
+//! second. The delay doubles with each attempt, up to a cap, and a random
+//! part keeps the nodes which lost the same peer from all dialing it again
+//! at once.
+use std::time::Duration;
+
+/// Delay before the next attempt, after the given number of failed attempts:
+/// `base` × 2^`attempt`, capped at `max`. The delay is drawn between half of
+/// this value and the value itself.
+pub fn delay(base: Duration, attempt: i32, max: Duration) -> Duration {
+    // 2^16 times the base delay is beyond any sensible cap.
+    let exponent = attempt.clamp(0, 16) as u32;
+    let full = base.saturating_mul(1 << exponent).min(max);
+    full / 2 + full.mul_f64(fastrand::f64() / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_double_the_delay_up_to_the_cap() {
+        let base = Duration::from_secs(1);
+        let max = Duration::from_secs(60);
+        for attempt in 0..8 {
+            let full = Duration::from_secs(1 << attempt).min(max);
+            let delay = delay(base, attempt, max);
+            assert!(delay >= full / 2 && delay <= full, "{attempt}: {delay:?}");
+        }
+        assert!(delay(base, i32::MAX, max) <= max);
+        assert!(delay(base, -1, max) <= base);
+    }
+}
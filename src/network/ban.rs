@@ -0,0 +1,160 @@
+//! Ban list.
+//!
+//! The controller bans remote nodes by address or by id, for a time or until
+//! it restarts. It rejects the connections from banned addresses, does not
+//! dial them, and closes the connections with banned nodes once their
+//! handshake tells us their id. A ban on an address applies to its IP, as
+//! the port of an incoming connection is not the one the remote listens on.
+//!
+//! The list holds a limited number of bans: beyond, the oldest ban is lifted.
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// What a ban applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    /// All the connections from, or to, the IP.
+    Addr(IpAddr),
+    /// The node with the given controller id, wherever it connects from.
+    Peer(Uuid),
+}
+
+/// Banned addresses and nodes, with the time their ban expires.
+#[derive(Debug, Default)]
+pub struct Bans {
+    bans: Mutex<HashMap<Target, Ban>>,
+    // 0 for no limit.
+    max: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Ban {
+    since: Instant,
+    // None for the bans which don't expire.
+    expiry: Option<Instant>,
+}
+
+impl Bans {
+    /// Create a ban list holding at most `max` bans, 0 for no limit.
+    pub fn new(max: usize) -> Bans {
+        Bans {
+            bans: Mutex::new(HashMap::new()),
+            max,
+        }
+    }
+
+    /// Ban the target for the given duration, or until the controller
+    /// restarts. A ban replaces the previous ban of the target. Returns the
+    /// target whose ban was lifted to make room, if any.
+    pub fn ban(&self, target: Target, duration: Option<Duration>, now: Instant) -> Option<Target> {
+        let expiry = duration.map(|duration| now + duration);
+        let mut bans = self.bans.lock().expect("bans lock");
+        bans.insert(target, Ban { since: now, expiry });
+        if self.max == 0 || bans.len() <= self.max {
+            return None;
+        }
+        bans.retain(|_, ban| ban.is_active(now));
+        if bans.len() <= self.max {
+            return None;
+        }
+        let oldest = bans
+            .iter()
+            .filter(|(banned, _)| **banned != target)
+            .min_by_key(|(_, ban)| ban.since)
+            .map(|(banned, _)| *banned)?;
+        bans.remove(&oldest);
+        Some(oldest)
+    }
+
+    /// Lift the ban of the target. Returns true if it was banned.
+    pub fn unban(&self, target: &Target) -> bool {
+        self.bans
+            .lock()
+            .expect("bans lock")
+            .remove(target)
+            .is_some()
+    }
+
+    /// Returns true if the target is banned at `now`.
+    pub fn is_banned(&self, target: &Target, now: Instant) -> bool {
+        let mut bans = self.bans.lock().expect("bans lock");
+        // Expired bans are forgotten.
+        bans.retain(|_, ban| ban.is_active(now));
+        bans.contains_key(target)
+    }
+
+    /// Returns true if the IP of the address is banned at `now`.
+    pub fn is_addr_banned(&self, addr: &SocketAddr, now: Instant) -> bool {
+        self.is_banned(&Target::Addr(addr.ip()), now)
+    }
+}
+
+impl Ban {
+    fn is_active(&self, now: Instant) -> bool {
+        self.expiry.is_none_or(|expiry| now < expiry)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Addr(ip) => write!(f, "{ip}"),
+            Target::Peer(id) => write!(f, "{id}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn should_expire_bans() {
+        let bans = Bans::default();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let addr = SocketAddr::from_str("192.168.0.10:8090").unwrap();
+        let now = Instant::now();
+        bans.ban(Target::Peer(alice), Some(Duration::from_secs(60)), now);
+        bans.ban(Target::Addr(addr.ip()), None, now);
+
+        assert!(bans.is_banned(&Target::Peer(alice), now));
+        assert!(!bans.is_banned(&Target::Peer(bob), now));
+        // Any port of the IP is banned.
+        let other_port = SocketAddr::from_str("192.168.0.10:51234").unwrap();
+        assert!(bans.is_addr_banned(&other_port, now));
+
+        let later = now + Duration::from_secs(60);
+        assert!(!bans.is_banned(&Target::Peer(alice), later));
+        assert!(bans.is_addr_banned(&addr, later));
+        assert!(bans.unban(&Target::Addr(addr.ip())));
+        assert!(!bans.is_addr_banned(&addr, later));
+    }
+
+    #[test]
+    fn should_lift_the_oldest_ban_beyond_the_limit() {
+        let bans = Bans::new(2);
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+        assert_eq!(bans.ban(Target::Peer(alice), None, now), None);
+        assert_eq!(bans.ban(Target::Peer(bob), None, later), None);
+        assert_eq!(
+            bans.ban(Target::Peer(carol), None, later),
+            Some(Target::Peer(alice))
+        );
+        assert!(!bans.is_banned(&Target::Peer(alice), later));
+        assert!(bans.is_banned(&Target::Peer(carol), later));
+
+        // Expired bans make room first.
+        let bans = Bans::new(1);
+        bans.ban(Target::Peer(alice), Some(Duration::from_secs(1)), now);
+        assert_eq!(bans.ban(Target::Peer(bob), None, later), None);
+    }
+}
@@ -0,0 +1,143 @@
+//! Chunked streaming of large frames.
+//!
+//! Frames larger than `wire.chunk_size` are encoded in the binary framing and
+//! split into `CHUNK` messages. The peer sends them one command at a time, so
+//! that heartbeats and other messages go out in between, rather than waiting
+//! behind a large payload. The receiving peer reassembles them into the
+//! original frame.
+use bytes::{Bytes, BytesMut};
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use crate::binary;
+use crate::frame::{Error, Limits};
+use crate::message::Chunk;
+use crate::Frame;
+
+/// Split a frame into chunks of at most `size` bytes, in the given stream.
+pub fn split(
+    frame: &Frame,
+    stream: u64,
+    size: usize,
+    limits: &Limits,
+) -> Result<Vec<Chunk>, Error> {
+    let mut buf = BytesMut::new();
+    binary::write(frame, &mut buf, limits)?;
+    let bytes: Bytes = buf.freeze();
+    let size = size.max(1);
+    let count = bytes.len().div_ceil(size);
+    Ok((0..count)
+        .map(|i| {
+            let end = ((i + 1) * size).min(bytes.len());
+            Chunk::new(stream, i as u64, i + 1 == count, bytes.slice(i * size..end))
+        })
+        .collect())
+}
+
+/// Reassembles the chunks received from the remote. The bytes buffered for
+/// all the streams are bounded by `max_buffer_size`.
+#[derive(Debug)]
+pub struct Reassembler {
+    limits: Limits,
+    streams: HashMap<u64, Stream>,
+    buffered: usize,
+}
+
+#[derive(Debug, Default)]
+struct Stream {
+    next: u64,
+    data: BytesMut,
+}
+
+impl Reassembler {
+    /// Reassembles frames within the given limits.
+    pub fn new(limits: Limits) -> Reassembler {
+        Reassembler {
+            limits,
+            streams: HashMap::new(),
+            buffered: 0,
+        }
+    }
+
+    /// Add a chunk, and return the frame once its last chunk is received.
+    /// A stream with a missing chunk, or exceeding the limits, is dropped.
+    pub fn push(&mut self, chunk: Chunk) -> Result<Option<Frame>, Error> {
+        let stream = self.streams.entry(chunk.stream).or_default();
+        if chunk.seq != stream.next {
+            let detail = format!(
+                "Chunk {} of stream {}, expected chunk {}",
+                chunk.seq, chunk.stream, stream.next
+            );
+            self.drop_stream(chunk.stream);
+            return Err(Error::UnexpectedBytes { detail });
+        }
+        if self.buffered + chunk.data.len() > self.limits.max_buffer_size {
+            self.drop_stream(chunk.stream);
+            return Err(Error::LimitExceeded {
+                detail: format!(
+                    "More than {} bytes of chunks buffered",
+                    self.limits.max_buffer_size
+                ),
+            });
+        }
+        stream.next += 1;
+        stream.data.extend_from_slice(&chunk.data);
+        self.buffered += chunk.data.len();
+        if !chunk.last {
+            return Ok(None);
+        }
+        let stream = self.drop_stream(chunk.stream).unwrap_or_default();
+        let mut src = Cursor::new(&stream.data[..]);
+        let frame = binary::parse(&mut src, &self.limits)?;
+        if src.position() as usize != stream.data.len() {
+            return Err(Error::UnexpectedBytes {
+                detail: format!("Trailing bytes in stream {}", chunk.stream),
+            });
+        }
+        Ok(Some(frame))
+    }
+
+    // Forget a stream, and release its bytes.
+    fn drop_stream(&mut self, id: u64) -> Option<Stream> {
+        let stream = self.streams.remove(&id)?;
+        self.buffered -= stream.data.len();
+        Some(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_split_and_reassemble_large_frames() {
+        let frame = Frame::Array(vec![
+            Frame::String("PAYLOAD".to_owned()),
+            Frame::Bulk(Bytes::from(vec![7u8; 10_000])),
+        ]);
+        let limits = Limits::default();
+        let chunks = split(&frame, 1, 4096, &limits).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[2].last && !chunks[1].last);
+
+        let mut reassembler = Reassembler::new(limits);
+        let mut frames = chunks
+            .into_iter()
+            .filter_map(|chunk| reassembler.push(chunk).unwrap());
+        let reassembled = frames.next().unwrap();
+        assert_eq!(format!("{reassembled:?}"), format!("{frame:?}"));
+        assert!(frames.next().is_none());
+
+        // A missing chunk drops the stream.
+        let mut chunks = split(&frame, 2, 4096, &limits).unwrap();
+        chunks.remove(1);
+        let mut reassembler = Reassembler::new(limits);
+        let mut results = chunks.into_iter().map(|chunk| reassembler.push(chunk));
+        assert!(matches!(results.next(), Some(Ok(None))));
+        assert!(matches!(
+            results.next(),
+            Some(Err(Error::UnexpectedBytes { .. }))
+        ));
+        assert_eq!(reassembler.buffered, 0);
+    }
+}
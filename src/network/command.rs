@@ -1,11 +1,26 @@
 //! Command are sent to the peer.
+use bytes::Bytes;
 use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use super::controller::Summary;
+use super::identity::Proof;
+use super::policy::Tags;
+use super::relay::RelayMessage;
+use super::transport::Connection;
+use crate::codec::{CodecOptions, Compression};
+use crate::message::bye::Reason;
+use crate::message::conn_rejection::RejectReason;
+use crate::message::dht::Node;
+use crate::message::{Contact, ContactResponse, Health, InfoResponse};
+use crate::Frame;
 
 /// Commands issued by the network controller to the peers
 #[derive(Debug)]
 pub enum Command {
-    /// Peer has to initiate a TcpStream connection
+    /// Peer has to initiate a connection
     /// TODO Do we need to add a timeout?
     Connect {
         /// address to connect to
@@ -15,26 +30,38 @@ pub enum Command {
     },
     /// Listen to messages from remote peer
     Listen {
-        /// TcpStream
-        stream: TcpStream,
+        /// Connection accepted by the controller, boxed as it is large
+        conn: Box<Connection>,
     },
     /// Send a Connection Request (for Handshake)
     SendConnRequest,
     /// Send a Connection Response (for Handshake)
     SendConnResponse {
         /// peer id
-        peer_id: String,
+        peer_id: Uuid,
         /// peer label
         peer_label: String,
         /// peer addr
-        peer_addr: String,
+        peer_addr: SocketAddr,
+        /// compression offered by the peer
+        compression: Option<Compression>,
+        /// signature of the request, if the peer sent one
+        proof: Option<Box<Proof>>,
+        /// network of the peer, if not the default one
+        network: Option<Box<String>>,
     },
     /// Finalize the connection
     FinalizeConn {
         /// peer id
-        peer_id: String,
+        peer_id: Uuid,
         /// peer label
         peer_label: String,
+        /// our address, as seen by the remote peer
+        observed: Option<SocketAddr>,
+        /// compression accepted by the peer
+        compression: Option<Compression>,
+        /// signature of the response, if the peer sent one
+        proof: Option<Box<Proof>>,
     },
     /// Send a heartbeat request
     HeartbeatRequest,
@@ -45,6 +72,8 @@ pub enum Command {
         /// that was given in the heartbeat
         /// request.
         src: i64,
+        /// health of the remote, if it sent it
+        health: Option<Health>,
     },
     /// We missed a heartbeat
     HeartbeatTimeout,
@@ -54,6 +83,10 @@ pub enum Command {
     CancelHeartbeatTimeout {
         /// Round Trip Time in microseconds.
         rtt: i64,
+        /// Clock offset of the remote in microseconds.
+        offset: i64,
+        /// health of the remote, if it sent it
+        health: Option<Health>,
     },
     /// Request the peer to send a ContactRequest to its remote.
     SendContactRequest,
@@ -63,11 +96,194 @@ pub enum Command {
     SendContactResponse {
         /// list of addresses to send to the remote
         addrs: Vec<SocketAddr>,
+        /// tags of each address
+        tags: Vec<Tags>,
+        /// node at each address
+        contacts: Vec<Contact>,
     },
     /// Request the peer to senda ContactUpdated to the controller
     UpdateContacts {
-        /// list of addresses to send to the controller
-        addrs: Vec<SocketAddr>,
+        /// contacts sent by the remote
+        response: Box<ContactResponse>,
+    },
+    /// Request the peer to send a relay message to its remote.
+    SendRelay {
+        /// relay message
+        message: RelayMessage,
+    },
+    /// Request the peer to hand a relay message received from its remote
+    /// over to the controller.
+    RelayReceived {
+        /// relay message
+        message: RelayMessage,
+    },
+    /// Request the peer to send application data to its remote.
+    SendPayload {
+        /// topic of the data, empty if none
+        topic: String,
+        /// application data
+        data: Bytes,
+    },
+    /// Request the peer to hand application data received from its remote
+    /// over to the controller.
+    PayloadReceived {
+        /// topic of the data, empty if none
+        topic: String,
+        /// application data
+        data: Bytes,
+    },
+    /// Request the peer to send a gossip message to its remote.
+    SendGossip {
+        /// id of the message
+        msg_id: Uuid,
+        /// number of hops left
+        ttl: u64,
+        /// topic of the data, empty if none
+        topic: String,
+        /// application data
+        data: Bytes,
+    },
+    /// Request the peer to hand a gossip message received from its remote
+    /// over to the controller.
+    GossipReceived {
+        /// id of the message
+        msg_id: Uuid,
+        /// number of hops left, this one included
+        ttl: u64,
+        /// topic of the data, empty if none
+        topic: String,
+        /// application data
+        data: Bytes,
+    },
+    /// Request the peer to ping its remote.
+    SendPing {
+        /// sequence number
+        seq: u64,
+        /// data echoed by the remote
+        data: Bytes,
+    },
+    /// Request the peer to echo a ping received from its remote.
+    SendPong {
+        /// sequence number of the ping
+        seq: u64,
+        /// data of the ping
+        data: Bytes,
+    },
+    /// Request the peer to hand the echo of a ping over to the controller.
+    PongReceived {
+        /// sequence number of the ping
+        seq: u64,
+        /// number of bytes echoed
+        size: usize,
+    },
+    /// Request the peer to ask its remote about itself.
+    SendInfoRequest,
+    /// Request the peer to ask the controller for the information its
+    /// remote asked for.
+    InfoRequested,
+    /// Request the peer to send the information of the node to its remote.
+    SendInfoResponse {
+        /// information of the node
+        info: Box<InfoResponse>,
+    },
+    /// Request the peer to hand the information of its remote over to the
+    /// controller.
+    InfoReceived {
+        /// information of the remote node
+        info: Box<InfoResponse>,
+    },
+    /// Request the peer to ask its remote for the nodes closest to an id.
+    SendFindNode {
+        /// id looked up
+        target: Uuid,
+    },
+    /// Request the peer to ask the controller for the nodes its remote
+    /// looks up.
+    FindNodeRequested {
+        /// id looked up
+        target: Uuid,
+    },
+    /// Request the peer to send the nodes closest to an id to its remote.
+    SendNodes {
+        /// id looked up
+        target: Uuid,
+        /// nodes, closest first
+        nodes: Vec<Node>,
+    },
+    /// Request the peer to hand the nodes sent by its remote over to the
+    /// controller.
+    NodesReceived {
+        /// id looked up
+        target: Uuid,
+        /// nodes, closest first
+        nodes: Vec<Node>,
+    },
+    /// Request the peer to answer the codec options offered by its remote.
+    CodecOffered {
+        /// options offered
+        options: CodecOptions,
+    },
+    /// Request the peer to switch to the codec options its remote accepted.
+    CodecAccepted {
+        /// options accepted
+        options: CodecOptions,
+    },
+    /// Request the peer to send a message of the application.
+    SendCustom {
+        /// tag of the message
+        tag: String,
+        /// frame of the message, tag included
+        frame: Frame,
+    },
+    /// Request the peer to send the next chunk of a large frame.
+    SendChunk,
+    /// Request the peer to deal with a message it doesn't know, as
+    /// configured in `wire.unknown_messages`.
+    UnknownReceived {
+        /// tag of the message
+        tag: String,
+        /// frame of the message
+        frame: Frame,
+    },
+    /// Request the peer to answer the challenge of its remote.
+    AuthChallengeReceived {
+        /// random bytes chosen by the remote
+        nonce: Bytes,
+    },
+    /// Request the peer to check the answer of its remote to its challenge.
+    AuthResponseReceived {
+        /// HMAC of the nonce
+        mac: Bytes,
+    },
+    /// Request the peer to do the work its remote asks for.
+    PowChallengeReceived {
+        /// random bytes chosen by the remote
+        nonce: Bytes,
+        /// number of leading zero bits required
+        difficulty: u64,
+    },
+    /// Request the peer to check the work of its remote.
+    PowResponseReceived {
+        /// solution found by the remote
+        solution: u64,
+    },
+    /// Request the peer to close its connection, as its remote refused it.
+    ConnRejected {
+        /// why the remote refused the connection
+        reason: RejectReason,
+    },
+    /// Request the peer to close its connection, as its remote said goodbye.
+    ByeReceived {
+        /// why the remote closes the connection
+        reason: Reason,
+    },
+    /// Request the peer to report a frame of the remote which failed the
+    /// session key check.
+    ViolationReceived {
+        /// what was wrong with the frame
+        detail: String,
+        /// whether the connection is closed, as the frame was not sealed at all
+        close: bool,
     },
     /// At anypoint we can ask the peer to terminate the connection with the remote peer.
     Disconnect,
@@ -75,6 +291,43 @@ pub enum Command {
     Terminate,
 }
 
+/// Commands sent by the application to the network controller.
+/// Unlike Command, which goes to a peer, these commands identify the
+/// remote node by its controller id.
+#[derive(Debug)]
+pub enum ControllerCommand {
+    /// Dial the given address, even if its node refused a connection before.
+    Connect {
+        /// address of the remote node
+        addr: SocketAddr,
+    },
+    /// Close the connection with the remote node, after telling it.
+    Disconnect {
+        /// id of the remote node's controller
+        peer_id: Uuid,
+    },
+    /// Close the connection with the remote node, and ban it along with the
+    /// IP of its address.
+    Ban {
+        /// id of the remote node's controller
+        peer_id: Uuid,
+        /// how long the ban lasts, or None until the controller restarts
+        duration: Option<Duration>,
+    },
+    /// Ask for the live connections, in both directions.
+    QueryStatus {
+        /// where the status is sent
+        reply: oneshot::Sender<Summary>,
+    },
+    /// Send data to all the remote nodes we are connected to.
+    Broadcast {
+        /// topic of the data, empty if none
+        topic: String,
+        /// application data
+        data: Bytes,
+    },
+}
+
 impl ToString for Command {
     fn to_string(&self) -> String {
         match self {
@@ -82,25 +335,60 @@ impl ToString for Command {
                 addr: _,
                 attempt: _,
             } => "connect".to_owned(),
-            Command::Listen { stream: _ } => "listen".to_owned(),
+            Command::Listen { conn: _ } => "listen".to_owned(),
             Command::SendConnRequest {} => "connection request".to_owned(),
             Command::SendConnResponse {
                 peer_id: _,
                 peer_label: _,
                 peer_addr: _,
+                compression: _,
+                proof: _,
+                network: _,
             } => "connection response".to_owned(),
             Command::FinalizeConn {
                 peer_id: _,
                 peer_label: _,
+                observed: _,
+                compression: _,
+                proof: _,
             } => "connection finalization".to_owned(),
-            Command::HeartbeatResponse { src: _ } => "heartbeat response".to_owned(),
+            Command::HeartbeatResponse { .. } => "heartbeat response".to_owned(),
             Command::HeartbeatRequest => "heartbeat request".to_owned(),
             Command::HeartbeatTimeout => "heartbeat timeout".to_owned(),
-            Command::CancelHeartbeatTimeout { rtt: _ } => "cancel heartbeat timeout".to_owned(),
+            Command::CancelHeartbeatTimeout { .. } => "cancel heartbeat timeout".to_owned(),
             Command::SendContactRequest => "contact request".to_owned(),
-            Command::SendContactResponse { addrs: _ } => "contact response".to_owned(),
+            Command::SendContactResponse { .. } => "contact response".to_owned(),
             Command::RequestContacts => "request contacts".to_owned(),
-            Command::UpdateContacts { addrs: _ } => "update contacts".to_owned(),
+            Command::UpdateContacts { .. } => "update contacts".to_owned(),
+            Command::SendRelay { .. } => "relay message".to_owned(),
+            Command::RelayReceived { .. } => "relay received".to_owned(),
+            Command::SendPayload { .. } => "payload".to_owned(),
+            Command::PayloadReceived { .. } => "payload received".to_owned(),
+            Command::SendGossip { .. } => "gossip".to_owned(),
+            Command::GossipReceived { .. } => "gossip received".to_owned(),
+            Command::SendPing { .. } => "ping".to_owned(),
+            Command::SendPong { .. } => "pong".to_owned(),
+            Command::PongReceived { .. } => "pong received".to_owned(),
+            Command::SendInfoRequest => "info request".to_owned(),
+            Command::InfoRequested => "info requested".to_owned(),
+            Command::SendInfoResponse { .. } => "info response".to_owned(),
+            Command::InfoReceived { .. } => "info received".to_owned(),
+            Command::SendFindNode { .. } => "find node".to_owned(),
+            Command::FindNodeRequested { .. } => "find node requested".to_owned(),
+            Command::SendNodes { .. } => "nodes".to_owned(),
+            Command::NodesReceived { .. } => "nodes received".to_owned(),
+            Command::CodecOffered { .. } => "codec offered".to_owned(),
+            Command::CodecAccepted { .. } => "codec accepted".to_owned(),
+            Command::SendCustom { .. } => "custom message".to_owned(),
+            Command::SendChunk => "chunk".to_owned(),
+            Command::UnknownReceived { .. } => "unknown message received".to_owned(),
+            Command::AuthChallengeReceived { .. } => "auth challenge received".to_owned(),
+            Command::AuthResponseReceived { .. } => "auth response received".to_owned(),
+            Command::PowChallengeReceived { .. } => "pow challenge received".to_owned(),
+            Command::PowResponseReceived { .. } => "pow response received".to_owned(),
+            Command::ConnRejected { .. } => "connection rejected".to_owned(),
+            Command::ByeReceived { .. } => "bye received".to_owned(),
+            Command::ViolationReceived { .. } => "violation received".to_owned(),
             Command::Disconnect => "disconnect".to_owned(),
             Command::Terminate => "terminate".to_owned(),
         }
@@ -0,0 +1,81 @@
+//! Contacts received from remote nodes.
+//!
+//! A malicious peer could flood the idle set of the controller with addresses
+//! no node listens on, so that its connection attempts go nowhere. The
+//! controller takes the contact lists of each peer at a limited rate, and
+//! only a limited number of contacts from each list. It drops the addresses
+//! which cannot be dialed. Peers may also be set to drop the lists the remote
+//! did not sign with its node key.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Time each peer last had its contact list taken.
+#[derive(Debug)]
+pub struct Guard {
+    interval: Duration,
+    last: HashMap<Uuid, Instant>,
+}
+
+impl Guard {
+    /// Take at most one list every `interval` from each peer.
+    pub fn new(interval: Duration) -> Guard {
+        Guard {
+            interval,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Returns true if the list the peer sent at `now` is taken.
+    pub fn admit(&mut self, peer_id: Uuid, now: Instant) -> bool {
+        // Peers which have not sent a list for a while are forgotten.
+        let interval = self.interval;
+        self.last
+            .retain(|_, last| now.saturating_duration_since(*last) < interval);
+        if self.last.contains_key(&peer_id) {
+            return false;
+        }
+        self.last.insert(peer_id, now);
+        true
+    }
+}
+
+/// Returns true if a node could listen on the address.
+pub fn is_dialable(addr: &SocketAddr) -> bool {
+    let ip = addr.ip();
+    let broadcast = match ip {
+        IpAddr::V4(ip) => ip.is_broadcast(),
+        IpAddr::V6(_) => false,
+    };
+    addr.port() != 0 && !ip.is_unspecified() && !ip.is_multicast() && !broadcast
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn should_rate_limit_contact_lists() {
+        let mut guard = Guard::new(Duration::from_secs(2));
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        assert!(guard.admit(alice, now));
+        assert!(!guard.admit(alice, now + Duration::from_secs(1)));
+        assert!(guard.admit(bob, now + Duration::from_secs(1)));
+        assert!(guard.admit(alice, now + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn should_only_keep_dialable_addresses() {
+        let dialable = |addr| is_dialable(&SocketAddr::from_str(addr).unwrap());
+        assert!(dialable("[::1]:8090"));
+        assert!(dialable("192.168.0.10:8090"));
+        assert!(!dialable("[::1]:0"));
+        assert!(!dialable("0.0.0.0:8090"));
+        assert!(!dialable("[::]:8090"));
+        assert!(!dialable("224.0.0.1:8090"));
+        assert!(!dialable("255.255.255.255:8090"));
+    }
+}
@@ -0,0 +1,391 @@
+//! Control socket
+//!
+//! A local Unix socket, to drive a running node from a CLI or from scripts
+//! without the admin server. Each line sent on the socket is a command, in
+//! JSON, answered by a line of JSON:
+//!
+//! ```text
+//! {"command":"status"}
+//! {"ok":true,"result":{"id":"…","label":"alice","addr":"127.0.0.1:8090","outgoing":2,"incoming":1}}
+//! {"command":"connect","addr":"192.168.0.10:8090"}
+//! {"ok":true}
+//! {"command":"disconnect","peer_id":"…"}
+//! {"ok":false,"error":"Not connected to …"}
+//! ```
+//!
+//! The commands are `status`, `peers`, `connect` (`addr`), `disconnect`
+//! (`peer_id`), `ban` (`peer_id`, and `duration` in seconds, until restart if
+//! omitted) and `shutdown`. The socket is only protected by its file
+//! permissions.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use super::command::ControllerCommand;
+use super::controller::{listen_unix, Summary};
+use super::event::Direction;
+
+/// A command sent on the control socket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Identity of the node, and number of connections.
+    Status,
+    /// Live connections, in both directions.
+    Peers,
+    /// Dial the address, even if its node refused a connection before.
+    Connect {
+        /// address of the remote node
+        addr: SocketAddr,
+    },
+    /// Close the connection with the remote node.
+    Disconnect {
+        /// id of the remote node's controller
+        peer_id: Uuid,
+    },
+    /// Close the connection with the remote node, and ban it along with the
+    /// IP of its address.
+    Ban {
+        /// id of the remote node's controller
+        peer_id: Uuid,
+        /// how long the ban lasts (s), until the node restarts if omitted
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration: Option<u64>,
+    },
+    /// Stop the node.
+    Shutdown,
+}
+
+/// Answer to a command.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reply {
+    /// Whether the command succeeded.
+    pub ok: bool,
+    /// Result of the command, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    /// Why the command failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Result of the status command.
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    /// Controller id of the node
+    pub id: Uuid,
+    /// Label of the node
+    pub label: String,
+    /// Address the node listens on
+    pub addr: SocketAddr,
+    /// Number of outgoing connections
+    pub outgoing: usize,
+    /// Number of incoming connections
+    pub incoming: usize,
+}
+
+/// A live connection, in the result of the peers command.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerEntry {
+    /// Controller id of the remote node
+    pub id: Uuid,
+    /// Label of the remote node
+    pub label: String,
+    /// Address the remote node listens on
+    pub addr: SocketAddr,
+    /// Direction of the connection
+    pub direction: Direction,
+}
+
+/// Serve the control socket at the given path, handing the commands over to
+/// the controller. A socket left by a previous run is replaced.
+pub async fn serve(
+    path: PathBuf,
+    tx_ctl: Sender<ControllerCommand>,
+    shutdown: CancellationToken,
+) -> Result<(), Error> {
+    let listener = listen_unix(&path).map_err(|err| Error::Bind {
+        detail: format!("Control socket cannot bind to {} ({})", path.display(), err),
+    })?;
+    log::info!("Control | listening on {}.", path.display());
+    accept(listener, tx_ctl, shutdown).await
+}
+
+async fn accept(
+    listener: UnixListener,
+    tx_ctl: Sender<ControllerCommand>,
+    shutdown: CancellationToken,
+) -> Result<(), Error> {
+    loop {
+        let (stream, _) = listener.accept().await.map_err(|err| Error::IO {
+            source: err,
+            detail: "Control socket cannot accept connections".to_owned(),
+        })?;
+        let tx_ctl = tx_ctl.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(err) = session(stream, tx_ctl, shutdown).await {
+                log::debug!("Control | Session closed | {err}");
+            }
+        });
+    }
+}
+
+// Answer the commands of a client, one line each, until it hangs up.
+async fn session(
+    stream: UnixStream,
+    tx_ctl: Sender<ControllerCommand>,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = serde_json::from_str::<Request>(&line);
+        let reply = match &request {
+            Ok(request) => execute(request.clone(), &tx_ctl).await,
+            Err(err) => Reply::error(format!("Invalid command: {err}")),
+        };
+        let mut line = serde_json::to_vec(&reply).expect("reply serializes");
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+        // The client gets the answer before the node stops.
+        if matches!(request, Ok(Request::Shutdown)) {
+            log::info!("Control | Shutting down on request");
+            shutdown.cancel();
+        }
+    }
+    Ok(())
+}
+
+async fn execute(request: Request, tx_ctl: &Sender<ControllerCommand>) -> Reply {
+    let send = |cmd| async move {
+        match tx_ctl.send(cmd).await {
+            Ok(()) => Reply::ok(None),
+            Err(_) => Reply::error("The controller is stopped".to_owned()),
+        }
+    };
+    match request {
+        Request::Status => match query(tx_ctl).await {
+            Ok(summary) => Reply::result(&Status {
+                id: summary.controller.id,
+                label: summary.controller.label,
+                addr: summary.controller.addr,
+                outgoing: summary.outgoing.len(),
+                incoming: summary.incoming.len(),
+            }),
+            Err(reply) => reply,
+        },
+        Request::Peers => match query(tx_ctl).await {
+            Ok(summary) => Reply::result(&peers(summary)),
+            Err(reply) => reply,
+        },
+        Request::Connect { addr } => send(ControllerCommand::Connect { addr }).await,
+        Request::Disconnect { peer_id } => {
+            let connected = match query(tx_ctl).await {
+                Ok(summary) => peers(summary).iter().any(|peer| peer.id == peer_id),
+                Err(reply) => return reply,
+            };
+            if !connected {
+                return Reply::error(format!("Not connected to {peer_id}"));
+            }
+            send(ControllerCommand::Disconnect { peer_id }).await
+        }
+        Request::Ban { peer_id, duration } => {
+            let duration = duration.map(Duration::from_secs);
+            send(ControllerCommand::Ban { peer_id, duration }).await
+        }
+        // The controller is stopped once the reply is sent.
+        Request::Shutdown => Reply::ok(None),
+    }
+}
+
+// The live connections, from the controller.
+async fn query(tx_ctl: &Sender<ControllerCommand>) -> Result<Summary, Reply> {
+    let stopped = || Reply::error("The controller is stopped".to_owned());
+    let (reply, rx) = oneshot::channel();
+    tx_ctl
+        .send(ControllerCommand::QueryStatus { reply })
+        .await
+        .map_err(|_| stopped())?;
+    rx.await.map_err(|_| stopped())
+}
+
+fn peers(summary: Summary) -> Vec<PeerEntry> {
+    let outgoing = summary.outgoing.into_iter().map(|info| PeerEntry {
+        id: info.id,
+        label: info.label,
+        addr: info.addr,
+        direction: Direction::Outgoing,
+    });
+    let incoming = summary.incoming.into_iter().map(|info| PeerEntry {
+        id: info.id,
+        label: info.label,
+        addr: info.addr,
+        direction: Direction::Incoming,
+    });
+    outgoing.chain(incoming).collect()
+}
+
+impl Reply {
+    fn ok(result: Option<serde_json::Value>) -> Reply {
+        Reply {
+            ok: true,
+            result,
+            error: None,
+        }
+    }
+
+    fn result<T: Serialize>(result: &T) -> Reply {
+        Reply::ok(Some(
+            serde_json::to_value(result).expect("result serializes"),
+        ))
+    }
+
+    fn error(error: String) -> Reply {
+        Reply {
+            ok: false,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// Error type for the control socket
+#[derive(Debug)]
+pub enum Error {
+    /// The control socket could not be bound
+    Bind {
+        /// Error detail
+        detail: String,
+    },
+    /// IO Error
+    IO {
+        /// Source
+        source: std::io::Error,
+        /// Error detail
+        detail: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Bind { detail } => write!(f, "Cannot Bind Control Socket => {}", detail),
+            Error::IO { source, detail } => write!(f, "IO Error => {} [{}]", detail, source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::controller::{InConnInfo, OutConnInfo};
+    use crate::network::policy::Tags;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn should_drive_the_controller_over_the_control_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let (tx_ctl, mut rx_ctl) = mpsc::channel(4);
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve(path.clone(), tx_ctl, shutdown.clone()));
+        // The controller answers with a single outgoing connection.
+        let bob = Uuid::new_v4();
+        let controller = tokio::spawn(async move {
+            let mut commands = Vec::new();
+            while let Some(cmd) = rx_ctl.recv().await {
+                match cmd {
+                    ControllerCommand::QueryStatus { reply } => {
+                        let addr = SocketAddr::from(([127, 0, 0, 1], 8090));
+                        let _ = reply.send(Summary {
+                            controller: InConnInfo {
+                                addr,
+                                id: Uuid::nil(),
+                                label: "alice".to_owned(),
+                                health: None,
+                            },
+                            incoming: Vec::new(),
+                            outgoing: vec![OutConnInfo {
+                                addr,
+                                id: bob,
+                                label: "bob".to_owned(),
+                                rtt: i64::MAX,
+                                offset: 0,
+                                tags: Tags::new(),
+                                health: None,
+                            }],
+                            scores: Vec::new(),
+                        });
+                    }
+                    cmd => commands.push(cmd),
+                }
+            }
+            commands
+        });
+        // The server may not be listening yet.
+        let stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut replies = Vec::new();
+        for line in [
+            r#"{"command":"status"}"#.to_owned(),
+            r#"{"command":"peers"}"#.to_owned(),
+            format!(r#"{{"command":"disconnect","peer_id":"{}"}}"#, Uuid::nil()),
+            format!(r#"{{"command":"disconnect","peer_id":"{bob}"}}"#),
+            r#"{"command":"connect","addr":"192.168.0.10:8090"}"#.to_owned(),
+            r#"{"command":"reboot"}"#.to_owned(),
+            r#"{"command":"shutdown"}"#.to_owned(),
+        ] {
+            writer
+                .write_all(format!("{line}\n").as_bytes())
+                .await
+                .unwrap();
+            let reply = lines.next_line().await.unwrap().unwrap();
+            replies.push(serde_json::from_str::<Reply>(&reply).unwrap());
+        }
+        let result = |i: usize| replies[i].result.clone().unwrap();
+        assert_eq!(result(0)["label"], "alice");
+        assert_eq!(result(0)["outgoing"], 1);
+        assert_eq!(result(1)[0]["id"], bob.to_string());
+        assert_eq!(result(1)[0]["direction"], "outgoing");
+        // Only the nodes we are connected to can be disconnected.
+        assert!(!replies[2].ok);
+        assert!(replies[3].ok && replies[4].ok);
+        assert!(replies[5]
+            .error
+            .as_ref()
+            .unwrap()
+            .starts_with("Invalid command"));
+        assert!(replies[6].ok);
+        assert!(shutdown.is_cancelled());
+
+        server.abort();
+        drop(writer);
+        let _ = server.await;
+        let commands = controller.await.unwrap();
+        assert!(matches!(
+            commands[..],
+            [
+                ControllerCommand::Disconnect { peer_id },
+                ControllerCommand::Connect { .. }
+            ] if peer_id == bob
+        ));
+    }
+}
@@ -1,30 +1,70 @@
 //! A network controller
-use futures::stream::StreamExt;
+use bytes::Bytes;
+use chrono::Utc;
+use futures::stream::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Write as FmtWrite;
 use std::hash::Hash;
 use std::io::Write as IoWrite;
-use std::net::{AddrParseError, IpAddr, SocketAddr};
+use std::net::{AddrParseError, IpAddr, SocketAddr, SocketAddrV6};
 use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
-use tokio::net::TcpStream;
+use tokio::sync::broadcast;
 use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 use tokio::task::JoinHandle;
 use tokio::time::{self, Duration};
 use tokio::{fs, task};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid; // for write_all()
 
-use super::command::Command;
-use super::event::Event;
+use super::addrbook;
+use super::admin::{self, AdminState};
+use super::allowlist::AllowList;
+use super::auth;
+use super::backoff;
+use super::ban;
+use super::command::{Command, ControllerCommand};
+use super::contacts;
+#[cfg(unix)]
+use super::control;
+use super::custom;
+use super::dht;
+use super::discovery::{self, ExternalAddr};
+use super::event::{self, Direction, Event, NetworkEvent};
+use super::gossip;
+use super::identity;
+use super::impairment::{self, Impairments};
+use super::journal::{self, Record};
+use super::mdns;
+use super::metrics::Metrics;
+use super::noise;
 use super::peer::{self, Peer};
+use super::policy::{self, Counts, Policies, Tags};
+use super::quic::Quic;
+use super::relay::{self, Circuits, RelayMessage};
+use super::score::{self, History};
+use super::snapshot;
+use super::socket;
+use super::socks::Socks5;
+use super::tls::{self, Tls};
+#[cfg(unix)]
+use super::transport::Listeners;
+use super::transport::{self, Admit, Connection, Guard, Protocol, Tcp, Transport};
+use super::websocket::WebSocket;
+use crate::codec::Wire;
+use crate::frame::Limits;
+use crate::message::conn_rejection::RejectReason;
+use crate::message::dht::Node;
+use crate::message::{self, Contact, CustomMessage, Health, InfoResponse, Provenance, Registry};
 
 /// Data used to track idle information about an
 /// unknown connection target.
@@ -35,6 +75,25 @@ pub struct AddrInfo {
     /// Number of time this address has been attempted.
     /// Maybe use AtomicI32 because its a counter
     pub attempt: Arc<Mutex<i32>>,
+    /// Tags of the peer at this address.
+    pub tags: Tags,
+}
+
+/// An entry of the target file: either an address, or an address with tags
+/// (eg '{ "addr": "[::1]:8090", "tags": { "region": "eu" } }').
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TargetEntry {
+    /// An address without tags.
+    Addr(String),
+    /// An address with tags.
+    Tagged {
+        /// Network address
+        addr: String,
+        /// Tags of the peer at this address.
+        #[serde(default)]
+        tags: Tags,
+    },
 }
 
 // We need to implement this trait because
@@ -58,6 +117,18 @@ impl Hash for AddrInfo {
 pub struct IdleState {
     /// Current list of addrs we need to connect to.
     pub addrs: HashSet<AddrInfo>,
+    /// History of the connections to the addresses we attempted, or
+    /// which our peers reported, used to score idle addresses.
+    pub history: HashMap<SocketAddr, History>,
+    /// Addresses whose node refused our connection, which are not
+    /// dialed again.
+    pub rejected: HashSet<SocketAddr>,
+    /// Time before which an address is not dialed again, after a failed
+    /// attempt or a disconnection.
+    pub retry_at: HashMap<SocketAddr, std::time::Instant>,
+    /// Addresses retired after too many failed attempts, with their tags and
+    /// the time they were retired. They are only dialed again when resurrected.
+    pub dead: HashMap<SocketAddr, (Tags, std::time::Instant)>,
 }
 
 /// Data used to track outbond connections
@@ -74,6 +145,13 @@ pub struct OutConnInfo {
     /// exchange took.
     /// (HeartbeatRequest and HeartbeatResponse)
     pub rtt: i64,
+    /// Clock offset (μs): how far the clock of the remote peer is ahead
+    /// of ours, estimated from the last heartbeat exchange.
+    pub offset: i64,
+    /// Tags of the remote peer.
+    pub tags: Tags,
+    /// Health the remote peer sent with its last heartbeat response.
+    pub health: Option<Health>,
 }
 
 /// Data used to track inbound connections
@@ -85,6 +163,8 @@ pub struct InConnInfo {
     pub id: Uuid,
     /// Label of the remote peer.
     pub label: String,
+    /// Health the remote peer sent with its last heartbeat request.
+    pub health: Option<Health>,
 }
 
 /// Network Controller State for outgoing connections.
@@ -98,6 +178,18 @@ pub struct OutgoingState {
     pub connected: HashMap<Uuid, OutConnInfo>,
 }
 
+impl OutgoingState {
+    /// Tag values of the peers we are connected to, or attempting to connect to.
+    pub fn counts(&self) -> Counts {
+        Counts::new(
+            self.connected
+                .values()
+                .map(|info| &info.tags)
+                .chain(self.attempting.values().map(|info| &info.tags)),
+        )
+    }
+}
+
 /// Network Controller State for incoming connections.
 #[derive(Debug, Default)]
 pub struct IncomingState {
@@ -116,6 +208,285 @@ pub struct PeerData {
 
 type PeerRepo = HashMap<Uuid, PeerData>;
 
+// The main loop of a controller started in the background.
+type ControllerTask = JoinHandle<Result<(), Error>>;
+
+/// Sends application data, and pings, to the remote nodes the controller is
+/// connected to. Data sent by remote nodes is published as
+/// NetworkEvent::Payload, gossip as NetworkEvent::Gossip, the echoes of
+/// pings as NetworkEvent::Pong, and the information of remote nodes as
+/// NetworkEvent::Info.
+#[derive(Debug, Clone)]
+pub struct Payloads {
+    peers: Arc<Mutex<PeerRepo>>,
+    outgoing: Arc<Mutex<OutgoingState>>,
+    incoming: Arc<Mutex<IncomingState>>,
+    seen: Arc<Mutex<gossip::Seen>>,
+    ttl: u64,
+}
+
+impl Payloads {
+    /// Send data to the remote node with the given controller id, on a
+    /// topic (empty for none).
+    pub async fn send(&self, peer_id: Uuid, topic: &str, data: Bytes) -> Result<(), Error> {
+        let topic = topic.to_owned();
+        self.command(peer_id, Command::SendPayload { topic, data })
+            .await
+    }
+
+    /// Ping the remote node with the given controller id. It echoes the data
+    /// back, which measures the round trip time, and whether messages of
+    /// that size get through.
+    pub async fn ping(&self, peer_id: Uuid, seq: u64, data: Bytes) -> Result<(), Error> {
+        self.command(peer_id, Command::SendPing { seq, data }).await
+    }
+
+    /// Ask the remote node with the given controller id about itself. Its
+    /// answer is published as NetworkEvent::Info.
+    pub async fn info(&self, peer_id: Uuid) -> Result<(), Error> {
+        self.command(peer_id, Command::SendInfoRequest).await
+    }
+
+    /// Flood data through the network, on a topic (empty for none). Each node
+    /// forwards it to its other peers, up to `gossip.ttl` hops away. Returns
+    /// the id of the message.
+    pub async fn gossip(&self, topic: &str, data: Bytes) -> Result<Uuid, Error> {
+        let msg_id = Uuid::new_v4();
+        self.seen.lock().await.insert(msg_id);
+        let ids = alive_peers(&self.outgoing, &self.incoming).await;
+        let peers = self.peers.lock().await;
+        for id in ids {
+            let Some(peer_data) = peers.get(&id) else {
+                continue;
+            };
+            let cmd = Command::SendGossip {
+                msg_id,
+                ttl: self.ttl,
+                topic: topic.to_owned(),
+                data: data.clone(),
+            };
+            send_command_single_peer(cmd, &peer_data.tx, &id).await?;
+        }
+        Ok(msg_id)
+    }
+
+    /// Send a message of the application to the remote node with the given
+    /// controller id. The remote handles it if it registered the message too.
+    pub async fn send_message<M: CustomMessage>(&self, peer_id: Uuid, msg: M) -> Result<(), Error> {
+        let frame = msg
+            .into_frame()
+            .map_err(|err| Error::InvalidMessage { source: err })?;
+        let tag = M::TAG.to_owned();
+        self.command(peer_id, Command::SendCustom { tag, frame })
+            .await
+    }
+
+    // Send a command to the peer connected to the given remote node.
+    async fn command(&self, peer_id: Uuid, cmd: Command) -> Result<(), Error> {
+        let (id, _) = local_peer(&self.outgoing, &self.incoming, peer_id)
+            .await
+            .ok_or_else(|| not_connected(peer_id))?;
+        let peers = self.peers.lock().await;
+        let peer_data = peers.get(&id).ok_or_else(|| not_connected(peer_id))?;
+        send_command_single_peer(cmd, &peer_data.tx, &id).await
+    }
+}
+
+/// Longest wait of `NetworkHandle::connect_to` for the outcome of a dial.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Handle on a controller running in the background, returned by
+/// `NetworkController::start`. It is cheap to clone, so that every part of
+/// the application can hold one.
+#[derive(Debug, Clone)]
+pub struct NetworkHandle {
+    controller: InConnInfo,
+    payloads: Payloads,
+    idle: Arc<Mutex<IdleState>>,
+    bans: Arc<ban::Bans>,
+    tx_pub: broadcast::Sender<NetworkEvent>,
+    tx_ctl: Sender<ControllerCommand>,
+    shutdown: CancellationToken,
+    task: Arc<Mutex<Option<ControllerTask>>>,
+}
+
+impl NetworkHandle {
+    /// Dial the given address. The idle monitor picks it up within a second,
+    /// even if its node refused a connection before.
+    pub async fn connect(&self, addr: SocketAddr) {
+        dial(&self.idle, addr).await
+    }
+
+    /// Dial the given address, and wait for the outcome: the controller id of
+    /// the remote node once the handshake completes, or the reason the first
+    /// attempt failed. A node we are already connected to, in either direction,
+    /// is returned right away. The wait is bounded by `CONNECT_TIMEOUT`, and
+    /// ends when the controller shuts down.
+    pub async fn connect_to(&self, addr: SocketAddr) -> Result<Uuid, Error> {
+        self.connect_within(addr, CONNECT_TIMEOUT).await
+    }
+
+    /// Same as `connect_to`, waiting at most `wait` for the outcome. The
+    /// address stays in the idle addresses when the wait is over, so that the
+    /// controller may still connect to it later.
+    pub async fn connect_within(&self, addr: SocketAddr, wait: Duration) -> Result<Uuid, Error> {
+        if self.bans.is_addr_banned(&addr, Instant::now()) {
+            return Err(connect_failed(addr, "The address is banned"));
+        }
+        // Subscribe before dialing, so that no outcome is missed.
+        let events = self.tx_pub.subscribe();
+        if let Some(peer_id) = self.connected_at(addr).await {
+            return Ok(peer_id);
+        }
+        dial(&self.idle, addr).await;
+        tokio::select! {
+            outcome = time::timeout(wait, self.outcome(events, addr)) => {
+                outcome.unwrap_or_else(|_| {
+                    let reason = format!("No outcome after {}s", wait.as_secs_f32());
+                    Err(connect_failed(addr, &reason))
+                })
+            }
+            _ = self.shutdown.cancelled() => {
+                Err(connect_failed(addr, "The controller is shut down"))
+            }
+        }
+    }
+
+    // Outcome of the first attempt to connect to the address.
+    async fn outcome(
+        &self,
+        mut events: broadcast::Receiver<NetworkEvent>,
+        addr: SocketAddr,
+    ) -> Result<Uuid, Error> {
+        loop {
+            match events.recv().await {
+                Ok(NetworkEvent::PeerConnected {
+                    peer_id,
+                    addr: peer_addr,
+                    direction: Direction::Outgoing,
+                    ..
+                }) if peer_addr == addr => return Ok(peer_id),
+                Ok(NetworkEvent::AttemptFailed {
+                    addr: failed,
+                    reason,
+                }) if failed == addr => return Err(connect_failed(addr, &reason)),
+                Ok(NetworkEvent::AddressRetired {
+                    addr: retired,
+                    attempts,
+                }) if retired == addr => {
+                    let reason = format!("Retired after {attempts} failed attempts");
+                    return Err(connect_failed(addr, &reason));
+                }
+                Ok(NetworkEvent::AddressDropped { addr: dropped }) if dropped == addr => {
+                    return Err(connect_failed(addr, "Dropped from the idle addresses"));
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    if let Some(peer_id) = self.connected_at(addr).await {
+                        return Ok(peer_id);
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(connect_failed(addr, "The controller is shut down"));
+                }
+            }
+        }
+    }
+
+    /// Close the connection with the remote node with the given controller
+    /// id, after telling it, and wait until it is closed. An outgoing
+    /// connection is dialed again later, as with any disconnection.
+    pub async fn disconnect(&self, peer_id: Uuid) -> Result<(), Error> {
+        let payloads = &self.payloads;
+        let mut events = self.tx_pub.subscribe();
+        close(
+            &payloads.peers,
+            &payloads.outgoing,
+            &payloads.incoming,
+            peer_id,
+        )
+        .await?;
+        loop {
+            match events.recv().await {
+                Ok(NetworkEvent::PeerDisconnected {
+                    peer_id: closed, ..
+                }) if closed == peer_id => return Ok(()),
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let (outgoing, incoming) = (&payloads.outgoing, &payloads.incoming);
+                    if local_peer(outgoing, incoming, peer_id).await.is_none() {
+                        return Ok(());
+                    }
+                }
+                // The connections are closed along with the controller.
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+
+    // Controller id of the remote node we are connected to at the address.
+    async fn connected_at(&self, addr: SocketAddr) -> Option<Uuid> {
+        let outgoing = self.payloads.outgoing.lock().await;
+        let mut infos = outgoing.connected.values();
+        if let Some(info) = infos.find(|info| info.addr == addr) {
+            return Some(info.id);
+        }
+        drop(outgoing);
+        let incoming = self.payloads.incoming.lock().await;
+        let mut infos = incoming.connected.values();
+        infos.find(|info| info.addr == addr).map(|info| info.id)
+    }
+
+    /// Sending end of the channel of commands to the controller.
+    pub fn commands(&self) -> Sender<ControllerCommand> {
+        self.tx_ctl.clone()
+    }
+
+    /// The live connections, in both directions.
+    pub async fn peers(&self) -> Summary {
+        summary(
+            self.controller.clone(),
+            &self.payloads.incoming,
+            &self.payloads.outgoing,
+            &self.idle,
+        )
+        .await
+    }
+
+    /// Send data to the remote node with the given controller id, on a
+    /// topic (empty for none).
+    pub async fn send(&self, peer_id: Uuid, topic: &str, data: Bytes) -> Result<(), Error> {
+        self.payloads.send(peer_id, topic, data).await
+    }
+
+    /// Send pings, gossip and messages of the application to remote nodes.
+    pub fn payloads(&self) -> Payloads {
+        self.payloads.clone()
+    }
+
+    /// Subscribe to the network events published by the controller.
+    pub fn subscribe(&self) -> broadcast::Receiver<NetworkEvent> {
+        self.tx_pub.subscribe()
+    }
+
+    /// Stream of the network events published by the controller.
+    pub fn events(&self) -> impl Stream<Item = NetworkEvent> {
+        event::stream(self.tx_pub.subscribe())
+    }
+
+    /// Shut the controller down, and wait until it is done. The controller
+    /// task returns its error, if any, to the first caller.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        self.shutdown.cancel();
+        let Some(task) = self.task.lock().await.take() else {
+            return Ok(());
+        };
+        task.await.map_err(|err| Error::Runtime {
+            detail: format!("The network controller task failed: {err}"),
+        })?
+    }
+}
+
 /// NetworkController
 /// The network controller holds state information about connections with peers, and configuration
 /// The state information is split so that we can keep separate locks for areas that don't
@@ -123,8 +494,10 @@ type PeerRepo = HashMap<Uuid, PeerData>;
 #[derive(Debug)]
 pub struct NetworkController {
     // FIXME Replace id, label, addr with a single ConnInfo
-    /// Unique id of the node in the network
+    /// Unique id of the node in the network, derived from its public key.
     pub id: Uuid,
+    /// Key pair of the node, which signs its handshake messages.
+    pub identity: identity::Identity,
     /// A label to make it easy to read. Hopefully it is a unique string in the retwork,
     /// but it's really that distinguishes it.
     pub label: String,
@@ -150,6 +523,15 @@ pub struct NetworkController {
     /// Receiving end of channel for peer -> controller. The controller
     /// monitors this endpoint to learn about peer status.
     pub rx_evt: Receiver<Event>,
+    /// Sending end of the channel publishing network events to applications.
+    pub tx_pub: broadcast::Sender<NetworkEvent>,
+    /// Sending end of channel for application -> controller.
+    pub tx_ctl: Sender<ControllerCommand>,
+    /// Receiving end of channel for application -> controller. The controller
+    /// handles these commands along with the events of the peers.
+    pub rx_ctl: Receiver<ControllerCommand>,
+    /// Counters describing the controller's activity.
+    pub metrics: Arc<Metrics>,
     /// Thread Handle for the listen thread.
     pub listen_handle: Option<JoinHandle<Result<(), Error>>>,
     /// Thread Handle for the monitor idle thread.
@@ -158,22 +540,123 @@ pub struct NetworkController {
     pub monitor_status_handle: Option<JoinHandle<()>>,
     /// Thread Handle for the network discovery thread.
     pub network_discovery_handle: Option<JoinHandle<()>>,
+    /// Thread Handle for the admin server.
+    pub admin_handle: Option<JoinHandle<Result<(), admin::Error>>>,
+    /// handle to the thread taking snapshots on signal
+    pub snapshot_handle: Option<JoinHandle<()>>,
+    /// Handle to the thread writing the address book.
+    pub address_book_handle: Option<JoinHandle<()>>,
+    /// Handle to the mDNS discovery thread.
+    pub mdns_handle: Option<JoinHandle<()>>,
+    /// Handle to the thread running the DHT lookups.
+    pub dht_handle: Option<JoinHandle<()>>,
+    /// Handle to the thread serving the control socket.
+    #[cfg(unix)]
+    pub control_handle: Option<JoinHandle<Result<(), control::Error>>>,
+    /// Handle to change the log filter from the admin server.
+    /// This is set by the application, before running the controller.
+    pub log_filter: Option<admin::LogFilter>,
+    /// Journal of events and decisions. It is opened when the controller
+    /// runs, if the journal section is present.
+    pub journal: Option<Arc<journal::Journal>>,
+    /// Transport used to dial and listen. This is TCP, unless the
+    /// application sets another transport before running the controller.
+    pub transport: Arc<dyn Transport>,
+    /// Unix sockets of the target file, by the address standing for them. The
+    /// transport dials them when the controller runs.
+    pub unix_targets: HashMap<SocketAddr, PathBuf>,
+    /// Address advertised to remote peers. This is the listen address, until
+    /// an external address is discovered.
+    pub external: Arc<ExternalAddr>,
+    /// Relay transport, which dials unreachable peers through a relay node.
+    /// It is only present if the relay section has a 'via' address.
+    pub relay: Option<Arc<relay::Relay>>,
+    /// Routing table of the circuits relayed by this node. It is only present
+    /// if this node serves as a relay.
+    pub circuits: Option<Mutex<Circuits>>,
+    /// Secret authenticating the handshakes. It is only present if the auth
+    /// section is present.
+    pub auth: Option<auth::Secret>,
+    /// Nonces of the connection requests received recently, which are not
+    /// accepted again.
+    pub replays: Arc<identity::Replays>,
+    /// Banned addresses and nodes, which are neither dialed nor accepted.
+    pub bans: Arc<ban::Bans>,
+    /// Ids of the gossip messages seen recently, which are not forwarded again.
+    pub gossip: Arc<Mutex<gossip::Seen>>,
+    /// Time each peer last had its contact list taken, which limits the
+    /// rate of the lists taken.
+    pub contacts: Mutex<contacts::Guard>,
+    /// DHT routing table, holding the nodes we were connected to.
+    pub dht: Mutex<dht::RoutingTable>,
+    /// Handlers of the messages registered by the application.
+    pub custom: custom::Handlers,
+    /// Cancelled to shut the controller down.
+    pub shutdown: CancellationToken,
 }
 
 impl NetworkController {
     /// Create a new network controller
     pub fn new(label: String, config: Config) -> Result<NetworkController, Error> {
-        let addr =
-            IpAddr::from_str(config.listen.addr.as_str()).map_err(|err| Error::InvalidAddr {
-                source: err,
-                detail: format!("Could not use {} as valid IP Address", config.listen.addr),
-            })?;
-        let addr = SocketAddr::from((addr, config.listen.port));
+        // The label is sent to remote peers in a string frame.
+        if label.contains(['\r', '\n']) {
+            return Err(Error::InvalidLabel {
+                detail: format!("'{}' contains CR or LF", label.escape_default()),
+            });
+        }
+        let addr = socket_addr(&config.listen.addr, config.listen.port)?;
+        let _ = AllowList::new(config.listen.allow.as_deref().unwrap_or_default())
+            .map_err(|err| Error::InvalidAllowList { source: err })?;
+        if let Some(impairment) = &config.impairment {
+            let impairments = impairment
+                .impairments()
+                .map_err(|err| Error::InvalidImpairment { source: err })?;
+            if !impairments.is_empty() {
+                log::warn!("Controller | Impairment rules delay or drop messages sent to peers");
+            }
+        }
+        let _ = Policies::new(config.policies.as_deref().unwrap_or_default())
+            .map_err(|err| Error::InvalidPolicy { source: err })?;
+        let identity = match &config.identity {
+            Some(section) => identity::Identity::with_key_file(&section.path())
+                .map_err(|err| Error::Identity { source: err })?,
+            None => identity::Identity::new(),
+        };
+        let (transport, relay) = build_transport(&config)?;
+        let auth = config
+            .auth
+            .as_ref()
+            .map(|auth| auth::Secret::with_file(&auth.path()))
+            .transpose()
+            .map_err(|err| Error::Auth { source: err })?;
+        let circuits = config
+            .relay
+            .as_ref()
+            .filter(|relay| relay.serve)
+            .map(|relay| {
+                Mutex::new(Circuits::new(
+                    relay.max_circuits,
+                    relay.max_circuits_per_peer,
+                ))
+            });
+        let confirmations = config
+            .discovery
+            .as_ref()
+            .map_or(0, |discovery| discovery.confirmations);
+        let external = Arc::new(ExternalAddr::new(addr, confirmations));
 
+        let seen = config.gossip.clone().unwrap_or_default().seen;
+        let limits = config.contacts.clone().unwrap_or_default();
         let (tx_evt, rx_evt) = mpsc::channel(32); // FIXME 32 Automagick
+        let (tx_pub, _) = broadcast::channel(64);
+        let (tx_ctl, rx_ctl) = mpsc::channel(32);
 
+        let max_banned_count = config.peers.max_banned_count.max(0) as usize;
+        let k = config.dht.as_ref().map_or_else(default_dht_k, |dht| dht.k);
+        let routing = dht::RoutingTable::new(identity.id(), k);
         Ok(NetworkController {
-            id: Uuid::new_v4(),
+            id: identity.id(),
+            identity,
             label,
             addr,
             config: Arc::new(config),
@@ -183,13 +666,108 @@ impl NetworkController {
             idle: Arc::new(Mutex::new(IdleState::default())),
             tx_evt,
             rx_evt,
+            tx_pub,
+            tx_ctl,
+            rx_ctl,
+            metrics: Arc::new(Metrics::default()),
             listen_handle: None,
             monitor_idle_handle: None,
             monitor_status_handle: None,
             network_discovery_handle: None,
+            admin_handle: None,
+            snapshot_handle: None,
+            address_book_handle: None,
+            mdns_handle: None,
+            dht_handle: None,
+            #[cfg(unix)]
+            control_handle: None,
+            log_filter: None,
+            journal: None,
+            transport,
+            unix_targets: HashMap::new(),
+            external,
+            relay,
+            circuits,
+            auth,
+            replays: Arc::new(identity::Replays::default()),
+            bans: Arc::new(ban::Bans::new(max_banned_count)),
+            gossip: Arc::new(Mutex::new(gossip::Seen::new(seen))),
+            contacts: Mutex::new(contacts::Guard::new(Duration::from_secs(limits.interval))),
+            dht: Mutex::new(routing),
+            custom: custom::Handlers::default(),
+            shutdown: CancellationToken::new(),
         })
     }
 
+    /// Subscribe to the network events published by the controller.
+    /// Since 'run' does not return, subscribe before running the controller.
+    pub fn subscribe(&self) -> broadcast::Receiver<NetworkEvent> {
+        self.tx_pub.subscribe()
+    }
+
+    /// Send commands to the controller: connect, disconnect, ban, query the
+    /// status, broadcast.
+    /// Since 'run' does not return, get the sender before running the controller.
+    pub fn commands(&self) -> Sender<ControllerCommand> {
+        self.tx_ctl.clone()
+    }
+
+    /// Stream of the network events published by the controller: peers
+    /// connecting and disconnecting, data received, failed attempts and
+    /// protocol violations.
+    /// Since 'run' does not return, get the stream before running the controller.
+    pub fn events(&self) -> impl Stream<Item = NetworkEvent> {
+        event::stream(self.tx_pub.subscribe())
+    }
+
+    /// Register a message of the application, and the handler called with the
+    /// controller id of the remote node and the message, for each one
+    /// received. Messages are sent with `Payloads::send_message`.
+    /// Since 'run' does not return, register messages before running the
+    /// controller.
+    pub fn register<M, F>(&mut self, handler: F) -> Result<(), Error>
+    where
+        M: CustomMessage,
+        F: Fn(Uuid, M) + Send + Sync + 'static,
+    {
+        self.custom
+            .register(handler)
+            .map_err(|err| Error::InvalidMessage { source: err })
+    }
+
+    /// Token shutting the controller down once cancelled: 'run' then
+    /// disconnects the peers, stops its tasks, and returns.
+    /// Since 'run' only returns then, get the token before running the controller.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Send application data to remote nodes.
+    /// Since 'run' does not return, get the sender before running the controller.
+    pub fn payloads(&self) -> Payloads {
+        Payloads {
+            peers: self.peers.clone(),
+            outgoing: self.outgoing.clone(),
+            incoming: self.incoming.clone(),
+            seen: self.gossip.clone(),
+            ttl: self.config.gossip.clone().unwrap_or_default().ttl,
+        }
+    }
+
+    /// Clock offsets (μs) of the remote nodes we connected to, by controller
+    /// id: how far their clock is ahead of ours. Remote nodes which did not
+    /// answer a heartbeat yet are left out.
+    pub async fn clock_offsets(&self) -> HashMap<Uuid, i64> {
+        self.outgoing
+            .lock()
+            .await
+            .connected
+            .values()
+            .filter(|info| info.rtt != i64::MAX)
+            .map(|info| (info.id, info.offset))
+            .collect()
+    }
+
     /// This function is ran when we start the Network Controller.
     /// It looks at the network controller's configuration for an
     /// initial list of peers, and stores them in the
@@ -207,39 +785,115 @@ impl NetworkController {
             detail: "Cannot read config file".to_owned(),
         })?;
 
-        let addrs: Vec<String> =
+        let entries: Vec<TargetEntry> =
             serde_json::from_str(&content).map_err(|err| Error::InvalidPeerFile { source: err })?;
-        let addrs: HashSet<AddrInfo> = addrs.iter().try_fold(HashSet::new(), |mut acc, t| {
-            let addr = SocketAddr::from_str(t).map_err(|err| Error::InvalidAddr {
-                source: err,
-                detail: format!("Could not turn {} into a network address", t),
-            })?;
-            acc.insert(AddrInfo {
+        let mut addrs = HashSet::new();
+        for entry in entries {
+            let (t, tags) = match entry {
+                TargetEntry::Addr(addr) => (addr, Tags::new()),
+                TargetEntry::Tagged { addr, tags } => (addr, tags),
+            };
+            let addr = match t.strip_prefix("unix:") {
+                Some(path) => self.unix_target(path)?,
+                None => SocketAddr::from_str(&t).map_err(|err| Error::InvalidAddr {
+                    source: err,
+                    detail: format!("Could not turn {} into a network address", t),
+                })?,
+            };
+            policy::validate(&tags).map_err(|err| Error::InvalidTags { source: err })?;
+            addrs.insert(AddrInfo {
                 addr,
                 attempt: Arc::new(Mutex::new(0)),
+                tags,
             });
-            Ok(acc)
-        })?;
+        }
 
-        let idle_state = IdleState { addrs };
+        // We stay connected to the relay node.
+        if let Some(relay) = &self.relay {
+            addrs.insert(AddrInfo {
+                addr: relay.via(),
+                attempt: Arc::new(Mutex::new(0)),
+                tags: Tags::new(),
+            });
+        }
 
         let mut idles = self.idle.lock().await;
-        *idles = idle_state;
+        idles.addrs = addrs;
+        drop(idles);
+
+        // The nodes known before a restart are dialed as well.
+        if let Some(book) = &self.config.address_book {
+            let path = book.path();
+            match addrbook::load(&path).await {
+                Ok(entries) => {
+                    log::info!(
+                        "Controller | {} nodes read from {}",
+                        entries.len(),
+                        path.display()
+                    );
+                    let mut idle = self.idle.lock().await;
+                    for entry in entries.into_iter().filter(|entry| entry.addr != self.addr) {
+                        if let Some(last_seen) = entry.last_seen {
+                            idle.history
+                                .entry(entry.addr)
+                                .or_default()
+                                .record_seen(last_seen);
+                        }
+                        // The target file comes first.
+                        if !idle.addrs.iter().any(|info| info.addr == entry.addr) {
+                            idle.addrs.insert(AddrInfo {
+                                addr: entry.addr,
+                                attempt: Arc::new(Mutex::new(0)),
+                                tags: entry.tags,
+                            });
+                        }
+                    }
+                }
+                // A book which cannot be read should not keep the node from starting.
+                Err(err) => log::warn!("Controller | Ignoring the address book | {err}"),
+            }
+        }
 
         Ok(())
     }
 
+    // A 'unix:' entry of the target file stands for a Unix socket, relative to
+    // the working dir, which is dialed by a synthetic address.
+    fn unix_target(&mut self, path: &str) -> Result<SocketAddr, Error> {
+        if !cfg!(unix) || path.is_empty() {
+            return Err(Error::InvalidTarget {
+                detail: format!("Cannot dial the Unix socket 'unix:{}'", path),
+            });
+        }
+        let mut full = PathBuf::from(get_working_dir());
+        full.push(path);
+        let addr = transport::unix_addr(&full);
+        self.unix_targets.insert(addr, full);
+        Ok(addr)
+    }
+
     /// Spawn a thread to listen for incoming connection request from remote peer.
     async fn start_listen(&self) -> Result<JoinHandle<Result<(), Error>>, Error> {
-        let controller = self.id;
+        let identity = self.identity.clone();
         let label = self.label.clone();
         let tx_evt = self.tx_evt.clone();
         let addr = self.addr;
         let peers = self.peers.clone();
         let incoming = self.incoming.clone();
         let config = self.config.clone();
+        let metrics = self.metrics.clone();
+        let transport = self.transport.clone();
+        let auth = self.auth.clone();
+        let replays = self.replays.clone();
+        let bans = self.bans.clone();
+        let idle = self.idle.clone();
+        let registry = self.custom.registry();
         let handle = tokio::spawn(async move {
-            listen(controller, label, addr, tx_evt, peers, incoming, config).await
+            listen(
+                identity, label, addr, tx_evt, peers, incoming, config, metrics, transport, auth,
+                replays, bans, idle, registry,
+            )
+            .await
         });
         Ok(handle)
     }
@@ -254,15 +908,31 @@ impl NetworkController {
     /// - The peer will respond after a while with a Connected Event, or a
     ///   ConnectionRefused.
     async fn start_monitor_idle(&self) -> Result<JoinHandle<()>, Error> {
-        let controller = self.id;
+        let identity = self.identity.clone();
         let label = self.label.clone();
-        let controller_addr = self.addr;
+        let external = self.external.clone();
         let tx_evt = self.tx_evt.clone();
         let idle = self.idle.clone();
         let peers = self.peers.clone();
         let outgoing = self.outgoing.clone();
         let incoming = self.incoming.clone();
         let config = self.config.clone();
+        let tx_pub = self.tx_pub.clone();
+        let metrics = self.metrics.clone();
+        let journal = self.journal.clone();
+        let transport = self.transport.clone();
+        let auth = self.auth.clone();
+        let bans = self.bans.clone();
+        let registry = self.custom.registry();
+        let max_message_sizes = Arc::new(
+            config
+                .messages
+                .as_ref()
+                .map(Messages::max_sizes)
+                .unwrap_or_default(),
+        );
+        let impairments = Arc::new(build_impairments(&config));
+        let policies = Arc::new(build_policies(&config));
         let handle = tokio::spawn(async move {
             let mut interval = time::interval(Duration::from_secs(1)); // Every second
             loop {
@@ -278,15 +948,71 @@ impl NetworkController {
                 // addr_info will figure in the new hashset.
                 // * If on the other hand we can send a connect to a new peer, then we remove that
                 // address from the hashset of idle addresses.
-                let addrs = futures::stream::iter(&idle_guard.addrs)
-                    .fold(HashSet::new(), |mut set, addr_info| {
+                //
+                // Addresses of the peers wanted by the connection policies are dialed first, and
+                // then the other addresses in a random order weighted by their score, so that
+                // the best addresses get the connection attempts available.
+                //
+                // Addresses waiting for their next attempt are kept for a later round.
+                let instant = std::time::Instant::now();
+                if let Some(delay) = config.peers.resurrect_delay {
+                    resurrect(&mut idle_guard, Duration::from_secs(delay), instant);
+                }
+                idle_guard
+                    .retry_at
+                    .retain(|_, retry_at| *retry_at > instant);
+                let waiting = idle_guard
+                    .addrs
+                    .iter()
+                    .filter(|addr_info| idle_guard.retry_at.contains_key(&addr_info.addr))
+                    .cloned()
+                    .collect::<HashSet<_>>();
+                let counts = outgoing.lock().await.counts();
+                let now = Utc::now().timestamp_micros();
+                let mut candidates = idle_guard
+                    .addrs
+                    .iter()
+                    .filter(|addr_info| !waiting.contains(*addr_info))
+                    .map(|addr_info| {
+                        let history = idle_guard.history.get(&addr_info.addr);
+                        let weight = score::score(history, &addr_info.tags, &counts, now);
+                        let wanted = policies.wanted(&addr_info.tags, &counts);
+                        (wanted, score::sort_key(weight), addr_info)
+                    })
+                    .collect::<Vec<_>>();
+                candidates.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.total_cmp(&a.1)));
+                let candidates = candidates.into_iter().map(|(_, _, addr_info)| addr_info);
+                let addrs = futures::stream::iter(candidates)
+                    .fold(waiting, |mut set, addr_info| {
                         let tx_evt = tx_evt.clone();
                         let peers = peers.clone();
                         let outgoing = outgoing.clone();
                         let incoming = incoming.clone();
                         let config = config.clone();
                         let label = label.clone();
+                        let tx_pub = tx_pub.clone();
+                        let metrics = metrics.clone();
+                        let max_message_sizes = max_message_sizes.clone();
+                        let registry = registry.clone();
+                        let impairments = impairments.clone();
+                        let policies = policies.clone();
+                        let transport = transport.clone();
+                        let journal = journal.clone();
+                        let external = external.clone();
+                        let auth = auth.clone();
+                        let identity = identity.clone();
+                        let bans = bans.clone();
                         async move {
+                            // Banned addresses are kept until their ban expires.
+                            if bans.is_addr_banned(&addr_info.addr, std::time::Instant::now()) {
+                                log::debug!(
+                                    "Controller | Not connecting to {} | Banned",
+                                    addr_info.addr
+                                );
+                                set.insert(addr_info.clone());
+                                return set;
+                            }
+
                             // If there are too many attempts at the moment, then we save that
                             // addr for the next round.
                             let mut outgoing = outgoing.lock().await;
@@ -302,6 +1028,17 @@ impl NetworkController {
                                 return set;
                             }
 
+                            // If a connection would exceed the maximum number of connections
+                            // for one of its tags, then we save that addr for later.
+                            if policies.saturated(&addr_info.tags, &outgoing.counts()) {
+                                log::debug!(
+                                    "Controller | Not connecting to {} | Connection policy",
+                                    addr_info.addr
+                                );
+                                set.insert(addr_info.clone());
+                                return set;
+                            }
+
                             // We need to make sure the address we want to connect to is not
                             // already in the incoming or outgoing sets. If it is, then we remove
                             // it from the next round.
@@ -320,16 +1057,32 @@ impl NetworkController {
                             }
 
                             let (tx_com, rx_com) = mpsc::channel(32);
-                            let peer = Peer::new(
-                                controller,
+                            let mut peer = Peer::new(
+                                identity,
                                 label,
-                                controller_addr,
+                                external.advertised(),
                                 tx_evt,
                                 tx_com.clone(),
                                 rx_com,
                                 config.peers.heartbeat_timeout,
                                 config.peers.heartbeat_period,
+                                config.outgoing.interface.clone(),
                             );
+                            peer.metrics = metrics.clone();
+                            peer.max_message_sizes = max_message_sizes.clone();
+                            peer.frame_limits = config.frames.unwrap_or_default();
+                            peer.wire = config.wire.unwrap_or_default();
+                            peer.registry = registry;
+                            peer.max_pow_difficulty = config.outgoing.max_pow_difficulty;
+                            peer.impairment = impairments.lookup(&addr_info.addr.ip());
+                            peer.transport = transport;
+                            peer.auth = auth;
+                            peer.network = config.network_id.clone().unwrap_or_default();
+                            peer.signed_contacts = config
+                                .contacts
+                                .as_ref()
+                                .is_some_and(|contacts| contacts.signed_only);
+                            peer.session_keys = config.outgoing.tls.is_none();
                             let id = peer.id;
                             log::trace!(
                                 "Controller | Starting peer {}",
@@ -347,7 +1100,8 @@ impl NetworkController {
                             let attempt: i32 = *addr_info.attempt.lock().await;
                             let new_addr_info = AddrInfo {
                                 addr: addr_info.addr,
-                                attempt: Arc::new(Mutex::new(attempt + 1))
+                                attempt: Arc::new(Mutex::new(attempt + 1)),
+                                tags: addr_info.tags.clone(),
                             };
                             if let Err(err) =
                                 send_connect(&id, new_addr_info.clone(), &tx).await
@@ -366,6 +1120,18 @@ impl NetworkController {
                                 //
                                 // FIXME Need to see what's going on if the id was already in the
                                 // map... but unlikely because we just created this uuid.
+                                Metrics::incr(&metrics.conn_attempts);
+                                if let Some(journal) = &journal {
+                                    journal.record(Record::Attempt {
+                                        peer: id,
+                                        addr: new_addr_info.addr,
+                                        attempt: attempt + 1,
+                                    });
+                                }
+                                let _ = tx_pub.send(NetworkEvent::Attempting {
+                                    addr: new_addr_info.addr,
+                                    attempt: attempt + 1,
+                                });
                                 let _ = outgoing.attempting.insert(id, new_addr_info);
                                 set
                             }
@@ -374,6 +1140,11 @@ impl NetworkController {
                     .await;
 
                 idle_guard.addrs = addrs;
+                let max_idle_count = config.peers.max_idle_count.max(0) as usize;
+                for addr in trim_idle(&mut idle_guard, max_idle_count, &counts, now) {
+                    log::info!("Controller | Dropping {addr} | Too many idle addresses");
+                    let _ = tx_pub.send(NetworkEvent::AddressDropped { addr });
+                }
             }
         });
         Ok(handle)
@@ -391,6 +1162,7 @@ impl NetworkController {
         let controller_addr = self.addr;
         let outgoing = self.outgoing.clone();
         let incoming = self.incoming.clone();
+        let idle = self.idle.clone();
         let interval = self.config.peer_file_dump_interval.try_into().unwrap();
         let profile_path = Path::new(&self.config.target.file)
             .parent()
@@ -408,31 +1180,18 @@ impl NetworkController {
                     id: controller,
                     label: label.clone(),
                     addr: controller_addr,
+                    health: None,
                 };
 
-                let incoming_guard = incoming.lock().await;
-
-                let incoming = futures::stream::iter(&incoming_guard.connected)
-                    .fold(Vec::new(), |mut acc, (_, info)| async move {
-                        acc.push(info.clone());
-                        acc
-                    })
-                    .await;
-
-                let outgoing_guard = outgoing.lock().await;
-
-                let outgoing = futures::stream::iter(&outgoing_guard.connected)
-                    .fold(Vec::new(), |mut acc, (_, info)| async move {
-                        acc.push(info.clone());
-                        acc
-                    })
-                    .await;
+                let summary = summary(controller, &incoming, &outgoing, &idle).await;
 
-                let mut addrs = outgoing
+                let mut addrs = summary
+                    .outgoing
                     .iter()
                     .map(|info| info.addr)
                     .collect::<Vec<SocketAddr>>();
-                let mut in_addrs = incoming
+                let mut in_addrs = summary
+                    .incoming
                     .iter()
                     .map(|info| info.addr)
                     .collect::<Vec<SocketAddr>>();
@@ -448,12 +1207,6 @@ impl NetworkController {
 
                 log::info!("D2: {:?}", d2);
                 if d2.unwrap_or_default() {
-                    let summary = Summary {
-                        controller,
-                        incoming,
-                        outgoing,
-                    };
-
                     path.pop(); // remove 'peers.json'
                     path.push("peers.d2");
 
@@ -469,311 +1222,1746 @@ impl NetworkController {
         Ok(handle)
     }
 
-    /// Spawn a thread which broadcast a 'SendContactRequest' command to all
-    /// the outgoing peers.
+    /// Spawn a thread which sends a 'SendContactRequest' command to a live
+    /// outgoing peer, picked at random, every `contacts.exchange_interval`.
+    /// Contacts are requested on the connections we dialed, and answered on
+    /// those we accepted.
     async fn start_network_discovery(&self) -> Result<JoinHandle<()>, Error> {
         let outgoing = self.outgoing.clone();
         let peers = self.peers.clone();
-        let interval = self.config.peer_file_dump_interval.try_into().unwrap();
+        let interval = self
+            .config
+            .contacts
+            .as_ref()
+            .and_then(|contacts| contacts.exchange_interval)
+            .unwrap_or_else(|| self.config.peer_file_dump_interval.max(1) as u64);
         let handle = tokio::spawn(async move {
-            let mut interval = time::interval(Duration::from_secs(interval)); // Every second
+            let mut interval = time::interval(Duration::from_secs(interval.max(1)));
             loop {
                 // We wait for the periodic tick,
                 interval.tick().await;
 
-                let outgoing = outgoing.lock().await;
+                let alive = outgoing
+                    .lock()
+                    .await
+                    .connected
+                    .keys()
+                    .copied()
+                    .collect::<Vec<_>>();
+                if alive.is_empty() {
+                    continue;
+                }
+                let id = alive[fastrand::usize(..alive.len())];
+                let peers = peers.lock().await;
+                // The peer may be gone since the connections were listed.
+                let Some(peer_data) = peers.get(&id) else {
+                    continue;
+                };
+                if let Err(err) =
+                    send_command_single_peer(Command::SendContactRequest, &peer_data.tx, &id).await
+                {
+                    log::error!(
+                        "Controller | Could not send 'contact request' to peer {} | {err}",
+                        id.to_string().get(0..8).unwrap()
+                    );
+                }
+            }
+        });
+        Ok(handle)
+    }
 
-                // FIXME Could we do without that ugly clone?
-                futures::stream::iter(outgoing.connected.clone()).for_each(|(id, _)| {
-                    let peers = peers.clone();
-                    async move {
-                        let peers = peers.lock().await;
-                        let peer_data = peers.get(&id).unwrap();
-                        if let Err(err) =
-                            send_command_single_peer(Command::SendContactRequest, &peer_data.tx, &id)
-                            .await
-                            {
-                                log::error!(
-                                    "Controller | Could not send 'contact request' to peer {} | {err}",
-                                    id.to_string().get(0..8).unwrap());
-                            }
-                    }
-                })
-                .await;
+    /// Spawn a thread serving the admin endpoints, on a separate address
+    /// from the peer-to-peer listener.
+    async fn start_admin(
+        &self,
+        config: &Admin,
+    ) -> Result<JoinHandle<Result<(), admin::Error>>, Error> {
+        let addr = socket_addr(&config.addr, config.port)?;
+        let allow = AllowList::new(config.allow.as_deref().unwrap_or_default())
+            .map_err(|err| Error::InvalidAllowList { source: err })?;
+        let state = AdminState {
+            controller: InConnInfo {
+                id: self.id,
+                label: self.label.clone(),
+                addr: self.addr,
+                health: None,
+            },
+            incoming: self.incoming.clone(),
+            outgoing: self.outgoing.clone(),
+            idle: self.idle.clone(),
+            metrics: self.metrics.clone(),
+            allow: Arc::new(allow),
+            snapshot: self.config.snapshot.as_ref().map(Snapshot::path),
+            log_filter: self.log_filter.clone(),
+        };
+        let handle = tokio::spawn(async move {
+            let res = admin::serve(addr, state).await;
+            if let Err(err) = &res {
+                log::error!("Controller | Admin server stopped | {err}");
             }
+            res
         });
         Ok(handle)
     }
 
-    /// The main network controller loop:
-    /// We spawn a thread to listen to incoming tcp connection,
-    /// We send a connect to all initial peers to connect to their remote,
-    /// and then we just listen to incoming events.
-    pub async fn run(&mut self) -> Result<(), Error> {
-        let handle = self.start_listen().await?;
-        self.listen_handle = Some(handle);
-        let handle = self.start_monitor_idle().await?;
-        self.monitor_idle_handle = Some(handle);
-        let handle = self.start_monitor_status().await?;
-        self.monitor_status_handle = Some(handle);
-        let handle = self.start_network_discovery().await?;
-        self.network_discovery_handle = Some(handle);
+    /// Spawn a thread serving the control socket, which hands the commands
+    /// of local clients over to the controller.
+    #[cfg(unix)]
+    async fn start_control(
+        &self,
+        config: &Control,
+    ) -> Result<JoinHandle<Result<(), control::Error>>, Error> {
+        let path = config.path();
+        let tx_ctl = self.tx_ctl.clone();
+        let shutdown = self.shutdown.clone();
+        let handle = tokio::spawn(async move {
+            let res = control::serve(path, tx_ctl, shutdown).await;
+            if let Err(err) = &res {
+                log::error!("Controller | Control socket stopped | {err}");
+            }
+            res
+        });
+        Ok(handle)
+    }
 
-        let peers = self.peers.clone();
-        let outgoing = self.outgoing.clone();
+    /// Spawn a thread which writes a snapshot of the controller's state
+    /// each time the process receives SIGUSR1.
+    #[cfg(unix)]
+    async fn start_snapshot(&self, config: &Snapshot) -> Result<JoinHandle<()>, Error> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut signals = signal(SignalKind::user_defined1()).map_err(|err| Error::IO {
+            source: err,
+            detail: "Cannot install SIGUSR1 handler".to_owned(),
+        })?;
+        let controller = InConnInfo {
+            id: self.id,
+            label: self.label.clone(),
+            addr: self.addr,
+            health: None,
+        };
         let incoming = self.incoming.clone();
+        let outgoing = self.outgoing.clone();
         let idle = self.idle.clone();
-        // let config = self.config.clone();
-        // Start receiving events
-        while let Some(event) = self.rx_evt.recv().await {
-            match event {
-                Event::BindError { source: _, addr } => {
-                    log::error!("Network controller cannot bind to addr {}.", addr);
-                    log::error!(
-                        "Maybe modify the network.controller.listen section in the configuration."
-                    );
-                    log::error!("Terminating");
-                    return Err(Error::Bind {
-                        detail: format!("Network controller cannot bind to addr {}", addr),
-                    });
+        let metrics = self.metrics.clone();
+        let path = config.path();
+        let handle = tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                let state =
+                    snapshot::take(controller.clone(), &incoming, &outgoing, &idle, &metrics).await;
+                match state.save(&path).await {
+                    Ok(()) => log::info!("Controller | Snapshot written to {}", path.display()),
+                    Err(err) => log::error!("Controller | Could not write snapshot | {err}"),
                 }
-                Event::InvalidState {
-                    id,
-                    expected,
-                    actual,
-                } => {
-                    // Note there is a slightly better solution, but requires nightly:
-                    // expected .into_iter() .map(|s| s.to_string()) .intersperse(String::from(", ")) .collect(),
-                    let expected = expected
-                        .into_iter()
-                        .fold(String::new(), |s, t| s + t.to_string().as_str() + " or ");
-                    let expected = expected.trim_end_matches(" or ");
-                    log::error!(
-                        "Controller | Peer {} is not in its expected state | Actual {}. Expected {}",
-                        id.to_string().get(0..8).unwrap(),
-                        actual.to_string(),
-                        expected // expected .into_iter() .map(|s| s.to_string()) .intersperse(String::from(", ")) .collect(),
-                    );
+            }
+        });
+        Ok(handle)
+    }
+
+    /// Spawn a thread which writes the address book periodically.
+    async fn start_address_book(&self, config: &AddressBook) -> Result<JoinHandle<()>, Error> {
+        let incoming = self.incoming.clone();
+        let outgoing = self.outgoing.clone();
+        let idle = self.idle.clone();
+        let path = config.path();
+        let period = Duration::from_secs(config.interval.max(1));
+        let handle = tokio::spawn(async move {
+            let mut interval = time::interval(period);
+            // The first tick completes at once: the book is not written before
+            // the controller knows anything.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let entries = addrbook::collect(&incoming, &outgoing, &idle).await;
+                if let Err(err) = addrbook::save(&path, &entries).await {
+                    log::error!("Controller | Could not write address book | {err}");
                 }
-                Event::Connected { id } => {
-                    // Peer is in OutConnecting, and has successfully connected, so we need to
-                    // * send it the connection request command to initiate the handshake.
-                    log::info!(
-                        "Controller | Peer {} is connected.",
-                        id.to_string().get(0..8).unwrap()
-                    );
-                    match peers.lock().await.get(&id).ok_or(Error::UnknownId {
-                        id,
-                        detail: "Controller | Could not find id in peer table.".to_owned(),
-                    }) {
-                        Err(err) => {
-                            log::error!("{err}");
+            }
+        });
+        Ok(handle)
+    }
+
+    /// Spawn a thread which announces the node on the LAN with mDNS, and adds
+    /// the nodes it hears of to the idle addresses.
+    async fn start_mdns(&self, config: &Mdns) -> Result<JoinHandle<()>, Error> {
+        let id = self.id;
+        let port = self.addr.port();
+        let own = [self.addr, self.external.advertised()];
+        let idle = self.idle.clone();
+        let incoming = self.incoming.clone();
+        let outgoing = self.outgoing.clone();
+        let max_idle = self.max_idle();
+        let period = Duration::from_secs(config.interval.max(1));
+        let handle = tokio::spawn(async move {
+            let (tx, mut rx) = mpsc::channel(16);
+            let serve = mdns::serve(id, port, period, tx);
+            tokio::pin!(serve);
+            loop {
+                tokio::select! {
+                    res = &mut serve => {
+                        if let Err(err) = res {
+                            log::error!("Controller | mDNS discovery stopped | {err}");
                         }
-                        Ok(peer_data) => {
-                            if let Err(err) = send_command_single_peer(
-                                Command::SendConnRequest,
-                                &peer_data.tx,
-                                &id,
-                            )
-                            .await
-                            {
-                                log::error!(
-                                    "Controller | Could not send 'connection request' to peer {} | {err}",
-                                    id.to_string().get(0..8).unwrap());
-                            }
+                        return;
+                    }
+                    Some((node, addr)) = rx.recv() => {
+                        // Nodes announce themselves periodically: those we
+                        // are connected to are not dialed again.
+                        let mut known = HashSet::from([id]);
+                        known.extend(incoming.lock().await.connected.values().map(|info| info.id));
+                        known.extend(outgoing.lock().await.connected.values().map(|info| info.id));
+                        if known.contains(&node) {
+                            continue;
                         }
-                    };
+                        let mut idle = idle.lock().await;
+                        let count = idle.addrs.len();
+                        add_contacts(&mut idle, &own, &known, max_idle, vec![addr], Vec::new(), Vec::new());
+                        if idle.addrs.len() > count {
+                            log::info!(
+                                "Controller | Found {} at {addr} on the LAN",
+                                node.to_string().get(0..8).unwrap()
+                            );
+                        }
+                    }
                 }
-                Event::Listening { id } => {
-                    // Peer is in InHandshaking, and is successfully listening to incoming
-                    // messages, so we need to:
-                    // * remove it from the list of attempted connections
-                    // * add it to the list of actual connections.
-                    // don't do anything else (wait for the remote peer to send handshake request.)
-                    log::info!(
-                        "Controller | Peer {} is listening.",
-                        id.to_string().get(0..8).unwrap()
-                    );
-                    // FIXME What to do in that state
-                    // let mut incoming_guard = incoming.lock().await;
-                    // incoming_guard.connected.insert(id);
-                }
-                Event::OutAlive {
-                    id,
-                    peer_id,
-                    peer_label,
-                    peer_addr,
-                } => {
-                    log::info!("Controller | Connection with {} is live.", peer_label);
-                    let mut outgoing_guard = outgoing.lock().await;
-                    let _addr_info = outgoing_guard
-                        .attempting
-                        .remove(&id)
-                        .expect("addr info for id");
-                    outgoing_guard.connected.insert(
-                        id,
-                        OutConnInfo {
-                            addr: peer_addr,
-                            id: peer_id,
-                            label: peer_label,
-                            rtt: i64::MAX,
-                        },
+            }
+        });
+        Ok(handle)
+    }
+
+    /// Spawn a thread which looks up our own id and a random id in the DHT
+    /// periodically, asking the peers closest to each id for the nodes they
+    /// know closest to it.
+    async fn start_dht(&self, config: &Dht) -> Result<JoinHandle<()>, Error> {
+        let id = self.id;
+        let peers = self.peers.clone();
+        let incoming = self.incoming.clone();
+        let outgoing = self.outgoing.clone();
+        let alpha = config.alpha.max(1);
+        let period = Duration::from_secs(config.interval.max(1));
+        let handle = tokio::spawn(async move {
+            let mut interval = time::interval(period);
+            loop {
+                interval.tick().await;
+                // Our own id finds our neighbours, a random id fills the
+                // farther buckets.
+                for target in [id, Uuid::new_v4()] {
+                    let mut closest = outgoing
+                        .lock()
+                        .await
+                        .connected
+                        .iter()
+                        .map(|(local, info)| (*local, info.id))
+                        .collect::<Vec<_>>();
+                    closest.extend(
+                        incoming
+                            .lock()
+                            .await
+                            .connected
+                            .iter()
+                            .map(|(local, info)| (*local, info.id)),
                     );
+                    closest.sort_by_key(|(_, remote)| dht::distance(remote, &target));
+                    closest.truncate(alpha);
+                    let peers = peers.lock().await;
+                    for (local, _) in closest {
+                        // The peer may be gone since the connections were listed.
+                        let Some(peer_data) = peers.get(&local) else {
+                            continue;
+                        };
+                        let cmd = Command::SendFindNode { target };
+                        if let Err(err) = send_command_single_peer(cmd, &peer_data.tx, &local).await
+                        {
+                            log::error!(
+                                "Controller | Could not send 'find node' to peer {} | {err}",
+                                local.to_string().get(0..8).unwrap()
+                            );
+                        }
+                    }
                 }
-                Event::InAlive {
-                    id,
-                    peer_id,
-                    peer_label,
-                    peer_addr,
-                } => {
-                    log::info!("Controller | Connection with {} is live.", peer_label);
-                    let mut incoming_guard = incoming.lock().await;
-                    incoming_guard.connected.insert(
-                        id,
-                        InConnInfo {
-                            addr: peer_addr,
-                            id: peer_id,
-                            label: peer_label,
-                        },
-                    );
+            }
+        });
+        Ok(handle)
+    }
+
+    /// Snapshots on signal are only supported on unix. On other platforms,
+    /// use the admin server.
+    #[cfg(not(unix))]
+    async fn start_snapshot(&self, _config: &Snapshot) -> Result<JoinHandle<()>, Error> {
+        Ok(tokio::spawn(async {}))
+    }
+
+    /// Run the controller in the background, and return a handle on it.
+    /// Subscribe and register messages before starting the controller, or
+    /// subscribe through the handle.
+    pub fn start(self) -> NetworkHandle {
+        let controller = InConnInfo {
+            addr: self.addr,
+            id: self.id,
+            label: self.label.clone(),
+            health: None,
+        };
+        let payloads = self.payloads();
+        let idle = self.idle.clone();
+        let bans = self.bans.clone();
+        let tx_pub = self.tx_pub.clone();
+        let tx_ctl = self.tx_ctl.clone();
+        let shutdown = self.shutdown.clone();
+        let task = tokio::spawn(self.run_on_runtime());
+        NetworkHandle {
+            controller,
+            payloads,
+            idle,
+            bans,
+            tx_pub,
+            tx_ctl,
+            shutdown,
+            task: Arc::new(Mutex::new(Some(task))),
+        }
+    }
+
+    /// Run the controller on its own runtime, if the runtime section is present,
+    /// so that a busy application sharing the process does not starve the
+    /// controller's tasks (heartbeats, accepts). Otherwise, the controller runs
+    /// on the current runtime.
+    pub async fn run_on_runtime(mut self) -> Result<(), Error> {
+        let config = match &self.config.runtime {
+            Some(config) => config.clone(),
+            None => return self.run().await,
+        };
+        let runtime = config.build().map_err(|err| Error::IO {
+            source: err,
+            detail: "Could not build the network runtime".to_owned(),
+        })?;
+        // Tasks spawned by the controller inherit its runtime.
+        let res = runtime
+            .spawn(async move { self.run().await })
+            .await
+            .map_err(|err| Error::Runtime {
+                detail: format!("The network controller task failed: {err}"),
+            });
+        // A runtime cannot be dropped from an asynchronous context.
+        runtime.shutdown_background();
+        res?
+    }
+
+    /// Ask the STUN server for our external address. Without an answer, we
+    /// keep advertising the listen address.
+    async fn discover_external_addr(&self, server: &str) {
+        let res = match tokio::net::lookup_host(server).await {
+            Ok(mut addrs) => match addrs.next() {
+                Some(server) => discovery::stun(server).await,
+                None => Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Could not resolve {server}"),
+                )),
+            },
+            Err(err) => Err(err),
+        };
+        match res {
+            Ok(ip) => {
+                self.external.set(ip);
+                log::info!(
+                    "Controller | Advertising the external address {}",
+                    self.external.advertised()
+                );
+            }
+            Err(err) => {
+                log::warn!("Controller | Could not discover the external address | {err}");
+            }
+        }
+    }
+
+    /// The main network controller loop:
+    /// We spawn a thread to listen to incoming tcp connection,
+    /// We send a connect to all initial peers to connect to their remote,
+    /// and then we just listen to incoming events, until the shutdown token
+    /// is cancelled.
+    pub async fn run(&mut self) -> Result<(), Error> {
+        #[cfg(unix)]
+        if !self.unix_targets.is_empty() {
+            self.transport = Arc::new(transport::UnixTargets::new(
+                self.transport.clone(),
+                self.unix_targets.clone(),
+            ));
+        }
+        if let Some(config) = &self.config.journal {
+            let journal = journal::Journal::open(&config.path())
+                .map_err(|err| Error::Journal { source: err })?
+                .with_payloads(config.payloads);
+            journal.record(Record::Start {
+                id: self.id,
+                label: self.label.clone(),
+                addr: self.addr,
+            });
+            self.journal = Some(Arc::new(journal));
+        }
+        if let Some(server) = self.config.discovery.as_ref().and_then(|d| d.stun.as_ref()) {
+            self.discover_external_addr(server).await;
+        }
+        let handle = self.start_listen().await?;
+        self.listen_handle = Some(handle);
+        let handle = self.start_monitor_idle().await?;
+        self.monitor_idle_handle = Some(handle);
+        let handle = self.start_monitor_status().await?;
+        self.monitor_status_handle = Some(handle);
+        let handle = self.start_network_discovery().await?;
+        self.network_discovery_handle = Some(handle);
+        if let Some(admin) = &self.config.admin {
+            let handle = self.start_admin(admin).await?;
+            self.admin_handle = Some(handle);
+        }
+        if let Some(snapshot) = &self.config.snapshot {
+            let handle = self.start_snapshot(snapshot).await?;
+            self.snapshot_handle = Some(handle);
+        }
+        if let Some(book) = &self.config.address_book {
+            let handle = self.start_address_book(book).await?;
+            self.address_book_handle = Some(handle);
+        }
+        if let Some(config) = &self.config.mdns {
+            let handle = self.start_mdns(config).await?;
+            self.mdns_handle = Some(handle);
+        }
+        if let Some(config) = &self.config.dht {
+            let handle = self.start_dht(config).await?;
+            self.dht_handle = Some(handle);
+        }
+        #[cfg(unix)]
+        if let Some(config) = &self.config.control {
+            let handle = self.start_control(config).await?;
+            self.control_handle = Some(handle);
+        }
+
+        let shutdown = self.shutdown.clone();
+        loop {
+            tokio::select! {
+                event = self.rx_evt.recv() => match event {
+                    Some(event) => self.handle_event(event).await?,
+                    None => return Ok(()),
+                },
+                // The controller holds a sender: the channel is never closed.
+                Some(cmd) = self.rx_ctl.recv() => self.handle_command(cmd).await,
+                _ = shutdown.cancelled() => return self.shut_down().await,
+            }
+        }
+    }
+
+    /// Stop the tasks of the controller, so that no connection is accepted
+    /// or dialed anymore, and close the live connections, after telling the
+    /// remote nodes. Peers which are not done by the deadline, and those
+    /// still connecting, are aborted.
+    async fn shut_down(&mut self) -> Result<(), Error> {
+        log::info!("Controller | Shutting down");
+        let handles = [
+            self.monitor_idle_handle.take(),
+            self.monitor_status_handle.take(),
+            self.network_discovery_handle.take(),
+            self.snapshot_handle.take(),
+            self.address_book_handle.take(),
+            self.mdns_handle.take(),
+            self.dht_handle.take(),
+        ];
+        for handle in handles.into_iter().flatten() {
+            handle.abort();
+        }
+        // The book is written while the connections are still live.
+        if let Some(book) = &self.config.address_book {
+            let entries = addrbook::collect(&self.incoming, &self.outgoing, &self.idle).await;
+            match addrbook::save(&book.path(), &entries).await {
+                Ok(()) => log::info!("Controller | Address book written"),
+                Err(err) => log::error!("Controller | Could not write address book | {err}"),
+            }
+        }
+        if let Some(handle) = self.listen_handle.take() {
+            handle.abort();
+        }
+        if let Some(handle) = self.admin_handle.take() {
+            handle.abort();
+        }
+        #[cfg(unix)]
+        if let Some(handle) = self.control_handle.take() {
+            handle.abort();
+        }
+        let outgoing = self
+            .outgoing
+            .lock()
+            .await
+            .connected
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        let incoming = self
+            .incoming
+            .lock()
+            .await
+            .connected
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        self.peers.lock().await.retain(|id, peer| {
+            let alive = outgoing.contains(id) || incoming.contains(id);
+            if !alive {
+                peer.handle.abort();
+            }
+            alive
+        });
+        let commands = outgoing
+            .into_iter()
+            .map(|id| (id, Command::Disconnect))
+            .chain(incoming.into_iter().map(|id| (id, Command::Terminate)));
+        for (id, cmd) in commands {
+            if let Err(err) = self.command_peer(id, cmd).await {
+                log::warn!(
+                    "Controller | Could not close the connection of peer {} | {err}",
+                    id.to_string().get(0..8).unwrap()
+                );
+            }
+        }
+        // The peers are removed as they report their connection closed.
+        let deadline = time::sleep(Duration::from_secs(self.config.peers.shutdown_timeout));
+        tokio::pin!(deadline);
+        while !self.peers.lock().await.is_empty() {
+            tokio::select! {
+                event = self.rx_evt.recv() => match event {
+                    Some(event) => self.handle_event(event).await?,
+                    None => break,
+                },
+                _ = &mut deadline => {
+                    log::warn!("Controller | Peers still connected at the shutdown deadline");
+                    break;
                 }
-                Event::ConnectionUpdate { id, rtt } => {
-                    let mut outgoing_guard = outgoing.lock().await;
-                    outgoing_guard
-                        .connected
-                        .entry(id)
-                        .and_modify(|info| info.rtt = rtt);
+            }
+        }
+        for (_, peer) in self.peers.lock().await.drain() {
+            peer.handle.abort();
+        }
+        log::info!("Controller | Shut down");
+        Ok(())
+    }
+
+    /// Handle a command sent by the application.
+    async fn handle_command(&self, cmd: ControllerCommand) {
+        match cmd {
+            ControllerCommand::Connect { addr } => {
+                log::info!("Controller | Dialing {addr} on request");
+                dial(&self.idle, addr).await;
+            }
+            ControllerCommand::Disconnect { peer_id } => {
+                if let Err(err) = close(&self.peers, &self.outgoing, &self.incoming, peer_id).await
+                {
+                    log::warn!("Controller | Could not disconnect {peer_id} | {err}");
                 }
-                Event::Disconnected { id, addr } => {
-                    // We remove the id from the list of outgoing peers,
-                    // and also push back the addr into the list of idle addresses.
-                    log::info!(
-                        "Controller | Peer {} is disconnected from {}.",
-                        id.to_string().get(0..8).unwrap(),
-                        addr
-                    );
-                    let addr_info = outgoing
+            }
+            ControllerCommand::Ban { peer_id, duration } => {
+                let outgoing = self.outgoing.lock().await;
+                let addr = match outgoing.connected.values().find(|info| info.id == peer_id) {
+                    Some(info) => Some(info.addr),
+                    None => self
+                        .incoming
                         .lock()
                         .await
                         .connected
-                        .remove(&id)
-                        .expect("addr_info for id");
-                    let addr_info = AddrInfo {
-                        addr: addr_info.addr,
-                        attempt: Arc::new(Mutex::new(0)),
+                        .values()
+                        .find(|info| info.id == peer_id)
+                        .map(|info| info.addr),
+                };
+                drop(outgoing);
+                log::info!("Controller | Banning {peer_id}");
+                self.ban(ban::Target::Peer(peer_id), duration);
+                if let Some(addr) = addr {
+                    log::info!("Controller | Banning {}", addr.ip());
+                    self.ban(ban::Target::Addr(addr.ip()), duration);
+                }
+                if let Err(err) = close(&self.peers, &self.outgoing, &self.incoming, peer_id).await
+                {
+                    log::warn!("Controller | Could not disconnect {peer_id} | {err}");
+                }
+            }
+            ControllerCommand::QueryStatus { reply } => {
+                let controller = InConnInfo {
+                    addr: self.addr,
+                    id: self.id,
+                    label: self.label.clone(),
+                    health: None,
+                };
+                // The application may have given up waiting.
+                let _ = reply
+                    .send(summary(controller, &self.incoming, &self.outgoing, &self.idle).await);
+            }
+            ControllerCommand::Broadcast { topic, data } => {
+                for id in alive_peers(&self.outgoing, &self.incoming).await {
+                    let cmd = Command::SendPayload {
+                        topic: topic.clone(),
+                        data: data.clone(),
                     };
-                    idle.lock().await.addrs.insert(addr_info);
-                    let peer = peers.lock().await.remove(&id).expect("peer for id");
-                    peer.handle.abort();
+                    if let Err(err) = self.command_peer(id, cmd).await {
+                        log::warn!(
+                            "Controller | Could not send payload to peer {} | {err}",
+                            id.to_string().get(0..8).unwrap()
+                        );
+                    }
                 }
-                Event::Terminated { id } => {
-                    log::info!(
-                        "Controller | Peer {} is terminated.",
+            }
+        }
+    }
+
+    /// Maximum number of idle addresses: `contacts.max_idle`, and
+    /// `peers.max_idle_count` if set. Contacts beyond are dropped.
+    fn max_idle(&self) -> usize {
+        let max_idle = self.config.contacts.clone().unwrap_or_default().max_idle;
+        match self.config.peers.max_idle_count {
+            count if count > 0 => max_idle.min(count as usize),
+            _ => max_idle,
+        }
+    }
+
+    /// Delay before the next attempt to an address, after the given number
+    /// of attempts.
+    fn retry_delay(&self, attempt: i32) -> Duration {
+        let peers = &self.config.peers;
+        backoff::delay(
+            Duration::from_secs(peers.conn_attempt_delay.max(0) as u64),
+            attempt,
+            Duration::from_secs(peers.max_conn_attempt_delay),
+        )
+    }
+
+    /// Ban the remote node if its score fell too low.
+    async fn judge(&self, peer_id: Uuid, addr: SocketAddr) {
+        let scoring = self.config.scoring.clone().unwrap_or_default();
+        let mut idle = self.idle.lock().await;
+        let history = idle.history.entry(addr).or_default();
+        let score = score::peer_score(Some(history));
+        if score >= scoring.ban_below {
+            return;
+        }
+        log::warn!("Controller | Banning {peer_id} at {addr} | Score {score:.2}");
+        history.dropped = Some(format!("banned | score {score:.2}"));
+        drop(idle);
+        let duration = Duration::from_secs(scoring.ban_duration);
+        self.ban(ban::Target::Peer(peer_id), Some(duration));
+        self.close_if_banned(peer_id).await;
+    }
+
+    /// Close one of the connections with the remote node, if the peer just
+    /// alive is not the only one connected to it. Both nodes keep the
+    /// connection dialed by the node with the lower id, and of the
+    /// connections in the same direction, the oldest one.
+    async fn close_duplicate(&self, id: Uuid, peer_id: Uuid, direction: Direction) {
+        let others = |local: &Uuid, remote: &Uuid| *local != id && *remote == peer_id;
+        let outgoing = self
+            .outgoing
+            .lock()
+            .await
+            .connected
+            .iter()
+            .find(|(local, info)| others(local, &info.id))
+            .map(|(local, _)| *local);
+        let incoming = self
+            .incoming
+            .lock()
+            .await
+            .connected
+            .iter()
+            .find(|(local, info)| others(local, &info.id))
+            .map(|(local, _)| *local);
+        let ours = self.id < peer_id;
+        let (duplicate, duplicate_direction) = match (direction, outgoing, incoming) {
+            (Direction::Outgoing, Some(_), _) => (id, Direction::Outgoing),
+            (Direction::Incoming, _, Some(_)) => (id, Direction::Incoming),
+            (Direction::Outgoing, None, Some(other)) if ours => (other, Direction::Incoming),
+            (Direction::Outgoing, None, Some(_)) => (id, Direction::Outgoing),
+            (Direction::Incoming, Some(_), None) if ours => (id, Direction::Incoming),
+            (Direction::Incoming, Some(other), None) => (other, Direction::Outgoing),
+            _ => return,
+        };
+        log::info!(
+            "Controller | Closing duplicate connection with {peer_id} | Peer {}",
+            duplicate.to_string().get(0..8).unwrap()
+        );
+        let cmd = match duplicate_direction {
+            Direction::Outgoing => Command::Disconnect,
+            Direction::Incoming => Command::Terminate,
+        };
+        if let Err(err) = self.command_peer(duplicate, cmd).await {
+            log::warn!("Controller | Could not close duplicate connection | {err}");
+        }
+    }
+
+    /// Add the target to the ban list, which may lift the oldest ban.
+    fn ban(&self, target: ban::Target, duration: Option<Duration>) {
+        let now = std::time::Instant::now();
+        if let Some(lifted) = self.bans.ban(target, duration, now) {
+            log::info!("Controller | Lifting the ban of {lifted} | Too many bans");
+            let _ = self.tx_pub.send(NetworkEvent::BanLifted { target: lifted });
+        }
+    }
+
+    /// Close the connection with the remote node if it is banned. Its id is
+    /// only known once the handshake completes.
+    async fn close_if_banned(&self, peer_id: Uuid) {
+        if !self
+            .bans
+            .is_banned(&ban::Target::Peer(peer_id), std::time::Instant::now())
+        {
+            return;
+        }
+        log::warn!("Controller | Closing connection with {peer_id} | Banned");
+        if let Err(err) = close(&self.peers, &self.outgoing, &self.incoming, peer_id).await {
+            log::warn!("Controller | Could not disconnect {peer_id} | {err}");
+        }
+    }
+
+    /// Handle an event sent by a peer.
+    pub(crate) async fn handle_event(&self, event: Event) -> Result<(), Error> {
+        if let Some(journal) = &self.journal {
+            journal.record(Record::Event {
+                event: (&event).into(),
+            });
+        }
+        let peers = self.peers.clone();
+        let outgoing = self.outgoing.clone();
+        let incoming = self.incoming.clone();
+        let idle = self.idle.clone();
+        let tx_pub = self.tx_pub.clone();
+        let metrics = self.metrics.clone();
+        match event {
+            Event::BindError { source: _, addr } => {
+                log::error!("Network controller cannot bind to addr {}.", addr);
+                log::error!(
+                    "Maybe modify the network.controller.listen section in the configuration."
+                );
+                log::error!("Terminating");
+                return Err(Error::Bind {
+                    detail: format!("Network controller cannot bind to addr {}", addr),
+                });
+            }
+            Event::InvalidState {
+                id,
+                expected,
+                actual,
+            } => {
+                // Note there is a slightly better solution, but requires nightly:
+                // expected .into_iter() .map(|s| s.to_string()) .intersperse(String::from(", ")) .collect(),
+                let expected = expected
+                    .into_iter()
+                    .fold(String::new(), |s, t| s + t.to_string().as_str() + " or ");
+                let expected = expected.trim_end_matches(" or ");
+                log::error!(
+                    "Controller | Peer {} is not in its expected state | Actual {}. Expected {}",
+                    id.to_string().get(0..8).unwrap(),
+                    actual.to_string(),
+                    expected // expected .into_iter() .map(|s| s.to_string()) .intersperse(String::from(", ")) .collect(),
+                );
+            }
+            Event::Connected { id } => {
+                // Peer is in OutConnecting, and has successfully connected, so we need to
+                // * send it the connection request command to initiate the handshake.
+                log::info!(
+                    "Controller | Peer {} is connected.",
+                    id.to_string().get(0..8).unwrap()
+                );
+                if let Err(err) = self.command_peer(id, Command::SendConnRequest).await {
+                    log::error!(
+                        "Controller | Could not send 'connection request' to peer {} | {err}",
                         id.to_string().get(0..8).unwrap()
                     );
-                    incoming.lock().await.connected.remove(&id);
-                    let peer = peers.lock().await.remove(&id).expect("peer for id");
+                }
+            }
+            Event::Listening { id } => {
+                // Peer is in InHandshaking, and is successfully listening to incoming
+                // messages, so we need to:
+                // * remove it from the list of attempted connections
+                // * add it to the list of actual connections.
+                // don't do anything else (wait for the remote peer to send handshake request.)
+                log::info!(
+                    "Controller | Peer {} is listening.",
+                    id.to_string().get(0..8).unwrap()
+                );
+                // FIXME What to do in that state
+                // let mut incoming_guard = incoming.lock().await;
+                // incoming_guard.connected.insert(id);
+            }
+            Event::OutAlive {
+                id,
+                peer_id,
+                peer_label,
+                peer_addr,
+            } => {
+                log::info!("Controller | Connection with {} is live.", peer_label);
+                let mut outgoing_guard = outgoing.lock().await;
+                let addr_info = outgoing_guard
+                    .attempting
+                    .remove(&id)
+                    .expect("addr info for id");
+                outgoing_guard.connected.insert(
+                    id,
+                    OutConnInfo {
+                        addr: peer_addr,
+                        id: peer_id,
+                        label: peer_label.clone(),
+                        rtt: i64::MAX,
+                        offset: 0,
+                        tags: addr_info.tags,
+                        health: None,
+                    },
+                );
+                drop(outgoing_guard);
+                self.count_connected().await;
+                if let Some(relay) = self.relay.as_ref().filter(|relay| relay.via() == peer_addr) {
+                    // There is no peer when replaying a journal.
+                    if let Some(peer) = peers.lock().await.get(&id) {
+                        log::info!("Controller | Dialing unreachable peers through {peer_addr}");
+                        relay.attach(id, peer.tx.clone());
+                    }
+                }
+                let mut idle_guard = idle.lock().await;
+                idle_guard
+                    .history
+                    .entry(addr_info.addr)
+                    .or_default()
+                    .record_success();
+                idle_guard.retry_at.remove(&addr_info.addr);
+                drop(idle_guard);
+                // An error only means there is no subscriber.
+                let _ = tx_pub.send(NetworkEvent::PeerConnected {
+                    peer_id,
+                    label: peer_label,
+                    addr: peer_addr,
+                    direction: Direction::Outgoing,
+                });
+                self.learn(peer_id, peer_addr).await;
+                self.close_if_banned(peer_id).await;
+                self.close_duplicate(id, peer_id, Direction::Outgoing).await;
+            }
+            Event::InAlive {
+                id,
+                peer_id,
+                peer_label,
+                peer_addr,
+            } => {
+                log::info!("Controller | Connection with {} is live.", peer_label);
+                let mut incoming_guard = incoming.lock().await;
+                incoming_guard.connected.insert(
+                    id,
+                    InConnInfo {
+                        addr: peer_addr,
+                        id: peer_id,
+                        label: peer_label.clone(),
+                        health: None,
+                    },
+                );
+                drop(incoming_guard);
+                self.count_connected().await;
+                let _ = tx_pub.send(NetworkEvent::PeerConnected {
+                    peer_id,
+                    label: peer_label,
+                    addr: peer_addr,
+                    direction: Direction::Incoming,
+                });
+                self.learn(peer_id, peer_addr).await;
+                self.close_if_banned(peer_id).await;
+                self.close_duplicate(id, peer_id, Direction::Incoming).await;
+            }
+            Event::ConnectionUpdate { id, rtt, offset } => {
+                let mut outgoing_guard = outgoing.lock().await;
+                outgoing_guard.connected.entry(id).and_modify(|info| {
+                    info.rtt = rtt;
+                    info.offset = offset;
+                });
+            }
+            Event::HealthUpdate { id, health } => {
+                if let Some(info) = outgoing.lock().await.connected.get_mut(&id) {
+                    info.health = Some(health);
+                    return Ok(());
+                }
+                if let Some(info) = incoming.lock().await.connected.get_mut(&id) {
+                    info.health = Some(health);
+                }
+            }
+            Event::Rejected { id, addr, reason } => {
+                // The remote node refused the connection, and would refuse it
+                // again: the address is not put back in the list of idle.
+                log::warn!(
+                    "Controller | Peer {} was rejected by {} | {reason}",
+                    id.to_string().get(0..8).unwrap(),
+                    addr
+                );
+                Metrics::incr(&metrics.conn_failures);
+                let _ = tx_pub.send(NetworkEvent::AttemptFailed {
+                    addr,
+                    reason: format!("Rejected | {reason}"),
+                });
+                let addr_info = outgoing
+                    .lock()
+                    .await
+                    .attempting
+                    .remove(&id)
+                    .expect("addr_info for id");
+                let mut idle_guard = idle.lock().await;
+                idle_guard
+                    .history
+                    .entry(addr_info.addr)
+                    .or_default()
+                    .record_failure();
+                idle_guard.rejected.insert(addr_info.addr);
+                drop(idle_guard);
+                // There is no peer when replaying a journal.
+                if let Some(peer) = peers.lock().await.remove(&id) {
                     peer.handle.abort();
                 }
-                Event::ConnectionError { id, addr, source } => {
-                    // The peer could not establish a Tcp connection.
-                    // So remove it from the list of attempting, and put it back in the list of
-                    // idle. The 'monitor_idle' thread will pick it up and automatically try to
-                    // reconnect.
-                    log::warn!(
-                        "Controller | Peer {} cannot connect to {} | {source}",
-                        id.to_string().get(0..8).unwrap(),
-                        addr
-                    );
-                    let addr_info = outgoing
-                        .lock()
-                        .await
-                        .attempting
-                        .remove(&id)
-                        .expect("addr_info for id");
-                    idle.lock().await.addrs.insert(addr_info);
+            }
+            Event::Disconnected { id, addr } => {
+                // We remove the id from the list of outgoing peers,
+                // and also push back the addr into the list of idle addresses.
+                log::info!(
+                    "Controller | Peer {} is disconnected from {}.",
+                    id.to_string().get(0..8).unwrap(),
+                    addr
+                );
+                let addr_info = outgoing
+                    .lock()
+                    .await
+                    .connected
+                    .remove(&id)
+                    .expect("addr_info for id");
+                self.count_connected().await;
+                let _ = tx_pub.send(NetworkEvent::PeerDisconnected {
+                    peer_id: addr_info.id,
+                    label: addr_info.label,
+                    addr: addr_info.addr,
+                    direction: Direction::Outgoing,
+                });
+                let rtt = addr_info.rtt;
+                let addr_info = AddrInfo {
+                    addr: addr_info.addr,
+                    attempt: Arc::new(Mutex::new(0)),
+                    tags: addr_info.tags,
+                };
+                let retry_at = std::time::Instant::now() + self.retry_delay(0);
+                let mut idle_guard = idle.lock().await;
+                if rtt != i64::MAX {
+                    idle_guard.history.entry(addr_info.addr).or_default().rtt = Some(rtt);
                 }
-                Event::ContactRequested { id } => {
+                idle_guard.retry_at.insert(addr_info.addr, retry_at);
+                idle_guard.addrs.insert(addr_info);
+                drop(idle_guard);
+                self.close_circuits(id).await;
+                // There is no peer when replaying a journal.
+                if let Some(peer) = peers.lock().await.remove(&id) {
+                    peer.handle.abort();
+                }
+            }
+            Event::Terminated { id } => {
+                log::info!(
+                    "Controller | Peer {} is terminated.",
+                    id.to_string().get(0..8).unwrap()
+                );
+                let removed = incoming.lock().await.connected.remove(&id);
+                self.count_connected().await;
+                if let Some(info) = removed {
+                    let _ = tx_pub.send(NetworkEvent::PeerDisconnected {
+                        peer_id: info.id,
+                        label: info.label,
+                        addr: info.addr,
+                        direction: Direction::Incoming,
+                    });
+                }
+                self.close_circuits(id).await;
+                // There is no peer when replaying a journal.
+                if let Some(peer) = peers.lock().await.remove(&id) {
+                    peer.handle.abort();
+                }
+            }
+            Event::Pong { id, seq, rtt, size } => match self.remote_id(id).await {
+                Some(peer_id) => {
+                    let _ = tx_pub.send(NetworkEvent::Pong {
+                        peer_id,
+                        seq,
+                        rtt,
+                        size,
+                    });
+                }
+                None => log::warn!(
+                    "Controller | Peer {} received a pong | Not connected",
+                    id.to_string().get(0..8).unwrap()
+                ),
+            },
+            Event::Gossip {
+                id,
+                msg_id,
+                ttl,
+                topic,
+                data,
+            } => {
+                if !self.gossip.lock().await.insert(msg_id) {
                     log::trace!(
-                        "Controller | Peer {} requested contacts.",
+                        "Controller | Dropping gossip {} from peer {} | Already seen",
+                        msg_id.to_string().get(0..8).unwrap(),
                         id.to_string().get(0..8).unwrap()
                     );
-                    // Build the contacts from the incoming and outgoing sets.
-                    let incoming_guard = incoming.lock().await;
-
-                    let incoming = futures::stream::iter(&incoming_guard.connected)
-                        .fold(Vec::new(), |mut acc, (_, info)| async move {
-                            acc.push(info.clone());
-                            acc
-                        })
-                        .await;
-
-                    let outgoing_guard = outgoing.lock().await;
-
-                    let outgoing = futures::stream::iter(&outgoing_guard.connected)
-                        .fold(Vec::new(), |mut acc, (_, info)| async move {
-                            acc.push(info.clone());
-                            acc
-                        })
-                        .await;
-                    let mut addrs = outgoing
-                        .iter()
-                        .map(|info| info.addr)
-                        .collect::<Vec<SocketAddr>>();
-                    let mut in_addrs = incoming
-                        .iter()
-                        .map(|info| info.addr)
-                        .collect::<Vec<SocketAddr>>();
-
-                    addrs.append(&mut in_addrs);
-                    match peers.lock().await.get(&id).ok_or(Error::UnknownId {
-                        id,
-                        detail: "Controller | Could not find id in peer table.".to_owned(),
-                    }) {
-                        Err(err) => {
-                            log::error!("{err}");
+                    return Ok(());
+                }
+                match self.remote_id(id).await {
+                    Some(peer_id) => {
+                        let _ = tx_pub.send(NetworkEvent::Gossip {
+                            peer_id,
+                            msg_id,
+                            topic: topic.clone(),
+                            data: data.clone(),
+                        });
+                    }
+                    None => log::warn!(
+                        "Controller | Peer {} received a gossip | Not connected",
+                        id.to_string().get(0..8).unwrap()
+                    ),
+                }
+                // The TTL counts the hops left, this one included.
+                if ttl > 1 {
+                    for to in alive_peers(&outgoing, &incoming).await {
+                        if to == id {
+                            continue;
                         }
-                        Ok(peer_data) => {
-                            if let Err(err) = send_command_single_peer(
-                                Command::SendContactResponse { addrs },
-                                &peer_data.tx,
-                                &id,
-                            )
-                            .await
-                            {
-                                log::error!(
-                            "Controller | Could not send 'connection request' to peer {} | {err}",
-                            id.to_string().get(0..8).unwrap()
-                        );
-                            }
+                        let cmd = Command::SendGossip {
+                            msg_id,
+                            ttl: ttl - 1,
+                            topic: topic.clone(),
+                            data: data.clone(),
+                        };
+                        if let Err(err) = self.command_peer(to, cmd).await {
+                            log::warn!(
+                                "Controller | Could not forward gossip to peer {} | {err}",
+                                to.to_string().get(0..8).unwrap()
+                            );
                         }
                     }
                 }
-                Event::ContactUpdated { id, mut addrs } => {
-                    log::trace!(
-                        "Controller | Peer {} provided a new list of contacts: {addrs:?}",
+            }
+            Event::Payload { id, topic, data } => match self.remote_id(id).await {
+                Some(peer_id) => {
+                    let _ = tx_pub.send(NetworkEvent::Payload {
+                        peer_id,
+                        topic,
+                        data,
+                    });
+                }
+                None => log::warn!(
+                    "Controller | Peer {} received a payload | Not connected",
+                    id.to_string().get(0..8).unwrap()
+                ),
+            },
+            Event::InfoRequested { id } => {
+                let info = InfoResponse {
+                    version: env!("CARGO_PKG_VERSION").to_owned(),
+                    label: self.label.clone(),
+                    outgoing: outgoing.lock().await.connected.len() as u64,
+                    incoming: incoming.lock().await.connected.len() as u64,
+                    uptime: metrics.started.elapsed().as_secs(),
+                };
+                let cmd = Command::SendInfoResponse {
+                    info: Box::new(info),
+                };
+                if let Err(err) = self.command_peer(id, cmd).await {
+                    log::error!(
+                        "Controller | Could not send 'info response' to peer {} | {err}",
                         id.to_string().get(0..8).unwrap()
                     );
-                    if let Some(pos) = addrs.iter().position(|addr| *addr == self.addr) {
-                        addrs.remove(pos);
-                        let mut idle_guard = idle.lock().await;
-                        addrs
-                            .into_iter()
-                            .map(|addr| AddrInfo {
-                                addr,
-                                attempt: Arc::new(Mutex::new(0)),
-                            })
-                            .for_each(|info| {
-                                idle_guard.addrs.insert(info);
-                            });
+                }
+            }
+            Event::FindNode { id, target } => {
+                let remote = self.remote_id(id).await;
+                let mut nodes = {
+                    let table = self.dht.lock().await;
+                    table.closest(&target, table.k() + 1)
+                };
+                // The remote knows itself.
+                nodes.retain(|node| Some(node.id) != remote);
+                nodes.truncate(self.dht.lock().await.k());
+                if let Err(err) = self
+                    .command_peer(id, Command::SendNodes { target, nodes })
+                    .await
+                {
+                    log::error!(
+                        "Controller | Could not send 'nodes' to peer {} | {err}",
+                        id.to_string().get(0..8).unwrap()
+                    );
+                }
+            }
+            Event::Nodes { id, target, nodes } => {
+                // Only the nodes which look up ids expect answers.
+                if self.config.dht.is_none() {
+                    log::debug!(
+                        "Controller | Peer {} sent unexpected 'nodes' | DHT disabled",
+                        id.to_string().get(0..8).unwrap()
+                    );
+                    return Ok(());
+                }
+                log::debug!(
+                    "Controller | Peer {} sent {} node(s) close to {}",
+                    id.to_string().get(0..8).unwrap(),
+                    nodes.len(),
+                    target.to_string().get(0..8).unwrap()
+                );
+                let k = self.dht.lock().await.k();
+                let own = [self.addr, self.external.advertised()];
+                let mut known = HashSet::from([self.id]);
+                known.extend(incoming.lock().await.connected.values().map(|info| info.id));
+                known.extend(outgoing.lock().await.connected.values().map(|info| info.id));
+                let addrs = nodes
+                    .into_iter()
+                    .filter(|node| !known.contains(&node.id))
+                    .take(k)
+                    .map(|node| node.addr)
+                    .collect();
+                let max_idle = self.max_idle();
+                let mut idle = idle.lock().await;
+                add_contacts(
+                    &mut idle,
+                    &own,
+                    &known,
+                    max_idle,
+                    addrs,
+                    Vec::new(),
+                    Vec::new(),
+                );
+            }
+            Event::Info { id, info } => match self.remote_id(id).await {
+                Some(peer_id) => {
+                    let _ = tx_pub.send(NetworkEvent::Info {
+                        peer_id,
+                        info: *info,
+                    });
+                }
+                None => log::warn!(
+                    "Controller | Peer {} received node information | Not connected",
+                    id.to_string().get(0..8).unwrap()
+                ),
+            },
+            Event::ProtocolViolation { id, detail } => {
+                log::warn!(
+                    "Controller | Peer {} received an invalid frame | {detail}",
+                    id.to_string().get(0..8).unwrap()
+                );
+                Metrics::incr(&metrics.protocol_violations);
+                if let Some((peer_id, addr)) = self.remote(id).await {
+                    let _ = tx_pub.send(NetworkEvent::ProtocolViolation { peer_id, detail });
+                    let mut idle_guard = idle.lock().await;
+                    idle_guard
+                        .history
+                        .entry(addr)
+                        .or_default()
+                        .record_violation();
+                    drop(idle_guard);
+                    self.judge(peer_id, addr).await;
+                }
+            }
+            Event::TimedOut { id } => {
+                // The peer closes the connection right after.
+                if let Some((peer_id, addr)) = self.remote(id).await {
+                    let mut idle_guard = idle.lock().await;
+                    idle_guard.history.entry(addr).or_default().record_timeout();
+                    drop(idle_guard);
+                    self.judge(peer_id, addr).await;
+                }
+            }
+            Event::UnknownMessage { id, tag, frame } => match self.remote_id(id).await {
+                Some(peer_id) => {
+                    if let Err(frame) = self.custom.dispatch(peer_id, &tag, frame) {
+                        let _ = tx_pub.send(NetworkEvent::UnknownMessage {
+                            peer_id,
+                            tag,
+                            frame,
+                        });
                     }
-                    // Need to remove ourselves from the list of addresses, and
-                    // then store them in idle.
+                }
+                None => log::warn!(
+                    "Controller | Peer {} received an unknown message | Not connected",
+                    id.to_string().get(0..8).unwrap()
+                ),
+            },
+            Event::Relay { id, message } => {
+                if let Some(relay) = self.relay.as_ref().filter(|relay| relay.is_link(id)) {
+                    relay.handle(message);
+                } else if let Some(circuits) = &self.circuits {
+                    self.route(id, message, circuits).await;
+                } else if !matches!(message, RelayMessage::Close { .. }) {
+                    log::warn!(
+                        "Controller | Peer {} sent a relay message | Not a relay",
+                        id.to_string().get(0..8).unwrap()
+                    );
+                    let message = RelayMessage::Close {
+                        circuit: message.circuit(),
+                    };
+                    let _ = self.command_peer(id, Command::SendRelay { message }).await;
                 }
             }
+            Event::AddrObserved { id, peer_id, addr } => {
+                log::debug!(
+                    "Controller | Peer {} saw our connection come from {addr}",
+                    id.to_string().get(0..8).unwrap()
+                );
+                if let Some(advertised) = self.external.report(peer_id, addr) {
+                    log::info!("Controller | Advertising the external address {advertised}");
+                }
+            }
+            Event::ConnectionError { id, addr, source } => {
+                // The peer could not establish a Tcp connection.
+                // So remove it from the list of attempting, and put it back in the list of
+                // idle. The 'monitor_idle' thread will pick it up and automatically try to
+                // reconnect.
+                log::warn!(
+                    "Controller | Peer {} cannot connect to {} | {source}",
+                    id.to_string().get(0..8).unwrap(),
+                    addr
+                );
+                Metrics::incr(&metrics.conn_failures);
+                let _ = tx_pub.send(NetworkEvent::AttemptFailed {
+                    addr,
+                    reason: source.to_string(),
+                });
+                let addr_info = outgoing
+                    .lock()
+                    .await
+                    .attempting
+                    .remove(&id)
+                    .expect("addr_info for id");
+                // There is no peer when replaying a journal.
+                if let Some(peer) = peers.lock().await.remove(&id) {
+                    peer.handle.abort();
+                }
+                let attempt = *addr_info.attempt.lock().await;
+                let mut idle_guard = idle.lock().await;
+                idle_guard
+                    .history
+                    .entry(addr_info.addr)
+                    .or_default()
+                    .record_failure();
+                let max_conn_attempt = self.config.peers.max_conn_attempt;
+                if max_conn_attempt > 0 && attempt >= max_conn_attempt {
+                    log::warn!(
+                        "Controller | Retiring {} | {attempt} failed attempts",
+                        addr_info.addr
+                    );
+                    let now = std::time::Instant::now();
+                    idle_guard
+                        .dead
+                        .insert(addr_info.addr, (addr_info.tags, now));
+                    drop(idle_guard);
+                    self.dht.lock().await.remove_addr(&addr_info.addr);
+                    let _ = tx_pub.send(NetworkEvent::AddressRetired {
+                        addr: addr_info.addr,
+                        attempts: attempt,
+                    });
+                    return Ok(());
+                }
+                let delay = self.retry_delay(attempt);
+                log::debug!(
+                    "Controller | Next attempt to {} in {:.1}s",
+                    addr_info.addr,
+                    delay.as_secs_f64()
+                );
+                idle_guard
+                    .retry_at
+                    .insert(addr_info.addr, std::time::Instant::now() + delay);
+                idle_guard.addrs.insert(addr_info);
+            }
+            Event::ContactRequested { id } => {
+                log::trace!(
+                    "Controller | Peer {} requested contacts.",
+                    id.to_string().get(0..8).unwrap()
+                );
+                // Build the contacts from the incoming and outgoing sets.
+                let incoming_guard = incoming.lock().await;
+
+                let incoming = futures::stream::iter(&incoming_guard.connected)
+                    .fold(Vec::new(), |mut acc, (_, info)| async move {
+                        acc.push(info.clone());
+                        acc
+                    })
+                    .await;
+
+                let outgoing_guard = outgoing.lock().await;
+
+                let outgoing = futures::stream::iter(&outgoing_guard.connected)
+                    .fold(Vec::new(), |mut acc, (_, info)| async move {
+                        acc.push(info.clone());
+                        acc
+                    })
+                    .await;
+                let mut addrs = outgoing
+                    .iter()
+                    .map(|info| info.addr)
+                    .collect::<Vec<SocketAddr>>();
+                let mut in_addrs = incoming
+                    .iter()
+                    .map(|info| info.addr)
+                    .collect::<Vec<SocketAddr>>();
+
+                addrs.append(&mut in_addrs);
+                // We don't know the tags of incoming peers.
+                let mut tags = outgoing
+                    .iter()
+                    .map(|info| info.tags.clone())
+                    .collect::<Vec<Tags>>();
+                tags.resize(addrs.len(), Tags::new());
+                // Connected nodes are heard from with each heartbeat.
+                let now = Utc::now().timestamp_micros();
+                let contacts = outgoing
+                    .iter()
+                    .map(|info| (info.id, &info.label, Provenance::Outgoing))
+                    .chain(
+                        incoming
+                            .iter()
+                            .map(|info| (info.id, &info.label, Provenance::Incoming)),
+                    )
+                    .map(|(id, label, provenance)| Contact {
+                        id,
+                        label: label.clone(),
+                        last_seen: now,
+                        provenance,
+                    })
+                    .collect();
+                if let Err(err) = self
+                    .command_peer(
+                        id,
+                        Command::SendContactResponse {
+                            addrs,
+                            tags,
+                            contacts,
+                        },
+                    )
+                    .await
+                {
+                    log::error!(
+                        "Controller | Could not send 'contact response' to peer {} | {err}",
+                        id.to_string().get(0..8).unwrap()
+                    );
+                }
+            }
+            Event::ContactUpdated {
+                id,
+                mut addrs,
+                tags,
+                contacts,
+            } => {
+                log::trace!(
+                    "Controller | Peer {} provided a new list of contacts: {addrs:?}",
+                    id.to_string().get(0..8).unwrap()
+                );
+                if !self.contacts.lock().await.admit(id, Instant::now()) {
+                    log::debug!(
+                        "Controller | Dropping the contacts of peer {} | Too soon",
+                        id.to_string().get(0..8).unwrap()
+                    );
+                    return Ok(());
+                }
+                let limits = self.config.contacts.clone().unwrap_or_default();
+                addrs.truncate(limits.max_per_list);
+                // The nodes we are connected to are not dialed again, at
+                // the same address or at another one.
+                let mut skip = vec![self.addr, self.external.advertised()];
+                let mut known = HashSet::from([self.id]);
+                for info in incoming.lock().await.connected.values() {
+                    skip.push(info.addr);
+                    known.insert(info.id);
+                }
+                for info in outgoing.lock().await.connected.values() {
+                    skip.push(info.addr);
+                    known.insert(info.id);
+                }
+                let mut idle = idle.lock().await;
+                add_contacts(
+                    &mut idle,
+                    &skip,
+                    &known,
+                    self.max_idle(),
+                    addrs,
+                    tags,
+                    contacts,
+                );
+            }
         }
         Ok(())
     }
+
+    /// Forward a relay message received by a peer to the other end of its circuit.
+    async fn route(&self, id: Uuid, message: RelayMessage, circuits: &Mutex<Circuits>) {
+        let circuit = message.circuit();
+        let (to, message) = match message {
+            RelayMessage::Open { addr, .. } => {
+                // The remote is told the address its peer advertised.
+                let source = self.remote_addr(id).await;
+                let target = self.peer_at(addr).await;
+                match (source, target) {
+                    (Some(source), Some(target))
+                        if circuits.lock().await.open(circuit, id, target) =>
+                    {
+                        log::debug!("Controller | Relaying circuit from {source} to {addr}");
+                        let message = RelayMessage::Open {
+                            circuit,
+                            addr: source,
+                        };
+                        (target, message)
+                    }
+                    _ => (id, RelayMessage::Close { circuit }),
+                }
+            }
+            RelayMessage::Data { .. } => match circuits.lock().await.route(circuit, id) {
+                Some(to) => (to, message),
+                None => (id, RelayMessage::Close { circuit }),
+            },
+            RelayMessage::Close { .. } => match circuits.lock().await.close(circuit, id) {
+                Some(to) => (to, message),
+                None => return,
+            },
+        };
+        if let Err(err) = self.command_peer(to, Command::SendRelay { message }).await {
+            log::warn!(
+                "Controller | Could not relay to peer {} | {err}",
+                to.to_string().get(0..8).unwrap()
+            );
+        }
+    }
+
+    /// Close the circuits of a peer which is gone.
+    async fn close_circuits(&self, id: Uuid) {
+        if let Some(relay) = &self.relay {
+            relay.detach(id);
+        }
+        let closed = match &self.circuits {
+            Some(circuits) => circuits.lock().await.remove_peer(id),
+            None => return,
+        };
+        for (circuit, to) in closed {
+            let message = RelayMessage::Close { circuit };
+            let _ = self.command_peer(to, Command::SendRelay { message }).await;
+        }
+    }
+
+    /// Address advertised by a connected peer.
+    async fn remote_addr(&self, id: Uuid) -> Option<SocketAddr> {
+        if let Some(info) = self.incoming.lock().await.connected.get(&id) {
+            return Some(info.addr);
+        }
+        self.outgoing
+            .lock()
+            .await
+            .connected
+            .get(&id)
+            .map(|info| info.addr)
+    }
+
+    // Count the remote nodes connected, which peers send with their
+    // heartbeats.
+    async fn count_connected(&self) {
+        let count =
+            self.outgoing.lock().await.connected.len() + self.incoming.lock().await.connected.len();
+        self.metrics
+            .connected
+            .store(count as u64, Ordering::Relaxed);
+    }
+
+    /// Add the node we are now connected to to the DHT routing table,
+    /// unless it is banned.
+    async fn learn(&self, peer_id: Uuid, addr: SocketAddr) {
+        let now = std::time::Instant::now();
+        if self.bans.is_banned(&ban::Target::Peer(peer_id), now)
+            || self.bans.is_addr_banned(&addr, now)
+        {
+            return;
+        }
+        self.dht.lock().await.insert(Node { id: peer_id, addr });
+    }
+
+    /// Controller id of the remote node a peer is connected to.
+    async fn remote_id(&self, id: Uuid) -> Option<Uuid> {
+        if let Some(info) = self.incoming.lock().await.connected.get(&id) {
+            return Some(info.id);
+        }
+        self.outgoing
+            .lock()
+            .await
+            .connected
+            .get(&id)
+            .map(|info| info.id)
+    }
+
+    /// Id and address of the remote node the peer is connected to.
+    async fn remote(&self, id: Uuid) -> Option<(Uuid, SocketAddr)> {
+        if let Some(info) = self.incoming.lock().await.connected.get(&id) {
+            return Some((info.id, info.addr));
+        }
+        self.outgoing
+            .lock()
+            .await
+            .connected
+            .get(&id)
+            .map(|info| (info.id, info.addr))
+    }
+
+    /// Id of the connected peer with the given address.
+    async fn peer_at(&self, addr: SocketAddr) -> Option<Uuid> {
+        let incoming = self.incoming.lock().await;
+        if let Some((id, _)) = incoming
+            .connected
+            .iter()
+            .find(|(_, info)| info.addr == addr)
+        {
+            return Some(*id);
+        }
+        drop(incoming);
+        let outgoing = self.outgoing.lock().await;
+        outgoing
+            .connected
+            .iter()
+            .find(|(_, info)| info.addr == addr)
+            .map(|(id, _)| *id)
+    }
+
+    /// Send a command to a peer, in response to an event.
+    /// The decision is journaled, and in a dry-run the command is not sent.
+    async fn command_peer(&self, id: Uuid, cmd: Command) -> Result<(), Error> {
+        if let Some(journal) = &self.journal {
+            journal.record(Record::Command {
+                peer: id,
+                command: cmd.to_string(),
+            });
+            if journal.is_dry_run() {
+                return Ok(());
+            }
+        }
+        let peers = self.peers.lock().await;
+        let peer_data = peers.get(&id).ok_or(Error::UnknownId {
+            id,
+            detail: "Controller | Could not find id in peer table.".to_owned(),
+        })?;
+        send_command_single_peer(cmd, &peer_data.tx, &id).await
+    }
+}
+
+/// Id of the peer connected to the given remote node, and the direction of
+/// its connection.
+async fn local_peer(
+    outgoing: &Mutex<OutgoingState>,
+    incoming: &Mutex<IncomingState>,
+    peer_id: Uuid,
+) -> Option<(Uuid, Direction)> {
+    let outgoing = outgoing.lock().await;
+    let mut ids = outgoing.connected.iter().map(|(id, info)| (*id, info.id));
+    match ids.find(|(_, remote)| *remote == peer_id) {
+        Some((id, _)) => Some((id, Direction::Outgoing)),
+        None => incoming
+            .lock()
+            .await
+            .connected
+            .iter()
+            .find(|(_, info)| info.id == peer_id)
+            .map(|(id, _)| (*id, Direction::Incoming)),
+    }
+}
+
+/// Drop the idle addresses with the lowest score, beyond the maximum (0 for
+/// no limit). Returns the dropped addresses.
+fn trim_idle(idle: &mut IdleState, max: usize, counts: &Counts, now: i64) -> Vec<SocketAddr> {
+    if max == 0 || idle.addrs.len() <= max {
+        return Vec::new();
+    }
+    let mut scored = idle
+        .addrs
+        .iter()
+        .map(|info| {
+            let history = idle.history.get(&info.addr);
+            (score::score(history, &info.tags, counts, now), info.addr)
+        })
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let excess = idle.addrs.len() - max;
+    let dropped = scored
+        .into_iter()
+        .take(excess)
+        .map(|(_, addr)| addr)
+        .collect::<Vec<_>>();
+    idle.addrs.retain(|info| !dropped.contains(&info.addr));
+    for addr in &dropped {
+        idle.retry_at.remove(addr);
+    }
+    dropped
+}
+
+/// Put the addresses retired for longer than the delay back in the list of
+/// idle, for a new round of attempts.
+fn resurrect(idle: &mut IdleState, delay: Duration, now: std::time::Instant) {
+    let dead = std::mem::take(&mut idle.dead);
+    for (addr, (tags, since)) in dead {
+        if now.saturating_duration_since(since) < delay {
+            idle.dead.insert(addr, (tags, since));
+            continue;
+        }
+        log::info!("Controller | Resurrecting {addr}");
+        idle.addrs.insert(AddrInfo {
+            addr,
+            attempt: Arc::new(Mutex::new(0)),
+            tags,
+        });
+    }
+}
+
+/// Ask the incoming peer with the lowest score to close its connection, if
+/// its score is below the threshold, to make room for a new connection.
+/// Returns true if a peer was evicted.
+async fn evict_worst(
+    idle: &Mutex<IdleState>,
+    incoming: &Mutex<IncomingState>,
+    peers: &Mutex<PeerRepo>,
+    threshold: f64,
+) -> bool {
+    let mut idle = idle.lock().await;
+    let worst = incoming
+        .lock()
+        .await
+        .connected
+        .iter()
+        .map(|(id, info)| {
+            let score = score::peer_score(idle.history.get(&info.addr));
+            (*id, info.addr, score)
+        })
+        .min_by(|a, b| a.2.total_cmp(&b.2));
+    let Some((id, addr, score)) = worst.filter(|(_, _, score)| *score < threshold) else {
+        return false;
+    };
+    log::warn!(
+        "Controller | Evicting peer {} at {addr} | Score {score:.2}",
+        id.to_string().get(0..8).unwrap()
+    );
+    idle.history.entry(addr).or_default().dropped = Some(format!("evicted | score {score:.2}"));
+    drop(idle);
+    let peers = peers.lock().await;
+    match peers.get(&id) {
+        Some(peer) => send_command_single_peer(Command::Terminate, &peer.tx, &id)
+            .await
+            .is_ok(),
+        None => false,
+    }
+}
+
+/// Add the address to the list of idle, even if its node refused a
+/// connection before.
+async fn dial(idle: &Mutex<IdleState>, addr: SocketAddr) {
+    let mut idle = idle.lock().await;
+    idle.rejected.remove(&addr);
+    idle.retry_at.remove(&addr);
+    idle.dead.remove(&addr);
+    idle.addrs.insert(AddrInfo {
+        addr,
+        attempt: Arc::new(Mutex::new(0)),
+        tags: Tags::new(),
+    });
+}
+
+/// Ask the peer connected to the given remote node to close its connection,
+/// after telling the remote.
+async fn close(
+    peers: &Mutex<PeerRepo>,
+    outgoing: &Mutex<OutgoingState>,
+    incoming: &Mutex<IncomingState>,
+    peer_id: Uuid,
+) -> Result<(), Error> {
+    let (id, direction) = local_peer(outgoing, incoming, peer_id)
+        .await
+        .ok_or_else(|| not_connected(peer_id))?;
+    let cmd = match direction {
+        Direction::Outgoing => Command::Disconnect,
+        Direction::Incoming => Command::Terminate,
+    };
+    let peers = peers.lock().await;
+    let peer_data = peers.get(&id).ok_or_else(|| not_connected(peer_id))?;
+    send_command_single_peer(cmd, &peer_data.tx, &id).await
+}
+
+fn connect_failed(addr: SocketAddr, reason: &str) -> Error {
+    Error::ConnectFailed {
+        addr,
+        detail: format!("Controller | {reason}"),
+    }
+}
+
+fn not_connected(peer_id: Uuid) -> Error {
+    Error::UnknownId {
+        id: peer_id,
+        detail: "Controller | Not connected to this remote node.".to_owned(),
+    }
+}
+
+/// Ids of the peers whose connection is alive, in either direction.
+async fn alive_peers(
+    outgoing: &Mutex<OutgoingState>,
+    incoming: &Mutex<IncomingState>,
+) -> Vec<Uuid> {
+    let mut ids = outgoing
+        .lock()
+        .await
+        .connected
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    ids.extend(incoming.lock().await.connected.keys().copied());
+    ids
+}
+
+/// Add the contacts received from a remote node to the idle addresses,
+/// leaving out the addresses to skip (ours, and those we are connected to),
+/// those of the nodes which rejected us, and the retired ones.
+fn add_contacts(
+    idle: &mut IdleState,
+    skip: &[SocketAddr],
+    known: &HashSet<Uuid>,
+    max_idle: usize,
+    addrs: Vec<SocketAddr>,
+    mut tags: Vec<Tags>,
+    contacts: Vec<Contact>,
+) {
+    // The remote may not know, or not send, the tags and nodes of its contacts.
+    tags.resize(addrs.len(), Tags::new());
+    let mut contacts = contacts.into_iter().map(Some).collect::<Vec<_>>();
+    contacts.resize(addrs.len(), None);
+    // A node may be listed at several addresses: only its most recently seen
+    // address is kept.
+    let mut latest = HashMap::new();
+    for contact in contacts.iter().flatten() {
+        let seen = latest.entry(contact.id).or_insert(contact.last_seen);
+        *seen = (*seen).max(contact.last_seen);
+    }
+    let mut added = HashSet::new();
+    for ((addr, tags), contact) in addrs.into_iter().zip(tags).zip(contacts) {
+        if skip.contains(&addr)
+            || idle.rejected.contains(&addr)
+            || idle.dead.contains_key(&addr)
+            || !contacts::is_dialable(&addr)
+        {
+            continue;
+        }
+        if idle.addrs.len() >= max_idle {
+            break;
+        }
+        if let Some(contact) = contact {
+            if known.contains(&contact.id)
+                || latest.get(&contact.id) != Some(&contact.last_seen)
+                || !added.insert(contact.id)
+            {
+                continue;
+            }
+            idle.history
+                .entry(addr)
+                .or_default()
+                .record_seen(contact.last_seen);
+        }
+        idle.addrs.insert(AddrInfo {
+            addr,
+            attempt: Arc::new(Mutex::new(0)),
+            tags,
+        });
+    }
 }
 
 /// Send a command
@@ -836,7 +3024,7 @@ async fn send_connect(
 
 /// Send a listen command to a single peer identified by its id.
 async fn send_listen(
-    stream: TcpStream,
+    conn: Connection,
     tx: &Sender<Command>,
     _incoming: Arc<Mutex<IncomingState>>,
     id: &Uuid,
@@ -855,7 +3043,12 @@ async fn send_listen(
     //         detail: "connect".to_owned(),
     //     });
     // } else if let Err(err) = tx.send(Command::Listen { stream }).await {
-    if let Err(err) = tx.send(Command::Listen { stream }).await {
+    if let Err(err) = tx
+        .send(Command::Listen {
+            conn: Box::new(conn),
+        })
+        .await
+    {
         log::error!(
             "Controller | Could not send listen command to peer {} | {}",
             id.to_string().get(0..8).unwrap(),
@@ -882,16 +3075,27 @@ async fn send_listen(
 /// addr is the address we're listening on
 /// tx is the channel through which we'll be sending network event back to
 /// the controller's main loop.
+#[allow(clippy::too_many_arguments)]
 async fn listen(
-    controller: Uuid,
+    identity: identity::Identity,
     label: String,
     addr: SocketAddr,
     tx: Sender<Event>,
     peers: Arc<Mutex<PeerRepo>>,
     incoming: Arc<Mutex<IncomingState>>,
     config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    transport: Arc<dyn Transport>,
+    auth: Option<auth::Secret>,
+    replays: Arc<identity::Replays>,
+    bans: Arc<ban::Bans>,
+    idle: Arc<Mutex<IdleState>>,
+    registry: Arc<Registry>,
 ) -> Result<(), Error> {
-    let listener = match TcpListener::bind(&addr).await {
+    let mut listener = match transport
+        .listen(addr, config.listen.interface.as_deref())
+        .await
+    {
         Ok(listener) => listener,
         Err(err) => {
             let msg = Event::BindError { source: err, addr };
@@ -907,20 +3111,109 @@ async fn listen(
         }
     };
 
+    #[cfg(unix)]
+    if let Some(unix) = &config.listen.unix {
+        let mut path = PathBuf::from(get_working_dir());
+        path.push(unix);
+        match listen_unix(&path) {
+            Ok(unix) => {
+                log::info!("Controller | listening on {}.", path.display());
+                let unix = transport::UnixAcceptor::new(unix);
+                listener = Box::new(Listeners(vec![listener, Box::new(unix)]));
+            }
+            Err(err) => {
+                log::error!(
+                    "Controller | Cannot listen on Unix socket {} | {}",
+                    path.display(),
+                    err
+                );
+                let msg = Event::BindError { source: err, addr };
+                tx.send(msg).await.map_err(|err| Error::EventError {
+                    source: err,
+                    detail: "Controller | Could not send event to main loop | Receiver dropped"
+                        .to_owned(),
+                })?;
+                return Ok(());
+            }
+        }
+    }
+
     log::info!("Controller | listening on {}.", addr);
 
+    // The allow list was validated when the controller was created.
+    let allow =
+        AllowList::new(config.listen.allow.as_deref().unwrap_or_default()).unwrap_or_default();
+
+    // Encrypted transports drop the connections we would reject before their handshake, so
+    // that unwanted remotes cannot make us run costly handshakes.
+    let admit: Admit = {
+        let allow = allow.clone();
+        let bans = bans.clone();
+        Arc::new(move |remote: &SocketAddr| {
+            allow.allows(&remote.ip()) && !bans.is_addr_banned(remote, std::time::Instant::now())
+        })
+    };
+    listener.guard(Guard::new(
+        admit,
+        config.incoming.max_simultaneous_conn_attempts.max(1) as usize,
+    ));
+
+    let max_message_sizes = Arc::new(
+        config
+            .messages
+            .as_ref()
+            .map(Messages::max_sizes)
+            .unwrap_or_default(),
+    );
+    let impairments = build_impairments(&config);
+
+    // Each incoming connection holds a permit until its handshake completes,
+    // so that a connection storm cannot spawn an unbounded number of peers.
+    let handshakes = Arc::new(Semaphore::new(
+        config.incoming.max_simultaneous_conn_attempts.max(1) as usize,
+    ));
+    let evict_below = config.scoring.clone().unwrap_or_default().evict_below;
+
     loop {
         let label = label.clone();
+        let permit = handshakes
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("handshake semaphore is never closed");
         match listener.accept().await {
-            Ok((stream, _)) => {
+            Ok(conn) => {
+                let remote = conn.peer_addr;
+                // A connection we don't accept still gets a peer, which answers the
+                // connection request with a rejection, so that the remote stops retrying.
+                let max_conn_count = config.incoming.max_conn_count.max(0) as usize;
+                let full = incoming.lock().await.connected.len() >= max_conn_count;
+                let rejection = if !allow.allows(&remote.ip()) {
+                    log::warn!(
+                        "Controller | Rejecting connection from {} | Not in allow list",
+                        remote
+                    );
+                    Some(RejectReason::Banned)
+                } else if bans.is_addr_banned(&remote, std::time::Instant::now()) {
+                    log::warn!("Controller | Rejecting connection from {} | Banned", remote);
+                    Some(RejectReason::Banned)
+                } else if full && !evict_worst(&idle, &incoming, &peers, evict_below).await {
+                    log::warn!(
+                        "Controller | Rejecting connection from {} | Too many connections",
+                        remote
+                    );
+                    Some(RejectReason::TooManyConnections)
+                } else {
+                    None
+                };
                 // We have received a connection, so:
                 // 1. Create a Peer
                 // 2. Spawn a thread for its main loop
                 // 3. Send the peer a command to listen.
                 let tx_event = tx.clone();
                 let (tx_com, rx_com) = mpsc::channel(64); // FIXME Automagick
-                let peer = Peer::new(
-                    controller,
+                let mut peer = Peer::new(
+                    identity.clone(),
                     label,
                     addr,
                     tx_event,
@@ -928,7 +3221,22 @@ async fn listen(
                     rx_com,
                     config.peers.heartbeat_timeout,
                     config.peers.heartbeat_period,
+                    None,
                 );
+                peer.handshake_permit = Some(permit);
+                peer.rejection = rejection;
+                peer.metrics = metrics.clone();
+                peer.max_message_sizes = max_message_sizes.clone();
+                peer.frame_limits = config.frames.unwrap_or_default();
+                peer.wire = config.wire.unwrap_or_default();
+                peer.registry = registry.clone();
+                peer.impairment = impairments.lookup(&remote.ip());
+                peer.transport = transport.clone();
+                peer.auth = auth.clone();
+                peer.replays = Some(replays.clone());
+                peer.pow_difficulty = config.incoming.pow_difficulty;
+                peer.session_keys = config.listen.tls.is_none();
+                peer.network = config.network_id.clone().unwrap_or_default();
                 let id = peer.id;
                 let tx = tx_com.clone();
                 let config = config.clone();
@@ -941,7 +3249,7 @@ async fn listen(
                         handle,
                     },
                 );
-                if let Err(err) = send_listen(stream, &tx, incoming, &id, config).await {
+                if let Err(err) = send_listen(conn, &tx, incoming, &id, config).await {
                     log::error!("Could not send listen command {err}");
                 }
             }
@@ -962,6 +3270,41 @@ pub enum Error {
         /// details
         detail: String,
     },
+    /// Invalid entry of the target file
+    InvalidTarget {
+        /// details
+        detail: String,
+    },
+    /// Invalid allow list entry
+    InvalidAllowList {
+        /// source
+        source: super::allowlist::Error,
+    },
+    /// Invalid impairment rule
+    InvalidImpairment {
+        /// source
+        source: impairment::Error,
+    },
+    /// Invalid connection policy
+    InvalidPolicy {
+        /// source
+        source: policy::Error,
+    },
+    /// Invalid tags in the target file
+    InvalidTags {
+        /// source
+        source: policy::Error,
+    },
+    /// The network interface is unknown, or cannot be used
+    InvalidInterface {
+        /// details
+        detail: String,
+    },
+    /// The label cannot be sent to remote peers
+    InvalidLabel {
+        /// details
+        detail: String,
+    },
     /// Something is missing
     Bind {
         /// Error detail
@@ -979,6 +3322,13 @@ pub enum Error {
         /// Error detail
         detail: String,
     },
+    /// A connection asked by the application could not be established.
+    ConnectFailed {
+        /// Dialed address.
+        addr: SocketAddr,
+        /// Error detail
+        detail: String,
+    },
     // /// Connect Errror
     // UnauthorizedConnectionAttempt {
     //     /// Error detail
@@ -1019,6 +3369,51 @@ pub enum Error {
         /// source error
         source: serde_json::Error,
     },
+    /// The dedicated runtime failed
+    Runtime {
+        /// Error detail
+        detail: String,
+    },
+    /// The journal could not be opened
+    Journal {
+        /// source error
+        source: journal::Error,
+    },
+    /// The TLS configuration is invalid
+    Tls {
+        /// source error
+        source: tls::Error,
+    },
+    /// The Noise configuration is invalid
+    Noise {
+        /// source error
+        source: noise::Error,
+    },
+    /// The identity configuration is invalid
+    Identity {
+        /// source error
+        source: identity::Error,
+    },
+    /// The auth configuration is invalid
+    Auth {
+        /// source error
+        source: auth::Error,
+    },
+    /// The relay configuration is invalid
+    Relay {
+        /// details
+        detail: String,
+    },
+    /// The SOCKS5 proxy configuration is invalid
+    Proxy {
+        /// details
+        detail: String,
+    },
+    /// A message of the application could not be registered or sent
+    InvalidMessage {
+        /// source error
+        source: message::Error,
+    },
 }
 
 impl fmt::Display for Error {
@@ -1031,6 +3426,27 @@ impl fmt::Display for Error {
                     detail, source
                 )
             }
+            Error::InvalidTarget { detail } => {
+                write!(f, "Invalid Target: {}", detail)
+            }
+            Error::InvalidAllowList { source } => {
+                write!(f, "Invalid Allow List: {}", source)
+            }
+            Error::InvalidImpairment { source } => {
+                write!(f, "Invalid Impairment: {}", source)
+            }
+            Error::InvalidPolicy { source } => {
+                write!(f, "Invalid Connection Policy: {}", source)
+            }
+            Error::InvalidTags { source } => {
+                write!(f, "Invalid Tags: {}", source)
+            }
+            Error::InvalidInterface { detail } => {
+                write!(f, "Invalid Network Interface: {}", detail)
+            }
+            Error::InvalidLabel { detail } => {
+                write!(f, "Invalid Label: {}", detail)
+            }
             Error::EventError { source: _, detail } => {
                 write!(f, "Could not send event to controller {}", detail)
             }
@@ -1046,13 +3462,218 @@ impl fmt::Display for Error {
             Error::OutgoingConnAttemptLimit { detail } => {
                 write!(f, "Too many connection attempts => {}", detail)
             }
+            Error::ConnectFailed { addr, detail } => {
+                write!(f, "Could not connect to {} => {}", addr, detail)
+            }
             Error::IO { source, detail } => {
                 write!(f, "IO Error => {} [{}]", detail, source)
             }
             Error::InvalidPeerFile { source: _ } => {
                 write!(f, "Invalid peer file content (Json Array of string)")
             }
+            Error::Runtime { detail } => {
+                write!(f, "Runtime Error: {}", detail)
+            }
+            Error::Journal { source } => {
+                write!(f, "Journal Error: {}", source)
+            }
+            Error::Tls { source } => {
+                write!(f, "TLS Error: {}", source)
+            }
+            Error::Noise { source } => {
+                write!(f, "Noise Error: {}", source)
+            }
+            Error::Identity { source } => {
+                write!(f, "Identity Error: {}", source)
+            }
+            Error::Auth { source } => {
+                write!(f, "Auth Error: {}", source)
+            }
+            Error::Relay { detail } => {
+                write!(f, "Invalid Relay Configuration: {}", detail)
+            }
+            Error::Proxy { detail } => {
+                write!(f, "Invalid Proxy Configuration: {}", detail)
+            }
+            Error::InvalidMessage { source } => {
+                write!(f, "Invalid Message: {}", source)
+            }
+        }
+    }
+}
+
+// The impairment rules were validated when the controller was created.
+fn build_impairments(config: &Config) -> Impairments {
+    config
+        .impairment
+        .as_ref()
+        .and_then(|impairment| impairment.impairments().ok())
+        .unwrap_or_default()
+}
+
+// The connection policies were validated when the controller was created.
+fn build_policies(config: &Config) -> Policies {
+    Policies::new(config.policies.as_deref().unwrap_or_default()).unwrap_or_default()
+}
+
+// A socket file left by a previous run is removed, but not any other file.
+#[cfg(unix)]
+pub(crate) fn listen_unix(path: &std::path::Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+// The relay transport, if any, is also returned so the controller can attach it
+// to the connection with the relay node.
+type Transports = (Arc<dyn Transport>, Option<Arc<relay::Relay>>);
+
+// Encryption is opt-in: without any tls or noise section, connections are plain TCP.
+// WebSockets run on top of TCP or TLS.
+fn build_transport(config: &Config) -> Result<Transports, Error> {
+    let path = |file: &str| {
+        let mut path = PathBuf::from(get_working_dir());
+        path.push(file);
+        path
+    };
+    let tls = config.listen.tls.is_some() || config.outgoing.tls.is_some();
+    let mut tcp = Tcp::default().with_options(config.peers.tcp.unwrap_or_default());
+    if let Some(bind_addr) = &config.outgoing.bind_addr {
+        if config.listen.protocol == Protocol::Quic {
+            return Err(Error::InvalidInterface {
+                detail: "QUIC connections cannot be dialed from a bind address".to_owned(),
+            });
+        }
+        tcp = tcp.with_bind_addr(socket_addr(bind_addr, 0)?);
+    }
+    // Outgoing connections go through the proxy, under any encryption.
+    let base: Arc<dyn Transport> = match &config.outgoing.proxy {
+        Some(proxy) => {
+            if config.listen.protocol == Protocol::Quic {
+                return Err(Error::Proxy {
+                    detail: "QUIC cannot be dialed through a SOCKS5 proxy".to_owned(),
+                });
+            }
+            let mut transport = Socks5::new(socket_addr(&proxy.addr, proxy.port)?).with_inner(tcp);
+            match (&proxy.username, &proxy.password) {
+                (Some(username), Some(password)) => {
+                    transport = transport.with_credentials(username, password);
+                }
+                (None, None) => {}
+                _ => {
+                    return Err(Error::Proxy {
+                        detail: "username and password must be set together".to_owned(),
+                    })
+                }
+            }
+            Arc::new(transport)
+        }
+        None => Arc::new(tcp),
+    };
+    // Circuits are opened under any encryption, so that relay nodes only see encrypted bytes.
+    let via = config.relay.as_ref().and_then(|relay| relay.via.as_deref());
+    let (base, relay): (Arc<dyn Transport>, _) = match via {
+        Some(via) => {
+            if config.listen.protocol == Protocol::Quic {
+                return Err(Error::Relay {
+                    detail: "QUIC connections cannot be relayed".to_owned(),
+                });
+            }
+            let via = SocketAddr::from_str(via).map_err(|err| Error::InvalidAddr {
+                source: err,
+                detail: format!("Could not use relay address {}", via),
+            })?;
+            let relay = Arc::new(relay::Relay::new(base, via));
+            (relay.clone(), Some(relay))
+        }
+        None => (base, None),
+    };
+    if config.listen.protocol == Protocol::Quic {
+        let (listen, outgoing) = match (&config.listen.tls, &config.outgoing.tls, &config.noise) {
+            (Some(listen), Some(outgoing), None) => (listen, outgoing),
+            _ => return Err(Error::Tls {
+                source: tls::Error {
+                    detail:
+                        "QUIC needs the listen.tls and outgoing.tls sections, and no noise section"
+                            .to_owned(),
+                },
+            }),
+        };
+        let transport = Quic::new()
+            .with_identity(&path(&listen.cert), &path(&listen.key))
+            .and_then(|quic| quic.with_trust_anchors(&path(&outgoing.ca), &outgoing.server_name))
+            .map_err(|err| Error::Tls { source: err })?;
+        return Ok((Arc::new(transport), None));
+    }
+    let transport: Arc<dyn Transport> = if let Some(noise) = &config.noise {
+        if tls || config.listen.protocol == Protocol::Websocket {
+            return Err(Error::Noise {
+                source: noise::Error {
+                    detail: "Noise cannot be used together with TLS or WebSocket".to_owned(),
+                },
+            });
+        }
+        let transport = match &noise.key {
+            Some(key) => noise::Noise::with_key_file(&path(key)),
+            None => noise::Noise::new(),
+        }
+        .map_err(|err| Error::Noise { source: err })?;
+        Arc::new(transport.with_inner(base))
+    } else if tls {
+        let mut transport = Tls::new().with_inner(base);
+        if let Some(tls) = &config.listen.tls {
+            transport = transport
+                .with_identity(&path(&tls.cert), &path(&tls.key))
+                .map_err(|err| Error::Tls { source: err })?;
+        }
+        if let Some(tls) = &config.outgoing.tls {
+            transport = transport
+                .with_trust_anchors(&path(&tls.ca), &tls.server_name)
+                .map_err(|err| Error::Tls { source: err })?;
+        }
+        Arc::new(transport)
+    } else {
+        base
+    };
+    if config.listen.protocol == Protocol::Websocket {
+        let secure = config.outgoing.tls.is_some();
+        return Ok((Arc::new(WebSocket::new(transport, secure)), relay));
+    }
+    Ok((transport, relay))
+}
+
+/// Builds a socket address from the configuration.
+/// IPv6 addresses can carry a scope id, either numeric or as an interface name,
+/// eg 'fe80::1%2' or 'fe80::1%eth0'.
+fn socket_addr(addr: &str, port: u16) -> Result<SocketAddr, Error> {
+    let (ip, scope) = match addr.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (addr, None),
+    };
+    let ip = IpAddr::from_str(ip).map_err(|err| Error::InvalidAddr {
+        source: err,
+        detail: format!("Could not use {} as valid IP Address", addr),
+    })?;
+    match (ip, scope) {
+        (ip, None) => Ok(SocketAddr::from((ip, port))),
+        (IpAddr::V6(ip), Some(scope)) => {
+            let scope_id = match scope.parse::<u32>() {
+                Ok(scope_id) => scope_id,
+                Err(_) => {
+                    socket::interface_index(scope).map_err(|err| Error::InvalidInterface {
+                        detail: format!("Could not find interface {} ({})", scope, err),
+                    })?
+                }
+            };
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
         }
+        (IpAddr::V4(_), Some(scope)) => Err(Error::InvalidInterface {
+            detail: format!("Scope id {} is only valid for IPv6 addresses", scope),
+        }),
     }
 }
 
@@ -1088,8 +3709,61 @@ pub struct Config {
     pub target: Target,
     /// Period, in seconds, for dumping peer file.
     pub peer_file_dump_interval: i32,
+    /// Id of the network the node belongs to (eg 'mainnet'). Nodes only connect
+    /// to nodes of the same network. Without it, the node belongs to the default
+    /// network, as do older nodes.
+    pub network_id: Option<String>,
     /// whether or not we output a d2 file
     pub d2: Option<bool>,
+    /// admin section. The admin server is only started if this section is present.
+    pub admin: Option<Admin>,
+    /// messages section
+    pub messages: Option<Messages>,
+    /// snapshot section. Snapshots can only be taken if this section is present.
+    pub snapshot: Option<Snapshot>,
+    /// address_book section. Known nodes are only kept across restarts if this
+    /// section is present.
+    pub address_book: Option<AddressBook>,
+    /// mdns section. The node is only announced, and nodes are only discovered,
+    /// on the LAN if this section is present.
+    pub mdns: Option<Mdns>,
+    /// dht section. The node answers DHT lookups in any case, but only looks
+    /// up nodes itself if this section is present.
+    pub dht: Option<Dht>,
+    /// control section. The control socket is only served if this section is
+    /// present, on unix.
+    pub control: Option<Control>,
+    /// frames section. Bounds applied to frames received from remote peers.
+    pub frames: Option<Limits>,
+    /// journal section. Events and decisions are only journaled if this section is present.
+    pub journal: Option<Journal>,
+    /// runtime section. The controller only has its own runtime if this section is present.
+    pub runtime: Option<Runtime>,
+    /// wire section. Framing used on the wire.
+    pub wire: Option<Wire>,
+    /// impairment section. Messages sent to peers are only impaired if this section is present.
+    pub impairment: Option<Impairment>,
+    /// Connection policies, based on the tags of the peers in the target file
+    /// and in the contacts.
+    pub policies: Option<Vec<policy::Policy>>,
+    /// noise section. Connections are only encrypted with Noise if this section is present.
+    pub noise: Option<Noise>,
+    /// identity section. Without it, the node has a new key pair, and a new id,
+    /// on every run.
+    pub identity: Option<Identity>,
+    /// auth section. Handshakes are only authenticated if this section is present.
+    pub auth: Option<Auth>,
+    /// gossip section. Without it, gossip is forwarded with the default settings.
+    pub gossip: Option<Gossip>,
+    /// contacts section. Without it, contacts are taken with the default limits.
+    pub contacts: Option<Contacts>,
+    /// relay section. Circuits are neither relayed nor opened unless this section is present.
+    pub relay: Option<Relay>,
+    /// discovery section. The listen address is advertised to remote peers, unless
+    /// this section is present and an external address is discovered.
+    pub discovery: Option<Discovery>,
+    /// scoring section. Without it, peers are banned and evicted with the default thresholds.
+    pub scoring: Option<Scoring>,
 }
 
 /// Configuration for the network controller. Incoming section
@@ -1099,6 +3773,10 @@ pub struct Incoming {
     pub max_conn_count: i32,
     /// maximum number of simultaneous connection attempts
     pub max_simultaneous_conn_attempts: i32,
+    /// Number of leading zero bits of the proof-of-work required from
+    /// remotes in the handshake. 0 requires no work.
+    #[serde(default)]
+    pub pow_difficulty: u64,
 }
 
 /// Configuration for the network controller. Outgoing section
@@ -1106,23 +3784,86 @@ pub struct Incoming {
 pub struct Outgoing {
     /// maximum number of simultaneous connection attempts
     pub max_simultaneous_conn_attempts: i32,
+    /// Name of the network interface outgoing connections originate from.
+    pub interface: Option<String>,
+    /// IP address outgoing connections originate from, on multi-homed hosts.
+    /// The port is picked by the system.
+    pub bind_addr: Option<String>,
+    /// tls section. Outgoing connections are only encrypted if this section is present.
+    pub tls: Option<OutgoingTls>,
+    /// proxy section. If present, outgoing connections go through this SOCKS5 proxy.
+    pub proxy: Option<Proxy>,
+    /// Highest proof-of-work difficulty worked for. Remotes asking for more
+    /// are not connected to. It cannot exceed `pow::MAX_DIFFICULTY`.
+    #[serde(default = "default_max_pow_difficulty")]
+    pub max_pow_difficulty: u64,
+}
+
+fn default_max_pow_difficulty() -> u64 {
+    24
+}
+
+/// Configuration for the network controller. outgoing.proxy section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Proxy {
+    /// Network address of the SOCKS5 proxy.
+    pub addr: String,
+    /// Port of the SOCKS5 proxy.
+    pub port: u16,
+    /// Username to authenticate with the proxy. Requires a password.
+    pub username: Option<String>,
+    /// Password to authenticate with the proxy.
+    pub password: Option<String>,
+}
+
+/// Configuration for the network controller. outgoing.tls section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingTls {
+    /// Path to a PEM file with the certificates trusted to sign the certificates
+    /// of remote nodes. A relative path is resolved against the working directory.
+    pub ca: String,
+    /// Name the certificates of remote nodes are verified against.
+    pub server_name: String,
 }
 
 /// Configuration for the network controller. peers section
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Peers {
-    /// maximum of connection attempt
+    /// Number of failed attempts after which an address is retired. 0 never
+    /// retires addresses.
     pub max_conn_attempt: i32,
-    /// Number of seconds between connection attempts
+    /// Number of seconds before the second attempt to an address. The delay
+    /// doubles with each failed attempt.
     pub conn_attempt_delay: i32,
-    /// maximum number of idle peers
+    /// Maximum number of seconds between two attempts to an address.
+    #[serde(default = "default_max_conn_attempt_delay")]
+    pub max_conn_attempt_delay: u64,
+    /// Number of seconds after which a retired address gets a new round of
+    /// attempts. Without it, retired addresses are only dialed when asked.
+    pub resurrect_delay: Option<u64>,
+    /// Maximum number of idle addresses. Beyond, those with the lowest score
+    /// are dropped. 0 for no limit.
     pub max_idle_count: i32,
-    /// maximum number of banned peers
+    /// Maximum number of bans. Beyond, the oldest ban is lifted. 0 for no limit.
     pub max_banned_count: i32,
     /// heartbeat timeout (seconds)
     pub heartbeat_timeout: i32,
     /// heartbeat period (seconds)
     pub heartbeat_period: i32,
+    /// Time (seconds) given to the peers to close their connection when the
+    /// controller shuts down.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// tcp section. Options of the TCP sockets of dialed and accepted connections.
+    pub tcp: Option<socket::Options>,
+}
+
+fn default_max_conn_attempt_delay() -> u64 {
+    300
+}
+
+fn default_shutdown_timeout() -> u64 {
+    5
 }
 
 /// Configuration for the network controller. listen section
@@ -1132,6 +3873,425 @@ pub struct Listen {
     pub addr: String,
     /// Port the network controller is listening on.
     pub port: u16,
+    /// Name of the network interface the network controller is listening on.
+    /// If not set, the listener accepts connections from any interface
+    /// matching the address.
+    pub interface: Option<String>,
+    /// Addresses or networks (eg '10.0.0.0/8') allowed to connect.
+    /// If not set, any remote peer can connect.
+    pub allow: Option<Vec<String>>,
+    /// tls section. If present, remote peers must connect with TLS.
+    pub tls: Option<ListenTls>,
+    /// Path of a Unix domain socket the controller also listens on, for co-located
+    /// processes. A relative path is resolved against the working directory.
+    pub unix: Option<String>,
+    /// Protocol used to listen and to dial ('tcp', 'quic' or 'websocket').
+    /// QUIC needs both the listen.tls and the outgoing.tls sections, and
+    /// WebSockets use TLS ('wss') if these sections are present.
+    #[serde(default)]
+    pub protocol: Protocol,
+}
+
+/// Configuration for the network controller. listen.tls section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListenTls {
+    /// Path to a PEM file with the certificate chain presented to remote nodes.
+    /// A relative path is resolved against the working directory.
+    pub cert: String,
+    /// Path to a PEM file with the private key of the certificate.
+    pub key: String,
+}
+
+/// Configuration for the network controller. admin section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Admin {
+    /// Network address the admin server is listening on.
+    pub addr: String,
+    /// Port the admin server is listening on.
+    pub port: u16,
+    /// Addresses or networks allowed to use the admin server.
+    /// If not set, any client can use it.
+    pub allow: Option<Vec<String>>,
+}
+
+/// Configuration for the network controller. messages section
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Messages {
+    /// Size ceiling (bytes), by message tag (eg 'CTCT_RESP').
+    /// Messages larger than their ceiling are dropped, whether sent or received.
+    pub max_size: HashMap<String, usize>,
+}
+
+impl Messages {
+    /// Size ceilings keyed by message tag.
+    /// The configuration lowercases keys, while tags are uppercase.
+    pub fn max_sizes(&self) -> HashMap<String, usize> {
+        self.max_size
+            .iter()
+            .map(|(tag, size)| (tag.to_uppercase(), *size))
+            .collect()
+    }
+}
+
+/// Configuration for the network controller. snapshot section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// path to the file the snapshot is written to,
+    /// on SIGUSR1 or on request to the admin server.
+    pub file: String,
+}
+
+impl Snapshot {
+    /// Path to the snapshot file. A relative path is resolved against
+    /// the working directory, like the target file.
+    pub fn path(&self) -> PathBuf {
+        let mut path = PathBuf::from(get_working_dir());
+        path.push(&self.file);
+        path
+    }
+}
+
+/// Configuration for the network controller. address_book section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressBook {
+    /// path to the file the known nodes are written to, and read from at startup.
+    pub file: String,
+    /// Number of seconds between two writes of the book. It is also written
+    /// on shutdown.
+    #[serde(default = "default_address_book_interval")]
+    pub interval: u64,
+}
+
+impl AddressBook {
+    /// Path to the address book. A relative path is resolved against
+    /// the working directory, like the target file.
+    pub fn path(&self) -> PathBuf {
+        let mut path = PathBuf::from(get_working_dir());
+        path.push(&self.file);
+        path
+    }
+}
+
+fn default_address_book_interval() -> u64 {
+    60
+}
+
+/// Configuration for the network controller. mdns section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mdns {
+    /// Number of seconds between two announcements of the node. It is also
+    /// announced when another node asks.
+    #[serde(default = "default_mdns_interval")]
+    pub interval: u64,
+}
+
+fn default_mdns_interval() -> u64 {
+    30
+}
+
+/// Configuration for the network controller. control section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Control {
+    /// path to the Unix socket taking commands, in JSON.
+    pub file: String,
+}
+
+impl Control {
+    /// Path to the control socket. A relative path is resolved against the
+    /// working directory, like the target file.
+    pub fn path(&self) -> PathBuf {
+        let mut path = PathBuf::from(get_working_dir());
+        path.push(&self.file);
+        path
+    }
+}
+
+/// Configuration for the network controller. dht section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dht {
+    /// Number of nodes in each bucket of the routing table, and in the
+    /// answers to lookups.
+    #[serde(default = "default_dht_k")]
+    pub k: usize,
+    /// Number of peers asked in each lookup.
+    #[serde(default = "default_dht_alpha")]
+    pub alpha: usize,
+    /// Number of seconds between two rounds of lookups.
+    #[serde(default = "default_dht_interval")]
+    pub interval: u64,
+}
+
+fn default_dht_k() -> usize {
+    20
+}
+
+fn default_dht_alpha() -> usize {
+    3
+}
+
+fn default_dht_interval() -> u64 {
+    60
+}
+
+/// Configuration for the network controller. journal section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Journal {
+    /// path to the file events and decisions are appended to.
+    pub file: String,
+    /// whether the application data of payloads and gossip is recorded.
+    /// Otherwise only its size and digest are.
+    #[serde(default)]
+    pub payloads: bool,
+}
+
+impl Journal {
+    /// Path to the journal file. A relative path is resolved against
+    /// the working directory, like the target file.
+    pub fn path(&self) -> PathBuf {
+        let mut path = PathBuf::from(get_working_dir());
+        path.push(&self.file);
+        path
+    }
+}
+
+/// Configuration for the network controller. runtime section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Runtime {
+    /// number of worker threads. Defaults to the number of cores.
+    pub worker_threads: Option<usize>,
+    /// name of the worker threads.
+    #[serde(default = "default_thread_name")]
+    pub thread_name: String,
+}
+
+fn default_thread_name() -> String {
+    "area-net".to_owned()
+}
+
+impl Runtime {
+    /// Build the runtime described by this section.
+    pub fn build(&self) -> Result<tokio::runtime::Runtime, std::io::Error> {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all().thread_name(&self.thread_name);
+        if let Some(worker_threads) = self.worker_threads {
+            builder.worker_threads(worker_threads);
+        }
+        builder.build()
+    }
+}
+
+/// Configuration for the network controller. impairment section
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Impairment {
+    /// Rules, matched in order against the address of each peer.
+    /// Only the first matching rule applies.
+    #[serde(default)]
+    pub rules: Vec<impairment::Rule>,
+}
+
+impl Impairment {
+    /// Impairment rules described by this section.
+    pub fn impairments(&self) -> Result<Impairments, impairment::Error> {
+        Impairments::new(&self.rules)
+    }
+}
+
+/// Configuration for the network controller. noise section
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Noise {
+    /// Path to a file holding the static private key of this node (32 bytes in
+    /// hexadecimal). A relative path is resolved against the working directory.
+    /// If not set, a new key pair is generated when the controller is created.
+    pub key: Option<String>,
+}
+
+/// Configuration for the network controller. identity section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identity {
+    /// Path to a file holding the ed25519 private key of this node (a 32 bytes
+    /// seed in hexadecimal). A new key is written to it if it does not exist.
+    pub key: String,
+}
+
+impl Identity {
+    /// Path to the key file. A relative path is resolved against
+    /// the working directory, like the target file.
+    pub fn path(&self) -> PathBuf {
+        let mut path = PathBuf::from(get_working_dir());
+        path.push(&self.key);
+        path
+    }
+}
+
+/// Configuration for the network controller. auth section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Auth {
+    /// Path to a file holding the secret shared by the nodes of the network.
+    /// A relative path is resolved against the working directory.
+    pub key: String,
+}
+
+impl Auth {
+    /// Path to the secret file. A relative path is resolved against
+    /// the working directory, like the target file.
+    pub fn path(&self) -> PathBuf {
+        let mut path = PathBuf::from(get_working_dir());
+        path.push(&self.key);
+        path
+    }
+}
+
+/// Configuration for the network controller. gossip section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Gossip {
+    /// Number of hops gossip sent by this node travels.
+    #[serde(default = "default_gossip_ttl")]
+    pub ttl: u64,
+    /// Number of message ids remembered, so that gossip reaching this node
+    /// again is not forwarded again.
+    #[serde(default = "default_gossip_seen")]
+    pub seen: usize,
+}
+
+impl Default for Gossip {
+    fn default() -> Self {
+        Gossip {
+            ttl: default_gossip_ttl(),
+            seen: default_gossip_seen(),
+        }
+    }
+}
+
+fn default_gossip_ttl() -> u64 {
+    6
+}
+
+fn default_gossip_seen() -> usize {
+    4096
+}
+
+/// Configuration for the network controller. contacts section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contacts {
+    /// Minimum number of seconds between two contact lists taken from a peer.
+    /// Lists arriving sooner are dropped.
+    #[serde(default = "default_contacts_interval")]
+    pub interval: u64,
+    /// Maximum number of contacts taken from a list.
+    #[serde(default = "default_max_contacts")]
+    pub max_per_list: usize,
+    /// Maximum number of idle addresses. Contacts beyond are dropped.
+    #[serde(default = "default_max_idle")]
+    pub max_idle: usize,
+    /// Number of seconds between two contact requests, each sent to a live
+    /// peer picked at random. Defaults to `peer_file_dump_interval`.
+    #[serde(default)]
+    pub exchange_interval: Option<u64>,
+    /// Whether lists the remote did not sign are dropped, by the peers.
+    #[serde(default)]
+    pub signed_only: bool,
+}
+
+impl Default for Contacts {
+    fn default() -> Self {
+        Contacts {
+            interval: default_contacts_interval(),
+            max_per_list: default_max_contacts(),
+            max_idle: default_max_idle(),
+            exchange_interval: None,
+            signed_only: false,
+        }
+    }
+}
+
+fn default_contacts_interval() -> u64 {
+    2
+}
+
+fn default_max_contacts() -> usize {
+    64
+}
+
+fn default_max_idle() -> usize {
+    1024
+}
+
+/// Configuration for the network controller. scoring section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scoring {
+    /// Nodes whose score falls below this value are banned.
+    #[serde(default = "default_ban_below")]
+    pub ban_below: f64,
+    /// Number of seconds a node with a low score stays banned.
+    #[serde(default = "default_ban_duration")]
+    pub ban_duration: u64,
+    /// When the incoming connections are at their maximum, the incoming peer
+    /// with the lowest score is evicted for a new connection, if its score is
+    /// below this value.
+    #[serde(default = "default_evict_below")]
+    pub evict_below: f64,
+}
+
+impl Default for Scoring {
+    fn default() -> Self {
+        Scoring {
+            ban_below: default_ban_below(),
+            ban_duration: default_ban_duration(),
+            evict_below: default_evict_below(),
+        }
+    }
+}
+
+fn default_ban_below() -> f64 {
+    0.1
+}
+
+fn default_ban_duration() -> u64 {
+    3600
+}
+
+fn default_evict_below() -> f64 {
+    0.5
+}
+
+/// Configuration for the network controller. relay section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Relay {
+    /// Whether this node relays circuits between the peers connected to it.
+    #[serde(default)]
+    pub serve: bool,
+    /// Maximum number of circuits relayed at once.
+    #[serde(default = "default_max_circuits")]
+    pub max_circuits: usize,
+    /// Maximum number of circuits a peer opens through this node at once.
+    #[serde(default = "default_max_circuits_per_peer")]
+    pub max_circuits_per_peer: usize,
+    /// Address ('ip:port') of a relay node. Peers which cannot be dialed
+    /// directly are dialed through it.
+    pub via: Option<String>,
+}
+
+fn default_max_circuits() -> usize {
+    64
+}
+
+fn default_max_circuits_per_peer() -> usize {
+    8
+}
+
+/// Configuration for the network controller. discovery section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Discovery {
+    /// STUN server ('host:port') asked for the external address at startup.
+    pub stun: Option<String>,
+    /// Number of remote peers which must report the same address before it is
+    /// advertised. 0 ignores the reports of remote peers.
+    #[serde(default = "default_confirmations")]
+    pub confirmations: usize,
+}
+
+fn default_confirmations() -> usize {
+    2
 }
 
 /// Configuration for the network controller. target section
@@ -1151,6 +4311,54 @@ pub struct Summary {
     pub incoming: Vec<InConnInfo>,
     /// outgoing
     pub outgoing: Vec<OutConnInfo>,
+    /// Scores of the nodes we are connected to, and of those we dropped.
+    pub scores: Vec<score::Standing>,
+}
+
+/// Builds a summary of the controller's current connections.
+pub async fn summary(
+    controller: InConnInfo,
+    incoming: &Mutex<IncomingState>,
+    outgoing: &Mutex<OutgoingState>,
+    idle: &Mutex<IdleState>,
+) -> Summary {
+    let incoming = incoming
+        .lock()
+        .await
+        .connected
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    let outgoing = outgoing
+        .lock()
+        .await
+        .connected
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    let connected = incoming
+        .iter()
+        .map(|info| info.addr)
+        .chain(outgoing.iter().map(|info| info.addr))
+        .collect::<HashSet<_>>();
+    let scores = idle
+        .lock()
+        .await
+        .history
+        .iter()
+        .filter(|(addr, history)| connected.contains(addr) || history.dropped.is_some())
+        .map(|(addr, history)| score::Standing {
+            addr: *addr,
+            score: score::peer_score(Some(history)),
+            history: history.clone(),
+        })
+        .collect();
+    Summary {
+        controller,
+        incoming,
+        outgoing,
+        scores,
+    }
 }
 
 impl Summary {
@@ -1196,3 +4404,537 @@ impl Summary {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::memory::MemoryTransport;
+    use std::str::FromStr;
+
+    // A controller listening on the given port of the memory transport.
+    fn controller(
+        label: &str,
+        port: u16,
+        transport: &MemoryTransport,
+        dir: &Path,
+    ) -> NetworkController {
+        let config = serde_json::json!({
+            "incoming": { "max_conn_count": 8, "max_simultaneous_conn_attempts": 4 },
+            "outgoing": { "max_simultaneous_conn_attempts": 4 },
+            "peers": {
+                "max_conn_attempt": 4,
+                "conn_attempt_delay": 1,
+                "max_idle_count": 4,
+                "max_banned_count": 4,
+                "heartbeat_timeout": 10,
+                "heartbeat_period": 2,
+            },
+            "listen": { "addr": "127.0.0.1", "port": port },
+            "target": { "file": dir.join("peers.json").display().to_string() },
+            "peer_file_dump_interval": 60,
+        });
+        let mut controller =
+            NetworkController::new(label.to_owned(), serde_json::from_value(config).unwrap())
+                .unwrap();
+        controller.transport = Arc::new(transport.clone());
+        controller
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn should_read_unix_sockets_from_the_target_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bob.sock");
+        let targets = serde_json::json!(["127.0.0.1:8091", format!("unix:{}", path.display())]);
+        std::fs::write(dir.path().join("peers.json"), targets.to_string()).unwrap();
+        let mut alice = controller("alice", 8090, &MemoryTransport::new(), dir.path());
+        alice.initialize().await.unwrap();
+        let addr = transport::unix_addr(&path);
+        assert_eq!(alice.unix_targets, HashMap::from([(addr, path)]));
+        let idle = alice.idle.lock().await;
+        assert!(idle.addrs.iter().any(|info| info.addr == addr));
+        drop(idle);
+
+        std::fs::write(dir.path().join("peers.json"), r#"["unix:"]"#).unwrap();
+        let err = alice.initialize().await.unwrap_err();
+        assert!(matches!(err, Error::InvalidTarget { .. }));
+    }
+
+    #[test]
+    fn should_add_contacts_to_idle() {
+        let own = SocketAddr::from_str("[::1]:8090").unwrap();
+        let rejected = SocketAddr::from_str("[::1]:8091").unwrap();
+        let contact = SocketAddr::from_str("[::1]:8092").unwrap();
+        let mut idle = IdleState::default();
+        idle.rejected.insert(rejected);
+        let tags = vec![
+            Tags::new(),
+            Tags::from([("region".to_owned(), "eu".to_owned())]),
+        ];
+
+        // Our own address may come anywhere in the list, or not at all.
+        let known = HashSet::new();
+        let addrs = vec![rejected, own, contact];
+        add_contacts(&mut idle, &[own], &known, 16, addrs, tags, Vec::new());
+        add_contacts(
+            &mut idle,
+            &[own],
+            &known,
+            16,
+            vec![contact],
+            Vec::new(),
+            Vec::new(),
+        );
+        let addrs = idle.addrs.iter().map(|info| info.addr).collect::<Vec<_>>();
+        assert_eq!(addrs, vec![contact]);
+
+        // Addresses no node listens on are dropped, and so are contacts
+        // beyond the size of the idle set.
+        let unspecified = SocketAddr::from_str("[::]:8093").unwrap();
+        let other = SocketAddr::from_str("[::1]:8094").unwrap();
+        add_contacts(
+            &mut idle,
+            &[own],
+            &known,
+            16,
+            vec![unspecified],
+            Vec::new(),
+            Vec::new(),
+        );
+        add_contacts(
+            &mut idle,
+            &[own],
+            &known,
+            1,
+            vec![other],
+            Vec::new(),
+            Vec::new(),
+        );
+        let addrs = idle.addrs.iter().map(|info| info.addr).collect::<Vec<_>>();
+        assert_eq!(addrs, vec![contact]);
+    }
+
+    #[test]
+    fn should_dedupe_contacts_by_id() {
+        let addrs = ["[::1]:8090", "[::1]:8091", "[::1]:8092", "[::1]:8093"]
+            .map(|addr| SocketAddr::from_str(addr).unwrap());
+        let contact = |id, last_seen| Contact {
+            id,
+            label: String::new(),
+            last_seen,
+            provenance: Provenance::Outgoing,
+        };
+        let (connected, moved, other) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let contacts = vec![
+            contact(connected, 10),
+            contact(moved, 10),
+            contact(moved, 20),
+            contact(other, 5),
+        ];
+        let mut idle = IdleState::default();
+        let known = HashSet::from([connected]);
+        add_contacts(
+            &mut idle,
+            &[],
+            &known,
+            usize::MAX,
+            addrs.to_vec(),
+            Vec::new(),
+            contacts,
+        );
+
+        // The node we are connected to is skipped, and the other one is kept
+        // at the address it was seen at last.
+        let mut kept = idle.addrs.iter().map(|info| info.addr).collect::<Vec<_>>();
+        kept.sort();
+        assert_eq!(kept, vec![addrs[2], addrs[3]]);
+        assert_eq!(idle.history[&addrs[2]].last_seen, Some(20));
+        assert_eq!(idle.history[&addrs[3]].last_seen, Some(5));
+    }
+
+    #[tokio::test]
+    async fn should_close_connections_on_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MemoryTransport::new();
+        let mut alice = controller("alice", 8090, &transport, dir.path());
+        let mut bob = controller("bob", 8091, &transport, dir.path());
+        bob.idle.lock().await.addrs.insert(AddrInfo {
+            addr: alice.addr,
+            attempt: Arc::new(Mutex::new(0)),
+            tags: Tags::new(),
+        });
+        let shutdown = alice.shutdown_token();
+        let mut alice_events = alice.subscribe();
+        let mut bob_events = bob.subscribe();
+        let alice = tokio::spawn(async move { alice.run().await });
+        tokio::spawn(async move { bob.run().await });
+
+        let wait = Duration::from_secs(5);
+        loop {
+            let event = time::timeout(wait, alice_events.recv()).await.unwrap();
+            if let Ok(NetworkEvent::PeerConnected { .. }) = event {
+                break;
+            }
+        }
+        // Alice says goodbye to bob before returning.
+        shutdown.cancel();
+        time::timeout(wait, alice).await.unwrap().unwrap().unwrap();
+        loop {
+            let event = time::timeout(wait, bob_events.recv()).await.unwrap();
+            if let Ok(NetworkEvent::PeerDisconnected { label, .. }) = event {
+                assert_eq!(label, "alice");
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn should_drive_the_controller_through_its_handle() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MemoryTransport::new();
+        let alice = controller("alice", 8090, &transport, dir.path());
+        let addr = alice.addr;
+        let alice = alice.start();
+        let bob = controller("bob", 8091, &transport, dir.path()).start();
+        let mut events = bob.subscribe();
+
+        bob.connect(addr).await;
+        let wait = Duration::from_secs(5);
+        let peer_id = loop {
+            let event = time::timeout(wait, events.recv()).await.unwrap();
+            if let Ok(NetworkEvent::PeerConnected { peer_id, .. }) = event {
+                break peer_id;
+            }
+        };
+        let peers = bob.peers().await;
+        assert_eq!(peers.outgoing.len(), 1);
+        assert_eq!(peers.outgoing[0].label, "alice");
+
+        bob.disconnect(peer_id).await.unwrap();
+        loop {
+            let event = time::timeout(wait, events.recv()).await.unwrap();
+            if let Ok(NetworkEvent::PeerDisconnected { .. }) = event {
+                break;
+            }
+        }
+        assert!(bob.disconnect(Uuid::new_v4()).await.is_err());
+        alice.shutdown().await.unwrap();
+        bob.shutdown().await.unwrap();
+        // Later calls have nothing to wait for.
+        bob.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_wait_for_runtime_connects_and_disconnects() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MemoryTransport::new();
+        let alice = controller("alice", 8090, &transport, dir.path());
+        let (alice_id, addr) = (alice.id, alice.addr);
+        let alice = alice.start();
+        let bob = controller("bob", 8091, &transport, dir.path()).start();
+
+        let wait = Duration::from_secs(5);
+        let peer_id = time::timeout(wait, bob.connect_to(addr))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer_id, alice_id);
+        // Bob is already connected to alice, who is returned right away.
+        assert_eq!(bob.connect_to(addr).await.unwrap(), alice_id);
+
+        // Nobody listens on this address.
+        let nowhere = SocketAddr::from_str("[::1]:8099").unwrap();
+        let failed = time::timeout(wait, bob.connect_to(nowhere)).await.unwrap();
+        assert!(matches!(failed, Err(Error::ConnectFailed { addr, .. }) if addr == nowhere));
+
+        let mut events = bob.subscribe();
+        time::timeout(wait, bob.disconnect(peer_id))
+            .await
+            .unwrap()
+            .unwrap();
+        // The disconnection is published before disconnect returns.
+        let closed = std::iter::from_fn(|| events.try_recv().ok()).find_map(|event| match event {
+            NetworkEvent::PeerDisconnected { peer_id, .. } => Some(peer_id),
+            _ => None,
+        });
+        assert_eq!(closed, Some(alice_id));
+
+        // A dial without an outcome gives up after the given wait.
+        let silent = SocketAddr::from_str("[::1]:8098").unwrap();
+        let wait = Duration::from_millis(1);
+        assert!(bob.connect_within(silent, wait).await.is_err());
+        alice.shutdown().await.unwrap();
+        bob.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_handle_commands_sent_to_the_controller() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MemoryTransport::new();
+        let alice = controller("alice", 8090, &transport, dir.path());
+        let addr = alice.addr;
+        let alice = alice.start();
+        let bob = controller("bob", 8091, &transport, dir.path());
+        let bans = bob.bans.clone();
+        let bob = bob.start();
+        let mut events = bob.subscribe();
+        let commands = bob.commands();
+
+        commands
+            .send(ControllerCommand::Connect { addr })
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let peer_id = loop {
+            let event = time::timeout(wait, events.recv()).await.unwrap();
+            if let Ok(NetworkEvent::PeerConnected { peer_id, .. }) = event {
+                break peer_id;
+            }
+        };
+        let (reply, status) = tokio::sync::oneshot::channel();
+        commands
+            .send(ControllerCommand::QueryStatus { reply })
+            .await
+            .unwrap();
+        let status = status.await.unwrap();
+        assert_eq!(status.outgoing.len(), 1);
+        assert_eq!(status.outgoing[0].id, peer_id);
+
+        // A banned node is not dialed again.
+        commands
+            .send(ControllerCommand::Ban {
+                peer_id,
+                duration: None,
+            })
+            .await
+            .unwrap();
+        loop {
+            let event = time::timeout(wait, events.recv()).await.unwrap();
+            if let Ok(NetworkEvent::PeerDisconnected { .. }) = event {
+                break;
+            }
+        }
+        let now = std::time::Instant::now();
+        assert!(bans.is_banned(&ban::Target::Peer(peer_id), now));
+        assert!(bans.is_addr_banned(&addr, now));
+        alice.shutdown().await.unwrap();
+        bob.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_evict_the_incoming_peer_with_the_lowest_score() {
+        let (good, bad) = (Uuid::new_v4(), Uuid::new_v4());
+        let good_addr = SocketAddr::from_str("192.168.0.10:8090").unwrap();
+        let bad_addr = SocketAddr::from_str("192.168.0.11:8090").unwrap();
+        let info = |addr| InConnInfo {
+            addr,
+            id: Uuid::new_v4(),
+            label: "remote".to_owned(),
+            health: None,
+        };
+        let incoming = Mutex::new(IncomingState {
+            connected: HashMap::from([(good, info(good_addr)), (bad, info(bad_addr))]),
+        });
+        let mut idle = IdleState::default();
+        let misbehaving = idle.history.entry(bad_addr).or_default();
+        misbehaving.record_violation();
+        misbehaving.record_timeout();
+        let idle = Mutex::new(idle);
+        let (tx, mut rx) = mpsc::channel(1);
+        let handle = tokio::spawn(async { Ok(()) });
+        let peers = Mutex::new(PeerRepo::from([(bad, PeerData { tx, handle })]));
+
+        // Neither peer scores below the threshold.
+        assert!(!evict_worst(&idle, &incoming, &peers, 0.1).await);
+        assert!(evict_worst(&idle, &incoming, &peers, 0.5).await);
+        assert!(matches!(rx.recv().await, Some(Command::Terminate)));
+        let idle = idle.lock().await;
+        assert!(idle.history[&bad_addr].dropped.is_some());
+    }
+
+    #[test]
+    fn should_resurrect_retired_addresses_after_the_delay() {
+        let now = std::time::Instant::now();
+        let old = SocketAddr::from_str("192.168.0.10:8090").unwrap();
+        let recent = SocketAddr::from_str("192.168.0.11:8090").unwrap();
+        let mut idle = IdleState::default();
+        idle.dead.insert(old, (Tags::new(), now));
+        idle.dead
+            .insert(recent, (Tags::new(), now + Duration::from_secs(30)));
+
+        resurrect(
+            &mut idle,
+            Duration::from_secs(60),
+            now + Duration::from_secs(60),
+        );
+        assert!(idle.addrs.iter().any(|info| info.addr == old));
+        assert!(idle.dead.contains_key(&recent));
+        assert_eq!(idle.addrs.len(), 1);
+    }
+
+    #[test]
+    fn should_drop_the_idle_addresses_with_the_lowest_score() {
+        let addr = |port| SocketAddr::from(([192, 168, 0, 10], port));
+        let mut idle = IdleState::default();
+        for port in 8090..8093 {
+            idle.addrs.insert(AddrInfo {
+                addr: addr(port),
+                attempt: Arc::new(Mutex::new(0)),
+                tags: Tags::new(),
+            });
+        }
+        idle.history.entry(addr(8090)).or_default().failures = 4;
+        idle.history.entry(addr(8092)).or_default().successes = 4;
+        let counts = Counts::default();
+
+        assert!(trim_idle(&mut idle, 0, &counts, 0).is_empty());
+        assert_eq!(trim_idle(&mut idle, 2, &counts, 0), vec![addr(8090)]);
+        assert_eq!(idle.addrs.len(), 2);
+        assert_eq!(trim_idle(&mut idle, 1, &counts, 0), vec![addr(8091)]);
+    }
+
+    #[tokio::test]
+    async fn should_keep_the_connection_dialed_by_the_lower_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MemoryTransport::new();
+        let alice = controller("alice", 8090, &transport, dir.path());
+        let addr = SocketAddr::from(([192, 168, 0, 10], 8090));
+        let (out_id, in_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let (tx_out, mut rx_out) = mpsc::channel(1);
+        let (tx_in, mut rx_in) = mpsc::channel(1);
+        let mut peers = alice.peers.lock().await;
+        let handle = tokio::spawn(async { Ok(()) });
+        peers.insert(out_id, PeerData { tx: tx_out, handle });
+        let handle = tokio::spawn(async { Ok(()) });
+        peers.insert(in_id, PeerData { tx: tx_in, handle });
+        drop(peers);
+        let (outgoing, incoming) = (alice.outgoing.clone(), alice.incoming.clone());
+        let connect = |remote| {
+            let (outgoing, incoming) = (outgoing.clone(), incoming.clone());
+            async move {
+                outgoing.lock().await.connected.insert(
+                    out_id,
+                    OutConnInfo {
+                        addr,
+                        id: remote,
+                        label: "bob".to_owned(),
+                        rtt: i64::MAX,
+                        offset: 0,
+                        tags: Tags::new(),
+                        health: None,
+                    },
+                );
+                incoming.lock().await.connected.insert(
+                    in_id,
+                    InConnInfo {
+                        addr,
+                        id: remote,
+                        label: "bob".to_owned(),
+                        health: None,
+                    },
+                );
+            }
+        };
+
+        // Alice has the lower id: the connection Alice dialed is kept.
+        connect(Uuid::max()).await;
+        alice
+            .close_duplicate(in_id, Uuid::max(), Direction::Incoming)
+            .await;
+        assert!(matches!(rx_in.recv().await, Some(Command::Terminate)));
+
+        // Bob has the lower id: the connection Bob dialed is kept.
+        connect(Uuid::nil()).await;
+        alice
+            .close_duplicate(in_id, Uuid::nil(), Direction::Incoming)
+            .await;
+        assert!(matches!(rx_out.recv().await, Some(Command::Disconnect)));
+    }
+
+    #[tokio::test]
+    async fn should_answer_dht_lookups_with_the_closest_nodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MemoryTransport::new();
+        let alice = controller("alice", 8090, &transport, dir.path());
+        let (bob, carol) = (Uuid::new_v4(), Uuid::new_v4());
+        let bob_addr = SocketAddr::from(([192, 168, 0, 10], 8090));
+        let carol_addr = SocketAddr::from(([192, 168, 0, 11], 8090));
+        let in_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(1);
+        let handle = tokio::spawn(async { Ok(()) });
+        alice
+            .peers
+            .lock()
+            .await
+            .insert(in_id, PeerData { tx, handle });
+        alice.incoming.lock().await.connected.insert(
+            in_id,
+            InConnInfo {
+                addr: bob_addr,
+                id: bob,
+                label: "bob".to_owned(),
+                health: None,
+            },
+        );
+        alice.learn(bob, bob_addr).await;
+        alice.learn(carol, carol_addr).await;
+        // Banned nodes are left out of the table.
+        alice.ban(ban::Target::Peer(Uuid::nil()), None);
+        alice.learn(Uuid::nil(), carol_addr).await;
+        assert_eq!(alice.dht.lock().await.len(), 2);
+
+        let event = Event::FindNode {
+            id: in_id,
+            target: carol,
+        };
+        alice.handle_event(event).await.unwrap();
+        match rx.recv().await {
+            // The node which asked is left out of the answer.
+            Some(Command::SendNodes { target, nodes }) => {
+                assert_eq!(target, carol);
+                assert_eq!(
+                    nodes,
+                    vec![Node {
+                        id: carol,
+                        addr: carol_addr
+                    }]
+                );
+            }
+            cmd => panic!("expected nodes, got {cmd:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_merge_contacts_up_to_max_idle_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MemoryTransport::new();
+        let alice = controller("alice", 8090, &transport, dir.path());
+        let bob_addr = SocketAddr::from(([192, 168, 0, 10], 8090));
+        let out_id = Uuid::new_v4();
+        alice.outgoing.lock().await.connected.insert(
+            out_id,
+            OutConnInfo {
+                addr: bob_addr,
+                id: Uuid::new_v4(),
+                label: "bob".to_owned(),
+                rtt: i64::MAX,
+                offset: 0,
+                tags: Tags::new(),
+                health: None,
+            },
+        );
+        // Bob is listed first, then more nodes than max_idle_count (4).
+        let mut addrs = vec![bob_addr];
+        addrs.extend((1..=6).map(|port| SocketAddr::from(([192, 168, 0, 20], port))));
+        let event = Event::ContactUpdated {
+            id: out_id,
+            addrs,
+            tags: Vec::new(),
+            contacts: Vec::new(),
+        };
+        alice.handle_event(event).await.unwrap();
+        let idle = alice.idle.lock().await;
+        assert_eq!(idle.addrs.len(), 4);
+        assert!(idle.addrs.iter().all(|info| info.addr != bob_addr));
+    }
+}
@@ -0,0 +1,140 @@
+//! Handlers of the messages defined by the application.
+//!
+//! Peers pass the messages with a registered tag to the controller, whatever
+//! `wire.unknown_messages` says. The controller parses them, and calls the
+//! handler registered for their tag. The handler runs on the controller's
+//! event loop, so it must hand lengthy work over, for instance to a channel.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::message::custom::CustomMessage;
+use crate::message::{Error, Registry};
+use crate::Frame;
+
+type Handler = Box<dyn Fn(Uuid, Frame) -> Result<(), Error> + Send + Sync>;
+
+/// Handlers of the messages of the application, by tag, and the registry of
+/// the messages they handle.
+#[derive(Default)]
+pub struct Handlers {
+    handlers: HashMap<String, Handler>,
+    registry: Arc<Registry>,
+}
+
+impl Handlers {
+    /// Register the message, and the handler called with the id of the remote
+    /// node and the message, for each one received. Registering the message
+    /// again replaces its handler.
+    pub fn register<M, F>(&mut self, handler: F) -> Result<(), Error>
+    where
+        M: CustomMessage,
+        F: Fn(Uuid, M) + Send + Sync + 'static,
+    {
+        if self.registry.id(M::TAG) != Some(M::ID) {
+            Arc::make_mut(&mut self.registry).register(M::ID, M::TAG)?;
+        }
+        let handler = move |peer_id, frame| {
+            handler(peer_id, M::from_frame(frame)?);
+            Ok(())
+        };
+        self.handlers
+            .insert(M::TAG.to_ascii_uppercase(), Box::new(handler));
+        Ok(())
+    }
+
+    /// The messages known to the controller, the registered ones included.
+    /// Peers share it, so messages are registered before the controller runs.
+    pub fn registry(&self) -> Arc<Registry> {
+        self.registry.clone()
+    }
+
+    /// Hand the message received from the remote node to the handler of its
+    /// tag. The frame is given back if no handler is registered for the tag.
+    pub fn dispatch(&self, peer_id: Uuid, tag: &str, frame: Frame) -> Result<(), Frame> {
+        match self.handlers.get(&tag.to_ascii_uppercase()) {
+            Some(handler) => {
+                if let Err(err) = handler(peer_id, frame) {
+                    log::warn!(
+                        "Controller | Dropping '{tag}' from peer {} | {err}",
+                        peer_id.to_string().get(0..8).unwrap()
+                    );
+                }
+                Ok(())
+            }
+            None => Err(frame),
+        }
+    }
+}
+
+impl fmt::Debug for Handlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.handlers.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parse;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq)]
+    struct Poll {
+        question: String,
+    }
+
+    impl CustomMessage for Poll {
+        const TAG: &'static str = "POLL";
+        const ID: u64 = crate::message::registry::CUSTOM_ID_BASE + 2;
+
+        fn parse_frames(parse: &mut Parse) -> Result<Poll, Error> {
+            let question = parse.next_string()?;
+            Ok(Poll { question })
+        }
+
+        fn into_fields(self) -> Result<Frame, Error> {
+            Ok(crate::frame![self.question])
+        }
+    }
+
+    #[test]
+    fn should_dispatch_registered_messages() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut handlers = Handlers::default();
+        let sink = received.clone();
+        handlers
+            .register(move |peer_id, poll: Poll| sink.lock().unwrap().push((peer_id, poll)))
+            .unwrap();
+        // Registering again replaces the handler, and other controllers
+        // do not know the message.
+        let sink = received.clone();
+        handlers
+            .register(move |peer_id, poll: Poll| sink.lock().unwrap().push((peer_id, poll)))
+            .unwrap();
+        assert_eq!(Handlers::default().registry().id("POLL"), None);
+
+        // The message is sent with its id, and parsed back.
+        let poll = Poll {
+            question: "lunch?".to_owned(),
+        };
+        let registry = handlers.registry();
+        let frame = registry.with_id(poll.into_frame().unwrap());
+        let peer_id = Uuid::new_v4();
+        let (tag, frame) = match crate::message::Message::from_frame_in(frame, &registry).unwrap() {
+            crate::message::Message::Unknown { tag, frame } => (tag, frame),
+            msg => panic!("Expected an unknown message, got {msg:?}"),
+        };
+        assert!(handlers.dispatch(peer_id, &tag, frame).is_ok());
+        let expected = Poll {
+            question: "lunch?".to_owned(),
+        };
+        assert_eq!(*received.lock().unwrap(), vec![(peer_id, expected)]);
+
+        // Other tags are given back.
+        assert!(handlers
+            .dispatch(peer_id, "NOPE", crate::frame!["NOPE"])
+            .is_err());
+    }
+}
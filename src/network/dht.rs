@@ -0,0 +1,159 @@
+//! Kademlia routing table.
+//!
+//! Nodes are identified in the DHT by their controller id: 128 bits, derived
+//! from the public key of the node when it has an identity. The distance
+//! between two ids is their XOR, and the table keeps the nodes we were
+//! connected to in 128 k-buckets, one for each length of the prefix they
+//! share with our id, so that we know many nodes close to us and a few far
+//! away.
+//!
+//! A lookup asks the peers closest to the target id for the nodes they know
+//! closest to it (FIND_NODE). The nodes of the answers (NODES) are added to
+//! the idle addresses, dialed, and asked in turn at the next round, so that
+//! each round gets closer to the target. Looking up our own id finds our
+//! neighbours, and looking up random ids fills the farther buckets.
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::message::dht::Node;
+
+/// Number of bits of an id, and number of buckets.
+pub const ID_BITS: usize = 128;
+
+/// Distance between two ids, in the XOR metric.
+pub fn distance(a: &Uuid, b: &Uuid) -> u128 {
+    a.as_u128() ^ b.as_u128()
+}
+
+/// Nodes we were connected to, by distance to our id.
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: Uuid,
+    k: usize,
+    // Bucket i holds the nodes at a distance in [2^i, 2^(i+1)), least
+    // recently seen first.
+    buckets: Vec<VecDeque<Node>>,
+}
+
+impl RoutingTable {
+    /// Create a table around our id, with buckets of `k` nodes.
+    pub fn new(id: Uuid, k: usize) -> RoutingTable {
+        RoutingTable {
+            id,
+            k: k.max(1),
+            buckets: vec![VecDeque::new(); ID_BITS],
+        }
+    }
+
+    /// Add the node, or move it to the end of its bucket as the most recently
+    /// seen, with its new address. A full bucket keeps the nodes it holds:
+    /// nodes which have been up for long are likely to stay up. Returns true
+    /// if the node is in the table.
+    pub fn insert(&mut self, node: Node) -> bool {
+        let Some(index) = self.bucket(&node.id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|known| known.id == node.id) {
+            bucket.remove(position);
+        } else if bucket.len() >= self.k {
+            return false;
+        }
+        bucket.push_back(node);
+        true
+    }
+
+    /// Remove the node with the given id. Returns true if it was in the table.
+    pub fn remove(&mut self, id: &Uuid) -> bool {
+        let Some(index) = self.bucket(id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+        let len = bucket.len();
+        bucket.retain(|node| node.id != *id);
+        bucket.len() < len
+    }
+
+    /// Remove the nodes at the given address. Returns true if any was in the
+    /// table.
+    pub fn remove_addr(&mut self, addr: &SocketAddr) -> bool {
+        let len = self.len();
+        for bucket in self.buckets.iter_mut() {
+            bucket.retain(|node| node.addr != *addr);
+        }
+        self.len() < len
+    }
+
+    /// The `count` nodes closest to the target, closest first.
+    pub fn closest(&self, target: &Uuid, count: usize) -> Vec<Node> {
+        let mut nodes = self.buckets.iter().flatten().copied().collect::<Vec<_>>();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Number of nodes in the table.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(VecDeque::len).sum()
+    }
+
+    /// Returns true if the table holds no node.
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(VecDeque::is_empty)
+    }
+
+    /// Number of nodes the table answers a lookup with.
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    // Index of the bucket of the id, None for our own id.
+    fn bucket(&self, id: &Uuid) -> Option<usize> {
+        let distance = distance(&self.id, id);
+        (distance != 0).then(|| ID_BITS - 1 - distance.leading_zeros() as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn node(id: u128) -> Node {
+        Node {
+            id: Uuid::from_u128(id),
+            addr: SocketAddr::from_str("[::1]:8090").unwrap(),
+        }
+    }
+
+    #[test]
+    fn should_keep_k_nodes_by_bucket_and_find_the_closest() {
+        let mut table = RoutingTable::new(Uuid::from_u128(0), 2);
+        assert!(!table.insert(node(0)));
+        // 4, 5, 6 and 7 share the same bucket.
+        assert!(table.insert(node(4)));
+        assert!(table.insert(node(5)));
+        assert!(!table.insert(node(6)));
+        assert!(table.insert(node(1)));
+        assert!(table.insert(node(1 << 100)));
+        assert_eq!(table.len(), 4);
+
+        // A known node is refreshed, even in a full bucket.
+        let mut moved = node(4);
+        moved.addr = SocketAddr::from_str("[::1]:8091").unwrap();
+        assert!(table.insert(moved));
+        assert_eq!(table.len(), 4);
+
+        let closest = table.closest(&Uuid::from_u128(6), 3);
+        let ids = closest.iter().map(|n| n.id.as_u128()).collect::<Vec<_>>();
+        assert_eq!(ids, vec![4, 5, 1]);
+        assert_eq!(closest[0].addr, moved.addr);
+
+        assert!(table.remove(&Uuid::from_u128(5)));
+        assert!(!table.remove(&Uuid::from_u128(5)));
+        assert!(table.insert(node(6)));
+        assert!(table.remove_addr(&moved.addr));
+        assert_eq!(table.len(), 3);
+    }
+}
@@ -0,0 +1,223 @@
+//! External address discovery.
+//!
+//! Behind NAT, the configured listen address is not the address remote nodes
+//! can reach us at. The controller learns its publicly observed IP address,
+//! either from a STUN server at startup, or from the remote nodes, which report
+//! in their connection response the address they saw our connection come from.
+//! As a single remote could lie, an address reported by the remote nodes is only
+//! adopted once enough of them agree. The advertised address is the observed IP
+//! address with the configured listen port, which assumes the NAT forwards that
+//! port unchanged.
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+
+// STUN (RFC 5389) binding request and response.
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const HEADER_LEN: usize = 20;
+
+/// Delay before a STUN request is sent again.
+const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+const STUN_ATTEMPTS: usize = 3;
+
+/// The address advertised to remote nodes.
+#[derive(Debug)]
+pub struct ExternalAddr {
+    listen: SocketAddr,
+    confirmations: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // Last address reported by each remote node.
+    reports: HashMap<Uuid, IpAddr>,
+    external: Option<IpAddr>,
+}
+
+impl ExternalAddr {
+    /// Advertise the listen address until an external address is observed.
+    /// An address reported by remote nodes is adopted once `confirmations`
+    /// of them agree. With no confirmations, reports are ignored.
+    pub fn new(listen: SocketAddr, confirmations: usize) -> ExternalAddr {
+        ExternalAddr {
+            listen,
+            confirmations,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// The address to advertise to remote nodes.
+    pub fn advertised(&self) -> SocketAddr {
+        let state = self.state.lock().expect("external addr lock");
+        match state.external {
+            Some(ip) => SocketAddr::new(ip, self.listen.port()),
+            None => self.listen,
+        }
+    }
+
+    /// Use the IP address returned by a STUN server.
+    pub fn set(&self, ip: IpAddr) {
+        self.state.lock().expect("external addr lock").external = Some(ip);
+    }
+
+    /// Record the address a remote node saw our connection come from. Returns
+    /// the new advertised address if this report changed it.
+    pub fn report(&self, peer_id: Uuid, observed: SocketAddr) -> Option<SocketAddr> {
+        let ip = observed.ip();
+        // Connections between co-located nodes say nothing about the NAT.
+        if self.confirmations == 0 || ip.is_loopback() || ip.is_unspecified() {
+            return None;
+        }
+        let mut state = self.state.lock().expect("external addr lock");
+        state.reports.insert(peer_id, ip);
+        let agreeing = state.reports.values().filter(|other| **other == ip).count();
+        if agreeing < self.confirmations || state.external == Some(ip) {
+            return None;
+        }
+        state.external = Some(ip);
+        Some(SocketAddr::new(ip, self.listen.port()))
+    }
+}
+
+/// Ask the STUN server at the given address for the IP address our UDP
+/// datagrams come from.
+pub async fn stun(server: SocketAddr) -> io::Result<IpAddr> {
+    let bind = match server {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(server).await?;
+    let transaction: [u8; 12] = std::array::from_fn(|_| fastrand::u8(..));
+    let mut request = Vec::with_capacity(HEADER_LEN);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction);
+    let mut buf = [0u8; 512];
+    for _ in 0..STUN_ATTEMPTS {
+        socket.send(&request).await?;
+        // Datagrams which are not our response are ignored.
+        let deadline = Instant::now() + STUN_TIMEOUT;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            if let Some(ip) = parse_response(&buf[..received?], &transaction) {
+                return Ok(ip);
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("No answer from the STUN server {server}"),
+    ))
+}
+
+// The mapped address of a binding response to our transaction.
+fn parse_response(message: &[u8], transaction: &[u8; 12]) -> Option<IpAddr> {
+    let header = message.get(..HEADER_LEN)?;
+    if u16::from_be_bytes([header[0], header[1]]) != BINDING_RESPONSE
+        || header[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || header[8..] != transaction[..]
+    {
+        return None;
+    }
+    let len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+    let mut attributes = message.get(HEADER_LEN..HEADER_LEN + len)?;
+    let mut mapped = None;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = usize::from(u16::from_be_bytes([attributes[2], attributes[3]]));
+        let value = attributes.get(4..4 + len)?;
+        match kind {
+            XOR_MAPPED_ADDRESS => return parse_address(value, Some(transaction)),
+            MAPPED_ADDRESS => mapped = parse_address(value, None),
+            _ => {}
+        }
+        // Attributes are padded to a multiple of 4 bytes.
+        attributes = attributes
+            .get((4 + len).next_multiple_of(4)..)
+            .unwrap_or_default();
+    }
+    mapped
+}
+
+// The address of a (XOR-)MAPPED-ADDRESS attribute. XORed addresses are
+// masked with the magic cookie and the transaction id.
+fn parse_address(value: &[u8], xor: Option<&[u8; 12]>) -> Option<IpAddr> {
+    let mut mask = [0u8; 16];
+    if let Some(transaction) = xor {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction);
+    }
+    let family = *value.get(1)?;
+    let address = value.get(4..)?;
+    let mut octets = [0u8; 16];
+    for (i, octet) in address.iter().take(16).enumerate() {
+        octets[i] = octet ^ mask[i];
+    }
+    match (family, address.len()) {
+        (0x01, 4) => Some(IpAddr::from([octets[0], octets[1], octets[2], octets[3]])),
+        (0x02, 16) => Some(IpAddr::from(octets)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    #[test]
+    fn should_adopt_an_address_reported_by_enough_peers() {
+        let listen = "0.0.0.0:8090".parse().unwrap();
+        let external = ExternalAddr::new(listen, 2);
+        let observed: SocketAddr = "203.0.113.7:51000".parse().unwrap();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(external.report(alice, observed), None);
+        assert_eq!(external.report(alice, observed), None);
+        assert_eq!(external.advertised(), listen);
+        let advertised = "203.0.113.7:8090".parse().unwrap();
+        assert_eq!(external.report(bob, observed), Some(advertised));
+        assert_eq!(external.advertised(), advertised);
+        assert_eq!(
+            external.report(bob, "127.0.0.1:51000".parse().unwrap()),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn should_read_the_mapped_address_from_a_stun_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (n, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, HEADER_LEN);
+            let mut response = buf[..HEADER_LEN].to_vec();
+            response[..2].copy_from_slice(&BINDING_RESPONSE.to_be_bytes());
+            response[2..4].copy_from_slice(&12u16.to_be_bytes());
+            // XOR-MAPPED-ADDRESS 198.51.100.9
+            response.extend_from_slice(&XOR_MAPPED_ADDRESS.to_be_bytes());
+            response.extend_from_slice(&8u16.to_be_bytes());
+            response.extend_from_slice(&[0, 0x01, 0, 0]);
+            let cookie = MAGIC_COOKIE.to_be_bytes();
+            for (i, octet) in [198u8, 51, 100, 9].iter().enumerate() {
+                response.push(octet ^ cookie[i]);
+            }
+            server.send_to(&response, from).await.unwrap();
+        });
+        let ip = timeout(Duration::from_secs(5), stun(addr))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ip, IpAddr::from([198, 51, 100, 9]));
+    }
+}
@@ -1,9 +1,20 @@
 //! A network controller
 
+use bytes::Bytes;
+use futures::stream::{self, Stream};
+use serde::Serialize;
 use std::net::SocketAddr;
+use tokio::sync::broadcast::{self, error::RecvError};
 use uuid::Uuid;
 
+use super::ban::Target;
 use super::peer::PeerState;
+use super::policy::Tags;
+use super::relay::RelayMessage;
+use crate::message::conn_rejection::RejectReason;
+use crate::message::dht::Node;
+use crate::message::{Contact, Health, InfoResponse};
+use crate::Frame;
 
 /// Event are messages sent to the network controller.
 #[derive(Debug)]
@@ -29,14 +40,14 @@ pub enum Event {
     },
 
     /// The peer is in OutConnecting state, and has successfully established
-    /// a TcpStream connection. It is about to start Handshaking
+    /// a connection. It is about to start Handshaking
     Connected {
         /// id of the peer
         id: Uuid,
     },
 
     /// The peer is in InHandshaking state, and has successfully established
-    /// a TcpStream connection
+    /// a connection
     Listening {
         /// id of the peer
         id: Uuid,
@@ -66,7 +77,18 @@ pub enum Event {
         peer_addr: SocketAddr,
     },
 
-    /// The peer cannot establish a TcpStream connection
+    /// The remote peer told the (out) peer the address our connection
+    /// came from.
+    AddrObserved {
+        /// id of the peer
+        id: Uuid,
+        /// remote id
+        peer_id: Uuid,
+        /// our address, as seen by the remote peer
+        addr: SocketAddr,
+    },
+
+    /// The peer cannot establish a connection
     ConnectionError {
         /// id of the peer
         id: Uuid,
@@ -80,12 +102,22 @@ pub enum Event {
     /// of the connection
     /// Currently only the out peer sends this update
     /// so that the controller can in turn store the
-    /// new RTT
+    /// new RTT and clock offset
     ConnectionUpdate {
         /// id of the peer
         id: Uuid,
-        /// address we tried to connect to
+        /// round trip time (μs)
         rtt: i64,
+        /// how far the clock of the remote is ahead of ours (μs)
+        offset: i64,
+    },
+
+    /// The remote has sent its health with a heartbeat.
+    HealthUpdate {
+        /// id of the peer
+        id: Uuid,
+        /// health of the remote node
+        health: Health,
     },
 
     /// The peer has received a request to forward
@@ -104,6 +136,112 @@ pub enum Event {
         id: Uuid,
         /// list of addresses.
         addrs: Vec<SocketAddr>,
+        /// tags of each address, if the remote sent any.
+        tags: Vec<Tags>,
+        /// node at each address, if the remote sent them.
+        contacts: Vec<Contact>,
+    },
+
+    /// The peer has received a relay message from its remote.
+    Relay {
+        /// id of the peer
+        id: Uuid,
+        /// relay message
+        message: RelayMessage,
+    },
+
+    /// The peer has received the echo of one of its pings.
+    Pong {
+        /// id of the peer
+        id: Uuid,
+        /// sequence number of the ping
+        seq: u64,
+        /// round trip time (μs)
+        rtt: i64,
+        /// number of bytes echoed
+        size: usize,
+    },
+
+    /// The remote asked the peer about the node.
+    InfoRequested {
+        /// id of the peer
+        id: Uuid,
+    },
+
+    /// The peer has received the information of its remote.
+    Info {
+        /// id of the peer
+        id: Uuid,
+        /// information of the remote node
+        info: Box<InfoResponse>,
+    },
+
+    /// The remote asked the peer for the nodes closest to an id.
+    FindNode {
+        /// id of the peer
+        id: Uuid,
+        /// id looked up
+        target: Uuid,
+    },
+
+    /// The peer has received the nodes closest to an id from its remote.
+    Nodes {
+        /// id of the peer
+        id: Uuid,
+        /// id looked up
+        target: Uuid,
+        /// nodes, closest first
+        nodes: Vec<Node>,
+    },
+
+    /// The peer has received application data from its remote.
+    Payload {
+        /// id of the peer
+        id: Uuid,
+        /// topic of the data, empty if none
+        topic: String,
+        /// application data
+        data: Bytes,
+    },
+
+    /// The peer has received a gossip message from its remote.
+    Gossip {
+        /// id of the peer
+        id: Uuid,
+        /// id of the message
+        msg_id: Uuid,
+        /// number of hops left, this one included
+        ttl: u64,
+        /// topic of the data, empty if none
+        topic: String,
+        /// application data
+        data: Bytes,
+    },
+
+    /// The peer has received a message it doesn't know, and is configured
+    /// to notify the controller.
+    UnknownMessage {
+        /// id of the peer
+        id: Uuid,
+        /// tag of the message
+        tag: String,
+        /// frame of the message
+        frame: Frame,
+    },
+
+    /// The remote sent a frame which failed the session key check: it was
+    /// forged, altered or replayed. The frame is dropped.
+    ProtocolViolation {
+        /// id of the peer
+        id: Uuid,
+        /// what was wrong with the frame
+        detail: String,
+    },
+
+    /// The remote missed its heartbeats. The peer closes the connection.
+    TimedOut {
+        /// id of the peer
+        id: Uuid,
     },
 
     /// The peer has successfully terminated.
@@ -112,6 +250,16 @@ pub enum Event {
         id: Uuid,
     },
 
+    /// The remote refused the connection of the (out) peer, which is closed.
+    Rejected {
+        /// id of the peer
+        id: Uuid,
+        /// address the peer tried to connect to.
+        addr: SocketAddr,
+        /// why the remote refused the connection
+        reason: RejectReason,
+    },
+
     /// The (out) peer has successfully closed its Tcp connection.
     Disconnected {
         /// id of the peer
@@ -120,3 +268,194 @@ pub enum Event {
         addr: SocketAddr,
     },
 }
+
+/// Direction of a connection, from the point of view of this node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// The remote node connected to us.
+    Incoming,
+    /// We connected to the remote node.
+    Outgoing,
+}
+
+/// Events published by the network controller to applications.
+/// Unlike Event, which refers to the peer's internal id, these events
+/// identify the remote node by its controller id, so that applications
+/// can track remote nodes across reconnections.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum NetworkEvent {
+    /// A connection with a remote node is live (handshake completed).
+    PeerConnected {
+        /// id of the remote node's controller
+        peer_id: Uuid,
+        /// label of the remote node
+        label: String,
+        /// address of the remote node
+        addr: SocketAddr,
+        /// direction of the connection
+        direction: Direction,
+    },
+
+    /// We are trying to connect to a remote node.
+    Attempting {
+        /// address of the remote node
+        addr: SocketAddr,
+        /// number of attempts made to connect to that address, including this one.
+        attempt: i32,
+    },
+
+    /// An attempt to connect to a remote node failed.
+    AttemptFailed {
+        /// address of the remote node
+        addr: SocketAddr,
+        /// reason for the failure
+        reason: String,
+    },
+
+    /// An address is retired after too many failed attempts: it is not
+    /// dialed again, unless resurrected.
+    AddressRetired {
+        /// address of the remote node
+        addr: SocketAddr,
+        /// number of failed attempts
+        attempts: i32,
+    },
+
+    /// An idle address is dropped, as there are too many idle addresses.
+    AddressDropped {
+        /// address of the remote node
+        addr: SocketAddr,
+    },
+
+    /// A ban is lifted before its expiry, as there are too many bans.
+    BanLifted {
+        /// address or node whose ban is lifted
+        target: Target,
+    },
+
+    /// A connection with a remote node is closed.
+    PeerDisconnected {
+        /// id of the remote node's controller
+        peer_id: Uuid,
+        /// label of the remote node
+        label: String,
+        /// address of the remote node
+        addr: SocketAddr,
+        /// direction of the connection
+        direction: Direction,
+    },
+
+    /// A remote node echoed a ping.
+    Pong {
+        /// id of the remote node's controller
+        peer_id: Uuid,
+        /// sequence number of the ping
+        seq: u64,
+        /// round trip time (μs)
+        rtt: i64,
+        /// number of bytes echoed
+        size: usize,
+    },
+
+    /// A remote node answered our request for its information.
+    Info {
+        /// id of the remote node's controller
+        peer_id: Uuid,
+        /// information of the remote node
+        info: InfoResponse,
+    },
+
+    /// A remote node sent application data.
+    Payload {
+        /// id of the remote node's controller
+        peer_id: Uuid,
+        /// topic of the data, empty if none
+        topic: String,
+        /// application data
+        data: Bytes,
+    },
+
+    /// A gossip message reached this node, for the first time. `peer_id` is
+    /// the remote node which forwarded it, not the one it originates from.
+    Gossip {
+        /// id of the remote node's controller
+        peer_id: Uuid,
+        /// id of the message
+        msg_id: Uuid,
+        /// topic of the data, empty if none
+        topic: String,
+        /// application data
+        data: Bytes,
+    },
+
+    /// A remote node sent a frame which failed the session key check.
+    ProtocolViolation {
+        /// id of the remote node's controller
+        peer_id: Uuid,
+        /// what was wrong with the frame
+        detail: String,
+    },
+
+    /// A remote node sent a message we don't know, most likely because it
+    /// runs a newer version. Only published with `unknown_messages = "notify"`,
+    /// or for registered messages without a handler.
+    UnknownMessage {
+        /// id of the remote node's controller
+        peer_id: Uuid,
+        /// tag of the message
+        tag: String,
+        /// frame of the message, tag included
+        frame: Frame,
+    },
+
+    /// The subscriber fell behind, and missed events. Only published on the
+    /// streams of events.
+    Lagged {
+        /// number of events missed
+        missed: u64,
+    },
+}
+
+/// Stream of the events received by the subscriber. It ends when the
+/// controller is dropped.
+pub fn stream(rx: broadcast::Receiver<NetworkEvent>) -> impl Stream<Item = NetworkEvent> {
+    stream::unfold(rx, |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => NetworkEvent::Lagged { missed },
+            Err(RecvError::Closed) => return None,
+        };
+        Some((event, rx))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn should_report_missed_events_on_the_stream() {
+        let (tx, rx) = broadcast::channel(1);
+        let events = stream(rx);
+        let pong = |seq| NetworkEvent::Pong {
+            peer_id: Uuid::nil(),
+            seq,
+            rtt: 0,
+            size: 0,
+        };
+        tx.send(pong(1)).unwrap();
+        tx.send(pong(2)).unwrap();
+        drop(tx);
+        let events = events.collect::<Vec<_>>().await;
+        assert!(matches!(
+            events[..],
+            [
+                NetworkEvent::Lagged { missed: 1 },
+                NetworkEvent::Pong { seq: 2, .. }
+            ]
+        ));
+    }
+}
@@ -0,0 +1,61 @@
+//! Gossip.
+//!
+//! A gossip message is flooded through the network: each node publishes it
+//! to the application, and forwards it to all its other peers until its TTL
+//! is spent. A message reaches a node through several paths, so nodes
+//! remember the ids of the messages they have seen, and only handle the
+//! first copy.
+use std::collections::{HashSet, VecDeque};
+use uuid::Uuid;
+
+/// Ids of the gossip messages seen recently.
+#[derive(Debug)]
+pub struct Seen {
+    ids: HashSet<Uuid>,
+    order: VecDeque<Uuid>,
+    capacity: usize,
+}
+
+impl Seen {
+    /// Remember up to `capacity` ids.
+    pub fn new(capacity: usize) -> Seen {
+        Seen {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Remember the id, and return true if it was not seen yet. Beyond the
+    /// capacity, the oldest ids are forgotten.
+    pub fn insert(&mut self, id: Uuid) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_forget_the_oldest_ids() {
+        let mut seen = Seen::new(2);
+        let ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        assert!(seen.insert(ids[0]));
+        assert!(!seen.insert(ids[0]));
+        assert!(seen.insert(ids[1]));
+        assert!(seen.insert(ids[2]));
+        assert!(!seen.insert(ids[2]));
+        // The first id is forgotten, so a late copy is handled again.
+        assert!(seen.insert(ids[0]));
+    }
+}
@@ -0,0 +1,338 @@
+//! Identity of the node.
+//!
+//! Each node has an ed25519 key pair, and its id is derived from the public
+//! key. When the key is kept in a file, the node has the same id across
+//! restarts, so that the peer state keyed by id, such as bans, outlives a run.
+//!
+//! The connection request and response carry the public key of the sender,
+//! and its signature over the handshake transcript. A remote cannot claim an
+//! id without holding the key it derives from.
+//!
+//! A connection request also carries a nonce and the time it was sent. The
+//! accepting node refuses requests sent outside of the replay window, and
+//! those whose nonce it has seen within the window, so that a captured
+//! request cannot be played again.
+use ring::digest::{self, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::hex;
+
+/// Number of bytes in a seed, from which the key pair is derived.
+const SEED_LEN: usize = 32;
+
+/// Connection requests sent longer ago than this, or this far ahead of our
+/// clock, are refused.
+pub const REPLAY_WINDOW: Duration = Duration::from_secs(120);
+
+/// Number of nonces remembered within the replay window. Beyond it, requests
+/// are refused until older nonces leave the window, as forgetting a nonce
+/// would let its request be played again.
+pub const MAX_REPLAYS: usize = 65_536;
+
+/// The key pair of a node.
+#[derive(Clone)]
+pub struct Identity {
+    keypair: Arc<Ed25519KeyPair>,
+}
+
+/// Signature of a handshake message, as received from the remote.
+#[derive(Debug, Clone)]
+pub struct Proof {
+    /// Public key of the remote.
+    pub public_key: Vec<u8>,
+    /// Fields of the message, as signed by the remote.
+    pub transcript: Vec<u8>,
+    /// Signature of the remote.
+    pub signature: Vec<u8>,
+    /// Nonce of a request, and the time (micros) it was sent, which the
+    /// transcript covers.
+    pub freshness: Option<(u64, i64)>,
+    /// Ephemeral public key of the remote, from which the session keys are
+    /// derived, which the transcript covers.
+    pub key_share: Option<Vec<u8>>,
+}
+
+/// Nonces of the connection requests received within the replay window.
+#[derive(Debug, Default)]
+pub struct Replays {
+    // Time (micros) of each request, by remote id and nonce.
+    seen: Mutex<HashMap<(Uuid, u64), i64>>,
+}
+
+/// Error type for the identity configuration
+#[derive(Debug)]
+pub struct Error {
+    /// Error detail
+    pub detail: String,
+}
+
+impl Identity {
+    /// Create an identity with a new key pair.
+    pub fn new() -> Identity {
+        let mut seed = [0u8; SEED_LEN];
+        SystemRandom::new()
+            .fill(&mut seed)
+            .expect("system random number generator");
+        Identity::with_seed(&seed)
+    }
+
+    /// Create an identity with the seed read from a file, holding its 32 bytes
+    /// in hexadecimal. If the file does not exist, a new seed is written to it,
+    /// readable by its owner only. On unix, a file which the group or others
+    /// can read is refused, as anyone reading it can impersonate the node.
+    pub fn with_key_file(path: &Path) -> Result<Identity, Error> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let mut seed = [0u8; SEED_LEN];
+                SystemRandom::new()
+                    .fill(&mut seed)
+                    .expect("system random number generator");
+                write_key(path, &seed).map_err(|err| Error {
+                    detail: format!("Could not write {}: {err}", path.display()),
+                })?;
+                log::info!("Identity | New key written to {}", path.display());
+                return Ok(Identity::with_seed(&seed));
+            }
+            Err(err) => {
+                return Err(Error {
+                    detail: format!("Could not read {}: {err}", path.display()),
+                })
+            }
+        };
+        check_permissions(path)?;
+        let seed = hex::decode(content.trim())
+            .filter(|seed| seed.len() == SEED_LEN)
+            .ok_or_else(|| Error {
+                detail: format!(
+                    "{} does not hold a 32 bytes hexadecimal key",
+                    path.display()
+                ),
+            })?;
+        Ok(Identity::with_seed(&seed))
+    }
+
+    fn with_seed(seed: &[u8]) -> Identity {
+        let keypair = Ed25519KeyPair::from_seed_unchecked(seed).expect("32 bytes seed");
+        Identity {
+            keypair: Arc::new(keypair),
+        }
+    }
+
+    /// Id of the node, derived from its public key.
+    pub fn id(&self) -> Uuid {
+        id(self.public_key())
+    }
+
+    /// Public key of the node.
+    pub fn public_key(&self) -> &[u8] {
+        self.keypair.public_key().as_ref()
+    }
+
+    /// Sign the transcript of a handshake message, in the given context.
+    pub fn sign(&self, context: &[u8], transcript: &[u8]) -> Vec<u8> {
+        let message = [context, transcript].concat();
+        self.keypair.sign(&message).as_ref().to_vec()
+    }
+}
+
+// Write the seed to a new file, readable and writable by its owner only.
+fn write_key(path: &Path, seed: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    file.write_all(format!("{}\n", hex::encode(seed)).as_bytes())
+}
+
+// Refuse a key file which the group or others can read.
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<(), Error> {
+    let metadata = std::fs::metadata(path).map_err(|err| Error {
+        detail: format!("Could not read {}: {err}", path.display()),
+    })?;
+    let mode = metadata.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(Error {
+            detail: format!(
+                "{} is accessible by other users (mode {:o}), restrict it with 'chmod 600'",
+                path.display(),
+                mode & 0o777
+            ),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Identity::new()
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("public_key", &hex::encode(self.public_key()))
+            .finish()
+    }
+}
+
+impl Proof {
+    /// Returns true if the remote signed the transcript, in the given context,
+    /// and its id is the one derived from its public key.
+    pub fn verify(&self, id: Uuid, context: &[u8]) -> bool {
+        let message = [context, &self.transcript].concat();
+        id == self::id(&self.public_key)
+            && signature::UnparsedPublicKey::new(&signature::ED25519, &self.public_key)
+                .verify(&message, &self.signature)
+                .is_ok()
+    }
+}
+
+impl Replays {
+    /// Returns true if the request of the remote, with the given nonce and
+    /// time (micros), was sent within the replay window of `now` (micros),
+    /// and its nonce was not seen yet.
+    pub fn check(&self, id: Uuid, nonce: u64, timestamp: i64, now: i64) -> bool {
+        // The remote chooses the timestamp, which may be anywhere in the range.
+        let window = REPLAY_WINDOW.as_micros() as u64;
+        if now.abs_diff(timestamp) > window {
+            return false;
+        }
+        let mut seen = self.seen.lock().expect("replays lock");
+        // Requests older than the window are refused anyway, so their nonces
+        // are forgotten once the map is full.
+        if seen.len() >= MAX_REPLAYS {
+            seen.retain(|_, sent| *sent >= now || now.abs_diff(*sent) <= window);
+        }
+        if seen.len() >= MAX_REPLAYS && !seen.contains_key(&(id, nonce)) {
+            return false;
+        }
+        seen.insert((id, nonce), timestamp).is_none()
+    }
+}
+
+/// Id of the node with the given public key: the first 16 bytes of its hash.
+pub fn id(public_key: &[u8]) -> Uuid {
+    let hash = digest::digest(&SHA256, public_key);
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hash.as_ref()[..16]);
+    Uuid::from_bytes(bytes)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid identity configuration: {}", self.detail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_verify_signatures_of_the_holder_of_the_key() {
+        let alice = Identity::new();
+        let proof = Proof {
+            public_key: alice.public_key().to_vec(),
+            transcript: b"alice".to_vec(),
+            signature: alice.sign(b"request", b"alice"),
+            freshness: None,
+            key_share: None,
+        };
+        assert!(proof.verify(alice.id(), b"request"));
+        assert!(!proof.verify(alice.id(), b"response"));
+
+        // Mallory cannot claim the id of alice.
+        let mallory = Identity::new();
+        let forged = Proof {
+            public_key: mallory.public_key().to_vec(),
+            transcript: b"alice".to_vec(),
+            signature: mallory.sign(b"request", b"alice"),
+            freshness: None,
+            key_share: None,
+        };
+        assert!(!forged.verify(alice.id(), b"request"));
+        assert!(forged.verify(mallory.id(), b"request"));
+    }
+
+    #[test]
+    fn should_refuse_replayed_requests() {
+        let replays = Replays::default();
+        let id = Uuid::new_v4();
+        let now = 1_670_000_000_000_000;
+        let window = REPLAY_WINDOW.as_micros() as i64;
+        assert!(replays.check(id, 1, now, now));
+        assert!(!replays.check(id, 1, now, now + 1));
+        // Another remote may pick the same nonce.
+        assert!(replays.check(Uuid::new_v4(), 1, now, now + 1));
+        assert!(replays.check(id, 2, now - window, now));
+        // Stale requests, and those from the future, are refused.
+        assert!(!replays.check(id, 3, now - window - 1, now));
+        assert!(!replays.check(id, 4, now + window + 1, now));
+        assert!(!replays.check(id, 1, now, now + window + 1));
+        // Timestamps at the ends of the range are refused, without overflowing.
+        assert!(!replays.check(id, 5, i64::MIN, now));
+        assert!(!replays.check(id, 6, i64::MAX, now));
+        assert!(!replays.check(id, 7, i64::MIN, i64::MAX));
+        assert!(!replays.check(id, 8, i64::MAX, i64::MIN));
+    }
+
+    #[test]
+    fn should_bound_the_nonces_remembered() {
+        let replays = Replays::default();
+        let id = Uuid::new_v4();
+        let now = 1_670_000_000_000_000;
+        for nonce in 0..MAX_REPLAYS as u64 {
+            assert!(replays.check(id, nonce, now, now));
+        }
+        assert!(!replays.check(id, u64::MAX, now, now));
+        // Once the requests leave the window, their nonces are forgotten.
+        let later = now + REPLAY_WINDOW.as_micros() as i64 + 1;
+        assert!(replays.check(id, u64::MAX, later, later));
+    }
+
+    #[test]
+    fn should_keep_the_identity_in_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node.identity");
+        let first = Identity::with_key_file(&path).unwrap();
+        let second = Identity::with_key_file(&path).unwrap();
+        assert_eq!(first.id(), second.id());
+
+        std::fs::write(&path, "not a key").unwrap();
+        assert!(Identity::with_key_file(&path).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_keep_the_key_file_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node.identity");
+        Identity::with_key_file(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // A key which other users can read is refused.
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(Identity::with_key_file(&path).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Artificial impairment of the send path.
+//!
+//! Operators can rehearse WAN conditions in a LAN staging environment by
+//! delaying (with some jitter) and dropping the messages sent to some peers,
+//! selected by address or by network.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use tokio::time::Duration;
+
+use super::allowlist::{self, AllowList};
+
+/// Configuration of an impairment rule.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Rule {
+    /// Addresses or networks (eg '10.0.0.0/8') of the peers this rule applies to.
+    /// A rule without peers applies to every peer.
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// Delay before sending each message (milliseconds).
+    #[serde(default)]
+    pub delay: u64,
+    /// Largest deviation from the delay, drawn uniformly for each message (milliseconds).
+    #[serde(default)]
+    pub jitter: u64,
+    /// Probability of dropping each message, between 0 and 1.
+    #[serde(default)]
+    pub drop: f64,
+}
+
+/// Impairment applied to the messages sent to a peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    /// Delay (milliseconds)
+    pub delay: u64,
+    /// Jitter (milliseconds)
+    pub jitter: u64,
+    /// Drop probability
+    pub drop: f64,
+}
+
+/// Impairment rules, matched in order against the address of a peer.
+#[derive(Debug, Clone, Default)]
+pub struct Impairments {
+    rules: Vec<(AllowList, Settings)>,
+}
+
+/// Error type for impairment rules
+#[derive(Debug)]
+pub enum Error {
+    /// A peer address or network could not be parsed
+    InvalidPeer {
+        /// Source
+        source: allowlist::Error,
+    },
+    /// The drop probability is not between 0 and 1
+    InvalidDrop {
+        /// Error detail
+        detail: String,
+    },
+}
+
+impl Settings {
+    /// Draw the fate of a message: None if it is dropped, otherwise
+    /// the delay to wait before sending it.
+    pub fn sample(&self) -> Option<Duration> {
+        if self.drop > 0.0 && fastrand::f64() < self.drop {
+            return None;
+        }
+        let offset = fastrand::u64(0..=self.jitter.saturating_mul(2));
+        let delay = self
+            .delay
+            .saturating_add(offset)
+            .saturating_sub(self.jitter);
+        Some(Duration::from_millis(delay))
+    }
+}
+
+impl Impairments {
+    /// Build the impairment rules from their configuration.
+    pub fn new(rules: &[Rule]) -> Result<Impairments, Error> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                if !(0.0..=1.0).contains(&rule.drop) {
+                    return Err(Error::InvalidDrop {
+                        detail: format!("{} is not between 0 and 1", rule.drop),
+                    });
+                }
+                let peers = AllowList::new(&rule.peers)
+                    .map_err(|err| Error::InvalidPeer { source: err })?;
+                let settings = Settings {
+                    delay: rule.delay,
+                    jitter: rule.jitter,
+                    drop: rule.drop,
+                };
+                Ok((peers, settings))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Impairments { rules })
+    }
+
+    /// Returns the settings of the first rule matching the address, if any.
+    pub fn lookup(&self, addr: &IpAddr) -> Option<Settings> {
+        self.rules
+            .iter()
+            .find(|(peers, _)| peers.allows(addr))
+            .map(|(_, settings)| *settings)
+    }
+
+    /// Returns true if there are no rules.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidPeer { source } => write!(f, "Invalid impairment peer: {}", source),
+            Error::InvalidDrop { detail } => {
+                write!(f, "Invalid impairment drop probability: {}", detail)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(peers: &[&str], delay: u64, drop: f64) -> Rule {
+        Rule {
+            peers: peers.iter().map(|peer| peer.to_string()).collect(),
+            delay,
+            jitter: 0,
+            drop,
+        }
+    }
+
+    #[test]
+    fn should_apply_the_first_matching_rule() {
+        let impairments =
+            Impairments::new(&[rule(&["10.0.0.1"], 10, 0.0), rule(&["10.0.0.0/8"], 20, 0.0)])
+                .unwrap();
+        let lookup = |addr: &str| impairments.lookup(&addr.parse().unwrap()).map(|s| s.delay);
+        assert_eq!(lookup("10.0.0.1"), Some(10));
+        assert_eq!(lookup("10.1.2.3"), Some(20));
+        assert_eq!(lookup("192.168.0.1"), None);
+
+        let impairments = Impairments::new(&[rule(&[], 30, 0.0)]).unwrap();
+        assert_eq!(
+            impairments.lookup(&"::1".parse().unwrap()).unwrap().delay,
+            30
+        );
+    }
+
+    #[test]
+    fn should_sample_delays_within_jitter() {
+        let settings = Settings {
+            delay: 5,
+            jitter: 10,
+            drop: 0.0,
+        };
+        for _ in 0..100 {
+            let delay = settings.sample().unwrap();
+            assert!(delay <= Duration::from_millis(15));
+        }
+        let settings = Settings {
+            drop: 1.0,
+            ..settings
+        };
+        assert_eq!(settings.sample(), None);
+    }
+
+    #[test]
+    fn should_reject_invalid_rules() {
+        assert!(matches!(
+            Impairments::new(&[rule(&["10.0.0.0/33"], 0, 0.0)]),
+            Err(Error::InvalidPeer { .. })
+        ));
+        assert!(matches!(
+            Impairments::new(&[rule(&[], 0, 1.5)]),
+            Err(Error::InvalidDrop { .. })
+        ));
+    }
+}
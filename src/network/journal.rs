@@ -0,0 +1,1013 @@
+//! Append-only journal of the network controller's events and decisions.
+//!
+//! Each line of the journal is a JSON entry with a sequence number. The
+//! journal records the events received from the peers, the connection
+//! attempts started by the controller, and the commands it sends to the
+//! peers in response to events. Replaying a journal feeds the recorded
+//! events back through the controller in a dry-run (no peer is started,
+//! no command is sent), and reports where the commands the controller
+//! decides on differ from the recorded ones. This way state management bugs
+//! seen in production can be reproduced from the journal.
+//!
+//! Entries are written to the file by a thread of their own, and flushed by
+//! batches, so that recording does not block the controller's tasks. The
+//! application data of payloads and gossip is recorded as its size and
+//! digest, unless the journal section asks for the data itself.
+use bytes::Bytes;
+use chrono::Utc;
+use ring::digest::{self, SHA256};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tokio::sync::Mutex as AsyncMutex;
+use uuid::Uuid;
+
+use super::controller::{AddrInfo, InConnInfo, NetworkController};
+use super::event::Event;
+use super::peer::PeerState;
+use super::policy::Tags;
+use super::relay::RelayMessage;
+use super::snapshot;
+use crate::hex;
+use crate::message::conn_rejection::RejectReason;
+use crate::message::dht::Node;
+use crate::message::{Contact, Health, InfoResponse};
+
+/// Number of entries waiting for the writer thread. Beyond it, entries are
+/// dropped rather than holding the controller back.
+const MAX_PENDING_ENTRIES: usize = 4096;
+
+/// A journal entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Sequence number, increasing across the whole journal.
+    pub seq: u64,
+    /// Time the entry was recorded (μs since epoch)
+    pub timestamp: i64,
+    /// What happened
+    pub record: Record,
+}
+
+/// What the journal records.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Record {
+    /// The controller started.
+    Start {
+        /// id of the controller
+        id: Uuid,
+        /// label of the controller
+        label: String,
+        /// address the controller listens on
+        addr: SocketAddr,
+    },
+    /// The controller received an event from a peer.
+    Event {
+        /// the event
+        event: EventRecord,
+    },
+    /// The controller started a peer to connect to an address.
+    Attempt {
+        /// id of the peer
+        peer: Uuid,
+        /// address the peer connects to
+        addr: SocketAddr,
+        /// number of attempts, including this one
+        attempt: i32,
+    },
+    /// The controller sent a command to a peer.
+    Command {
+        /// id of the peer
+        peer: Uuid,
+        /// command (eg 'connection request')
+        command: String,
+    },
+}
+
+/// The journaled form of an Event. Errors are kept as their description.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventRecord {
+    /// See Event::BindError
+    BindError {
+        /// source
+        source: String,
+        /// addr
+        addr: SocketAddr,
+    },
+    /// See Event::InvalidState
+    InvalidState {
+        /// id of the peer
+        id: Uuid,
+        /// expected states
+        expected: Vec<PeerState>,
+        /// actual state
+        actual: PeerState,
+    },
+    /// See Event::Connected
+    Connected {
+        /// id of the peer
+        id: Uuid,
+    },
+    /// See Event::Listening
+    Listening {
+        /// id of the peer
+        id: Uuid,
+    },
+    /// See Event::OutAlive
+    OutAlive {
+        /// id of the peer
+        id: Uuid,
+        /// remote id
+        peer_id: Uuid,
+        /// remote label
+        peer_label: String,
+        /// remote address
+        peer_addr: SocketAddr,
+    },
+    /// See Event::InAlive
+    InAlive {
+        /// id of the peer
+        id: Uuid,
+        /// remote id
+        peer_id: Uuid,
+        /// remote label
+        peer_label: String,
+        /// remote address
+        peer_addr: SocketAddr,
+    },
+    /// See Event::AddrObserved
+    AddrObserved {
+        /// id of the peer
+        id: Uuid,
+        /// remote id
+        peer_id: Uuid,
+        /// our address, as seen by the remote peer
+        addr: SocketAddr,
+    },
+    /// See Event::ConnectionError
+    ConnectionError {
+        /// id of the peer
+        id: Uuid,
+        /// address we tried to connect to
+        addr: SocketAddr,
+        /// error
+        source: String,
+    },
+    /// See Event::ConnectionUpdate
+    ConnectionUpdate {
+        /// id of the peer
+        id: Uuid,
+        /// round trip time
+        rtt: i64,
+        /// clock offset of the remote. Older journals don't have it.
+        #[serde(default)]
+        offset: i64,
+    },
+    /// See Event::HealthUpdate
+    HealthUpdate {
+        /// id of the peer
+        id: Uuid,
+        /// health of the remote node
+        health: Health,
+    },
+    /// See Event::ContactRequested
+    ContactRequested {
+        /// id of the peer
+        id: Uuid,
+    },
+    /// See Event::ContactUpdated
+    ContactUpdated {
+        /// id of the peer
+        id: Uuid,
+        /// list of addresses.
+        addrs: Vec<SocketAddr>,
+        /// tags of each address.
+        #[serde(default)]
+        tags: Vec<Tags>,
+        /// node at each address.
+        #[serde(default)]
+        contacts: Vec<Contact>,
+    },
+    /// See Event::Relay
+    Relay {
+        /// id of the peer
+        id: Uuid,
+        /// relay message
+        message: RelayMessage,
+    },
+    /// See Event::Pong
+    Pong {
+        /// id of the peer
+        id: Uuid,
+        /// sequence number of the ping
+        seq: u64,
+        /// round trip time (μs)
+        rtt: i64,
+        /// number of bytes echoed
+        size: usize,
+    },
+    /// See Event::Payload
+    Payload {
+        /// id of the peer
+        id: Uuid,
+        /// topic of the data.
+        #[serde(default)]
+        topic: String,
+        /// size of the application data (bytes)
+        #[serde(default)]
+        size: usize,
+        /// SHA-256 digest of the application data, in hex
+        #[serde(default)]
+        digest: String,
+        /// application data, if the journal records it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        data: Option<Bytes>,
+    },
+    /// See Event::Gossip
+    Gossip {
+        /// id of the peer
+        id: Uuid,
+        /// id of the message
+        msg_id: Uuid,
+        /// number of hops left
+        ttl: u64,
+        /// topic of the data
+        topic: String,
+        /// size of the application data (bytes)
+        #[serde(default)]
+        size: usize,
+        /// SHA-256 digest of the application data, in hex
+        #[serde(default)]
+        digest: String,
+        /// application data, if the journal records it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        data: Option<Bytes>,
+    },
+    /// See Event::UnknownMessage. The fields of the message are not
+    /// recorded: the controller only forwards them.
+    UnknownMessage {
+        /// id of the peer
+        id: Uuid,
+        /// tag of the message
+        tag: String,
+    },
+    /// See Event::InfoRequested
+    InfoRequested {
+        /// id of the peer
+        id: Uuid,
+    },
+    /// See Event::Info
+    Info {
+        /// id of the peer
+        id: Uuid,
+        /// information of the remote node
+        info: InfoResponse,
+    },
+    /// See Event::FindNode
+    FindNode {
+        /// id of the peer
+        id: Uuid,
+        /// id looked up
+        target: Uuid,
+    },
+    /// See Event::Nodes
+    Nodes {
+        /// id of the peer
+        id: Uuid,
+        /// id looked up
+        target: Uuid,
+        /// nodes, closest first
+        nodes: Vec<Node>,
+    },
+    /// See Event::ProtocolViolation
+    ProtocolViolation {
+        /// id of the peer
+        id: Uuid,
+        /// what was wrong with the frame
+        detail: String,
+    },
+    /// See Event::TimedOut
+    TimedOut {
+        /// id of the peer
+        id: Uuid,
+    },
+    /// See Event::Terminated
+    Terminated {
+        /// id of the peer
+        id: Uuid,
+    },
+    /// See Event::Rejected
+    Rejected {
+        /// id of the peer
+        id: Uuid,
+        /// address the peer tried to connect to.
+        addr: SocketAddr,
+        /// why the remote refused the connection
+        reason: RejectReason,
+    },
+    /// See Event::Disconnected
+    Disconnected {
+        /// id of the peer
+        id: Uuid,
+        /// address the peer was connected to.
+        addr: SocketAddr,
+    },
+}
+
+impl From<&Event> for EventRecord {
+    fn from(event: &Event) -> Self {
+        match event {
+            Event::BindError { source, addr } => EventRecord::BindError {
+                source: source.to_string(),
+                addr: *addr,
+            },
+            Event::InvalidState {
+                id,
+                expected,
+                actual,
+            } => EventRecord::InvalidState {
+                id: *id,
+                expected: expected.clone(),
+                actual: *actual,
+            },
+            Event::Connected { id } => EventRecord::Connected { id: *id },
+            Event::Listening { id } => EventRecord::Listening { id: *id },
+            Event::OutAlive {
+                id,
+                peer_id,
+                peer_label,
+                peer_addr,
+            } => EventRecord::OutAlive {
+                id: *id,
+                peer_id: *peer_id,
+                peer_label: peer_label.clone(),
+                peer_addr: *peer_addr,
+            },
+            Event::InAlive {
+                id,
+                peer_id,
+                peer_label,
+                peer_addr,
+            } => EventRecord::InAlive {
+                id: *id,
+                peer_id: *peer_id,
+                peer_label: peer_label.clone(),
+                peer_addr: *peer_addr,
+            },
+            Event::AddrObserved { id, peer_id, addr } => EventRecord::AddrObserved {
+                id: *id,
+                peer_id: *peer_id,
+                addr: *addr,
+            },
+            Event::ConnectionError { id, addr, source } => EventRecord::ConnectionError {
+                id: *id,
+                addr: *addr,
+                source: source.to_string(),
+            },
+            Event::ConnectionUpdate { id, rtt, offset } => EventRecord::ConnectionUpdate {
+                id: *id,
+                rtt: *rtt,
+                offset: *offset,
+            },
+            Event::HealthUpdate { id, health } => EventRecord::HealthUpdate {
+                id: *id,
+                health: *health,
+            },
+            Event::ContactRequested { id } => EventRecord::ContactRequested { id: *id },
+            Event::ContactUpdated {
+                id,
+                addrs,
+                tags,
+                contacts,
+            } => EventRecord::ContactUpdated {
+                id: *id,
+                addrs: addrs.clone(),
+                tags: tags.clone(),
+                contacts: contacts.clone(),
+            },
+            Event::Relay { id, message } => EventRecord::Relay {
+                id: *id,
+                message: message.clone(),
+            },
+            Event::Pong { id, seq, rtt, size } => EventRecord::Pong {
+                id: *id,
+                seq: *seq,
+                rtt: *rtt,
+                size: *size,
+            },
+            Event::Payload { id, topic, data } => EventRecord::Payload {
+                id: *id,
+                topic: topic.clone(),
+                size: data.len(),
+                digest: hex_digest(data),
+                data: Some(data.clone()),
+            },
+            Event::Gossip {
+                id,
+                msg_id,
+                ttl,
+                topic,
+                data,
+            } => EventRecord::Gossip {
+                id: *id,
+                msg_id: *msg_id,
+                ttl: *ttl,
+                topic: topic.clone(),
+                size: data.len(),
+                digest: hex_digest(data),
+                data: Some(data.clone()),
+            },
+            Event::UnknownMessage { id, tag, .. } => EventRecord::UnknownMessage {
+                id: *id,
+                tag: tag.clone(),
+            },
+            Event::InfoRequested { id } => EventRecord::InfoRequested { id: *id },
+            Event::Info { id, info } => EventRecord::Info {
+                id: *id,
+                info: (**info).clone(),
+            },
+            Event::FindNode { id, target } => EventRecord::FindNode {
+                id: *id,
+                target: *target,
+            },
+            Event::Nodes { id, target, nodes } => EventRecord::Nodes {
+                id: *id,
+                target: *target,
+                nodes: nodes.clone(),
+            },
+            Event::ProtocolViolation { id, detail } => EventRecord::ProtocolViolation {
+                id: *id,
+                detail: detail.clone(),
+            },
+            Event::TimedOut { id } => EventRecord::TimedOut { id: *id },
+            Event::Terminated { id } => EventRecord::Terminated { id: *id },
+            Event::Rejected { id, addr, reason } => EventRecord::Rejected {
+                id: *id,
+                addr: *addr,
+                reason: *reason,
+            },
+            Event::Disconnected { id, addr } => EventRecord::Disconnected {
+                id: *id,
+                addr: *addr,
+            },
+        }
+    }
+}
+
+impl From<EventRecord> for Event {
+    fn from(record: EventRecord) -> Self {
+        match record {
+            EventRecord::BindError { source, addr } => Event::BindError {
+                source: std::io::Error::other(source),
+                addr,
+            },
+            EventRecord::InvalidState {
+                id,
+                expected,
+                actual,
+            } => Event::InvalidState {
+                id,
+                expected,
+                actual,
+            },
+            EventRecord::Connected { id } => Event::Connected { id },
+            EventRecord::Listening { id } => Event::Listening { id },
+            EventRecord::OutAlive {
+                id,
+                peer_id,
+                peer_label,
+                peer_addr,
+            } => Event::OutAlive {
+                id,
+                peer_id,
+                peer_label,
+                peer_addr,
+            },
+            EventRecord::InAlive {
+                id,
+                peer_id,
+                peer_label,
+                peer_addr,
+            } => Event::InAlive {
+                id,
+                peer_id,
+                peer_label,
+                peer_addr,
+            },
+            EventRecord::AddrObserved { id, peer_id, addr } => {
+                Event::AddrObserved { id, peer_id, addr }
+            }
+            EventRecord::ConnectionError { id, addr, source } => Event::ConnectionError {
+                id,
+                addr,
+                source: std::io::Error::other(source),
+            },
+            EventRecord::ConnectionUpdate { id, rtt, offset } => {
+                Event::ConnectionUpdate { id, rtt, offset }
+            }
+            EventRecord::HealthUpdate { id, health } => Event::HealthUpdate { id, health },
+            EventRecord::ContactRequested { id } => Event::ContactRequested { id },
+            EventRecord::ContactUpdated {
+                id,
+                addrs,
+                tags,
+                contacts,
+            } => Event::ContactUpdated {
+                id,
+                addrs,
+                tags,
+                contacts,
+            },
+            EventRecord::Relay { id, message } => Event::Relay { id, message },
+            EventRecord::Pong { id, seq, rtt, size } => Event::Pong { id, seq, rtt, size },
+            // Without the data, the events are replayed with empty data.
+            EventRecord::Payload {
+                id, topic, data, ..
+            } => Event::Payload {
+                id,
+                topic,
+                data: data.unwrap_or_default(),
+            },
+            EventRecord::Gossip {
+                id,
+                msg_id,
+                ttl,
+                topic,
+                data,
+                ..
+            } => Event::Gossip {
+                id,
+                msg_id,
+                ttl,
+                topic,
+                data: data.unwrap_or_default(),
+            },
+            EventRecord::UnknownMessage { id, tag } => Event::UnknownMessage {
+                id,
+                frame: crate::frame![tag.clone()],
+                tag,
+            },
+            EventRecord::InfoRequested { id } => Event::InfoRequested { id },
+            EventRecord::Info { id, info } => Event::Info {
+                id,
+                info: Box::new(info),
+            },
+            EventRecord::FindNode { id, target } => Event::FindNode { id, target },
+            EventRecord::Nodes { id, target, nodes } => Event::Nodes { id, target, nodes },
+            EventRecord::ProtocolViolation { id, detail } => {
+                Event::ProtocolViolation { id, detail }
+            }
+            EventRecord::TimedOut { id } => Event::TimedOut { id },
+            EventRecord::Terminated { id } => Event::Terminated { id },
+            EventRecord::Rejected { id, addr, reason } => Event::Rejected { id, addr, reason },
+            EventRecord::Disconnected { id, addr } => Event::Disconnected { id, addr },
+        }
+    }
+}
+
+impl EventRecord {
+    // Drop the application data, which is still told by its size and digest.
+    fn without_data(mut self) -> EventRecord {
+        if let EventRecord::Payload { data, .. } | EventRecord::Gossip { data, .. } = &mut self {
+            *data = None;
+        }
+        self
+    }
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    hex::encode(digest::digest(&SHA256, data).as_ref())
+}
+
+/// Where the journal entries go.
+#[derive(Debug)]
+enum Sink {
+    /// Handed over to the writer thread, which appends them to a file, one
+    /// JSON entry per line.
+    File(SyncSender<Entry>),
+    /// Kept in memory. This is the dry-run mode used by the replay.
+    Memory(Vec<Entry>),
+}
+
+#[derive(Debug)]
+struct Inner {
+    seq: u64,
+    sink: Sink,
+}
+
+/// The journal. It is shared by the controller's threads.
+#[derive(Debug)]
+pub struct Journal {
+    inner: Mutex<Inner>,
+    writer: Option<JoinHandle<()>>,
+    payloads: bool,
+}
+
+impl Journal {
+    /// Open the journal file for appending. The sequence numbers continue
+    /// from the last entry in the file.
+    pub fn open(path: &Path) -> Result<Journal, Error> {
+        let seq = match File::open(path) {
+            Ok(file) => last_seq(file).map_err(|err| Error::IO {
+                source: err,
+                detail: format!("Could not read journal {}", path.display()),
+            })?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(err) => {
+                return Err(Error::IO {
+                    source: err,
+                    detail: format!("Could not open journal {}", path.display()),
+                })
+            }
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| Error::IO {
+                source: err,
+                detail: format!("Could not open journal {}", path.display()),
+            })?;
+        let (tx, rx) = mpsc::sync_channel(MAX_PENDING_ENTRIES);
+        let writer = thread::Builder::new()
+            .name("journal".to_owned())
+            .spawn(move || write(file, rx))
+            .map_err(|err| Error::IO {
+                source: err,
+                detail: "Could not start the journal writer".to_owned(),
+            })?;
+        Ok(Journal {
+            inner: Mutex::new(Inner {
+                seq,
+                sink: Sink::File(tx),
+            }),
+            writer: Some(writer),
+            payloads: false,
+        })
+    }
+
+    /// A journal kept in memory, for dry-runs.
+    pub fn dry_run() -> Journal {
+        Journal {
+            inner: Mutex::new(Inner {
+                seq: 0,
+                sink: Sink::Memory(Vec::new()),
+            }),
+            writer: None,
+            payloads: false,
+        }
+    }
+
+    /// Record the application data of payloads and gossip, rather than only
+    /// its size and digest.
+    pub fn with_payloads(mut self, payloads: bool) -> Journal {
+        self.payloads = payloads;
+        self
+    }
+
+    /// Returns true if this is a dry-run journal: the controller must then
+    /// not act on its decisions.
+    pub fn is_dry_run(&self) -> bool {
+        matches!(self.inner.lock().unwrap().sink, Sink::Memory(_))
+    }
+
+    /// Append a record to the journal.
+    /// The journal is a debugging aid, so a failure to write is logged, but
+    /// does not interrupt the controller.
+    pub fn record(&self, record: Record) {
+        let record = match record {
+            Record::Event { event } if !self.payloads => Record::Event {
+                event: event.without_data(),
+            },
+            record => record,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        inner.seq += 1;
+        let entry = Entry {
+            seq: inner.seq,
+            timestamp: Utc::now().timestamp_micros(),
+            record,
+        };
+        match &mut inner.sink {
+            Sink::File(tx) => match tx.try_send(entry) {
+                Ok(()) => {}
+                Err(TrySendError::Full(entry)) => log::warn!(
+                    "Controller | Dropping journal entry {} | Writer is behind",
+                    entry.seq
+                ),
+                Err(TrySendError::Disconnected(entry)) => log::warn!(
+                    "Controller | Dropping journal entry {} | Writer stopped",
+                    entry.seq
+                ),
+            },
+            Sink::Memory(entries) => entries.push(entry),
+        }
+    }
+
+    /// Take the entries recorded in memory since the last call.
+    fn take(&self) -> Vec<Entry> {
+        match &mut self.inner.lock().unwrap().sink {
+            Sink::File(_) => Vec::new(),
+            Sink::Memory(entries) => std::mem::take(entries),
+        }
+    }
+}
+
+/// The entries waiting are written once the writer stops: the journal is
+/// complete once dropped.
+impl Drop for Journal {
+    fn drop(&mut self) {
+        if let Ok(inner) = self.inner.get_mut() {
+            inner.sink = Sink::Memory(Vec::new());
+        }
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+// Append the entries to the file until the journal is dropped. The entries
+// waiting are written together, and flushed once.
+fn write(file: File, rx: Receiver<Entry>) {
+    let mut writer = BufWriter::new(file);
+    while let Ok(entry) = rx.recv() {
+        for entry in std::iter::once(entry).chain(rx.try_iter()) {
+            let res = serde_json::to_writer(&mut writer, &entry)
+                .map_err(std::io::Error::from)
+                .and_then(|_| writeln!(writer));
+            if let Err(err) = res {
+                log::warn!(
+                    "Controller | Could not write journal entry {} | {err}",
+                    entry.seq
+                );
+            }
+        }
+        if let Err(err) = writer.flush() {
+            log::warn!("Controller | Could not flush the journal | {err}");
+        }
+    }
+}
+
+fn last_seq(file: File) -> Result<u64, std::io::Error> {
+    let mut seq = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        // A line truncated by a crash is skipped.
+        if let Ok(entry) = serde_json::from_str::<Entry>(&line) {
+            seq = entry.seq;
+        }
+    }
+    Ok(seq)
+}
+
+/// Read all the entries of a journal.
+pub fn read(path: &Path) -> Result<Vec<Entry>, Error> {
+    let file = File::open(path).map_err(|err| Error::IO {
+        source: err,
+        detail: format!("Could not open journal {}", path.display()),
+    })?;
+    let mut entries = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| Error::IO {
+            source: err,
+            detail: format!("Could not read journal {}", path.display()),
+        })?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line).map_err(|err| Error::Deserialize {
+            source: err,
+            detail: format!("Invalid entry at line {}", n + 1),
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// An event after which the controller, replaying the journal, did not
+/// send the same commands as recorded.
+#[derive(Debug, Clone, Serialize)]
+pub struct Divergence {
+    /// Sequence number of the event
+    pub seq: u64,
+    /// The event
+    pub event: EventRecord,
+    /// Commands recorded in the journal
+    pub expected: Vec<Record>,
+    /// Commands sent by the controller during the replay
+    pub actual: Vec<Record>,
+}
+
+/// Outcome of a replay.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// Number of events replayed
+    pub events: usize,
+    /// Events after which the controller's decisions differ from the journal
+    pub divergences: Vec<Divergence>,
+    /// State of the controller at the end of the replay
+    pub state: snapshot::State,
+}
+
+/// Replay the journal entries through the controller, in a dry-run.
+/// Events are handled by the controller as if they came from peers, and
+/// connection attempts are restored as recorded, since they are driven by a
+/// timer rather than by events. A start entry resets the controller's state,
+/// and its identity to the journaled one.
+pub async fn replay(mut controller: NetworkController, entries: Vec<Entry>) -> Report {
+    let journal = Arc::new(Journal::dry_run());
+    controller.journal = Some(journal.clone());
+    let mut report_events = 0;
+    let mut divergences = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        match &entry.record {
+            Record::Start { id, label, addr } => {
+                controller.id = *id;
+                controller.label = label.clone();
+                controller.addr = *addr;
+                controller.incoming.lock().await.connected.clear();
+                let mut outgoing = controller.outgoing.lock().await;
+                outgoing.attempting.clear();
+                outgoing.connected.clear();
+                controller.idle.lock().await.addrs.clear();
+            }
+            Record::Attempt {
+                peer,
+                addr,
+                attempt,
+            } => {
+                controller
+                    .idle
+                    .lock()
+                    .await
+                    .addrs
+                    .retain(|info| info.addr != *addr);
+                controller.outgoing.lock().await.attempting.insert(
+                    *peer,
+                    AddrInfo {
+                        addr: *addr,
+                        attempt: Arc::new(AsyncMutex::new(*attempt)),
+                        tags: Tags::new(),
+                    },
+                );
+            }
+            Record::Event { event } => {
+                report_events += 1;
+                if let Err(err) = controller.handle_event(event.clone().into()).await {
+                    log::warn!("Replay | Event {} | {err}", entry.seq);
+                }
+                let actual = journal
+                    .take()
+                    .into_iter()
+                    .map(|entry| entry.record)
+                    .filter(|record| matches!(record, Record::Command { .. }))
+                    .collect::<Vec<_>>();
+                // The commands sent in response to an event are recorded before
+                // the next event. Connection attempts may be interleaved.
+                let expected = entries[index + 1..]
+                    .iter()
+                    .map(|entry| &entry.record)
+                    .take_while(|record| {
+                        !matches!(record, Record::Event { .. } | Record::Start { .. })
+                    })
+                    .filter(|record| matches!(record, Record::Command { .. }))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if actual != expected {
+                    divergences.push(Divergence {
+                        seq: entry.seq,
+                        event: event.clone(),
+                        expected,
+                        actual,
+                    });
+                }
+            }
+            Record::Command { .. } => {
+                // Compared with the commands sent in response to the previous event.
+            }
+        }
+    }
+    let state = snapshot::take(
+        InConnInfo {
+            addr: controller.addr,
+            id: controller.id,
+            label: controller.label.clone(),
+            health: None,
+        },
+        &controller.incoming,
+        &controller.outgoing,
+        &controller.idle,
+        &controller.metrics,
+    )
+    .await;
+    Report {
+        events: report_events,
+        divergences,
+        state,
+    }
+}
+
+/// Error type for the journal
+#[derive(Debug)]
+pub enum Error {
+    /// IO Error
+    IO {
+        /// Source
+        source: std::io::Error,
+        /// Error detail
+        detail: String,
+    },
+    /// A journal entry could not be deserialized
+    Deserialize {
+        /// Source
+        source: serde_json::Error,
+        /// Error detail
+        detail: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IO { source, detail } => write!(f, "IO Error => {} [{}]", detail, source),
+            Error::Deserialize { source, detail } => {
+                write!(f, "Invalid journal => {} [{}]", detail, source)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_continue_sequence_across_opens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+        let id = Uuid::new_v4();
+        Journal::open(&path).unwrap().record(Record::Event {
+            event: EventRecord::Connected { id },
+        });
+        Journal::open(&path).unwrap().record(Record::Command {
+            peer: id,
+            command: "connection request".to_owned(),
+        });
+
+        let entries = read(&path).unwrap();
+        assert_eq!(
+            entries.iter().map(|entry| entry.seq).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            entries[0].record,
+            Record::Event {
+                event: EventRecord::Connected { id }
+            }
+        );
+    }
+
+    #[test]
+    fn should_record_the_digest_of_payloads_unless_asked_for_the_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+        let id = Uuid::new_v4();
+        let event = Event::Payload {
+            id,
+            topic: "news".to_owned(),
+            data: Bytes::from_static(b"hello"),
+        };
+        Journal::open(&path).unwrap().record(Record::Event {
+            event: EventRecord::from(&event),
+        });
+        let journal = Journal::open(&path).unwrap().with_payloads(true);
+        journal.record(Record::Event {
+            event: EventRecord::from(&event),
+        });
+        drop(journal);
+
+        let entries = read(&path).unwrap();
+        let digest = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        for (entry, data) in entries
+            .iter()
+            .zip([None, Some(Bytes::from_static(b"hello"))])
+        {
+            let expected = EventRecord::Payload {
+                id,
+                topic: "news".to_owned(),
+                size: 5,
+                digest: digest.to_owned(),
+                data,
+            };
+            assert_eq!(entry.record, Record::Event { event: expected });
+        }
+        assert_eq!(entries.len(), 2);
+    }
+}
@@ -0,0 +1,286 @@
+//! Local network discovery, with multicast DNS (RFC 6762).
+//!
+//! Each node announces its service instance on the LAN, periodically and when
+//! asked, and listens for the announcements of the other nodes, so that the
+//! nodes of a local cluster find each other without a target file. A node is
+//! announced with three records:
+//! - PTR `_area-net._tcp.local` to its instance, `<id>._area-net._tcp.local`,
+//! - SRV of its instance, with the port it listens on,
+//! - TXT of its instance, with its controller id.
+//!
+//! The address of a node is the source IP of its announcement, with the port
+//! of its SRV record.
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::Sender;
+use tokio::time::{self, Duration};
+use uuid::Uuid;
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE: &str = "_area-net._tcp.local";
+
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+// Authoritative answer.
+const FLAGS_RESPONSE: u16 = 0x8400;
+// Time to live of the records, in seconds.
+const TTL: u32 = 120;
+const HEADER_LEN: usize = 12;
+// Bound on the compression pointers followed in a name.
+const MAX_JUMPS: usize = 16;
+
+/// What a multicast DNS message tells about area-net nodes.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Parsed {
+    /// The message asks for the area-net nodes.
+    pub query: bool,
+    /// The message announces the node with this controller id, listening on
+    /// this port.
+    pub node: Option<(Uuid, u16)>,
+}
+
+/// Announce the node with the given id, listening on the given port, every
+/// `period` and when asked, and send the ids and addresses of the other nodes
+/// announced on the LAN to `found`. Returns on a socket error, or when the
+/// receiving end of `found` is dropped.
+pub async fn serve(
+    id: Uuid,
+    port: u16,
+    period: Duration,
+    found: Sender<(Uuid, SocketAddr)>,
+) -> io::Result<()> {
+    let socket = bind()?;
+    let group = SocketAddr::from((MDNS_ADDR, MDNS_PORT));
+    let announcement = announcement(id, port);
+    socket.send_to(&query(), group).await?;
+    let mut interval = time::interval(period);
+    let mut buf = [0u8; 9000];
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                socket.send_to(&announcement, group).await?;
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (len, from) = received?;
+                let parsed = parse(&buf[..len]).unwrap_or_default();
+                if parsed.query {
+                    socket.send_to(&announcement, group).await?;
+                }
+                match parsed.node {
+                    Some((node, port)) if node != id => {
+                        let addr = SocketAddr::new(from.ip(), port);
+                        if found.send((node, addr)).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+// A socket on the mDNS port, which other responders on the host may share,
+// member of the mDNS group. Our own datagrams loop back, so that the nodes of
+// a host find each other.
+fn bind() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Query for the area-net nodes of the LAN.
+pub fn query() -> Vec<u8> {
+    let mut message = header(0, 1, 0);
+    write_name(&mut message, SERVICE);
+    message.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    message
+}
+
+/// Announcement of the node with the given id, listening on the given port.
+pub fn announcement(id: Uuid, port: u16) -> Vec<u8> {
+    let instance = format!("{}.{SERVICE}", id.simple());
+    let mut message = header(FLAGS_RESPONSE, 0, 3);
+
+    let mut ptr = Vec::new();
+    write_name(&mut ptr, &instance);
+    write_record(&mut message, SERVICE, TYPE_PTR, &ptr);
+
+    let mut srv = Vec::new();
+    // Priority and weight.
+    srv.extend_from_slice(&[0, 0, 0, 0]);
+    srv.extend_from_slice(&port.to_be_bytes());
+    write_name(&mut srv, &format!("{}.local", id.simple()));
+    write_record(&mut message, &instance, TYPE_SRV, &srv);
+
+    let entry = format!("id={id}");
+    let mut txt = vec![entry.len() as u8];
+    txt.extend_from_slice(entry.as_bytes());
+    write_record(&mut message, &instance, TYPE_TXT, &txt);
+    message
+}
+
+/// What the message tells about area-net nodes, or None if it is not a valid
+/// DNS message.
+pub fn parse(message: &[u8]) -> Option<Parsed> {
+    let header = message.get(..HEADER_LEN)?;
+    let count = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+    let (questions, answers) = (count(4), count(6));
+    // Authority and additional records may hold announcements too.
+    let records = usize::from(answers) + usize::from(count(8)) + usize::from(count(10));
+    let mut parsed = Parsed::default();
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        let name = read_name(message, &mut offset)?;
+        let kind = u16::from_be_bytes(message.get(offset..offset + 2)?.try_into().ok()?);
+        offset += 4;
+        parsed.query |= kind == TYPE_PTR && name.eq_ignore_ascii_case(SERVICE);
+    }
+    let (mut id, mut port) = (None, None);
+    for _ in 0..records {
+        let name = read_name(message, &mut offset)?;
+        let fields = message.get(offset..offset + 10)?;
+        let kind = u16::from_be_bytes([fields[0], fields[1]]);
+        let len = usize::from(u16::from_be_bytes([fields[8], fields[9]]));
+        offset += 10;
+        let data = message.get(offset..offset + len)?;
+        let ours = name.to_ascii_lowercase().ends_with(&format!(".{SERVICE}"));
+        match kind {
+            TYPE_SRV if ours && len >= 6 => port = Some(u16::from_be_bytes([data[4], data[5]])),
+            TYPE_TXT if ours => id = id.or_else(|| txt_id(data)),
+            _ => {}
+        }
+        offset += len;
+    }
+    parsed.node = id.zip(port);
+    Some(parsed)
+}
+
+// The id of the TXT record strings ('id=<uuid>').
+fn txt_id(mut data: &[u8]) -> Option<Uuid> {
+    while let Some((&len, rest)) = data.split_first() {
+        let entry = rest.get(..usize::from(len))?;
+        if let Some(id) = entry.strip_prefix(b"id=") {
+            return Uuid::try_parse_ascii(id).ok();
+        }
+        data = &rest[usize::from(len)..];
+    }
+    None
+}
+
+fn header(flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+    let mut message = Vec::with_capacity(512);
+    // mDNS messages have a zero id.
+    message.extend_from_slice(&0u16.to_be_bytes());
+    message.extend_from_slice(&flags.to_be_bytes());
+    message.extend_from_slice(&questions.to_be_bytes());
+    message.extend_from_slice(&answers.to_be_bytes());
+    message.extend_from_slice(&[0, 0, 0, 0]);
+    message
+}
+
+fn write_record(message: &mut Vec<u8>, name: &str, kind: u16, data: &[u8]) {
+    write_name(message, name);
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    message.extend_from_slice(&TTL.to_be_bytes());
+    message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    message.extend_from_slice(data);
+}
+
+// Names are written without compression.
+fn write_name(message: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+}
+
+// Read the name at the offset, following compression pointers, and move the
+// offset past it.
+fn read_name(message: &[u8], offset: &mut usize) -> Option<String> {
+    let mut labels = Vec::new();
+    let mut position = *offset;
+    let mut jumps = 0;
+    loop {
+        let len = *message.get(position)?;
+        match len {
+            0 => {
+                if jumps == 0 {
+                    *offset = position + 1;
+                }
+                return Some(labels.join("."));
+            }
+            len if len & 0xC0 == 0xC0 => {
+                let pointer = usize::from(u16::from_be_bytes([
+                    len & 0x3F,
+                    *message.get(position + 1)?,
+                ]));
+                if jumps == 0 {
+                    *offset = position + 2;
+                }
+                jumps += 1;
+                if jumps > MAX_JUMPS {
+                    return None;
+                }
+                position = pointer;
+            }
+            len => {
+                let label = message.get(position + 1..position + 1 + usize::from(len))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                position += 1 + usize::from(len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_announcements_and_queries() {
+        let id = Uuid::new_v4();
+        let parsed = parse(&announcement(id, 8090)).unwrap();
+        assert_eq!(
+            parsed,
+            Parsed {
+                query: false,
+                node: Some((id, 8090)),
+            }
+        );
+        let parsed = parse(&query()).unwrap();
+        assert!(parsed.query);
+        assert_eq!(parsed.node, None);
+        assert!(parse(&[0u8; 4]).is_none());
+        // A truncated announcement is dropped.
+        let message = announcement(id, 8090);
+        assert!(parse(&message[..message.len() - 4]).is_none());
+    }
+
+    #[test]
+    fn should_follow_compressed_names() {
+        // The query name, then a pointer to it.
+        let mut message = query();
+        message.extend_from_slice(&[0xC0, HEADER_LEN as u8]);
+        let mut offset = HEADER_LEN;
+        assert_eq!(read_name(&message, &mut offset).unwrap(), SERVICE);
+        let mut offset = message.len() - 2;
+        assert_eq!(read_name(&message, &mut offset).unwrap(), SERVICE);
+        assert_eq!(offset, message.len());
+        // Pointers which loop are refused.
+        let looping = [0xC0, 0x00];
+        assert!(read_name(&looping, &mut 0).is_none());
+    }
+}
@@ -0,0 +1,828 @@
+//! In-memory transport.
+//!
+//! Nodes running in the same process (tests, or an application embedding
+//! several controllers) connect without binding any socket. Clones of a
+//! `MemoryTransport` share the same network: a connection dialed to an
+//! address is accepted by the listener registered at that address in the
+//! network, and carried by a `tokio::io::duplex` pipe.
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+use super::transport::{Connection, Listener, Transport};
+
+/// Size of the buffer of each direction of a connection.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// First port given to listeners bound to port 0, and to dialed connections.
+const EPHEMERAL_PORT: u16 = 49152;
+
+/// The in-memory transport.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryTransport {
+    network: Arc<Mutex<Network>>,
+}
+
+#[derive(Debug, Default)]
+struct Network {
+    listeners: HashMap<SocketAddr, mpsc::Sender<Connection>>,
+    next_port: u16,
+}
+
+/// Accepts the connections dialed to its address. The address is released
+/// when the listener is dropped.
+pub struct MemoryListener {
+    addr: SocketAddr,
+    rx: mpsc::Receiver<Connection>,
+    network: Arc<Mutex<Network>>,
+}
+
+impl MemoryTransport {
+    /// A new network, with no listener.
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+}
+
+impl Network {
+    // A port which no listener is bound to, on the given address.
+    fn ephemeral(&mut self, ip: IpAddr) -> SocketAddr {
+        loop {
+            self.next_port = self.next_port.max(EPHEMERAL_PORT).wrapping_add(1);
+            let addr = SocketAddr::new(ip, self.next_port);
+            if !self.listeners.contains_key(&addr) {
+                return addr;
+            }
+        }
+    }
+
+    // The listener at the given address, or at the unspecified address with the same port.
+    fn listener(&self, addr: SocketAddr) -> Option<mpsc::Sender<Connection>> {
+        let unspecified = match addr {
+            SocketAddr::V4(_) => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+        };
+        self.listeners
+            .get(&addr)
+            .or_else(|| {
+                self.listeners
+                    .get(&SocketAddr::new(unspecified, addr.port()))
+            })
+            .cloned()
+    }
+}
+
+impl Transport for MemoryTransport {
+    // There is no network interface in memory.
+    fn dial<'a>(
+        &'a self,
+        addr: SocketAddr,
+        _interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Connection>> {
+        Box::pin(async move {
+            let refused = || {
+                io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("No listener at {addr}"),
+                )
+            };
+            let (tx, local_addr) = {
+                let mut network = self.network.lock().expect("memory network lock");
+                let tx = network.listener(addr).ok_or_else(refused)?;
+                let local_addr = network.ephemeral(IpAddr::from(Ipv4Addr::LOCALHOST));
+                (tx, local_addr)
+            };
+            let (local, remote) = tokio::io::duplex(BUFFER_SIZE);
+            let accepted = Connection {
+                stream: Box::new(remote),
+                local_addr: addr,
+                peer_addr: local_addr,
+                binding: Vec::new(),
+            };
+            tx.send(accepted).await.map_err(|_| refused())?;
+            Ok(Connection {
+                stream: Box::new(local),
+                local_addr,
+                peer_addr: addr,
+                binding: Vec::new(),
+            })
+        })
+    }
+
+    fn listen<'a>(
+        &'a self,
+        addr: SocketAddr,
+        _interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            let mut network = self.network.lock().expect("memory network lock");
+            let addr = match addr.port() {
+                0 => network.ephemeral(addr.ip()),
+                _ if network.listeners.contains_key(&addr) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("A listener is already bound to {addr}"),
+                    ))
+                }
+                _ => addr,
+            };
+            let (tx, rx) = mpsc::channel(16);
+            network.listeners.insert(addr, tx);
+            Ok(Box::new(MemoryListener {
+                addr,
+                rx,
+                network: self.network.clone(),
+            }) as Box<dyn Listener>)
+        })
+    }
+}
+
+impl Listener for MemoryListener {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Connection>> {
+        Box::pin(async move {
+            // The listener holds the network, so a sender remains while it is registered.
+            self.rx.recv().await.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotConnected, "Memory network is gone")
+            })
+        })
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        if let Ok(mut network) = self.network.lock() {
+            network.listeners.remove(&self.addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Compression, Format, FrameCodec, Wire};
+    use crate::frame::{IntEncoding, Limits};
+    use crate::message::conn_rejection::RejectReason;
+    use crate::message::{AuthChallenge, AuthResponse, ConnResponse, Message, Ping};
+    use crate::network::auth::Secret;
+    use crate::network::command::Command;
+    use crate::network::event::Event;
+    use crate::network::identity::{Identity, Replays};
+    use crate::network::noise::Noise;
+    use crate::network::peer::Peer;
+    use crate::network::session::KeyShare;
+    use crate::network::transport::ByteStream;
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::{timeout, Duration};
+    use tokio_util::codec::Framed;
+
+    #[tokio::test]
+    async fn should_dial_and_accept_memory_connections() {
+        let transport = MemoryTransport::new();
+        let any: SocketAddr = "0.0.0.0:8090".parse().unwrap();
+        let mut listener = transport.listen(any, None).await.unwrap();
+        assert!(transport.listen(any, None).await.is_err());
+
+        let addr = "127.0.0.1:8090".parse().unwrap();
+        let (dialed, accepted) = tokio::join!(transport.dial(addr, None), listener.accept());
+        let (mut dialed, mut accepted) = (dialed.unwrap(), accepted.unwrap());
+        assert_eq!(accepted.peer_addr, dialed.local_addr);
+        dialed.stream.write_all(b"*1\r\n").await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"*1\r\n");
+
+        // Once the listener is dropped, its address is free again.
+        drop(listener);
+        assert!(transport.dial(addr, None).await.is_err());
+        assert!(transport.listen(any, None).await.is_ok());
+    }
+
+    // Spawn a peer using the memory transport, and return its command channel.
+    fn spawn_peer(
+        transport: &MemoryTransport,
+        addr: SocketAddr,
+        tx_evt: &mpsc::Sender<Event>,
+        label: &str,
+        rejection: Option<RejectReason>,
+        secret: Option<&[u8]>,
+        network: &str,
+    ) -> mpsc::Sender<Command> {
+        spawn_peer_with(transport, addr, tx_evt, label, |peer| {
+            peer.rejection = rejection;
+            peer.auth = secret.map(Secret::new);
+            peer.network = network.to_owned();
+        })
+    }
+
+    // Spawn a peer using the memory transport, set up by `setup`.
+    fn spawn_peer_with(
+        transport: &MemoryTransport,
+        addr: SocketAddr,
+        tx_evt: &mpsc::Sender<Event>,
+        label: &str,
+        setup: impl FnOnce(&mut Peer),
+    ) -> mpsc::Sender<Command> {
+        let (tx_com, rx_com) = mpsc::channel(32);
+        let mut peer = Peer::new(
+            Identity::new(),
+            label.to_owned(),
+            addr,
+            tx_evt.clone(),
+            tx_com.clone(),
+            rx_com,
+            i32::MAX,
+            i32::MAX,
+            None,
+        );
+        peer.transport = Arc::new(transport.clone());
+        peer.replays = Some(Arc::new(Replays::default()));
+        setup(&mut peer);
+        tokio::spawn(async move { peer.run().await });
+        tx_com
+    }
+
+    // Two peers complete their handshake over the memory transport.
+    #[tokio::test]
+    async fn should_complete_a_handshake_in_memory() {
+        let transport = MemoryTransport::new();
+        let addr: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let mut listener = transport.listen(addr, None).await.unwrap();
+        let (tx_evt, mut rx_evt) = mpsc::channel(32);
+        let alice = spawn_peer(&transport, addr, &tx_evt, "alice", None, None, "");
+        let bob = spawn_peer(&transport, addr, &tx_evt, "bob", None, None, "");
+
+        alice
+            .send(Command::Connect { addr, attempt: 0 })
+            .await
+            .unwrap();
+        let conn = listener.accept().await.unwrap();
+        bob.send(Command::Listen {
+            conn: Box::new(conn),
+        })
+        .await
+        .unwrap();
+
+        let mut alive = Vec::new();
+        while alive.len() < 2 {
+            let event = timeout(Duration::from_secs(5), rx_evt.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event {
+                Event::Connected { .. } => alice.send(Command::SendConnRequest).await.unwrap(),
+                Event::OutAlive { peer_label, .. } | Event::InAlive { peer_label, .. } => {
+                    alive.push(peer_label)
+                }
+                _ => {}
+            }
+        }
+        alive.sort();
+        assert_eq!(alive, ["alice", "bob"]);
+
+        // Once alive, bob echoes the pings of alice.
+        let data = bytes::Bytes::from(vec![7u8; 1000]);
+        alice
+            .send(Command::SendPing { seq: 1, data })
+            .await
+            .unwrap();
+        loop {
+            let event = timeout(Duration::from_secs(5), rx_evt.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let Event::Pong { seq, rtt, size, .. } = event {
+                assert_eq!((seq, size), (1, 1000));
+                assert!(rtt >= 0);
+                break;
+            }
+        }
+    }
+
+    // A peer told to reject the connection answers with a rejection, which
+    // the remote reports to its controller.
+    #[tokio::test]
+    async fn should_reject_a_handshake_in_memory() {
+        let transport = MemoryTransport::new();
+        let addr: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let mut listener = transport.listen(addr, None).await.unwrap();
+        let (tx_evt, mut rx_evt) = mpsc::channel(32);
+        let alice = spawn_peer(&transport, addr, &tx_evt, "alice", None, None, "");
+        let bob = spawn_peer(
+            &transport,
+            addr,
+            &tx_evt,
+            "bob",
+            Some(RejectReason::TooManyConnections),
+            None,
+            "",
+        );
+
+        alice
+            .send(Command::Connect { addr, attempt: 0 })
+            .await
+            .unwrap();
+        let conn = listener.accept().await.unwrap();
+        bob.send(Command::Listen {
+            conn: Box::new(conn),
+        })
+        .await
+        .unwrap();
+
+        let (mut rejected, mut terminated) = (None, false);
+        while rejected.is_none() || !terminated {
+            let event = timeout(Duration::from_secs(5), rx_evt.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event {
+                Event::Connected { .. } => alice.send(Command::SendConnRequest).await.unwrap(),
+                Event::Rejected { reason, .. } => rejected = Some(reason),
+                Event::Terminated { .. } => terminated = true,
+                Event::OutAlive { .. } | Event::InAlive { .. } => panic!("Unexpected {event:?}"),
+                _ => {}
+            }
+        }
+        assert_eq!(rejected, Some(RejectReason::TooManyConnections));
+    }
+
+    // Run a handshake with a listening peer holding a secret, in the default
+    // network, and return the rejection reason, if any.
+    async fn authenticated_handshake(secret: Option<&[u8]>, network: &str) -> Option<RejectReason> {
+        handshake_with_secrets(secret, Some(b"open sesame"), network).await
+    }
+
+    // Run a handshake between a dialing peer and a listening peer, holding
+    // their own secret, and return the rejection reason, if any.
+    async fn handshake_with_secrets(
+        dialer: Option<&[u8]>,
+        listener: Option<&[u8]>,
+        network: &str,
+    ) -> Option<RejectReason> {
+        let transport = MemoryTransport::new();
+        let addr: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let bob = listener;
+        let mut listener = transport.listen(addr, None).await.unwrap();
+        let (tx_evt, mut rx_evt) = mpsc::channel(32);
+        let alice = spawn_peer(&transport, addr, &tx_evt, "alice", None, dialer, network);
+        let bob = spawn_peer(&transport, addr, &tx_evt, "bob", None, bob, "");
+
+        alice
+            .send(Command::Connect { addr, attempt: 0 })
+            .await
+            .unwrap();
+        let conn = listener.accept().await.unwrap();
+        bob.send(Command::Listen {
+            conn: Box::new(conn),
+        })
+        .await
+        .unwrap();
+
+        let mut alive = 0;
+        while alive < 2 {
+            let event = timeout(Duration::from_secs(5), rx_evt.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event {
+                Event::Connected { .. } => alice.send(Command::SendConnRequest).await.unwrap(),
+                Event::OutAlive { .. } | Event::InAlive { .. } => alive += 1,
+                Event::Rejected { reason, .. } => return Some(reason),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // Only the remotes which know the secret complete the handshake.
+    #[tokio::test]
+    async fn should_authenticate_a_handshake_in_memory() {
+        assert_eq!(
+            authenticated_handshake(Some(b"open sesame"), "").await,
+            None
+        );
+        assert_eq!(
+            authenticated_handshake(Some(b"open barley"), "").await,
+            Some(RejectReason::Unauthenticated)
+        );
+        assert_eq!(
+            authenticated_handshake(None, "").await,
+            Some(RejectReason::Unauthenticated)
+        );
+    }
+
+    // A dialing peer holding a secret refuses a listening peer which did
+    // not challenge it.
+    #[tokio::test]
+    async fn should_refuse_a_listener_without_the_secret_in_memory() {
+        assert_eq!(
+            handshake_with_secrets(Some(b"open sesame"), None, "").await,
+            Some(RejectReason::Unauthenticated)
+        );
+        assert_eq!(handshake_with_secrets(None, None, "").await, None);
+    }
+
+    // The proof of the secret goes both ways: a listening peer which does not
+    // know it cannot pass off the answer of the dialing peer as its own, by
+    // challenging it with the signature of the connection request.
+    #[tokio::test]
+    async fn should_refuse_a_listener_reflecting_the_answer_in_memory() {
+        let transport = MemoryTransport::new();
+        let addr: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let mut listener = transport.listen(addr, None).await.unwrap();
+        let (tx_evt, mut rx_evt) = mpsc::channel(32);
+        let secret = Some(b"open sesame".as_slice());
+        let alice = spawn_peer(&transport, addr, &tx_evt, "alice", None, secret, "");
+
+        alice
+            .send(Command::Connect { addr, attempt: 0 })
+            .await
+            .unwrap();
+        let conn = listener.accept().await.unwrap();
+        let codec = FrameCodec::incoming(Limits::default(), &Wire::default());
+        let mut frames = Framed::new(conn.stream, codec);
+        loop {
+            let event = timeout(Duration::from_secs(5), rx_evt.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let Event::Connected { .. } = event {
+                alice.send(Command::SendConnRequest).await.unwrap();
+                break;
+            }
+        }
+
+        let Message::ConnRequest(request) = next_message(&mut frames).await else {
+            panic!("Expected a connection request");
+        };
+        let signature = Bytes::from(request.signature.unwrap());
+        let challenge = Message::AuthChallenge(AuthChallenge::new(signature));
+        frames.send(challenge.into_frame().unwrap()).await.unwrap();
+        let Message::AuthResponse(response) = next_message(&mut frames).await else {
+            panic!("Expected an auth response");
+        };
+        let reflected = Message::AuthResponse(AuthResponse::new(response.mac));
+        frames.send(reflected.into_frame().unwrap()).await.unwrap();
+
+        loop {
+            let event = timeout(Duration::from_secs(5), rx_evt.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event {
+                Event::Rejected { reason, .. } => {
+                    assert_eq!(reason, RejectReason::Unauthenticated);
+                    break;
+                }
+                Event::OutAlive { .. } => panic!("The listener was accepted"),
+                _ => {}
+            }
+        }
+    }
+
+    // Once the dialing peer offered session keys, a frame without an HMAC
+    // injected right behind the connection response closes the connection.
+    #[tokio::test]
+    async fn should_close_on_a_plain_frame_after_the_response_in_memory() {
+        let transport = MemoryTransport::new();
+        let addr: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let mut listener = transport.listen(addr, None).await.unwrap();
+        let (tx_evt, mut rx_evt) = mpsc::channel(32);
+        let alice = spawn_peer_with(&transport, addr, &tx_evt, "alice", |peer| {
+            peer.session_keys = true;
+        });
+
+        alice
+            .send(Command::Connect { addr, attempt: 0 })
+            .await
+            .unwrap();
+        let conn = listener.accept().await.unwrap();
+        let observed = conn.peer_addr;
+        let codec = FrameCodec::incoming(Limits::default(), &Wire::default());
+        let mut frames = Framed::new(conn.stream, codec);
+        loop {
+            let event = timeout(Duration::from_secs(5), rx_evt.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let Event::Connected { .. } = event {
+                alice.send(Command::SendConnRequest).await.unwrap();
+                break;
+            }
+        }
+
+        let Message::ConnRequest(request) = next_message(&mut frames).await else {
+            panic!("Expected a connection request");
+        };
+        assert!(request.key_share.is_some());
+        let bob = Identity::new();
+        let mut response = ConnResponse::new(bob.id(), "bob".to_owned(), Some(observed), None)
+            .with_public_key(bob.public_key().to_vec())
+            .with_key_share(KeyShare::new().public_key().to_vec());
+        let context = request.signature.unwrap();
+        response.signature = Some(bob.sign(&context, &response.transcript()));
+        let injected = Message::Ping(Ping::new(1, Bytes::from_static(b"injected")));
+        frames
+            .send(Message::ConnResponse(response).into_frame().unwrap())
+            .await
+            .unwrap();
+        frames.send(injected.into_frame().unwrap()).await.unwrap();
+
+        let (mut violation, mut disconnected) = (false, false);
+        while !disconnected {
+            let event = timeout(Duration::from_secs(5), rx_evt.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event {
+                Event::ProtocolViolation { .. } => violation = true,
+                Event::Disconnected { .. } => disconnected = true,
+                _ => {}
+            }
+        }
+        assert!(violation);
+    }
+
+    // The next message received on a raw connection.
+    async fn next_message(frames: &mut Framed<Box<dyn ByteStream>, FrameCodec>) -> Message {
+        let frame = timeout(Duration::from_secs(5), frames.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        Message::from_frame(frame).unwrap()
+    }
+
+    // Run a handshake over Noise, with session keys, and return the rejection
+    // reason, if any. A relayed request comes from another Noise connection,
+    // as when a node in the middle runs its own handshake with each side.
+    async fn noise_handshake(relayed: bool) -> Option<RejectReason> {
+        let transport = MemoryTransport::new();
+        let noise = Noise::new()
+            .unwrap()
+            .with_inner(Arc::new(transport.clone()));
+        let addr: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let mut listener = noise.listen(addr, None).await.unwrap();
+        let (tx_evt, mut rx_evt) = mpsc::channel(32);
+        let alice = spawn_peer_with(&transport, addr, &tx_evt, "alice", |peer| {
+            peer.transport = Arc::new(noise.clone());
+            peer.session_keys = true;
+        });
+        let bob = spawn_peer_with(&transport, addr, &tx_evt, "bob", |peer| {
+            peer.session_keys = true;
+        });
+
+        alice
+            .send(Command::Connect { addr, attempt: 0 })
+            .await
+            .unwrap();
+        let mut conn = listener.accept().await.unwrap();
+        if relayed {
+            conn.binding = Noise::new().unwrap().public_key().to_vec();
+        }
+        bob.send(Command::Listen {
+            conn: Box::new(conn),
+        })
+        .await
+        .unwrap();
+
+        let mut alive = 0;
+        while alive < 2 {
+            let event = timeout(Duration::from_secs(5), rx_evt.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event {
+                Event::Connected { .. } => alice.send(Command::SendConnRequest).await.unwrap(),
+                Event::OutAlive { .. } | Event::InAlive { .. } => alive += 1,
+                Event::Rejected { reason, .. } => return Some(reason),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // The connection request signs the Noise handshake hash, so that it is
+    // only accepted on the connection it was sent on.
+    #[tokio::test]
+    async fn should_bind_the_handshake_to_the_noise_connection_in_memory() {
+        assert_eq!(noise_handshake(false).await, None);
+        assert_eq!(
+            noise_handshake(true).await,
+            Some(RejectReason::Unauthenticated)
+        );
+    }
+
+    // Nodes of another network are rejected, even if they know the secret.
+    #[tokio::test]
+    async fn should_reject_other_networks_in_memory() {
+        assert_eq!(
+            authenticated_handshake(Some(b"open sesame"), "testnet").await,
+            Some(RejectReason::NetworkMismatch)
+        );
+    }
+
+    // A listening peer requiring work accepts the remotes which do it, and
+    // then still asks for the secret.
+    #[tokio::test]
+    async fn should_require_work_in_memory() {
+        let transport = MemoryTransport::new();
+        let addr: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let mut listener = transport.listen(addr, None).await.unwrap();
+        let (tx_evt, mut rx_evt) = mpsc::channel(32);
+        let secret = Some(b"open sesame".as_slice());
+        let alice = spawn_peer(&transport, addr, &tx_evt, "alice", None, secret, "");
+        let bob = spawn_peer_with(&transport, addr, &tx_evt, "bob", |peer| {
+            peer.auth = secret.map(Secret::new);
+            peer.pow_difficulty = 8;
+        });
+
+        alice
+            .send(Command::Connect { addr, attempt: 0 })
+            .await
+            .unwrap();
+        let conn = listener.accept().await.unwrap();
+        bob.send(Command::Listen {
+            conn: Box::new(conn),
+        })
+        .await
+        .unwrap();
+
+        let mut alive = 0;
+        while alive < 2 {
+            let event = timeout(Duration::from_secs(5), rx_evt.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event {
+                Event::Connected { .. } => alice.send(Command::SendConnRequest).await.unwrap(),
+                Event::OutAlive { .. } | Event::InAlive { .. } => alive += 1,
+                Event::Rejected { .. } => panic!("Unexpected {event:?}"),
+                _ => {}
+            }
+        }
+    }
+
+    // A remote asking for more work than the dialing peer does for anyone
+    // does not get a solution, and rejects it.
+    #[tokio::test]
+    async fn should_refuse_work_above_the_local_maximum_in_memory() {
+        let transport = MemoryTransport::new();
+        let addr: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let mut listener = transport.listen(addr, None).await.unwrap();
+        let (tx_evt, mut rx_evt) = mpsc::channel(32);
+        let alice = spawn_peer_with(&transport, addr, &tx_evt, "alice", |peer| {
+            peer.max_pow_difficulty = 4;
+        });
+        let bob = spawn_peer_with(&transport, addr, &tx_evt, "bob", |peer| {
+            peer.pow_difficulty = 8;
+        });
+
+        alice
+            .send(Command::Connect { addr, attempt: 0 })
+            .await
+            .unwrap();
+        let conn = listener.accept().await.unwrap();
+        bob.send(Command::Listen {
+            conn: Box::new(conn),
+        })
+        .await
+        .unwrap();
+
+        loop {
+            let event = timeout(Duration::from_secs(5), rx_evt.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event {
+                Event::Connected { .. } => alice.send(Command::SendConnRequest).await.unwrap(),
+                Event::Rejected { .. } => break,
+                Event::OutAlive { .. } | Event::InAlive { .. } => panic!("Unexpected {event:?}"),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn should_authenticate_frames_with_session_keys_in_memory() {
+        let transport = MemoryTransport::new();
+        let addr: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let mut listener = transport.listen(addr, None).await.unwrap();
+        let (tx_evt, mut rx_evt) = mpsc::channel(32);
+        let alice = spawn_peer_with(&transport, addr, &tx_evt, "alice", |peer| {
+            peer.session_keys = true;
+        });
+        let bob = spawn_peer_with(&transport, addr, &tx_evt, "bob", |peer| {
+            peer.session_keys = true;
+        });
+
+        alice
+            .send(Command::Connect { addr, attempt: 0 })
+            .await
+            .unwrap();
+        let conn = listener.accept().await.unwrap();
+        bob.send(Command::Listen {
+            conn: Box::new(conn),
+        })
+        .await
+        .unwrap();
+
+        // Once alive, each peer sends a payload, sealed with its session key.
+        let mut payloads = 0;
+        while payloads < 2 {
+            let event = timeout(Duration::from_secs(5), rx_evt.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            let payload = |topic: &str| Command::SendPayload {
+                topic: topic.to_owned(),
+                data: Bytes::from_static(b"sealed"),
+            };
+            match event {
+                Event::Connected { .. } => alice.send(Command::SendConnRequest).await.unwrap(),
+                Event::OutAlive { .. } => alice.send(payload("from alice")).await.unwrap(),
+                Event::InAlive { .. } => bob.send(payload("from bob")).await.unwrap(),
+                Event::Payload { data, .. } => {
+                    assert_eq!(data, Bytes::from_static(b"sealed"));
+                    payloads += 1;
+                }
+                Event::Rejected { .. } | Event::ProtocolViolation { .. } => {
+                    panic!("Unexpected {event:?}")
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // The outgoing peer offers binary frames, varints and lz4 right after the
+    // handshake, and both switch to them.
+    #[tokio::test]
+    async fn should_negotiate_codec_options_in_memory() {
+        let transport = MemoryTransport::new();
+        let addr: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let mut listener = transport.listen(addr, None).await.unwrap();
+        let (tx_evt, mut rx_evt) = mpsc::channel(32);
+        let wire = Wire {
+            format: Format::Binary,
+            compression: Some(Compression::Lz4),
+            compression_threshold: 0,
+            integers: IntEncoding::Varint,
+            negotiate: true,
+            ..Wire::default()
+        };
+        let alice = spawn_peer_with(&transport, addr, &tx_evt, "alice", |peer| {
+            peer.wire = wire;
+        });
+        let bob = spawn_peer_with(&transport, addr, &tx_evt, "bob", |peer| {
+            peer.wire = wire;
+        });
+
+        alice
+            .send(Command::Connect { addr, attempt: 0 })
+            .await
+            .unwrap();
+        let conn = listener.accept().await.unwrap();
+        bob.send(Command::Listen {
+            conn: Box::new(conn),
+        })
+        .await
+        .unwrap();
+
+        // Payloads go back and forth, the later ones with the agreed options.
+        let payload = |topic: &str| Command::SendPayload {
+            topic: topic.to_owned(),
+            data: Bytes::from_static(b"switched"),
+        };
+        let mut payloads = 0;
+        while payloads < 6 {
+            let event = timeout(Duration::from_secs(5), rx_evt.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event {
+                Event::Connected { .. } => alice.send(Command::SendConnRequest).await.unwrap(),
+                Event::OutAlive { .. } => alice.send(payload("from alice")).await.unwrap(),
+                Event::Payload { topic, data, .. } => {
+                    assert_eq!(data, Bytes::from_static(b"switched"));
+                    payloads += 1;
+                    match topic.as_str() {
+                        "from alice" => bob.send(payload("from bob")).await.unwrap(),
+                        _ => alice.send(payload("from alice")).await.unwrap(),
+                    }
+                }
+                Event::Rejected { .. } | Event::Disconnected { .. } => {
+                    panic!("Unexpected {event:?}")
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Network controller metrics
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Counters updated by the network controller.
+/// They are shared between threads, so they use atomics rather than a mutex.
+#[derive(Debug)]
+pub struct Metrics {
+    /// When the controller started.
+    pub started: Instant,
+    /// Number of remote nodes connected, in either direction.
+    pub connected: AtomicU64,
+    /// Number of outgoing connection attempts.
+    pub conn_attempts: AtomicU64,
+    /// Number of outgoing connection attempts that failed.
+    pub conn_failures: AtomicU64,
+    /// Number of frames which failed the session key check.
+    pub protocol_violations: AtomicU64,
+    /// Traffic, by message tag (eg 'HBT_REQ').
+    /// The set of tags is not known in advance, so this one sits behind a
+    /// (synchronous, never held across an await) mutex.
+    pub messages: Mutex<BTreeMap<String, MessageStats>>,
+}
+
+/// Traffic for a single message tag.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MessageStats {
+    /// Number of messages sent.
+    pub sent: u64,
+    /// Number of bytes sent.
+    pub sent_bytes: u64,
+    /// Number of messages received.
+    pub received: u64,
+    /// Number of bytes received.
+    pub received_bytes: u64,
+    /// Number of messages dropped because they exceeded their size ceiling.
+    pub dropped: u64,
+}
+
+/// A point in time copy of the metrics, suitable for reporting.
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    /// Seconds since the controller started.
+    pub uptime: u64,
+    /// Number of remote nodes connected.
+    pub connected: u64,
+    /// Number of outgoing connection attempts.
+    pub conn_attempts: u64,
+    /// Number of outgoing connection attempts that failed.
+    pub conn_failures: u64,
+    /// Number of frames which failed the session key check.
+    pub protocol_violations: u64,
+    /// Traffic, by message tag.
+    pub messages: BTreeMap<String, MessageStats>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            started: Instant::now(),
+            connected: AtomicU64::default(),
+            conn_attempts: AtomicU64::default(),
+            conn_failures: AtomicU64::default(),
+            protocol_violations: AtomicU64::default(),
+            messages: Mutex::default(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Increment a counter
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a message sent to a remote peer.
+    pub fn record_sent(&self, tag: &str, bytes: usize) {
+        self.update(tag, |stats| {
+            stats.sent += 1;
+            stats.sent_bytes += bytes as u64;
+        });
+    }
+
+    /// Record a message received from a remote peer.
+    pub fn record_received(&self, tag: &str, bytes: usize) {
+        self.update(tag, |stats| {
+            stats.received += 1;
+            stats.received_bytes += bytes as u64;
+        });
+    }
+
+    /// Record a message dropped because it was too large.
+    pub fn record_dropped(&self, tag: &str) {
+        self.update(tag, |stats| stats.dropped += 1);
+    }
+
+    fn update<F: FnOnce(&mut MessageStats)>(&self, tag: &str, f: F) {
+        let mut messages = self.messages.lock().expect("metrics lock");
+        match messages.get_mut(tag) {
+            Some(stats) => f(stats),
+            None => f(messages.entry(tag.to_owned()).or_default()),
+        }
+    }
+
+    /// Take a copy of the current metrics
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            uptime: self.started.elapsed().as_secs(),
+            connected: self.connected.load(Ordering::Relaxed),
+            conn_attempts: self.conn_attempts.load(Ordering::Relaxed),
+            conn_failures: self.conn_failures.load(Ordering::Relaxed),
+            protocol_violations: self.protocol_violations.load(Ordering::Relaxed),
+            messages: self.messages.lock().expect("metrics lock").clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_count_messages_by_tag() {
+        let metrics = Metrics::default();
+        metrics.record_sent("HBT_REQ", 40);
+        metrics.record_sent("HBT_REQ", 42);
+        metrics.record_received("CTCT_RESP", 100);
+        metrics.record_dropped("CTCT_RESP");
+        let snapshot = metrics.snapshot();
+        let hbt = &snapshot.messages["HBT_REQ"];
+        assert_eq!((hbt.sent, hbt.sent_bytes, hbt.received), (2, 82, 0));
+        let ctct = &snapshot.messages["CTCT_RESP"];
+        assert_eq!(
+            (ctct.received, ctct.received_bytes, ctct.dropped),
+            (1, 100, 1)
+        );
+    }
+}
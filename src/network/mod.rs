@@ -1,10 +1,50 @@
 //! network module
 use serde::{Deserialize, Serialize};
 
+pub mod addrbook;
+pub mod admin;
+pub mod allowlist;
+pub mod auth;
+pub mod backoff;
+pub mod ban;
+pub mod chunk;
 pub mod command;
+pub mod contacts;
+#[cfg(unix)]
+pub mod control;
 pub mod controller;
+pub mod custom;
+pub mod dht;
+pub mod discovery;
 pub mod event;
+pub mod gossip;
+pub mod identity;
+pub mod impairment;
+pub mod journal;
+pub mod mdns;
+pub mod memory;
+pub mod metrics;
+pub mod noise;
 pub mod peer;
+pub mod policy;
+pub mod pow;
+pub mod quic;
+pub mod relay;
+pub mod replay;
+pub mod score;
+pub mod session;
+pub mod snapshot;
+pub mod socket;
+pub mod socks;
+pub mod stress;
+pub mod tls;
+pub mod transport;
+pub mod websocket;
+
+/// Application protocol identifier negotiated with ALPN on encrypted transports.
+/// The major version is part of the identifier, so that future major versions of
+/// the protocol can be served on the same port.
+pub const ALPN_PROTOCOL: &[u8] = b"area-net/1";
 
 /// Network Configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,332 @@
+//! Noise transport.
+//!
+//! An alternative to TLS which fits the peer to peer model better than
+//! certificates: each node has a static key pair, and every connection starts
+//! with a Noise XX handshake, right after the TCP connect and before the
+//! connection request. The handshake derives session keys specific to the
+//! connection, which encrypt every subsequent frame. The prologue is the ALPN
+//! protocol identifier, so that the handshake fails between nodes speaking
+//! different major versions of the protocol.
+//!
+//! The Noise static keys are not the node identities: the handshake hash is
+//! the channel binding of the connection, which the connection request signs
+//! with the identity key, so that a node in the middle, running its own
+//! handshake with each side, is detected.
+//!
+//! Noise messages are prefixed by their length on two bytes (big endian).
+use futures::future::BoxFuture;
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+use super::transport::{ByteStream, Connection, HandshakeListener, Listener, Tcp, Transport};
+use super::ALPN_PROTOCOL;
+use crate::hex;
+
+/// Noise protocol used for the handshake.
+pub const PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+// Largest Noise message, and largest payload of a transport message
+// (the rest is the authentication tag).
+const MAX_MESSAGE_LEN: usize = 65535;
+const MAX_PAYLOAD_LEN: usize = MAX_MESSAGE_LEN - 16;
+
+/// The Noise transport.
+#[derive(Clone)]
+pub struct Noise {
+    inner: Arc<dyn Transport>,
+    private_key: Arc<Vec<u8>>,
+    public_key: Vec<u8>,
+}
+
+/// Error type for the Noise configuration
+#[derive(Debug)]
+pub struct Error {
+    /// Error detail
+    pub detail: String,
+}
+
+impl Noise {
+    /// Create a Noise transport with a new static key pair.
+    pub fn new() -> Result<Noise, Error> {
+        let keypair = builder().generate_keypair().map_err(|err| Error {
+            detail: format!("Could not generate a key pair: {err}"),
+        })?;
+        Ok(Noise {
+            inner: Arc::new(Tcp::default()),
+            private_key: Arc::new(keypair.private),
+            public_key: keypair.public,
+        })
+    }
+
+    /// Create a Noise transport with the static private key read from a file,
+    /// holding the 32 bytes of the key in hexadecimal.
+    pub fn with_key_file(path: &Path) -> Result<Noise, Error> {
+        let content = std::fs::read_to_string(path).map_err(|err| Error {
+            detail: format!("Could not read {}: {err}", path.display()),
+        })?;
+        let private_key = hex::decode(content.trim())
+            .filter(|key| key.len() == 32)
+            .ok_or_else(|| Error {
+                detail: format!(
+                    "{} does not hold a 32 bytes hexadecimal key",
+                    path.display()
+                ),
+            })?;
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("curve25519 is supported");
+        dh.set(&private_key);
+        Ok(Noise {
+            inner: Arc::new(Tcp::default()),
+            public_key: dh.pubkey().to_vec(),
+            private_key: Arc::new(private_key),
+        })
+    }
+
+    /// Run the handshake on top of the given transport instead of TCP.
+    pub fn with_inner(mut self, inner: Arc<dyn Transport>) -> Noise {
+        self.inner = inner;
+        self
+    }
+
+    /// Static public key of this node.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+}
+
+impl fmt::Debug for Noise {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Noise")
+            .field("inner", &self.inner)
+            .field("public_key", &hex::encode(&self.public_key))
+            .finish()
+    }
+}
+
+impl Transport for Noise {
+    fn dial<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Connection>> {
+        Box::pin(async move {
+            let conn = self.inner.dial(addr, interface).await?;
+            handshake(self.private_key.clone(), conn, true).await
+        })
+    }
+
+    fn listen<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        let private_key = self.private_key.clone();
+        Box::pin(async move {
+            let listener = self.inner.listen(addr, interface).await?;
+            let handshake = Arc::new(move |conn| {
+                Box::pin(handshake(private_key.clone(), conn, false)) as BoxFuture<'static, _>
+            });
+            Ok(Box::new(HandshakeListener::new(listener, handshake)) as Box<dyn Listener>)
+        })
+    }
+}
+
+fn builder() -> Builder<'static> {
+    Builder::new(PATTERN.parse().expect("valid noise pattern"))
+}
+
+fn noise_error(err: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Noise: {err}"))
+}
+
+async fn handshake(
+    private_key: Arc<Vec<u8>>,
+    mut conn: Connection,
+    initiator: bool,
+) -> io::Result<Connection> {
+    let builder = builder()
+        .local_private_key(&private_key)
+        .prologue(ALPN_PROTOCOL);
+    let state = if initiator {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    };
+    let (binding, session) = exchange(state.map_err(noise_error)?, &mut conn.stream).await?;
+    Ok(Connection {
+        stream: Box::new(encrypt(conn.stream, session)),
+        binding,
+        ..conn
+    })
+}
+
+// Runs the handshake messages, and returns the handshake hash and the
+// session keys.
+async fn exchange(
+    mut state: HandshakeState,
+    stream: &mut Box<dyn ByteStream>,
+) -> io::Result<(Vec<u8>, StatelessTransportState)> {
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state.write_message(&[], &mut buf).map_err(noise_error)?;
+            write_message(stream, &buf[..len]).await?;
+        } else {
+            let message = read_message(stream).await?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "Noise handshake interrupted")
+            })?;
+            state
+                .read_message(&message, &mut buf)
+                .map_err(noise_error)?;
+        }
+    }
+    let hash = state.get_handshake_hash().to_vec();
+    let session = state.into_stateless_transport_mode().map_err(noise_error)?;
+    Ok((hash, session))
+}
+
+// The connection's byte stream is one end of an in-memory pipe, and two tasks
+// encrypt what is written to it, and decrypt what is received from the remote.
+// Each direction counts its own nonces.
+fn encrypt(stream: Box<dyn ByteStream>, session: StatelessTransportState) -> DuplexStream {
+    let session = Arc::new(session);
+    let (local, remote) = tokio::io::duplex(MAX_MESSAGE_LEN);
+    let (app_reader, app_writer) = tokio::io::split(local);
+    let (tcp_reader, tcp_writer) = tokio::io::split(stream);
+    tokio::spawn(send_loop(app_reader, tcp_writer, session.clone()));
+    tokio::spawn(receive_loop(tcp_reader, app_writer, session));
+    remote
+}
+
+async fn send_loop(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    session: Arc<StatelessTransportState>,
+) -> io::Result<()> {
+    let mut payload = vec![0u8; MAX_PAYLOAD_LEN];
+    let mut message = vec![0u8; MAX_MESSAGE_LEN];
+    for nonce in 0.. {
+        let n = reader.read(&mut payload).await?;
+        if n == 0 {
+            break;
+        }
+        let len = session
+            .write_message(nonce, &payload[..n], &mut message)
+            .map_err(noise_error)?;
+        write_message(&mut writer, &message[..len]).await?;
+    }
+    writer.shutdown().await
+}
+
+async fn receive_loop(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    session: Arc<StatelessTransportState>,
+) -> io::Result<()> {
+    let mut payload = vec![0u8; MAX_MESSAGE_LEN];
+    for nonce in 0.. {
+        let message = match read_message(&mut reader).await {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(err) => {
+                log::debug!("Noise | Could not receive message | {err}");
+                break;
+            }
+        };
+        let n = match session.read_message(nonce, &message, &mut payload) {
+            Ok(n) => n,
+            Err(err) => {
+                log::warn!("Noise | Could not decrypt message | {err}");
+                break;
+            }
+        };
+        writer.write_all(&payload[..n]).await?;
+    }
+    writer.shutdown().await
+}
+
+async fn write_message(writer: &mut (impl AsyncWrite + Unpin), message: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(message.len()).expect("noise messages fit in 64KiB");
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(message).await?;
+    writer.flush().await
+}
+
+// Returns None if the stream ends before a new message.
+async fn read_message(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 2];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let mut message = vec![0u8; usize::from(u16::from_be_bytes(len))];
+    reader.read_exact(&mut message).await?;
+    Ok(Some(message))
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid Noise configuration: {}", self.detail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn should_encrypt_connections_after_a_noise_handshake() {
+        let server = Noise::new().unwrap();
+        let client = Noise::new().unwrap();
+        let mut listener = server
+            .listen("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (dialed, accepted) = tokio::join!(client.dial(addr, None), listener.accept());
+        let (mut dialed, mut accepted) = (dialed.unwrap(), accepted.unwrap());
+        assert_eq!(accepted.peer_addr, dialed.local_addr);
+        // Both ends share the channel binding, specific to the connection.
+        assert_eq!(accepted.binding, dialed.binding);
+        assert!(!dialed.binding.is_empty());
+        let (other, _) = tokio::join!(client.dial(addr, None), listener.accept());
+        assert_ne!(other.unwrap().binding, dialed.binding);
+
+        // Larger than a single Noise message.
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let (written, read) = tokio::join!(dialed.stream.write_all(&data), async {
+            let mut buf = vec![0u8; data.len()];
+            accepted.stream.read_exact(&mut buf).await.map(|_| buf)
+        });
+        written.unwrap();
+        assert_eq!(read.unwrap(), data);
+
+        accepted.stream.write_all(b"*1\r\n").await.unwrap();
+        let mut buf = [0u8; 4];
+        dialed.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"*1\r\n");
+    }
+
+    #[test]
+    fn should_read_the_static_key_from_a_file() {
+        let noise = Noise::new().unwrap();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "{}", hex::encode(&noise.private_key)).unwrap();
+        let loaded = Noise::with_key_file(file.path()).unwrap();
+        assert_eq!(loaded.public_key(), noise.public_key());
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "not a key").unwrap();
+        assert!(Noise::with_key_file(file.path()).is_err());
+    }
+}
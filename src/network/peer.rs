@@ -1,30 +1,53 @@
 //! A node
 use async_recursion::async_recursion;
+use bytes::Bytes;
 use chrono::Utc;
 use futures::sink::SinkExt;
-use futures::stream::SplitSink;
 use futures::stream::StreamExt;
+use futures::stream::{SplitSink, SplitStream};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::net::SocketAddr;
-use std::str::FromStr;
 use std::string::ToString;
-use tokio::net::TcpStream;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::{watch, OwnedSemaphorePermit};
 use tokio::task::{JoinError, JoinHandle};
-use tokio::time::{self, Duration};
+use tokio::time::{self, Duration, Instant};
 use tokio_util::codec::Framed;
 use uuid::Uuid;
 
+use super::auth::{self, Secret, Side};
+use super::chunk::{self, Reassembler};
 use super::command::Command;
 use super::event::Event;
-use crate::codec;
+use super::identity::{self, Identity, Proof, Replays};
+use super::impairment;
+use super::metrics::Metrics;
+use super::pow;
+use super::relay::RelayMessage;
+use super::session::{self, KeyShare, Opener, Sealer};
+use super::transport::{ByteStream, Connection, Tcp, Transport};
+use crate::codec::{
+    self, Ceilings, CodecOptions, Compression, Compressor, Format, Switcher, UnknownMessages, Wire,
+};
+use crate::frame::{IntEncoding, Limits};
+use crate::message::bye::Reason;
+use crate::message::conn_rejection::RejectReason;
 use crate::message::{
-    self, ConnRequest, ConnResponse, ContactRequest, ContactResponse, HeartbeatRequest,
-    HeartbeatResponse, Message,
+    self, AuthChallenge, AuthResponse, Bye, Chunk, CodecAccept, CodecOffer, ConnRejection,
+    ConnRequest, ConnResponse, ContactRequest, ContactResponse, FindNode, Gossip, Health,
+    HeartbeatRequest, HeartbeatResponse, InfoRequest, Message, Nodes, Payload, Ping, Pong,
+    PowChallenge, PowResponse, Registry, WireMessage,
 };
 use crate::Frame;
 use crate::FrameCodec;
 
+// Frames exchanged with the remote, over the connection given by the transport.
+type Frames = Framed<Box<dyn ByteStream>, FrameCodec>;
+
 /// NetworkController
 #[derive(Debug)]
 pub struct Peer {
@@ -34,6 +57,9 @@ pub struct Peer {
     /// id of the controller (we publish this information to remote peers)
     /// This information is used to provide identity to the peer
     pub controller: Uuid,
+    /// Key pair of the controller, which signs the handshake messages.
+    /// The id of the controller is derived from it.
+    pub identity: Identity,
     /// label (it's the controller's label)
     /// This information is used to provide identity to the peer
     pub label: String,
@@ -50,10 +76,10 @@ pub struct Peer {
     pub state: PeerState,
     /// Sink. This is an option, because we don't have one until
     /// we establish a connection with the peer.
-    pub sink: Option<SplitSink<Framed<TcpStream, FrameCodec>, Frame>>,
+    pub sink: Option<SplitSink<Frames, Frame>>,
     // /// Stream. This is an option, because we don't have one until
     // /// we establish a connection with the peer.
-    // pub stream: Option<SplitStream<Framed<TcpStream, FrameCodec>>>,
+    // pub stream: Option<SplitStream<Frames>>,
     /// This is the way to receive commands from the controller.
     pub rx_com: Receiver<Command>,
     /// This is the way to update the controller.
@@ -68,16 +94,122 @@ pub struct Peer {
     pub heartbeat_timeout: i32,
     /// heartbeat
     pub heartbeat_period: i32,
+    /// Name of the network interface outgoing connections originate from.
+    pub interface: Option<String>,
     /// handle to a thread that will trigger a timeout
     pub heartbeat_timeout_handle: Option<JoinHandle<()>>,
     /// handle to the listen thread
     pub listen_handle: Option<JoinHandle<()>>,
     /// handle to the periodic heartbeat thread
     pub heartbeat_handle: Option<JoinHandle<()>>,
+    /// Permit given by the controller to an incoming peer, and released
+    /// when the handshake is complete. This limits the number of simultaneous
+    /// incoming handshakes.
+    pub handshake_permit: Option<OwnedSemaphorePermit>,
+    /// Metrics, shared with the controller.
+    pub metrics: Arc<Metrics>,
+    /// Size ceilings (bytes), by message tag. Larger messages are dropped.
+    pub max_message_sizes: Arc<HashMap<String, usize>>,
+    /// Bounds applied to frames received from the remote peer.
+    pub frame_limits: Limits,
+    /// Framing used on the wire.
+    pub wire: Wire,
+    /// Messages known to the controller, the application's included.
+    pub registry: Arc<Registry>,
+    /// Switches on the compression of the frames sent, once negotiated.
+    pub compressor: Compressor,
+    /// Switches the framing and the integer encoding of the frames sent,
+    /// once agreed with the remote.
+    pub switcher: Switcher,
+    /// Transport used to connect to the remote.
+    pub transport: Arc<dyn Transport>,
+    /// Artificial delay, jitter and loss applied to the messages sent to the remote.
+    pub impairment: Option<impairment::Settings>,
+    /// Commands that arrived before the peer reached the state they expect,
+    /// with that state. They are replayed once the peer reaches it.
+    pub deferred: VecDeque<(PeerState, Command)>,
+    /// Chunks of large frames waiting to be sent, one command at a time.
+    pub chunks: VecDeque<Chunk>,
+    /// Id of the next stream of chunks.
+    pub next_stream: u64,
+    /// Pings sent to the remote and waiting for their pong, with the time
+    /// they were sent.
+    pub pings: VecDeque<(u64, Instant)>,
+    /// Secret shared by the nodes of the network, which authenticates the
+    /// handshakes. Without it, remotes are not challenged.
+    pub auth: Option<Secret>,
+    // Challenge sent to the remote, with its connection request, until the
+    // remote answers.
+    challenge: Option<Challenge>,
+    // Whether the remote proved it knows the secret, answering our
+    // connection request. With a secret, a remote accepting us without the
+    // proof is refused.
+    proven: bool,
+    /// Number of leading zero bits of the proof-of-work required from the
+    /// remote in the handshake. With 0, no work is required.
+    pub pow_difficulty: u64,
+    /// Highest difficulty of the proof-of-work the peer does for the remote.
+    /// It cannot exceed `pow::MAX_DIFFICULTY`.
+    pub max_pow_difficulty: u64,
+    /// Nonces of the connection requests received recently, shared by the
+    /// incoming peers. Without it, requests are not checked for replays.
+    pub replays: Option<Arc<Replays>>,
+    // Signature of the connection request, sent or received. The signature
+    // of the response follows it, so that a response only matches its request.
+    request_signature: Option<Vec<u8>>,
+    // Channel binding of the connection, which the request signature follows,
+    // so that a request only matches the encrypted connection it was sent on.
+    binding: Vec<u8>,
+    // Public key of the remote, once it proved holding it in the handshake.
+    // The contact lists it signs are checked against it.
+    remote_key: Option<Vec<u8>>,
+    /// Whether the contact lists the remote did not sign are dropped.
+    pub signed_contacts: bool,
+    /// Whether the frames exchanged after the handshake are authenticated
+    /// with session keys, if the remote agrees. Set unless TLS encrypts them.
+    pub session_keys: bool,
+    // Ephemeral key pair sent with the connection request, until the
+    // response comes back.
+    key_share: Option<KeyShare>,
+    // Ephemeral public key the remote sent with its connection request,
+    // until we answer it.
+    remote_share: Option<Vec<u8>>,
+    // Authenticates the frames sent, once the session keys are derived.
+    sealer: Option<Sealer>,
+    // Hands the key which checks the frames received over to the task
+    // receiving them.
+    opener: watch::Sender<Option<Opener>>,
+    /// Id of the network of the node, empty for the default network. Remotes
+    /// of other networks are rejected.
+    pub network: String,
+    /// Set by the controller on an incoming peer it doesn't accept: the
+    /// connection request of the remote is answered with a rejection.
+    pub rejection: Option<RejectReason>,
+}
+
+// A connection request waiting for the answer to a challenge: a
+// proof-of-work one if the difficulty is not 0, an authentication one
+// otherwise.
+#[derive(Debug)]
+struct Challenge {
+    nonce: Bytes,
+    difficulty: u64,
+    peer_id: Uuid,
+    peer_label: String,
+    peer_addr: SocketAddr,
+    compression: Option<Compression>,
 }
 
+/// Maximum number of commands a peer keeps waiting for the right state.
+const MAX_DEFERRED_COMMANDS: usize = 32;
+
+/// Maximum number of pings a peer keeps waiting for their pong. Older
+/// pings are forgotten, as lost.
+const MAX_PENDING_PINGS: usize = 32;
+
 /// Peer Status
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PeerState {
     /// We know about the peer but we aren't currently doing anything with it
     Idle,
@@ -123,7 +255,7 @@ impl Peer {
     /// * or call 'listen(stream)' so that it starts incoming
     ///   request from the connection (stream)
     pub fn new(
-        controller: Uuid,
+        identity: Identity,
         label: String,
         controller_addr: SocketAddr,
         tx_evt: Sender<Event>,
@@ -131,10 +263,12 @@ impl Peer {
         rx_com: Receiver<Command>,
         heartbeat_timeout: i32,
         heartbeat_period: i32,
+        interface: Option<String>,
     ) -> Peer {
         Peer {
             id: Uuid::new_v4(),
-            controller,
+            controller: identity.id(),
+            identity,
             label,
             controller_addr,
             peer_id: None,
@@ -148,13 +282,45 @@ impl Peer {
             rx_com,
             heartbeat_timeout,
             heartbeat_period,
+            interface,
             heartbeat_timeout_handle: None,
             listen_handle: None,
             heartbeat_handle: None,
+            handshake_permit: None,
+            metrics: Arc::new(Metrics::default()),
+            max_message_sizes: Arc::new(HashMap::new()),
+            frame_limits: Limits::default(),
+            wire: Wire::default(),
+            registry: Arc::default(),
+            compressor: Compressor::default(),
+            switcher: Switcher::default(),
+            transport: Arc::new(Tcp::default()),
+            impairment: None,
+            deferred: VecDeque::new(),
+            chunks: VecDeque::new(),
+            next_stream: 0,
+            pings: VecDeque::new(),
+            auth: None,
+            challenge: None,
+            proven: false,
+            pow_difficulty: 0,
+            max_pow_difficulty: pow::MAX_DIFFICULTY,
+            replays: None,
+            request_signature: None,
+            binding: Vec::new(),
+            remote_key: None,
+            signed_contacts: false,
+            session_keys: false,
+            key_share: None,
+            remote_share: None,
+            sealer: None,
+            opener: watch::channel(None).0,
+            network: String::new(),
+            rejection: None,
         }
     }
 
-    /// We want the peer to establish a TCP connection.
+    /// We want the peer to establish a connection, through its transport.
     /// If everything goes well, the peer exits in the OutConnecting state
     async fn connect(&mut self, addr: &SocketAddr, attempt: i32) -> Result<(), Error> {
         // Guarding against invalid state.
@@ -186,9 +352,8 @@ impl Peer {
             addr,
             attempt,
         );
-        let stream = match TcpStream::connect(addr).await {
-            // FIXME Unwrap
-            Ok(stream) => stream,
+        let conn = match self.transport.dial(*addr, self.interface.as_deref()).await {
+            Ok(conn) => conn,
             Err(err) => {
                 let msg = Event::ConnectionError {
                     id: self.id,
@@ -209,35 +374,28 @@ impl Peer {
         };
 
         self.addr = Some(*addr);
-        self.local_addr = Some(stream.local_addr().expect("local addr"));
-        self.peer_addr = Some(stream.peer_addr().expect("peer addr"));
+        self.local_addr = Some(conn.local_addr);
+        self.peer_addr = Some(conn.peer_addr);
+        self.binding = conn.binding;
 
-        let frames = Framed::new(stream, FrameCodec);
+        // When negotiating, we start with the options every node understands.
+        let (format, integers) = match self.wire.negotiate {
+            true => (Format::Text, IntEncoding::Fixed),
+            false => (self.wire.format, self.wire.integers),
+        };
+        let codec = FrameCodec::connecting(self.frame_limits, format)
+            .with_checksum(self.wire.checksum)
+            .with_integers(integers)
+            .with_ceilings(self.ceilings());
+        self.compressor = codec.compressor();
+        self.switcher = codec.switcher();
+        let frames = Framed::new(conn.stream, codec);
 
-        let (sink, mut stream) = frames.split();
+        let (sink, stream) = frames.split();
 
         self.sink = Some(sink);
 
-        let id = self.id;
-        let tx_com = self.tx_com.clone();
-
-        let handle = tokio::spawn(async move {
-            while let Some(frame) = stream.next().await {
-                match frame {
-                    Ok(frame) => {
-                        let msg = Message::from_frame(frame).expect("Message decoding from frame");
-                        if let Err(err) = handle_message(id, msg, tx_com.clone()).await {
-                            log::error!("Error handling a message: {err}");
-                        }
-                    }
-                    Err(err) => {
-                        log::error!("Error from message stream {}", err);
-                    }
-                }
-            }
-        });
-
-        self.listen_handle = Some(handle);
+        self.listen_handle = Some(self.receive(stream));
 
         log::trace!(
             "Connection {} <=> {}",
@@ -255,9 +413,9 @@ impl Peer {
         Ok(())
     }
 
-    /// We want the peer to listen on the given TcpStream
+    /// We want the peer to listen on the given connection
     /// If everything goes well, the peer exits in the InHandshaking state
-    async fn listen(&mut self, stream: TcpStream) -> Result<(), Error> {
+    async fn listen(&mut self, conn: Connection) -> Result<(), Error> {
         // Guarding against invalid state.
         // The peer can be either in
         // * idle state (it hasn't tried to connect yet.)
@@ -286,8 +444,9 @@ impl Peer {
             "Peer {} | is InHandshaking",
             self.id.to_string().get(0..8).unwrap()
         );
-        self.local_addr = Some(stream.local_addr().expect("local addr"));
-        self.peer_addr = Some(stream.peer_addr().expect("peer addr"));
+        self.local_addr = Some(conn.local_addr);
+        self.peer_addr = Some(conn.peer_addr);
+        self.binding = conn.binding;
 
         log::info!(
             "Peer {} | listening on {}",
@@ -295,32 +454,24 @@ impl Peer {
             self.peer_addr.unwrap(),
         );
 
-        let frames = Framed::new(stream, FrameCodec);
+        let codec = FrameCodec::incoming(self.frame_limits, &self.wire)
+            .with_checksum(self.wire.checksum)
+            .with_integers(self.wire.integers)
+            .with_ceilings(self.ceilings());
+        self.compressor = codec.compressor();
+        self.switcher = codec.switcher();
+        let frames = Framed::new(conn.stream, codec);
 
-        let (sink, mut stream) = frames.split();
+        let (sink, stream) = frames.split();
 
         self.sink = Some(sink);
 
-        let tx_com = self.tx_com.clone();
+        self.listen_handle = Some(self.receive(stream));
 
-        let id = self.id;
-        let handle = tokio::spawn(async move {
-            while let Some(frame) = stream.next().await {
-                match frame {
-                    Ok(frame) => {
-                        let msg = Message::from_frame(frame).expect("Message decoding from frame");
-                        if let Err(err) = handle_message(id, msg, tx_com.clone()).await {
-                            log::error!("Error handling a message: {err}");
-                        }
-                    }
-                    Err(err) => {
-                        log::error!("Error from message stream {}", err);
-                    }
-                }
-            }
-        });
+        // The remote peer has to complete the handshake before the timeout. This
+        // timeout is then renewed by each heartbeat request.
+        self.heartbeat_timeout_handle = Some(self.timeout());
 
-        self.listen_handle = Some(handle);
         log::info!(
             "Connection {} <=> {}",
             self.local_addr.unwrap(),
@@ -344,15 +495,21 @@ impl Peer {
     pub async fn run(mut self) -> Result<(), Error> {
         log::trace!("Peer {} | running", self.id.to_string().get(0..8).unwrap());
         while let Some(cmd) = self.rx_com.recv().await {
-            if let Err(err) = self.handle_command(cmd).await {
+            let mut res = self.handle_command(cmd).await;
+            if res.is_ok() {
+                res = self.replay_deferred().await;
+            }
+            if let Err(err) = res {
                 log::warn!(
                     "Peer {} | Could not process command in main loop | {err} | => Terminating",
                     self.id.to_string().get(0..8).unwrap(),
                 );
                 match self.state {
-                    PeerState::InAlive | PeerState::InHandshaking => self.terminate().await?,
+                    PeerState::InAlive | PeerState::InHandshaking => {
+                        self.terminate(Some(Reason::Error)).await?
+                    }
                     PeerState::OutAlive | PeerState::OutHandshaking | PeerState::OutConnecting => {
-                        self.disconnect().await?
+                        self.disconnect(Some(Reason::Error)).await?
                     }
                     _ => {
                         log::warn!(
@@ -383,6 +540,44 @@ impl Peer {
             handle.abort();
             self.heartbeat_handle = None;
         }
+        // Deferred commands and chunks belong to the connection we are tearing down.
+        self.deferred.clear();
+        self.chunks.clear();
+        self.pings.clear();
+        Ok(())
+    }
+
+    /// Replay the deferred commands waiting for the current state, in the
+    /// order they arrived, and drop those the peer can no longer expect to see
+    /// their state.
+    async fn replay_deferred(&mut self) -> Result<(), Error> {
+        while let Some(index) = self
+            .deferred
+            .iter()
+            .position(|(awaited, _)| *awaited == self.state)
+        {
+            let (_, command) = self.deferred.remove(index).unwrap(); // safe, we have the index
+            log::debug!(
+                "Peer {} | Replaying deferred command '{}'",
+                self.id.to_string().get(0..8).unwrap(),
+                command.to_string(),
+            );
+            self.handle_command(command).await?;
+        }
+        let state = self.state;
+        let id = self.id;
+        self.deferred.retain(|(awaited, command)| {
+            let keep = precedes(state, *awaited);
+            if !keep {
+                log::info!(
+                    "Peer {} | Dropping deferred command '{}' in state '{}'",
+                    id.to_string().get(0..8).unwrap(),
+                    command.to_string(),
+                    state.to_string(),
+                );
+            }
+            keep
+        });
         Ok(())
     }
 
@@ -407,12 +602,18 @@ impl Peer {
         Ok(())
     }
 
-    async fn terminate(&mut self) -> Result<(), Error> {
+    /// Close the connection, after telling the remote why, unless it is the
+    /// one which said goodbye.
+    async fn terminate(&mut self, bye: Option<Reason>) -> Result<(), Error> {
         log::info!(
             "Peer {} | Terminating",
             self.id.to_string().get(0..8).unwrap()
         );
+        if let Some(reason) = bye {
+            self.say_goodbye(reason).await;
+        }
         self.abort_threads().await?;
+        self.handshake_permit = None;
 
         self.close_receiver().await?;
 
@@ -434,11 +635,16 @@ impl Peer {
         Ok(())
     }
 
-    async fn disconnect(&mut self) -> Result<(), Error> {
+    /// Close the connection, after telling the remote why, unless it is the
+    /// one which said goodbye.
+    async fn disconnect(&mut self, bye: Option<Reason>) -> Result<(), Error> {
         log::info!(
             "Peer {} | Disconnecting",
             self.id.to_string().get(0..8).unwrap()
         );
+        if let Some(reason) = bye {
+            self.say_goodbye(reason).await;
+        }
         self.abort_threads().await?;
 
         self.close_receiver().await?;
@@ -472,6 +678,171 @@ impl Peer {
         Ok(())
     }
 
+    /// The remote refused the connection: close it, and let the controller
+    /// know, so that it doesn't dial the address again.
+    async fn rejected(&mut self, reason: RejectReason) -> Result<(), Error> {
+        log::info!(
+            "Peer {} | Connection rejected | {reason}",
+            self.id.to_string().get(0..8).unwrap()
+        );
+        self.abort_threads().await?;
+
+        self.close_receiver().await?;
+
+        let addr = self.addr.ok_or_else(|| Error::InvalidAddr {
+            detail: format!(
+                "Peer {} | Should have an address.",
+                self.id.to_string().get(0..8).unwrap()
+            ),
+        })?;
+        let msg = Event::Rejected {
+            id: self.id,
+            addr,
+            reason,
+        };
+        if let Err(err) = self.tx_evt.send(msg).await {
+            return Err(Error::SendEvent {
+                source: err,
+                detail: format!(
+                    "Peer {} | Could not send 'rejected' to controller | Receiver dropped",
+                    self.id.to_string().get(0..8).unwrap()
+                ),
+            });
+        }
+        self.state = PeerState::Idle;
+        Ok(())
+    }
+
+    // Challenge the remote which sent the connection request, if the network
+    // has a secret, or accept it.
+    async fn authenticate(&mut self, request: Challenge) -> Result<(), Error> {
+        if self.auth.is_some() {
+            let nonce = Bytes::copy_from_slice(&auth::nonce());
+            self.challenge = Some(Challenge {
+                nonce: nonce.clone(),
+                difficulty: 0,
+                ..request
+            });
+            return self
+                .send(Message::AuthChallenge(AuthChallenge::new(nonce)))
+                .await;
+        }
+        self.accept(
+            request.peer_id,
+            request.peer_label,
+            request.peer_addr,
+            request.compression,
+        )
+        .await
+    }
+
+    /// Send a connection response to the remote, and tell the controller
+    /// the connection is live. The response itself is sent uncompressed.
+    async fn accept(
+        &mut self,
+        peer_id: Uuid,
+        peer_label: String,
+        peer_addr: SocketAddr,
+        compression: Option<Compression>,
+    ) -> Result<(), Error> {
+        let compression = compression.filter(|c| self.wire.compression == Some(*c));
+        let mut response = ConnResponse::new(
+            self.controller,
+            self.label.clone(),
+            self.peer_addr,
+            compression,
+        )
+        .with_public_key(self.identity.public_key().to_vec());
+        // The remote offered session keys: we derive them, and send ours.
+        let mut sealer = None;
+        if let Some(remote_share) = self.remote_share.take().filter(|_| self.session_keys) {
+            let key_share = KeyShare::new();
+            response = response.with_key_share(key_share.public_key().to_vec());
+            let Some((send, receive)) = key_share.agree(&remote_share, false) else {
+                return self.reject(peer_id, RejectReason::Unauthenticated).await;
+            };
+            // The remote seals its frames as soon as it gets our response.
+            self.opener.send_replace(Some(receive));
+            sealer = Some(send);
+        }
+        let context = self.request_signature.take().unwrap_or_default();
+        response.signature = Some(self.identity.sign(&context, &response.transcript()));
+        self.send(Message::ConnResponse(response)).await?;
+        self.sealer = sealer;
+        self.enable_compression(compression);
+        self.state = PeerState::InAlive;
+        self.handshake_permit = None;
+        let event = Event::InAlive {
+            id: self.id,
+            peer_id,
+            peer_label,
+            peer_addr,
+        };
+        if let Err(err) = self.tx_evt.send(event).await {
+            // We're in deep trouble here, we can't communicate with
+            // the network controller. So we shutdown.
+            return Err(Error::SendEvent {
+                source: err,
+                detail: format!(
+                    "Peer {} | Could not send 'established' to controller | Receiver dropped",
+                    self.id.to_string().get(0..8).unwrap()
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Tell the controller the remote missed its heartbeats, before closing
+    /// the connection.
+    async fn timed_out(&mut self) -> Result<(), Error> {
+        if let Err(err) = self.tx_evt.send(Event::TimedOut { id: self.id }).await {
+            return Err(Error::SendEvent {
+                source: err,
+                detail: format!(
+                    "Peer {} | Could not send 'timed out' to controller | Receiver dropped",
+                    self.id.to_string().get(0..8).unwrap()
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Send a connection rejection to the remote, and close the connection.
+    async fn reject(&mut self, peer_id: Uuid, reason: RejectReason) -> Result<(), Error> {
+        log::info!(
+            "Peer {} | Rejecting connection from {} | {reason}",
+            self.id.to_string().get(0..8).unwrap(),
+            peer_id.to_string().get(0..8).unwrap()
+        );
+        self.send(Message::ConnRejection(ConnRejection::new(
+            self.controller,
+            reason,
+        )))
+        .await?;
+        self.terminate(None).await
+    }
+
+    // Send a BYE, so that the remote doesn't wait for a timeout. The
+    // connection may already be broken, so failing to send is not an error.
+    async fn say_goodbye(&mut self, reason: Reason) {
+        let connected = matches!(
+            self.state,
+            PeerState::OutHandshaking
+                | PeerState::OutAlive
+                | PeerState::InHandshaking
+                | PeerState::InAlive
+        );
+        if !connected || self.sink.is_none() {
+            return;
+        }
+        if let Err(err) = self.send(Bye::new(reason).into()).await {
+            log::debug!(
+                "Peer {} | Could not say goodbye to remote | {err}",
+                self.id.to_string().get(0..8).unwrap()
+            );
+        }
+    }
+
     #[async_recursion]
     async fn handle_command(&mut self, command: Command) -> Result<(), Error> {
         match (self.state, command) {
@@ -479,10 +850,10 @@ impl Peer {
                 // We receive an address to connect to from the controller.
                 self.connect(&addr, attempt).await
             }
-            (PeerState::Idle, Command::Listen { stream }) => {
-                // The controller has a socket on which the peer
+            (PeerState::Idle, Command::Listen { conn }) => {
+                // The controller has a connection on which the peer
                 // need to listen.
-                self.listen(stream).await
+                self.listen(*conn).await
             }
             (PeerState::OutConnecting, Command::Connect { addr, attempt }) => {
                 // We're just retrying to connect
@@ -493,19 +864,24 @@ impl Peer {
                 // So we change our state to out-handshaking, and send a conn-request.
                 // TODO We need to check if we don't have too many connections,
                 self.state = PeerState::OutHandshaking;
-                let frame = Message::ConnRequest(ConnRequest::new(
-                    self.controller.to_string(),
+                let mut request = ConnRequest::new(
+                    self.controller,
                     self.label.clone(),
-                    self.controller_addr.to_string(),
-                ))
-                .into_frame()
-                .map_err(|err| Error::Message { source: err })?;
-                self.sink
-                    .as_mut()
-                    .unwrap()
-                    .send(frame)
-                    .await
-                    .map_err(|err| Error::Codec { source: err })
+                    self.controller_addr,
+                    self.handshake_compression(),
+                )
+                .with_public_key(self.identity.public_key().to_vec())
+                .with_nonce()
+                .with_network(self.network.clone());
+                if self.session_keys {
+                    let key_share = KeyShare::new();
+                    request = request.with_key_share(key_share.public_key().to_vec());
+                    self.key_share = Some(key_share);
+                }
+                let signature = self.identity.sign(&self.binding, &request.transcript());
+                self.request_signature = Some(signature.clone());
+                request.signature = Some(signature);
+                self.send(Message::ConnRequest(request)).await
             }
             (
                 PeerState::InHandshaking,
@@ -513,51 +889,221 @@ impl Peer {
                     peer_id,
                     peer_label,
                     peer_addr,
+                    compression,
+                    proof,
+                    network,
                 },
             ) => {
-                // Our listening thread has received a connection request,
-                // so we send back a connection response. Then we cross fingers,
-                // because we're expecting the message to arrive, so we
-                // set the state to InAlive, and notify the controller.
-                let frame = Message::ConnResponse(ConnResponse::new(
-                    self.controller.to_string(),
-                    self.label.clone(),
-                ))
-                .into_frame()
-                .map_err(|err| Error::Message { source: err })?;
-                self.sink
-                    .as_mut()
-                    .unwrap()
-                    .send(frame)
-                    .await
-                    .map_err(|err| Error::Codec { source: err })?;
-                self.state = PeerState::InAlive;
-                let event = Event::InAlive {
-                    id: self.id,
-                    peer_id: Uuid::parse_str(&peer_id).unwrap(),
-                    peer_label,
-                    peer_addr: SocketAddr::from_str(&peer_addr).unwrap(),
+                // Our listening thread has received a connection request. Unless
+                // the controller told us to reject it, or the remote must first
+                // answer a challenge, we accept it.
+                if let Some(reason) = self.rejection {
+                    return self.reject(peer_id, reason).await;
+                }
+                let network = network.map(|network| *network).unwrap_or_default();
+                if network != self.network {
+                    log::warn!(
+                        "Peer {} | Rejecting 'connection request' | Network '{network}', expected '{}'",
+                        self.id.to_string().get(0..8).unwrap(),
+                        self.network
+                    );
+                    return self.reject(peer_id, RejectReason::NetworkMismatch).await;
+                }
+                // The remote must hold the key its id is derived from.
+                let freshness = match proof {
+                    Some(proof) if proof.verify(peer_id, &self.binding) => {
+                        self.request_signature = Some(proof.signature);
+                        self.remote_share = proof.key_share;
+                        proof.freshness
+                    }
+                    _ => return self.reject(peer_id, RejectReason::Unauthenticated).await,
                 };
-                if let Err(err) = self.tx_evt.send(event).await {
-                    // We're in deep trouble here, we can't communicate with
-                    // the network controller. So we shutdown.
-                    return Err(Error::SendEvent {
-                        source: err,
-                        detail: format!(
-                            "Peer {} | Could not send 'established' to controller | Receiver dropped",
+                // The request must be fresh, not one captured and played again.
+                if let Some(replays) = &self.replays {
+                    let now = Utc::now().timestamp_micros();
+                    let fresh =
+                        freshness.is_some_and(|(nonce, ts)| replays.check(peer_id, nonce, ts, now));
+                    if !fresh {
+                        log::warn!(
+                            "Peer {} | Rejecting 'connection request' | Stale or replayed",
                             self.id.to_string().get(0..8).unwrap()
-                        ),
+                        );
+                        return self.reject(peer_id, RejectReason::Unauthenticated).await;
+                    }
+                }
+                let request = Challenge {
+                    nonce: Bytes::new(),
+                    difficulty: 0,
+                    peer_id,
+                    peer_label,
+                    peer_addr,
+                    compression,
+                };
+                // The work comes first, as it costs us the least.
+                if self.pow_difficulty > 0 {
+                    let nonce = Bytes::copy_from_slice(&auth::nonce());
+                    let difficulty = self.pow_difficulty;
+                    self.challenge = Some(Challenge {
+                        nonce: nonce.clone(),
+                        difficulty,
+                        ..request
                     });
+                    return self
+                        .send(Message::PowChallenge(PowChallenge::new(nonce, difficulty)))
+                        .await;
+                }
+                self.authenticate(request).await
+            }
+            (PeerState::InHandshaking, Command::PowResponseReceived { solution }) => {
+                let Some(challenge) = self.challenge.take_if(|challenge| challenge.difficulty > 0)
+                else {
+                    log::warn!(
+                        "Peer {} | Ignoring 'pow response' | No challenge sent",
+                        self.id.to_string().get(0..8).unwrap()
+                    );
+                    return Ok(());
+                };
+                if !pow::verify(&challenge.nonce, challenge.difficulty, solution) {
+                    return self
+                        .reject(challenge.peer_id, RejectReason::InsufficientWork)
+                        .await;
+                }
+                self.authenticate(challenge).await
+            }
+            (PeerState::InHandshaking, Command::AuthResponseReceived { mac }) => {
+                let challenge = self
+                    .challenge
+                    .take_if(|challenge| challenge.difficulty == 0);
+                let (Some(secret), Some(challenge)) = (&self.auth, challenge) else {
+                    log::warn!(
+                        "Peer {} | Ignoring 'auth response' | No challenge sent",
+                        self.id.to_string().get(0..8).unwrap()
+                    );
+                    return Ok(());
+                };
+                if !secret.verify(Side::Dialer, &challenge.nonce, &mac) {
+                    return self
+                        .reject(challenge.peer_id, RejectReason::Unauthenticated)
+                        .await;
+                }
+                // We prove we know the secret too, answering the request.
+                let request = self.request_signature.as_deref().unwrap_or_default();
+                let mac = Bytes::from(secret.sign(Side::Listener, request));
+                self.send(Message::AuthResponse(AuthResponse::new(mac)))
+                    .await?;
+                self.accept(
+                    challenge.peer_id,
+                    challenge.peer_label,
+                    challenge.peer_addr,
+                    challenge.compression,
+                )
+                .await
+            }
+            (PeerState::OutHandshaking, Command::AuthChallengeReceived { nonce }) => {
+                // Without a secret, the answer is empty, and the remote rejects us.
+                let mac = match &self.auth {
+                    Some(secret) => Bytes::from(secret.sign(Side::Dialer, &nonce)),
+                    None => {
+                        log::warn!(
+                            "Peer {} | Remote requires authentication | No secret",
+                            self.id.to_string().get(0..8).unwrap()
+                        );
+                        Bytes::new()
+                    }
+                };
+                self.send(Message::AuthResponse(AuthResponse::new(mac)))
+                    .await
+            }
+            (PeerState::OutHandshaking, Command::AuthResponseReceived { mac }) => {
+                // The remote answers our connection request, once we answered
+                // its challenge.
+                let Some(secret) = &self.auth else {
+                    log::warn!(
+                        "Peer {} | Ignoring 'auth response' | No secret",
+                        self.id.to_string().get(0..8).unwrap()
+                    );
+                    return Ok(());
+                };
+                let request = self.request_signature.as_deref().unwrap_or_default();
+                if !secret.verify(Side::Listener, request, &mac) {
+                    log::warn!(
+                        "Peer {} | Remote does not know the secret",
+                        self.id.to_string().get(0..8).unwrap()
+                    );
+                    return self.rejected(RejectReason::Unauthenticated).await;
                 }
+                self.proven = true;
                 Ok(())
             }
+            (PeerState::OutHandshaking, Command::PowChallengeReceived { nonce, difficulty }) => {
+                // Beyond the highest difficulty, we send no solution, and the
+                // remote rejects us.
+                let solution = if difficulty <= self.max_pow_difficulty.min(pow::MAX_DIFFICULTY) {
+                    pow::solve_blocking(nonce.to_vec(), difficulty)
+                        .await
+                        .unwrap_or(u64::MAX)
+                } else {
+                    log::warn!(
+                        "Peer {} | Remote requires too much work | Difficulty {difficulty}",
+                        self.id.to_string().get(0..8).unwrap()
+                    );
+                    u64::MAX
+                };
+                self.send(Message::PowResponse(PowResponse::new(solution)))
+                    .await
+            }
             (
                 PeerState::OutHandshaking,
                 Command::FinalizeConn {
                     peer_id,
                     peer_label,
+                    observed,
+                    compression,
+                    proof,
                 },
             ) => {
+                // With a secret, the remote must have proved it knows it: a
+                // remote which does not accepts anyone.
+                if self.auth.is_some() && !self.proven {
+                    log::warn!(
+                        "Peer {} | Remote accepted us without proving it knows the secret",
+                        self.id.to_string().get(0..8).unwrap()
+                    );
+                    return self.rejected(RejectReason::Unauthenticated).await;
+                }
+                // The remote must hold the key its id is derived from, and
+                // answer our request.
+                let context = self.request_signature.take().unwrap_or_default();
+                let Some(proof) = proof.filter(|proof| proof.verify(peer_id, &context)) else {
+                    log::warn!(
+                        "Peer {} | Invalid signature of the connection response",
+                        self.id.to_string().get(0..8).unwrap()
+                    );
+                    return self.rejected(RejectReason::Unauthenticated).await;
+                };
+                // The remote answers our ephemeral key with its own, unless
+                // it doesn't use session keys.
+                let mut opener = None;
+                if let Some((key_share, remote_share)) = self.key_share.take().zip(proof.key_share)
+                {
+                    let Some((send, receive)) = key_share.agree(&remote_share, true) else {
+                        log::warn!(
+                            "Peer {} | Invalid session key in the connection response",
+                            self.id.to_string().get(0..8).unwrap()
+                        );
+                        return self.rejected(RejectReason::Unauthenticated).await;
+                    };
+                    self.sealer = Some(send);
+                    opener = Some(receive);
+                }
+                // The receiving task waits for the keys, or for their absence,
+                // before the frames following the response.
+                self.opener.send_replace(opener);
+                self.remote_key = Some(proof.public_key);
+                // The remote can only accept the compression we offered.
+                self.enable_compression(
+                    compression.filter(|c| self.handshake_compression() == Some(*c)),
+                );
                 // We're done with the connection setup, now we're Alive.
                 // Change our state
                 // Notify the controller (not sure if its necessary, but its good tell the boss you're alive)
@@ -566,7 +1112,7 @@ impl Peer {
                 self.state = PeerState::OutAlive;
                 let event = Event::OutAlive {
                     id: self.id,
-                    peer_id: Uuid::parse_str(&peer_id).unwrap(),
+                    peer_id,
                     peer_label,
                     peer_addr: self.addr.unwrap(),
                 };
@@ -581,6 +1127,28 @@ impl Peer {
                         ),
                     });
                 }
+                if let Some(addr) = observed {
+                    let event = Event::AddrObserved {
+                        id: self.id,
+                        peer_id,
+                        addr,
+                    };
+                    if let Err(err) = self.tx_evt.send(event).await {
+                        return Err(Error::SendEvent {
+                            source: err,
+                            detail: format!(
+                                "Peer {} | Could not send 'address observed' to controller | Receiver dropped",
+                                self.id.to_string().get(0..8).unwrap()
+                            ),
+                        });
+                    }
+                }
+                // The codec options are agreed on now, in text.
+                if self.wire.negotiate {
+                    let options = self.wire.offer();
+                    self.send(Message::CodecOffer(CodecOffer { options }))
+                        .await?;
+                }
                 let handle = self.heartbeats().await?;
                 self.heartbeat_handle = Some(handle);
                 Ok(())
@@ -589,24 +1157,11 @@ impl Peer {
                 // We have received a periodic tick, and need to send a heartbeat request
                 // We also store a handle to a detached thread that will trigger a timeout
                 // if we haven't received a response before a configurable duration.
-                let frame = Message::HeartbeatRequest(HeartbeatRequest::now(
-                    self.id.to_string(),
-                    self.label.clone(),
+                let health = self.health();
+                self.send(Message::HeartbeatRequest(
+                    HeartbeatRequest::now(self.id, self.label.clone()).with_health(health),
                 ))
-                .into_frame()
-                .map_err(|err| Error::Message { source: err })?;
-                self.sink
-                    .as_mut()
-                    .unwrap()
-                    .send(frame)
-                    .await
-                    .map_err(|err| {
-                        log::warn!(
-                            "Peer {} | Could not send 'heartbeat request' to remote | {err}",
-                            self.id.to_string().get(0..8).unwrap()
-                        );
-                        Error::Codec { source: err }
-                    })?;
+                .await?;
                 log::trace!(
                     "Peer {} | Sent a 'heartbeat request'",
                     self.id.to_string().get(0..8).unwrap()
@@ -634,28 +1189,21 @@ impl Peer {
                 Ok(())
             }
             (PeerState::OutAlive, Command::SendContactRequest) => {
-                let frame = Message::ContactRequest(ContactRequest)
-                    .into_frame()
-                    .map_err(|err| Error::Message { source: err })?;
-                self.sink
-                    .as_mut()
-                    .unwrap()
-                    .send(frame)
-                    .await
-                    .map_err(|err| {
-                        log::warn!(
-                            "Peer {} | Could not send 'contact request' to remote | {err}",
-                            self.id.to_string().get(0..8).unwrap()
-                        );
-                        Error::Codec { source: err }
-                    })?;
+                self.send(Message::ContactRequest(ContactRequest)).await?;
                 log::trace!(
                     "Peer {} | Sent a 'contact request'",
                     self.id.to_string().get(0..8).unwrap()
                 );
                 Ok(())
             }
-            (PeerState::OutAlive, Command::CancelHeartbeatTimeout { rtt }) => {
+            (
+                PeerState::OutAlive,
+                Command::CancelHeartbeatTimeout {
+                    rtt,
+                    offset,
+                    health,
+                },
+            ) => {
                 // We have received a request to remove a task that would
                 // trigger a timeout, and we need to send the rtt to the
                 // controller so that he can update the connection status.
@@ -668,7 +1216,11 @@ impl Peer {
                         self.id.to_string().get(0..8).unwrap()
                     );
                 }
-                let msg = Event::ConnectionUpdate { id: self.id, rtt };
+                let msg = Event::ConnectionUpdate {
+                    id: self.id,
+                    rtt,
+                    offset,
+                };
                 if let Err(err) = self.tx_evt.send(msg).await {
                     // We're in deep trouble here, we can't communicate with
                     // the network controller. So we shutdown.
@@ -678,7 +1230,7 @@ impl Peer {
                         .to_owned(),
                     });
                 }
-                Ok(())
+                self.report_health(health).await
             }
             (PeerState::OutAlive, Command::HeartbeatTimeout) => {
                 // We have received a heartbeat timeout. Remote is not reachable => disconnect
@@ -689,7 +1241,16 @@ impl Peer {
                     "Peer {} | Heartbeat timeout | Disconnecting",
                     self.id.to_string().get(0..8).unwrap()
                 );
-                self.disconnect().await
+                self.timed_out().await?;
+                self.disconnect(Some(Reason::Timeout)).await
+            }
+            (PeerState::InHandshaking, Command::HeartbeatTimeout) => {
+                // The remote did not complete the handshake in time => terminate
+                log::warn!(
+                    "Peer {} | Handshake timeout | Terminating",
+                    self.id.to_string().get(0..8).unwrap()
+                );
+                self.terminate(Some(Reason::Timeout)).await
             }
             (PeerState::InAlive, Command::HeartbeatTimeout) => {
                 // We have received a heartbeat timeout. Remote is not reachable => terminate
@@ -697,41 +1258,22 @@ impl Peer {
                     "Peer {} | Heartbeat timeout | Terminating",
                     self.id.to_string().get(0..8).unwrap()
                 );
-                self.terminate().await
+                self.timed_out().await?;
+                self.terminate(Some(Reason::Timeout)).await
             }
-            (PeerState::InAlive, Command::HeartbeatResponse { src }) => {
+            (PeerState::InAlive, Command::HeartbeatResponse { src, health }) => {
                 // We have received a heartbeat request, and are
                 // asked to send a response back.
                 // We also store a handle to a thread
-                let frame = Message::HeartbeatResponse(HeartbeatResponse::now(
-                    self.controller.to_string(),
-                    self.label.clone(),
-                    src,
-                ))
-                .into_frame()
-                .map_err(|err| Error::Message { source: err })?;
-                self.sink
-                    .as_mut()
-                    .unwrap()
-                    .send(frame)
-                    .await
-                    .map_err(|err| Error::Codec { source: err })?;
+                self.report_health(health).await?;
+                let response = HeartbeatResponse::now(self.controller, self.label.clone(), src)
+                    .with_health(self.health());
+                self.send(Message::HeartbeatResponse(response)).await?;
                 log::trace!(
                     "Peer {} | Sent a heartbeat response.",
                     self.id.to_string().get(0..8).unwrap()
                 );
-                let timeout = self.heartbeat_timeout.try_into().unwrap();
-                let tx = self.tx_com.clone();
-                let id = self.id;
-                let handle = tokio::spawn(async move {
-                    time::sleep(Duration::from_secs(timeout)).await;
-                    if let Err(err) = tx.send(Command::HeartbeatTimeout).await {
-                        log::error!(
-                            "Peer {} | Could not send 'heartbeat timout' to itself. receiver dropped: {err}",
-                            id.to_string().get(0..8).unwrap()
-                            );
-                    }
-                });
+                let handle = self.timeout();
                 if let Some(old_handle) = &self.heartbeat_timeout_handle {
                     old_handle.abort();
                 }
@@ -755,55 +1297,731 @@ impl Peer {
                 }
                 Ok(())
             }
-            (PeerState::InAlive, Command::SendContactResponse { addrs }) => {
+            (
+                PeerState::InAlive,
+                Command::SendContactResponse {
+                    addrs,
+                    tags,
+                    contacts,
+                },
+            ) => {
                 log::trace!(
                     "Peer {} | Sending contacts to remote.",
                     self.id.to_string().get(0..8).unwrap()
                 );
-                let frame = Message::ContactResponse(ContactResponse::new(addrs))
-                    .into_frame()
-                    .map_err(|err| Error::Message { source: err })?;
-                self.sink
-                    .as_mut()
-                    .unwrap()
-                    .send(frame)
-                    .await
-                    .map_err(|err| {
-                        log::warn!(
-                            "Peer {} | Could not send 'contact response' to remote | {err}",
-                            self.id.to_string().get(0..8).unwrap()
-                        );
-                        Error::Codec { source: err }
-                    })?;
+                // The list is signed, so that we answer for its entries.
+                let mut response = ContactResponse::new(addrs, tags).with_contacts(contacts);
+                if !response.contacts.is_empty() {
+                    let signature = self.identity.sign(&[], &response.transcript());
+                    response.signature = Some(signature);
+                }
+                self.send(Message::ContactResponse(response)).await?;
                 log::info!(
                     "Peer {} | Sent a 'contact response'",
                     self.id.to_string().get(0..8).unwrap()
                 );
                 Ok(())
             }
-            (PeerState::OutAlive, Command::UpdateContacts { addrs }) => {
-                let msg = Event::ContactUpdated { id: self.id, addrs };
-                if let Err(err) = self.tx_evt.send(msg).await {
-                    // We're in deep trouble here, we can't communicate with
-                    // the network controller. So we shutdown.
-                    return Err(Error::SendEvent {
-                        source: err,
-                        detail: "Peer {} | Could not send 'contact updated' to controller | Receiver dropped."
-                        .to_owned(),
-                    });
-                }
-                Ok(())
-            }
-            (state, command) => {
+            (PeerState::OutAlive, Command::UpdateContacts { response }) => {
+                // A list with a signature the remote's key does not check is
+                // dropped.
+                let signed = match (&response.signature, &self.remote_key) {
+                    (Some(signature), Some(public_key)) => {
+                        let proof = Proof {
+                            public_key: public_key.clone(),
+                            transcript: response.transcript(),
+                            signature: signature.clone(),
+                            freshness: None,
+                            key_share: None,
+                        };
+                        proof.verify(identity::id(public_key), &[])
+                    }
+                    _ => false,
+                };
+                if response.signature.is_some() && !signed {
+                    log::warn!(
+                        "Peer {} | Dropping 'contact response' | Invalid signature",
+                        self.id.to_string().get(0..8).unwrap()
+                    );
+                    return Ok(());
+                }
+                if self.signed_contacts && !signed {
+                    log::debug!(
+                        "Peer {} | Dropping 'contact response' | Not signed",
+                        self.id.to_string().get(0..8).unwrap()
+                    );
+                    return Ok(());
+                }
+                let ContactResponse {
+                    addrs,
+                    tags,
+                    contacts,
+                    ..
+                } = *response;
+                let msg = Event::ContactUpdated {
+                    id: self.id,
+                    addrs,
+                    tags,
+                    contacts,
+                };
+                if let Err(err) = self.tx_evt.send(msg).await {
+                    // We're in deep trouble here, we can't communicate with
+                    // the network controller. So we shutdown.
+                    return Err(Error::SendEvent {
+                        source: err,
+                        detail: "Peer {} | Could not send 'contact updated' to controller | Receiver dropped."
+                        .to_owned(),
+                    });
+                }
+                Ok(())
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::SendRelay { message }) => {
+                self.send(message.into()).await
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::RelayReceived { message }) => {
+                let msg = Event::Relay {
+                    id: self.id,
+                    message,
+                };
+                if let Err(err) = self.tx_evt.send(msg).await {
+                    return Err(Error::SendEvent {
+                        source: err,
+                        detail: format!(
+                            "Peer {} | Could not send 'relay' to controller | Receiver dropped",
+                            self.id.to_string().get(0..8).unwrap()
+                        ),
+                    });
+                }
+                Ok(())
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::SendPayload { topic, data }) => {
+                self.send(Payload::new(topic, data).into()).await
+            }
+            (
+                PeerState::OutAlive | PeerState::InAlive,
+                Command::SendGossip {
+                    msg_id,
+                    ttl,
+                    topic,
+                    data,
+                },
+            ) => {
+                self.send(Gossip::new(msg_id, ttl, topic, data).into())
+                    .await
+            }
+            (
+                PeerState::OutAlive | PeerState::InAlive,
+                Command::GossipReceived {
+                    msg_id,
+                    ttl,
+                    topic,
+                    data,
+                },
+            ) => {
+                let msg = Event::Gossip {
+                    id: self.id,
+                    msg_id,
+                    ttl,
+                    topic,
+                    data,
+                };
+                if let Err(err) = self.tx_evt.send(msg).await {
+                    return Err(Error::SendEvent {
+                        source: err,
+                        detail: format!(
+                            "Peer {} | Could not send 'gossip' to controller | Receiver dropped",
+                            self.id.to_string().get(0..8).unwrap()
+                        ),
+                    });
+                }
+                Ok(())
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::SendCustom { tag, frame }) => {
+                self.send(Message::Unknown { tag, frame }).await
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::SendPing { seq, data }) => {
+                if self.pings.len() == MAX_PENDING_PINGS {
+                    self.pings.pop_front();
+                }
+                self.pings.push_back((seq, Instant::now()));
+                self.send(Ping::new(seq, data).into()).await
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::SendPong { seq, data }) => {
+                self.send(Pong::new(seq, data).into()).await
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::PongReceived { seq, size }) => {
+                let Some(index) = self.pings.iter().position(|(sent, _)| *sent == seq) else {
+                    log::debug!(
+                        "Peer {} | Ignoring 'pong' {seq} | No such ping",
+                        self.id.to_string().get(0..8).unwrap()
+                    );
+                    return Ok(());
+                };
+                let (_, sent) = self.pings.remove(index).unwrap(); // safe, we have the index
+                let rtt = sent.elapsed().as_micros() as i64;
+                let msg = Event::Pong {
+                    id: self.id,
+                    seq,
+                    rtt,
+                    size,
+                };
+                if let Err(err) = self.tx_evt.send(msg).await {
+                    return Err(Error::SendEvent {
+                        source: err,
+                        detail: format!(
+                            "Peer {} | Could not send 'pong' to controller | Receiver dropped",
+                            self.id.to_string().get(0..8).unwrap()
+                        ),
+                    });
+                }
+                Ok(())
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::SendInfoRequest) => {
+                self.send(Message::InfoRequest(InfoRequest)).await
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::InfoRequested) => {
+                let msg = Event::InfoRequested { id: self.id };
+                if let Err(err) = self.tx_evt.send(msg).await {
+                    return Err(Error::SendEvent {
+                        source: err,
+                        detail: format!(
+                            "Peer {} | Could not send 'info requested' to controller | Receiver dropped",
+                            self.id.to_string().get(0..8).unwrap()
+                        ),
+                    });
+                }
+                Ok(())
+            }
+            (PeerState::InAlive, Command::CodecOffered { options }) => {
+                // Our answer is the last frame sent with the previous options.
+                let options = self.wire.agree(options);
+                self.send(Message::CodecAccept(CodecAccept { options }))
+                    .await?;
+                self.switch_codec(options);
+                Ok(())
+            }
+            (PeerState::OutAlive, Command::CodecAccepted { options }) => {
+                // The remote can only accept the options we offered, or fall
+                // back to those every node understands.
+                let offer = self.wire.offer();
+                if options.format != offer.format && options.format != Format::Text
+                    || options.compression.is_some() && options.compression != offer.compression
+                    || options.integers != offer.integers && options.integers != IntEncoding::Fixed
+                {
+                    log::warn!(
+                        "Peer {} | Codec options accepted by the remote were not offered",
+                        self.id.to_string().get(0..8).unwrap()
+                    );
+                    return Ok(());
+                }
+                self.switch_codec(options);
+                Ok(())
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::SendInfoResponse { info }) => {
+                self.send(Message::InfoResponse(*info)).await
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::InfoReceived { info }) => {
+                let msg = Event::Info { id: self.id, info };
+                if let Err(err) = self.tx_evt.send(msg).await {
+                    return Err(Error::SendEvent {
+                        source: err,
+                        detail: format!(
+                            "Peer {} | Could not send 'info' to controller | Receiver dropped",
+                            self.id.to_string().get(0..8).unwrap()
+                        ),
+                    });
+                }
+                Ok(())
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::SendFindNode { target }) => {
+                self.send(Message::FindNode(FindNode { target })).await
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::FindNodeRequested { target }) => {
+                let msg = Event::FindNode {
+                    id: self.id,
+                    target,
+                };
+                if let Err(err) = self.tx_evt.send(msg).await {
+                    return Err(Error::SendEvent {
+                        source: err,
+                        detail: format!(
+                            "Peer {} | Could not send 'find node' to controller | Receiver dropped",
+                            self.id.to_string().get(0..8).unwrap()
+                        ),
+                    });
+                }
+                Ok(())
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::SendNodes { target, nodes }) => {
+                self.send(Message::Nodes(Nodes { target, nodes })).await
+            }
+            (
+                PeerState::OutAlive | PeerState::InAlive,
+                Command::NodesReceived { target, nodes },
+            ) => {
+                let msg = Event::Nodes {
+                    id: self.id,
+                    target,
+                    nodes,
+                };
+                if let Err(err) = self.tx_evt.send(msg).await {
+                    return Err(Error::SendEvent {
+                        source: err,
+                        detail: format!(
+                            "Peer {} | Could not send 'nodes' to controller | Receiver dropped",
+                            self.id.to_string().get(0..8).unwrap()
+                        ),
+                    });
+                }
+                Ok(())
+            }
+            (PeerState::OutAlive | PeerState::InAlive, Command::SendChunk) => {
+                if let Some(chunk) = self.chunks.pop_front() {
+                    let frame = self.encode(Message::Chunk(chunk))?;
+                    self.send_frame(Chunk::TAG, frame).await?;
+                }
+                if !self.chunks.is_empty() {
+                    self.schedule_chunk();
+                }
+                Ok(())
+            }
+            (
+                PeerState::OutAlive | PeerState::InAlive,
+                Command::PayloadReceived { topic, data },
+            ) => {
+                let msg = Event::Payload {
+                    id: self.id,
+                    topic,
+                    data,
+                };
+                if let Err(err) = self.tx_evt.send(msg).await {
+                    return Err(Error::SendEvent {
+                        source: err,
+                        detail: format!(
+                            "Peer {} | Could not send 'payload' to controller | Receiver dropped",
+                            self.id.to_string().get(0..8).unwrap()
+                        ),
+                    });
+                }
+                Ok(())
+            }
+            (PeerState::OutHandshaking, Command::ConnRejected { reason }) => {
+                self.rejected(reason).await
+            }
+            (
+                PeerState::OutConnecting | PeerState::OutHandshaking | PeerState::OutAlive,
+                Command::ByeReceived { reason },
+            ) => {
+                log::info!(
+                    "Peer {} | Remote said goodbye ({reason}) | Disconnecting",
+                    self.id.to_string().get(0..8).unwrap()
+                );
+                self.disconnect(None).await
+            }
+            (PeerState::InHandshaking | PeerState::InAlive, Command::ByeReceived { reason }) => {
                 log::info!(
-                    "Peer {} | Unhandled command '{}' in state '{}'",
+                    "Peer {} | Remote said goodbye ({reason}) | Terminating",
+                    self.id.to_string().get(0..8).unwrap()
+                );
+                self.terminate(None).await
+            }
+            (
+                PeerState::OutConnecting | PeerState::OutHandshaking | PeerState::OutAlive,
+                Command::Disconnect,
+            ) => self.disconnect(Some(Reason::Shutdown)).await,
+            (PeerState::InHandshaking | PeerState::InAlive, Command::Terminate) => {
+                self.terminate(Some(Reason::Shutdown)).await
+            }
+            (state, Command::ViolationReceived { detail, close }) => {
+                let event = Event::ProtocolViolation {
+                    id: self.id,
+                    detail,
+                };
+                if let Err(err) = self.tx_evt.send(event).await {
+                    return Err(Error::SendEvent {
+                        source: err,
+                        detail: format!(
+                            "Peer {} | Could not send 'protocol violation' to controller | Receiver dropped",
+                            self.id.to_string().get(0..8).unwrap()
+                        ),
+                    });
+                }
+                match state {
+                    PeerState::OutHandshaking | PeerState::OutAlive if close => {
+                        self.disconnect(Some(Reason::Error)).await
+                    }
+                    PeerState::InHandshaking | PeerState::InAlive if close => {
+                        self.terminate(Some(Reason::Error)).await
+                    }
+                    _ => Ok(()),
+                }
+            }
+            // The messages registered by the application are always passed
+            // on, for the controller to dispatch them.
+            (_, Command::UnknownReceived { tag, frame }) => match self.wire.unknown_messages {
+                _ if self.registry.id(&tag).is_some() => self.notify_unknown(tag, frame).await,
+                UnknownMessages::Ignore => Ok(()),
+                UnknownMessages::Log => {
+                    log::info!(
+                        "Peer {} | Ignoring unknown message '{tag}' from remote",
+                        self.id.to_string().get(0..8).unwrap()
+                    );
+                    Ok(())
+                }
+                UnknownMessages::Notify => self.notify_unknown(tag, frame).await,
+            },
+            (state, command) => {
+                match awaited_state(&command) {
+                    Some(awaited) if precedes(state, awaited) => {
+                        if self.deferred.len() < MAX_DEFERRED_COMMANDS {
+                            log::debug!(
+                                "Peer {} | Deferring command '{}' until state '{}'",
+                                self.id.to_string().get(0..8).unwrap(),
+                                command.to_string(),
+                                awaited.to_string(),
+                            );
+                            self.deferred.push_back((awaited, command));
+                        } else {
+                            log::warn!(
+                                "Peer {} | Too many deferred commands, dropping '{}' in state '{}'",
+                                self.id.to_string().get(0..8).unwrap(),
+                                command.to_string(),
+                                state.to_string(),
+                            );
+                        }
+                    }
+                    _ => {
+                        log::info!(
+                            "Peer {} | Unhandled command '{}' in state '{}'",
+                            self.id.to_string().get(0..8).unwrap(),
+                            command.to_string(),
+                            state.to_string(),
+                        );
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Compression offered in the handshake: none when it is negotiated afterwards.
+    fn handshake_compression(&self) -> Option<Compression> {
+        self.wire.compression.filter(|_| !self.wire.negotiate)
+    }
+
+    // Send the frames with the agreed codec options from now on.
+    fn switch_codec(&self, options: CodecOptions) {
+        log::debug!(
+            "Peer {} | Sending {} frames, with {} integers",
+            self.id.to_string().get(0..8).unwrap(),
+            options.format.name(),
+            options.integers.name()
+        );
+        self.switcher.switch(options.format, options.integers);
+        self.enable_compression(options.compression);
+    }
+
+    // Compress the frames sent from now on, if a compression was negotiated.
+    fn enable_compression(&self, compression: Option<Compression>) {
+        if let Some(compression) = compression {
+            log::debug!(
+                "Peer {} | Compressing frames with {}",
+                self.id.to_string().get(0..8).unwrap(),
+                compression.name()
+            );
+            self.compressor
+                .enable(compression, self.wire.compression_threshold);
+        }
+    }
+
+    // Health of our node, as seen from this peer.
+    fn health(&self) -> Health {
+        let pending = self.tx_com.max_capacity() - self.tx_com.capacity();
+        Health {
+            peers: self.metrics.connected.load(Ordering::Relaxed),
+            queue: (pending + self.chunks.len()) as u64,
+            uptime: self.metrics.started.elapsed().as_secs(),
+        }
+    }
+
+    // Hand the health sent by the remote over to the controller.
+    async fn report_health(&self, health: Option<Health>) -> Result<(), Error> {
+        let Some(health) = health else {
+            return Ok(());
+        };
+        let msg = Event::HealthUpdate {
+            id: self.id,
+            health,
+        };
+        if let Err(err) = self.tx_evt.send(msg).await {
+            return Err(Error::SendEvent {
+                source: err,
+                detail: format!(
+                    "Peer {} | Could not send 'health update' to controller | Receiver dropped",
+                    self.id.to_string().get(0..8).unwrap()
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    // Pass a message we don't know on to the controller.
+    async fn notify_unknown(&mut self, tag: String, frame: Frame) -> Result<(), Error> {
+        let msg = Event::UnknownMessage {
+            id: self.id,
+            tag,
+            frame,
+        };
+        if let Err(err) = self.tx_evt.send(msg).await {
+            return Err(Error::SendEvent {
+                source: err,
+                detail: format!(
+                    "Peer {} | Could not send 'unknown message' to controller | Receiver dropped",
+                    self.id.to_string().get(0..8).unwrap()
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Send a message to the remote peer.
+    /// A message larger than the ceiling configured for its tag is dropped.
+    async fn send(&mut self, msg: Message) -> Result<(), Error> {
+        let tag = msg.tag().to_owned();
+        let frame = self.encode(msg)?;
+        let size = frame.encoded_len();
+        if exceeds(&self.max_message_sizes, &tag, size) {
+            log::warn!(
+                "Peer {} | Dropping '{tag}' to remote | {size} bytes exceeds ceiling",
+                self.id.to_string().get(0..8).unwrap()
+            );
+            self.metrics.record_dropped(&tag);
+            return Ok(());
+        }
+        match self.wire.chunk_size {
+            Some(chunk_size) if size > chunk_size => {
+                let chunks = chunk::split(&frame, self.next_stream, chunk_size, &self.frame_limits)
+                    .map_err(|err| Error::Message { source: err.into() })?;
+                log::debug!(
+                    "Peer {} | Sending '{tag}' in {} chunks",
                     self.id.to_string().get(0..8).unwrap(),
-                    command.to_string(),
-                    state.to_string(),
+                    chunks.len()
                 );
+                self.next_stream += 1;
+                if self.chunks.is_empty() {
+                    self.schedule_chunk();
+                }
+                self.chunks.extend(chunks);
+                self.metrics.record_sent(&tag, size);
                 Ok(())
             }
+            _ => self.send_frame(&tag, frame).await,
+        }
+    }
+
+    // The frame of a message, starting with its id rather than its tag if
+    // configured so.
+    fn encode(&self, msg: Message) -> Result<Frame, Error> {
+        let frame = msg
+            .into_frame()
+            .map_err(|err| Error::Message { source: err })?;
+        if self.wire.numeric_ids {
+            Ok(self.registry.with_id(frame))
+        } else {
+            Ok(frame)
+        }
+    }
+
+    // Send the next chunk once the commands already queued are processed, so
+    // that they are not held up by a large frame.
+    fn schedule_chunk(&self) {
+        let tx = self.tx_com.clone();
+        tokio::spawn(async move {
+            let _ = tx.send(Command::SendChunk).await;
+        });
+    }
+
+    async fn send_frame(&mut self, tag: &str, frame: Frame) -> Result<(), Error> {
+        let size = frame.encoded_len();
+        // Messages are delayed in order, like on a slow link.
+        if let Some(impairment) = self.impairment {
+            match impairment.sample() {
+                Some(delay) => time::sleep(delay).await,
+                None => {
+                    log::debug!(
+                        "Peer {} | Dropping '{tag}' to remote | Impairment",
+                        self.id.to_string().get(0..8).unwrap()
+                    );
+                    self.metrics.record_dropped(tag);
+                    return Ok(());
+                }
+            }
         }
+        // Frames lost on the way would fail the sequence of the remote:
+        // frames are sealed once sure to be sent.
+        let frame = match &mut self.sealer {
+            Some(sealer) => sealer.seal(frame),
+            None => frame,
+        };
+        self.sink
+            .as_mut()
+            .unwrap()
+            .send(frame)
+            .await
+            .map_err(|err| {
+                log::warn!(
+                    "Peer {} | Could not send '{tag}' to remote | {err}",
+                    self.id.to_string().get(0..8).unwrap()
+                );
+                Error::Codec { source: err }
+            })?;
+        self.metrics.record_sent(tag, size);
+        Ok(())
+    }
+
+    /// Ceilings of the messages received, checked by the codec before they are
+    /// decoded. The messages dropped are counted.
+    fn ceilings(&self) -> Ceilings {
+        let id = self.id;
+        let metrics = self.metrics.clone();
+        let ceilings =
+            Ceilings::new(self.max_message_sizes.clone()).registry(self.registry.clone());
+        ceilings.on_drop(move |tag, size| {
+            log::warn!(
+                "Peer {} | Dropping '{tag}' from remote | {size} bytes exceeds ceiling",
+                id.to_string().get(0..8).unwrap()
+            );
+            metrics.record_dropped(tag);
+        })
+    }
+
+    /// Spawn a thread which decodes messages from the remote peer, and hands
+    /// them over to 'handle_message'.
+    fn receive(&self, mut stream: SplitStream<Frames>) -> JoinHandle<()> {
+        let id = self.id;
+        let tx_com = self.tx_com.clone();
+        let metrics = self.metrics.clone();
+        let max_message_sizes = self.max_message_sizes.clone();
+        let registry = self.registry.clone();
+        let mut reassembler = Reassembler::new(self.frame_limits);
+        let mut keys = self.opener.subscribe();
+        let mut opener: Option<Opener> = None;
+        tokio::spawn(async move {
+            while let Some(frame) = stream.next().await {
+                match frame {
+                    Ok(frame) => {
+                        // The remote may seal its frames before the peer derives
+                        // the session keys. Once it has, every frame must be sealed.
+                        if opener.is_none()
+                            && session::is_sealed(&frame)
+                            && keys.wait_for(Option::is_some).await.is_err()
+                        {
+                            break;
+                        }
+                        if opener.is_none() {
+                            opener = keys.borrow().clone();
+                        }
+                        // An altered frame is dropped, but a frame without an
+                        // HMAC was injected in the connection, which is closed.
+                        let sealed = session::is_sealed(&frame);
+                        let frame = match opener.as_mut() {
+                            Some(opener) => match opener.open(frame) {
+                                Ok(frame) => frame,
+                                Err(err) => {
+                                    log::warn!(
+                                        "Peer {} | Dropping frame from remote | {err}",
+                                        id.to_string().get(0..8).unwrap()
+                                    );
+                                    let command = Command::ViolationReceived {
+                                        detail: err.detail,
+                                        close: !sealed,
+                                    };
+                                    if tx_com.send(command).await.is_err() || !sealed {
+                                        break;
+                                    }
+                                    continue;
+                                }
+                            },
+                            None => frame,
+                        };
+                        let size = frame.encoded_len();
+                        // The frame comes from the remote: it may not be a message we know.
+                        let msg = match Message::from_frame_in(frame, &registry) {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                log::warn!(
+                                    "Peer {} | Dropping invalid message from remote | {err}",
+                                    id.to_string().get(0..8).unwrap()
+                                );
+                                continue;
+                            }
+                        };
+                        // The codec skips the frames over their ceiling, but it
+                        // only sees the chunks of a chunked message.
+                        let (msg, size) = match msg {
+                            Message::Chunk(chunk) => {
+                                metrics.record_received(Chunk::TAG, size);
+                                match reassemble(&mut reassembler, chunk, &registry) {
+                                    Ok(Some(reassembled)) => reassembled,
+                                    Ok(None) => continue,
+                                    Err(err) => {
+                                        log::warn!(
+                                            "Peer {} | Dropping chunked frame from remote | {err}",
+                                            id.to_string().get(0..8).unwrap()
+                                        );
+                                        continue;
+                                    }
+                                }
+                            }
+                            msg => (msg, size),
+                        };
+                        let tag = msg.tag();
+                        if exceeds(&max_message_sizes, tag, size) {
+                            log::warn!(
+                                "Peer {} | Dropping '{tag}' from remote | {size} bytes exceeds ceiling",
+                                id.to_string().get(0..8).unwrap()
+                            );
+                            metrics.record_dropped(tag);
+                            continue;
+                        }
+                        metrics.record_received(tag, size);
+                        let response = matches!(msg, Message::ConnResponse(_));
+                        if let Err(err) = handle_message(id, msg, tx_com.clone()).await {
+                            log::error!("Error handling a message: {err}");
+                        }
+                        // The frames following the connection response are
+                        // checked with the session keys derived from it, if any,
+                        // so that none passes unchecked in the meantime.
+                        if response {
+                            if keys.changed().await.is_err() {
+                                break;
+                            }
+                            opener = keys.borrow_and_update().clone();
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("Error from message stream {}", err);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Spawn a thread which sends a 'heartbeat timeout' command to the peer
+    /// once the heartbeat timeout expires.
+    fn timeout(&self) -> JoinHandle<()> {
+        let timeout = self.heartbeat_timeout.try_into().unwrap();
+        let tx = self.tx_com.clone();
+        let id = self.id;
+        tokio::spawn(async move {
+            time::sleep(Duration::from_secs(timeout)).await;
+            if let Err(err) = tx.send(Command::HeartbeatTimeout).await {
+                log::error!(
+                    "Peer {} | Could not send 'heartbeat timout' to itself. receiver dropped: {err}",
+                    id.to_string().get(0..8).unwrap()
+                );
+            }
+        })
     }
 
     async fn heartbeats(&self) -> Result<JoinHandle<()>, Error> {
@@ -827,6 +2045,96 @@ impl Peer {
     }
 }
 
+/// Returns true if a message of the given tag and size exceeds its ceiling.
+fn exceeds(max_message_sizes: &HashMap<String, usize>, tag: &str, size: usize) -> bool {
+    max_message_sizes
+        .get(tag)
+        .is_some_and(|max_size| size > *max_size)
+}
+
+/// The state a command can be deferred until, if it arrives early.
+/// Timing related commands (heartbeats, timeouts) and commands
+/// driving the lifecycle are never deferred.
+fn awaited_state(command: &Command) -> Option<PeerState> {
+    match command {
+        Command::SendConnRequest => Some(PeerState::OutConnecting),
+        Command::FinalizeConn { .. } => Some(PeerState::OutHandshaking),
+        Command::SendContactRequest | Command::UpdateContacts { .. } => Some(PeerState::OutAlive),
+        Command::SendConnResponse { .. } => Some(PeerState::InHandshaking),
+        Command::CodecAccepted { .. } => Some(PeerState::OutAlive),
+        Command::RequestContacts
+        | Command::SendContactResponse { .. }
+        | Command::HeartbeatResponse { .. }
+        | Command::CodecOffered { .. } => Some(PeerState::InAlive),
+        _ => None,
+    }
+}
+
+/// Add a chunk received from the remote, and return the message and its
+/// size once all its chunks are received.
+fn reassemble(
+    reassembler: &mut Reassembler,
+    chunk: Chunk,
+    registry: &Registry,
+) -> Result<Option<(Message, usize)>, message::Error> {
+    match reassembler.push(chunk)? {
+        Some(frame) => {
+            let size = frame.encoded_len();
+            Ok(Some((Message::from_frame_in(frame, registry)?, size)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Returns true if a peer in `state` can still reach `awaited`, following the
+/// outgoing (idle, connecting, handshaking, alive) or incoming (idle,
+/// handshaking, alive) lifecycle.
+fn precedes(state: PeerState, awaited: PeerState) -> bool {
+    const OUT: [PeerState; 4] = [
+        PeerState::Idle,
+        PeerState::OutConnecting,
+        PeerState::OutHandshaking,
+        PeerState::OutAlive,
+    ];
+    const IN: [PeerState; 3] = [
+        PeerState::Idle,
+        PeerState::InHandshaking,
+        PeerState::InAlive,
+    ];
+    [&OUT[..], &IN[..]].iter().any(|lifecycle| {
+        let position = |s: PeerState| lifecycle.iter().position(|l| *l == s);
+        matches!((position(state), position(awaited)), (Some(s), Some(a)) if s < a)
+    })
+}
+
+// Round trip time and clock offset of the remote, from the time (micros) we
+// sent a heartbeat request, the time the remote answered, and the time we got
+// its response. As with NTP, the remote is assumed to answer halfway through
+// the round trip, so the offset is how far its clock is ahead of ours.
+fn clock(src: i64, dst: i64, now: i64) -> (i64, i64) {
+    let rtt = now - src;
+    let offset = ((dst - src) + (dst - now)) / 2;
+    (rtt, offset)
+}
+
+// The signature of a handshake message, if the remote sent one.
+fn proof(
+    transcript: Vec<u8>,
+    public_key: Option<Vec<u8>>,
+    signature: Option<Vec<u8>>,
+    freshness: Option<(u64, i64)>,
+    key_share: Option<Vec<u8>>,
+) -> Option<Box<Proof>> {
+    let (public_key, signature) = public_key.zip(signature)?;
+    Some(Box::new(Proof {
+        public_key,
+        transcript,
+        signature,
+        freshness,
+        key_share,
+    }))
+}
+
 async fn handle_message(id: Uuid, msg: Message, tx: Sender<Command>) -> Result<(), Error> {
     match msg {
         Message::ConnRequest(conn_request) => {
@@ -834,13 +2142,23 @@ async fn handle_message(id: Uuid, msg: Message, tx: Sender<Command>) -> Result<(
             log::info!(
                 "Peer {} | Received a 'connection request' from {}",
                 id.to_string().get(0..8).unwrap(),
-                conn_request.id.get(0..8).unwrap()
+                conn_request.id.to_string().get(0..8).unwrap()
             );
             if let Err(err) = tx
                 .send(Command::SendConnResponse {
-                    peer_id: conn_request.id().to_owned(),
+                    peer_id: conn_request.id(),
                     peer_label: conn_request.label().to_owned(),
-                    peer_addr: conn_request.address().to_owned(),
+                    peer_addr: conn_request.address(),
+                    compression: conn_request.compression(),
+                    network: Some(Box::new(conn_request.network.clone()))
+                        .filter(|network| !network.is_empty()),
+                    proof: proof(
+                        conn_request.transcript(),
+                        conn_request.public_key,
+                        conn_request.signature,
+                        conn_request.nonce.zip(conn_request.timestamp),
+                        conn_request.key_share,
+                    ),
                 })
                 .await
             {
@@ -854,12 +2172,21 @@ async fn handle_message(id: Uuid, msg: Message, tx: Sender<Command>) -> Result<(
             log::info!(
                 "Peer {} | Received a connection response from {}",
                 id.to_string().get(0..8).unwrap(),
-                conn_response.id.get(0..8).unwrap()
+                conn_response.id.to_string().get(0..8).unwrap()
             );
             if let Err(err) = tx
                 .send(Command::FinalizeConn {
-                    peer_id: conn_response.id().to_owned(),
+                    peer_id: conn_response.id(),
                     peer_label: conn_response.label().to_owned(),
+                    observed: conn_response.observed(),
+                    compression: conn_response.compression(),
+                    proof: proof(
+                        conn_response.transcript(),
+                        conn_response.public_key,
+                        conn_response.signature,
+                        None,
+                        conn_response.key_share,
+                    ),
                 })
                 .await
             {
@@ -869,11 +2196,17 @@ async fn handle_message(id: Uuid, msg: Message, tx: Sender<Command>) -> Result<(
                         );
             }
         }
-        Message::ConnRejection(_conn_rejection) => {
+        Message::ConnRejection(conn_rejection) => {
             log::info!(
-                "Peer {} | Received a 'connection rejection'",
-                id.to_string().get(0..8).unwrap()
+                "Peer {} | Received a 'connection rejection' from {}",
+                id.to_string().get(0..8).unwrap(),
+                conn_rejection.id.to_string().get(0..8).unwrap()
             );
+            tx.send(Command::ConnRejected {
+                reason: conn_rejection.reason(),
+            })
+            .await
+            .expect("Cannot send command to self");
         }
         Message::HeartbeatRequest(heartbeat_request) => {
             log::trace!(
@@ -884,6 +2217,7 @@ async fn handle_message(id: Uuid, msg: Message, tx: Sender<Command>) -> Result<(
             // timestamp so that it can be forwarded back to the origin.
             tx.send(Command::HeartbeatResponse {
                 src: heartbeat_request.src(),
+                health: heartbeat_request.health,
             })
             .await
             .expect("Cannot send command to self");
@@ -891,16 +2225,21 @@ async fn handle_message(id: Uuid, msg: Message, tx: Sender<Command>) -> Result<(
         Message::HeartbeatResponse(heartbeat_response) => {
             let dt = Utc::now();
             let ts = dt.timestamp_micros();
-            let rtt = ts - heartbeat_response.src();
+            let (rtt, offset) = clock(heartbeat_response.src(), heartbeat_response.dst(), ts);
             log::trace!(
-                "Peer {} | Received a 'heartbeat response' from {} | RTT {} μs",
+                "Peer {} | Received a 'heartbeat response' from {} | RTT {} μs | Offset {} μs",
                 id.to_string().get(0..8).unwrap(),
                 heartbeat_response.label(),
-                rtt
+                rtt,
+                offset
             );
-            tx.send(Command::CancelHeartbeatTimeout { rtt })
-                .await
-                .expect("Cannot send command to self");
+            tx.send(Command::CancelHeartbeatTimeout {
+                rtt,
+                offset,
+                health: heartbeat_response.health,
+            })
+            .await
+            .expect("Cannot send command to self");
         }
         Message::ContactRequest(_) => {
             log::info!(
@@ -917,15 +2256,213 @@ async fn handle_message(id: Uuid, msg: Message, tx: Sender<Command>) -> Result<(
                 id.to_string().get(0..8).unwrap()
             );
             tx.send(Command::UpdateContacts {
-                addrs: contact_response
-                    .addrs()
-                    .iter()
-                    .map(|addr| SocketAddr::from_str(addr).unwrap())
-                    .collect(),
+                response: Box::new(contact_response),
+            })
+            .await
+            .expect("Cannot send command to self");
+        }
+        msg @ (Message::RelayOpen(_) | Message::RelayData(_) | Message::RelayClose(_)) => {
+            log::trace!(
+                "Peer {} | Received a '{}'",
+                id.to_string().get(0..8).unwrap(),
+                msg.tag()
+            );
+            let message = RelayMessage::from_message(msg).expect("relay message");
+            tx.send(Command::RelayReceived { message })
+                .await
+                .expect("Cannot send command to self");
+        }
+        Message::Chunk(chunk) => {
+            log::warn!(
+                "Peer {} | Ignoring a chunk nested in stream {}",
+                id.to_string().get(0..8).unwrap(),
+                chunk.stream
+            );
+        }
+        Message::Payload(payload) => {
+            log::trace!(
+                "Peer {} | Received a 'payload' of {} bytes",
+                id.to_string().get(0..8).unwrap(),
+                payload.data.len()
+            );
+            tx.send(Command::PayloadReceived {
+                topic: payload.topic,
+                data: payload.data,
+            })
+            .await
+            .expect("Cannot send command to self");
+        }
+        Message::Gossip(gossip) => {
+            log::trace!(
+                "Peer {} | Received a 'gossip' of {} bytes",
+                id.to_string().get(0..8).unwrap(),
+                gossip.data.len()
+            );
+            tx.send(Command::GossipReceived {
+                msg_id: gossip.id,
+                ttl: gossip.ttl,
+                topic: gossip.topic,
+                data: gossip.data,
+            })
+            .await
+            .expect("Cannot send command to self");
+        }
+        Message::Ping(ping) => {
+            log::trace!(
+                "Peer {} | Received a 'ping' of {} bytes",
+                id.to_string().get(0..8).unwrap(),
+                ping.data.len()
+            );
+            tx.send(Command::SendPong {
+                seq: ping.seq,
+                data: ping.data,
+            })
+            .await
+            .expect("Cannot send command to self");
+        }
+        Message::Pong(pong) => {
+            log::trace!(
+                "Peer {} | Received a 'pong' of {} bytes",
+                id.to_string().get(0..8).unwrap(),
+                pong.data.len()
+            );
+            tx.send(Command::PongReceived {
+                seq: pong.seq,
+                size: pong.data.len(),
+            })
+            .await
+            .expect("Cannot send command to self");
+        }
+        Message::InfoRequest(_) => {
+            log::trace!(
+                "Peer {} | Received an 'info request'",
+                id.to_string().get(0..8).unwrap()
+            );
+            tx.send(Command::InfoRequested)
+                .await
+                .expect("Cannot send command to self");
+        }
+        Message::InfoResponse(info) => {
+            log::trace!(
+                "Peer {} | Received an 'info response' from '{}'",
+                id.to_string().get(0..8).unwrap(),
+                info.label
+            );
+            tx.send(Command::InfoReceived {
+                info: Box::new(info),
+            })
+            .await
+            .expect("Cannot send command to self");
+        }
+        Message::FindNode(find) => {
+            log::trace!(
+                "Peer {} | Received a 'find node'",
+                id.to_string().get(0..8).unwrap()
+            );
+            tx.send(Command::FindNodeRequested {
+                target: find.target,
+            })
+            .await
+            .expect("Cannot send command to self");
+        }
+        Message::Nodes(nodes) => {
+            log::trace!(
+                "Peer {} | Received {} 'nodes'",
+                id.to_string().get(0..8).unwrap(),
+                nodes.nodes.len()
+            );
+            tx.send(Command::NodesReceived {
+                target: nodes.target,
+                nodes: nodes.nodes,
+            })
+            .await
+            .expect("Cannot send command to self");
+        }
+        Message::CodecOffer(offer) => {
+            log::trace!(
+                "Peer {} | Received a 'codec offer'",
+                id.to_string().get(0..8).unwrap()
+            );
+            tx.send(Command::CodecOffered {
+                options: offer.options,
+            })
+            .await
+            .expect("Cannot send command to self");
+        }
+        Message::CodecAccept(accept) => {
+            log::trace!(
+                "Peer {} | Received a 'codec accept'",
+                id.to_string().get(0..8).unwrap()
+            );
+            tx.send(Command::CodecAccepted {
+                options: accept.options,
+            })
+            .await
+            .expect("Cannot send command to self");
+        }
+        Message::AuthChallenge(challenge) => {
+            log::trace!(
+                "Peer {} | Received an 'auth challenge'",
+                id.to_string().get(0..8).unwrap()
+            );
+            tx.send(Command::AuthChallengeReceived {
+                nonce: challenge.nonce,
+            })
+            .await
+            .expect("Cannot send command to self");
+        }
+        Message::AuthResponse(response) => {
+            log::trace!(
+                "Peer {} | Received an 'auth response'",
+                id.to_string().get(0..8).unwrap()
+            );
+            tx.send(Command::AuthResponseReceived { mac: response.mac })
+                .await
+                .expect("Cannot send command to self");
+        }
+        Message::PowChallenge(challenge) => {
+            log::trace!(
+                "Peer {} | Received a 'pow challenge'",
+                id.to_string().get(0..8).unwrap()
+            );
+            tx.send(Command::PowChallengeReceived {
+                nonce: challenge.nonce,
+                difficulty: challenge.difficulty,
+            })
+            .await
+            .expect("Cannot send command to self");
+        }
+        Message::PowResponse(response) => {
+            log::trace!(
+                "Peer {} | Received a 'pow response'",
+                id.to_string().get(0..8).unwrap()
+            );
+            tx.send(Command::PowResponseReceived {
+                solution: response.solution,
+            })
+            .await
+            .expect("Cannot send command to self");
+        }
+        Message::Bye(bye) => {
+            log::trace!(
+                "Peer {} | Received a 'bye'",
+                id.to_string().get(0..8).unwrap()
+            );
+            tx.send(Command::ByeReceived {
+                reason: bye.reason(),
             })
             .await
             .expect("Cannot send command to self");
         }
+        Message::Unknown { tag, frame } => {
+            log::debug!(
+                "Peer {} | Received an unknown message '{tag}'",
+                id.to_string().get(0..8).unwrap()
+            );
+            tx.send(Command::UnknownReceived { tag, frame })
+                .await
+                .expect("Cannot send command to self");
+        }
     }
     Ok(())
 }
@@ -998,3 +2535,30 @@ impl fmt::Display for Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_defer_commands_until_a_later_state() {
+        let state = awaited_state(&Command::SendConnRequest).unwrap();
+        assert!(precedes(PeerState::Idle, state));
+        assert!(!precedes(PeerState::OutConnecting, state));
+        assert!(!precedes(PeerState::InHandshaking, state));
+
+        let state = awaited_state(&Command::RequestContacts).unwrap();
+        assert!(precedes(PeerState::InHandshaking, state));
+        assert!(!precedes(PeerState::OutHandshaking, state));
+
+        assert!(awaited_state(&Command::HeartbeatTimeout).is_none());
+    }
+
+    #[test]
+    fn should_estimate_the_clock_offset() {
+        // The remote clock is 5ms ahead, and each way takes 1ms.
+        assert_eq!(clock(100_000, 106_000, 102_000), (2_000, 5_000));
+        // The remote clock is 5ms behind.
+        assert_eq!(clock(100_000, 96_000, 102_000), (2_000, -5_000));
+    }
+}
@@ -0,0 +1,170 @@
+//! Peer tags and connection policies.
+//!
+//! Peers can carry tags, like their region or role, given in the target file
+//! or learned from contacts. Policies bound the number of outgoing connections
+//! to the peers sharing a tag value (eg at least 2 connections per region), and
+//! steer which idle addresses the controller dials.
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Tags of a peer, by name (eg 'region' => 'eu-west').
+pub type Tags = BTreeMap<String, String>;
+
+/// Configuration of a connection policy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Policy {
+    /// Name of the tag (eg 'region'). The bounds apply to each value of the tag.
+    pub tag: String,
+    /// Minimum number of connections per value.
+    /// Addresses of the values below their minimum are dialed first.
+    pub min: Option<usize>,
+    /// Maximum number of connections per value.
+    /// Addresses of the values at their maximum are not dialed.
+    pub max: Option<usize>,
+}
+
+/// Connection policies, applied to the dialing decisions.
+#[derive(Debug, Clone, Default)]
+pub struct Policies {
+    policies: Vec<Policy>,
+}
+
+/// Number of outgoing connections and connection attempts, by tag and value.
+#[derive(Debug, Default)]
+pub struct Counts(HashMap<(String, String), usize>);
+
+/// Error type for tags and policies
+#[derive(Debug)]
+pub struct Error {
+    /// Error detail
+    pub detail: String,
+}
+
+impl Policies {
+    /// Build the policies from their configuration.
+    pub fn new(policies: &[Policy]) -> Result<Policies, Error> {
+        for policy in policies {
+            if let (Some(min), Some(max)) = (policy.min, policy.max) {
+                if min > max {
+                    return Err(Error {
+                        detail: format!(
+                            "Policy on '{}' has a minimum ({min}) above its maximum ({max})",
+                            policy.tag
+                        ),
+                    });
+                }
+            }
+        }
+        Ok(Policies {
+            policies: policies.to_vec(),
+        })
+    }
+
+    /// Returns true if a peer with these tags would bring one of its
+    /// tag values closer to its minimum.
+    pub fn wanted(&self, tags: &Tags, counts: &Counts) -> bool {
+        self.policies.iter().any(|policy| match policy.min {
+            Some(min) => tags
+                .get(&policy.tag)
+                .is_some_and(|value| counts.get(&policy.tag, value) < min),
+            None => false,
+        })
+    }
+
+    /// Returns true if a connection to a peer with these tags would take
+    /// one of its tag values beyond its maximum.
+    pub fn saturated(&self, tags: &Tags, counts: &Counts) -> bool {
+        self.policies.iter().any(|policy| match policy.max {
+            Some(max) => tags
+                .get(&policy.tag)
+                .is_some_and(|value| counts.get(&policy.tag, value) >= max),
+            None => false,
+        })
+    }
+}
+
+impl Counts {
+    /// Count the tag values of the given peers.
+    pub fn new<'a>(peers: impl IntoIterator<Item = &'a Tags>) -> Counts {
+        let mut counts = HashMap::new();
+        for tags in peers {
+            for (tag, value) in tags {
+                *counts.entry((tag.clone(), value.clone())).or_default() += 1;
+            }
+        }
+        Counts(counts)
+    }
+
+    /// Number of peers with this tag value.
+    pub fn get(&self, tag: &str, value: &str) -> usize {
+        self.0
+            .get(&(tag.to_owned(), value.to_owned()))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Check that tags can be sent to remote peers, where they are encoded as
+/// 'name=value' pairs separated by commas.
+pub fn validate(tags: &Tags) -> Result<(), Error> {
+    for (tag, value) in tags {
+        let invalid = |s: &str| s.is_empty() || s.contains([',', '=', '\r', '\n']);
+        if invalid(tag) || invalid(value) {
+            return Err(Error {
+                detail: format!(
+                    "'{}={}' is empty or contains ',', '=', CR or LF",
+                    tag.escape_default(),
+                    value.escape_default()
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid tags or policy: {}", self.detail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(region: &str) -> Tags {
+        Tags::from([("region".to_owned(), region.to_owned())])
+    }
+
+    #[test]
+    fn should_steer_dialing_by_tag_value() {
+        let policies = Policies::new(&[Policy {
+            tag: "region".to_owned(),
+            min: Some(2),
+            max: Some(3),
+        }])
+        .unwrap();
+        let connected = [tags("eu"), tags("eu"), tags("eu"), tags("us")];
+        let counts = Counts::new(&connected);
+        assert!(!policies.wanted(&tags("eu"), &counts));
+        assert!(policies.saturated(&tags("eu"), &counts));
+        assert!(policies.wanted(&tags("us"), &counts));
+        assert!(!policies.saturated(&tags("us"), &counts));
+        assert!(!policies.wanted(&Tags::new(), &counts));
+        assert!(!policies.saturated(&Tags::new(), &counts));
+    }
+
+    #[test]
+    fn should_reject_invalid_tags_and_policies() {
+        assert!(validate(&tags("eu-west")).is_ok());
+        assert!(validate(&tags("eu,us")).is_err());
+        assert!(validate(&tags("")).is_err());
+        assert!(Policies::new(&[Policy {
+            tag: "region".to_owned(),
+            min: Some(3),
+            max: Some(2),
+        }])
+        .is_err());
+    }
+}
@@ -0,0 +1,90 @@
+//! Proof-of-work admission.
+//!
+//! On an open network, anyone can connect, and a single host can open many
+//! connections under many identities. A node may make each connection cost
+//! some work: its incoming peers answer a connection request with a random
+//! nonce and a difficulty, and the remote must find a number whose SHA-256
+//! hash with the nonce starts with as many zero bits. Each extra bit doubles
+//! the work of the remote, while checking the solution takes a single hash.
+use ring::digest::{self, SHA256};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::task;
+
+/// Highest difficulty a node works for. Remotes asking for more are
+/// answered with no solution. Nodes may set a lower one.
+pub const MAX_DIFFICULTY: u64 = 32;
+
+/// Number of hashes between two checks of the cancellation flag.
+const CHECK_INTERVAL: u64 = 1 << 12;
+
+/// Returns true if the hash of the nonce and the solution starts with
+/// `difficulty` zero bits.
+pub fn verify(nonce: &[u8], difficulty: u64, solution: u64) -> bool {
+    let mut ctx = digest::Context::new(&SHA256);
+    ctx.update(nonce);
+    ctx.update(&solution.to_be_bytes());
+    leading_zeros(ctx.finish().as_ref()) >= difficulty
+}
+
+/// Find the smallest solution to the challenge. This takes about
+/// 2^difficulty hashes, so run it off the async runtime. The search gives up
+/// once `cancelled` is set.
+pub fn solve(nonce: &[u8], difficulty: u64, cancelled: &AtomicBool) -> Option<u64> {
+    (0..u64::MAX)
+        .take_while(|solution| solution % CHECK_INTERVAL != 0 || !cancelled.load(Ordering::Relaxed))
+        .find(|solution| verify(nonce, difficulty, *solution))
+}
+
+/// Solve the challenge on a blocking thread. The search stops once the
+/// returned future is dropped, eg when the peer waiting for it is aborted.
+pub async fn solve_blocking(nonce: Vec<u8>, difficulty: u64) -> Option<u64> {
+    let cancel = Cancel(Arc::new(AtomicBool::new(false)));
+    let cancelled = cancel.0.clone();
+    task::spawn_blocking(move || solve(&nonce, difficulty, &cancelled))
+        .await
+        .ok()
+        .flatten()
+}
+
+// Sets the flag when dropped.
+struct Cancel(Arc<AtomicBool>);
+
+impl Drop for Cancel {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+fn leading_zeros(hash: &[u8]) -> u64 {
+    let mut zeros = 0;
+    for byte in hash {
+        zeros += u64::from(byte.leading_zeros());
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_verify_solutions_of_the_difficulty() {
+        let nonce = b"nonce";
+        let solution = solve(nonce, 12, &AtomicBool::new(false)).unwrap();
+        assert!(verify(nonce, 12, solution));
+        assert!(verify(nonce, 8, solution));
+        // The solution found is the smallest one.
+        assert!((0..solution).all(|solution| !verify(nonce, 12, solution)));
+        assert!(verify(nonce, 0, 0));
+        assert_eq!(leading_zeros(&[0, 0x1f, 0xff]), 11);
+    }
+
+    #[test]
+    fn should_give_up_once_cancelled() {
+        assert_eq!(solve(b"nonce", 64, &AtomicBool::new(true)), None);
+    }
+}
@@ -0,0 +1,285 @@
+//! QUIC transport.
+//!
+//! Nodes behind lossy links get faster handshakes, and built-in encryption,
+//! with QUIC (quinn). Each connection between two nodes is a QUIC connection
+//! carrying a single bidirectional stream, which is framed with the
+//! `FrameCodec` like a TCP stream, so that peers run the same state machine
+//! over both transports. QUIC always uses TLS: the listener presents a
+//! certificate, and the dialer verifies it against its trust anchors, with
+//! the ALPN protocol identifier `ALPN_PROTOCOL`.
+use futures::future::BoxFuture;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+
+use super::tls;
+use super::transport::{Connection, Guard, Listener, Transport};
+
+/// Delay for a remote node to complete the QUIC handshake and open its stream.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The QUIC transport.
+#[derive(Clone, Default)]
+pub struct Quic {
+    server: Option<quinn::ServerConfig>,
+    client: Option<(quinn::ClientConfig, String)>,
+}
+
+// The bidirectional stream of a QUIC connection.
+#[derive(Debug)]
+struct QuicStream {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+}
+
+// Accepts QUIC connections, and waits for their stream in separate tasks,
+// so that a slow remote does not hold the other connections back.
+struct QuicListener {
+    endpoint: quinn::Endpoint,
+    guard: Guard,
+    tx: mpsc::Sender<Connection>,
+    rx: mpsc::Receiver<Connection>,
+}
+
+impl Quic {
+    /// Create a QUIC transport, which can neither listen nor dial yet.
+    pub fn new() -> Quic {
+        Quic::default()
+    }
+
+    /// Listen with the certificate chain and private key read from the given PEM files.
+    pub fn with_identity(mut self, cert: &Path, key: &Path) -> Result<Quic, tls::Error> {
+        let config = tls::server_config(cert, key)?;
+        self.server = Some(quinn::ServerConfig::with_crypto(Arc::new(config)));
+        Ok(self)
+    }
+
+    /// Dial trusting the certificates read from the given PEM file.
+    /// Remote certificates are verified against the server name.
+    pub fn with_trust_anchors(mut self, ca: &Path, server_name: &str) -> Result<Quic, tls::Error> {
+        let config = tls::client_config(ca)?;
+        self.client = Some((
+            quinn::ClientConfig::new(Arc::new(config)),
+            server_name.to_owned(),
+        ));
+        Ok(self)
+    }
+}
+
+impl fmt::Debug for Quic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Quic")
+            .field("listen", &self.server.is_some())
+            .field("outgoing", &self.client.is_some())
+            .finish()
+    }
+}
+
+impl Transport for Quic {
+    fn dial<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Connection>> {
+        Box::pin(async move {
+            unsupported_interface(interface)?;
+            let (config, server_name) = self.client.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "QUIC needs trust anchors to dial",
+                )
+            })?;
+            let bind = match addr {
+                SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            };
+            // Each connection has its own endpoint, which lives as long as the connection.
+            let endpoint = quinn::Endpoint::client(bind)?;
+            let connection = endpoint
+                .connect_with(config.clone(), addr, server_name)
+                .map_err(io::Error::other)?
+                .await?;
+            let (send, recv) = connection.open_bi().await?;
+            Ok(Connection {
+                stream: Box::new(QuicStream { send, recv }),
+                local_addr: endpoint.local_addr()?,
+                peer_addr: connection.remote_address(),
+                binding: Vec::new(),
+            })
+        })
+    }
+
+    fn listen<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            unsupported_interface(interface)?;
+            let config = self.server.clone().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "QUIC needs a certificate to listen",
+                )
+            })?;
+            let endpoint = quinn::Endpoint::server(config, addr)?;
+            let (tx, rx) = mpsc::channel(16);
+            Ok(Box::new(QuicListener {
+                endpoint,
+                guard: Guard::default(),
+                tx,
+                rx,
+            }) as Box<dyn Listener>)
+        })
+    }
+}
+
+impl Listener for QuicListener {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Connection>> {
+        Box::pin(async move {
+            let local_addr = self.endpoint.local_addr()?;
+            loop {
+                tokio::select! {
+                    connecting = self.endpoint.accept() => {
+                        let connecting = connecting.ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::NotConnected, "QUIC endpoint closed")
+                        })?;
+                        let remote = connecting.remote_address();
+                        // Dropping the connection closes it.
+                        let permit = match self.guard.enter(&remote) {
+                            Some(permit) => permit,
+                            None => continue,
+                        };
+                        let tx = self.tx.clone();
+                        tokio::spawn(async move {
+                            let handshake = async {
+                                let connection = connecting.await?;
+                                let (send, recv) = connection.accept_bi().await?;
+                                Ok::<_, io::Error>(Connection {
+                                    stream: Box::new(QuicStream { send, recv }),
+                                    local_addr,
+                                    peer_addr: connection.remote_address(),
+                                    binding: Vec::new(),
+                                })
+                            };
+                            let res = timeout(HANDSHAKE_TIMEOUT, handshake).await;
+                            drop(permit);
+                            match res {
+                                Ok(Ok(conn)) => {
+                                    let _ = tx.send(conn).await;
+                                }
+                                Ok(Err(err)) => {
+                                    log::warn!("QUIC | Handshake with {remote} failed | {err}");
+                                }
+                                Err(_) => {
+                                    log::warn!("QUIC | Handshake with {remote} timed out");
+                                }
+                            }
+                        });
+                    }
+                    Some(conn) = self.rx.recv() => return Ok(conn),
+                }
+            }
+        })
+    }
+
+    fn guard(&mut self, guard: Guard) {
+        self.guard = guard;
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+// QUIC endpoints are UDP sockets created by quinn, which cannot be
+// restricted to a network interface.
+fn unsupported_interface(interface: Option<&str>) -> io::Result<()> {
+    match interface {
+        Some(interface) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("QUIC cannot be restricted to interface {interface}, use an address instead"),
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn pem_file(content: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    #[tokio::test]
+    async fn should_dial_and_accept_quic_connections() {
+        let cert = rcgen::generate_simple_self_signed(vec!["node.area-net".to_owned()]).unwrap();
+        let cert_file = pem_file(&cert.serialize_pem().unwrap());
+        let key_file = pem_file(&cert.serialize_private_key_pem());
+
+        let server = Quic::new()
+            .with_identity(cert_file.path(), key_file.path())
+            .unwrap();
+        let client = Quic::new()
+            .with_trust_anchors(cert_file.path(), "node.area-net")
+            .unwrap();
+        let mut listener = server
+            .listen("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The stream is only announced to the listener once the dialer writes.
+        let mut dialed = client.dial(addr, None).await.unwrap();
+        dialed.stream.write_all(b"*1\r\n").await.unwrap();
+        let mut accepted = listener.accept().await.unwrap();
+        assert_eq!(accepted.peer_addr.port(), dialed.local_addr.port());
+
+        let mut buf = [0u8; 4];
+        accepted.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"*1\r\n");
+        accepted.stream.write_all(b"*2\r\n").await.unwrap();
+        dialed.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"*2\r\n");
+    }
+}
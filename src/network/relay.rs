@@ -0,0 +1,521 @@
+//! Relay (circuit) mode.
+//!
+//! Two nodes behind NAT cannot dial each other, but they can both dial a
+//! publicly reachable node. A relay node forwards the frames of circuits
+//! between the peers connected to it: the routing table (`Circuits`) maps each
+//! circuit to the peers at its two ends.
+//!
+//! On the other nodes, the `Relay` transport dials peers directly, and when
+//! that fails, opens a circuit through the peer connected to the relay node.
+//! The circuit is a virtual connection: its byte stream is carried in relay
+//! messages, so that peers run the same state machine over it. Circuits opened
+//! by remote nodes are accepted by the listener like any other connection.
+//! Encrypted transports are layered on top of the relay transport, so that the
+//! relay node only forwards encrypted bytes.
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+use uuid::Uuid;
+
+use super::command::Command;
+use super::transport::{Connection, Listener, Listeners, Transport};
+use crate::message::{Message, RelayClose, RelayData, RelayOpen};
+
+/// Largest payload of a data message.
+pub const MAX_PAYLOAD_LEN: usize = 16 * 1024;
+
+/// Delay for a direct connection, after which the peer is dialed through the relay.
+const DIRECT_DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Data messages received on a circuit, waiting to be read by the peer.
+const CIRCUIT_BUFFER: usize = 64;
+
+/// A relay message, exchanged between the peers and the controller.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RelayMessage {
+    /// See RelayOpen
+    Open {
+        /// id of the circuit
+        circuit: Uuid,
+        /// address of the other end
+        addr: SocketAddr,
+    },
+    /// See RelayData
+    Data {
+        /// id of the circuit
+        circuit: Uuid,
+        /// bytes carried by the circuit
+        data: Vec<u8>,
+    },
+    /// See RelayClose
+    Close {
+        /// id of the circuit
+        circuit: Uuid,
+    },
+}
+
+impl RelayMessage {
+    /// The relay message carried by a message, if it is one.
+    pub fn from_message(msg: Message) -> Option<RelayMessage> {
+        match msg {
+            Message::RelayOpen(open) => Some(RelayMessage::Open {
+                circuit: open.circuit,
+                addr: open.addr,
+            }),
+            Message::RelayData(data) => Some(RelayMessage::Data {
+                circuit: data.circuit,
+                data: data.data,
+            }),
+            Message::RelayClose(close) => Some(RelayMessage::Close {
+                circuit: close.circuit,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Id of the circuit
+    pub fn circuit(&self) -> Uuid {
+        match self {
+            RelayMessage::Open { circuit, .. }
+            | RelayMessage::Data { circuit, .. }
+            | RelayMessage::Close { circuit } => *circuit,
+        }
+    }
+}
+
+impl From<RelayMessage> for Message {
+    fn from(msg: RelayMessage) -> Self {
+        match msg {
+            RelayMessage::Open { circuit, addr } => {
+                Message::RelayOpen(RelayOpen::new(circuit, addr))
+            }
+            RelayMessage::Data { circuit, data } => {
+                Message::RelayData(RelayData::new(circuit, data))
+            }
+            RelayMessage::Close { circuit } => Message::RelayClose(RelayClose::new(circuit)),
+        }
+    }
+}
+
+/// Routing table of a relay node: the peers at the two ends of each circuit.
+#[derive(Debug, Default)]
+pub struct Circuits {
+    ends: HashMap<Uuid, (Uuid, Uuid)>,
+    max_circuits: usize,
+    max_per_peer: usize,
+}
+
+impl Circuits {
+    /// Relay at most `max_circuits` circuits at once, of which a peer opens
+    /// at most `max_per_peer`.
+    pub fn new(max_circuits: usize, max_per_peer: usize) -> Circuits {
+        Circuits {
+            ends: HashMap::new(),
+            max_circuits,
+            max_per_peer,
+        }
+    }
+
+    /// Open a circuit between two peers. Returns false if the circuit already
+    /// exists, or if there are too many circuits, overall or opened by the peer.
+    pub fn open(&mut self, circuit: Uuid, from: Uuid, to: Uuid) -> bool {
+        if self.ends.len() >= self.max_circuits || self.ends.contains_key(&circuit) {
+            return false;
+        }
+        let opened = self.ends.values().filter(|(a, _)| *a == from).count();
+        if opened >= self.max_per_peer {
+            return false;
+        }
+        self.ends.insert(circuit, (from, to));
+        true
+    }
+
+    /// The other end of a circuit the peer is an end of.
+    pub fn route(&self, circuit: Uuid, from: Uuid) -> Option<Uuid> {
+        match self.ends.get(&circuit) {
+            Some((a, b)) if *a == from => Some(*b),
+            Some((a, b)) if *b == from => Some(*a),
+            _ => None,
+        }
+    }
+
+    /// Close a circuit the peer is an end of, and return the other end.
+    pub fn close(&mut self, circuit: Uuid, from: Uuid) -> Option<Uuid> {
+        let to = self.route(circuit, from)?;
+        self.ends.remove(&circuit);
+        Some(to)
+    }
+
+    /// Close the circuits of a peer which is gone, and return each circuit
+    /// with its other end.
+    pub fn remove_peer(&mut self, peer: Uuid) -> Vec<(Uuid, Uuid)> {
+        let closed = self
+            .ends
+            .keys()
+            .filter_map(|circuit| Some((*circuit, self.route(*circuit, peer)?)))
+            .collect::<Vec<_>>();
+        for (circuit, _) in &closed {
+            self.ends.remove(circuit);
+        }
+        closed
+    }
+}
+
+/// The relay transport.
+pub struct Relay {
+    inner: Arc<dyn Transport>,
+    via: SocketAddr,
+    link: Arc<Mutex<Link>>,
+    accepted: mpsc::Sender<Connection>,
+    // Taken by the first listener.
+    pending: Mutex<Option<mpsc::Receiver<Connection>>>,
+}
+
+// The peer connected to the relay node, and the circuits opened through it.
+#[derive(Default)]
+struct Link {
+    peer: Option<(Uuid, mpsc::Sender<Command>)>,
+    circuits: HashMap<Uuid, mpsc::Sender<Vec<u8>>>,
+}
+
+// Accepts the circuits opened by remote nodes.
+struct RelayListener {
+    addr: SocketAddr,
+    rx: mpsc::Receiver<Connection>,
+}
+
+impl Relay {
+    /// Dial with the given transport, and through the relay node at `via`
+    /// when a direct connection fails.
+    pub fn new(inner: Arc<dyn Transport>, via: SocketAddr) -> Relay {
+        let (accepted, pending) = mpsc::channel(16);
+        Relay {
+            inner,
+            via,
+            link: Arc::new(Mutex::new(Link::default())),
+            accepted,
+            pending: Mutex::new(Some(pending)),
+        }
+    }
+
+    /// Address of the relay node.
+    pub fn via(&self) -> SocketAddr {
+        self.via
+    }
+
+    /// Open circuits through the given peer, which is connected to the relay node.
+    pub fn attach(&self, peer: Uuid, tx: mpsc::Sender<Command>) {
+        let mut link = self.link.lock().expect("relay lock");
+        link.peer = Some((peer, tx));
+        link.circuits.clear();
+    }
+
+    /// The peer is gone. If it was connected to the relay node, its circuits are closed.
+    pub fn detach(&self, peer: Uuid) {
+        let mut link = self.link.lock().expect("relay lock");
+        if link.peer.as_ref().map(|(id, _)| *id) == Some(peer) {
+            link.peer = None;
+            link.circuits.clear();
+        }
+    }
+
+    /// Returns true if the peer is connected to the relay node.
+    pub fn is_link(&self, peer: Uuid) -> bool {
+        let link = self.link.lock().expect("relay lock");
+        link.peer.as_ref().map(|(id, _)| *id) == Some(peer)
+    }
+
+    /// Handle a relay message received from the relay node.
+    pub fn handle(&self, msg: RelayMessage) {
+        match msg {
+            RelayMessage::Open { circuit, addr } => {
+                let link = self.link.lock().expect("relay lock");
+                // The circuit in use is not replaced.
+                if link.circuits.contains_key(&circuit) {
+                    log::warn!("Relay | Refusing circuit {circuit} from {addr} | Already open");
+                    return;
+                }
+                let tx = match &link.peer {
+                    Some((_, tx)) => tx.clone(),
+                    None => return,
+                };
+                drop(link);
+                let conn = Connection {
+                    stream: Box::new(self.bridge(circuit, tx.clone())),
+                    local_addr: unspecified(),
+                    peer_addr: addr,
+                    binding: Vec::new(),
+                };
+                if self.accepted.try_send(conn).is_err() {
+                    log::warn!("Relay | Dropping circuit from {addr} | Not listening");
+                    self.close(circuit, tx);
+                }
+            }
+            RelayMessage::Data { circuit, data } => {
+                let link = self.link.lock().expect("relay lock");
+                let full = match link.circuits.get(&circuit) {
+                    Some(tx) => tx.try_send(data).is_err(),
+                    None => false,
+                };
+                let tx = link.peer.as_ref().map(|(_, tx)| tx.clone());
+                drop(link);
+                if let (true, Some(tx)) = (full, tx) {
+                    log::warn!("Relay | Closing circuit {circuit} | Data is not read");
+                    self.close(circuit, tx);
+                }
+            }
+            RelayMessage::Close { circuit } => {
+                // The end of the stream is seen by the peer reading the circuit.
+                self.link
+                    .lock()
+                    .expect("relay lock")
+                    .circuits
+                    .remove(&circuit);
+            }
+        }
+    }
+
+    fn close(&self, circuit: Uuid, tx: mpsc::Sender<Command>) {
+        self.link
+            .lock()
+            .expect("relay lock")
+            .circuits
+            .remove(&circuit);
+        tokio::spawn(async move {
+            let message = RelayMessage::Close { circuit };
+            let _ = tx.send(Command::SendRelay { message }).await;
+        });
+    }
+
+    // The connection's byte stream is one end of an in-memory pipe. A task sends
+    // what is written to it in data messages, and the data messages received
+    // on the circuit are written to it.
+    fn bridge(&self, circuit: Uuid, tx: mpsc::Sender<Command>) -> DuplexStream {
+        let (local, remote) = tokio::io::duplex(MAX_PAYLOAD_LEN);
+        let (mut reader, mut writer) = tokio::io::split(local);
+        let (data_tx, mut data_rx) = mpsc::channel::<Vec<u8>>(CIRCUIT_BUFFER);
+        self.link
+            .lock()
+            .expect("relay lock")
+            .circuits
+            .insert(circuit, data_tx);
+        let link = self.link.clone();
+        tokio::spawn(async move {
+            let mut payload = vec![0u8; MAX_PAYLOAD_LEN];
+            loop {
+                let data = match reader.read(&mut payload).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => payload[..n].to_vec(),
+                };
+                let message = RelayMessage::Data { circuit, data };
+                if tx.send(Command::SendRelay { message }).await.is_err() {
+                    return;
+                }
+            }
+            // Unless the remote end closed the circuit first.
+            let open = link
+                .lock()
+                .expect("relay lock")
+                .circuits
+                .remove(&circuit)
+                .is_some();
+            if open {
+                let message = RelayMessage::Close { circuit };
+                let _ = tx.send(Command::SendRelay { message }).await;
+            }
+        });
+        tokio::spawn(async move {
+            while let Some(data) = data_rx.recv().await {
+                if writer.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+            let _ = writer.shutdown().await;
+        });
+        remote
+    }
+}
+
+impl fmt::Debug for Relay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Relay")
+            .field("inner", &self.inner)
+            .field("via", &self.via)
+            .finish()
+    }
+}
+
+impl Transport for Relay {
+    fn dial<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Connection>> {
+        if addr == self.via {
+            return self.inner.dial(addr, interface);
+        }
+        Box::pin(async move {
+            let err = match timeout(DIRECT_DIAL_TIMEOUT, self.inner.dial(addr, interface)).await {
+                Ok(Ok(conn)) => return Ok(conn),
+                Ok(Err(err)) => err,
+                Err(_) => io::Error::new(io::ErrorKind::TimedOut, "Direct connection timed out"),
+            };
+            let tx = match &self.link.lock().expect("relay lock").peer {
+                Some((_, tx)) => tx.clone(),
+                None => return Err(err),
+            };
+            log::debug!("Relay | Dialing {addr} through {} | {err}", self.via);
+            let circuit = Uuid::new_v4();
+            let stream = self.bridge(circuit, tx.clone());
+            let message = RelayMessage::Open { circuit, addr };
+            tx.send(Command::SendRelay { message })
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "Relay link is gone"))?;
+            Ok(Connection {
+                stream: Box::new(stream),
+                local_addr: unspecified(),
+                peer_addr: addr,
+                binding: Vec::new(),
+            })
+        })
+    }
+
+    fn listen<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            let listener = self.inner.listen(addr, interface).await?;
+            match self.pending.lock().expect("relay lock").take() {
+                Some(rx) => Ok(Box::new(Listeners(vec![
+                    listener,
+                    Box::new(RelayListener { addr, rx }),
+                ])) as Box<dyn Listener>),
+                None => Ok(listener),
+            }
+        })
+    }
+}
+
+impl Listener for RelayListener {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Connection>> {
+        Box::pin(async move {
+            self.rx
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Relay is gone"))
+        })
+    }
+}
+
+// Circuits have no local address.
+fn unspecified() -> SocketAddr {
+    SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::transport::Tcp;
+
+    #[test]
+    fn should_route_between_the_ends_of_a_circuit() {
+        let mut circuits = Circuits::new(2, 1);
+        let (circuit, alice, bob, carol) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+        assert!(circuits.open(circuit, alice, bob));
+        assert!(!circuits.open(circuit, carol, bob));
+        // A peer cannot take all the circuits.
+        assert!(!circuits.open(Uuid::new_v4(), alice, carol));
+        let other = Uuid::new_v4();
+        assert!(circuits.open(other, carol, alice));
+        assert!(!circuits.open(Uuid::new_v4(), bob, carol));
+        assert_eq!(circuits.close(other, alice), Some(carol));
+        assert_eq!(circuits.route(circuit, alice), Some(bob));
+        assert_eq!(circuits.route(circuit, bob), Some(alice));
+        assert_eq!(circuits.route(circuit, carol), None);
+        assert_eq!(circuits.remove_peer(bob), vec![(circuit, alice)]);
+        assert_eq!(circuits.close(circuit, alice), None);
+    }
+
+    // Two nodes linked through a relay, played by this test: the messages one
+    // node sends to its link are handed over to the other node.
+    #[tokio::test]
+    async fn should_carry_a_connection_over_a_circuit() {
+        let unreachable: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let via: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let alice = Relay::new(Arc::new(Tcp::default()), via);
+        let bob = Relay::new(Arc::new(Tcp::default()), via);
+        let mut listener = bob
+            .listen("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let (alice_tx, mut alice_rx) = mpsc::channel(16);
+        let (bob_tx, mut bob_rx) = mpsc::channel(16);
+        alice.attach(Uuid::new_v4(), alice_tx);
+        bob.attach(Uuid::new_v4(), bob_tx);
+
+        let mut dialed = alice.dial(unreachable, None).await.unwrap();
+        let open = match alice_rx.recv().await {
+            Some(Command::SendRelay { message }) => message,
+            _ => panic!("expected a relay message"),
+        };
+        assert!(matches!(open, RelayMessage::Open { addr, .. } if addr == unreachable));
+        bob.handle(RelayMessage::Open {
+            circuit: open.circuit(),
+            addr: "203.0.113.7:8090".parse().unwrap(),
+        });
+        let mut accepted = listener.accept().await.unwrap();
+        assert_eq!(accepted.peer_addr, "203.0.113.7:8090".parse().unwrap());
+
+        // A circuit already open is not opened again.
+        bob.handle(RelayMessage::Open {
+            circuit: open.circuit(),
+            addr: "203.0.113.8:8090".parse().unwrap(),
+        });
+        let again = timeout(Duration::from_millis(50), listener.accept()).await;
+        assert!(again.is_err());
+
+        dialed.stream.write_all(b"*1\r\n").await.unwrap();
+        match alice_rx.recv().await {
+            Some(Command::SendRelay { message }) => bob.handle(message),
+            _ => panic!("expected a relay message"),
+        }
+        let mut buf = [0u8; 4];
+        accepted.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"*1\r\n");
+
+        // Closing one end closes the circuit.
+        drop(accepted);
+        match bob_rx.recv().await {
+            Some(Command::SendRelay { message }) => {
+                assert_eq!(
+                    message,
+                    RelayMessage::Close {
+                        circuit: open.circuit()
+                    }
+                );
+                alice.handle(message);
+            }
+            _ => panic!("expected a relay message"),
+        }
+        assert_eq!(dialed.stream.read(&mut buf).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,382 @@
+//! Replay a captured session against a peer.
+//!
+//! A session is a sequence of steps, recorded from the wire: frames sent by
+//! the remote node, and the messages we expect our node to answer with.
+//! The harness starts a single peer in listening mode, plays the role of the
+//! remote node, and checks the peer's responses. This turns protocol bugs
+//! reported from the field into reproducible regression tests. The remote is
+//! connected over the in-memory transport, so a replay binds no socket.
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration};
+use tokio_util::codec::Framed;
+
+use super::command::Command;
+use super::event::Event;
+use super::identity::{Identity, Replays};
+use super::memory::MemoryTransport;
+use super::peer::Peer;
+use super::transport::{ByteStream, Transport};
+use crate::codec;
+use crate::message::{self, Message};
+use crate::FrameCodec;
+
+/// Default delay (milliseconds) to wait for an expected message.
+const DEFAULT_TIMEOUT: u64 = 2000;
+
+/// A captured session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    /// Label given to the peer under test.
+    #[serde(default = "default_label")]
+    pub label: String,
+    /// Delay (milliseconds) to wait for each expected message.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Sequence of steps to replay.
+    pub steps: Vec<Step>,
+}
+
+/// A single step in a captured session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    /// Raw bytes sent by the remote node, as they appeared on the wire.
+    Send(String),
+    /// Tag of the message (eg 'CONN_RESP') we expect the peer to send back.
+    Expect(String),
+}
+
+fn default_label() -> String {
+    String::from("replay")
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+impl Session {
+    /// Read a session from a JSON file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Session, Error> {
+        let content = std::fs::read_to_string(path).map_err(|err| Error::IO {
+            source: err,
+            detail: "Could not read session file".to_owned(),
+        })?;
+        Session::from_json(&content)
+    }
+
+    /// Read a session from a JSON string.
+    pub fn from_json(content: &str) -> Result<Session, Error> {
+        serde_json::from_str(content).map_err(|err| Error::InvalidSession { source: err })
+    }
+
+    /// Replay the session against a new peer, and return the messages
+    /// the peer sent back.
+    pub async fn replay(&self) -> Result<Vec<Message>, Error> {
+        self.replay_with(None).await
+    }
+
+    /// Replay the session against a new peer, which checks the connection
+    /// requests for replays with `replays` if given, and return the messages
+    /// the peer sent back.
+    pub async fn replay_with(&self, replays: Option<Arc<Replays>>) -> Result<Vec<Message>, Error> {
+        let transport = MemoryTransport::new();
+        let mut listener = transport
+            .listen(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), None)
+            .await
+            .map_err(|err| Error::IO {
+                source: err,
+                detail: "Could not bind replay listener".to_owned(),
+            })?;
+        let addr = listener.local_addr().map_err(|err| Error::IO {
+            source: err,
+            detail: "Could not get replay listener address".to_owned(),
+        })?;
+        let remote = transport.dial(addr, None).await.map_err(|err| Error::IO {
+            source: err,
+            detail: "Could not connect to replay listener".to_owned(),
+        })?;
+        let conn = listener.accept().await.map_err(|err| Error::IO {
+            source: err,
+            detail: "Could not accept replay connection".to_owned(),
+        })?;
+
+        // The peer under test, and a minimal controller answering its events.
+        let (tx_evt, rx_evt) = mpsc::channel(32);
+        let (tx_com, rx_com) = mpsc::channel(32);
+        let mut peer = Peer::new(
+            Identity::new(),
+            self.label.clone(),
+            addr,
+            tx_evt,
+            tx_com.clone(),
+            rx_com,
+            i32::MAX,
+            i32::MAX,
+            None,
+        );
+        peer.replays = replays;
+        let peer_handle = tokio::spawn(async move { peer.run().await });
+        let controller_handle = tokio::spawn(controller(rx_evt, tx_com.clone()));
+        tx_com
+            .send(Command::Listen { conn: Box::new(conn) })
+            .await
+            .map_err(|_| Error::PeerGone)?;
+
+        let result = self.play(remote.stream).await;
+
+        peer_handle.abort();
+        controller_handle.abort();
+        result
+    }
+
+    async fn play(&self, remote: Box<dyn ByteStream>) -> Result<Vec<Message>, Error> {
+        let mut frames = Framed::new(remote, FrameCodec::default());
+        let mut received = Vec::new();
+        for (index, step) in self.steps.iter().enumerate() {
+            match step {
+                Step::Send(bytes) => {
+                    frames.flush().await.map_err(|err| Error::Codec { source: err })?;
+                    frames
+                        .get_mut()
+                        .write_all(bytes.as_bytes())
+                        .await
+                        .map_err(|err| Error::IO {
+                            source: err,
+                            detail: format!("Could not send step {index}"),
+                        })?;
+                }
+                Step::Expect(tag) => {
+                    let frame = time::timeout(Duration::from_millis(self.timeout), frames.next())
+                        .await
+                        .map_err(|_| Error::Timeout {
+                            step: index,
+                            expected: tag.clone(),
+                        })?
+                        .ok_or(Error::PeerGone)?
+                        .map_err(|err| Error::Codec { source: err })?;
+                    let msg =
+                        Message::from_frame(frame).map_err(|err| Error::Message { source: err })?;
+                    if !msg.tag().eq_ignore_ascii_case(tag) {
+                        return Err(Error::Mismatch {
+                            step: index,
+                            expected: tag.clone(),
+                            actual: msg.tag().to_owned(),
+                        });
+                    }
+                    received.push(msg);
+                }
+            }
+        }
+        Ok(received)
+    }
+}
+
+/// Stand-in for the network controller: it answers the peer's requests
+/// with empty data, and ignores all other events.
+async fn controller(mut rx_evt: mpsc::Receiver<Event>, tx_com: mpsc::Sender<Command>) {
+    while let Some(event) = rx_evt.recv().await {
+        if let Event::ContactRequested { id: _ } = event {
+            let _ = tx_com
+                .send(Command::SendContactResponse {
+                    addrs: Vec::new(),
+                    tags: Vec::new(),
+                    contacts: Vec::new(),
+                })
+                .await;
+        }
+    }
+}
+
+/// Error type for the replay harness
+#[derive(Debug)]
+pub enum Error {
+    /// IO Error
+    IO {
+        /// Source
+        source: std::io::Error,
+        /// Error detail
+        detail: String,
+    },
+    /// Content of the session file is invalid
+    InvalidSession {
+        /// source error
+        source: serde_json::Error,
+    },
+    /// Codec Error
+    Codec {
+        /// Just the source
+        source: codec::Error,
+    },
+    /// The peer sent something that is not a valid message
+    Message {
+        /// Just the source
+        source: message::Error,
+    },
+    /// The peer did not answer in time
+    Timeout {
+        /// index of the step
+        step: usize,
+        /// expected message tag
+        expected: String,
+    },
+    /// The peer answered with an unexpected message
+    Mismatch {
+        /// index of the step
+        step: usize,
+        /// expected message tag
+        expected: String,
+        /// actual message tag
+        actual: String,
+    },
+    /// The peer closed the connection, or stopped processing commands
+    PeerGone,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IO { source, detail } => write!(f, "IO Error => {} [{}]", detail, source),
+            Error::InvalidSession { source } => write!(f, "Invalid session file: {}", source),
+            Error::Codec { source } => write!(f, "Codec Error: {}", source),
+            Error::Message { source } => write!(f, "Invalid message from peer: {}", source),
+            Error::Timeout { step, expected } => {
+                write!(f, "Step {}: timed out waiting for {}", step, expected)
+            }
+            Error::Mismatch {
+                step,
+                expected,
+                actual,
+            } => write!(f, "Step {}: expected {}, got {}", step, expected, actual),
+            Error::PeerGone => write!(f, "Peer is gone"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::conn_rejection::RejectReason;
+    use crate::message::{ConnRequest, WireMessage};
+    use bytes::BytesMut;
+    use tokio_util::codec::Encoder;
+
+    #[tokio::test]
+    async fn should_replay_handshake_session() {
+        let session = Session::from_json(include_str!("../../tests/sessions/handshake.json"))
+            .expect("valid session");
+        let messages = session.replay().await.unwrap();
+        assert_eq!(messages.len(), 3);
+        let id = match &messages[0] {
+            Message::ConnResponse(response) => {
+                assert_eq!(response.label(), "replay");
+                assert_eq!(response.compression(), None);
+                response.id()
+            }
+            _ => panic!("Expected a ConnResponse"),
+        };
+        match &messages[1] {
+            Message::HeartbeatResponse(response) => {
+                assert_eq!(response.id(), id);
+                assert_eq!(response.label(), "replay");
+                assert_eq!(response.src(), 1670000000000000);
+                assert!(response.dst() > response.src());
+            }
+            _ => panic!("Expected a HeartbeatResponse"),
+        }
+        match &messages[2] {
+            Message::ContactResponse(response) => {
+                assert!(response.addrs().is_empty());
+                assert!(response.tags().is_empty());
+                assert!(response.contacts().is_empty());
+            }
+            _ => panic!("Expected a ContactResponse"),
+        }
+    }
+
+    // A fresh request is answered once: the same session, played again
+    // against a peer sharing its replay protection, is refused.
+    #[tokio::test]
+    async fn should_refuse_replayed_sessions() {
+        let identity = Identity::new();
+        let address = "[::1]:8090".parse().unwrap();
+        let mut request = ConnRequest::new(identity.id(), "alice".to_owned(), address, None)
+            .with_public_key(identity.public_key().to_vec())
+            .with_nonce();
+        request.signature = Some(identity.sign(&[], &request.transcript()));
+        let mut bytes = BytesMut::new();
+        FrameCodec::default().encode(request.into_frame().unwrap(), &mut bytes).unwrap();
+        let send = Step::Send(String::from_utf8(bytes.to_vec()).unwrap());
+        let replays = Arc::new(Replays::default());
+
+        let session = Session {
+            label: default_label(),
+            timeout: DEFAULT_TIMEOUT,
+            steps: vec![send.clone(), Step::Expect("CONN_RESP".to_owned())],
+        };
+        let messages = session.replay_with(Some(replays.clone())).await.unwrap();
+        match &messages[0] {
+            Message::ConnResponse(response) => assert_eq!(response.label(), "replay"),
+            _ => panic!("Expected a ConnResponse"),
+        }
+
+        let session = Session {
+            steps: vec![send, Step::Expect("CONN_REJECT".to_owned())],
+            ..session
+        };
+        let messages = session.replay_with(Some(replays)).await.unwrap();
+        match &messages[0] {
+            Message::ConnRejection(rejection) => {
+                assert_eq!(rejection.reason(), RejectReason::Unauthenticated)
+            }
+            _ => panic!("Expected a ConnRejection"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_reject_unsigned_requests() {
+        let session = Session::from_json(
+            r#"{ "steps": [
+                { "send": "*4\r\n+CONN_REQ\r\n+8c2a4ba5-6c7b-4a5c-9a51-2b1d7f1c0c7e\r\n+alice\r\n+[::1]:8090\r\n" },
+                { "expect": "CONN_REJECT" }
+            ] }"#,
+        )
+        .expect("valid session");
+        let messages = session.replay().await.unwrap();
+        match &messages[0] {
+            Message::ConnRejection(rejection) => {
+                assert_eq!(rejection.reason(), RejectReason::Unauthenticated)
+            }
+            _ => panic!("Expected a ConnRejection"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_report_mismatch() {
+        let session = Session::from_json(
+            r#"{ "steps": [
+                { "send": "*7\r\n+CONN_REQ\r\n+fe812c12-f3ab-4ce6-ac5d-b69ac352f906\r\n+alice\r\n+[::1]:8090\r\n+\r\n+ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c\r\n+31438d593766a5c6c1dc16130eb74040188d781a569ad706c006d8945ff2b36e85a40d72d68e3f4c7028bb43dfd36692e4f8655ee868303bc91a53441e5b550e\r\n" },
+                { "expect": "HBT_RESP" }
+            ] }"#,
+        )
+        .expect("valid session");
+        match session.replay().await {
+            Err(Error::Mismatch {
+                step,
+                expected: _,
+                actual,
+            }) => {
+                assert_eq!(step, 1);
+                assert_eq!(actual, "CONN_RESP");
+            }
+            res => panic!("Expected a mismatch, got {res:?}"),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Scoring of idle addresses and of peers.
+//!
+//! After the addresses wanted by the connection policies, the controller dials
+//! idle addresses in a random order weighted by their score, so that the
+//! connection attempts available go first to the addresses which answered
+//! quickly and reliably before, were seen recently by our peers, and which add
+//! diversity to the tags we are connected to.
+//!
+//! The nodes we are connected to are scored on their conduct as well: the
+//! frames which failed the session key check, and the heartbeats they missed.
+//! The controller bans the nodes whose score falls too low, and evicts the
+//! worst incoming peer to make room for a new connection.
+use serde::Serialize;
+use std::net::SocketAddr;
+
+use super::policy::{Counts, Tags};
+
+/// Round trip time (μs) for which the RTT factor of a score is one half.
+const RTT_REFERENCE: f64 = 50_000.0;
+
+/// Time (μs) since the node was last seen for which the freshness factor of a
+/// score is one half: one hour.
+const SEEN_REFERENCE: f64 = 3_600_000_000.0;
+
+/// Weight of a protocol violation in the conduct factor of a score.
+const VIOLATION_WEIGHT: f64 = 1.0;
+
+/// Weight of a heartbeat timeout in the conduct factor of a score.
+const TIMEOUT_WEIGHT: f64 = 0.25;
+
+/// History of the connections to an address.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct History {
+    /// Connection attempts which completed the handshake.
+    pub successes: u32,
+    /// Connection attempts which failed.
+    pub failures: u32,
+    /// Last round trip time measured on a connection to this address (μs).
+    pub rtt: Option<i64>,
+    /// Last time (micros) a peer reported hearing from the node at this
+    /// address.
+    pub last_seen: Option<i64>,
+    /// Frames from the node which failed the session key check.
+    pub violations: u32,
+    /// Connections with the node closed on a heartbeat timeout.
+    pub timeouts: u32,
+    /// Why the controller dropped the node, if it did.
+    pub dropped: Option<String>,
+}
+
+/// Score of a node we are, or were, connected to, as shown in the summary.
+#[derive(Debug, Clone, Serialize)]
+pub struct Standing {
+    /// Address of the node.
+    pub addr: SocketAddr,
+    /// Score of the node, between 0 (excluded) and 1.
+    pub score: f64,
+    /// History the score is computed from.
+    pub history: History,
+}
+
+impl History {
+    /// Record a successful connection attempt.
+    pub fn record_success(&mut self) {
+        self.successes = self.successes.saturating_add(1);
+    }
+
+    /// Record a failed connection attempt.
+    pub fn record_failure(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    /// Record a frame which failed the session key check.
+    pub fn record_violation(&mut self) {
+        self.violations = self.violations.saturating_add(1);
+    }
+
+    /// Record a connection closed on a heartbeat timeout.
+    pub fn record_timeout(&mut self) {
+        self.timeouts = self.timeouts.saturating_add(1);
+    }
+
+    /// Record the time (micros) a peer last heard from the node, keeping the
+    /// most recent one.
+    pub fn record_seen(&mut self, last_seen: i64) {
+        self.last_seen = Some(self.last_seen.map_or(last_seen, |seen| seen.max(last_seen)));
+    }
+
+    // Success rate, starting from one success and one failure, so that a
+    // single failure does not rule an address out.
+    fn success_rate(&self) -> f64 {
+        (f64::from(self.successes) + 1.0)
+            / (f64::from(self.successes) + f64::from(self.failures) + 2.0)
+    }
+
+    // Addresses without RTT are scored as if they had the reference RTT.
+    fn rtt_factor(&self) -> f64 {
+        let rtt = self.rtt.map_or(RTT_REFERENCE, |rtt| rtt.max(0) as f64);
+        RTT_REFERENCE / (RTT_REFERENCE + rtt)
+    }
+
+    // Addresses never reported are scored as if they were seen the reference
+    // time ago.
+    fn seen_factor(&self, now: i64) -> f64 {
+        let age = self
+            .last_seen
+            .map_or(SEEN_REFERENCE, |seen| (now - seen).max(0) as f64);
+        SEEN_REFERENCE / (SEEN_REFERENCE + age)
+    }
+
+    // Each violation halves the factor of a node without timeouts.
+    fn conduct_factor(&self) -> f64 {
+        let misconduct = f64::from(self.violations) * VIOLATION_WEIGHT
+            + f64::from(self.timeouts) * TIMEOUT_WEIGHT;
+        1.0 / (1.0 + misconduct)
+    }
+}
+
+/// Score of an address at `now` (micros), between 0 (excluded) and 1. The score
+/// is the product of the score of the node, a factor decreasing with the time
+/// since the node was last seen, and a factor decreasing with the number of
+/// connections sharing one of its tag values.
+pub fn score(history: Option<&History>, tags: &Tags, counts: &Counts, now: i64) -> f64 {
+    let default = History::default();
+    let history = history.unwrap_or(&default);
+    let shared: usize = tags.iter().map(|(tag, value)| counts.get(tag, value)).sum();
+    peer_score(Some(history)) * history.seen_factor(now) / (1.0 + shared as f64)
+}
+
+/// Score of a node, between 0 (excluded) and 1. The score is the product of
+/// the success rate of the connection attempts, a factor decreasing with the
+/// RTT, and a factor decreasing with the violations and timeouts.
+pub fn peer_score(history: Option<&History>) -> f64 {
+    let default = History::default();
+    let history = history.unwrap_or(&default);
+    history.success_rate() * history.rtt_factor() * history.conduct_factor()
+}
+
+/// Random key for a weighted selection: sorting addresses by decreasing key
+/// draws them with probabilities proportional to their weights.
+pub fn sort_key(weight: f64) -> f64 {
+    fastrand::f64().powf(1.0 / weight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_670_000_000_000_000;
+
+    #[test]
+    fn should_score_reliable_fast_and_diverse_addresses_higher() {
+        let counts = Counts::new(&[Tags::from([("region".to_owned(), "eu".to_owned())])]);
+        let tags = Tags::new();
+        let unknown = score(None, &tags, &counts, NOW);
+
+        let reliable = History {
+            successes: 4,
+            failures: 0,
+            rtt: None,
+            last_seen: None,
+            ..History::default()
+        };
+        let unreliable = History {
+            successes: 0,
+            failures: 4,
+            rtt: None,
+            last_seen: None,
+            ..History::default()
+        };
+        assert!(score(Some(&reliable), &tags, &counts, NOW) > unknown);
+        assert!(score(Some(&unreliable), &tags, &counts, NOW) < unknown);
+
+        let fast = History {
+            rtt: Some(1_000),
+            ..History::default()
+        };
+        let slow = History {
+            rtt: Some(500_000),
+            ..History::default()
+        };
+        assert!(score(Some(&fast), &tags, &counts, NOW) > unknown);
+        assert!(score(Some(&slow), &tags, &counts, NOW) < unknown);
+
+        let eu = Tags::from([("region".to_owned(), "eu".to_owned())]);
+        let us = Tags::from([("region".to_owned(), "us".to_owned())]);
+        assert!(score(None, &eu, &counts, NOW) < score(None, &us, &counts, NOW));
+        assert!(score(None, &eu, &counts, NOW) > 0.0);
+
+        let recent = History {
+            last_seen: Some(NOW - 60_000_000),
+            ..History::default()
+        };
+        let stale = History {
+            last_seen: Some(NOW - 86_400_000_000),
+            ..History::default()
+        };
+        assert!(score(Some(&recent), &tags, &counts, NOW) > unknown);
+        assert!(score(Some(&stale), &tags, &counts, NOW) < unknown);
+    }
+
+    #[test]
+    fn should_score_misbehaving_peers_lower() {
+        let mut history = History {
+            successes: 1,
+            rtt: Some(1_000),
+            ..History::default()
+        };
+        let clean = peer_score(Some(&history));
+        history.record_timeout();
+        let timed_out = peer_score(Some(&history));
+        assert!(timed_out < clean);
+        history.record_violation();
+        assert!(peer_score(Some(&history)) < timed_out);
+        // A violation weighs more than a timeout.
+        let violated = History {
+            successes: 1,
+            rtt: Some(1_000),
+            violations: 1,
+            ..History::default()
+        };
+        assert!(peer_score(Some(&violated)) < timed_out);
+        assert!(peer_score(Some(&violated)) > 0.0);
+    }
+}
@@ -0,0 +1,236 @@
+//! Session keys.
+//!
+//! Without an encrypting transport (noise, TLS or QUIC), an on-path attacker
+//! could forge or alter the frames of a connection once the handshake is
+//! done. So each side of the handshake adds an ephemeral X25519 public key to
+//! its signed message, and both derive two keys from the shared secret, one
+//! for each direction of the connection.
+//!
+//! Every frame sent after the handshake is wrapped in an array, along with
+//! the HMAC of its sequence number and its encoding. Frames which are forged,
+//! altered, replayed or reordered fail the check, and the receiving node
+//! counts them as protocol violations. A frame without an HMAC, once the keys
+//! are derived, was injected in the connection, which is closed.
+use bytes::{Bytes, BytesMut};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::hmac;
+use ring::rand::SystemRandom;
+use std::fmt;
+
+use crate::frame::Limits;
+use crate::Frame;
+
+/// Ephemeral key pair, offered in the handshake.
+#[derive(Debug)]
+pub struct KeyShare {
+    private_key: EphemeralPrivateKey,
+    public_key: Vec<u8>,
+}
+
+/// Authenticates the frames sent to the remote.
+#[derive(Debug)]
+pub struct Sealer {
+    key: hmac::Key,
+    sequence: u64,
+}
+
+/// Checks the frames received from the remote.
+#[derive(Debug, Clone)]
+pub struct Opener {
+    key: hmac::Key,
+    sequence: u64,
+}
+
+/// Error type for the session keys
+#[derive(Debug)]
+pub struct Error {
+    /// Error detail
+    pub detail: String,
+}
+
+impl KeyShare {
+    /// Create a new key pair.
+    pub fn new() -> KeyShare {
+        let private_key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .expect("system random number generator");
+        let public_key = private_key
+            .compute_public_key()
+            .expect("X25519 public key")
+            .as_ref()
+            .to_vec();
+        KeyShare {
+            private_key,
+            public_key,
+        }
+    }
+
+    /// Public key, sent to the remote.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Derive the keys of the session from the public key of the remote.
+    /// The initiator is the side which sent the connection request. Returns
+    /// None if the remote key is not a valid X25519 key.
+    pub fn agree(self, remote_key: &[u8], initiator: bool) -> Option<(Sealer, Opener)> {
+        // Both sides salt the secret with the two public keys, in the same order.
+        let salt = match initiator {
+            true => [self.public_key.as_slice(), remote_key].concat(),
+            false => [remote_key, self.public_key.as_slice()].concat(),
+        };
+        let (initiator_key, responder_key) = agreement::agree_ephemeral(
+            self.private_key,
+            &UnparsedPublicKey::new(&X25519, remote_key),
+            (),
+            |secret| {
+                let prk = Salt::new(HKDF_SHA256, &salt).extract(secret);
+                let key = |info: &[u8]| -> Result<hmac::Key, ()> {
+                    let info = [info];
+                    let okm = prk.expand(&info, hmac::HMAC_SHA256).map_err(|_| ())?;
+                    Ok(hmac::Key::from(okm))
+                };
+                Ok((key(b"area-net initiator")?, key(b"area-net responder")?))
+            },
+        )
+        .ok()?;
+        let (send, receive) = match initiator {
+            true => (initiator_key, responder_key),
+            false => (responder_key, initiator_key),
+        };
+        Some((
+            Sealer {
+                key: send,
+                sequence: 0,
+            },
+            Opener {
+                key: receive,
+                sequence: 0,
+            },
+        ))
+    }
+}
+
+impl Default for KeyShare {
+    fn default() -> Self {
+        KeyShare::new()
+    }
+}
+
+impl Sealer {
+    /// Wrap the frame along with its HMAC.
+    pub fn seal(&mut self, frame: Frame) -> Frame {
+        let tag = hmac::sign(&self.key, &input(self.sequence, &frame));
+        self.sequence += 1;
+        Frame::Array(vec![
+            frame,
+            Frame::Bulk(Bytes::copy_from_slice(tag.as_ref())),
+        ])
+    }
+}
+
+impl Opener {
+    /// Unwrap the frame, if its HMAC is the one expected next. Each frame
+    /// takes a sequence number, even one which fails the check, so that a
+    /// single altered frame doesn't fail those after it.
+    pub fn open(&mut self, frame: Frame) -> Result<Frame, Error> {
+        let (frame, tag) = match frame {
+            Frame::Array(mut frames) if is_sealed_array(&frames) => {
+                let tag = frames.pop().expect("sealed frame");
+                (frames.pop().expect("sealed frame"), tag)
+            }
+            _ => {
+                return Err(Error {
+                    detail: "frame without an HMAC".to_owned(),
+                })
+            }
+        };
+        let sequence = self.sequence;
+        self.sequence += 1;
+        let Frame::Bulk(tag) = tag else {
+            unreachable!("sealed frames end with bulk bytes")
+        };
+        hmac::verify(&self.key, &input(sequence, &frame), &tag).map_err(|_| Error {
+            detail: format!("invalid HMAC for frame {sequence}"),
+        })?;
+        Ok(frame)
+    }
+}
+
+/// Returns true if the frame was sealed. A message starts with its tag or
+/// id, never with an array.
+pub fn is_sealed(frame: &Frame) -> bool {
+    matches!(frame, Frame::Array(frames) if is_sealed_array(frames))
+}
+
+fn is_sealed_array(frames: &[Frame]) -> bool {
+    matches!(frames, [Frame::Array(_), Frame::Bulk(_)])
+}
+
+// The sequence number, then the encoding of the frame. The frame was already
+// decoded, or is about to be encoded, within limits: none apply here.
+fn input(sequence: u64, frame: &Frame) -> Vec<u8> {
+    let limits = Limits {
+        max_depth: usize::MAX,
+        max_array_len: usize::MAX,
+        ..Limits::default()
+    };
+    let mut buf = BytesMut::from(&sequence.to_be_bytes()[..]);
+    frame
+        .write_with_limits(&mut buf, &limits)
+        .expect("frame within limits");
+    buf.to_vec()
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Session error: {}", self.detail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frames don't implement PartialEq.
+    fn debug(frame: &Frame) -> String {
+        format!("{frame:?}")
+    }
+
+    #[test]
+    fn should_detect_altered_frames() {
+        let (alice, bob) = (KeyShare::new(), KeyShare::new());
+        let (alice_key, bob_key) = (alice.public_key().to_vec(), bob.public_key().to_vec());
+        let (mut alice_sealer, mut alice_opener) = alice.agree(&bob_key, true).unwrap();
+        let (mut bob_sealer, mut bob_opener) = bob.agree(&alice_key, false).unwrap();
+
+        let frame = crate::frame!["PAYLOAD", "topic", Bytes::from_static(b"data")];
+        let sealed = alice_sealer.seal(frame.clone());
+        assert!(is_sealed(&sealed));
+        assert_eq!(debug(&bob_opener.open(sealed).unwrap()), debug(&frame));
+        // Each direction has its own key.
+        let sealed = bob_sealer.seal(frame.clone());
+        assert_eq!(debug(&alice_opener.open(sealed).unwrap()), debug(&frame));
+
+        // An altered frame fails the check, but not the frames after it.
+        let Frame::Array(mut frames) = alice_sealer.seal(frame.clone()) else {
+            panic!("Expected a sealed frame");
+        };
+        frames[0] = crate::frame!["PAYLOAD", "topic", Bytes::from_static(b"evil")];
+        assert!(bob_opener.open(Frame::Array(frames)).is_err());
+        let sealed = alice_sealer.seal(frame.clone());
+        assert_eq!(
+            debug(&bob_opener.open(sealed.clone()).unwrap()),
+            debug(&frame)
+        );
+        // The same frame, played again, has the wrong sequence number.
+        assert!(bob_opener.open(sealed).is_err());
+        // Frames must be sealed.
+        assert!(bob_opener.open(frame).is_err());
+    }
+
+    #[test]
+    fn should_refuse_invalid_remote_keys() {
+        assert!(KeyShare::new().agree(&[0u8; 8], true).is_none());
+    }
+}
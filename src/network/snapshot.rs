@@ -0,0 +1,212 @@
+//! Full state snapshot of the network controller.
+//!
+//! When a node looks stuck, operators can ask it to explain itself: on SIGUSR1,
+//! or on a request to the admin server, the controller writes its connections,
+//! connection attempts, idle addresses and metrics to a JSON file.
+use chrono::Utc;
+use serde::Serialize;
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::sync::Mutex;
+use tokio::task;
+
+use super::controller::{IdleState, InConnInfo, IncomingState, OutConnInfo, OutgoingState};
+use super::metrics::{Metrics, MetricsSnapshot};
+use super::score::History;
+
+/// State of the network controller at a point in time.
+#[derive(Debug, Clone, Serialize)]
+pub struct State {
+    /// Time the snapshot was taken (μs since epoch)
+    pub timestamp: i64,
+    /// controller
+    pub controller: InConnInfo,
+    /// incoming connections
+    pub incoming: Vec<InConnInfo>,
+    /// outgoing connections
+    pub outgoing: Vec<OutConnInfo>,
+    /// outgoing connection attempts in progress
+    pub attempting: Vec<AddrState>,
+    /// addresses waiting for a connection attempt
+    pub idle: Vec<AddrState>,
+    /// metrics
+    pub metrics: MetricsSnapshot,
+}
+
+/// An address we are trying to connect to.
+#[derive(Debug, Clone, Serialize)]
+pub struct AddrState {
+    /// Network address
+    pub addr: SocketAddr,
+    /// Number of connection attempts so far.
+    pub attempt: i32,
+    /// History of the connections to this address, for idle addresses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history: Option<History>,
+}
+
+/// Builds a snapshot of the controller's state.
+/// The locks are taken one at a time, so the snapshot is not a consistent
+/// cut across the whole controller, but each section is.
+pub async fn take(
+    controller: InConnInfo,
+    incoming: &Mutex<IncomingState>,
+    outgoing: &Mutex<OutgoingState>,
+    idle: &Mutex<IdleState>,
+    metrics: &Metrics,
+) -> State {
+    let incoming = incoming
+        .lock()
+        .await
+        .connected
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    let (outgoing, attempting) = {
+        let outgoing = outgoing.lock().await;
+        let mut attempting = Vec::new();
+        for info in outgoing.attempting.values() {
+            attempting.push(AddrState {
+                addr: info.addr,
+                attempt: *info.attempt.lock().await,
+                history: None,
+            });
+        }
+        (
+            outgoing.connected.values().cloned().collect::<Vec<_>>(),
+            attempting,
+        )
+    };
+    let mut idle_addrs = Vec::new();
+    let idle = idle.lock().await;
+    for info in idle.addrs.iter() {
+        idle_addrs.push(AddrState {
+            addr: info.addr,
+            attempt: *info.attempt.lock().await,
+            history: idle.history.get(&info.addr).cloned(),
+        });
+    }
+    drop(idle);
+    State {
+        timestamp: Utc::now().timestamp_micros(),
+        controller,
+        incoming,
+        outgoing,
+        attempting,
+        idle: idle_addrs,
+        metrics: metrics.snapshot(),
+    }
+}
+
+impl State {
+    /// Write the snapshot to the given path.
+    /// The snapshot is first written to a temporary file in the same directory,
+    /// and then renamed, so readers never see a partial snapshot.
+    pub async fn save(&self, path: &Path) -> Result<(), Error> {
+        let content =
+            serde_json::to_vec_pretty(self).map_err(|err| Error::Serialize { source: err })?;
+        let path = path.to_owned();
+        task::spawn_blocking(move || write_atomic(&path, &content))
+            .await
+            .map_err(|err| Error::IO {
+                source: err.into(),
+                detail: "Snapshot thread failed".to_owned(),
+            })?
+    }
+}
+
+pub(crate) fn write_atomic(path: &Path, content: &[u8]) -> Result<(), Error> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir).map_err(|err| Error::IO {
+        source: err,
+        detail: format!("Could not create temporary file in {}", dir.display()),
+    })?;
+    file.write_all(content).map_err(|err| Error::IO {
+        source: err,
+        detail: "Could not write snapshot".to_owned(),
+    })?;
+    file.as_file().sync_all().map_err(|err| Error::IO {
+        source: err,
+        detail: "Could not sync snapshot".to_owned(),
+    })?;
+    file.persist(path).map_err(|err| Error::IO {
+        source: err.error,
+        detail: format!("Could not rename snapshot to {}", path.display()),
+    })?;
+    Ok(())
+}
+
+/// Error type for snapshots
+#[derive(Debug)]
+pub enum Error {
+    /// IO Error
+    IO {
+        /// Source
+        source: std::io::Error,
+        /// Error detail
+        detail: String,
+    },
+    /// The snapshot could not be serialized
+    Serialize {
+        /// Source
+        source: serde_json::Error,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IO { source, detail } => write!(f, "IO Error => {} [{}]", detail, source),
+            Error::Serialize { source } => write!(f, "Could not serialize snapshot: {}", source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    use crate::network::controller::AddrInfo;
+
+    #[tokio::test]
+    async fn should_save_snapshot() {
+        let addr = SocketAddr::from_str("[::1]:8090").unwrap();
+        let idle = Mutex::new(IdleState::default());
+        idle.lock().await.addrs.insert(AddrInfo {
+            addr,
+            attempt: Arc::new(Mutex::new(2)),
+            tags: Default::default(),
+        });
+        let state = take(
+            InConnInfo {
+                addr,
+                id: Uuid::new_v4(),
+                label: "alice".to_owned(),
+                health: None,
+            },
+            &Mutex::new(IncomingState::default()),
+            &Mutex::new(OutgoingState::default()),
+            &idle,
+            &Metrics::default(),
+        )
+        .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot.json");
+        state.save(&path).await.unwrap();
+
+        let content: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(content["controller"]["label"], "alice");
+        assert_eq!(content["idle"][0]["attempt"], 2);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,162 @@
+//! Helpers to create TCP sockets bound to a given network interface, and tuned
+//! with the socket options of the configuration.
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// Options applied to the TCP sockets of both dialed and accepted connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Options {
+    /// Disable Nagle's algorithm, so that small messages (eg heartbeats) are not delayed.
+    #[serde(default)]
+    pub nodelay: bool,
+    /// Idle time (seconds) before keepalive probes are sent. No probes are sent if not set.
+    pub keepalive: Option<u64>,
+    /// Size (bytes) of the send buffer. The system default is used if not set.
+    pub send_buffer_size: Option<u32>,
+    /// Size (bytes) of the receive buffer. The system default is used if not set.
+    pub recv_buffer_size: Option<u32>,
+}
+
+/// Returns the index of the network interface with the given name.
+/// This is used to resolve IPv6 scope ids given by name (eg 'fe80::1%eth0').
+#[cfg(unix)]
+pub fn interface_index(name: &str) -> io::Result<u32> {
+    let name = std::ffi::CString::new(name)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    // Safe: name is a valid nul terminated string.
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+/// Returns the index of the network interface with the given name.
+#[cfg(not(unix))]
+pub fn interface_index(_name: &str) -> io::Result<u32> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Interface names are not supported on this platform, use a numeric scope id",
+    ))
+}
+
+/// Restrict the socket to the given network interface (SO_BINDTODEVICE).
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+pub fn bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+/// Restrict the socket to the given network interface (SO_BINDTODEVICE).
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+pub fn bind_device(_socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Cannot bind to interface {interface} on this platform, use an address instead"),
+    ))
+}
+
+/// Create a socket of the same family as the address, optionally bound to
+/// an interface. Buffer sizes are set before connecting (or listening), as the
+/// receive buffer determines the window advertised in the handshake.
+fn socket(addr: &SocketAddr, interface: Option<&str>, options: &Options) -> io::Result<TcpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    Ok(socket)
+}
+
+/// Apply the options which are set on connected sockets. Accepted sockets
+/// inherit their buffer sizes from the listener.
+pub fn tune(stream: &TcpStream, options: &Options) -> io::Result<()> {
+    if options.nodelay {
+        stream.set_nodelay(true)?;
+    }
+    if let Some(keepalive) = options.keepalive {
+        let keepalive = socket2::TcpKeepalive::new().with_time(Duration::from_secs(keepalive));
+        socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+/// Listen on the given address, optionally restricted to an interface. As
+/// with `TcpListener::bind`, the address is reused on unix, so that a restarted
+/// node can bind while the sockets of the previous run are in TIME_WAIT.
+pub fn listen(
+    addr: &SocketAddr,
+    interface: Option<&str>,
+    options: &Options,
+) -> io::Result<TcpListener> {
+    let socket = socket(addr, interface, options)?;
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(*addr)?;
+    socket.listen(1024)
+}
+
+/// Connect to the given address, optionally from an interface and a local address.
+pub async fn connect(
+    addr: &SocketAddr,
+    interface: Option<&str>,
+    bind_addr: Option<&SocketAddr>,
+    options: &Options,
+) -> io::Result<TcpStream> {
+    let socket = socket(addr, interface, options)?;
+    if let Some(bind_addr) = bind_addr {
+        if bind_addr.is_ipv4() != addr.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Cannot connect to {addr} from {bind_addr}, of another address family"),
+            ));
+        }
+        socket.bind(*bind_addr)?;
+    }
+    let stream = socket.connect(*addr).await?;
+    tune(&stream, options)?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn should_tune_dialed_and_accepted_sockets() {
+        let options = Options {
+            nodelay: true,
+            keepalive: Some(60),
+            send_buffer_size: Some(64 * 1024),
+            recv_buffer_size: None,
+        };
+        let listener = listen(&"127.0.0.1:0".parse().unwrap(), None, &options).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (dialed, accepted) =
+            tokio::join!(connect(&addr, None, None, &options), listener.accept());
+        let (dialed, (accepted, _)) = (dialed.unwrap(), accepted.unwrap());
+        tune(&accepted, &options).unwrap();
+        for stream in [&dialed, &accepted] {
+            assert!(stream.nodelay().unwrap());
+            assert!(socket2::SockRef::from(stream).keepalive().unwrap());
+        }
+        assert!(!TcpStream::connect(addr).await.unwrap().nodelay().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn should_reuse_the_listening_address() {
+        let listener = listen(&"127.0.0.1:0".parse().unwrap(), None, &Options::default()).unwrap();
+        let socket = socket2::SockRef::from(&listener);
+        assert!(socket.reuse_address().unwrap());
+    }
+}
@@ -0,0 +1,153 @@
+//! SOCKS5 transport.
+//!
+//! Nodes which cannot reach their peers directly (behind a corporate proxy, or
+//! dialing through Tor) open their outgoing connections through a SOCKS5
+//! proxy. The proxy is dialed with the TCP transport, and asked to connect to
+//! the remote node, optionally authenticating with a username and password.
+//! Incoming connections do not go through the proxy.
+use futures::future::BoxFuture;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use tokio_socks::tcp::Socks5Stream;
+
+use super::transport::{Connection, Listener, Tcp, Transport};
+
+/// The SOCKS5 transport.
+#[derive(Clone)]
+pub struct Socks5 {
+    proxy: SocketAddr,
+    auth: Option<(String, String)>,
+    inner: Tcp,
+}
+
+impl Socks5 {
+    /// Dial through the SOCKS5 proxy at the given address, without authentication.
+    pub fn new(proxy: SocketAddr) -> Socks5 {
+        Socks5 {
+            proxy,
+            auth: None,
+            inner: Tcp::default(),
+        }
+    }
+
+    /// Dial the proxy with the given TCP transport, eg from a bind address.
+    pub fn with_inner(mut self, inner: Tcp) -> Socks5 {
+        self.inner = inner;
+        self
+    }
+
+    /// Authenticate with the proxy.
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Socks5 {
+        self.auth = Some((username.to_owned(), password.to_owned()));
+        self
+    }
+}
+
+impl fmt::Debug for Socks5 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Socks5")
+            .field("proxy", &self.proxy)
+            .field("auth", &self.auth.is_some())
+            .finish()
+    }
+}
+
+impl Transport for Socks5 {
+    // The interface restricts the connection to the proxy.
+    fn dial<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Connection>> {
+        Box::pin(async move {
+            let conn = self.inner.dial(self.proxy, interface).await?;
+            let stream = match &self.auth {
+                Some((username, password)) => {
+                    Socks5Stream::connect_with_password_and_socket(
+                        conn.stream,
+                        addr,
+                        username,
+                        password,
+                    )
+                    .await
+                }
+                None => Socks5Stream::connect_with_socket(conn.stream, addr).await,
+            }
+            .map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("SOCKS5 proxy {}: {err}", self.proxy),
+                )
+            })?;
+            Ok(Connection {
+                stream: Box::new(stream),
+                local_addr: conn.local_addr,
+                peer_addr: addr,
+                binding: conn.binding,
+            })
+        })
+    }
+
+    fn listen<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        self.inner.listen(addr, interface)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Accepts a single connection, without authentication, and relays it to
+    // the requested IPv4 address.
+    async fn proxy(mut listener: Box<dyn Listener>) -> SocketAddr {
+        let mut conn = listener.accept().await.unwrap();
+        let mut greeting = [0u8; 3];
+        conn.stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [5, 1, 0]);
+        conn.stream.write_all(&[5, 0]).await.unwrap();
+        let mut request = [0u8; 10];
+        conn.stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[..4], [5, 1, 0, 1]);
+        let ip: [u8; 4] = request[4..8].try_into().unwrap();
+        let target = SocketAddr::from((ip, u16::from_be_bytes([request[8], request[9]])));
+        let mut remote = Tcp::default().dial(target, None).await.unwrap();
+        conn.stream
+            .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            let _ = tokio::io::copy_bidirectional(&mut conn.stream, &mut remote.stream).await;
+        });
+        target
+    }
+
+    #[tokio::test]
+    async fn should_dial_through_the_proxy() {
+        let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = Tcp::default().listen(any, None).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy_listener = Tcp::default().listen(any, None).await.unwrap();
+        let transport = Socks5::new(proxy_listener.local_addr().unwrap());
+        let relay = tokio::spawn(proxy(proxy_listener));
+
+        let (dialed, accepted) = tokio::join!(transport.dial(addr, None), listener.accept());
+        let (mut dialed, mut accepted) = (dialed.unwrap(), accepted.unwrap());
+        assert_eq!(relay.await.unwrap(), addr);
+        assert_eq!(dialed.peer_addr, addr);
+
+        dialed.stream.write_all(b"*1\r\n").await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"*1\r\n");
+
+        // Without a proxy, the dial fails.
+        let transport = Socks5::new(any);
+        assert!(transport.dial(addr, None).await.is_err());
+    }
+}
@@ -0,0 +1,233 @@
+//! Load generation against a node.
+//!
+//! The stress test opens many connections to a target node, completes the
+//! handshake on each, and then sends heartbeat requests (and optionally
+//! contact requests) at a fixed rate, measuring the time it takes for the
+//! target to answer. This is a built-in way to capacity-test a node.
+use chrono::Utc;
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration, Instant};
+use tokio_util::codec::Framed;
+
+use super::identity::Identity;
+use crate::codec::{Format, FrameCodec};
+use crate::frame::Limits;
+use crate::message::{ConnRequest, ContactRequest, HeartbeatRequest, Message};
+
+/// Delay to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parameters of a stress test.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Address of the node under test.
+    pub target: SocketAddr,
+    /// Number of connections.
+    pub conns: usize,
+    /// Number of requests per second, on each connection.
+    pub rate: f64,
+    /// Duration of the traffic, once connections are established.
+    pub duration: Duration,
+    /// Send a contact request instead of a heartbeat request every so many
+    /// requests. 0 means only heartbeat requests are sent.
+    pub contact_every: u64,
+    /// Framing used on the wire.
+    pub format: Format,
+    /// Network of the node under test, empty for the default network.
+    pub network: String,
+}
+
+/// Latency percentiles (μs)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Latency {
+    /// median
+    pub p50: u64,
+    /// 90th percentile
+    pub p90: u64,
+    /// 99th percentile
+    pub p99: u64,
+    /// maximum
+    pub max: u64,
+}
+
+/// Outcome of a stress test.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    /// Connections which completed the handshake.
+    pub connected: usize,
+    /// Connections which could not connect or complete the handshake.
+    pub failed: usize,
+    /// Requests sent.
+    pub sent: u64,
+    /// Responses received.
+    pub received: u64,
+    /// Connections lost during the test.
+    pub errors: u64,
+    /// Time between a request and its response.
+    pub latency: Latency,
+}
+
+#[derive(Debug, Default)]
+struct ConnStats {
+    sent: u64,
+    received: u64,
+    error: bool,
+    latencies: Vec<u64>,
+}
+
+/// Run the stress test, and report once every connection is done.
+pub async fn run(options: &Options) -> Report {
+    let handles = (0..options.conns)
+        .map(|i| {
+            let options = options.clone();
+            tokio::spawn(async move { stress(&options, i).await })
+        })
+        .collect::<Vec<_>>();
+    let mut report = Report::default();
+    let mut latencies = Vec::new();
+    for handle in handles {
+        match handle.await {
+            Ok(Some(stats)) => {
+                report.connected += 1;
+                report.sent += stats.sent;
+                report.received += stats.received;
+                report.errors += u64::from(stats.error);
+                latencies.extend(stats.latencies);
+            }
+            Ok(None) | Err(_) => report.failed += 1,
+        }
+    }
+    report.latency = percentiles(latencies);
+    report
+}
+
+// A single connection. Returns None if the handshake did not complete.
+async fn stress(options: &Options, index: usize) -> Option<ConnStats> {
+    let label = format!("stress-{index}");
+    let stream = match TcpStream::connect(options.target).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            log::warn!("Stress | {label} | Could not connect | {err}");
+            return None;
+        }
+    };
+    let local_addr = stream.local_addr().ok()?;
+    let mut frames = Framed::new(
+        stream,
+        FrameCodec::connecting(Limits::default(), options.format),
+    );
+
+    let identity = Identity::new();
+    let id = identity.id();
+    let mut request = ConnRequest::new(id, label.clone(), local_addr, None)
+        .with_public_key(identity.public_key().to_vec())
+        .with_nonce()
+        .with_network(options.network.clone());
+    request.signature = Some(identity.sign(&[], &request.transcript()));
+    let request = Message::ConnRequest(request);
+    frames.send(request.into_frame().ok()?).await.ok()?;
+    match time::timeout(HANDSHAKE_TIMEOUT, frames.next()).await {
+        Ok(Some(Ok(frame))) => match Message::from_frame(frame) {
+            Ok(Message::ConnResponse(_)) => {}
+            _ => {
+                log::warn!("Stress | {label} | Unexpected handshake response");
+                return None;
+            }
+        },
+        _ => {
+            log::warn!("Stress | {label} | Handshake did not complete");
+            return None;
+        }
+    }
+
+    let mut stats = ConnStats::default();
+    // Contact responses don't carry a timestamp, but they come back in order.
+    let mut contacts = VecDeque::new();
+    let mut interval = time::interval(Duration::from_secs_f64(1.0 / options.rate));
+    let deadline = time::sleep(options.duration);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            _ = interval.tick() => {
+                let contact = options.contact_every > 0
+                    && (stats.sent + 1) % options.contact_every == 0;
+                let msg = if contact {
+                    contacts.push_back(Instant::now());
+                    Message::ContactRequest(ContactRequest)
+                } else {
+                    Message::HeartbeatRequest(HeartbeatRequest::now(id, label.clone()))
+                };
+                let frame = msg.into_frame().ok()?;
+                if let Err(err) = frames.send(frame).await {
+                    log::warn!("Stress | {label} | Could not send request | {err}");
+                    stats.error = true;
+                    break;
+                }
+                stats.sent += 1;
+            }
+            frame = frames.next() => {
+                let frame = match frame {
+                    Some(Ok(frame)) => frame,
+                    _ => {
+                        log::warn!("Stress | {label} | Connection lost");
+                        stats.error = true;
+                        break;
+                    }
+                };
+                let latency = match Message::from_frame(frame) {
+                    Ok(Message::HeartbeatResponse(response)) => {
+                        (Utc::now().timestamp_micros() - response.src()).max(0) as u64
+                    }
+                    Ok(Message::ContactResponse(_)) => match contacts.pop_front() {
+                        Some(sent) => sent.elapsed().as_micros() as u64,
+                        None => continue,
+                    },
+                    _ => continue,
+                };
+                stats.received += 1;
+                stats.latencies.push(latency);
+            }
+        }
+    }
+    Some(stats)
+}
+
+fn percentiles(mut latencies: Vec<u64>) -> Latency {
+    if latencies.is_empty() {
+        return Latency::default();
+    }
+    latencies.sort_unstable();
+    let at = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    Latency {
+        p50: at(50),
+        p90: at(90),
+        p99: at(99),
+        max: at(100),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_compute_percentiles() {
+        assert_eq!(percentiles(vec![]), Latency::default());
+        let latency = percentiles((1..=101).rev().collect());
+        assert_eq!(
+            latency,
+            Latency {
+                p50: 51,
+                p90: 91,
+                p99: 100,
+                max: 101,
+            }
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! TLS transport.
+//!
+//! Encrypts the connections between nodes with rustls. TLS is opt-in, and
+//! configured separately for each direction: the listener requires TLS from
+//! remote nodes when it has a certificate and key, and outgoing connections
+//! use TLS when they have trust anchors. A direction without TLS configuration
+//! stays plain TCP. Both sides advertise and require the ALPN protocol
+//! identifier `ALPN_PROTOCOL`.
+use futures::future::BoxFuture;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore, ServerName};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use super::transport::{Connection, HandshakeListener, Listener, Tcp, Transport};
+use super::ALPN_PROTOCOL;
+
+/// The TLS transport.
+#[derive(Clone)]
+pub struct Tls {
+    inner: Arc<dyn Transport>,
+    acceptor: Option<TlsAcceptor>,
+    connector: Option<(TlsConnector, ServerName)>,
+}
+
+/// Error type for the TLS configuration
+#[derive(Debug)]
+pub struct Error {
+    /// Error detail
+    pub detail: String,
+}
+
+impl Tls {
+    /// Create a TLS transport which does not encrypt any direction yet.
+    pub fn new() -> Tls {
+        Tls::default()
+    }
+
+    /// Run TLS on top of the given transport instead of TCP.
+    pub fn with_inner(mut self, inner: Arc<dyn Transport>) -> Tls {
+        self.inner = inner;
+        self
+    }
+
+    /// Require TLS from remote nodes, which are presented the certificate
+    /// chain and private key read from the given PEM files.
+    pub fn with_identity(mut self, cert: &Path, key: &Path) -> Result<Tls, Error> {
+        let config = server_config(cert, key)?;
+        self.acceptor = Some(TlsAcceptor::from(Arc::new(config)));
+        Ok(self)
+    }
+
+    /// Use TLS for outgoing connections, trusting the certificates read from
+    /// the given PEM file. Remote certificates are verified against the server name.
+    pub fn with_trust_anchors(mut self, ca: &Path, server_name: &str) -> Result<Tls, Error> {
+        let config = client_config(ca)?;
+        let server_name = ServerName::try_from(server_name).map_err(|_| Error {
+            detail: format!("Invalid server name '{server_name}'"),
+        })?;
+        self.connector = Some((TlsConnector::from(Arc::new(config)), server_name));
+        Ok(self)
+    }
+}
+
+/// Server configuration presenting the certificate chain and private key read
+/// from the given PEM files, and advertising `ALPN_PROTOCOL`.
+pub fn server_config(cert: &Path, key: &Path) -> Result<rustls::ServerConfig, Error> {
+    let certs = read_certs(cert)?;
+    let key = read_key(key)?;
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| Error {
+            detail: format!("Invalid certificate or key: {err}"),
+        })?;
+    config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+    Ok(config)
+}
+
+/// Client configuration trusting the certificates read from the given PEM file,
+/// and advertising `ALPN_PROTOCOL`.
+pub fn client_config(ca: &Path) -> Result<rustls::ClientConfig, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(ca)? {
+        roots.add(&cert).map_err(|err| Error {
+            detail: format!("Invalid trust anchor in {}: {err}", ca.display()),
+        })?;
+    }
+    let mut config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+    Ok(config)
+}
+
+impl Default for Tls {
+    fn default() -> Tls {
+        Tls {
+            inner: Arc::new(Tcp::default()),
+            acceptor: None,
+            connector: None,
+        }
+    }
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tls")
+            .field("inner", &self.inner)
+            .field("listen", &self.acceptor.is_some())
+            .field("outgoing", &self.connector.is_some())
+            .finish()
+    }
+}
+
+impl Transport for Tls {
+    fn dial<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Connection>> {
+        let (connector, server_name) = match &self.connector {
+            Some(connector) => connector,
+            None => return self.inner.dial(addr, interface),
+        };
+        Box::pin(async move {
+            let conn = self.inner.dial(addr, interface).await?;
+            let stream = connector.connect(server_name.clone(), conn.stream).await?;
+            check_alpn(stream.get_ref().1.alpn_protocol())?;
+            Ok(Connection {
+                stream: Box::new(stream),
+                ..conn
+            })
+        })
+    }
+
+    fn listen<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        let acceptor = match &self.acceptor {
+            Some(acceptor) => acceptor.clone(),
+            None => return self.inner.listen(addr, interface),
+        };
+        Box::pin(async move {
+            let listener = self.inner.listen(addr, interface).await?;
+            let handshake = Arc::new(move |conn| {
+                Box::pin(handshake(acceptor.clone(), conn)) as BoxFuture<'static, _>
+            });
+            Ok(Box::new(HandshakeListener::new(listener, handshake)) as Box<dyn Listener>)
+        })
+    }
+}
+
+async fn handshake(acceptor: TlsAcceptor, conn: Connection) -> io::Result<Connection> {
+    let stream = acceptor.accept(conn.stream).await?;
+    check_alpn(stream.get_ref().1.alpn_protocol())?;
+    Ok(Connection {
+        stream: Box::new(stream),
+        ..conn
+    })
+}
+
+// rustls only rejects a remote advertising other protocols, so we also
+// reject a remote which did not negotiate any.
+fn check_alpn(protocol: Option<&[u8]>) -> io::Result<()> {
+    if protocol == Some(ALPN_PROTOCOL) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Remote did not negotiate the area-net ALPN protocol",
+        ))
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, Error> {
+    File::open(path).map(BufReader::new).map_err(|err| Error {
+        detail: format!("Could not open {}: {err}", path.display()),
+    })
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let certs = rustls_pemfile::certs(&mut open(path)?).map_err(|err| Error {
+        detail: format!("Could not read certificates from {}: {err}", path.display()),
+    })?;
+    if certs.is_empty() {
+        return Err(Error {
+            detail: format!("No certificate found in {}", path.display()),
+        });
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+// The first PKCS#8, RSA or EC private key of the file.
+fn read_key(path: &Path) -> Result<PrivateKey, Error> {
+    let items = rustls_pemfile::read_all(&mut open(path)?).map_err(|err| Error {
+        detail: format!("Could not read private key from {}: {err}", path.display()),
+    })?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| Error {
+            detail: format!("No private key found in {}", path.display()),
+        })
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid TLS configuration: {}", self.detail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn pem_file(content: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    #[tokio::test]
+    async fn should_dial_and_accept_tls_connections() {
+        let cert = rcgen::generate_simple_self_signed(vec!["node.area-net".to_owned()]).unwrap();
+        let cert_file = pem_file(&cert.serialize_pem().unwrap());
+        let key_file = pem_file(&cert.serialize_private_key_pem());
+
+        let server = Tls::new()
+            .with_identity(cert_file.path(), key_file.path())
+            .unwrap();
+        let client = Tls::new()
+            .with_trust_anchors(cert_file.path(), "node.area-net")
+            .unwrap();
+        let mut listener = server
+            .listen("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (dialed, accepted) = tokio::join!(client.dial(addr, None), listener.accept());
+        let (mut dialed, mut accepted) = (dialed.unwrap(), accepted.unwrap());
+        assert_eq!(accepted.peer_addr, dialed.local_addr);
+
+        dialed.stream.write_all(b"*1\r\n").await.unwrap();
+        dialed.stream.flush().await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"*1\r\n");
+
+        // A certificate for another name is rejected.
+        let other = Tls::new()
+            .with_trust_anchors(cert_file.path(), "other.area-net")
+            .unwrap();
+        tokio::select! {
+            res = other.dial(addr, None) => assert!(res.is_err()),
+            _ = listener.accept() => panic!("handshake should have failed"),
+        }
+    }
+}
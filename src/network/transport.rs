@@ -0,0 +1,618 @@
+//! Transports carry the byte streams between nodes.
+//!
+//! Peers and the network controller are not tied to TCP: they dial and listen
+//! through a `Transport`, and frame the byte streams it returns with the
+//! `FrameCodec`. TCP is the default transport, and TLS is selected by the
+//! configuration. Other transports (QUIC, in-memory) are plugged in by setting
+//! `NetworkController::transport` before running the controller.
+use futures::future::BoxFuture;
+use ring::digest::{self, SHA256};
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{timeout, Duration};
+
+use super::socket;
+use crate::hex;
+
+/// Delay for a remote node to complete the handshake of an encrypted transport.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of handshakes a listener runs at once, unless it is guarded.
+const MAX_PENDING_HANDSHAKES: usize = 64;
+
+/// Addresses standing for Unix sockets and their clients are taken from the
+/// discard-only prefix (RFC 6666), which no remote node connects from.
+const UNIX_PREFIX: u128 = 0x100 << 112;
+
+/// Address standing for the Unix socket at `path`, which the target file names
+/// with a `unix:` entry. It is on port 0, so it is not shared with other nodes.
+pub fn unix_addr(path: &Path) -> SocketAddr {
+    let digest = digest::digest(&SHA256, path.to_string_lossy().as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes[2..].copy_from_slice(&digest.as_ref()[..6]);
+    let ip = Ipv6Addr::from(UNIX_PREFIX | 1 << 64 | u128::from(u64::from_be_bytes(bytes)));
+    SocketAddr::from((ip, 0))
+}
+
+// Address of the n-th client accepted on a Unix socket, so that each client is
+// told apart from the others (bans, scores, duplicate connections).
+#[cfg(unix)]
+fn unix_client(n: u64) -> SocketAddr {
+    SocketAddr::from((Ipv6Addr::from(UNIX_PREFIX | u128::from(n)), 0))
+}
+
+/// Protocol the controller listens and dials with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// TCP, optionally encrypted with TLS or Noise.
+    #[default]
+    Tcp,
+    /// QUIC, always encrypted with TLS.
+    Quic,
+    /// WebSocket, on top of TCP or TLS.
+    Websocket,
+}
+
+/// A byte stream between two nodes.
+pub trait ByteStream: AsyncRead + AsyncWrite + fmt::Debug + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + fmt::Debug + Send + Sync + Unpin> ByteStream for T {}
+
+/// A connection established by a transport.
+pub struct Connection {
+    /// Byte stream to the remote node.
+    pub stream: Box<dyn ByteStream>,
+    /// Local address of the connection.
+    pub local_addr: SocketAddr,
+    /// Address of the remote node.
+    pub peer_addr: SocketAddr,
+    /// Channel binding of the encryption layer, which the connection request
+    /// signs, so that a handshake cannot be relayed over another encrypted
+    /// connection. Empty if the layer has none.
+    pub binding: Vec<u8>,
+}
+
+/// Accepts connections from remote nodes.
+pub trait Listener: Send {
+    /// Address the listener is bound to.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Wait for the next connection.
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Connection>>;
+
+    /// Screen the connections with `guard` before their handshake. Listeners without a
+    /// handshake hand their connections over right away, and ignore it.
+    fn guard(&mut self, _guard: Guard) {}
+}
+
+/// Dials and listens for connections between nodes.
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// Open a connection to the node at `addr`, optionally from a network interface.
+    fn dial<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Connection>>;
+
+    /// Listen for connections on `addr`, optionally restricted to a network interface.
+    fn listen<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Listener>>>;
+}
+
+/// The TCP transport. Connections are dialed from an address picked by the
+/// system, unless a bind address is given.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tcp {
+    bind_addr: Option<SocketAddr>,
+    options: socket::Options,
+}
+
+// Accepts TCP connections, and tunes their sockets.
+struct TcpAcceptor {
+    listener: TcpListener,
+    options: socket::Options,
+}
+
+/// Accepts connections from several listeners, eg on a TCP port and on a Unix socket.
+pub struct Listeners(pub Vec<Box<dyn Listener>>);
+
+/// Accepts connections on a Unix socket. They come from co-located processes,
+/// and each one is given a distinct address of the discard-only prefix, on port 0.
+#[cfg(unix)]
+pub struct UnixAcceptor {
+    listener: UnixListener,
+    accepted: u64,
+}
+
+/// Dials the Unix sockets of the target file, by the addresses standing for them,
+/// and any other address with an inner transport.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixTargets {
+    inner: Arc<dyn Transport>,
+    paths: HashMap<SocketAddr, PathBuf>,
+}
+
+/// Handshake turning an accepted connection into the connection of another transport
+/// layered on top of it (eg TLS on top of TCP).
+pub type Handshake =
+    Arc<dyn Fn(Connection) -> BoxFuture<'static, io::Result<Connection>> + Send + Sync>;
+
+/// Decides from the address of a remote node whether its connection is accepted.
+pub type Admit = Arc<dyn Fn(&SocketAddr) -> bool + Send + Sync>;
+
+/// Screens the connections of a listener before their handshake, which is costly: connections
+/// from addresses which are not admitted are dropped, and so are the connections arriving while
+/// too many handshakes are pending.
+#[derive(Clone)]
+pub struct Guard {
+    admit: Admit,
+    pending: Arc<Semaphore>,
+}
+
+/// Accepts connections from an inner listener, and completes a handshake on each of them in a
+/// separate task, so that a slow remote does not hold the other connections back.
+/// Connections failing their handshake are logged and dropped.
+pub struct HandshakeListener {
+    listener: Box<dyn Listener>,
+    handshake: Handshake,
+    guard: Guard,
+    tx: mpsc::Sender<Connection>,
+    rx: mpsc::Receiver<Connection>,
+}
+
+impl Tcp {
+    /// Dial from the given local address. With port 0, the system picks the port.
+    pub fn with_bind_addr(mut self, addr: SocketAddr) -> Tcp {
+        self.bind_addr = Some(addr);
+        self
+    }
+
+    /// Tune the sockets of dialed and accepted connections.
+    pub fn with_options(mut self, options: socket::Options) -> Tcp {
+        self.options = options;
+        self
+    }
+}
+
+impl Connection {
+    /// Wrap a TCP stream.
+    pub fn tcp(stream: TcpStream) -> io::Result<Connection> {
+        Ok(Connection {
+            local_addr: stream.local_addr()?,
+            peer_addr: stream.peer_addr()?,
+            stream: Box::new(stream),
+            binding: Vec::new(),
+        })
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("local_addr", &self.local_addr)
+            .field("peer_addr", &self.peer_addr)
+            .field("binding", &hex::encode(&self.binding))
+            .finish()
+    }
+}
+
+impl Transport for Tcp {
+    fn dial<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Connection>> {
+        Box::pin(async move {
+            let bind_addr = self.bind_addr.as_ref();
+            let stream = socket::connect(&addr, interface, bind_addr, &self.options).await?;
+            Connection::tcp(stream)
+        })
+    }
+
+    fn listen<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            let listener = socket::listen(&addr, interface, &self.options)?;
+            Ok(Box::new(TcpAcceptor {
+                listener,
+                options: self.options,
+            }) as Box<dyn Listener>)
+        })
+    }
+}
+
+impl Guard {
+    /// Admit the connections `admit` accepts, and run at most `max_pending` handshakes at once.
+    pub fn new(admit: Admit, max_pending: usize) -> Guard {
+        Guard {
+            admit,
+            pending: Arc::new(Semaphore::new(max_pending.max(1))),
+        }
+    }
+
+    /// Permit to run the handshake of a connection from `remote`, held until the handshake
+    /// completes. None if the connection must be dropped.
+    pub fn enter(&self, remote: &SocketAddr) -> Option<OwnedSemaphorePermit> {
+        if !(self.admit)(remote) {
+            log::warn!("Transport | Dropping connection from {remote} | Not admitted");
+            return None;
+        }
+        match self.pending.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                log::warn!(
+                    "Transport | Dropping connection from {remote} | Too many pending handshakes"
+                );
+                None
+            }
+        }
+    }
+}
+
+impl Default for Guard {
+    fn default() -> Guard {
+        Guard::new(Arc::new(|_| true), MAX_PENDING_HANDSHAKES)
+    }
+}
+
+impl fmt::Debug for Guard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Guard")
+            .field("available", &self.pending.available_permits())
+            .finish()
+    }
+}
+
+impl HandshakeListener {
+    /// Complete the handshake on each connection accepted by the inner listener.
+    pub fn new(listener: Box<dyn Listener>, handshake: Handshake) -> HandshakeListener {
+        let (tx, rx) = mpsc::channel(16);
+        HandshakeListener {
+            listener,
+            handshake,
+            guard: Guard::default(),
+            tx,
+            rx,
+        }
+    }
+}
+
+impl Listener for HandshakeListener {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Connection>> {
+        Box::pin(async move {
+            loop {
+                tokio::select! {
+                    res = self.listener.accept() => {
+                        let conn = res?;
+                        let remote = conn.peer_addr;
+                        let permit = match self.guard.enter(&remote) {
+                            Some(permit) => permit,
+                            None => continue,
+                        };
+                        let handshake = (self.handshake)(conn);
+                        let tx = self.tx.clone();
+                        tokio::spawn(async move {
+                            let res = timeout(HANDSHAKE_TIMEOUT, handshake).await;
+                            drop(permit);
+                            match res {
+                                Ok(Ok(conn)) => {
+                                    let _ = tx.send(conn).await;
+                                }
+                                Ok(Err(err)) => {
+                                    log::warn!("Transport | Handshake with {remote} failed | {err}");
+                                }
+                                Err(_) => {
+                                    log::warn!("Transport | Handshake with {remote} timed out");
+                                }
+                            }
+                        });
+                    }
+                    Some(conn) = self.rx.recv() => return Ok(conn),
+                }
+            }
+        })
+    }
+
+    fn guard(&mut self, guard: Guard) {
+        self.guard = guard;
+    }
+}
+
+impl Listener for Listeners {
+    // The address of the first listener.
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.0.first() {
+            Some(listener) => listener.local_addr(),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "No listener")),
+        }
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Connection>> {
+        Box::pin(async move {
+            if self.0.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "No listener"));
+            }
+            let accepts = self.0.iter_mut().map(|listener| listener.accept());
+            let (res, _, _) = futures::future::select_all(accepts).await;
+            res
+        })
+    }
+
+    fn guard(&mut self, guard: Guard) {
+        for listener in &mut self.0 {
+            listener.guard(guard.clone());
+        }
+    }
+}
+
+#[cfg(unix)]
+impl UnixAcceptor {
+    /// Accept the connections of `listener`.
+    pub fn new(listener: UnixListener) -> Self {
+        UnixAcceptor {
+            listener,
+            accepted: 0,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixAcceptor {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(unix_client(0))
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Connection>> {
+        Box::pin(async move {
+            let (stream, _) = self.listener.accept().await?;
+            self.accepted += 1;
+            Ok(Connection {
+                stream: Box::new(stream),
+                local_addr: unix_client(0),
+                peer_addr: unix_client(self.accepted),
+                binding: Vec::new(),
+            })
+        })
+    }
+}
+
+#[cfg(unix)]
+impl UnixTargets {
+    /// Dial the sockets of `paths` by their address, and other addresses with `inner`.
+    pub fn new(inner: Arc<dyn Transport>, paths: HashMap<SocketAddr, PathBuf>) -> Self {
+        UnixTargets { inner, paths }
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixTargets {
+    fn dial<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Connection>> {
+        let path = match self.paths.get(&addr) {
+            Some(path) => path,
+            None => return self.inner.dial(addr, interface),
+        };
+        Box::pin(async move {
+            let stream = UnixStream::connect(path).await?;
+            Ok(Connection {
+                stream: Box::new(stream),
+                local_addr: unix_client(0),
+                peer_addr: addr,
+                binding: Vec::new(),
+            })
+        })
+    }
+
+    fn listen<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        self.inner.listen(addr, interface)
+    }
+}
+
+impl Listener for TcpAcceptor {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Connection>> {
+        Box::pin(async move {
+            let (stream, remote) = self.listener.accept().await?;
+            // The connection is still usable, so it is not dropped.
+            if let Err(err) = socket::tune(&stream, &self.options) {
+                log::warn!("Transport | Could not tune the connection from {remote} | {err}");
+            }
+            Connection::tcp(stream)
+        })
+    }
+}
+
+impl Listener for TcpListener {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Connection>> {
+        Box::pin(async move {
+            let (stream, _) = TcpListener::accept(self).await?;
+            Connection::tcp(stream)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn should_dial_and_accept_tcp_connections() {
+        let mut listener = Tcp::default()
+            .listen("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let transport = Tcp::default();
+        let (dialed, accepted) = tokio::join!(transport.dial(addr, None), listener.accept());
+        let (mut dialed, mut accepted) = (dialed.unwrap(), accepted.unwrap());
+        assert_eq!(dialed.peer_addr, addr);
+        assert_eq!(accepted.peer_addr, dialed.local_addr);
+
+        dialed.stream.write_all(b"*1\r\n").await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"*1\r\n");
+    }
+
+    #[tokio::test]
+    async fn should_dial_from_the_bind_addr() {
+        let mut listener = Tcp::default()
+            .listen("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tcp = Tcp::default().with_bind_addr("127.0.0.1:0".parse().unwrap());
+        let (dialed, accepted) = tokio::join!(tcp.dial(addr, None), listener.accept());
+        let dialed = dialed.unwrap();
+        assert_eq!(dialed.local_addr.ip(), addr.ip());
+        assert_eq!(accepted.unwrap().peer_addr, dialed.local_addr);
+
+        let tcp = Tcp::default().with_bind_addr("[::1]:0".parse().unwrap());
+        let err = tcp.dial(addr, None).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn should_accept_connections_from_tcp_and_unix_listeners() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("area-net.sock");
+        let tcp = Tcp::default()
+            .listen("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let unix = UnixAcceptor::new(UnixListener::bind(&path).unwrap());
+        let mut listener = Listeners(vec![tcp, Box::new(unix)]);
+        assert_eq!(listener.local_addr().unwrap(), addr);
+
+        let transport = Tcp::default();
+        let (dialed, accepted) = tokio::join!(transport.dial(addr, None), listener.accept());
+        assert_eq!(accepted.unwrap().peer_addr, dialed.unwrap().local_addr);
+
+        let (dialed, accepted) =
+            tokio::join!(tokio::net::UnixStream::connect(&path), listener.accept());
+        let (mut dialed, mut accepted) = (dialed.unwrap(), accepted.unwrap());
+        assert_eq!(accepted.peer_addr.port(), 0);
+        dialed.write_all(b"*1\r\n").await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"*1\r\n");
+
+        // Each client is told apart from the others.
+        let (_, other) = tokio::join!(tokio::net::UnixStream::connect(&path), listener.accept());
+        assert_ne!(other.unwrap().peer_addr, accepted.peer_addr);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn should_dial_the_unix_sockets_of_the_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("area-net.sock");
+        let mut listener = UnixAcceptor::new(UnixListener::bind(&path).unwrap());
+        let addr = unix_addr(&path);
+        assert_eq!(addr, unix_addr(&path));
+        assert_ne!(addr, unix_addr(&dir.path().join("other.sock")));
+
+        let transport = UnixTargets::new(
+            Arc::new(Tcp::default()),
+            HashMap::from([(addr, path.clone())]),
+        );
+        let (dialed, accepted) = tokio::join!(transport.dial(addr, None), listener.accept());
+        let (mut dialed, mut accepted) = (dialed.unwrap(), accepted.unwrap());
+        assert_eq!(dialed.peer_addr, addr);
+        dialed.stream.write_all(b"*1\r\n").await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"*1\r\n");
+    }
+
+    #[tokio::test]
+    async fn should_screen_connections_before_their_handshake() {
+        let tcp = Tcp::default()
+            .listen("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let addr = tcp.local_addr().unwrap();
+        // The handshake completes when the remote sends a byte.
+        let handshake: Handshake = Arc::new(|mut conn: Connection| {
+            Box::pin(async move {
+                conn.stream.read_exact(&mut [0u8; 1]).await?;
+                Ok(conn)
+            }) as BoxFuture<'static, _>
+        });
+        let mut listener = HandshakeListener::new(tcp, handshake);
+        let open = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let admit: Admit = {
+            let open = open.clone();
+            Arc::new(move |_: &SocketAddr| open.load(std::sync::atomic::Ordering::SeqCst))
+        };
+        listener.guard(Guard::new(admit, 1));
+        let transport = Tcp::default();
+        let wait = Duration::from_millis(100);
+        let mut buf = [0u8; 1];
+
+        // Not admitted.
+        let mut refused = transport.dial(addr, None).await.unwrap();
+        assert!(timeout(wait, listener.accept()).await.is_err());
+        assert!(matches!(
+            refused.stream.read(&mut buf).await,
+            Ok(0) | Err(_)
+        ));
+
+        // Admitted, but one handshake is already pending.
+        open.store(true, std::sync::atomic::Ordering::SeqCst);
+        let mut pending = transport.dial(addr, None).await.unwrap();
+        assert!(timeout(wait, listener.accept()).await.is_err());
+        let mut refused = transport.dial(addr, None).await.unwrap();
+        assert!(timeout(wait, listener.accept()).await.is_err());
+        assert!(matches!(
+            refused.stream.read(&mut buf).await,
+            Ok(0) | Err(_)
+        ));
+
+        pending.stream.write_all(b"*").await.unwrap();
+        let accepted = listener.accept().await.unwrap();
+        assert_eq!(accepted.peer_addr, pending.local_addr);
+    }
+}
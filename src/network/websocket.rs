@@ -0,0 +1,228 @@
+//! WebSocket transport.
+//!
+//! Nodes behind HTTP-only infrastructure can accept and dial peers over
+//! WebSocket. The wire protocol is carried, unchanged, inside binary WebSocket
+//! messages. The WebSocket runs on top of another transport: plain TCP for
+//! `ws://`, or TLS for `wss://`. Both sides require the subprotocol
+//! `ALPN_PROTOCOL`, the counterpart of ALPN for WebSocket.
+use futures::future::BoxFuture;
+use futures::{SinkExt, StreamExt};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use super::transport::{ByteStream, Connection, HandshakeListener, Listener, Transport};
+use super::ALPN_PROTOCOL;
+
+/// Path of the WebSocket endpoint.
+pub const PATH: &str = "/area-net";
+
+const SUBPROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+
+// Largest payload of a binary message sent, and capacity of the pipe
+// between the connection's byte stream and the WebSocket.
+const MAX_PAYLOAD_LEN: usize = 64 * 1024;
+
+/// The WebSocket transport.
+#[derive(Clone)]
+pub struct WebSocket {
+    inner: Arc<dyn Transport>,
+    secure: bool,
+}
+
+impl WebSocket {
+    /// Run WebSockets on top of the given transport. `secure` tells if the
+    /// transport encrypts outgoing connections ('wss' scheme).
+    pub fn new(inner: Arc<dyn Transport>, secure: bool) -> WebSocket {
+        WebSocket { inner, secure }
+    }
+}
+
+impl fmt::Debug for WebSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocket")
+            .field("inner", &self.inner)
+            .field("secure", &self.secure)
+            .finish()
+    }
+}
+
+impl Transport for WebSocket {
+    fn dial<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Connection>> {
+        Box::pin(async move {
+            let conn = self.inner.dial(addr, interface).await?;
+            let scheme = if self.secure { "wss" } else { "ws" };
+            let mut request = format!("{scheme}://{addr}{PATH}")
+                .into_client_request()
+                .map_err(ws_error)?;
+            request.headers_mut().insert(
+                SUBPROTOCOL_HEADER,
+                HeaderValue::from_bytes(ALPN_PROTOCOL).expect("valid subprotocol"),
+            );
+            let (ws, response) = tokio_tungstenite::client_async(request, conn.stream)
+                .await
+                .map_err(ws_error)?;
+            if response
+                .headers()
+                .get(SUBPROTOCOL_HEADER)
+                .map(|v| v.as_bytes())
+                != Some(ALPN_PROTOCOL)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Remote did not accept the area-net subprotocol",
+                ));
+            }
+            Ok(Connection {
+                stream: Box::new(bridge(ws)),
+                ..conn
+            })
+        })
+    }
+
+    fn listen<'a>(
+        &'a self,
+        addr: SocketAddr,
+        interface: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            let listener = self.inner.listen(addr, interface).await?;
+            let handshake = Arc::new(|conn| Box::pin(accept(conn)) as BoxFuture<'static, _>);
+            Ok(Box::new(HandshakeListener::new(listener, handshake)) as Box<dyn Listener>)
+        })
+    }
+}
+
+fn ws_error(err: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("WebSocket: {err}"))
+}
+
+async fn accept(conn: Connection) -> io::Result<Connection> {
+    // The error response type is imposed by tungstenite.
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, mut response: Response| {
+        let offered = request
+            .headers()
+            .get_all(SUBPROTOCOL_HEADER)
+            .iter()
+            .flat_map(|value| value.as_bytes().split(|b| *b == b','))
+            .any(|protocol| protocol.trim_ascii() == ALPN_PROTOCOL);
+        if !offered || request.uri().path() != PATH {
+            let mut error =
+                ErrorResponse::new(Some("Expected the area-net subprotocol".to_owned()));
+            *error.status_mut() = StatusCode::BAD_REQUEST;
+            return Err(error);
+        }
+        response.headers_mut().insert(
+            SUBPROTOCOL_HEADER,
+            HeaderValue::from_bytes(ALPN_PROTOCOL).expect("valid subprotocol"),
+        );
+        Ok(response)
+    };
+    let ws = tokio_tungstenite::accept_hdr_async(conn.stream, callback)
+        .await
+        .map_err(ws_error)?;
+    Ok(Connection {
+        stream: Box::new(bridge(ws)),
+        ..conn
+    })
+}
+
+// The connection's byte stream is one end of an in-memory pipe, and two tasks
+// send what is written to it in binary messages, and write the content of the
+// binary messages received from the remote to it.
+fn bridge(ws: WebSocketStream<Box<dyn ByteStream>>) -> DuplexStream {
+    let (local, remote) = tokio::io::duplex(MAX_PAYLOAD_LEN);
+    let (app_reader, app_writer) = tokio::io::split(local);
+    let (sink, stream) = ws.split();
+    tokio::spawn(send_loop(app_reader, sink));
+    tokio::spawn(receive_loop(stream, app_writer));
+    remote
+}
+
+async fn send_loop(
+    mut reader: impl AsyncRead + Unpin,
+    mut sink: impl futures::Sink<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+) -> io::Result<()> {
+    let mut payload = vec![0u8; MAX_PAYLOAD_LEN];
+    loop {
+        let n = reader.read(&mut payload).await?;
+        if n == 0 {
+            break;
+        }
+        sink.send(Message::Binary(payload[..n].to_vec()))
+            .await
+            .map_err(ws_error)?;
+    }
+    sink.close().await.map_err(ws_error)
+}
+
+async fn receive_loop(
+    mut stream: impl futures::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>>
+        + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+) -> io::Result<()> {
+    while let Some(message) = stream.next().await {
+        match message {
+            Ok(Message::Binary(data)) => writer.write_all(&data).await?,
+            Ok(Message::Close(_)) => break,
+            // Pings are answered by tungstenite.
+            Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_)) => {}
+            Ok(Message::Text(_)) => {
+                log::warn!("WebSocket | Unexpected text message");
+                break;
+            }
+            Err(err) => {
+                log::debug!("WebSocket | Could not receive message | {err}");
+                break;
+            }
+        }
+    }
+    writer.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::transport::Tcp;
+
+    #[tokio::test]
+    async fn should_carry_bytes_in_websocket_messages() {
+        let transport = WebSocket::new(Arc::new(Tcp::default()), false);
+        let mut listener = transport
+            .listen("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (dialed, accepted) = tokio::join!(transport.dial(addr, None), listener.accept());
+        let (mut dialed, mut accepted) = (dialed.unwrap(), accepted.unwrap());
+        assert_eq!(accepted.peer_addr, dialed.local_addr);
+
+        dialed.stream.write_all(b"*1\r\n").await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"*1\r\n");
+
+        // A plain TCP client is not accepted.
+        let mut conn = Tcp::default().dial(addr, None).await.unwrap();
+        conn.stream
+            .write_all(b"*1\r\n+CTCT_REQ\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::select! {
+            _ = conn.stream.read(&mut buf) => {}
+            _ = listener.accept() => panic!("handshake should have failed"),
+        }
+    }
+}
@@ -1,7 +1,11 @@
 //! Provides a type for parsing frames into commands.
 
+use bytes::Bytes;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::net::SocketAddr;
 use std::vec;
+use uuid::Uuid;
 
 use crate::Frame;
 
@@ -23,6 +27,11 @@ pub enum Error {
         /// Error detail
         detail: String,
     },
+    /// The frame has the expected type, but its content is invalid
+    InvalidValue {
+        /// Error detail
+        detail: String,
+    },
 }
 
 impl Parse {
@@ -56,26 +65,128 @@ impl Parse {
         }
     }
 
-    /// Return the integer contained in the Frame::Timestamp
+    /// Return the integer contained in the Frame::Timestamp. Self-describing
+    /// formats (CBOR, MessagePack) don't tell signed and unsigned integers
+    /// apart, so an unsigned integer is also accepted, if it fits.
     pub fn next_integer(&mut self) -> Result<i64, Error> {
         match self.next_frame()? {
             Frame::Int(i) => Ok(i),
+            Frame::UInt(u) if i64::try_from(u).is_ok() => Ok(u as i64),
             frame => Err(Error::InvalidFrameType {
                 detail: format!("Expected Timestamp Frame, got {frame:?}"),
             }),
         }
     }
 
-    /// Return the unsigned contained in the Frame::Integer
+    /// Return the unsigned contained in the Frame::Integer. A positive signed
+    /// integer is also accepted.
     pub fn next_unsigned(&mut self) -> Result<u64, Error> {
         match self.next_frame()? {
             Frame::UInt(u) => Ok(u),
+            Frame::Int(i) if i >= 0 => Ok(i as u64),
             frame => Err(Error::InvalidFrameType {
                 detail: format!("Expected Integer Frame, got {frame:?}"),
             }),
         }
     }
 
+    /// Return the boolean contained in the Frame::Bool
+    pub fn next_bool(&mut self) -> Result<bool, Error> {
+        match self.next_frame()? {
+            Frame::Bool(b) => Ok(b),
+            frame => Err(Error::InvalidFrameType {
+                detail: format!("Expected Bool Frame, got {frame:?}"),
+            }),
+        }
+    }
+
+    /// Return the number contained in the Frame::Double
+    pub fn next_double(&mut self) -> Result<f64, Error> {
+        match self.next_frame()? {
+            Frame::Double(d) => Ok(d),
+            frame => Err(Error::InvalidFrameType {
+                detail: format!("Expected Double Frame, got {frame:?}"),
+            }),
+        }
+    }
+
+    /// Return the bytes contained in the Frame::Bulk
+    pub fn next_bytes(&mut self) -> Result<Bytes, Error> {
+        match self.next_frame()? {
+            Frame::Bulk(b) => Ok(b),
+            frame => Err(Error::InvalidFrameType {
+                detail: format!("Expected Bulk Frame, got {frame:?}"),
+            }),
+        }
+    }
+
+    /// Return the frames contained in the Frame::Map, by key. Unlike the
+    /// positional fields of an array, fields can be added to a map, or made
+    /// optional, without breaking older nodes. If a key is repeated, the last
+    /// value wins.
+    pub fn next_map(&mut self) -> Result<BTreeMap<String, Frame>, Error> {
+        match self.next_frame()? {
+            Frame::Map(entries) => Ok(entries.into_iter().collect()),
+            frame => Err(Error::InvalidFrameType {
+                detail: format!("Expected Map Frame, got {frame:?}"),
+            }),
+        }
+    }
+
+    /// Return the UUID contained in the next Frame::Simple
+    pub fn next_uuid(&mut self) -> Result<Uuid, Error> {
+        let s = self.next_string()?;
+        Uuid::parse_str(&s).map_err(|err| Error::InvalidValue {
+            detail: format!("Expected UUID, got '{s}': {err}"),
+        })
+    }
+
+    /// Return the socket address contained in the next Frame::Simple
+    pub fn next_addr(&mut self) -> Result<SocketAddr, Error> {
+        let s = self.next_string()?;
+        s.parse::<SocketAddr>().map_err(|err| Error::InvalidValue {
+            detail: format!("Expected socket address, got '{s}': {err}"),
+        })
+    }
+
+    /// Return the string contained in the next Frame::Simple, or None if there
+    /// are no more frames. This is used for optional trailing fields, which
+    /// messages sent by older nodes don't have.
+    pub fn next_string_opt(&mut self) -> Result<Option<String>, Error> {
+        if self.remaining() == 0 {
+            return Ok(None);
+        }
+        self.next_string().map(Some)
+    }
+
+    /// Return the unsigned contained in the next Frame::Integer, or None if
+    /// there are no more frames.
+    pub fn next_unsigned_opt(&mut self) -> Result<Option<u64>, Error> {
+        if self.remaining() == 0 {
+            return Ok(None);
+        }
+        self.next_unsigned().map(Some)
+    }
+
+    /// Return the integer contained in the next Frame::Timestamp, or None if
+    /// there are no more frames.
+    pub fn next_integer_opt(&mut self) -> Result<Option<i64>, Error> {
+        if self.remaining() == 0 {
+            return Ok(None);
+        }
+        self.next_integer().map(Some)
+    }
+
+    /// Return the frames left to parse, in an array frame
+    pub fn rest(&mut self) -> Frame {
+        Frame::Array(self.parts.by_ref().collect())
+    }
+
+    /// Return the number of frames left to parse
+    pub fn remaining(&self) -> usize {
+        self.parts.len()
+    }
+
     /// Return Ok(()) if there is no more frames
     pub fn finish(&mut self) -> Result<(), Error> {
         if self.parts.next().is_none() {
@@ -92,6 +203,90 @@ impl fmt::Display for Error {
             Error::EndOfStream => write!(f, "Unexpected End Of Stream"),
             Error::UnexpectedFrame => write!(f, "Unexpected Frame"),
             Error::InvalidFrameType { detail } => write!(f, "Invalid Frame Type: {}", detail),
+            Error::InvalidValue { detail } => write!(f, "Invalid Value: {}", detail),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_optional_trailing_fields() {
+        let frame = Frame::Array(vec![Frame::String("bob".to_owned()), Frame::Int(42)]);
+        let mut parse = Parse::new(frame).unwrap();
+        assert_eq!(parse.remaining(), 2);
+        assert_eq!(parse.next_string_opt().unwrap(), Some("bob".to_owned()));
+        assert_eq!(parse.next_integer_opt().unwrap(), Some(42));
+        assert_eq!(parse.remaining(), 0);
+        assert_eq!(parse.next_string_opt().unwrap(), None);
+        assert_eq!(parse.next_integer_opt().unwrap(), None);
+    }
+
+    #[test]
+    fn should_reject_optional_field_of_the_wrong_type() {
+        let frame = Frame::Array(vec![Frame::String("bob".to_owned())]);
+        let mut parse = Parse::new(frame).unwrap();
+        assert!(parse.next_integer_opt().is_err());
+    }
+
+    #[test]
+    fn should_parse_bools_and_doubles() {
+        let frame = Frame::Array(vec![Frame::Bool(true), Frame::Double(0.5), Frame::Int(1)]);
+        let mut parse = Parse::new(frame).unwrap();
+        assert!(parse.next_bool().unwrap());
+        assert_eq!(parse.next_double().unwrap(), 0.5);
+        assert!(parse.next_double().is_err());
+    }
+
+    #[test]
+    fn should_parse_maps() {
+        let frame = Frame::Array(vec![
+            Frame::Map(vec![
+                ("label".to_owned(), Frame::String("bob".to_owned())),
+                ("rtt".to_owned(), Frame::UInt(12)),
+            ]),
+            Frame::String("bob".to_owned()),
+        ]);
+        let mut parse = Parse::new(frame).unwrap();
+        let map = parse.next_map().unwrap();
+        assert!(matches!(map.get("rtt"), Some(Frame::UInt(12))));
+        assert!(!map.contains_key("tags"));
+        assert!(matches!(
+            parse.next_map(),
+            Err(Error::InvalidFrameType { .. })
+        ));
+    }
+
+    #[test]
+    fn should_parse_uuid_and_addr() {
+        let id = Uuid::new_v4();
+        let frame = Frame::Array(vec![
+            Frame::String(id.to_string()),
+            Frame::String("[::1]:8090".to_owned()),
+        ]);
+        let mut parse = Parse::new(frame).unwrap();
+        assert_eq!(parse.next_uuid().unwrap(), id);
+        assert_eq!(
+            parse.next_addr().unwrap(),
+            "[::1]:8090".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_uuid_and_addr() {
+        let frame = Frame::Array(vec![
+            Frame::String("id".to_owned()),
+            Frame::String("localhost".to_owned()),
+            Frame::UInt(42),
+        ]);
+        let mut parse = Parse::new(frame).unwrap();
+        assert!(matches!(parse.next_uuid(), Err(Error::InvalidValue { .. })));
+        assert!(matches!(parse.next_addr(), Err(Error::InvalidValue { .. })));
+        assert!(matches!(
+            parse.next_addr(),
+            Err(Error::InvalidFrameType { .. })
+        ));
+    }
+}
@@ -0,0 +1,956 @@
+//! Protobuf messages
+//!
+//! The messages defined in `proto/area_net.proto`, so that nodes written in
+//! other languages can generate compatible types with their own protobuf
+//! tooling. The types are written with the prost derive rather than generated
+//! by prost-build, so that building the crate does not require protoc: they
+//! must be kept in sync with the schema.
+//!
+//! `ProtoCodec` exchanges messages as envelopes prefixed by their length (a
+//! varint), and `Message::from_proto` / `Message::into_proto` convert to and
+//! from the envelope.
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use tokio_util::codec::{Decoder, Encoder};
+use uuid::Uuid;
+
+use crate::codec::{self, Compression, Error, Format};
+use crate::frame::{IntEncoding, Limits};
+use crate::message::conn_rejection::RejectReason;
+use crate::message::{self, Message};
+use crate::parse;
+
+/// A message, as sent on the wire.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Envelope {
+    /// The message, None if the sender knows a message we don't.
+    #[prost(oneof = "Kind", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12")]
+    pub message: Option<Kind>,
+}
+
+/// The messages an envelope can hold.
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum Kind {
+    /// CONN_REQ
+    #[prost(message, tag = "1")]
+    ConnRequest(ConnRequest),
+    /// CONN_RESP
+    #[prost(message, tag = "2")]
+    ConnResponse(ConnResponse),
+    /// CONN_REJECT
+    #[prost(message, tag = "3")]
+    ConnRejection(ConnRejection),
+    /// HBT_REQ
+    #[prost(message, tag = "4")]
+    HeartbeatRequest(HeartbeatRequest),
+    /// HBT_RESP
+    #[prost(message, tag = "5")]
+    HeartbeatResponse(HeartbeatResponse),
+    /// CTCT_REQ
+    #[prost(message, tag = "6")]
+    ContactRequest(ContactRequest),
+    /// CTCT_RESP
+    #[prost(message, tag = "7")]
+    ContactResponse(ContactResponse),
+    /// RELAY_OPEN
+    #[prost(message, tag = "8")]
+    RelayOpen(RelayOpen),
+    /// RELAY_DATA
+    #[prost(message, tag = "9")]
+    RelayData(RelayData),
+    /// RELAY_CLOSE
+    #[prost(message, tag = "10")]
+    RelayClose(RelayClose),
+    /// PAYLOAD
+    #[prost(message, tag = "11")]
+    Payload(Payload),
+    /// CHUNK
+    #[prost(message, tag = "12")]
+    Chunk(Chunk),
+    /// BYE
+    #[prost(message, tag = "13")]
+    Bye(Bye),
+    /// PING
+    #[prost(message, tag = "14")]
+    Ping(Ping),
+    /// PONG
+    #[prost(message, tag = "15")]
+    Pong(Pong),
+    /// AUTH_CHAL
+    #[prost(message, tag = "16")]
+    AuthChallenge(AuthChallenge),
+    /// AUTH_RESP
+    #[prost(message, tag = "17")]
+    AuthResponse(AuthResponse),
+    /// GOSSIP
+    #[prost(message, tag = "18")]
+    Gossip(Gossip),
+    /// POW_CHAL
+    #[prost(message, tag = "19")]
+    PowChallenge(PowChallenge),
+    /// POW_RESP
+    #[prost(message, tag = "20")]
+    PowResponse(PowResponse),
+    /// INFO_REQ
+    #[prost(message, tag = "21")]
+    InfoRequest(InfoRequest),
+    /// INFO_RESP
+    #[prost(message, tag = "22")]
+    InfoResponse(InfoResponse),
+    /// CODEC_OFFER
+    #[prost(message, tag = "23")]
+    CodecOffer(CodecOptions),
+    /// CODEC_ACCEPT
+    #[prost(message, tag = "24")]
+    CodecAccept(CodecOptions),
+    /// FIND_NODE
+    #[prost(message, tag = "25")]
+    FindNode(FindNode),
+    /// NODES
+    #[prost(message, tag = "26")]
+    Nodes(Nodes),
+}
+
+/// CONN_REQ, sent by the node initiating a connection.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConnRequest {
+    /// Id of the controller
+    #[prost(string, tag = "1")]
+    pub id: String,
+    /// Label of the controller
+    #[prost(string, tag = "2")]
+    pub label: String,
+    /// Address the node listens on
+    #[prost(string, tag = "3")]
+    pub address: String,
+    /// Compression offered
+    #[prost(string, optional, tag = "4")]
+    pub compression: Option<String>,
+    /// Public key of the controller
+    #[prost(bytes = "vec", optional, tag = "5")]
+    pub public_key: Option<Vec<u8>>,
+    /// Signature of the transcript
+    #[prost(bytes = "vec", optional, tag = "6")]
+    pub signature: Option<Vec<u8>>,
+    /// Nonce of the request
+    #[prost(uint64, optional, tag = "7")]
+    pub nonce: Option<u64>,
+    /// Time (micros) when the request was sent
+    #[prost(int64, optional, tag = "8")]
+    pub timestamp: Option<i64>,
+    /// Id of the network, empty for the default network
+    #[prost(string, tag = "9")]
+    pub network: String,
+    /// Ephemeral public key, for the session keys
+    #[prost(bytes = "vec", optional, tag = "10")]
+    pub key_share: Option<Vec<u8>>,
+}
+
+/// CONN_RESP, accepting a connection.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConnResponse {
+    /// Id of the controller
+    #[prost(string, tag = "1")]
+    pub id: String,
+    /// Label of the controller
+    #[prost(string, tag = "2")]
+    pub label: String,
+    /// Address the connection was received from
+    #[prost(string, optional, tag = "3")]
+    pub observed: Option<String>,
+    /// Compression accepted
+    #[prost(string, optional, tag = "4")]
+    pub compression: Option<String>,
+    /// Public key of the controller
+    #[prost(bytes = "vec", optional, tag = "5")]
+    pub public_key: Option<Vec<u8>>,
+    /// Signature of the transcript
+    #[prost(bytes = "vec", optional, tag = "6")]
+    pub signature: Option<Vec<u8>>,
+    /// Ephemeral public key, for the session keys
+    #[prost(bytes = "vec", optional, tag = "7")]
+    pub key_share: Option<Vec<u8>>,
+}
+
+/// CONN_REJECT, refusing a connection.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConnRejection {
+    /// Id of the controller
+    #[prost(string, tag = "1")]
+    pub id: String,
+    /// Reason of the rejection, eg 'banned'
+    #[prost(string, tag = "2")]
+    pub reason: String,
+}
+
+/// HBT_REQ
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HeartbeatRequest {
+    /// Id of the controller
+    #[prost(string, tag = "1")]
+    pub id: String,
+    /// Label of the controller
+    #[prost(string, tag = "2")]
+    pub label: String,
+    /// Timestamp (micros) when the request was sent
+    #[prost(int64, tag = "3")]
+    pub src: i64,
+    /// Health of the node
+    #[prost(message, optional, tag = "4")]
+    pub health: Option<Health>,
+}
+
+/// Health of the node sending a heartbeat.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Health {
+    /// Number of remote nodes the node is connected to
+    #[prost(uint64, tag = "1")]
+    pub peers: u64,
+    /// Number of commands and chunks waiting to be sent
+    #[prost(uint64, tag = "2")]
+    pub queue: u64,
+    /// Seconds since the node started
+    #[prost(uint64, tag = "3")]
+    pub uptime: u64,
+}
+
+/// HBT_RESP
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HeartbeatResponse {
+    /// Id of the controller
+    #[prost(string, tag = "1")]
+    pub id: String,
+    /// Label of the controller
+    #[prost(string, tag = "2")]
+    pub label: String,
+    /// Timestamp (micros) of the request
+    #[prost(int64, tag = "3")]
+    pub src: i64,
+    /// Timestamp (micros) when the response was sent
+    #[prost(int64, tag = "4")]
+    pub dst: i64,
+    /// Health of the node
+    #[prost(message, optional, tag = "5")]
+    pub health: Option<Health>,
+}
+
+/// CTCT_REQ
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ContactRequest {}
+
+/// Tags of an address.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Tags {
+    /// Tag values, by name
+    #[prost(btree_map = "string, string", tag = "1")]
+    pub tags: BTreeMap<String, String>,
+}
+
+/// CTCT_RESP
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ContactResponse {
+    /// Addresses of the known nodes
+    #[prost(string, repeated, tag = "1")]
+    pub addrs: Vec<String>,
+    /// Tags of each address, if any
+    #[prost(message, repeated, tag = "2")]
+    pub tags: Vec<Tags>,
+    /// Node at each address, if any
+    #[prost(message, repeated, tag = "3")]
+    pub contacts: Vec<Contact>,
+    /// Signature of the transcript
+    #[prost(bytes = "vec", optional, tag = "4")]
+    pub signature: Option<Vec<u8>>,
+}
+
+/// A node known to the sender of a contact response.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Contact {
+    /// Id of the node
+    #[prost(string, tag = "1")]
+    pub id: String,
+    /// Label of the node
+    #[prost(string, tag = "2")]
+    pub label: String,
+    /// Last time (micros) the sender heard from the node
+    #[prost(int64, tag = "3")]
+    pub last_seen: i64,
+    /// How the sender knows the node
+    #[prost(string, tag = "4")]
+    pub provenance: String,
+}
+
+/// RELAY_OPEN
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RelayOpen {
+    /// Id of the circuit
+    #[prost(string, tag = "1")]
+    pub circuit: String,
+    /// Address of the other end
+    #[prost(string, tag = "2")]
+    pub addr: String,
+}
+
+/// RELAY_DATA
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RelayData {
+    /// Id of the circuit
+    #[prost(string, tag = "1")]
+    pub circuit: String,
+    /// Bytes carried by the circuit
+    #[prost(bytes = "vec", tag = "2")]
+    pub data: Vec<u8>,
+}
+
+/// RELAY_CLOSE
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RelayClose {
+    /// Id of the circuit
+    #[prost(string, tag = "1")]
+    pub circuit: String,
+}
+
+/// PAYLOAD
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Payload {
+    /// Bytes sent by the application
+    #[prost(bytes = "bytes", tag = "1")]
+    pub data: Bytes,
+    /// Topic chosen by the application, empty if none
+    #[prost(string, tag = "2")]
+    pub topic: String,
+}
+
+/// CHUNK
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Chunk {
+    /// Id of the stream
+    #[prost(uint64, tag = "1")]
+    pub stream: u64,
+    /// Position of the chunk in the stream
+    #[prost(uint64, tag = "2")]
+    pub seq: u64,
+    /// True for the last chunk of the stream
+    #[prost(bool, tag = "3")]
+    pub last: bool,
+    /// Bytes of the frame, in the binary framing
+    #[prost(bytes = "bytes", tag = "4")]
+    pub data: Bytes,
+}
+
+/// BYE
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Bye {
+    /// Why the connection is closed
+    #[prost(uint64, tag = "1")]
+    pub reason: u64,
+}
+
+/// PING
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Ping {
+    /// Sequence number, chosen by the sender
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    /// Opaque data, echoed by the remote
+    #[prost(bytes = "bytes", tag = "2")]
+    pub data: Bytes,
+}
+
+/// PONG
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Pong {
+    /// Sequence number of the ping
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    /// Data of the ping
+    #[prost(bytes = "bytes", tag = "2")]
+    pub data: Bytes,
+}
+
+/// AUTH_CHAL
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AuthChallenge {
+    /// Random bytes, chosen by the incoming peer
+    #[prost(bytes = "bytes", tag = "1")]
+    pub nonce: Bytes,
+}
+
+/// AUTH_RESP
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AuthResponse {
+    /// HMAC of the nonce
+    #[prost(bytes = "bytes", tag = "1")]
+    pub mac: Bytes,
+}
+
+/// POW_CHAL
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PowChallenge {
+    /// Random bytes, chosen by the incoming peer
+    #[prost(bytes = "bytes", tag = "1")]
+    pub nonce: Bytes,
+    /// Number of leading zero bits required
+    #[prost(uint64, tag = "2")]
+    pub difficulty: u64,
+}
+
+/// POW_RESP
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PowResponse {
+    /// Solution found by the remote
+    #[prost(uint64, tag = "1")]
+    pub solution: u64,
+}
+
+/// INFO_REQ
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct InfoRequest {}
+
+/// INFO_RESP
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct InfoResponse {
+    /// Version of area-net the node runs
+    #[prost(string, tag = "1")]
+    pub version: String,
+    /// Label of the node
+    #[prost(string, tag = "2")]
+    pub label: String,
+    /// Number of outgoing connections
+    #[prost(uint64, tag = "3")]
+    pub outgoing: u64,
+    /// Number of incoming connections
+    #[prost(uint64, tag = "4")]
+    pub incoming: u64,
+    /// Seconds since the node started
+    #[prost(uint64, tag = "5")]
+    pub uptime: u64,
+}
+
+/// FIND_NODE
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FindNode {
+    /// Id looked up
+    #[prost(string, tag = "1")]
+    pub target: String,
+}
+
+/// NODES
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Nodes {
+    /// Id looked up
+    #[prost(string, tag = "1")]
+    pub target: String,
+    /// Nodes, closest first
+    #[prost(message, repeated, tag = "2")]
+    pub nodes: Vec<Node>,
+}
+
+/// A node of a NODES answer.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Node {
+    /// Id of the node
+    #[prost(string, tag = "1")]
+    pub id: String,
+    /// Address the node listens on
+    #[prost(string, tag = "2")]
+    pub addr: String,
+}
+
+/// CODEC_OFFER / CODEC_ACCEPT
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CodecOptions {
+    /// Name of the framing
+    #[prost(string, tag = "1")]
+    pub format: String,
+    /// Name of the compression, empty for none
+    #[prost(string, tag = "2")]
+    pub compression: String,
+    /// Name of the integer encoding
+    #[prost(string, tag = "3")]
+    pub integers: String,
+}
+
+/// GOSSIP
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Gossip {
+    /// Id of the message
+    #[prost(string, tag = "1")]
+    pub id: String,
+    /// Number of hops left
+    #[prost(uint64, tag = "2")]
+    pub ttl: u64,
+    /// Topic of the data
+    #[prost(string, tag = "3")]
+    pub topic: String,
+    /// Opaque data
+    #[prost(bytes = "bytes", tag = "4")]
+    pub data: Bytes,
+}
+
+impl TryFrom<Message> for Kind {
+    type Error = message::Error;
+
+    fn try_from(msg: Message) -> Result<Kind, message::Error> {
+        let kind = match msg {
+            Message::ConnRequest(msg) => Kind::ConnRequest(ConnRequest {
+                id: msg.id.to_string(),
+                label: msg.label,
+                address: msg.address.to_string(),
+                compression: msg.compression.map(|c| c.name().to_owned()),
+                public_key: msg.public_key,
+                signature: msg.signature,
+                nonce: msg.nonce,
+                timestamp: msg.timestamp,
+                network: msg.network,
+                key_share: msg.key_share,
+            }),
+            Message::ConnResponse(msg) => Kind::ConnResponse(ConnResponse {
+                id: msg.id.to_string(),
+                label: msg.label,
+                observed: msg.observed.map(|addr| addr.to_string()),
+                compression: msg.compression.map(|c| c.name().to_owned()),
+                public_key: msg.public_key,
+                signature: msg.signature,
+                key_share: msg.key_share,
+            }),
+            Message::ConnRejection(msg) => Kind::ConnRejection(ConnRejection {
+                id: msg.id.to_string(),
+                reason: msg.reason.name().to_owned(),
+            }),
+            Message::HeartbeatRequest(msg) => Kind::HeartbeatRequest(HeartbeatRequest {
+                id: msg.id.to_string(),
+                label: msg.label,
+                src: msg.src,
+                health: msg.health.map(Health::from),
+            }),
+            Message::HeartbeatResponse(msg) => Kind::HeartbeatResponse(HeartbeatResponse {
+                id: msg.id.to_string(),
+                label: msg.label,
+                src: msg.src,
+                dst: msg.dst,
+                health: msg.health.map(Health::from),
+            }),
+            Message::ContactRequest(_) => Kind::ContactRequest(ContactRequest {}),
+            Message::ContactResponse(msg) => Kind::ContactResponse(ContactResponse {
+                addrs: msg.addrs.iter().map(|addr| addr.to_string()).collect(),
+                tags: msg.tags.into_iter().map(|tags| Tags { tags }).collect(),
+                contacts: msg
+                    .contacts
+                    .into_iter()
+                    .map(|contact| Contact {
+                        id: contact.id.to_string(),
+                        label: contact.label,
+                        last_seen: contact.last_seen,
+                        provenance: contact.provenance.name().to_owned(),
+                    })
+                    .collect(),
+                signature: msg.signature,
+            }),
+            Message::RelayOpen(msg) => Kind::RelayOpen(RelayOpen {
+                circuit: msg.circuit.to_string(),
+                addr: msg.addr.to_string(),
+            }),
+            Message::RelayData(msg) => Kind::RelayData(RelayData {
+                circuit: msg.circuit.to_string(),
+                data: msg.data,
+            }),
+            Message::RelayClose(msg) => Kind::RelayClose(RelayClose {
+                circuit: msg.circuit.to_string(),
+            }),
+            Message::Payload(msg) => Kind::Payload(Payload {
+                data: msg.data,
+                topic: msg.topic,
+            }),
+            Message::Chunk(msg) => Kind::Chunk(Chunk {
+                stream: msg.stream,
+                seq: msg.seq,
+                last: msg.last,
+                data: msg.data,
+            }),
+            Message::Bye(msg) => Kind::Bye(Bye {
+                reason: msg.reason.code(),
+            }),
+            Message::Ping(msg) => Kind::Ping(Ping {
+                seq: msg.seq,
+                data: msg.data,
+            }),
+            Message::Pong(msg) => Kind::Pong(Pong {
+                seq: msg.seq,
+                data: msg.data,
+            }),
+            Message::AuthChallenge(msg) => Kind::AuthChallenge(AuthChallenge { nonce: msg.nonce }),
+            Message::AuthResponse(msg) => Kind::AuthResponse(AuthResponse { mac: msg.mac }),
+            Message::Gossip(msg) => Kind::Gossip(Gossip {
+                id: msg.id.to_string(),
+                ttl: msg.ttl,
+                topic: msg.topic,
+                data: msg.data,
+            }),
+            Message::PowChallenge(msg) => Kind::PowChallenge(PowChallenge {
+                nonce: msg.nonce,
+                difficulty: msg.difficulty,
+            }),
+            Message::PowResponse(msg) => Kind::PowResponse(PowResponse {
+                solution: msg.solution,
+            }),
+            Message::InfoRequest(_) => Kind::InfoRequest(InfoRequest {}),
+            Message::InfoResponse(msg) => Kind::InfoResponse(InfoResponse {
+                version: msg.version,
+                label: msg.label,
+                outgoing: msg.outgoing,
+                incoming: msg.incoming,
+                uptime: msg.uptime,
+            }),
+            Message::CodecOffer(msg) => Kind::CodecOffer(msg.options.into()),
+            Message::CodecAccept(msg) => Kind::CodecAccept(msg.options.into()),
+            Message::FindNode(msg) => Kind::FindNode(FindNode {
+                target: msg.target.to_string(),
+            }),
+            Message::Nodes(msg) => Kind::Nodes(Nodes {
+                target: msg.target.to_string(),
+                nodes: msg
+                    .nodes
+                    .iter()
+                    .map(|node| Node {
+                        id: node.id.to_string(),
+                        addr: node.addr.to_string(),
+                    })
+                    .collect(),
+            }),
+            Message::Unknown { tag, .. } => {
+                return Err(message::Error::UnexpectedMessage {
+                    detail: format!("'{tag}' has no protobuf form"),
+                })
+            }
+        };
+        Ok(kind)
+    }
+}
+
+impl TryFrom<Kind> for Message {
+    type Error = message::Error;
+
+    fn try_from(kind: Kind) -> Result<Message, message::Error> {
+        let msg = match kind {
+            Kind::ConnRequest(msg) => Message::ConnRequest(message::ConnRequest {
+                id: uuid(&msg.id)?,
+                label: msg.label,
+                address: addr(&msg.address)?,
+                // A compression we don't know is not offered.
+                compression: msg
+                    .compression
+                    .and_then(|name| Compression::from_name(&name)),
+                public_key: msg.public_key,
+                signature: msg.signature,
+                nonce: msg.nonce,
+                timestamp: msg.timestamp,
+                network: msg.network,
+                key_share: msg.key_share,
+            }),
+            Kind::ConnResponse(msg) => Message::ConnResponse(message::ConnResponse {
+                id: uuid(&msg.id)?,
+                label: msg.label,
+                observed: msg.observed.as_deref().map(addr).transpose()?,
+                compression: msg
+                    .compression
+                    .and_then(|name| Compression::from_name(&name)),
+                public_key: msg.public_key,
+                signature: msg.signature,
+                key_share: msg.key_share,
+            }),
+            Kind::ConnRejection(msg) => Message::ConnRejection(message::ConnRejection::new(
+                uuid(&msg.id)?,
+                RejectReason::from_name(&msg.reason),
+            )),
+            Kind::HeartbeatRequest(msg) => Message::HeartbeatRequest(message::HeartbeatRequest {
+                id: uuid(&msg.id)?,
+                label: msg.label,
+                src: msg.src,
+                health: msg.health.map(message::Health::from),
+            }),
+            Kind::HeartbeatResponse(msg) => {
+                Message::HeartbeatResponse(message::HeartbeatResponse {
+                    id: uuid(&msg.id)?,
+                    label: msg.label,
+                    src: msg.src,
+                    dst: msg.dst,
+                    health: msg.health.map(message::Health::from),
+                })
+            }
+            Kind::ContactRequest(_) => Message::ContactRequest(message::ContactRequest),
+            Kind::ContactResponse(msg) => Message::ContactResponse(message::ContactResponse {
+                addrs: msg
+                    .addrs
+                    .iter()
+                    .map(|s| addr(s))
+                    .collect::<Result<_, _>>()?,
+                tags: msg.tags.into_iter().map(|tags| tags.tags).collect(),
+                contacts: msg
+                    .contacts
+                    .into_iter()
+                    .map(|contact| {
+                        Ok(message::Contact {
+                            id: uuid(&contact.id)?,
+                            label: contact.label,
+                            last_seen: contact.last_seen,
+                            provenance: message::Provenance::from_name(&contact.provenance),
+                        })
+                    })
+                    .collect::<Result<_, message::Error>>()?,
+                signature: msg.signature,
+            }),
+            Kind::RelayOpen(msg) => Message::RelayOpen(message::RelayOpen {
+                circuit: uuid(&msg.circuit)?,
+                addr: addr(&msg.addr)?,
+            }),
+            Kind::RelayData(msg) => Message::RelayData(message::RelayData {
+                circuit: uuid(&msg.circuit)?,
+                data: msg.data,
+            }),
+            Kind::RelayClose(msg) => Message::RelayClose(message::RelayClose {
+                circuit: uuid(&msg.circuit)?,
+            }),
+            Kind::Payload(msg) => Message::Payload(message::Payload::new(msg.topic, msg.data)),
+            Kind::Chunk(msg) => {
+                Message::Chunk(message::Chunk::new(msg.stream, msg.seq, msg.last, msg.data))
+            }
+            Kind::Bye(msg) => Message::Bye(message::Bye::new(message::bye::Reason::from_code(
+                msg.reason,
+            ))),
+            Kind::Ping(msg) => Message::Ping(message::Ping::new(msg.seq, msg.data)),
+            Kind::Pong(msg) => Message::Pong(message::Pong::new(msg.seq, msg.data)),
+            Kind::AuthChallenge(msg) => {
+                Message::AuthChallenge(message::AuthChallenge::new(msg.nonce))
+            }
+            Kind::AuthResponse(msg) => Message::AuthResponse(message::AuthResponse::new(msg.mac)),
+            Kind::Gossip(msg) => Message::Gossip(message::Gossip::new(
+                uuid(&msg.id)?,
+                msg.ttl,
+                msg.topic,
+                msg.data,
+            )),
+            Kind::PowChallenge(msg) => {
+                Message::PowChallenge(message::PowChallenge::new(msg.nonce, msg.difficulty))
+            }
+            Kind::PowResponse(msg) => Message::PowResponse(message::PowResponse::new(msg.solution)),
+            Kind::InfoRequest(_) => Message::InfoRequest(message::InfoRequest),
+            Kind::InfoResponse(msg) => Message::InfoResponse(message::InfoResponse {
+                version: msg.version,
+                label: msg.label,
+                outgoing: msg.outgoing,
+                incoming: msg.incoming,
+                uptime: msg.uptime,
+            }),
+            Kind::CodecOffer(msg) => Message::CodecOffer(message::CodecOffer {
+                options: msg.into(),
+            }),
+            Kind::CodecAccept(msg) => Message::CodecAccept(message::CodecAccept {
+                options: msg.into(),
+            }),
+            Kind::FindNode(msg) => Message::FindNode(message::FindNode {
+                target: uuid(&msg.target)?,
+            }),
+            Kind::Nodes(msg) => Message::Nodes(message::Nodes {
+                target: uuid(&msg.target)?,
+                nodes: msg
+                    .nodes
+                    .iter()
+                    .map(|node| {
+                        Ok(message::dht::Node {
+                            id: uuid(&node.id)?,
+                            addr: addr(&node.addr)?,
+                        })
+                    })
+                    .collect::<Result<_, message::Error>>()?,
+            }),
+        };
+        Ok(msg)
+    }
+}
+
+impl From<message::Health> for Health {
+    fn from(health: message::Health) -> Health {
+        Health {
+            peers: health.peers,
+            queue: health.queue,
+            uptime: health.uptime,
+        }
+    }
+}
+
+impl From<Health> for message::Health {
+    fn from(health: Health) -> message::Health {
+        message::Health {
+            peers: health.peers,
+            queue: health.queue,
+            uptime: health.uptime,
+        }
+    }
+}
+
+impl From<codec::CodecOptions> for CodecOptions {
+    fn from(options: codec::CodecOptions) -> CodecOptions {
+        CodecOptions {
+            format: options.format.name().to_owned(),
+            compression: options.compression.map_or("", |c| c.name()).to_owned(),
+            integers: options.integers.name().to_owned(),
+        }
+    }
+}
+
+// Options we don't know are read as those every node understands.
+impl From<CodecOptions> for codec::CodecOptions {
+    fn from(options: CodecOptions) -> codec::CodecOptions {
+        codec::CodecOptions {
+            format: Format::from_name(&options.format).unwrap_or_default(),
+            compression: Compression::from_name(&options.compression),
+            integers: IntEncoding::from_name(&options.integers).unwrap_or_default(),
+        }
+    }
+}
+
+fn uuid(s: &str) -> Result<Uuid, message::Error> {
+    Uuid::parse_str(s).map_err(|err| {
+        message::Error::from(parse::Error::InvalidValue {
+            detail: format!("Expected UUID, got '{s}': {err}"),
+        })
+    })
+}
+
+fn addr(s: &str) -> Result<SocketAddr, message::Error> {
+    s.parse::<SocketAddr>().map_err(|err| {
+        message::Error::from(parse::Error::InvalidValue {
+            detail: format!("Expected socket address, got '{s}': {err}"),
+        })
+    })
+}
+
+/// Codec exchanging messages as protobuf envelopes, each prefixed by its
+/// length (a varint).
+#[derive(Debug, Default)]
+pub struct ProtoCodec {
+    limits: Limits,
+}
+
+impl ProtoCodec {
+    /// Creates a codec decoding envelopes within the given limits
+    pub fn new(limits: Limits) -> ProtoCodec {
+        ProtoCodec { limits }
+    }
+}
+
+impl Decoder for ProtoCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Error> {
+        // A varint of a u64 takes at most 10 bytes.
+        let len = match prost::decode_length_delimiter(&src[..]) {
+            Ok(len) => len,
+            Err(_) if src.len() < 10 => return Ok(None),
+            Err(err) => {
+                return Err(Error::UnexpectedBytes {
+                    detail: format!("Invalid envelope length: {err}"),
+                })
+            }
+        };
+        if len > self.limits.max_buffer_size {
+            return Err(Error::BufferFull {
+                detail: format!(
+                    "Envelope of {} bytes, more than {}",
+                    len, self.limits.max_buffer_size
+                ),
+            });
+        }
+        let header = prost::length_delimiter_len(len);
+        if src.len() < header + len {
+            return Ok(None);
+        }
+        src.advance(header);
+        let envelope = src.split_to(len);
+        let envelope = <Envelope as prost::Message>::decode(envelope.freeze()).map_err(|err| {
+            Error::UnexpectedBytes {
+                detail: format!("Invalid envelope: {err}"),
+            }
+        })?;
+        Message::from_proto(envelope)
+            .map(Some)
+            .map_err(|err| Error::UnexpectedBytes {
+                detail: err.to_string(),
+            })
+    }
+}
+
+impl Encoder<Message> for ProtoCodec {
+    type Error = Error;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), Error> {
+        let envelope = msg.into_proto().map_err(|err| Error::UnexpectedBytes {
+            detail: err.to_string(),
+        })?;
+        prost::Message::encode_length_delimited(&envelope, dst).map_err(|err| {
+            Error::UnexpectedBytes {
+                detail: format!("Cannot encode envelope: {err}"),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codec_exchanges_protobuf_envelopes() {
+        let id = Uuid::new_v4();
+        let observed: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let mut codec = ProtoCodec::default();
+        let mut src = BytesMut::new();
+        codec
+            .encode(
+                Message::ConnResponse(message::ConnResponse::new(
+                    id,
+                    "bob".into(),
+                    Some(observed),
+                    Some(Compression::Lz4),
+                )),
+                &mut src,
+            )
+            .unwrap();
+        codec
+            .encode(
+                Message::Payload(message::Payload::new("greetings".into(), "hello".into())),
+                &mut src,
+            )
+            .unwrap();
+
+        // The first envelope arrives in two parts.
+        let mut partial = src.split_to(3);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(src);
+        match codec.decode(&mut partial).unwrap() {
+            Some(Message::ConnResponse(response)) => {
+                assert_eq!(response.id, id);
+                assert_eq!(response.label, "bob");
+                assert_eq!(response.observed, Some(observed));
+                assert_eq!(response.compression, Some(Compression::Lz4));
+            }
+            msg => panic!("Expected a ConnResponse, got {msg:?}"),
+        }
+        match codec.decode(&mut partial).unwrap() {
+            Some(Message::Payload(payload)) => assert_eq!(&payload.data[..], b"hello"),
+            msg => panic!("Expected a Payload, got {msg:?}"),
+        }
+        assert!(partial.is_empty());
+
+        // An envelope with an invalid id is rejected.
+        let envelope = Envelope {
+            message: Some(Kind::RelayClose(RelayClose {
+                circuit: "nope".to_owned(),
+            })),
+        };
+        assert!(Message::from_proto(envelope).is_err());
+    }
+}